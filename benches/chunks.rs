@@ -0,0 +1,202 @@
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use ddup_bak::{
+    archive::{Archive, CompressionFormat, entries::Entry},
+    chunks::{
+        ChunkIndex, ChunkIndexTuning,
+        storage::{ChunkStorage, ChunkStorageLocal, ChunkStorageMemory},
+    },
+};
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+fn temp_directory(name: &str) -> PathBuf {
+    let directory =
+        std::env::temp_dir().join(format!("ddup-bak-bench-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+
+    directory
+}
+
+/// Content that compresses about as well as source code does.
+fn text(length: usize) -> Vec<u8> {
+    (0..length)
+        .map(|i| b"fn chunk(&self) -> u64 { self.id }\n"[i % 35] ^ ((i / 4096) as u8 & 7))
+        .collect()
+}
+
+fn memory_index(directory: &Path, chunk_size: usize, tuning: ChunkIndexTuning) -> ChunkIndex {
+    ChunkIndex::new_with_tuning(
+        directory.to_path_buf(),
+        chunk_size,
+        0,
+        Arc::new(ChunkStorageMemory::default()),
+        tuning,
+    )
+    .unwrap()
+}
+
+fn chunk_file(c: &mut Criterion) {
+    let directory = temp_directory("chunk-file");
+    let path = directory.join("file");
+    std::fs::write(&path, text(16 * 1024 * 1024)).unwrap();
+
+    let mut group = c.benchmark_group("chunk_file");
+    group.sample_size(10);
+
+    for compression in [CompressionFormat::None, CompressionFormat::Deflate] {
+        for (name, tuning) in [
+            ("default tuning", ChunkIndexTuning::default()),
+            (
+                "4 shards",
+                ChunkIndexTuning {
+                    shard_amount: 4,
+                    capacity: 0,
+                },
+            ),
+        ] {
+            group.bench_function(format!("{compression:?}, serial, {name}"), |b| {
+                b.iter_batched(
+                    || memory_index(&directory, 64 * 1024, tuning),
+                    |index| index.chunk_file(&path, compression, None, None).unwrap(),
+                    BatchSize::PerIteration,
+                )
+            });
+
+            group.bench_function(format!("{compression:?}, parallel, {name}"), |b| {
+                b.iter_batched(
+                    || memory_index(&directory, 64 * 1024, tuning),
+                    |index| {
+                        rayon::scope(|scope| {
+                            index.chunk_file(&path, compression, Some(scope), None)
+                        })
+                        .unwrap()
+                    },
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+    }
+
+    group.finish();
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+fn save_index(c: &mut Criterion) {
+    let directory = temp_directory("save-index");
+    let path = directory.join("file");
+    std::fs::write(
+        &path,
+        (0..1024 * 1024u32)
+            .flat_map(u32::to_le_bytes)
+            .collect::<Vec<_>>(),
+    )
+    .unwrap();
+
+    // 65536 distinct chunks
+    let index = memory_index(&directory, 64, ChunkIndexTuning::default());
+    index
+        .chunk_file(&path, CompressionFormat::None, None, None)
+        .unwrap();
+
+    let mut group = c.benchmark_group("save_index");
+    group.sample_size(10);
+
+    group.bench_function("unchanged", |b| b.iter(|| index.save(false).unwrap()));
+    group.bench_function("forced", |b| b.iter(|| index.save(true).unwrap()));
+
+    group.finish();
+    drop(index);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+fn write_archive(c: &mut Criterion) {
+    let directory = temp_directory("write-archive");
+    let path = directory.join("archive");
+    let content = text(4096);
+
+    let mut group = c.benchmark_group("write_archive");
+    group.sample_size(10);
+
+    for write_buffer_size in [0, 256 * 1024] {
+        group.bench_function(format!("{write_buffer_size} byte buffer"), |b| {
+            b.iter(|| {
+                let mut archive = Archive::new(std::fs::File::create(&path).unwrap()).unwrap();
+                archive.set_write_buffer_size(write_buffer_size).unwrap();
+
+                for i in 0..2000 {
+                    let entry = archive
+                        .write_file_entry(
+                            &content[..i * 2],
+                            None,
+                            format!("file-{i}"),
+                            0o100644.into(),
+                            SystemTime::UNIX_EPOCH,
+                            (1000, 1000),
+                            CompressionFormat::None,
+                        )
+                        .unwrap();
+                    archive.entries.push(Entry::File(entry));
+                }
+
+                archive.write_end_header().unwrap();
+            })
+        });
+    }
+
+    group.finish();
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+fn read_chunks(c: &mut Criterion) {
+    let directory = temp_directory("read-chunks");
+    let local = ChunkStorageLocal(directory.clone());
+
+    let hashes = (0..64u8).map(|i| [i; 32]).collect::<Vec<_>>();
+    for hash in &hashes {
+        local.write_chunk_bytes(hash, &text(1024 * 1024)).unwrap();
+    }
+
+    let storages: Vec<(&str, Arc<dyn ChunkStorage>)> = vec![
+        ("file", Arc::new(local)),
+        #[cfg(all(unix, feature = "mmap"))]
+        (
+            "mmap",
+            ddup_bak::chunks::storage::storage_from_url(
+                &format!("mmap://{}", directory.display()),
+                &directory,
+            )
+            .unwrap(),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("read_chunks");
+    group.sample_size(10);
+
+    for (name, storage) in storages {
+        group.bench_function(name, |b| {
+            let mut buffer = Vec::with_capacity(1024 * 1024);
+
+            b.iter(|| {
+                for hash in &hashes {
+                    buffer.clear();
+                    storage
+                        .read_chunk_content(hash)
+                        .unwrap()
+                        .read_to_end(&mut buffer)
+                        .unwrap();
+                }
+            })
+        });
+    }
+
+    group.finish();
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+criterion_group!(benches, chunk_file, save_index, write_archive, read_chunks);
+criterion_main!(benches);
@@ -0,0 +1,174 @@
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use ddup_bak::{
+    archive::entries::Entry,
+    chunks::reader::DEFAULT_COPY_BUFFER_SIZE,
+    repository::{CreateOptions, Repository, RestoreOptions},
+};
+use std::path::{Path, PathBuf};
+
+fn temp_directory(name: &str) -> PathBuf {
+    let directory =
+        std::env::temp_dir().join(format!("ddup-bak-bench-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+
+    directory
+}
+
+/// Content that does not compress, so chunks cost what they would for real data.
+fn noise(length: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed | 1;
+
+    (0..length)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// 500 small files spread over nested directories and a 32 MiB file, large enough
+/// to be read ahead in parallel.
+fn source(directory: &Path) -> PathBuf {
+    let source = directory.join("source");
+
+    for i in 0..500 {
+        let directory = source.join(format!("directory-{}/nested-{}", i % 10, i % 7));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join(format!("file-{i}")),
+            noise(512 + i * 16, i as u64),
+        )
+        .unwrap();
+    }
+    std::fs::write(source.join("large"), noise(32 * 1024 * 1024, 0)).unwrap();
+
+    source
+}
+
+fn create_archive(repository: &Repository, source: &Path, threads: usize) {
+    repository
+        .create_archive(
+            "archive",
+            Some(ignore::WalkBuilder::new(source).build()),
+            Some(source),
+            None,
+            None,
+            threads,
+            CreateOptions::default(),
+        )
+        .unwrap();
+}
+
+fn create(c: &mut Criterion) {
+    let directory = temp_directory("create");
+    let source = source(&directory);
+
+    let mut group = c.benchmark_group("create_archive");
+    group.sample_size(10);
+
+    for threads in [1, 4] {
+        group.bench_function(format!("{threads} threads"), |b| {
+            b.iter_batched(
+                || {
+                    let path = directory.join("repository");
+                    let _ = std::fs::remove_dir_all(&path);
+
+                    Repository::new(&path, 1024 * 1024, 0, None).unwrap()
+                },
+                |repository| create_archive(&repository, &source, threads),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    group.finish();
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+fn restore(c: &mut Criterion) {
+    let directory = temp_directory("restore");
+    let source = source(&directory);
+    let repository = Repository::new(&directory.join("repository"), 1024 * 1024, 0, None).unwrap();
+    create_archive(&repository, &source, 4);
+
+    let destination = directory.join("destination");
+
+    let mut group = c.benchmark_group("restore");
+    group.sample_size(10);
+
+    for (name, inline_restore_size) in [("batched", None), ("task per entry", Some(0))] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let _ = std::fs::remove_dir_all(&destination);
+
+                    repository
+                        .get_archive("archive")
+                        .unwrap()
+                        .into_entries()
+                        .unwrap()
+                },
+                |entries| {
+                    repository
+                        .restore_entries_with_options(
+                            "archive",
+                            entries,
+                            None,
+                            4,
+                            RestoreOptions {
+                                destination: Some(destination.clone()),
+                                inline_restore_size,
+                                ..Default::default()
+                            },
+                        )
+                        .unwrap()
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    group.finish();
+    drop(repository);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+fn read_entry(c: &mut Criterion) {
+    let directory = temp_directory("read-entry");
+    let source = source(&directory);
+    let mut repository =
+        Repository::new(&directory.join("repository"), 1024 * 1024, 0, None).unwrap();
+    create_archive(&repository, &source, 4);
+
+    let archive = repository.get_archive("archive").unwrap();
+    let Some(entry @ Entry::File(_)) = archive.find_archive_entry(Path::new("large")).unwrap()
+    else {
+        panic!("expected a file entry");
+    };
+    let entry = entry.clone();
+
+    let mut group = c.benchmark_group("read_entry_content");
+    group.sample_size(10);
+
+    for io_buffer_size in [16 * 1024, DEFAULT_COPY_BUFFER_SIZE, 4 * 1024 * 1024] {
+        repository.set_io_buffer_size(io_buffer_size);
+
+        group.bench_function(format!("{} KiB buffer", io_buffer_size / 1024), |b| {
+            b.iter(|| {
+                repository
+                    .read_entry_content(entry.clone(), &mut std::io::sink())
+                    .unwrap()
+            })
+        });
+    }
+
+    group.finish();
+    drop(repository);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+criterion_group!(benches, create, restore, read_entry);
+criterion_main!(benches);
@@ -6,6 +6,69 @@
 #include <stdint.h>
 #include <stdlib.h>
 
+/**
+ * Incremented with every incompatible change of the functions or types in this header,
+ * compare it with `ddup_bak_abi_version` to detect a mismatched library at runtime.
+ */
+#define DDUP_BAK_ABI_VERSION 3
+
+/**
+ * Keeps the holders of the lock in the lock file, polled by a thread, the default.
+ */
+#define DDUP_LOCK_BACKEND_POLLED 0
+
+/**
+ * Uses the advisory locks of the operating system, which are released when a process
+ * exits but are not supported by every network file system.
+ */
+#define DDUP_LOCK_BACKEND_NATIVE 1
+
+/**
+ * A write lock that modifies the chunk index, e.g. to create or delete archives.
+ */
+#define DDUP_LOCK_DESTRUCTIVE 1
+
+/**
+ * A write lock that leaves the chunk index alone, e.g. to rename archives.
+ */
+#define DDUP_LOCK_NON_DESTRUCTIVE 2
+
+/**
+ * Stop the create at the first path that cannot be read, the default.
+ */
+#define DDUP_CREATE_ABORT_ON_ERROR 0
+
+/**
+ * Leave files and directories that cannot be read out of the archive.
+ */
+#define DDUP_CREATE_SKIP_ERRORS 1
+
+/**
+ * Replace files that already exist at the destination, the default.
+ */
+#define DDUP_RESTORE_OVERWRITE 0
+
+/**
+ * Leave files with the same size and modification time alone, only correcting their
+ * mode and owner.
+ */
+#define DDUP_RESTORE_SKIP_IDENTICAL 1
+
+/**
+ * Leave every file and symlink that already exists alone.
+ */
+#define DDUP_RESTORE_SKIP_EXISTING 2
+
+/**
+ * Only checks that every referenced chunk exists in the index and storage.
+ */
+#define DDUP_VERIFY_QUICK 0
+
+/**
+ * Reads every referenced chunk, checks its hash and the resulting file sizes.
+ */
+#define DDUP_VERIFY_FULL 1
+
 typedef enum CCompressionFormat {
   None = 0,
   Gzip = 1,
@@ -13,23 +76,137 @@ typedef enum CCompressionFormat {
   Brotli = 3,
 } CCompressionFormat;
 
+/**
+ * The codes returned by the functions of this header, 0 or one of the negative
+ * `DDUP_ERR_*` values. Unless documented otherwise, a function returning an error
+ * code also records a message for `last_error_message`.
+ */
+typedef enum CDdupError {
+  DDUP_OK = 0,
+  /**
+   * An argument was NULL, not valid UTF-8 or out of range.
+   */
+  DDUP_ERR_NULL_ARG = -1,
+  /**
+   * The archive, an entry or a chunk does not exist.
+   */
+  DDUP_ERR_NOT_FOUND = -2,
+  /**
+   * Something to be created already exists.
+   */
+  DDUP_ERR_EXISTS = -3,
+  /**
+   * Any other failure of the file system or the chunk storage.
+   */
+  DDUP_ERR_IO = -4,
+  /**
+   * The repository is locked by another process.
+   */
+  DDUP_ERR_LOCK = -5,
+  /**
+   * An archive, the chunk index or a chunk could not be decoded.
+   */
+  DDUP_ERR_CORRUPT = -6,
+  /**
+   * The chunk storage or the platform does not support the operation.
+   */
+  DDUP_ERR_UNSUPPORTED = -7,
+  /**
+   * A callback asked to stop, or the operation was interrupted.
+   */
+  DDUP_ERR_ABORTED = -8,
+  /**
+   * A function requiring exclusive access ran while another call used the handle,
+   * always detected for repository handles and in debug builds for archive handles.
+   */
+  DDUP_ERR_CONCURRENT_USE = -9,
+  /**
+   * A buffer passed by the caller cannot hold the result, the function reports the
+   * size it needs.
+   */
+  DDUP_ERR_BUFFER_TOO_SMALL = -10,
+  /**
+   * The end of a file was reached before the requested number of bytes was read.
+   */
+  DDUP_ERR_EOF = -11,
+} CDdupError;
+
 typedef enum CEntryType {
   File = 0,
   Directory = 1,
   Symlink = 2,
 } CEntryType;
 
-typedef struct Option_ProgressCallbackFn Option_ProgressCallbackFn;
+typedef enum CProgressEventKind {
+  /**
+   * The totals are known, reported once before any other event.
+   */
+  ScanComplete = 0,
+  /**
+   * A file was processed, `path` is set.
+   */
+  FileDone = 1,
+  /**
+   * File content was processed, `current_bytes` grew.
+   */
+  BytesProcessed = 2,
+  /**
+   * A chunk reference of a deleted archive was released.
+   */
+  ChunkReleased = 3,
+} CProgressEventKind;
 
+/**
+ * An archive handle. Functions only reading it, like `archive_entries` or
+ * `archive_find_entry`, may run on several threads at once. Functions changing it,
+ * the `archive_add_*`, `archive_set_*`, `archive_write_file_entry` and
+ * `archive_finalize`, require exclusive access, debug builds fail them with
+ * `DDUP_ERR_CONCURRENT_USE` if two of them overlap.
+ */
 typedef struct CArchive {
   uint8_t _private[0];
 } CArchive;
 
+/**
+ * Receives a path and the `user_data` passed along with the callback. Like for every
+ * callback the `user_data` pointer is managed by the caller and must stay valid until
+ * the call taking the callback returns, it may be called from other threads.
+ */
+typedef void (*CProgressCallback)(const char *path, void *user_data);
+
+/**
+ * An entry copied out of an archive. Every entry handed out by this library is an
+ * independent copy which stays valid after the archive handle is freed, it owns its
+ * strings and, for a directory, its children.
+ */
 typedef struct CEntry {
   enum CEntryType entry_type;
   void *entry;
 } CEntry;
 
+/**
+ * Fills a buffer with up to `length` bytes of file content, returning the number of
+ * bytes written to it, 0 once everything was read or a negative number on failure.
+ */
+typedef intptr_t (*CReadCallback)(uint8_t *buffer, uintptr_t length, void *user_data);
+
+/**
+ * A repository handle, which may be used from several threads at once. Only
+ * `free_repository` requires exclusive access, `repository_set_save_on_drop` and
+ * `repository_set_lock_backend` fail with `DDUP_ERR_CONCURRENT_USE` while another call
+ * uses the handle. `repository_clone_handle` gives every thread its own handle.
+ */
+typedef struct CRepository {
+  uint8_t _private[0];
+} CRepository;
+
+/**
+ * Receives a piece of a stream along with `user_data`. Returns how many bytes of it
+ * were consumed, at least 1, the rest is passed again in the next call. A negative
+ * return aborts the stream.
+ */
+typedef intptr_t (*CStreamCallback)(const uint8_t *data, uintptr_t length, void *user_data);
+
 typedef struct CEntryCommon {
   char *name;
   uint32_t mode;
@@ -45,10 +222,22 @@ typedef struct CFileEntry {
   uint64_t size;
   uint64_t size_real;
   uint64_t size_compressed;
-  void *file;
+  /**
+   * Where the chunk ID list starts in the archive, see `archive_entry_open_reader`.
+   */
   uint64_t offset;
+  bool delta_chunk_ids;
+  /**
+   * Opaque reference to the file in its archive, owned by the entry, which lets
+   * `entry_open_reader` open it without the archive handle.
+   */
+  void *token;
 } CFileEntry;
 
+/**
+ * A directory entry, `entries` are owned by it and freed along with it. To keep a
+ * child after freeing the directory, copy it with `entry_clone`.
+ */
 typedef struct CDirectoryEntry {
   struct CEntryCommon common;
   unsigned int entries_count;
@@ -61,100 +250,924 @@ typedef struct CSymlinkEntry {
   bool target_dir;
 } CSymlinkEntry;
 
+/**
+ * Iterates the archive names of a repository as they were when it was created.
+ */
+typedef struct CArchiveIter {
+  uint8_t _private[0];
+} CArchiveIter;
+
 typedef struct CEntryReader {
   uint8_t _private[0];
 } CEntryReader;
 
-typedef struct CRepository {
+/**
+ * Receives the content of a file in pieces of at most a few KiB, returning
+ * anything but 0 stops reading.
+ */
+typedef int (*CWriteCallback)(const uint8_t *data, uintptr_t length, void *user_data);
+
+/**
+ * Numbers of the chunk index, filled by `repository_chunk_stats`. The caller sets
+ * `struct_size` to `sizeof(CChunkStats)`, fields added later to the end are only
+ * written if they fit into it.
+ */
+typedef struct CChunkStats {
+  uintptr_t struct_size;
+  uint64_t chunk_count;
+  uint64_t total_references;
+  uint64_t deleted_id_count;
+  uint64_t chunk_size;
+  uint64_t max_chunk_count;
+} CChunkStats;
+
+/**
+ * The repository lock taken by `repository_try_lock`, released with `repository_unlock`.
+ * It belongs to the process rather than the handle, so every call on any handle to the
+ * repository reuses it while it is held.
+ */
+typedef struct CRepositoryLock {
   uint8_t _private[0];
-} CRepository;
+} CRepositoryLock;
+
+/**
+ * Who holds the repository lock, filled by `repository_lock_status`.
+ */
+typedef struct CLockStatus {
+  /**
+   * The process holding the write lock, 0 if there is none.
+   */
+  uint64_t writer_pid;
+  /**
+   * One of the `DDUP_LOCK_*` values, 0 if there is no writer.
+   */
+  int writer_mode;
+  uint64_t destructive_readers;
+  uint64_t non_destructive_readers;
+  /**
+   * Seconds since a holder of the lock was last known to be alive, -1 if never.
+   */
+  int64_t heartbeat_age;
+} CLockStatus;
+
+/**
+ * What a clean would delete, filled by `repository_clean_plan`.
+ */
+typedef struct CCleanPlan {
+  uint64_t chunk_count;
+  uint64_t bytes;
+} CCleanPlan;
+
+/**
+ * Receives every dereferenced chunk, whether it was deleted and `user_data`.
+ */
+typedef void (*CDeletionProgressCallback)(uint64_t chunk_id, bool deleted, void *user_data);
+
+/**
+ * What a clean deleted, filled by `repository_clean`.
+ */
+typedef struct CCleanResult {
+  uint64_t chunks_deleted;
+  uint64_t bytes_reclaimed;
+  uint64_t duration_ms;
+} CCleanResult;
+
+/**
+ * A progress event passed to a `CProgressEventCallback`. `path` is NULL unless the
+ * kind is `FileDone`, the event and the string are only valid during the call.
+ *
+ * `current_items` and `total_items` count regular files, or chunk references when
+ * deleting an archive. `current_bytes` and `total_bytes` count the real size of file
+ * content and stay 0 when deleting.
+ */
+typedef struct CProgressEvent {
+  enum CProgressEventKind kind;
+  const char *path;
+  uint64_t current_items;
+  uint64_t total_items;
+  uint64_t current_bytes;
+  uint64_t total_bytes;
+} CProgressEvent;
+
+/**
+ * Receives a progress event and the `user_data` passed along with the callback, it
+ * may be called from other threads.
+ */
+typedef void (*CProgressEventCallback)(const struct CProgressEvent *event, void *user_data);
+
+/**
+ * Chooses the compression of the file at a path, receives `user_data`.
+ */
+typedef enum CCompressionFormat (*CCompressionFormatCallback)(const char *path, void *user_data);
+
+/**
+ * What a `repository_restore_archive_ex` did. `skipped` counts the entries left alone
+ * because of the overwrite policy, `warnings` the messages returned by
+ * `repository_restore_warnings`.
+ */
+typedef struct CRestoreReport {
+  uint64_t files;
+  uint64_t directories;
+  uint64_t symlinks;
+  uint64_t bytes;
+  uint64_t skipped;
+  uint64_t warnings;
+} CRestoreReport;
+
+/**
+ * Receives a piece of chunk content from the `read` function of a `CChunkStorage`,
+ * along with the `sink_data` passed to `read`. Returns 0 to continue.
+ */
+typedef int (*CChunkSink)(const uint8_t *data, uintptr_t length, void *sink_data);
 
-typedef void (*CDeletionProgressCallback)(uint64_t chunk_id, bool deleted);
+/**
+ * Receives one 32 byte chunk hash from the `list` function of a `CChunkStorage`.
+ */
+typedef void (*CChunkHashSink)(const uint8_t *hash, void *sink_data);
 
-typedef void (*CProgressCallback)(const char*);
+/**
+ * A chunk storage implemented by the host. Every `hash` points to 32 bytes.
+ *
+ * All functions return 0 on success, `DDUP_ERR_NOT_FOUND` for a chunk that does not
+ * exist and any other negative value for a failure. They are called concurrently from
+ * the worker threads of the library, so the functions and `ctx` must be thread-safe.
+ *
+ * - `read` passes the whole content of a chunk to `sink` in one or more pieces, the
+ *   library buffers it until `read` returns.
+ * - `write` receives the whole content of a chunk at once. Chunks are content-addressed,
+ *   writing a chunk that already exists must succeed without changing it.
+ * - `remove` deletes a chunk.
+ * - `exists` returns 1 if the chunk exists and 0 if it does not.
+ * - `size` is optional, without it the size of a chunk is determined by reading it.
+ * - `list` is optional and passes every stored hash to `sink`, it is needed to rebuild
+ *   or verify the repository.
+ * - `flush` is optional and makes every written chunk durable, see `repository_flush`.
+ * - `release` is optional and called with `ctx` once the repository is freed.
+ *
+ * The struct itself is copied, only `ctx` has to stay valid until `release` is called.
+ */
+typedef struct CChunkStorage {
+  void *ctx;
+  int (*read)(void *ctx, const uint8_t *hash, CChunkSink sink, void *sink_data);
+  int (*write)(void *ctx, const uint8_t *hash, const uint8_t *data, uintptr_t length);
+  int (*remove)(void *ctx, const uint8_t *hash);
+  int (*exists)(void *ctx, const uint8_t *hash);
+  int (*size)(void *ctx, const uint8_t *hash, uint64_t *size);
+  int (*list)(void *ctx, CChunkHashSink sink, void *sink_data);
+  int (*flush)(void *ctx);
+  void (*release)(void *ctx);
+} CChunkStorage;
 
-typedef enum CCompressionFormat (*CCompressionFormatCallback)(const char*);
+/**
+ * Receives the path of a checked file, whether it was found without problems and `user_data`.
+ */
+typedef void (*CVerifyProgressCallback)(const char *path, bool ok, void *user_data);
 
+/**
+ * Totals of a verify, filled by `repository_verify_archive`.
+ */
+typedef struct CVerifySummary {
+  uint64_t entries_checked;
+  uint64_t chunks_checked;
+  uint64_t missing_chunks;
+  uint64_t corrupt_chunks;
+} CVerifySummary;
+
+/**
+ * Returns the error message of the last failed call on this thread, or NULL if
+ * there is none. Only functions documented to set it do so. The string is owned
+ * by the library and stays valid until the next failing call on the same thread,
+ * it must not be freed.
+ */
+const char *last_error_message(void);
+
+/**
+ * Returns the version of this library, the string is static and must not be freed.
+ */
+const char *ddup_bak_version(void);
+
+/**
+ * Returns the `DDUP_BAK_ABI_VERSION` this library was built with.
+ */
+unsigned int ddup_bak_abi_version(void);
+
+/**
+ * Returns whether this library was built with the optional feature `name`, one of
+ * `brotli`, `xz`, `zstd`, `mmap`, `s3` and `mount`. `zstd`, `s3` and `mount` are not
+ * offered by this version yet and always return false, like unknown names and NULL.
+ */
+bool ddup_bak_has_feature(const char *name);
+
+/**
+ * Frees a string returned by this library, NULL is ignored.
+ */
 void free_string(char *ptr);
 
+/**
+ * Frees a NULL-terminated string array, like the one returned by `repository_list_archives`.
+ */
 void free_string_array(char **ptr);
 
+/**
+ * Creates an archive file at `path`, which on unix may be any byte string and on
+ * windows has to be UTF-8, see `new_archive_w`. Returns NULL on failure, see
+ * `last_error_message`.
+ */
 struct CArchive *new_archive(const char *path);
 
+/**
+ * Opens the archive file at `path`, taken like by `new_archive`. Returns NULL on
+ * failure, see `last_error_message`.
+ */
 struct CArchive *open_archive(const char *path);
 
+/**
+ * Frees an archive handle, NULL is ignored. This does NOT finalize the archive, call `archive_finalize`
+ * after adding entries or the archive cannot be opened again.
+ */
 void free_archive(struct CArchive *archive);
 
+/**
+ * Adds everything in the directory at `path` to the archive and finalizes it,
+ * `progress_callback` may be NULL. Returns 0, `DDUP_ERR_NULL_ARG` for NULL
+ * arguments, `DDUP_ERR_CONCURRENT_USE` or another negative `DDUP_ERR_*` code if
+ * reading the directory or writing the archive failed, see `last_error_message`.
+ */
 int archive_add_directory(struct CArchive *archive,
                           const char *path,
-                          struct Option_ProgressCallbackFn progress_callback);
+                          CProgressCallback progress_callback,
+                          void *user_data);
 
+/**
+ * Sets the callback choosing the compression of every file added by
+ * `archive_add_directory`, NULL restores the default. Returns the archive, or NULL
+ * with the last error set if it is NULL or used by another thread.
+ */
 struct CArchive *archive_set_compression_callback(struct CArchive *archive,
                                                   enum CCompressionFormat (*callback)(const char *path,
-                                                                                      uint64_t size));
+                                                                                      uint64_t size,
+                                                                                      void *user_data),
+                                                  void *user_data);
 
+/**
+ * Sets the callback reporting the real size of every file added by
+ * `archive_add_directory`, NULL restores the default. Returns the archive, or NULL
+ * with the last error set if it is NULL or used by another thread.
+ */
 struct CArchive *archive_set_real_size_callback(struct CArchive *archive,
-                                                uint64_t (*callback)(const char *path));
+                                                uint64_t (*callback)(const char *path,
+                                                                     void *user_data),
+                                                void *user_data);
 
+/**
+ * Returns the number of top level entries, 0 for a NULL archive.
+ */
 unsigned int archive_entries_count(const struct CArchive *archive);
 
+/**
+ * Deprecated, use `archive_entries2` which also reports the length. Returns the top
+ * level entries as a NULL-terminated array, freed with `free_entry_array`.
+ */
 const struct CEntry **archive_entries(const struct CArchive *archive);
 
+/**
+ * Returns the top level entries as a NULL-terminated array and stores their number in
+ * `out_count`, which may be NULL. The array and every entry in it are owned by the
+ * caller and freed at once with `free_entry_array`, entries must not be freed on their
+ * own with `free_entry`. Returns NULL for a NULL archive.
+ */
+struct CEntry **archive_entries2(const struct CArchive *archive, unsigned int *out_count);
+
+/**
+ * Returns a deep copy of the entry at `path` in the archive, including everything below
+ * a directory, or NULL with the last error set if an argument is NULL or there is no
+ * such entry. The copy is owned by the caller, freed with `free_entry` and stays valid
+ * after the archive is freed.
+ */
 struct CEntry *archive_find_entry(const struct CArchive *archive, const char *path);
 
+/**
+ * Writes a file entry at `path` whose content is pulled from `read_callback`, called
+ * with `user_data` on the calling thread until it returns 0. Parent directories must
+ * already exist, see `archive_add_empty_directory`. `mtime` is in seconds since the
+ * unix epoch.
+ *
+ * The archive must be finalized after adding entries, until then it cannot be opened.
+ * Returns a copy of the new entry to free with `free_entry`, or NULL with the last
+ * error set, see `last_error_message`.
+ */
+struct CEntry *archive_write_file_entry(struct CArchive *archive,
+                                        const char *path,
+                                        uint32_t mode,
+                                        uint64_t mtime,
+                                        uint32_t uid,
+                                        uint32_t gid,
+                                        enum CCompressionFormat compression,
+                                        CReadCallback read_callback,
+                                        void *user_data);
+
+/**
+ * Adds a symlink entry at `path` pointing to `target`, `target_dir` marks targets that
+ * are directories, which matters when restoring on windows. Returns 0,
+ * `DDUP_ERR_EXISTS` if an entry exists at `path`, `DDUP_ERR_NULL_ARG` for
+ * NULL arguments or a parent that is not a directory or `DDUP_ERR_CONCURRENT_USE`,
+ * see `archive_write_file_entry` for the other arguments.
+ */
+int archive_add_symlink(struct CArchive *archive,
+                        const char *path,
+                        const char *target,
+                        bool target_dir,
+                        uint32_t mode,
+                        uint64_t mtime,
+                        uint32_t uid,
+                        uint32_t gid);
+
+/**
+ * Adds an empty directory entry at `path`, entries can be added to it afterwards.
+ * Returns 0 or a negative `DDUP_ERR_*` code like `archive_add_symlink`, see
+ * `archive_write_file_entry` for the other arguments.
+ */
+int archive_add_empty_directory(struct CArchive *archive,
+                                const char *path,
+                                uint32_t mode,
+                                uint64_t mtime,
+                                uint32_t uid,
+                                uint32_t gid);
+
+/**
+ * Writes the end header describing all entries, which makes the archive readable by
+ * `open_archive`. Entries may still be added afterwards, the archive then has to be
+ * finalized again. Returns 0, `DDUP_ERR_NULL_ARG` for a NULL archive,
+ * `DDUP_ERR_CONCURRENT_USE` or `DDUP_ERR_IO` if writing failed, see
+ * `last_error_message`.
+ */
+int archive_finalize(struct CArchive *archive);
+
+/**
+ * Returns whether every entry added to the archive is written to its end header,
+ * false for a NULL archive.
+ */
+bool archive_is_finalized(const struct CArchive *archive);
+
+/**
+ * Streams an archive as a tar file, compressed with gzip if `gzip` is set, through
+ * `write_callback` on the calling thread. Returns 0, `DDUP_ERR_ABORTED` if the
+ * callback aborted or another negative `DDUP_ERR_*` code, see `last_error_message`.
+ */
+int repository_convert_to_tar(struct CRepository *repo,
+                              const char *archive_name,
+                              bool gzip,
+                              CStreamCallback write_callback,
+                              void *user_data);
+
+/**
+ * Returns the type of an entry, `File` for a NULL entry.
+ */
 enum CEntryType get_entry_type(const struct CEntry *entry);
 
+/**
+ * Returns the fields every entry type has, owned by the entry, or NULL for a NULL entry.
+ */
 const struct CEntryCommon *entry_get_common(const struct CEntry *entry);
 
+/**
+ * Returns the name of an entry, owned by the entry, or NULL for a NULL entry.
+ */
 const char *entry_name(const struct CEntry *entry);
 
+/**
+ * Frees an entry along with everything below it, NULL is ignored. Entries of an
+ * array returned by `archive_entries2` are freed with `free_entry_array` instead.
+ */
 void free_entry(struct CEntry *entry);
 
+/**
+ * Frees an array returned by `archive_entries2` along with every entry in it. `count`
+ * is the number reported for the array, which entries are freed is decided by its
+ * NULL terminator.
+ */
+void free_entry_array(struct CEntry **entries, unsigned int count);
+
+/**
+ * Returns a deep copy of an entry, including everything below a directory, which is
+ * freed with `free_entry` independently of the original. Returns NULL for a NULL entry.
+ */
+struct CEntry *entry_clone(const struct CEntry *entry);
+
+/**
+ * Returns the file fields of an entry, or NULL if it is NULL or not a file.
+ */
 const struct CFileEntry *entry_as_file(const struct CEntry *entry);
 
+/**
+ * Returns the directory fields of an entry, or NULL if it is NULL or not a directory.
+ */
 const struct CDirectoryEntry *entry_as_directory(const struct CEntry *entry);
 
+/**
+ * Returns the symlink fields of an entry, or NULL if it is NULL or not a symlink.
+ */
 const struct CSymlinkEntry *entry_as_symlink(const struct CEntry *entry);
 
-struct CEntryReader *repository_create_entry_reader(struct CRepository *repo,
-                                                    const struct CFileEntry *entry);
+/**
+ * Starts iterating the archive names of a repository, an alternative to
+ * `repository_list_archives` that copies every name into a buffer of the caller.
+ * Returns NULL on failure, see `last_error_message`. The iterator is freed with
+ * `repository_archive_iter_free` and does not borrow the repository.
+ */
+struct CArchiveIter *repository_archive_iter_new(struct CRepository *repo);
+
+/**
+ * Copies the next archive name into `buffer` with a terminating NUL and returns its
+ * length without the NUL, or 0 once every name was returned.
+ *
+ * If the name and its NUL do not fit into `buffer_length` bytes, nothing is copied,
+ * the iterator stays at the name and `DDUP_ERR_BUFFER_TOO_SMALL` is returned with
+ * the required length including the NUL stored in `out_required` unless it is NULL.
+ * Other negative `DDUP_ERR_*` codes are returned for invalid arguments, see
+ * `last_error_message`.
+ */
+int repository_archive_iter_next(struct CArchiveIter *iter,
+                                 char *buffer,
+                                 uintptr_t buffer_length,
+                                 uintptr_t *out_required);
+
+/**
+ * Frees an archive name iterator, NULL is ignored.
+ */
+void repository_archive_iter_free(struct CArchiveIter *iter);
 
+/**
+ * Opens a reader for a file entry returned by the functions of `archive`, like
+ * `archive_entries`. The entry is looked up in the archive by its name and offset,
+ * so it must come from the same archive. Returns NULL on failure, see
+ * `last_error_message`. The reader is freed with `free_entry_reader`.
+ */
+struct CEntryReader *archive_entry_open_reader(struct CRepository *repo,
+                                               const struct CArchive *archive,
+                                               const struct CEntry *entry);
+
+/**
+ * Opens a reader for a file entry returned by this library, using the reference to its
+ * archive stored in the entry, so the archive handle may already be freed. Returns
+ * NULL on failure, see `last_error_message`. The reader is freed with
+ * `free_entry_reader`.
+ */
+struct CEntryReader *entry_open_reader(struct CRepository *repo, const struct CEntry *entry);
+
+/**
+ * Opens a reader for the file at `path` in `archive`. Returns NULL on failure, see
+ * `last_error_message`. The reader is freed with `free_entry_reader`.
+ */
+struct CEntryReader *archive_open_entry_by_path(struct CRepository *repo,
+                                                const struct CArchive *archive,
+                                                const char *path);
+
+/**
+ * Reads up to `buffer_size` bytes, at most `INT_MAX`, into `buffer` and returns how
+ * many were read, 0 at the end of the file. Returns `DDUP_ERR_NULL_ARG` for
+ * NULL arguments, `DDUP_ERR_NOT_FOUND` for a missing chunk, `DDUP_ERR_CORRUPT` for
+ * one that cannot be decoded or another negative `DDUP_ERR_*` code, see
+ * `last_error_message`.
+ */
 int entry_reader_read(struct CEntryReader *reader, char *buffer, uintptr_t buffer_size);
 
+/**
+ * Reads exactly `buffer_size` bytes into `buffer`, looping over short reads. Returns 0,
+ * `DDUP_ERR_EOF` if the file ended first, in which case the bytes read so far are in
+ * `buffer` and the reader is at the end, or a negative `DDUP_ERR_*` code like
+ * `entry_reader_read`.
+ */
+int entry_reader_read_exact(struct CEntryReader *reader, char *buffer, uintptr_t buffer_size);
+
+/**
+ * Streams the rest of the file to `write_callback`, which is called with `user_data`
+ * on the calling thread until the end of the file or until it returns nonzero.
+ *
+ * Returns the number of bytes passed to the callback, `DDUP_ERR_ABORTED` if it
+ * stopped reading or another negative `DDUP_ERR_*` code like `entry_reader_read`.
+ */
+int64_t entry_reader_read_all(struct CEntryReader *reader,
+                              CWriteCallback write_callback,
+                              void *user_data);
+
+/**
+ * Moves the reader to `offset` bytes from the start of the file, offsets past the
+ * end move it to the end. Seeking backward starts over from the beginning, forward
+ * seeks only read the chunk the new position falls into.
+ *
+ * Returns 0 or a negative `DDUP_ERR_*` code like `entry_reader_read`.
+ */
+int entry_reader_seek(struct CEntryReader *reader, uint64_t offset);
+
+/**
+ * Skips up to `count` bytes, returning the number of bytes skipped, which is only
+ * less than `count` at the end of the file, or a negative `DDUP_ERR_*` code like
+ * `entry_reader_read`.
+ */
+int64_t entry_reader_skip(struct CEntryReader *reader, uint64_t count);
+
+/**
+ * Returns the number of bytes read or skipped so far, 0 for a NULL reader.
+ */
+uint64_t entry_reader_position(const struct CEntryReader *reader);
+
+/**
+ * Returns the number of bytes left until the end of the file, 0 for a NULL reader.
+ */
+uint64_t entry_reader_remaining(const struct CEntryReader *reader);
+
+/**
+ * Frees an entry reader, NULL is ignored.
+ */
 void free_entry_reader(struct CEntryReader *reader);
 
+/**
+ * Streams the content of the file at `path` in an archive to `write_callback`,
+ * which is called with `user_data` on the calling thread until the whole file
+ * was passed or it returns nonzero.
+ *
+ * Returns 0 on success, `DDUP_ERR_ABORTED` if the callback stopped reading,
+ * `DDUP_ERR_NULL_ARG` for NULL arguments or a path that is not a file,
+ * `DDUP_ERR_NOT_FOUND` if the archive or path does not exist or another negative
+ * code like `entry_reader_read`, see `last_error_message`.
+ */
+int repository_read_entry(struct CRepository *repo,
+                          const char *archive_name,
+                          const char *path,
+                          CWriteCallback write_callback,
+                          void *user_data);
+
+/**
+ * Writes the content of the file at `path` in an archive to a new file at
+ * `destination`, replacing an existing one. Nothing is left at `destination`
+ * on failure. Returns 0 or a negative `DDUP_ERR_*` code like `repository_read_entry`.
+ */
+int repository_read_entry_to_file(struct CRepository *repo,
+                                  const char *archive_name,
+                                  const char *path,
+                                  const char *destination);
+
+/**
+ * Creates a repository in `directory`, which on unix may be any byte string and on
+ * windows has to be UTF-8, see `new_repository_w`. Returns NULL on failure, see
+ * `last_error_message`.
+ */
 struct CRepository *new_repository(const char *directory,
                                    unsigned int chunk_size,
                                    unsigned int max_chunk_count);
 
+/**
+ * Opens the repository in `directory` with the lock backend stored by
+ * `repository_set_lock_backend`, `chunks_directory` may be NULL. Paths are taken like by
+ * `new_repository`. Returns NULL on failure, see `last_error_message`.
+ */
 struct CRepository *open_repository(const char *directory, const char *chunks_directory);
 
+/**
+ * Frees a repository handle, saving the chunk index if it changed unless disabled with
+ * `repository_set_save_on_drop`. NULL is ignored.
+ */
 void free_repository(struct CRepository *repo);
 
+/**
+ * Saves the chunk index if it changed since it was loaded or last saved, see
+ * `repository_flush` to also flush the chunk storage.
+ * Returns 0, `DDUP_ERR_NULL_ARG` for a NULL repository or `DDUP_ERR_IO`,
+ * see `last_error_message`.
+ */
 int repository_save(struct CRepository *repo);
 
+/**
+ * Saves the chunk index and flushes the chunk storage, so everything written so far
+ * survives a crash. Returns 0, `DDUP_ERR_NULL_ARG` for a NULL repository or
+ * `DDUP_ERR_IO` if saving or flushing failed, see `last_error_message`.
+ */
+int repository_flush(struct CRepository *repo);
+
+/**
+ * Sets whether `free_repository` saves the chunk index, which it does by default.
+ * Returns the repository, or NULL with the last error set if it is NULL or used by
+ * another thread.
+ */
 struct CRepository *repository_set_save_on_drop(struct CRepository *repo, bool save_on_drop);
 
-int repository_clean(struct CRepository *repo, CDeletionProgressCallback progress_callback);
+/**
+ * Switches the repository lock to `backend`, one of the `DDUP_LOCK_BACKEND_*` values,
+ * and stores it in the repository, so `open_repository` uses it from then on. Every
+ * process using the repository has to use the same backend, so it is set right after
+ * creating the repository, before other handles are cloned from this one. Returns 0 or a
+ * `DDUP_ERR_*` code, `DDUP_ERR_CONCURRENT_USE` if the handle is used by another thread.
+ */
+int repository_set_lock_backend(struct CRepository *repo, int backend);
+
+/**
+ * Returns a new handle to the same repository, sharing its chunk index and lock, so
+ * every thread can hold its own. Each handle is freed with `free_repository`, and
+ * saves the chunk index on free unless `repository_set_save_on_drop` disabled it.
+ */
+struct CRepository *repository_clone_handle(const struct CRepository *repo);
+
+/**
+ * Fills `out` with the numbers of the chunk index, which are kept in memory so this
+ * is cheap. Returns 0 or `DDUP_ERR_NULL_ARG` for NULL arguments or an unset
+ * `struct_size`, see `last_error_message`.
+ */
+int repository_chunk_stats(struct CRepository *repo, struct CChunkStats *out);
+
+/**
+ * Takes the repository lock in `mode`, one of the `DDUP_LOCK_*` values, without
+ * waiting for it. Returns 0 and stores the lock in `out_lock`, or `DDUP_ERR_LOCK`
+ * if another process holds it, e.g. to skip a backup while another one is running.
+ */
+int repository_try_lock(struct CRepository *repo, int mode, struct CRepositoryLock **out_lock);
+
+/**
+ * Releases and frees a lock returned by `repository_try_lock`, NULL is ignored.
+ * Returns 0, or `DDUP_ERR_IO` if the lock file could not be updated, the lock is
+ * freed either way.
+ */
+int repository_unlock(struct CRepositoryLock *lock);
 
+/**
+ * Fills `status` with the holders of the repository lock as found in the lock file,
+ * for diagnostics. Returns 0 or a `DDUP_ERR_*` code.
+ */
+int repository_lock_status(struct CRepository *repo, struct CLockStatus *status);
+
+/**
+ * Returns the reference count of a chunk ID, or -1 with the last error set if the
+ * chunk is not part of the index.
+ */
+int64_t repository_chunk_references(struct CRepository *repo, uint64_t chunk_id);
+
+/**
+ * Fills `out_plan` with the unreferenced chunks a clean would delete and the bytes it
+ * would reclaim, without deleting anything. Returns 0, `DDUP_ERR_NULL_ARG`
+ * for NULL arguments or `DDUP_ERR_IO` if the chunk storage failed, see
+ * `last_error_message`.
+ */
+int repository_clean_plan(struct CRepository *repo, struct CCleanPlan *out_plan);
+
+/**
+ * Deletes all unreferenced chunks, `out_result` may be NULL. Returns 0,
+ * `DDUP_ERR_NULL_ARG` for a NULL repository, `DDUP_ERR_LOCK` or
+ * `DDUP_ERR_IO` if the chunk storage failed, see `last_error_message`.
+ */
+int repository_clean(struct CRepository *repo,
+                     CDeletionProgressCallback progress_callback,
+                     void *user_data,
+                     struct CCleanResult *out_result);
+
+/**
+ * Creates an archive of `directory`, or of the repository directory if it is NULL.
+ *
+ * Paths matching one of the `exclude_count` glob patterns in `excludes` are left out,
+ * a matching directory with everything below it. With `DDUP_CREATE_SKIP_ERRORS` as
+ * `error_policy` files and directories that cannot be read are left out as well,
+ * with `DDUP_CREATE_ABORT_ON_ERROR` they fail the create.
+ *
+ * If `progress_callback` is set, the directory is walked once before chunking to
+ * report the totals as `ScanComplete`.
+ *
+ * If `out_skipped` is not NULL it receives a NULL-terminated array of the paths that
+ * were left out, relative to the directory, which is freed with `free_string_array`.
+ * Returns NULL on failure, see `last_error_message`.
+ */
+struct CArchive *repository_create_archive_ex(struct CRepository *repo,
+                                              const char *name,
+                                              const char *directory,
+                                              const char *const *excludes,
+                                              uintptr_t exclude_count,
+                                              CProgressEventCallback progress_callback,
+                                              void *progress_user_data,
+                                              CCompressionFormatCallback compression_callback,
+                                              void *compression_user_data,
+                                              int error_policy,
+                                              unsigned int threads,
+                                              char ***out_skipped);
+
+/**
+ * Creates an archive of `directory` without excludes, reporting only the path of
+ * every file, see `repository_create_archive_ex`.
+ */
 struct CArchive *repository_create_archive(struct CRepository *repo,
                                            const char *name,
                                            const char *directory,
                                            CProgressCallback progress_chunking,
                                            CCompressionFormatCallback compression_callback,
+                                           void *user_data,
                                            unsigned int threads);
 
+/**
+ * Returns the archive names as a NULL-terminated array freed with `free_string_array`
+ * and stores their number in `count`, see `repository_archive_iter_new` for a way
+ * without allocating every name. Returns NULL with `count` set to 0 on failure, see
+ * `last_error_message`.
+ */
 char **repository_list_archives(struct CRepository *repo, unsigned int *count);
 
+/**
+ * Opens an archive of the repository, freed with `free_archive`. Returns NULL on
+ * failure, see `last_error_message`.
+ */
 struct CArchive *repository_get_archive(struct CRepository *repo, const char *archive_name);
 
+/**
+ * Restores an archive into the default restore directory of the repository and
+ * returns that directory, freed with `free_string`, or NULL on failure, see
+ * `last_error_message`. On windows
+ * it is only exact if it is valid UTF-8, see `repository_restore_archive_w`.
+ */
 char *repository_restore_archive(struct CRepository *repo,
                                  const char *archive_name,
                                  CProgressCallback progress_callback,
+                                 void *user_data,
                                  unsigned int threads);
 
+/**
+ * Deletes an archive, `progress_callback` may be NULL and receives every chunk ID
+ * whose reference was released. Returns 0, `DDUP_ERR_NULL_ARG` for NULL
+ * arguments, `DDUP_ERR_NOT_FOUND` if the archive does not exist or another negative
+ * `DDUP_ERR_*` code, see `last_error_message`.
+ */
 int repository_delete_archive(struct CRepository *repo,
                               const char *archive_name,
-                              CDeletionProgressCallback progress_callback);
+                              CDeletionProgressCallback progress_callback,
+                              void *user_data);
+
+/**
+ * Restores an archive into `destination`, or into the default restore directory of
+ * the repository if it is NULL. `overwrite_policy` is one of the `DDUP_RESTORE_*`
+ * values. `progress_callback` may be NULL, it is called from the restore threads with
+ * `user_data` and first receives the totals of the archive as `ScanComplete`.
+ *
+ * Returns 0 and fills `out_report` unless it is NULL, `DDUP_ERR_NULL_ARG`
+ * for NULL arguments or an unknown policy, `DDUP_ERR_NOT_FOUND` if the archive does
+ * not exist, `DDUP_ERR_CORRUPT` or `DDUP_ERR_IO` if reading a chunk or writing a
+ * file failed, see `last_error_message`.
+ */
+int repository_restore_archive_ex(struct CRepository *repo,
+                                  const char *archive_name,
+                                  const char *destination,
+                                  int overwrite_policy,
+                                  CProgressEventCallback progress_callback,
+                                  void *user_data,
+                                  unsigned int threads,
+                                  struct CRestoreReport *out_report);
+
+/**
+ * Returns the warnings of the last `repository_restore_archive_ex` on this handle, like
+ * ownership or permissions that could not be applied, as a NULL-terminated array freed
+ * with `free_string_array`. Their number is stored in `out_count` unless it is NULL.
+ */
+char **repository_restore_warnings(struct CRepository *repo, unsigned int *out_count);
+
+/**
+ * Deletes an archive and releases its chunk references. `progress_callback` may be
+ * NULL, it first receives the number of chunk references of the archive as
+ * `ScanComplete` and then `ChunkReleased` for each of them. Returns 0 or a negative
+ * `DDUP_ERR_*` code like `repository_delete_archive`.
+ */
+int repository_delete_archive_ex(struct CRepository *repo,
+                                 const char *archive_name,
+                                 CProgressEventCallback progress_callback,
+                                 void *user_data);
+
+/**
+ * Restores the entries at `paths` of an archive into `destination`, keeping their
+ * parent directories. `paths` is an array of `count` UTF-8 strings relative to the
+ * archive root, it and the strings stay owned by the caller. `progress_callback`
+ * may be NULL, it is called from the restore threads with `user_data`.
+ *
+ * Returns 0 on success or a negative `DDUP_ERR_*` code like
+ * `repository_restore_archive_ex`, in which case `last_error_message` describes the
+ * failure. Nothing is restored and `DDUP_ERR_NOT_FOUND` is returned if a path does
+ * not exist in the archive.
+ */
+int repository_restore_paths(struct CRepository *repo,
+                             const char *archive_name,
+                             const char *const *paths,
+                             uintptr_t count,
+                             const char *destination,
+                             CProgressCallback progress_callback,
+                             void *user_data,
+                             unsigned int threads);
+
+/**
+ * Restores a whole archive into `destination` instead of the repository's
+ * `archives-restored` directory, see `repository_restore_paths` for the arguments
+ * and return codes.
+ */
+int repository_restore_archive_to(struct CRepository *repo,
+                                  const char *archive_name,
+                                  const char *destination,
+                                  CProgressCallback progress_callback,
+                                  void *user_data,
+                                  unsigned int threads);
+
+/**
+ * Opens a repository whose chunks are kept in a storage implemented by the host,
+ * `chunks_directory` may be NULL. Returns NULL on failure, see `last_error_message`.
+ * Once the storage was accepted, its `release` is also called if opening fails.
+ */
+struct CRepository *open_repository_with_storage(const char *directory,
+                                                 const char *chunks_directory,
+                                                 const struct CChunkStorage *storage);
+
+/**
+ * Creates a repository whose chunks are kept in a storage implemented by the host.
+ * Returns NULL on failure, see `last_error_message`. `release` of the storage is also
+ * called if creating fails, unless the storage itself was rejected.
+ */
+struct CRepository *new_repository_with_storage(const char *directory,
+                                                unsigned int chunk_size,
+                                                unsigned int max_chunk_count,
+                                                const struct CChunkStorage *storage);
+
+/**
+ * Verifies the archive `name` at `level`, `DDUP_VERIFY_QUICK` or `DDUP_VERIFY_FULL`.
+ * `progress_callback` is called from the worker threads after each file was checked.
+ *
+ * Returns 0 if the archive is intact and 1 if problems were found, in both cases
+ * `out_summary` is filled unless it is NULL and the problems can be fetched with
+ * `repository_verify_problems`. Returns a negative `DDUP_ERR_*` code if the verify
+ * itself failed, see `last_error_message`.
+ */
+int repository_verify_archive(struct CRepository *repo,
+                              const char *name,
+                              int level,
+                              CVerifyProgressCallback progress_callback,
+                              void *user_data,
+                              unsigned int threads,
+                              struct CVerifySummary *out_summary);
+
+/**
+ * Returns the problems found by the last `repository_verify_archive` on this handle as
+ * a NULL-terminated array of `<path>: [chunk <id>: ]<reason>` lines, freed with
+ * `free_string_array`. Their number is stored in `out_count` unless it is NULL.
+ */
+char **repository_verify_problems(struct CRepository *repo, unsigned int *out_count);
+
+#if defined(_WIN32)
+/**
+ * Frees a string returned by one of the `_w` functions.
+ */
+void free_wide_string(uint16_t *ptr);
+#endif
+
+#if defined(_WIN32)
+/**
+ * Like `new_repository`, with `directory` as UTF-16.
+ */
+struct CRepository *new_repository_w(const uint16_t *directory,
+                                     unsigned int chunk_size,
+                                     unsigned int max_chunk_count);
+#endif
+
+#if defined(_WIN32)
+/**
+ * Like `open_repository`, with both directories as UTF-16.
+ */
+struct CRepository *open_repository_w(const uint16_t *directory, const uint16_t *chunks_directory);
+#endif
+
+#if defined(_WIN32)
+/**
+ * Like `new_archive`, with `path` as UTF-16.
+ */
+struct CArchive *new_archive_w(const uint16_t *path);
+#endif
+
+#if defined(_WIN32)
+/**
+ * Like `open_archive`, with `path` as UTF-16.
+ */
+struct CArchive *open_archive_w(const uint16_t *path);
+#endif
+
+#if defined(_WIN32)
+/**
+ * Like `repository_restore_archive`, returning the directory as UTF-16 which is freed
+ * with `free_wide_string`.
+ */
+uint16_t *repository_restore_archive_w(struct CRepository *repo,
+                                       const char *archive_name,
+                                       CProgressCallback progress_callback,
+                                       void *user_data,
+                                       unsigned int threads);
+#endif
+
+#if defined(_WIN32)
+/**
+ * Like `repository_restore_archive_to`, with `destination` as UTF-16.
+ */
+int repository_restore_archive_to_w(struct CRepository *repo,
+                                    const char *archive_name,
+                                    const uint16_t *destination,
+                                    CProgressCallback progress_callback,
+                                    void *user_data,
+                                    unsigned int threads);
+#endif
 
 #endif /* LIB_DDUPBAK_H */
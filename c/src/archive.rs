@@ -1,10 +1,23 @@
 use crate::entries::CEntry;
-use ddup_bak::archive::{Archive, CompressionFormat, ProgressCallback};
+use crate::repository::{wrap_progress_callback, CProgressCallback};
+use crate::{
+    error_code, null_terminated, path_argument, set_last_error, utf8_argument, CDdupError,
+    UseGuard, DDUP_ERR_NULL_ARG,
+};
+use ddup_bak::archive::entries::{DirectoryEntry, Entry, EntryMode, SymlinkEntry};
+use ddup_bak::archive::{Archive, CompressionFormat};
 use std::ffi::*;
+use std::io::Read;
 use std::ops::{Deref, DerefMut};
 use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
+/// An archive handle. Functions only reading it, like `archive_entries` or
+/// `archive_find_entry`, may run on several threads at once. Functions changing it,
+/// the `archive_add_*`, `archive_set_*`, `archive_write_file_entry` and
+/// `archive_finalize`, require exclusive access, debug builds fail them with
+/// `DDUP_ERR_CONCURRENT_USE` if two of them overlap.
 #[repr(C)]
 pub struct CArchive {
     _private: [u8; 0],
@@ -12,6 +25,9 @@ pub struct CArchive {
 
 pub struct ArchiveHandle {
     inner: Box<Archive>,
+    /// Whether the end header describes every entry, only then the archive can be opened.
+    finalized: bool,
+    guard: UseGuard,
 }
 
 impl Deref for ArchiveHandle {
@@ -46,6 +62,8 @@ impl CArchive {
     pub fn from_archive(archive: Archive) -> *mut CArchive {
         let handle = Box::new(ArchiveHandle {
             inner: Box::new(archive),
+            finalized: true,
+            guard: UseGuard::default(),
         });
         Box::into_raw(handle) as *mut CArchive
     }
@@ -98,50 +116,58 @@ impl From<CompressionFormat> for CCompressionFormat {
     }
 }
 
-pub type ProgressCallbackFn = extern "C" fn(path: *const c_char);
+/// Creates an archive file, shared by the byte and wide variants of the functions.
+pub(crate) fn new_archive_at(path: &Path) -> *mut CArchive {
+    let archive = match std::fs::File::create(path).and_then(Archive::new) {
+        Ok(archive) => archive,
+        Err(err) => {
+            set_last_error(err);
+            return std::ptr::null_mut();
+        }
+    };
 
-fn build_progress_callback(callback: Option<ProgressCallbackFn>) -> ProgressCallback {
-    if let Some(callback_fn) = callback {
-        Some(Arc::new(move |path: &Path| {
-            if let Some(path_str) = path.to_str() {
-                let c_path = CString::new(path_str).unwrap();
-                callback_fn(c_path.as_ptr());
-            }
-        }))
-    } else {
-        None
+    let archive = CArchive::from_archive(archive);
+    unsafe { CArchive::as_handle_mut(archive).finalized = false };
+
+    archive
+}
+
+/// Opens an archive file, shared by the byte and wide variants of the functions.
+pub(crate) fn open_archive_at(path: &Path) -> *mut CArchive {
+    match Archive::open(path) {
+        Ok(archive) => CArchive::from_archive(archive),
+        Err(err) => {
+            set_last_error(err);
+            std::ptr::null_mut()
+        }
     }
 }
 
+/// Creates an archive file at `path`, which on unix may be any byte string and on
+/// windows has to be UTF-8, see `new_archive_w`. Returns NULL on failure, see
+/// `last_error_message`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn new_archive(path: *const c_char) -> *mut CArchive {
-    let path = unsafe { CStr::from_ptr(path).to_string_lossy().into_owned() };
-
-    let file = match std::fs::File::create(&path) {
-        Ok(file) => file,
-        Err(_) => return std::ptr::null_mut(),
-    };
-
-    let archive = match Archive::new(file) {
-        Ok(archive) => archive,
-        Err(_) => return std::ptr::null_mut(),
-    };
-
-    CArchive::from_archive(archive)
+    match unsafe { path_argument(path, "path") } {
+        Ok(path) => new_archive_at(&path),
+        Err(_) => std::ptr::null_mut(),
+    }
 }
 
+/// Opens the archive file at `path`, taken like by `new_archive`. Returns NULL on
+/// failure, see `last_error_message`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn open_archive(path: *const c_char) -> *mut CArchive {
-    let path = unsafe { CStr::from_ptr(path).to_string_lossy().into_owned() };
-
-    match Archive::open(&path) {
-        Ok(archive) => CArchive::from_archive(archive),
+    match unsafe { path_argument(path, "path") } {
+        Ok(path) => open_archive_at(&path),
         Err(_) => std::ptr::null_mut(),
     }
 }
 
+/// Frees an archive handle, NULL is ignored. This does NOT finalize the archive, call `archive_finalize`
+/// after adding entries or the archive cannot be opened again.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn free_archive(archive: *mut CArchive) {
@@ -154,47 +180,76 @@ pub unsafe extern "C" fn free_archive(archive: *mut CArchive) {
     }
 }
 
+/// Adds everything in the directory at `path` to the archive and finalizes it,
+/// `progress_callback` may be NULL. Returns 0, `DDUP_ERR_NULL_ARG` for NULL
+/// arguments, `DDUP_ERR_CONCURRENT_USE` or another negative `DDUP_ERR_*` code if
+/// reading the directory or writing the archive failed, see `last_error_message`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn archive_add_directory(
     archive: *mut CArchive,
     path: *const c_char,
-    progress_callback: Option<ProgressCallbackFn>,
+    progress_callback: CProgressCallback,
+    user_data: *mut c_void,
 ) -> c_int {
-    if archive.is_null() || path.is_null() {
-        return -1;
+    if archive.is_null() {
+        set_last_error("archive is NULL");
+        return DDUP_ERR_NULL_ARG;
     }
 
+    let _token = match unsafe { CArchive::as_handle(archive) }.guard.enter() {
+        Ok(token) => token,
+        Err(code) => return code,
+    };
     let archive = unsafe { &mut *archive };
-    let path = unsafe { CStr::from_ptr(path).to_string_lossy().into_owned() };
+    let path = match unsafe { path_argument(path, "path") } {
+        Ok(path) => path,
+        Err(code) => return code,
+    };
 
-    let callback = build_progress_callback(progress_callback);
+    let callback = wrap_progress_callback(progress_callback, user_data);
 
     match archive.add_directory(&path, callback) {
-        Ok(_) => 0,
-        Err(_) => -1,
+        Ok(_) => {
+            archive.finalized = true;
+            0
+        }
+        Err(err) => error_code(err),
     }
 }
 
+/// Sets the callback choosing the compression of every file added by
+/// `archive_add_directory`, NULL restores the default. Returns the archive, or NULL
+/// with the last error set if it is NULL or used by another thread.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn archive_set_compression_callback(
     archive: *mut CArchive,
-    callback: Option<extern "C" fn(path: *const c_char, size: u64) -> CCompressionFormat>,
+    callback: Option<
+        extern "C" fn(path: *const c_char, size: u64, user_data: *mut c_void) -> CCompressionFormat,
+    >,
+    user_data: *mut c_void,
 ) -> *mut CArchive {
     if archive.is_null() {
+        set_last_error("archive is NULL");
         return std::ptr::null_mut();
     }
 
+    let _token = match unsafe { CArchive::as_handle(archive) }.guard.enter() {
+        Ok(token) => token,
+        Err(_) => return std::ptr::null_mut(),
+    };
     let archive = unsafe { &mut *archive };
 
+    let user_data = user_data as usize;
     if let Some(callback_fn) = callback {
         archive.set_compression_callback(Some(Arc::new(
             move |path: &Path, metadata: &std::fs::Metadata| {
                 if let Some(path_str) = path.to_str() {
                     let c_path = CString::new(path_str).unwrap();
                     let size = metadata.len();
-                    let compression_format = callback_fn(c_path.as_ptr(), size);
+                    let compression_format =
+                        callback_fn(c_path.as_ptr(), size, user_data as *mut c_void);
 
                     CompressionFormat::from(compression_format)
                 } else {
@@ -209,23 +264,33 @@ pub unsafe extern "C" fn archive_set_compression_callback(
     archive
 }
 
+/// Sets the callback reporting the real size of every file added by
+/// `archive_add_directory`, NULL restores the default. Returns the archive, or NULL
+/// with the last error set if it is NULL or used by another thread.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn archive_set_real_size_callback(
     archive: *mut CArchive,
-    callback: Option<extern "C" fn(path: *const c_char) -> u64>,
+    callback: Option<extern "C" fn(path: *const c_char, user_data: *mut c_void) -> u64>,
+    user_data: *mut c_void,
 ) -> *mut CArchive {
     if archive.is_null() {
+        set_last_error("archive is NULL");
         return std::ptr::null_mut();
     }
 
+    let _token = match unsafe { CArchive::as_handle(archive) }.guard.enter() {
+        Ok(token) => token,
+        Err(_) => return std::ptr::null_mut(),
+    };
     let archive = unsafe { &mut *archive };
 
+    let user_data = user_data as usize;
     if let Some(callback_fn) = callback {
         archive.set_real_size_callback(Some(Arc::new(move |path: &Path| {
             if let Some(path_str) = path.to_str() {
                 let c_path = CString::new(path_str).unwrap();
-                callback_fn(c_path.as_ptr())
+                callback_fn(c_path.as_ptr(), user_data as *mut c_void)
             } else {
                 0
             }
@@ -237,6 +302,7 @@ pub unsafe extern "C" fn archive_set_real_size_callback(
     archive
 }
 
+/// Returns the number of top level entries, 0 for a NULL archive.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn archive_entries_count(archive: *const CArchive) -> c_uint {
@@ -249,26 +315,54 @@ pub unsafe extern "C" fn archive_entries_count(archive: *const CArchive) -> c_ui
     archive.entries().len() as c_uint
 }
 
+/// Deprecated, use `archive_entries2` which also reports the length. Returns the top
+/// level entries as a NULL-terminated array, freed with `free_entry_array`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn archive_entries(archive: *const CArchive) -> *mut *const CEntry {
+    unsafe { archive_entries2(archive, std::ptr::null_mut()) as *mut *const CEntry }
+}
+
+/// Returns the top level entries as a NULL-terminated array and stores their number in
+/// `out_count`, which may be NULL. The array and every entry in it are owned by the
+/// caller and freed at once with `free_entry_array`, entries must not be freed on their
+/// own with `free_entry`. Returns NULL for a NULL archive.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn archive_entries2(
+    archive: *const CArchive,
+    out_count: *mut c_uint,
+) -> *mut *mut CEntry {
     if archive.is_null() {
         return std::ptr::null_mut();
     }
 
     let archive = unsafe { &*archive };
-
     let entries = archive.entries();
 
-    let mut entry_ptrs: Vec<*const CEntry> = Vec::with_capacity(entries.len());
+    if !out_count.is_null() {
+        unsafe { *out_count = entries.len() as c_uint };
+    }
+
+    let c_entries = entries
+        .iter()
+        .map(crate::entries::entry_to_c)
+        .collect::<Vec<_>>();
+    if c_entries.iter().any(|entry| entry.is_null()) {
+        for entry in c_entries {
+            unsafe { crate::entries::free_entry(entry) };
+        }
 
-    for entry in entries.iter() {
-        entry_ptrs.push(crate::entries::entry_to_c(entry));
+        return std::ptr::null_mut();
     }
 
-    Box::into_raw(entry_ptrs.into_boxed_slice()) as *mut *const CEntry
+    null_terminated(c_entries)
 }
 
+/// Returns a deep copy of the entry at `path` in the archive, including everything below
+/// a directory, or NULL with the last error set if an argument is NULL or there is no
+/// such entry. The copy is owned by the caller, freed with `free_entry` and stays valid
+/// after the archive is freed.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn archive_find_entry(
@@ -276,6 +370,7 @@ pub unsafe extern "C" fn archive_find_entry(
     path: *const c_char,
 ) -> *mut CEntry {
     if archive.is_null() || path.is_null() {
+        set_last_error("archive or path is NULL");
         return std::ptr::null_mut();
     }
 
@@ -283,7 +378,467 @@ pub unsafe extern "C" fn archive_find_entry(
     let path_str = unsafe { CStr::from_ptr(path).to_string_lossy().into_owned() };
 
     match archive.find_archive_entry(Path::new(&path_str)) {
-        Some(entry) => crate::entries::entry_to_c(entry),
-        None => std::ptr::null_mut(),
+        Ok(Some(entry)) => crate::entries::entry_to_c(entry),
+        Ok(None) => {
+            set_last_error(format!("{path_str} not found in archive"));
+            std::ptr::null_mut()
+        }
+        Err(err) => {
+            error_code(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Fills a buffer with up to `length` bytes of file content, returning the number of
+/// bytes written to it, 0 once everything was read or a negative number on failure.
+pub type CReadCallback =
+    Option<extern "C" fn(buffer: *mut u8, length: usize, user_data: *mut c_void) -> isize>;
+
+/// Pulls the content of a new file entry from a `CReadCallback`.
+struct CallbackReader {
+    callback: extern "C" fn(*mut u8, usize, *mut c_void) -> isize,
+    user_data: *mut c_void,
+}
+
+impl Read for CallbackReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = (self.callback)(buf.as_mut_ptr(), buf.len(), self.user_data);
+
+        if read < 0 {
+            return Err(std::io::Error::other(format!(
+                "Read callback failed with {read}"
+            )));
+        }
+
+        Ok((read as usize).min(buf.len()))
+    }
+}
+
+/// Checks that nothing exists at `path` yet and that its parent is a directory of the
+/// archive, returning the parent path (`None` for the archive root) and the entry name.
+fn new_entry_location<'a>(
+    archive: &Archive,
+    path: &'a str,
+) -> Result<(Option<&'a Path>, &'a str), c_int> {
+    let path_ref = Path::new(path);
+    let Some(name) = path_ref.file_name().and_then(|name| name.to_str()) else {
+        set_last_error(format!("{path} is not a valid entry name"));
+        return Err(DDUP_ERR_NULL_ARG);
+    };
+
+    match archive.find_archive_entry(path_ref) {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            set_last_error(format!("{path} already exists in the archive"));
+            return Err(CDdupError::DDUP_ERR_EXISTS as c_int);
+        }
+        Err(err) => return Err(error_code(err)),
+    }
+
+    let parent = path_ref
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty());
+    let parent_is_directory = match parent.map(|parent| archive.find_archive_entry(parent)) {
+        None | Some(Ok(Some(Entry::Directory(_)))) => true,
+        Some(Ok(_)) => false,
+        Some(Err(err)) => return Err(error_code(err)),
+    };
+    if let (Some(parent), false) = (parent, parent_is_directory) {
+        set_last_error(format!(
+            "{} is not a directory in the archive",
+            parent.display()
+        ));
+        return Err(DDUP_ERR_NULL_ARG);
+    }
+
+    Ok((parent, name))
+}
+
+fn insert_entry(archive: &mut Archive, parent: Option<&Path>, entry: Entry) -> std::io::Result<()> {
+    let parent = match parent {
+        Some(parent) => archive.find_archive_entry_mut(parent)?,
+        None => None,
+    };
+
+    match parent {
+        Some(Entry::Directory(directory)) => directory.entries_mut()?.push(entry),
+        _ => archive.entries.push(entry),
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn mtime_from_secs(mtime: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(mtime)
+}
+
+/// Writes a file entry at `path` whose content is pulled from `read_callback`, called
+/// with `user_data` on the calling thread until it returns 0. Parent directories must
+/// already exist, see `archive_add_empty_directory`. `mtime` is in seconds since the
+/// unix epoch.
+///
+/// The archive must be finalized after adding entries, until then it cannot be opened.
+/// Returns a copy of the new entry to free with `free_entry`, or NULL with the last
+/// error set, see `last_error_message`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::too_many_arguments)]
+pub unsafe extern "C" fn archive_write_file_entry(
+    archive: *mut CArchive,
+    path: *const c_char,
+    mode: u32,
+    mtime: u64,
+    uid: u32,
+    gid: u32,
+    compression: CCompressionFormat,
+    read_callback: CReadCallback,
+    user_data: *mut c_void,
+) -> *mut CEntry {
+    if archive.is_null() {
+        set_last_error("archive is NULL");
+        return std::ptr::null_mut();
+    }
+    let Some(callback) = read_callback else {
+        set_last_error("read_callback is NULL");
+        return std::ptr::null_mut();
+    };
+
+    let _token = match unsafe { CArchive::as_handle(archive) }.guard.enter() {
+        Ok(token) => token,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let archive = unsafe { &mut *archive };
+    let Ok(path) = (unsafe { utf8_argument(path, "path") }) else {
+        return std::ptr::null_mut();
+    };
+    let Ok((parent, name)) = new_entry_location(archive, path) else {
+        return std::ptr::null_mut();
+    };
+
+    let file_entry = archive.trim_end_header().and_then(|_| {
+        archive.write_file_entry(
+            CallbackReader {
+                callback,
+                user_data,
+            },
+            None,
+            name,
+            EntryMode::from(mode),
+            mtime_from_secs(mtime),
+            (uid, gid),
+            compression.into(),
+        )
+    });
+
+    match file_entry {
+        Ok(file_entry) => {
+            let entry = Entry::File(file_entry);
+            let c_entry = crate::entries::entry_to_c(&entry);
+
+            if let Err(err) = insert_entry(archive, parent, entry) {
+                unsafe { crate::entries::free_entry(c_entry) };
+                error_code(err);
+
+                return std::ptr::null_mut();
+            }
+            archive.finalized = false;
+
+            c_entry
+        }
+        Err(err) => {
+            error_code(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Adds a symlink entry at `path` pointing to `target`, `target_dir` marks targets that
+/// are directories, which matters when restoring on windows. Returns 0,
+/// `DDUP_ERR_EXISTS` if an entry exists at `path`, `DDUP_ERR_NULL_ARG` for
+/// NULL arguments or a parent that is not a directory or `DDUP_ERR_CONCURRENT_USE`,
+/// see `archive_write_file_entry` for the other arguments.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::too_many_arguments)]
+pub unsafe extern "C" fn archive_add_symlink(
+    archive: *mut CArchive,
+    path: *const c_char,
+    target: *const c_char,
+    target_dir: bool,
+    mode: u32,
+    mtime: u64,
+    uid: u32,
+    gid: u32,
+) -> c_int {
+    if archive.is_null() {
+        set_last_error("archive is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let _token = match unsafe { CArchive::as_handle(archive) }.guard.enter() {
+        Ok(token) => token,
+        Err(code) => return code,
+    };
+    let archive = unsafe { &mut *archive };
+    let (path, target) =
+        match unsafe { (utf8_argument(path, "path"), utf8_argument(target, "target")) } {
+            (Ok(path), Ok(target)) => (path, target),
+            (Err(code), _) | (_, Err(code)) => return code,
+        };
+    let (parent, name) = match new_entry_location(archive, path) {
+        Ok(location) => location,
+        Err(code) => return code,
+    };
+
+    if let Err(err) = insert_entry(
+        archive,
+        parent,
+        Entry::Symlink(Box::new(SymlinkEntry {
+            name: name.to_string(),
+            mode: EntryMode::from(mode),
+            owner: (uid, gid),
+            mtime: mtime_from_secs(mtime),
+            target: target.to_string(),
+            target_dir,
+        })),
+    ) {
+        return error_code(err);
+    }
+    archive.finalized = false;
+
+    0
+}
+
+/// Adds an empty directory entry at `path`, entries can be added to it afterwards.
+/// Returns 0 or a negative `DDUP_ERR_*` code like `archive_add_symlink`, see
+/// `archive_write_file_entry` for the other arguments.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn archive_add_empty_directory(
+    archive: *mut CArchive,
+    path: *const c_char,
+    mode: u32,
+    mtime: u64,
+    uid: u32,
+    gid: u32,
+) -> c_int {
+    if archive.is_null() {
+        set_last_error("archive is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let _token = match unsafe { CArchive::as_handle(archive) }.guard.enter() {
+        Ok(token) => token,
+        Err(code) => return code,
+    };
+    let archive = unsafe { &mut *archive };
+    let path = match unsafe { utf8_argument(path, "path") } {
+        Ok(path) => path,
+        Err(code) => return code,
+    };
+    let (parent, name) = match new_entry_location(archive, path) {
+        Ok(location) => location,
+        Err(code) => return code,
+    };
+
+    if let Err(err) = insert_entry(
+        archive,
+        parent,
+        Entry::Directory(Box::new(DirectoryEntry::new(
+            name,
+            EntryMode::from(mode),
+            (uid, gid),
+            mtime_from_secs(mtime),
+            Vec::new(),
+        ))),
+    ) {
+        return error_code(err);
+    }
+    archive.finalized = false;
+
+    0
+}
+
+/// Writes the end header describing all entries, which makes the archive readable by
+/// `open_archive`. Entries may still be added afterwards, the archive then has to be
+/// finalized again. Returns 0, `DDUP_ERR_NULL_ARG` for a NULL archive,
+/// `DDUP_ERR_CONCURRENT_USE` or `DDUP_ERR_IO` if writing failed, see
+/// `last_error_message`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn archive_finalize(archive: *mut CArchive) -> c_int {
+    if archive.is_null() {
+        set_last_error("archive is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let _token = match unsafe { CArchive::as_handle(archive) }.guard.enter() {
+        Ok(token) => token,
+        Err(code) => return code,
+    };
+    let archive = unsafe { &mut *archive };
+
+    match archive
+        .trim_end_header()
+        .and_then(|_| archive.write_end_header())
+    {
+        Ok(()) => {
+            archive.finalized = true;
+            0
+        }
+        Err(err) => error_code(err),
+    }
+}
+
+/// Returns whether every entry added to the archive is written to its end header,
+/// false for a NULL archive.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn archive_is_finalized(archive: *const CArchive) -> bool {
+    if archive.is_null() {
+        return false;
+    }
+
+    unsafe { CArchive::as_handle(archive).finalized }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entries::{entry_as_directory, entry_as_file, entry_as_symlink, free_entry, CEntry};
+    use crate::last_error_message;
+    use crate::test_util::{c_path, c_string, file_content, temp_directory};
+
+    /// Hands out the rest of the `&[u8]` behind `user_data` in pieces of at most 100 bytes.
+    extern "C" fn read_slice(buffer: *mut u8, length: usize, user_data: *mut c_void) -> isize {
+        let content = unsafe { &mut *(user_data as *mut &[u8]) };
+        let length = length.min(100).min(content.len());
+
+        unsafe { std::ptr::copy_nonoverlapping(content.as_ptr(), buffer, length) };
+        *content = &content[length..];
+
+        length as isize
+    }
+
+    extern "C" fn fail(_buffer: *mut u8, _length: usize, _user_data: *mut c_void) -> isize {
+        -1
+    }
+
+    #[test]
+    fn written_entries_are_readable_after_finalizing() {
+        let directory = temp_directory("archive-write");
+        let path = c_path(&directory.join("written.ddup"));
+        let content = file_content();
+
+        unsafe {
+            let archive = new_archive(path.as_ptr());
+            assert!(!archive.is_null());
+
+            assert_eq!(
+                archive_add_empty_directory(archive, c_string("dir").as_ptr(), 0o755, 10, 1, 2),
+                0
+            );
+
+            let mut remaining = &content[..];
+            let entry = archive_write_file_entry(
+                archive,
+                c_string("dir/file").as_ptr(),
+                0o644,
+                20,
+                1,
+                2,
+                CCompressionFormat::Gzip,
+                Some(read_slice),
+                &mut remaining as *mut &[u8] as *mut c_void,
+            );
+            assert!(!entry.is_null());
+            assert!(remaining.is_empty());
+            assert_eq!((*entry_as_file(entry)).size_real, content.len() as u64);
+            free_entry(entry);
+
+            assert_eq!(
+                archive_add_symlink(
+                    archive,
+                    c_string("link").as_ptr(),
+                    c_string("dir/file").as_ptr(),
+                    false,
+                    0o777,
+                    30,
+                    1,
+                    2,
+                ),
+                0
+            );
+
+            // existing paths and parents that are no directories are refused
+            let symlink = |path: &str| {
+                archive_add_symlink(
+                    archive,
+                    c_string(path).as_ptr(),
+                    c_string("dir").as_ptr(),
+                    true,
+                    0o777,
+                    0,
+                    0,
+                    0,
+                )
+            };
+            assert_eq!(symlink("dir"), CDdupError::DDUP_ERR_EXISTS as c_int);
+            assert_eq!(symlink("link/child"), DDUP_ERR_NULL_ARG);
+            assert_eq!(symlink("missing/child"), DDUP_ERR_NULL_ARG);
+
+            let failed = archive_write_file_entry(
+                archive,
+                c_string("failed").as_ptr(),
+                0o644,
+                0,
+                0,
+                0,
+                CCompressionFormat::None,
+                Some(fail),
+                std::ptr::null_mut(),
+            );
+            assert!(failed.is_null());
+            assert!(!last_error_message().is_null());
+
+            assert!(!archive_is_finalized(archive));
+            assert!(open_archive(path.as_ptr()).is_null());
+            assert_eq!(archive_finalize(archive), 0);
+            assert!(archive_is_finalized(archive));
+            free_archive(archive);
+
+            let archive = open_archive(path.as_ptr());
+            assert!(!archive.is_null());
+            assert!(archive_is_finalized(archive));
+            assert_eq!(archive_entries_count(archive), 2);
+
+            let find = |path: &str| -> *mut CEntry {
+                let entry = archive_find_entry(archive, c_string(path).as_ptr());
+                assert!(!entry.is_null(), "{path}");
+                entry
+            };
+
+            let dir = find("dir");
+            assert_eq!((*entry_as_directory(dir)).entries_count, 1);
+            assert_eq!((*entry_as_directory(dir)).common.mode & 0o777, 0o755);
+            free_entry(dir);
+
+            let file = find("dir/file");
+            let file_fields = &*entry_as_file(file);
+            assert_eq!(file_fields.size_real, content.len() as u64);
+            assert!(matches!(file_fields.compression, CCompressionFormat::Gzip));
+            assert_eq!(file_fields.common.mtime, 20);
+            assert_eq!((file_fields.common.uid, file_fields.common.gid), (1, 2));
+            free_entry(file);
+
+            let link = find("link");
+            let target = CStr::from_ptr((*entry_as_symlink(link)).target);
+            assert_eq!(target.to_str().unwrap(), "dir/file");
+            free_entry(link);
+
+            free_archive(archive);
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
     }
 }
@@ -0,0 +1,156 @@
+use crate::repository::CRepository;
+use crate::{error_code, set_last_error, utf8_argument, DDUP_ERR_ABORTED, DDUP_ERR_NULL_ARG};
+use ddup_bak::convert;
+use std::ffi::*;
+use std::io::{BufWriter, Write};
+
+/// Size of the pieces passed to a `CStreamCallback`, except for the last one.
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Receives a piece of a stream along with `user_data`. Returns how many bytes of it
+/// were consumed, at least 1, the rest is passed again in the next call. A negative
+/// return aborts the stream.
+pub type CStreamCallback =
+    Option<extern "C" fn(data: *const u8, length: usize, user_data: *mut c_void) -> isize>;
+
+struct StreamWriter {
+    callback: extern "C" fn(*const u8, usize, *mut c_void) -> isize,
+    user_data: *mut c_void,
+    aborted: bool,
+}
+
+impl Write for StreamWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let consumed = (self.callback)(buf.as_ptr(), buf.len(), self.user_data);
+        if consumed < 0 {
+            self.aborted = true;
+
+            return Err(std::io::Error::other("Aborted by the stream callback"));
+        }
+
+        Ok((consumed as usize).min(buf.len()))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Streams an archive as a tar file, compressed with gzip if `gzip` is set, through
+/// `write_callback` on the calling thread. Returns 0, `DDUP_ERR_ABORTED` if the
+/// callback aborted or another negative `DDUP_ERR_*` code, see `last_error_message`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_convert_to_tar(
+    repo: *mut CRepository,
+    archive_name: *const c_char,
+    gzip: bool,
+    write_callback: CStreamCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+    let Some(callback) = write_callback else {
+        set_last_error("write_callback is NULL");
+        return DDUP_ERR_NULL_ARG;
+    };
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+    let archive_name = match unsafe { utf8_argument(archive_name, "archive_name") } {
+        Ok(archive_name) => archive_name,
+        Err(code) => return code,
+    };
+
+    let archive = match repo.get_archive(archive_name) {
+        Ok(archive) => archive,
+        Err(err) => return error_code(err),
+    };
+
+    let mut writer = StreamWriter {
+        callback,
+        user_data,
+        aborted: false,
+    };
+    let mut output = BufWriter::with_capacity(STREAM_BUFFER_SIZE, &mut writer);
+
+    let result = archive
+        .into_entries()
+        .and_then(|entries| {
+            if gzip {
+                convert::to_tar_gz(&repo, entries, &mut output, 6, None)
+            } else {
+                convert::to_tar(&repo, entries, &mut output, None)
+            }
+        })
+        .and_then(|_| output.flush());
+    drop(output);
+
+    match result {
+        Ok(()) => 0,
+        Err(_) if writer.aborted => {
+            set_last_error("aborted by the stream callback");
+            DDUP_ERR_ABORTED
+        }
+        Err(err) => error_code(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{c_string, file_content, Fixture};
+
+    /// Appends at most 100 bytes of every piece to the `Vec<u8>` behind `user_data`, so
+    /// the rest is passed again.
+    extern "C" fn collect(data: *const u8, length: usize, user_data: *mut c_void) -> isize {
+        let stream = unsafe { &mut *(user_data as *mut Vec<u8>) };
+        let length = length.min(100);
+        stream.extend_from_slice(unsafe { std::slice::from_raw_parts(data, length) });
+
+        length as isize
+    }
+
+    extern "C" fn abort(_data: *const u8, _length: usize, _user_data: *mut c_void) -> isize {
+        -1
+    }
+
+    fn convert(fixture: &Fixture, gzip: bool, callback: CStreamCallback) -> (c_int, Vec<u8>) {
+        let mut stream = Vec::new();
+        let code = unsafe {
+            repository_convert_to_tar(
+                fixture.repo,
+                c_string("archive").as_ptr(),
+                gzip,
+                callback,
+                &mut stream as *mut Vec<u8> as *mut c_void,
+            )
+        };
+
+        (code, stream)
+    }
+
+    #[test]
+    fn archives_stream_as_tar() {
+        let fixture = Fixture::new("convert-tar");
+
+        let (code, tar) = convert(&fixture, false, Some(collect));
+        assert_eq!(code, 0);
+        assert_eq!(tar.len() % 512, 0);
+        assert_eq!(&tar[257..262], b"ustar");
+
+        let content = file_content();
+        let position = |needle: &[u8]| tar.windows(needle.len()).position(|w| w == needle);
+        assert!(position(&content).is_some());
+        assert!(position(b"directory/nested/deep").is_some());
+
+        let (code, gz) = convert(&fixture, true, Some(collect));
+        assert_eq!(code, 0);
+        assert_eq!(gz[..2], [0x1f, 0x8b]);
+
+        assert_eq!(convert(&fixture, false, Some(abort)).0, DDUP_ERR_ABORTED);
+        assert_eq!(convert(&fixture, false, None).0, DDUP_ERR_NULL_ARG);
+    }
+}
@@ -1,7 +1,6 @@
 use crate::archive::CCompressionFormat;
-use ddup_bak::archive::entries::Entry;
+use ddup_bak::archive::entries::{Entry, FileEntry};
 use std::ffi::*;
-use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 #[repr(C)]
@@ -12,6 +11,9 @@ pub enum CEntryType {
     Symlink = 2,
 }
 
+/// An entry copied out of an archive. Every entry handed out by this library is an
+/// independent copy which stays valid after the archive handle is freed, it owns its
+/// strings and, for a directory, its children.
 #[repr(C)]
 pub struct CEntry {
     pub entry_type: CEntryType,
@@ -36,10 +38,17 @@ pub struct CFileEntry {
     pub size_real: u64,
     pub size_compressed: u64,
 
-    pub file: *mut c_void,
+    /// Where the chunk ID list starts in the archive, see `archive_entry_open_reader`.
     pub offset: u64,
+    pub delta_chunk_ids: bool,
+
+    /// Opaque reference to the file in its archive, owned by the entry, which lets
+    /// `entry_open_reader` open it without the archive handle.
+    pub token: *mut c_void,
 }
 
+/// A directory entry, `entries` are owned by it and freed along with it. To keep a
+/// child after freeing the directory, copy it with `entry_clone`.
 #[repr(C)]
 pub struct CDirectoryEntry {
     pub common: CEntryCommon,
@@ -80,6 +89,7 @@ fn create_c_entry_common(entry: &Entry) -> CEntryCommon {
     }
 }
 
+/// Returns the type of an entry, `File` for a NULL entry.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn get_entry_type(entry: *const CEntry) -> CEntryType {
@@ -90,6 +100,7 @@ pub unsafe extern "C" fn get_entry_type(entry: *const CEntry) -> CEntryType {
     unsafe { (*entry).entry_type }
 }
 
+/// Returns the fields every entry type has, owned by the entry, or NULL for a NULL entry.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn entry_get_common(entry: *const CEntry) -> *const CEntryCommon {
@@ -115,6 +126,7 @@ pub unsafe extern "C" fn entry_get_common(entry: *const CEntry) -> *const CEntry
     }
 }
 
+/// Returns the name of an entry, owned by the entry, or NULL for a NULL entry.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn entry_name(entry: *const CEntry) -> *const c_char {
@@ -127,6 +139,8 @@ pub unsafe extern "C" fn entry_name(entry: *const CEntry) -> *const c_char {
     unsafe { (*common).name }
 }
 
+/// Frees an entry along with everything below it, NULL is ignored. Entries of an
+/// array returned by `archive_entries2` are freed with `free_entry_array` instead.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn free_entry(entry: *mut CEntry) {
@@ -144,6 +158,9 @@ pub unsafe extern "C" fn free_entry(entry: *mut CEntry) {
                 if !(*file_entry).common.name.is_null() {
                     let _ = CString::from_raw((*file_entry).common.name);
                 }
+                if !(*file_entry).token.is_null() {
+                    let _ = Box::from_raw((*file_entry).token as *mut FileEntry);
+                }
                 let _ = Box::from_raw(file_entry);
             }
         }
@@ -162,7 +179,10 @@ pub unsafe extern "C" fn free_entry(entry: *mut CEntry) {
                         }
                     }
 
-                    let _ = Box::from_raw((*dir_entry).entries);
+                    let _ = Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+                        (*dir_entry).entries,
+                        (*dir_entry).entries_count as usize,
+                    ));
                 }
 
                 let _ = Box::from_raw(dir_entry);
@@ -189,6 +209,26 @@ pub unsafe extern "C" fn free_entry(entry: *mut CEntry) {
     }
 }
 
+/// Frees an array returned by `archive_entries2` along with every entry in it. `count`
+/// is the number reported for the array, which entries are freed is decided by its
+/// NULL terminator.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn free_entry_array(entries: *mut *mut CEntry, count: c_uint) {
+    if entries.is_null() {
+        return;
+    }
+
+    let entries = unsafe { crate::free_null_terminated(entries) };
+    let _ = count;
+
+    for entry in entries {
+        unsafe { free_entry(entry) };
+    }
+}
+
+/// Copies an entry and everything below it, returns NULL with the last error set if
+/// the children of a directory cannot be decoded.
 pub fn entry_to_c(entry: &Entry) -> *mut CEntry {
     match entry {
         Entry::File(file_entry) => {
@@ -200,8 +240,9 @@ pub fn entry_to_c(entry: &Entry) -> *mut CEntry {
                 size: file_entry.size,
                 size_real: file_entry.size_real,
                 size_compressed: file_entry.size_compressed.unwrap_or(0),
-                file: Arc::into_raw(file_entry.file.clone()) as *mut c_void,
                 offset: file_entry.offset,
+                delta_chunk_ids: file_entry.delta_chunk_ids,
+                token: Box::into_raw(Box::new(FileEntry::clone(file_entry))) as *mut c_void,
             }));
 
             Box::into_raw(Box::new(CEntry {
@@ -210,15 +251,32 @@ pub fn entry_to_c(entry: &Entry) -> *mut CEntry {
             }))
         }
         Entry::Directory(dir_entry) => {
-            let common = create_c_entry_common(entry);
+            let sub_entries = match dir_entry.entries() {
+                Ok(sub_entries) => sub_entries,
+                Err(err) => {
+                    crate::set_last_error(err);
+                    return std::ptr::null_mut();
+                }
+            };
 
-            let entries_count = dir_entry.entries.len();
+            let entries_count = sub_entries.len();
             let mut entries = Vec::with_capacity(entries_count);
 
-            for sub_entry in &dir_entry.entries {
-                entries.push(entry_to_c(sub_entry));
+            for sub_entry in sub_entries {
+                let c_entry = entry_to_c(sub_entry);
+                if c_entry.is_null() {
+                    for entry in entries {
+                        unsafe { free_entry(entry) };
+                    }
+
+                    return std::ptr::null_mut();
+                }
+
+                entries.push(c_entry);
             }
 
+            let common = create_c_entry_common(entry);
+
             let entries_ptr = Box::into_raw(entries.into_boxed_slice()) as *mut *mut CEntry;
 
             let dir_entry_ptr = Box::into_raw(Box::new(CDirectoryEntry {
@@ -251,6 +309,103 @@ pub fn entry_to_c(entry: &Entry) -> *mut CEntry {
     }
 }
 
+/// Returns the archived file an entry refers to, or `None` if it is NULL or not a file.
+pub(crate) unsafe fn entry_file_token<'a>(entry: *const CEntry) -> Option<&'a FileEntry> {
+    let file = unsafe { entry_as_file(entry) };
+
+    if file.is_null() || unsafe { (*file).token.is_null() } {
+        return None;
+    }
+
+    Some(unsafe { &*((*file).token as *const FileEntry) })
+}
+
+unsafe fn clone_c_string(ptr: *const c_char) -> *mut c_char {
+    if ptr.is_null() {
+        return std::ptr::null_mut();
+    }
+
+    unsafe { CStr::from_ptr(ptr) }.to_owned().into_raw()
+}
+
+unsafe fn clone_c_entry_common(common: &CEntryCommon) -> CEntryCommon {
+    CEntryCommon {
+        name: unsafe { clone_c_string(common.name) },
+        mode: common.mode,
+        uid: common.uid,
+        gid: common.gid,
+        mtime: common.mtime,
+        entry_type: common.entry_type,
+    }
+}
+
+/// Returns a deep copy of an entry, including everything below a directory, which is
+/// freed with `free_entry` independently of the original. Returns NULL for a NULL entry.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn entry_clone(entry: *const CEntry) -> *mut CEntry {
+    if entry.is_null() {
+        return std::ptr::null_mut();
+    }
+
+    let entry_type = unsafe { (*entry).entry_type };
+    let entry_ptr: *mut c_void = match entry_type {
+        CEntryType::File => {
+            let file_entry = unsafe { &*((*entry).entry as *const CFileEntry) };
+            let token = if file_entry.token.is_null() {
+                std::ptr::null_mut()
+            } else {
+                let file = unsafe { &*(file_entry.token as *const FileEntry) };
+                Box::into_raw(Box::new(file.clone())) as *mut c_void
+            };
+
+            Box::into_raw(Box::new(CFileEntry {
+                common: unsafe { clone_c_entry_common(&file_entry.common) },
+                compression: file_entry.compression,
+                size: file_entry.size,
+                size_real: file_entry.size_real,
+                size_compressed: file_entry.size_compressed,
+                offset: file_entry.offset,
+                delta_chunk_ids: file_entry.delta_chunk_ids,
+                token,
+            })) as *mut c_void
+        }
+        CEntryType::Directory => {
+            let dir_entry = unsafe { &*((*entry).entry as *const CDirectoryEntry) };
+            let entries = if dir_entry.entries.is_null() {
+                std::ptr::null_mut()
+            } else {
+                let sub_entries = (0..dir_entry.entries_count as usize)
+                    .map(|i| unsafe { entry_clone(*dir_entry.entries.add(i)) })
+                    .collect::<Vec<_>>();
+
+                Box::into_raw(sub_entries.into_boxed_slice()) as *mut *mut CEntry
+            };
+
+            Box::into_raw(Box::new(CDirectoryEntry {
+                common: unsafe { clone_c_entry_common(&dir_entry.common) },
+                entries_count: dir_entry.entries_count,
+                entries,
+            })) as *mut c_void
+        }
+        CEntryType::Symlink => {
+            let symlink_entry = unsafe { &*((*entry).entry as *const CSymlinkEntry) };
+
+            Box::into_raw(Box::new(CSymlinkEntry {
+                common: unsafe { clone_c_entry_common(&symlink_entry.common) },
+                target: unsafe { clone_c_string(symlink_entry.target) },
+                target_dir: symlink_entry.target_dir,
+            })) as *mut c_void
+        }
+    };
+
+    Box::into_raw(Box::new(CEntry {
+        entry_type,
+        entry: entry_ptr,
+    }))
+}
+
+/// Returns the file fields of an entry, or NULL if it is NULL or not a file.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn entry_as_file(entry: *const CEntry) -> *const CFileEntry {
@@ -267,6 +422,7 @@ pub unsafe extern "C" fn entry_as_file(entry: *const CEntry) -> *const CFileEntr
     unsafe { (*entry).entry as *const CFileEntry }
 }
 
+/// Returns the directory fields of an entry, or NULL if it is NULL or not a directory.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn entry_as_directory(entry: *const CEntry) -> *const CDirectoryEntry {
@@ -283,6 +439,7 @@ pub unsafe extern "C" fn entry_as_directory(entry: *const CEntry) -> *const CDir
     unsafe { (*entry).entry as *const CDirectoryEntry }
 }
 
+/// Returns the symlink fields of an entry, or NULL if it is NULL or not a symlink.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn entry_as_symlink(entry: *const CEntry) -> *const CSymlinkEntry {
@@ -298,3 +455,109 @@ pub unsafe extern "C" fn entry_as_symlink(entry: *const CEntry) -> *const CSymli
 
     unsafe { (*entry).entry as *const CSymlinkEntry }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{archive_entries, archive_entries2, archive_find_entry, free_archive};
+    use crate::repository::repository_get_archive;
+    use crate::test_util::{c_string, file_content, Fixture};
+
+    unsafe fn name<'a>(entry: *const CEntry) -> &'a str {
+        unsafe { CStr::from_ptr(entry_name(entry)) }
+            .to_str()
+            .unwrap()
+    }
+
+    #[test]
+    fn entry_arrays_are_null_terminated() {
+        let fixture = Fixture::new("entry-arrays");
+        let top_level = if cfg!(unix) { 3 } else { 2 };
+
+        unsafe {
+            let archive = repository_get_archive(fixture.repo, c_string("archive").as_ptr());
+            assert!(!archive.is_null());
+
+            let mut count = 0;
+            let entries = archive_entries2(archive, &mut count);
+            assert_eq!(count, top_level);
+            assert!((*entries.add(count as usize)).is_null());
+
+            let mut names = (0..count as usize)
+                .map(|i| name(*entries.add(i)))
+                .collect::<Vec<_>>();
+            names.sort();
+            assert_eq!(names[..2], ["directory", "file"]);
+            free_entry_array(entries, count);
+
+            // the deprecated variant is terminated the same way
+            let entries = archive_entries(archive);
+            assert!((*entries.add(top_level as usize)).is_null());
+            free_entry_array(entries as *mut *mut CEntry, top_level);
+
+            free_archive(archive);
+        }
+    }
+
+    #[test]
+    fn found_entries_are_deep_copies() {
+        let fixture = Fixture::new("entry-copies");
+
+        unsafe {
+            let archive = repository_get_archive(fixture.repo, c_string("archive").as_ptr());
+            let directory = archive_find_entry(archive, c_string("directory").as_ptr());
+            let file = archive_find_entry(archive, c_string("file").as_ptr());
+            assert!(archive_find_entry(archive, c_string("missing").as_ptr()).is_null());
+            free_archive(archive);
+
+            // everything below the directory outlives the archive
+            assert!(get_entry_type(directory) == CEntryType::Directory);
+            assert!(entry_as_file(directory).is_null());
+            let children = &*entry_as_directory(directory);
+            assert_eq!(children.entries_count, 2);
+            let mut names = (0..children.entries_count as usize)
+                .map(|i| name(*children.entries.add(i)))
+                .collect::<Vec<_>>();
+            names.sort();
+            assert_eq!(names, ["nested", "small"]);
+
+            let clone = entry_clone(directory);
+            free_entry(directory);
+            assert_eq!(name(clone), "directory");
+            assert_eq!((*entry_as_directory(clone)).entries_count, 2);
+            free_entry(clone);
+
+            assert!(get_entry_type(file) == CEntryType::File);
+            let common = &*entry_get_common(file);
+            assert_eq!(CStr::from_ptr(common.name).to_str().unwrap(), "file");
+            assert!(common.entry_type == CEntryType::File);
+            assert_eq!(
+                (*entry_as_file(file)).size_real,
+                file_content().len() as u64
+            );
+            assert!(entry_as_symlink(file).is_null());
+            free_entry(file);
+
+            assert!(entry_clone(std::ptr::null()).is_null());
+            assert!(entry_name(std::ptr::null()).is_null());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_keep_their_target() {
+        let fixture = Fixture::new("entry-symlinks");
+
+        unsafe {
+            let archive = repository_get_archive(fixture.repo, c_string("archive").as_ptr());
+            let link = archive_find_entry(archive, c_string("link").as_ptr());
+            free_archive(archive);
+
+            assert!(get_entry_type(link) == CEntryType::Symlink);
+            let symlink = &*entry_as_symlink(link);
+            assert_eq!(CStr::from_ptr(symlink.target).to_str().unwrap(), "file");
+            assert!(!symlink.target_dir);
+            free_entry(link);
+        }
+    }
+}
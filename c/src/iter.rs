@@ -0,0 +1,162 @@
+use crate::repository::CRepository;
+use crate::{error_code, set_last_error, DDUP_ERR_BUFFER_TOO_SMALL, DDUP_ERR_NULL_ARG};
+use std::ffi::*;
+
+/// Iterates the archive names of a repository as they were when it was created.
+#[repr(C)]
+pub struct CArchiveIter {
+    _private: [u8; 0],
+}
+
+struct ArchiveIter {
+    names: Vec<String>,
+    position: usize,
+}
+
+/// Starts iterating the archive names of a repository, an alternative to
+/// `repository_list_archives` that copies every name into a buffer of the caller.
+/// Returns NULL on failure, see `last_error_message`. The iterator is freed with
+/// `repository_archive_iter_free` and does not borrow the repository.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_archive_iter_new(repo: *mut CRepository) -> *mut CArchiveIter {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return std::ptr::null_mut();
+    }
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+
+    match repo.list_archives() {
+        Ok(names) => {
+            Box::into_raw(Box::new(ArchiveIter { names, position: 0 })) as *mut CArchiveIter
+        }
+        Err(err) => {
+            error_code(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Copies the next archive name into `buffer` with a terminating NUL and returns its
+/// length without the NUL, or 0 once every name was returned.
+///
+/// If the name and its NUL do not fit into `buffer_length` bytes, nothing is copied,
+/// the iterator stays at the name and `DDUP_ERR_BUFFER_TOO_SMALL` is returned with
+/// the required length including the NUL stored in `out_required` unless it is NULL.
+/// Other negative `DDUP_ERR_*` codes are returned for invalid arguments, see
+/// `last_error_message`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_archive_iter_next(
+    iter: *mut CArchiveIter,
+    buffer: *mut c_char,
+    buffer_length: usize,
+    out_required: *mut usize,
+) -> c_int {
+    if iter.is_null() || (buffer.is_null() && buffer_length > 0) {
+        set_last_error("iterator or buffer is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let iter = unsafe { &mut *(iter as *mut ArchiveIter) };
+    let Some(name) = iter.names.get(iter.position) else {
+        return 0;
+    };
+
+    let required = name.len() + 1;
+    if !out_required.is_null() {
+        unsafe { *out_required = required };
+    }
+
+    if required > buffer_length {
+        set_last_error(format!(
+            "buffer of {buffer_length} bytes is too small, {required} are needed"
+        ));
+        return DDUP_ERR_BUFFER_TOO_SMALL;
+    }
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(name.as_ptr(), buffer as *mut u8, name.len());
+        *buffer.add(name.len()) = 0;
+    }
+    iter.position += 1;
+
+    name.len() as c_int
+}
+
+/// Frees an archive name iterator, NULL is ignored.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_archive_iter_free(iter: *mut CArchiveIter) {
+    if !iter.is_null() {
+        let _ = unsafe { Box::from_raw(iter as *mut ArchiveIter) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::repository_list_archives;
+    use crate::test_util::{strings, Fixture};
+
+    #[test]
+    fn names_are_copied_into_the_buffer() {
+        let fixture = Fixture::new("archive-iter");
+        fixture.create_archive("second-archive");
+
+        unsafe {
+            let iter = repository_archive_iter_new(fixture.repo);
+            assert!(!iter.is_null());
+
+            // a later archive does not change the names being iterated
+            fixture.create_archive("later");
+
+            let mut names = Vec::new();
+            let mut buffer = [0 as c_char; 8];
+            let mut required = 0;
+            loop {
+                match repository_archive_iter_next(iter, buffer.as_mut_ptr(), 8, &mut required) {
+                    0 => break,
+                    DDUP_ERR_BUFFER_TOO_SMALL => {
+                        assert_eq!(required, "second-archive".len() + 1);
+                        assert_eq!(buffer[0], 0);
+
+                        let mut large = vec![0 as c_char; required];
+                        let length = repository_archive_iter_next(
+                            iter,
+                            large.as_mut_ptr(),
+                            required,
+                            std::ptr::null_mut(),
+                        );
+                        assert_eq!(length as usize, required - 1);
+                        names.push(CStr::from_ptr(large.as_ptr()).to_str().unwrap().to_string());
+                    }
+                    length => {
+                        assert_eq!(required, length as usize + 1);
+                        names.push(
+                            CStr::from_ptr(buffer.as_ptr())
+                                .to_str()
+                                .unwrap()
+                                .to_string(),
+                        );
+                    }
+                }
+            }
+            assert_eq!(
+                repository_archive_iter_next(iter, buffer.as_mut_ptr(), 8, std::ptr::null_mut()),
+                0
+            );
+            repository_archive_iter_free(iter);
+
+            names.sort();
+            assert_eq!(names, ["archive", "second-archive"]);
+
+            let mut count = 0;
+            let listed = strings(repository_list_archives(fixture.repo, &mut count));
+            assert_eq!(count, 3);
+            assert!(listed.iter().any(|name| name == "later"));
+        }
+    }
+}
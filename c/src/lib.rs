@@ -1,10 +1,131 @@
-use std::ffi::*;
+#[cfg(debug_assertions)]
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::{
+    cell::RefCell,
+    ffi::*,
+    path::{Path, PathBuf},
+};
 
 pub mod archive;
+pub mod convert;
 pub mod entries;
+pub mod iter;
+pub mod progress;
 pub mod reader;
 pub mod repository;
+pub mod storage;
+#[cfg(test)]
+mod test_util;
+pub mod verify;
+#[cfg(windows)]
+pub mod wide;
 
+/// Incremented with every incompatible change of the functions or types in this header,
+/// compare it with `ddup_bak_abi_version` to detect a mismatched library at runtime.
+pub const DDUP_BAK_ABI_VERSION: c_uint = 3;
+
+/// The codes returned by the functions of this header, 0 or one of the negative
+/// `DDUP_ERR_*` values. Unless documented otherwise, a function returning an error
+/// code also records a message for `last_error_message`.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub enum CDdupError {
+    DDUP_OK = 0,
+    /// An argument was NULL, not valid UTF-8 or out of range.
+    DDUP_ERR_NULL_ARG = -1,
+    /// The archive, an entry or a chunk does not exist.
+    DDUP_ERR_NOT_FOUND = -2,
+    /// Something to be created already exists.
+    DDUP_ERR_EXISTS = -3,
+    /// Any other failure of the file system or the chunk storage.
+    DDUP_ERR_IO = -4,
+    /// The repository is locked by another process.
+    DDUP_ERR_LOCK = -5,
+    /// An archive, the chunk index or a chunk could not be decoded.
+    DDUP_ERR_CORRUPT = -6,
+    /// The chunk storage or the platform does not support the operation.
+    DDUP_ERR_UNSUPPORTED = -7,
+    /// A callback asked to stop, or the operation was interrupted.
+    DDUP_ERR_ABORTED = -8,
+    /// A function requiring exclusive access ran while another call used the handle,
+    /// always detected for repository handles and in debug builds for archive handles.
+    DDUP_ERR_CONCURRENT_USE = -9,
+    /// A buffer passed by the caller cannot hold the result, the function reports the
+    /// size it needs.
+    DDUP_ERR_BUFFER_TOO_SMALL = -10,
+    /// The end of a file was reached before the requested number of bytes was read.
+    DDUP_ERR_EOF = -11,
+}
+
+pub(crate) const DDUP_ERR_NULL_ARG: c_int = CDdupError::DDUP_ERR_NULL_ARG as c_int;
+pub(crate) const DDUP_ERR_NOT_FOUND: c_int = CDdupError::DDUP_ERR_NOT_FOUND as c_int;
+pub(crate) const DDUP_ERR_ABORTED: c_int = CDdupError::DDUP_ERR_ABORTED as c_int;
+pub(crate) const DDUP_ERR_CONCURRENT_USE: c_int = CDdupError::DDUP_ERR_CONCURRENT_USE as c_int;
+pub(crate) const DDUP_ERR_BUFFER_TOO_SMALL: c_int = CDdupError::DDUP_ERR_BUFFER_TOO_SMALL as c_int;
+pub(crate) const DDUP_ERR_LOCK: c_int = CDdupError::DDUP_ERR_LOCK as c_int;
+pub(crate) const DDUP_ERR_EOF: c_int = CDdupError::DDUP_ERR_EOF as c_int;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Records the message returned by `last_error_message` for the calling thread.
+pub(crate) fn set_last_error(message: impl std::fmt::Display) {
+    let message =
+        CString::new(message.to_string().replace('\0', "")).expect("nul bytes were removed");
+
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+/// Returns the error message of the last failed call on this thread, or NULL if
+/// there is none. Only functions documented to set it do so. The string is owned
+/// by the library and stays valid until the next failing call on the same thread,
+/// it must not be freed.
+#[no_mangle]
+pub extern "C" fn last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Returns the version of this library, the string is static and must not be freed.
+#[no_mangle]
+pub extern "C" fn ddup_bak_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// Returns the `DDUP_BAK_ABI_VERSION` this library was built with.
+#[no_mangle]
+pub extern "C" fn ddup_bak_abi_version() -> c_uint {
+    DDUP_BAK_ABI_VERSION
+}
+
+/// Returns whether this library was built with the optional feature `name`, one of
+/// `brotli`, `xz`, `zstd`, `mmap`, `s3` and `mount`. `zstd`, `s3` and `mount` are not
+/// offered by this version yet and always return false, like unknown names and NULL.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn ddup_bak_has_feature(name: *const c_char) -> bool {
+    if name.is_null() {
+        return false;
+    }
+
+    match unsafe { CStr::from_ptr(name) }.to_bytes() {
+        b"brotli" => cfg!(feature = "brotli"),
+        b"xz" => cfg!(feature = "xz"),
+        b"mmap" => cfg!(feature = "mmap"),
+        b"zstd" | b"s3" | b"mount" => false,
+        _ => false,
+    }
+}
+
+/// Frees a string returned by this library, NULL is ignored.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn free_string(ptr: *mut c_char) {
@@ -17,6 +138,29 @@ pub unsafe extern "C" fn free_string(ptr: *mut c_char) {
     }
 }
 
+/// Hands out `items` as a NULL-terminated array, every array returned by this
+/// library is built this way and freed with `free_null_terminated`.
+pub(crate) fn null_terminated<T>(mut items: Vec<*mut T>) -> *mut *mut T {
+    items.push(std::ptr::null_mut());
+
+    Box::into_raw(items.into_boxed_slice()) as *mut *mut T
+}
+
+/// Frees an array built by `null_terminated`, returning its items.
+pub(crate) unsafe fn free_null_terminated<T>(ptr: *mut *mut T) -> Vec<*mut T> {
+    let mut length = 0;
+    while !unsafe { *ptr.add(length) }.is_null() {
+        length += 1;
+    }
+
+    let array = unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, length + 1)) };
+    let mut items = array.into_vec();
+    items.pop();
+
+    items
+}
+
+/// Frees a NULL-terminated string array, like the one returned by `repository_list_archives`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn free_string_array(ptr: *mut *mut c_char) {
@@ -24,13 +168,354 @@ pub unsafe extern "C" fn free_string_array(ptr: *mut *mut c_char) {
         return;
     }
 
-    unsafe {
-        let mut i = 0;
-        while !(*ptr.add(i)).is_null() {
-            let _ = CString::from_raw(*ptr.add(i));
-            i += 1;
+    for string in unsafe { free_null_terminated(ptr) } {
+        let _ = unsafe { CString::from_raw(string) };
+    }
+}
+
+/// Catches functions requiring exclusive access to a handle overlapping with another
+/// call on it. Only debug builds check, release builds trust the caller. The flag lives
+/// in its own allocation so a token does not borrow the handle it guards.
+#[derive(Default)]
+pub(crate) struct UseGuard {
+    #[cfg(debug_assertions)]
+    in_use: Arc<AtomicBool>,
+}
+
+/// Marks a handle as used until it is dropped.
+pub(crate) struct UseToken {
+    #[cfg(debug_assertions)]
+    in_use: Arc<AtomicBool>,
+}
+
+impl UseGuard {
+    /// Marks the handle as used, setting the last error if it already is.
+    pub(crate) fn enter(&self) -> Result<UseToken, c_int> {
+        #[cfg(debug_assertions)]
+        if self.in_use.swap(true, Ordering::Acquire) {
+            set_last_error("handle used concurrently");
+            return Err(DDUP_ERR_CONCURRENT_USE);
+        }
+
+        Ok(UseToken {
+            #[cfg(debug_assertions)]
+            in_use: Arc::clone(&self.in_use),
+        })
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for UseToken {
+    fn drop(&mut self) {
+        self.in_use.store(false, Ordering::Release);
+    }
+}
+
+/// Reads a required UTF-8 string argument, setting the last error if it is NULL or invalid.
+pub(crate) unsafe fn utf8_argument<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, c_int> {
+    if ptr.is_null() {
+        set_last_error(format!("{name} is NULL"));
+        return Err(DDUP_ERR_NULL_ARG);
+    }
+
+    unsafe { CStr::from_ptr(ptr) }.to_str().map_err(|_| {
+        set_last_error(format!("{name} is not valid UTF-8"));
+        DDUP_ERR_NULL_ARG
+    })
+}
+
+/// Reads a required path argument. On unix the bytes are taken as they are, elsewhere
+/// they have to be valid UTF-8, see the `_w` functions for wide paths on windows.
+pub(crate) unsafe fn path_argument(ptr: *const c_char, name: &str) -> Result<PathBuf, c_int> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        if ptr.is_null() {
+            set_last_error(format!("{name} is NULL"));
+            return Err(DDUP_ERR_NULL_ARG);
+        }
+
+        Ok(PathBuf::from(OsStr::from_bytes(
+            unsafe { CStr::from_ptr(ptr) }.to_bytes(),
+        )))
+    }
+    #[cfg(not(unix))]
+    {
+        unsafe { utf8_argument(ptr, name) }.map(PathBuf::from)
+    }
+}
+
+/// Converts a path handed to the caller, keeping its bytes on unix and replacing what
+/// is not valid UTF-8 elsewhere. Returns `None` if it contains a nul byte.
+pub(crate) fn path_c_string(path: &Path) -> Option<CString> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        CString::new(path.as_os_str().as_bytes()).ok()
+    }
+    #[cfg(not(unix))]
+    {
+        CString::new(path.to_string_lossy().into_owned()).ok()
+    }
+}
+
+/// Records `err` as the last error and maps it to a `DDUP_ERR_*` code.
+pub(crate) fn error_code(err: std::io::Error) -> c_int {
+    set_last_error(&err);
+
+    let code = match err.kind() {
+        std::io::ErrorKind::NotFound => CDdupError::DDUP_ERR_NOT_FOUND,
+        std::io::ErrorKind::AlreadyExists => CDdupError::DDUP_ERR_EXISTS,
+        std::io::ErrorKind::InvalidInput => CDdupError::DDUP_ERR_NULL_ARG,
+        std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => {
+            CDdupError::DDUP_ERR_CORRUPT
+        }
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => CDdupError::DDUP_ERR_LOCK,
+        std::io::ErrorKind::Interrupted => CDdupError::DDUP_ERR_ABORTED,
+        std::io::ErrorKind::Unsupported => CDdupError::DDUP_ERR_UNSUPPORTED,
+        _ => CDdupError::DDUP_ERR_IO,
+    };
+
+    code as c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{archive::*, convert::*, iter::*, reader::*, repository::*, verify::*};
+    use std::ptr::{null, null_mut};
+
+    #[test]
+    fn error_codes_keep_their_values() {
+        assert_eq!(CDdupError::DDUP_ERR_NULL_ARG as c_int, -1);
+        assert_eq!(CDdupError::DDUP_ERR_NOT_FOUND as c_int, -2);
+        assert_eq!(CDdupError::DDUP_ERR_EXISTS as c_int, -3);
+        assert_eq!(CDdupError::DDUP_ERR_IO as c_int, -4);
+        assert_eq!(CDdupError::DDUP_ERR_LOCK as c_int, -5);
+        assert_eq!(CDdupError::DDUP_ERR_CORRUPT as c_int, -6);
+        assert_eq!(CDdupError::DDUP_ERR_UNSUPPORTED as c_int, -7);
+    }
+
+    /// Every function returning an error code rejects NULL handles with
+    /// `DDUP_ERR_NULL_ARG` and a message instead of dereferencing them.
+    #[test]
+    fn null_arguments_return_null_arg() {
+        let check = |name: &str, code: c_int| {
+            assert_eq!(code, DDUP_ERR_NULL_ARG, "{name}");
+            assert!(!last_error_message().is_null(), "{name}");
+        };
+
+        unsafe {
+            check("repository_save", repository_save(null_mut()));
+            check("repository_flush", repository_flush(null_mut()));
+            check(
+                "repository_set_lock_backend",
+                repository_set_lock_backend(null_mut(), DDUP_LOCK_BACKEND_POLLED),
+            );
+            check(
+                "repository_chunk_stats",
+                repository_chunk_stats(null_mut(), null_mut()),
+            );
+            check(
+                "repository_try_lock",
+                repository_try_lock(null_mut(), 1, null_mut()),
+            );
+            check(
+                "repository_lock_status",
+                repository_lock_status(null_mut(), null_mut()),
+            );
+            check(
+                "repository_chunk_references",
+                repository_chunk_references(null_mut(), 1) as c_int,
+            );
+            check(
+                "repository_clean_plan",
+                repository_clean_plan(null_mut(), null_mut()),
+            );
+            check(
+                "repository_clean",
+                repository_clean(null_mut(), None, null_mut(), null_mut()),
+            );
+            check(
+                "repository_delete_archive",
+                repository_delete_archive(null_mut(), null(), None, null_mut()),
+            );
+            check(
+                "repository_delete_archive_ex",
+                repository_delete_archive_ex(null_mut(), null(), None, null_mut()),
+            );
+            check(
+                "repository_restore_archive_ex",
+                repository_restore_archive_ex(
+                    null_mut(),
+                    null(),
+                    null(),
+                    0,
+                    None,
+                    null_mut(),
+                    1,
+                    null_mut(),
+                ),
+            );
+            check(
+                "repository_restore_archive_to",
+                repository_restore_archive_to(null_mut(), null(), null(), None, null_mut(), 1),
+            );
+            check(
+                "repository_restore_paths",
+                repository_restore_paths(
+                    null_mut(),
+                    null(),
+                    null(),
+                    0,
+                    null(),
+                    None,
+                    null_mut(),
+                    1,
+                ),
+            );
+            check(
+                "repository_verify_archive",
+                repository_verify_archive(null_mut(), null(), 0, None, null_mut(), 1, null_mut()),
+            );
+            check(
+                "repository_convert_to_tar",
+                repository_convert_to_tar(null_mut(), null(), false, None, null_mut()),
+            );
+            check(
+                "repository_read_entry",
+                repository_read_entry(null_mut(), null(), null(), None, null_mut()),
+            );
+            check(
+                "repository_read_entry_to_file",
+                repository_read_entry_to_file(null_mut(), null(), null(), null()),
+            );
+            check(
+                "repository_archive_iter_next",
+                repository_archive_iter_next(null_mut(), null_mut(), 0, null_mut()),
+            );
+
+            check(
+                "archive_add_directory",
+                archive_add_directory(null_mut(), null(), None, null_mut()),
+            );
+            check(
+                "archive_add_symlink",
+                archive_add_symlink(null_mut(), null(), null(), false, 0o777, 0, 0, 0),
+            );
+            check(
+                "archive_add_empty_directory",
+                archive_add_empty_directory(null_mut(), null(), 0o755, 0, 0, 0),
+            );
+            check("archive_finalize", archive_finalize(null_mut()));
+
+            check(
+                "entry_reader_read",
+                entry_reader_read(null_mut(), null_mut(), 0),
+            );
+            check(
+                "entry_reader_read_exact",
+                entry_reader_read_exact(null_mut(), null_mut(), 0),
+            );
+            check(
+                "entry_reader_read_all",
+                entry_reader_read_all(null_mut(), None, null_mut()) as c_int,
+            );
+            check("entry_reader_seek", entry_reader_seek(null_mut(), 0));
+            check(
+                "entry_reader_skip",
+                entry_reader_skip(null_mut(), 0) as c_int,
+            );
+        }
+    }
+
+    /// Functions returning handles return NULL for NULL arguments.
+    #[test]
+    fn null_arguments_return_null_handles() {
+        unsafe {
+            assert!(new_repository(null(), 1024, 0).is_null());
+            assert!(open_repository(null(), null()).is_null());
+            assert!(repository_clone_handle(null()).is_null());
+            assert!(repository_get_archive(null_mut(), null()).is_null());
+            assert!(repository_list_archives(null_mut(), null_mut()).is_null());
+            assert!(repository_archive_iter_new(null_mut()).is_null());
+            assert!(new_archive(null()).is_null());
+            assert!(open_archive(null()).is_null());
+            assert!(archive_find_entry(null(), null()).is_null());
+            assert!(entry_open_reader(null_mut(), null()).is_null());
+            assert!(archive_open_entry_by_path(null_mut(), null(), null()).is_null());
+            assert!(archive_write_file_entry(
+                null_mut(),
+                null(),
+                0o644,
+                0,
+                0,
+                0,
+                CCompressionFormat::None,
+                None,
+                null_mut()
+            )
+            .is_null());
+
+            // freeing NULL is a no-op
+            assert_eq!(repository_unlock(null_mut()), 0);
+            free_repository(null_mut());
+            free_archive(null_mut());
+            free_entry_reader(null_mut());
+            free_string(null_mut());
+        }
+    }
+
+    #[test]
+    fn setters_refuse_a_handle_in_use() {
+        let directory =
+            std::env::temp_dir().join(format!("ddup-bak-c-setters-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let repo = CRepository::from_repository(
+            ddup_bak::repository::Repository::new(&directory, 1024, 0, None).unwrap(),
+        );
+
+        unsafe {
+            let in_use = CRepository::as_handle(repo).repository();
+            assert_eq!(
+                repository_set_lock_backend(repo, DDUP_LOCK_BACKEND_NATIVE),
+                DDUP_ERR_CONCURRENT_USE
+            );
+            assert!(repository_set_save_on_drop(repo, false).is_null());
+            drop(in_use);
+
+            assert_eq!(
+                repository_set_lock_backend(repo, DDUP_LOCK_BACKEND_NATIVE),
+                0
+            );
+            assert_eq!(repository_set_save_on_drop(repo, false), repo);
+            free_repository(repo);
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn features_match_the_build() {
+        let has = |name: &str| {
+            let name = CString::new(name).unwrap();
+            unsafe { ddup_bak_has_feature(name.as_ptr()) }
+        };
+
+        assert_eq!(has("brotli"), cfg!(feature = "brotli"));
+        assert_eq!(has("xz"), cfg!(feature = "xz"));
+        assert_eq!(has("mmap"), cfg!(feature = "mmap"));
+        for name in ["zstd", "s3", "mount", "unknown", ""] {
+            assert!(!has(name), "{name:?}");
         }
+        assert!(!unsafe { ddup_bak_has_feature(null()) });
 
-        let _ = Box::from_raw(ptr);
+        assert_eq!(ddup_bak_abi_version(), DDUP_BAK_ABI_VERSION);
+        let version = unsafe { CStr::from_ptr(ddup_bak_version()) };
+        assert_eq!(version.to_str().unwrap(), env!("CARGO_PKG_VERSION"));
     }
 }
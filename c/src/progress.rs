@@ -0,0 +1,170 @@
+use crate::path_c_string;
+use ddup_bak::archive::entries::Entry;
+use ddup_bak::chunks::ids::ChunkIdDecoder;
+use ddup_bak::repository::{DeletionProgressCallback, ProgressEvent, ProgressEventCallback};
+use std::ffi::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CProgressEventKind {
+    /// The totals are known, reported once before any other event.
+    ScanComplete = 0,
+    /// A file was processed, `path` is set.
+    FileDone = 1,
+    /// File content was processed, `current_bytes` grew.
+    BytesProcessed = 2,
+    /// A chunk reference of a deleted archive was released.
+    ChunkReleased = 3,
+}
+
+/// A progress event passed to a `CProgressEventCallback`. `path` is NULL unless the
+/// kind is `FileDone`, the event and the string are only valid during the call.
+///
+/// `current_items` and `total_items` count regular files, or chunk references when
+/// deleting an archive. `current_bytes` and `total_bytes` count the real size of file
+/// content and stay 0 when deleting.
+#[repr(C)]
+pub struct CProgressEvent {
+    pub kind: CProgressEventKind,
+    pub path: *const c_char,
+    pub current_items: u64,
+    pub total_items: u64,
+    pub current_bytes: u64,
+    pub total_bytes: u64,
+}
+
+/// Receives a progress event and the `user_data` passed along with the callback, it
+/// may be called from other threads.
+pub type CProgressEventCallback =
+    Option<extern "C" fn(event: *const CProgressEvent, user_data: *mut c_void)>;
+
+/// Running counts of an operation reporting to a `CProgressEventCallback`.
+struct EventCounter {
+    callback: extern "C" fn(*const CProgressEvent, *mut c_void),
+    user_data: usize,
+
+    items: AtomicU64,
+    total_items: AtomicU64,
+    bytes: AtomicU64,
+    total_bytes: AtomicU64,
+}
+
+impl EventCounter {
+    fn emit(&self, kind: CProgressEventKind, path: *const c_char) {
+        let event = CProgressEvent {
+            kind,
+            path,
+            current_items: self.items.load(Ordering::Relaxed),
+            total_items: self.total_items.load(Ordering::Relaxed),
+            current_bytes: self.bytes.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+        };
+
+        (self.callback)(&event, self.user_data as *mut c_void);
+    }
+
+    fn scan_complete(&self, files: u64, bytes: u64) {
+        self.total_items.store(files, Ordering::Relaxed);
+        self.total_bytes.store(bytes, Ordering::Relaxed);
+        self.emit(CProgressEventKind::ScanComplete, std::ptr::null());
+    }
+}
+
+fn event_counter(
+    callback: CProgressEventCallback,
+    user_data: *mut c_void,
+) -> Option<Arc<EventCounter>> {
+    callback.map(|callback| {
+        Arc::new(EventCounter {
+            callback,
+            user_data: user_data as usize,
+            items: AtomicU64::new(0),
+            total_items: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(0),
+        })
+    })
+}
+
+/// Adapts a `CProgressEventCallback` to the events of the library. If `totals` are
+/// given, they are reported as `ScanComplete` right away, otherwise the operation is
+/// expected to report them itself.
+pub(crate) fn wrap_progress_event_callback(
+    callback: CProgressEventCallback,
+    user_data: *mut c_void,
+    totals: Option<(u64, u64)>,
+) -> ProgressEventCallback {
+    let counter = event_counter(callback, user_data)?;
+
+    if let Some((files, bytes)) = totals {
+        counter.scan_complete(files, bytes);
+    }
+
+    Some(Arc::new(move |event: ProgressEvent| match event {
+        ProgressEvent::ScanComplete { files, bytes } => counter.scan_complete(files, bytes),
+        ProgressEvent::FileDone { path, .. } => {
+            counter.items.fetch_add(1, Ordering::Relaxed);
+
+            let c_path = path_c_string(path).unwrap_or_default();
+            counter.emit(CProgressEventKind::FileDone, c_path.as_ptr());
+        }
+        ProgressEvent::BytesProcessed(bytes) => {
+            counter.bytes.fetch_add(bytes, Ordering::Relaxed);
+            counter.emit(CProgressEventKind::BytesProcessed, std::ptr::null());
+        }
+    }))
+}
+
+/// Adapts a `CProgressEventCallback` to the chunk callback of a delete, reporting the
+/// `chunk_references` of the archive as `ScanComplete` right away.
+pub(crate) fn wrap_deletion_event_callback(
+    callback: CProgressEventCallback,
+    user_data: *mut c_void,
+    chunk_references: u64,
+) -> DeletionProgressCallback {
+    let counter = event_counter(callback, user_data)?;
+    counter.scan_complete(chunk_references, 0);
+
+    Some(Arc::new(move |_chunk_id: u64, _deleted: bool| {
+        counter.items.fetch_add(1, Ordering::Relaxed);
+        counter.emit(CProgressEventKind::ChunkReleased, std::ptr::null());
+    }))
+}
+
+/// Counts the regular files below `entries` and their real size.
+pub(crate) fn entry_totals(entries: &[Entry]) -> std::io::Result<(u64, u64)> {
+    entries
+        .iter()
+        .try_fold((0, 0), |(files, bytes), entry| match entry {
+            Entry::File(file) => Ok((files + 1, bytes + file.size_real)),
+            Entry::Directory(directory) => {
+                let (sub_files, sub_bytes) = entry_totals(directory.entries()?)?;
+                Ok((files + sub_files, bytes + sub_bytes))
+            }
+            Entry::Symlink(_) => Ok((files, bytes)),
+        })
+}
+
+/// Counts the chunk references of the files below `entries`, decoding their chunk lists.
+pub(crate) fn chunk_references(entries: &[Entry]) -> std::io::Result<u64> {
+    let mut references = 0;
+
+    for entry in entries {
+        match entry {
+            Entry::File(file) => {
+                let mut file = file.clone();
+                let mut ids = ChunkIdDecoder::new(&file);
+
+                while ids.next_id(&mut file)?.is_some() {
+                    references += 1;
+                }
+            }
+            Entry::Directory(directory) => references += chunk_references(directory.entries()?)?,
+            Entry::Symlink(_) => {}
+        }
+    }
+
+    Ok(references)
+}
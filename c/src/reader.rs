@@ -1,13 +1,22 @@
-use crate::archive::CCompressionFormat;
-use crate::entries::CFileEntry;
-use ddup_bak::archive::entries::{Entry, EntryMode, FileEntry};
+use crate::archive::CArchive;
+use crate::entries::{entry_as_file, entry_file_token, CEntry};
+use crate::repository::CRepository;
+use crate::{
+    error_code, path_argument, set_last_error, utf8_argument, DDUP_ERR_ABORTED, DDUP_ERR_EOF,
+    DDUP_ERR_NOT_FOUND, DDUP_ERR_NULL_ARG,
+};
+use ddup_bak::archive::entries::Entry;
 use ddup_bak::chunks::reader::EntryReader;
 use std::ffi::*;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::ops::{Deref, DerefMut};
+use std::path::Path;
 use std::slice;
-use std::sync::Arc;
-use std::time::SystemTime;
+
+/// Receives the content of a file in pieces of at most a few KiB, returning
+/// anything but 0 stops reading.
+pub type CWriteCallback =
+    Option<extern "C" fn(data: *const u8, length: usize, user_data: *mut c_void) -> c_int>;
 
 #[repr(C)]
 pub struct CEntryReader {
@@ -32,66 +41,138 @@ impl DerefMut for EntryReaderHandle {
     }
 }
 
+fn open_entry_reader(repo: &CRepository, entry: &Entry) -> *mut CEntryReader {
+    match repo.repository().entry_reader(entry.clone()) {
+        Ok(reader) => {
+            let handle = Box::new(EntryReaderHandle {
+                inner: Box::new(reader),
+            });
+
+            Box::into_raw(handle) as *mut CEntryReader
+        }
+        Err(err) => {
+            set_last_error(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Opens a reader for a file entry returned by the functions of `archive`, like
+/// `archive_entries`. The entry is looked up in the archive by its name and offset,
+/// so it must come from the same archive. Returns NULL on failure, see
+/// `last_error_message`. The reader is freed with `free_entry_reader`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
-pub unsafe extern "C" fn repository_create_entry_reader(
-    repo: *mut crate::repository::CRepository,
-    entry: *const CFileEntry,
+pub unsafe extern "C" fn archive_entry_open_reader(
+    repo: *mut CRepository,
+    archive: *const CArchive,
+    entry: *const CEntry,
 ) -> *mut CEntryReader {
-    if repo.is_null() || entry.is_null() {
+    if repo.is_null() || archive.is_null() {
+        set_last_error("repository or archive is NULL");
         return std::ptr::null_mut();
     }
 
-    let repo = &*repo;
-    let entry = &*entry;
+    let file = entry_as_file(entry);
+    if file.is_null() {
+        set_last_error("entry is NULL or not a file");
+        return std::ptr::null_mut();
+    }
 
-    let name = match CStr::from_ptr(entry.common.name).to_str() {
-        Ok(s) => s.to_string(),
+    let file = &*file;
+    let name = match utf8_argument(file.common.name, "entry name") {
+        Ok(name) => name,
         Err(_) => return std::ptr::null_mut(),
     };
 
-    let file_arc = if !entry.file.is_null() {
-        let file_ref = &*(entry.file as *const std::fs::File);
-        Arc::new(
-            file_ref
-                .try_clone()
-                .unwrap_or_else(|_| std::fs::File::open("/dev/null").unwrap()),
-        )
-    } else {
+    let archive = &*archive;
+    let found = archive.walk().find(|item| match item {
+        Ok((_, _, Entry::File(archive_file))) => {
+            archive_file.offset == file.offset && archive_file.name == name
+        }
+        Ok(_) => false,
+        Err(_) => true,
+    });
+
+    match found {
+        Some(Ok((_, _, archive_entry))) => open_entry_reader(&*repo, archive_entry),
+        Some(Err(err)) => {
+            error_code(err);
+            std::ptr::null_mut()
+        }
+        None => {
+            set_last_error(format!("{name} is not part of the archive"));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Opens a reader for a file entry returned by this library, using the reference to its
+/// archive stored in the entry, so the archive handle may already be freed. Returns
+/// NULL on failure, see `last_error_message`. The reader is freed with
+/// `free_entry_reader`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn entry_open_reader(
+    repo: *mut CRepository,
+    entry: *const CEntry,
+) -> *mut CEntryReader {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
         return std::ptr::null_mut();
-    };
+    }
 
-    let file_entry = FileEntry {
-        name,
-        mode: EntryMode::from(entry.common.mode),
-        owner: (entry.common.uid, entry.common.gid),
-        mtime: SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(entry.common.mtime),
-        compression: entry.compression.into(),
-        size_compressed: if matches!(entry.compression, CCompressionFormat::None) {
-            None
-        } else {
-            Some(entry.size_compressed)
-        },
-        size_real: entry.size_real,
-        size: entry.size,
-        file: file_arc,
-        offset: entry.offset,
-        decoder: None,
-        consumed: 0,
-    };
+    match entry_file_token(entry) {
+        Some(file) => open_entry_reader(&*repo, &Entry::File(Box::new(file.clone()))),
+        None => {
+            set_last_error("entry is NULL or not a file");
+            std::ptr::null_mut()
+        }
+    }
+}
 
-    match repo.entry_reader(Entry::File(Box::new(file_entry))) {
-        Ok(reader) => {
-            let handle = Box::new(EntryReaderHandle {
-                inner: Box::new(reader),
-            });
+/// Opens a reader for the file at `path` in `archive`. Returns NULL on failure, see
+/// `last_error_message`. The reader is freed with `free_entry_reader`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn archive_open_entry_by_path(
+    repo: *mut CRepository,
+    archive: *const CArchive,
+    path: *const c_char,
+) -> *mut CEntryReader {
+    if repo.is_null() || archive.is_null() {
+        set_last_error("repository or archive is NULL");
+        return std::ptr::null_mut();
+    }
 
-            Box::into_raw(handle) as *mut CEntryReader
+    let path = match utf8_argument(path, "path") {
+        Ok(path) => path,
+        Err(_) => return std::ptr::null_mut(),
+    };
+
+    let archive = &*archive;
+    match archive.find_archive_entry(Path::new(path)) {
+        Ok(Some(entry @ Entry::File(_))) => open_entry_reader(&*repo, entry),
+        Ok(Some(_)) => {
+            set_last_error(format!("{path} is not a file"));
+            std::ptr::null_mut()
+        }
+        Ok(None) => {
+            set_last_error(format!("{path} not found in archive"));
+            std::ptr::null_mut()
+        }
+        Err(err) => {
+            error_code(err);
+            std::ptr::null_mut()
         }
-        Err(_) => std::ptr::null_mut(),
     }
 }
 
+/// Reads up to `buffer_size` bytes, at most `INT_MAX`, into `buffer` and returns how
+/// many were read, 0 at the end of the file. Returns `DDUP_ERR_NULL_ARG` for
+/// NULL arguments, `DDUP_ERR_NOT_FOUND` for a missing chunk, `DDUP_ERR_CORRUPT` for
+/// one that cannot be decoded or another negative `DDUP_ERR_*` code, see
+/// `last_error_message`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn entry_reader_read(
@@ -100,18 +181,162 @@ pub unsafe extern "C" fn entry_reader_read(
     buffer_size: usize,
 ) -> c_int {
     if reader.is_null() || buffer.is_null() {
-        return -1;
+        set_last_error("reader or buffer is NULL");
+        return DDUP_ERR_NULL_ARG;
     }
 
     let reader_handle = &mut *(reader as *mut EntryReaderHandle);
-    let buf_slice = slice::from_raw_parts_mut(buffer as *mut u8, buffer_size);
+    let buf_slice =
+        slice::from_raw_parts_mut(buffer as *mut u8, buffer_size.min(c_int::MAX as usize));
 
     match reader_handle.read(buf_slice) {
         Ok(bytes_read) => bytes_read as c_int,
-        Err(_) => -1,
+        Err(err) => error_code(err),
+    }
+}
+
+/// Reads exactly `buffer_size` bytes into `buffer`, looping over short reads. Returns 0,
+/// `DDUP_ERR_EOF` if the file ended first, in which case the bytes read so far are in
+/// `buffer` and the reader is at the end, or a negative `DDUP_ERR_*` code like
+/// `entry_reader_read`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn entry_reader_read_exact(
+    reader: *mut CEntryReader,
+    buffer: *mut c_char,
+    buffer_size: usize,
+) -> c_int {
+    if reader.is_null() || (buffer.is_null() && buffer_size > 0) {
+        set_last_error("reader or buffer is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+    if buffer_size == 0 {
+        return 0;
+    }
+
+    let reader_handle = &mut *(reader as *mut EntryReaderHandle);
+    let buf_slice = slice::from_raw_parts_mut(buffer as *mut u8, buffer_size);
+
+    let mut filled = 0;
+    while filled < buffer_size {
+        match reader_handle.read(&mut buf_slice[filled..]) {
+            Ok(0) => {
+                set_last_error(format!("end of file after {filled} of {buffer_size} bytes"));
+                return DDUP_ERR_EOF;
+            }
+            Ok(bytes_read) => filled += bytes_read,
+            Err(err) => return error_code(err),
+        }
+    }
+
+    0
+}
+
+/// Streams the rest of the file to `write_callback`, which is called with `user_data`
+/// on the calling thread until the end of the file or until it returns nonzero.
+///
+/// Returns the number of bytes passed to the callback, `DDUP_ERR_ABORTED` if it
+/// stopped reading or another negative `DDUP_ERR_*` code like `entry_reader_read`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn entry_reader_read_all(
+    reader: *mut CEntryReader,
+    write_callback: CWriteCallback,
+    user_data: *mut c_void,
+) -> i64 {
+    if reader.is_null() {
+        set_last_error("reader is NULL");
+        return DDUP_ERR_NULL_ARG as i64;
+    }
+    let Some(callback) = write_callback else {
+        set_last_error("write_callback is NULL");
+        return DDUP_ERR_NULL_ARG as i64;
+    };
+
+    let reader_handle = &mut *(reader as *mut EntryReaderHandle);
+    let mut writer = CallbackWriter {
+        callback,
+        user_data,
+        aborted: false,
+    };
+
+    match std::io::copy(&mut **reader_handle, &mut writer) {
+        Ok(written) => written as i64,
+        Err(_) if writer.aborted => {
+            set_last_error("aborted by the write callback");
+            DDUP_ERR_ABORTED as i64
+        }
+        Err(err) => error_code(err) as i64,
     }
 }
 
+/// Moves the reader to `offset` bytes from the start of the file, offsets past the
+/// end move it to the end. Seeking backward starts over from the beginning, forward
+/// seeks only read the chunk the new position falls into.
+///
+/// Returns 0 or a negative `DDUP_ERR_*` code like `entry_reader_read`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn entry_reader_seek(reader: *mut CEntryReader, offset: u64) -> c_int {
+    if reader.is_null() {
+        set_last_error("reader is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let reader_handle = &mut *(reader as *mut EntryReaderHandle);
+
+    match reader_handle.seek(SeekFrom::Start(offset)) {
+        Ok(_) => 0,
+        Err(err) => error_code(err),
+    }
+}
+
+/// Skips up to `count` bytes, returning the number of bytes skipped, which is only
+/// less than `count` at the end of the file, or a negative `DDUP_ERR_*` code like
+/// `entry_reader_read`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn entry_reader_skip(reader: *mut CEntryReader, count: u64) -> i64 {
+    if reader.is_null() {
+        set_last_error("reader is NULL");
+        return DDUP_ERR_NULL_ARG as i64;
+    }
+
+    let reader_handle = &mut *(reader as *mut EntryReaderHandle);
+
+    match reader_handle.skip(count) {
+        Ok(skipped) => skipped as i64,
+        Err(err) => error_code(err) as i64,
+    }
+}
+
+/// Returns the number of bytes read or skipped so far, 0 for a NULL reader.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn entry_reader_position(reader: *const CEntryReader) -> u64 {
+    if reader.is_null() {
+        return 0;
+    }
+
+    let reader_handle = &*(reader as *const EntryReaderHandle);
+
+    reader_handle.position()
+}
+
+/// Returns the number of bytes left until the end of the file, 0 for a NULL reader.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn entry_reader_remaining(reader: *const CEntryReader) -> u64 {
+    if reader.is_null() {
+        return 0;
+    }
+
+    let reader_handle = &*(reader as *const EntryReaderHandle);
+
+    reader_handle.remaining()
+}
+
+/// Frees an entry reader, NULL is ignored.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn free_entry_reader(reader: *mut CEntryReader) {
@@ -119,3 +344,384 @@ pub unsafe extern "C" fn free_entry_reader(reader: *mut CEntryReader) {
         let _ = Box::from_raw(reader as *mut EntryReaderHandle);
     }
 }
+
+/// Passes everything written on to a `CWriteCallback`, remembering if it asked to stop.
+struct CallbackWriter {
+    callback: extern "C" fn(*const u8, usize, *mut c_void) -> c_int,
+    user_data: *mut c_void,
+    aborted: bool,
+}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if (self.callback)(buf.as_ptr(), buf.len(), self.user_data) != 0 {
+            self.aborted = true;
+
+            return Err(std::io::Error::other("Aborted by the write callback"));
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Finds the file at `path` in an archive, setting the last error if it is missing or not a file.
+unsafe fn find_file_entry(
+    repo: *mut CRepository,
+    archive_name: *const c_char,
+    path: *const c_char,
+) -> Result<Entry, c_int> {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return Err(DDUP_ERR_NULL_ARG);
+    }
+
+    let repo = (*repo).repository();
+    let archive_name = utf8_argument(archive_name, "archive_name")?;
+    let path = utf8_argument(path, "path")?;
+
+    let archive = repo.get_archive(archive_name).map_err(error_code)?;
+    match archive
+        .find_archive_entry(Path::new(path))
+        .map_err(error_code)?
+    {
+        Some(entry @ Entry::File(_)) => Ok(entry.clone()),
+        Some(_) => {
+            set_last_error(format!("{path} is not a file"));
+            Err(DDUP_ERR_NULL_ARG)
+        }
+        None => {
+            set_last_error(format!("{path} not found in archive {archive_name}"));
+            Err(DDUP_ERR_NOT_FOUND)
+        }
+    }
+}
+
+/// Streams the content of the file at `path` in an archive to `write_callback`,
+/// which is called with `user_data` on the calling thread until the whole file
+/// was passed or it returns nonzero.
+///
+/// Returns 0 on success, `DDUP_ERR_ABORTED` if the callback stopped reading,
+/// `DDUP_ERR_NULL_ARG` for NULL arguments or a path that is not a file,
+/// `DDUP_ERR_NOT_FOUND` if the archive or path does not exist or another negative
+/// code like `entry_reader_read`, see `last_error_message`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_read_entry(
+    repo: *mut CRepository,
+    archive_name: *const c_char,
+    path: *const c_char,
+    write_callback: CWriteCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let Some(callback) = write_callback else {
+        set_last_error("write_callback is NULL");
+        return DDUP_ERR_NULL_ARG;
+    };
+
+    let entry = match find_file_entry(repo, archive_name, path) {
+        Ok(entry) => entry,
+        Err(code) => return code,
+    };
+
+    let mut writer = CallbackWriter {
+        callback,
+        user_data,
+        aborted: false,
+    };
+
+    match (*repo).repository().read_entry_content(entry, &mut writer) {
+        Ok(()) => 0,
+        Err(_) if writer.aborted => {
+            set_last_error("aborted by the write callback");
+            DDUP_ERR_ABORTED
+        }
+        Err(err) => error_code(err),
+    }
+}
+
+/// Writes the content of the file at `path` in an archive to a new file at
+/// `destination`, replacing an existing one. Nothing is left at `destination`
+/// on failure. Returns 0 or a negative `DDUP_ERR_*` code like `repository_read_entry`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_read_entry_to_file(
+    repo: *mut CRepository,
+    archive_name: *const c_char,
+    path: *const c_char,
+    destination: *const c_char,
+) -> c_int {
+    let entry = match find_file_entry(repo, archive_name, path) {
+        Ok(entry) => entry,
+        Err(code) => return code,
+    };
+    let destination = match path_argument(destination, "destination") {
+        Ok(destination) => destination,
+        Err(code) => return code,
+    };
+
+    let result = std::fs::File::create(&destination).and_then(|file| {
+        let mut writer = std::io::BufWriter::new(file);
+
+        (*repo)
+            .repository()
+            .read_entry_content(entry, &mut writer)?;
+        writer.flush()
+    });
+
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            std::fs::remove_file(&destination).ok();
+            error_code(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{archive_entries2, archive_find_entry, free_archive};
+    use crate::entries::{entry_name, free_entry, free_entry_array};
+    use crate::repository::{free_repository, open_repository, repository_get_archive};
+    use crate::test_util::{c_path, c_string, file_content, Fixture};
+    use ddup_bak::repository::{CreateOptions, Repository};
+    use std::ptr::null_mut;
+
+    #[test]
+    fn seek_skip_and_position() {
+        let directory =
+            std::env::temp_dir().join(format!("libddupbak-reader-seek-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        let source = directory.join("source");
+        std::fs::create_dir_all(&source).unwrap();
+        let content = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(source.join("file"), &content).unwrap();
+
+        let repository = Repository::new(&directory.join("repository"), 64, 0, None).unwrap();
+        repository
+            .create_archive(
+                "archive",
+                Some(ignore::WalkBuilder::new(&source).build()),
+                Some(&source),
+                None,
+                None,
+                1,
+                CreateOptions::default(),
+            )
+            .unwrap();
+        drop(repository);
+
+        let repository_path = CString::new(directory.join("repository").to_str().unwrap()).unwrap();
+        let name = CString::new("archive").unwrap();
+        let path = CString::new("file").unwrap();
+
+        unsafe {
+            let repo = open_repository(repository_path.as_ptr(), std::ptr::null());
+            assert!(!repo.is_null());
+            let archive = repository_get_archive(repo, name.as_ptr());
+            assert!(!archive.is_null());
+            let reader = archive_open_entry_by_path(repo, archive, path.as_ptr());
+            assert!(!reader.is_null());
+
+            let mut buffer = [0 as c_char; 16];
+            let mut read = |reader| {
+                let length = entry_reader_read(reader, buffer.as_mut_ptr(), 4);
+                assert_eq!(length, 4);
+                buffer[..4]
+                    .iter()
+                    .map(|byte| *byte as u8)
+                    .collect::<Vec<_>>()
+            };
+
+            assert_eq!(entry_reader_seek(reader, 130), 0);
+            assert_eq!(entry_reader_position(reader), 130);
+            assert_eq!(read(reader), content[130..134]);
+
+            assert_eq!(entry_reader_skip(reader, 266), 266);
+            assert_eq!(entry_reader_position(reader), 400);
+            assert_eq!(read(reader), content[400..404]);
+
+            assert_eq!(entry_reader_seek(reader, 10), 0);
+            assert_eq!(read(reader), content[10..14]);
+            assert_eq!(entry_reader_remaining(reader), 986);
+
+            assert_eq!(entry_reader_skip(reader, 5000), 986);
+            assert_eq!(entry_reader_position(reader), 1000);
+            assert_eq!(entry_reader_seek(reader, 5000), 0);
+            assert_eq!(entry_reader_position(reader), 1000);
+
+            assert_eq!(
+                entry_reader_seek(std::ptr::null_mut(), 0),
+                DDUP_ERR_NULL_ARG
+            );
+            assert_eq!(entry_reader_position(std::ptr::null()), 0);
+
+            free_entry_reader(reader);
+            free_archive(archive);
+            free_repository(repo);
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    /// Appends everything passed to it to the `Vec<u8>` behind `user_data`.
+    extern "C" fn collect(data: *const u8, length: usize, user_data: *mut c_void) -> c_int {
+        let content = unsafe { &mut *(user_data as *mut Vec<u8>) };
+        content.extend_from_slice(unsafe { slice::from_raw_parts(data, length) });
+
+        0
+    }
+
+    extern "C" fn stop(_data: *const u8, _length: usize, _user_data: *mut c_void) -> c_int {
+        1
+    }
+
+    #[test]
+    fn read_entry_streams_the_content() {
+        let fixture = Fixture::new("read-entry");
+        let name = c_string("archive");
+        let destination = fixture.directory.join("read");
+        let mut content = Vec::<u8>::new();
+
+        unsafe {
+            let read = |path: &str, callback: CWriteCallback, user_data: *mut c_void| {
+                repository_read_entry(
+                    fixture.repo,
+                    name.as_ptr(),
+                    c_string(path).as_ptr(),
+                    callback,
+                    user_data,
+                )
+            };
+
+            assert_eq!(
+                read("file", Some(collect), &mut content as *mut _ as *mut c_void),
+                0
+            );
+            assert_eq!(content, file_content());
+
+            assert_eq!(read("file", Some(stop), null_mut()), DDUP_ERR_ABORTED);
+            assert_eq!(read("directory", Some(stop), null_mut()), DDUP_ERR_NULL_ARG);
+            assert_eq!(read("missing", Some(stop), null_mut()), DDUP_ERR_NOT_FOUND);
+            assert_eq!(read("file", None, null_mut()), DDUP_ERR_NULL_ARG);
+
+            let read_to_file = |path: &str| {
+                repository_read_entry_to_file(
+                    fixture.repo,
+                    name.as_ptr(),
+                    c_string(path).as_ptr(),
+                    c_path(&destination).as_ptr(),
+                )
+            };
+
+            assert_eq!(read_to_file("directory/nested/deep"), 0);
+            assert_eq!(std::fs::read_to_string(&destination).unwrap(), "deep");
+
+            std::fs::remove_file(&destination).unwrap();
+            assert_eq!(read_to_file("missing"), DDUP_ERR_NOT_FOUND);
+            assert!(!destination.exists());
+        }
+    }
+
+    #[test]
+    fn read_exact_read_all_and_remaining() {
+        let fixture = Fixture::new("read-exact");
+        let content = file_content();
+        let path = c_string("file");
+
+        unsafe {
+            let archive = repository_get_archive(fixture.repo, c_string("archive").as_ptr());
+            let reader = archive_open_entry_by_path(fixture.repo, archive, path.as_ptr());
+            assert!(!reader.is_null());
+
+            let mut buffer = [0 as c_char; 100];
+            assert_eq!(entry_reader_read_exact(reader, buffer.as_mut_ptr(), 100), 0);
+            assert!(buffer.iter().zip(&content).all(|(a, b)| *a as u8 == *b));
+            assert_eq!(entry_reader_remaining(reader), 900);
+
+            let mut rest = Vec::<u8>::new();
+            assert_eq!(
+                entry_reader_read_all(reader, Some(collect), &mut rest as *mut _ as *mut c_void),
+                900
+            );
+            assert_eq!(rest, content[100..]);
+            assert_eq!(entry_reader_remaining(reader), 0);
+
+            // a short file leaves what was read in the buffer
+            assert_eq!(entry_reader_seek(reader, 990), 0);
+            assert_eq!(
+                entry_reader_read_exact(reader, buffer.as_mut_ptr(), 100),
+                DDUP_ERR_EOF
+            );
+            assert!(buffer[..10]
+                .iter()
+                .zip(&content[990..])
+                .all(|(a, b)| *a as u8 == *b));
+            assert_eq!(entry_reader_position(reader), 1000);
+
+            assert_eq!(entry_reader_seek(reader, 0), 0);
+            assert_eq!(
+                entry_reader_read_all(reader, Some(stop), null_mut()),
+                DDUP_ERR_ABORTED as i64
+            );
+
+            free_entry_reader(reader);
+            free_archive(archive);
+        }
+    }
+
+    #[test]
+    fn readers_open_from_entries_of_freed_archives() {
+        let fixture = Fixture::new("reader-entries");
+        let mut content = vec![0 as c_char; 1000];
+
+        unsafe {
+            let archive = repository_get_archive(fixture.repo, c_string("archive").as_ptr());
+            let entry = archive_find_entry(archive, c_string("file").as_ptr());
+            assert!(!entry.is_null());
+
+            // the entries of archive_entries2 are opened through the archive
+            let mut count = 0;
+            let entries = archive_entries2(archive, &mut count);
+            let file = (0..count as usize)
+                .map(|i| *entries.add(i))
+                .find(|entry| CStr::from_ptr(entry_name(*entry)).to_bytes() == b"file")
+                .unwrap();
+            let reader = archive_entry_open_reader(fixture.repo, archive, file);
+            assert!(!reader.is_null());
+            assert_eq!(
+                entry_reader_read_exact(reader, content.as_mut_ptr(), 1000),
+                0
+            );
+            free_entry_reader(reader);
+            free_entry_array(entries, count);
+
+            free_archive(archive);
+
+            let reader = entry_open_reader(fixture.repo, entry);
+            assert!(!reader.is_null());
+            content.fill(0);
+            assert_eq!(
+                entry_reader_read_exact(reader, content.as_mut_ptr(), 1000),
+                0
+            );
+            assert!(content
+                .iter()
+                .zip(file_content())
+                .all(|(a, b)| *a as u8 == b));
+            free_entry_reader(reader);
+
+            let archive = repository_get_archive(fixture.repo, c_string("archive").as_ptr());
+            let directory = archive_find_entry(archive, c_string("directory").as_ptr());
+            free_archive(archive);
+            assert!(entry_open_reader(fixture.repo, directory).is_null());
+            free_entry(directory);
+            free_entry(entry);
+        }
+    }
+}
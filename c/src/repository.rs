@@ -1,36 +1,84 @@
 use crate::archive::{CArchive, CCompressionFormat};
+use crate::progress::{
+    chunk_references, entry_totals, wrap_deletion_event_callback, wrap_progress_event_callback,
+    CProgressEventCallback,
+};
+use crate::{
+    error_code, null_terminated, path_argument, path_c_string, set_last_error, utf8_argument,
+    DDUP_ERR_CONCURRENT_USE, DDUP_ERR_LOCK, DDUP_ERR_NULL_ARG,
+};
 use ddup_bak::archive::CompressionFormat;
-use ddup_bak::repository::Repository;
+use ddup_bak::archive::ProgressCallback;
+use ddup_bak::chunks::lock::{LockBackend, LockMode, WriteGuard};
+use ddup_bak::repository::{CreateOptions, Repository, RestoreMode, RestoreOptions};
 use std::ffi::*;
 use std::fs::Metadata;
-use std::ops::{Deref, DerefMut};
-use std::path::Path;
-use std::sync::Arc;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::time::Instant;
 
-pub type CProgressCallback = Option<extern "C" fn(*const c_char)>;
-pub type CDeletionProgressCallback = Option<extern "C" fn(chunk_id: u64, deleted: bool)>;
-pub type CCompressionFormatCallback = Option<extern "C" fn(*const c_char) -> CCompressionFormat>;
+/// Receives a path and the `user_data` passed along with the callback. Like for every
+/// callback the `user_data` pointer is managed by the caller and must stay valid until
+/// the call taking the callback returns, it may be called from other threads.
+pub type CProgressCallback = Option<extern "C" fn(path: *const c_char, user_data: *mut c_void)>;
+/// Receives every dereferenced chunk, whether it was deleted and `user_data`.
+pub type CDeletionProgressCallback =
+    Option<extern "C" fn(chunk_id: u64, deleted: bool, user_data: *mut c_void)>;
+/// Chooses the compression of the file at a path, receives `user_data`.
+pub type CCompressionFormatCallback =
+    Option<extern "C" fn(path: *const c_char, user_data: *mut c_void) -> CCompressionFormat>;
 
+/// Wraps a `CProgressCallback`, the `user_data` pointer is captured as an address so the
+/// closure may be sent to other threads.
+pub(crate) fn wrap_progress_callback(
+    callback: CProgressCallback,
+    user_data: *mut c_void,
+) -> ProgressCallback {
+    let user_data = user_data as usize;
+
+    callback.map(|callback_fn| {
+        Arc::new(move |path: &Path| {
+            if let Some(c_path) = path_c_string(path) {
+                callback_fn(c_path.as_ptr(), user_data as *mut c_void);
+            }
+        }) as Arc<dyn Fn(&Path) + Send + Sync>
+    })
+}
+
+/// A repository handle, which may be used from several threads at once. Only
+/// `free_repository` requires exclusive access, `repository_set_save_on_drop` and
+/// `repository_set_lock_backend` fail with `DDUP_ERR_CONCURRENT_USE` while another call
+/// uses the handle. `repository_clone_handle` gives every thread its own handle.
 #[repr(C)]
 pub struct CRepository {
     _private: [u8; 0],
 }
 
 pub struct RepositoryHandle {
-    inner: Box<Repository>,
+    /// Read by every call, written only by the setters, which never wait for it.
+    inner: RwLock<Repository>,
+    /// Problems found by the last `repository_verify_archive` on this handle.
+    pub(crate) verify_problems: Mutex<Vec<String>>,
+    /// Warnings of the last `repository_restore_archive_ex` on this handle.
+    restore_warnings: Mutex<Vec<String>>,
 }
 
-impl Deref for RepositoryHandle {
-    type Target = Repository;
-
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+impl RepositoryHandle {
+    pub(crate) fn repository(&self) -> RwLockReadGuard<'_, Repository> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
     }
-}
 
-impl DerefMut for RepositoryHandle {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+    /// Borrows the repository mutably, setting the last error if another call uses it.
+    fn repository_mut(&self) -> Result<RwLockWriteGuard<'_, Repository>, c_int> {
+        match self.inner.try_write() {
+            Ok(repository) => Ok(repository),
+            Err(TryLockError::Poisoned(err)) => Ok(err.into_inner()),
+            Err(TryLockError::WouldBlock) => {
+                set_last_error("handle used concurrently");
+                Err(DDUP_ERR_CONCURRENT_USE)
+            }
+        }
     }
 }
 
@@ -42,16 +90,12 @@ impl Deref for CRepository {
     }
 }
 
-impl DerefMut for CRepository {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *(self as *mut CRepository as *mut RepositoryHandle) }
-    }
-}
-
 impl CRepository {
     pub fn from_repository(repository: Repository) -> *mut CRepository {
         let handle = Box::new(RepositoryHandle {
-            inner: Box::new(repository),
+            inner: RwLock::new(repository),
+            verify_problems: Mutex::new(Vec::new()),
+            restore_warnings: Mutex::new(Vec::new()),
         });
         Box::into_raw(handle) as *mut CRepository
     }
@@ -61,69 +105,92 @@ impl CRepository {
         &*(ptr as *const RepositoryHandle)
     }
 
-    #[allow(clippy::missing_safety_doc)]
-    pub unsafe fn as_handle_mut(ptr: *mut CRepository) -> &'static mut RepositoryHandle {
-        &mut *(ptr as *mut RepositoryHandle)
-    }
-
     #[allow(clippy::missing_safety_doc)]
     pub unsafe fn into_repository(ptr: *mut CRepository) -> Repository {
         let handle = Box::from_raw(ptr as *mut RepositoryHandle);
-        *handle.inner
+        handle
+            .inner
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
     }
 }
 
-#[no_mangle]
-#[allow(clippy::missing_safety_doc)]
-pub unsafe extern "C" fn new_repository(
-    directory: *const c_char,
+/// Creates a repository handle, shared by the byte and wide variants of the functions.
+pub(crate) fn new_repository_at(
+    directory: &Path,
     chunk_size: c_uint,
     max_chunk_count: c_uint,
 ) -> *mut CRepository {
-    let directory = unsafe { CStr::from_ptr(directory).to_string_lossy().into_owned() };
-
-    let repository = match Repository::new(
-        Path::new(&directory),
+    match Repository::new(
+        directory,
         chunk_size as usize,
         max_chunk_count as usize,
         None,
     ) {
-        Ok(repo) => repo,
-        Err(_) => return std::ptr::null_mut(),
-    };
+        Ok(repository) => CRepository::from_repository(repository),
+        Err(err) => {
+            set_last_error(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Opens a repository handle, shared by the byte and wide variants of the functions.
+pub(crate) fn open_repository_at(
+    directory: &Path,
+    chunks_directory: Option<&Path>,
+) -> *mut CRepository {
+    match Repository::open(directory, chunks_directory, None) {
+        Ok(repository) => CRepository::from_repository(repository),
+        Err(err) => {
+            set_last_error(err);
+            std::ptr::null_mut()
+        }
+    }
+}
 
-    CRepository::from_repository(repository)
+/// Creates a repository in `directory`, which on unix may be any byte string and on
+/// windows has to be UTF-8, see `new_repository_w`. Returns NULL on failure, see
+/// `last_error_message`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn new_repository(
+    directory: *const c_char,
+    chunk_size: c_uint,
+    max_chunk_count: c_uint,
+) -> *mut CRepository {
+    match unsafe { path_argument(directory, "directory") } {
+        Ok(directory) => new_repository_at(&directory, chunk_size, max_chunk_count),
+        Err(_) => std::ptr::null_mut(),
+    }
 }
 
+/// Opens the repository in `directory` with the lock backend stored by
+/// `repository_set_lock_backend`, `chunks_directory` may be NULL. Paths are taken like by
+/// `new_repository`. Returns NULL on failure, see `last_error_message`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn open_repository(
     directory: *const c_char,
     chunks_directory: *const c_char,
 ) -> *mut CRepository {
-    let directory = unsafe { CStr::from_ptr(directory).to_string_lossy().into_owned() };
+    let Ok(directory) = (unsafe { path_argument(directory, "directory") }) else {
+        return std::ptr::null_mut();
+    };
     let chunks_directory = if chunks_directory.is_null() {
         None
     } else {
-        Some(unsafe {
-            CStr::from_ptr(chunks_directory)
-                .to_string_lossy()
-                .into_owned()
-        })
+        match unsafe { path_argument(chunks_directory, "chunks_directory") } {
+            Ok(chunks_directory) => Some(chunks_directory),
+            Err(_) => return std::ptr::null_mut(),
+        }
     };
 
-    let repository = Repository::open(
-        Path::new(&directory),
-        chunks_directory.as_ref().map(Path::new),
-        None,
-    );
-
-    match repository {
-        Ok(repo) => CRepository::from_repository(repo),
-        Err(_) => std::ptr::null_mut(),
-    }
+    open_repository_at(&directory, chunks_directory.as_deref())
 }
 
+/// Frees a repository handle, saving the chunk index if it changed unless disabled with
+/// `repository_set_save_on_drop`. NULL is ignored.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn free_repository(repo: *mut CRepository) {
@@ -136,113 +203,668 @@ pub unsafe extern "C" fn free_repository(repo: *mut CRepository) {
     }
 }
 
+/// Saves the chunk index if it changed since it was loaded or last saved, see
+/// `repository_flush` to also flush the chunk storage.
+/// Returns 0, `DDUP_ERR_NULL_ARG` for a NULL repository or `DDUP_ERR_IO`,
+/// see `last_error_message`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn repository_save(repo: *mut CRepository) -> c_int {
-    let repo = unsafe { &mut *repo };
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
 
     match repo.save() {
         Ok(_) => 0,
-        Err(_) => -1,
+        Err(err) => error_code(err),
+    }
+}
+
+/// Saves the chunk index and flushes the chunk storage, so everything written so far
+/// survives a crash. Returns 0, `DDUP_ERR_NULL_ARG` for a NULL repository or
+/// `DDUP_ERR_IO` if saving or flushing failed, see `last_error_message`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_flush(repo: *mut CRepository) -> c_int {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+
+    match repo.flush() {
+        Ok(()) => 0,
+        Err(err) => error_code(err),
     }
 }
 
+/// Sets whether `free_repository` saves the chunk index, which it does by default.
+/// Returns the repository, or NULL with the last error set if it is NULL or used by
+/// another thread.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn repository_set_save_on_drop(
     repo: *mut CRepository,
     save_on_drop: bool,
 ) -> *mut CRepository {
-    let repo = unsafe { &mut *repo };
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return std::ptr::null_mut();
+    }
+
+    match unsafe { CRepository::as_handle(repo) }.repository_mut() {
+        Ok(mut repository) => {
+            repository.set_save_on_drop(save_on_drop);
+
+            repo
+        }
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Keeps the holders of the lock in the lock file, polled by a thread, the default.
+pub const DDUP_LOCK_BACKEND_POLLED: c_int = 0;
+/// Uses the advisory locks of the operating system, which are released when a process
+/// exits but are not supported by every network file system.
+pub const DDUP_LOCK_BACKEND_NATIVE: c_int = 1;
+
+/// Switches the repository lock to `backend`, one of the `DDUP_LOCK_BACKEND_*` values,
+/// and stores it in the repository, so `open_repository` uses it from then on. Every
+/// process using the repository has to use the same backend, so it is set right after
+/// creating the repository, before other handles are cloned from this one. Returns 0 or a
+/// `DDUP_ERR_*` code, `DDUP_ERR_CONCURRENT_USE` if the handle is used by another thread.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_set_lock_backend(
+    repo: *mut CRepository,
+    backend: c_int,
+) -> c_int {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let backend = match backend {
+        DDUP_LOCK_BACKEND_POLLED => LockBackend::Polled,
+        DDUP_LOCK_BACKEND_NATIVE => LockBackend::Native,
+        _ => {
+            set_last_error(format!("unknown lock backend {backend}"));
+            return DDUP_ERR_NULL_ARG;
+        }
+    };
+
+    let mut repository = match unsafe { CRepository::as_handle(repo) }.repository_mut() {
+        Ok(repository) => repository,
+        Err(code) => return code,
+    };
+
+    match repository.set_lock_backend(backend) {
+        Ok(_) => 0,
+        Err(err) => error_code(err),
+    }
+}
+
+/// Returns a new handle to the same repository, sharing its chunk index and lock, so
+/// every thread can hold its own. Each handle is freed with `free_repository`, and
+/// saves the chunk index on free unless `repository_set_save_on_drop` disabled it.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_clone_handle(repo: *const CRepository) -> *mut CRepository {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return std::ptr::null_mut();
+    }
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+
+    CRepository::from_repository(repo.clone())
+}
+
+/// Numbers of the chunk index, filled by `repository_chunk_stats`. The caller sets
+/// `struct_size` to `sizeof(CChunkStats)`, fields added later to the end are only
+/// written if they fit into it.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CChunkStats {
+    pub struct_size: usize,
+    pub chunk_count: u64,
+    pub total_references: u64,
+    pub deleted_id_count: u64,
+    pub chunk_size: u64,
+    pub max_chunk_count: u64,
+}
+
+/// Fills `out` with the numbers of the chunk index, which are kept in memory so this
+/// is cheap. Returns 0 or `DDUP_ERR_NULL_ARG` for NULL arguments or an unset
+/// `struct_size`, see `last_error_message`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_chunk_stats(
+    repo: *mut CRepository,
+    out: *mut CChunkStats,
+) -> c_int {
+    if repo.is_null() || out.is_null() {
+        set_last_error("repository or out is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let struct_size = unsafe { (*out).struct_size };
+    if struct_size < std::mem::size_of::<usize>() {
+        set_last_error("struct_size of out is not set");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+    let stats = repo.chunk_index.stats();
+    let c_stats = CChunkStats {
+        struct_size,
+        chunk_count: stats.chunk_count,
+        total_references: stats.total_references,
+        deleted_id_count: stats.deleted_id_count,
+        chunk_size: stats.chunk_size as u64,
+        max_chunk_count: stats.max_chunk_count as u64,
+    };
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            &c_stats as *const CChunkStats as *const u8,
+            out as *mut u8,
+            struct_size.min(std::mem::size_of::<CChunkStats>()),
+        )
+    };
+
+    0
+}
+
+/// A write lock that modifies the chunk index, e.g. to create or delete archives.
+pub const DDUP_LOCK_DESTRUCTIVE: c_int = 1;
+/// A write lock that leaves the chunk index alone, e.g. to rename archives.
+pub const DDUP_LOCK_NON_DESTRUCTIVE: c_int = 2;
+
+fn lock_mode(mode: c_int) -> Result<LockMode, c_int> {
+    match mode {
+        DDUP_LOCK_DESTRUCTIVE => Ok(LockMode::Destructive),
+        DDUP_LOCK_NON_DESTRUCTIVE => Ok(LockMode::NonDestructive),
+        _ => {
+            set_last_error(format!("unknown lock mode {mode}"));
+            Err(DDUP_ERR_NULL_ARG)
+        }
+    }
+}
+
+/// The repository lock taken by `repository_try_lock`, released with `repository_unlock`.
+/// It belongs to the process rather than the handle, so every call on any handle to the
+/// repository reuses it while it is held.
+#[repr(C)]
+pub struct CRepositoryLock {
+    _private: [u8; 0],
+}
+
+/// Takes the repository lock in `mode`, one of the `DDUP_LOCK_*` values, without
+/// waiting for it. Returns 0 and stores the lock in `out_lock`, or `DDUP_ERR_LOCK`
+/// if another process holds it, e.g. to skip a backup while another one is running.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_try_lock(
+    repo: *mut CRepository,
+    mode: c_int,
+    out_lock: *mut *mut CRepositoryLock,
+) -> c_int {
+    if repo.is_null() || out_lock.is_null() {
+        set_last_error("repository or out_lock is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let mode = match lock_mode(mode) {
+        Ok(mode) => mode,
+        Err(code) => return code,
+    };
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+    match repo.try_lock(mode) {
+        Ok(Some(guard)) => {
+            unsafe { *out_lock = Box::into_raw(Box::new(guard)) as *mut CRepositoryLock };
+
+            0
+        }
+        Ok(None) => {
+            set_last_error(format!(
+                "repository is locked by {}",
+                repo.chunk_index.lock.holders()
+            ));
+
+            DDUP_ERR_LOCK
+        }
+        Err(err) => error_code(err),
+    }
+}
+
+/// Releases and frees a lock returned by `repository_try_lock`, NULL is ignored.
+/// Returns 0, or `DDUP_ERR_IO` if the lock file could not be updated, the lock is
+/// freed either way.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_unlock(lock: *mut CRepositoryLock) -> c_int {
+    if lock.is_null() {
+        return 0;
+    }
+
+    let mut guard = unsafe { Box::from_raw(lock as *mut WriteGuard) };
+    match guard.unlock() {
+        Ok(()) => 0,
+        Err(err) => error_code(err),
+    }
+}
+
+/// Who holds the repository lock, filled by `repository_lock_status`.
+#[repr(C)]
+pub struct CLockStatus {
+    /// The process holding the write lock, 0 if there is none.
+    pub writer_pid: u64,
+    /// One of the `DDUP_LOCK_*` values, 0 if there is no writer.
+    pub writer_mode: c_int,
+    pub destructive_readers: u64,
+    pub non_destructive_readers: u64,
+    /// Seconds since a holder of the lock was last known to be alive, -1 if never.
+    pub heartbeat_age: i64,
+}
+
+/// Fills `status` with the holders of the repository lock as found in the lock file,
+/// for diagnostics. Returns 0 or a `DDUP_ERR_*` code.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_lock_status(
+    repo: *mut CRepository,
+    status: *mut CLockStatus,
+) -> c_int {
+    if repo.is_null() || status.is_null() {
+        set_last_error("repository or status is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+    let lock_status = match repo.lock_status() {
+        Ok(lock_status) => lock_status,
+        Err(err) => return error_code(err),
+    };
+
+    unsafe {
+        *status = CLockStatus {
+            writer_pid: lock_status.writer_pid.unwrap_or(0),
+            writer_mode: lock_status.writer_mode.map_or(0, |mode| mode as c_int),
+            destructive_readers: lock_status.reader_counts[LockMode::Destructive as usize],
+            non_destructive_readers: lock_status.reader_counts[LockMode::NonDestructive as usize],
+            heartbeat_age: lock_status
+                .heartbeat_age
+                .map_or(-1, |age| age.as_secs() as i64),
+        }
+    };
 
-    repo.set_save_on_drop(save_on_drop);
+    0
+}
+
+/// Returns the reference count of a chunk ID, or -1 with the last error set if the
+/// chunk is not part of the index.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_chunk_references(repo: *mut CRepository, chunk_id: u64) -> i64 {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return -1;
+    }
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+
+    match repo.chunk_index.references_by_id(chunk_id) {
+        Some(references) => references as i64,
+        None => {
+            set_last_error(format!("chunk {chunk_id} not found"));
+            -1
+        }
+    }
+}
+
+/// What a clean would delete, filled by `repository_clean_plan`.
+#[repr(C)]
+pub struct CCleanPlan {
+    pub chunk_count: u64,
+    pub bytes: u64,
+}
+
+/// What a clean deleted, filled by `repository_clean`.
+#[repr(C)]
+pub struct CCleanResult {
+    pub chunks_deleted: u64,
+    pub bytes_reclaimed: u64,
+    pub duration_ms: u64,
+}
+
+/// Fills `out_plan` with the unreferenced chunks a clean would delete and the bytes it
+/// would reclaim, without deleting anything. Returns 0, `DDUP_ERR_NULL_ARG`
+/// for NULL arguments or `DDUP_ERR_IO` if the chunk storage failed, see
+/// `last_error_message`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_clean_plan(
+    repo: *mut CRepository,
+    out_plan: *mut CCleanPlan,
+) -> c_int {
+    if repo.is_null() || out_plan.is_null() {
+        set_last_error("repository or out_plan is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
 
-    repo
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+
+    match repo.clean(true, None) {
+        Ok(plan) => {
+            unsafe {
+                *out_plan = CCleanPlan {
+                    chunk_count: plan.chunk_count,
+                    bytes: plan.bytes,
+                }
+            };
+
+            0
+        }
+        Err(err) => error_code(err),
+    }
 }
 
+/// Deletes all unreferenced chunks, `out_result` may be NULL. Returns 0,
+/// `DDUP_ERR_NULL_ARG` for a NULL repository, `DDUP_ERR_LOCK` or
+/// `DDUP_ERR_IO` if the chunk storage failed, see `last_error_message`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn repository_clean(
     repo: *mut CRepository,
     progress_callback: CDeletionProgressCallback,
+    user_data: *mut c_void,
+    out_result: *mut CCleanResult,
 ) -> c_int {
     if repo.is_null() {
-        return -1;
+        set_last_error("repository is NULL");
+        return DDUP_ERR_NULL_ARG;
     }
 
-    let repo = unsafe { &mut *repo };
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
 
+    let user_data = user_data as usize;
     let progress_callback = progress_callback.map(|callback_fn| {
-        Arc::new(move |chunk_id: u64, deleted: bool| {
-            callback_fn(chunk_id, deleted);
-        }) as Arc<dyn Fn(u64, bool) + Send + Sync>
+        Arc::new(move |chunk_id: u64, _size: u64| {
+            callback_fn(chunk_id, true, user_data as *mut c_void);
+        }) as Arc<dyn Fn(u64, u64) + Send + Sync>
     });
 
-    match repo.clean(progress_callback) {
-        Ok(_) => 0,
-        Err(_) => -1,
+    let start = Instant::now();
+    match repo.clean(false, progress_callback) {
+        Ok(plan) => {
+            if !out_result.is_null() {
+                unsafe {
+                    *out_result = CCleanResult {
+                        chunks_deleted: plan.chunk_count,
+                        bytes_reclaimed: plan.bytes,
+                        duration_ms: start.elapsed().as_millis() as u64,
+                    }
+                };
+            }
+
+            0
+        }
+        Err(err) => error_code(err),
     }
 }
 
-#[no_mangle]
-#[allow(clippy::missing_safety_doc)]
-pub unsafe extern "C" fn repository_create_archive(
+/// Stop the create at the first path that cannot be read, the default.
+pub const DDUP_CREATE_ABORT_ON_ERROR: c_int = 0;
+/// Leave files and directories that cannot be read out of the archive.
+pub const DDUP_CREATE_SKIP_ERRORS: c_int = 1;
+
+/// Returns whether the content of a path can be read, as far as the create needs it.
+fn readable(entry: &ignore::DirEntry) -> bool {
+    match entry.file_type() {
+        Some(file_type) if file_type.is_dir() => std::fs::read_dir(entry.path()).is_ok(),
+        Some(file_type) if file_type.is_file() => std::fs::File::open(entry.path()).is_ok(),
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Creates an archive like `repository_create_archive_ex`, reporting to either kind of
+/// progress callback.
+#[allow(clippy::too_many_arguments)]
+unsafe fn create_archive(
     repo: *mut CRepository,
     name: *const c_char,
     directory: *const c_char,
-    progress_chunking: CProgressCallback,
+    excludes: *const *const c_char,
+    exclude_count: usize,
+    progress_chunking: ProgressCallback,
+    options: CreateOptions,
     compression_callback: CCompressionFormatCallback,
+    compression_user_data: *mut c_void,
+    error_policy: c_int,
     threads: c_uint,
+    out_skipped: *mut *mut *mut c_char,
 ) -> *mut CArchive {
-    if repo.is_null() || name.is_null() {
-        return std::ptr::null_mut();
+    if !out_skipped.is_null() {
+        unsafe { *out_skipped = std::ptr::null_mut() };
     }
 
-    let repo = unsafe { &mut *repo };
-    let name = unsafe { CStr::from_ptr(name).to_string_lossy().into_owned() };
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return std::ptr::null_mut();
+    }
+    if excludes.is_null() && exclude_count > 0 {
+        set_last_error("excludes is NULL");
+        return std::ptr::null_mut();
+    }
+    if error_policy != DDUP_CREATE_ABORT_ON_ERROR && error_policy != DDUP_CREATE_SKIP_ERRORS {
+        set_last_error(format!("unknown error policy {error_policy}"));
+        return std::ptr::null_mut();
+    }
 
-    let directory_str = if directory.is_null() {
-        None
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+    let Ok(name) = (unsafe { utf8_argument(name, "name") }) else {
+        return std::ptr::null_mut();
+    };
+    let root = if directory.is_null() {
+        repo.directory.clone()
     } else {
-        Some(unsafe { CStr::from_ptr(directory).to_string_lossy().into_owned() })
+        match unsafe { path_argument(directory, "directory") } {
+            Ok(directory) => directory,
+            Err(_) => return std::ptr::null_mut(),
+        }
     };
 
-    let directory_path = directory_str.as_ref().map(|d| {
-        ignore::WalkBuilder::new(Path::new(d))
-            .follow_links(false)
-            .git_global(false)
-            .build()
-    });
+    let mut overrides = ignore::overrides::OverrideBuilder::new(&root);
+    for i in 0..exclude_count {
+        let Ok(pattern) = (unsafe { utf8_argument(*excludes.add(i), "exclude pattern") }) else {
+            return std::ptr::null_mut();
+        };
+
+        if let Err(err) = overrides.add(&format!("!{pattern}")) {
+            set_last_error(err);
+            return std::ptr::null_mut();
+        }
+    }
+    let overrides = match overrides.build() {
+        Ok(overrides) => overrides,
+        Err(err) => {
+            set_last_error(err);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let skipped = Arc::new(Mutex::new(Vec::new()));
+    let walker = ignore::WalkBuilder::new(&root)
+        .follow_links(false)
+        .git_global(false)
+        .filter_entry({
+            let root = root.clone();
+            let skipped = Arc::clone(&skipped);
+
+            move |entry| {
+                let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+
+                if entry.depth() > 0
+                    && (overrides.matched(entry.path(), is_dir).is_ignore()
+                        || (error_policy == DDUP_CREATE_SKIP_ERRORS && !readable(entry)))
+                {
+                    let path = entry.path().strip_prefix(&root).unwrap_or(entry.path());
+                    skipped.lock().unwrap().push(path.to_path_buf());
 
-    let progress_chunking = progress_chunking.map(|callback_fn| {
-        Arc::new(move |path: &std::path::Path| {
-            if let Some(path_str) = path.to_str() {
-                let c_path = CString::new(path_str).unwrap();
-                callback_fn(c_path.as_ptr());
+                    return false;
+                }
+
+                true
             }
-        }) as Arc<dyn Fn(&std::path::Path) + Send + Sync>
-    });
+        })
+        .build();
 
+    let user_data = compression_user_data as usize;
     let compression_callback = compression_callback.map(|callback_fn| {
         Arc::new(move |path: &Path, _: &Metadata| {
-            let c_compression_str = CString::new(path.to_string_lossy().into_owned()).unwrap();
-            callback_fn(c_compression_str.as_ptr()).into()
+            let c_path = path_c_string(path).unwrap_or_default();
+            callback_fn(c_path.as_ptr(), user_data as *mut c_void).into()
         }) as Arc<dyn Fn(&Path, &Metadata) -> CompressionFormat + Send + Sync>
     });
 
-    match repo.create_archive(
-        &name,
-        directory_path,
-        directory_str.as_ref().map(Path::new),
+    let result = repo.create_archive(
+        name,
+        Some(walker),
+        Some(&root),
         progress_chunking,
         compression_callback,
         threads as usize,
-    ) {
+        options,
+    );
+
+    if !out_skipped.is_null() {
+        let skipped = std::mem::take(&mut *skipped.lock().unwrap());
+
+        unsafe {
+            *out_skipped = null_terminated(
+                skipped
+                    .into_iter()
+                    .filter_map(|path| path_c_string(&path))
+                    .map(CString::into_raw)
+                    .collect(),
+            )
+        };
+    }
+
+    match result {
         Ok(archive) => CArchive::from_archive(archive),
-        Err(_) => std::ptr::null_mut(),
+        Err(err) => {
+            set_last_error(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Creates an archive of `directory`, or of the repository directory if it is NULL.
+///
+/// Paths matching one of the `exclude_count` glob patterns in `excludes` are left out,
+/// a matching directory with everything below it. With `DDUP_CREATE_SKIP_ERRORS` as
+/// `error_policy` files and directories that cannot be read are left out as well,
+/// with `DDUP_CREATE_ABORT_ON_ERROR` they fail the create.
+///
+/// If `progress_callback` is set, the directory is walked once before chunking to
+/// report the totals as `ScanComplete`.
+///
+/// If `out_skipped` is not NULL it receives a NULL-terminated array of the paths that
+/// were left out, relative to the directory, which is freed with `free_string_array`.
+/// Returns NULL on failure, see `last_error_message`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::too_many_arguments)]
+pub unsafe extern "C" fn repository_create_archive_ex(
+    repo: *mut CRepository,
+    name: *const c_char,
+    directory: *const c_char,
+    excludes: *const *const c_char,
+    exclude_count: usize,
+    progress_callback: CProgressEventCallback,
+    progress_user_data: *mut c_void,
+    compression_callback: CCompressionFormatCallback,
+    compression_user_data: *mut c_void,
+    error_policy: c_int,
+    threads: c_uint,
+    out_skipped: *mut *mut *mut c_char,
+) -> *mut CArchive {
+    let options = CreateOptions {
+        count_first: progress_callback.is_some(),
+        progress: wrap_progress_event_callback(progress_callback, progress_user_data, None),
+        ..Default::default()
+    };
+
+    unsafe {
+        create_archive(
+            repo,
+            name,
+            directory,
+            excludes,
+            exclude_count,
+            None,
+            options,
+            compression_callback,
+            compression_user_data,
+            error_policy,
+            threads,
+            out_skipped,
+        )
+    }
+}
+
+/// Creates an archive of `directory` without excludes, reporting only the path of
+/// every file, see `repository_create_archive_ex`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_create_archive(
+    repo: *mut CRepository,
+    name: *const c_char,
+    directory: *const c_char,
+    progress_chunking: CProgressCallback,
+    compression_callback: CCompressionFormatCallback,
+    user_data: *mut c_void,
+    threads: c_uint,
+) -> *mut CArchive {
+    unsafe {
+        create_archive(
+            repo,
+            name,
+            directory,
+            std::ptr::null(),
+            0,
+            wrap_progress_callback(progress_chunking, user_data),
+            CreateOptions::default(),
+            compression_callback,
+            user_data,
+            DDUP_CREATE_ABORT_ON_ERROR,
+            threads,
+            std::ptr::null_mut(),
+        )
     }
 }
 
+/// Returns the archive names as a NULL-terminated array freed with `free_string_array`
+/// and stores their number in `count`, see `repository_archive_iter_new` for a way
+/// without allocating every name. Returns NULL with `count` set to 0 on failure, see
+/// `last_error_message`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn repository_list_archives(
@@ -250,36 +872,34 @@ pub unsafe extern "C" fn repository_list_archives(
     count: *mut c_uint,
 ) -> *mut *mut c_char {
     if repo.is_null() || count.is_null() {
+        set_last_error("repository or count is NULL");
         return std::ptr::null_mut();
     }
 
-    let repo = unsafe { &*repo };
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
 
     match repo.list_archives() {
         Ok(archives) => {
             unsafe { *count = archives.len() as c_uint };
 
-            let mut c_archives = Vec::with_capacity(archives.len() + 1);
-
-            for archive in archives {
-                let c_archive = CString::new(archive).unwrap();
-                c_archives.push(c_archive.into_raw());
-            }
-
-            c_archives.push(std::ptr::null_mut());
-
-            let ptr = c_archives.as_mut_ptr();
-            std::mem::forget(c_archives);
-
-            ptr
+            null_terminated(
+                archives
+                    .into_iter()
+                    .map(|archive| CString::new(archive).unwrap().into_raw())
+                    .collect(),
+            )
         }
-        Err(_) => {
+        Err(err) => {
+            set_last_error(err);
             unsafe { *count = 0 };
             std::ptr::null_mut()
         }
     }
 }
 
+/// Opens an archive of the repository, freed with `free_archive`. Returns NULL on
+/// failure, see `last_error_message`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn repository_get_archive(
@@ -287,77 +907,1031 @@ pub unsafe extern "C" fn repository_get_archive(
     archive_name: *const c_char,
 ) -> *mut CArchive {
     if repo.is_null() || archive_name.is_null() {
+        set_last_error("repository or archive_name is NULL");
         return std::ptr::null_mut();
     }
 
-    let repo = unsafe { &*repo };
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
     let archive_name = unsafe { CStr::from_ptr(archive_name).to_string_lossy().into_owned() };
 
     match repo.get_archive(&archive_name) {
         Ok(archive) => CArchive::from_archive(archive),
-        Err(_) => std::ptr::null_mut(),
+        Err(err) => {
+            set_last_error(err);
+            std::ptr::null_mut()
+        }
     }
 }
 
-#[no_mangle]
-#[allow(clippy::missing_safety_doc)]
-pub unsafe extern "C" fn repository_restore_archive(
+/// Restores an archive into the default restore directory, returning it.
+pub(crate) unsafe fn restore_archive_default(
     repo: *mut CRepository,
     archive_name: *const c_char,
     progress_callback: CProgressCallback,
+    user_data: *mut c_void,
     threads: c_uint,
-) -> *mut c_char {
+) -> Option<PathBuf> {
     if repo.is_null() || archive_name.is_null() {
-        return std::ptr::null_mut();
+        set_last_error("repository or archive_name is NULL");
+        return None;
     }
 
-    let repo = unsafe { &*repo };
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
     let archive_name = unsafe { CStr::from_ptr(archive_name).to_string_lossy().into_owned() };
 
-    let progress_callback = progress_callback.map(|callback_fn| {
-        Arc::new(move |path: &std::path::Path| {
-            if let Some(path_str) = path.to_str() {
-                let c_path = CString::new(path_str).unwrap();
-                callback_fn(c_path.as_ptr());
-            }
-        }) as Arc<dyn Fn(&std::path::Path) + Send + Sync>
-    });
+    let progress_callback = wrap_progress_callback(progress_callback, user_data);
 
-    match repo.restore_archive(&archive_name, progress_callback, threads as usize) {
-        Ok(path) => {
-            if let Some(path_str) = path.to_str() {
-                let c_path = CString::new(path_str).unwrap();
-                c_path.into_raw()
-            } else {
-                std::ptr::null_mut()
-            }
-        }
-        Err(_) => std::ptr::null_mut(),
-    }
+    repo.restore_archive(&archive_name, progress_callback, threads as usize)
+        .map_err(set_last_error)
+        .ok()
+}
+
+/// Restores an archive into the default restore directory of the repository and
+/// returns that directory, freed with `free_string`, or NULL on failure, see
+/// `last_error_message`. On windows
+/// it is only exact if it is valid UTF-8, see `repository_restore_archive_w`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_restore_archive(
+    repo: *mut CRepository,
+    archive_name: *const c_char,
+    progress_callback: CProgressCallback,
+    user_data: *mut c_void,
+    threads: c_uint,
+) -> *mut c_char {
+    unsafe { restore_archive_default(repo, archive_name, progress_callback, user_data, threads) }
+        .and_then(|path| path_c_string(&path))
+        .map_or(std::ptr::null_mut(), CString::into_raw)
 }
 
+/// Deletes an archive, `progress_callback` may be NULL and receives every chunk ID
+/// whose reference was released. Returns 0, `DDUP_ERR_NULL_ARG` for NULL
+/// arguments, `DDUP_ERR_NOT_FOUND` if the archive does not exist or another negative
+/// `DDUP_ERR_*` code, see `last_error_message`.
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn repository_delete_archive(
     repo: *mut CRepository,
     archive_name: *const c_char,
     progress_callback: CDeletionProgressCallback,
+    user_data: *mut c_void,
 ) -> c_int {
     if repo.is_null() || archive_name.is_null() {
-        return -1;
+        set_last_error("repository or archive_name is NULL");
+        return DDUP_ERR_NULL_ARG;
     }
 
-    let repo = unsafe { &mut *repo };
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
     let archive_name = unsafe { CStr::from_ptr(archive_name).to_string_lossy().into_owned() };
 
+    let user_data = user_data as usize;
     let progress_callback = progress_callback.map(|callback_fn| {
         Arc::new(move |chunk_id: u64, deleted: bool| {
-            callback_fn(chunk_id, deleted);
+            callback_fn(chunk_id, deleted, user_data as *mut c_void);
         }) as Arc<dyn Fn(u64, bool) + Send + Sync>
     });
 
     match repo.delete_archive(&archive_name, progress_callback) {
         Ok(_) => 0,
-        Err(_) => -1,
+        Err(err) => error_code(err),
+    }
+}
+
+/// Replace files that already exist at the destination, the default.
+pub const DDUP_RESTORE_OVERWRITE: c_int = 0;
+/// Leave files with the same size and modification time alone, only correcting their
+/// mode and owner.
+pub const DDUP_RESTORE_SKIP_IDENTICAL: c_int = 1;
+/// Leave every file and symlink that already exists alone.
+pub const DDUP_RESTORE_SKIP_EXISTING: c_int = 2;
+
+/// What a `repository_restore_archive_ex` did. `skipped` counts the entries left alone
+/// because of the overwrite policy, `warnings` the messages returned by
+/// `repository_restore_warnings`.
+#[repr(C)]
+pub struct CRestoreReport {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    pub bytes: u64,
+    pub skipped: u64,
+    pub warnings: u64,
+}
+
+/// Restores an archive into `destination`, or into the default restore directory of
+/// the repository if it is NULL. `overwrite_policy` is one of the `DDUP_RESTORE_*`
+/// values. `progress_callback` may be NULL, it is called from the restore threads with
+/// `user_data` and first receives the totals of the archive as `ScanComplete`.
+///
+/// Returns 0 and fills `out_report` unless it is NULL, `DDUP_ERR_NULL_ARG`
+/// for NULL arguments or an unknown policy, `DDUP_ERR_NOT_FOUND` if the archive does
+/// not exist, `DDUP_ERR_CORRUPT` or `DDUP_ERR_IO` if reading a chunk or writing a
+/// file failed, see `last_error_message`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::too_many_arguments)]
+pub unsafe extern "C" fn repository_restore_archive_ex(
+    repo: *mut CRepository,
+    archive_name: *const c_char,
+    destination: *const c_char,
+    overwrite_policy: c_int,
+    progress_callback: CProgressEventCallback,
+    user_data: *mut c_void,
+    threads: c_uint,
+    out_report: *mut CRestoreReport,
+) -> c_int {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let mode = match overwrite_policy {
+        DDUP_RESTORE_OVERWRITE => RestoreMode::Overwrite,
+        DDUP_RESTORE_SKIP_IDENTICAL => RestoreMode::SkipIdentical,
+        DDUP_RESTORE_SKIP_EXISTING => RestoreMode::SkipExisting,
+        _ => {
+            set_last_error(format!("unknown overwrite policy {overwrite_policy}"));
+            return DDUP_ERR_NULL_ARG;
+        }
+    };
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+    let archive_name = match unsafe { utf8_argument(archive_name, "archive_name") } {
+        Ok(archive_name) => archive_name,
+        Err(code) => return code,
+    };
+    let destination = if destination.is_null() {
+        None
+    } else {
+        match unsafe { path_argument(destination, "destination") } {
+            Ok(destination) => Some(destination),
+            Err(code) => return code,
+        }
+    };
+
+    let entries = match repo
+        .get_archive(archive_name)
+        .and_then(|archive| archive.into_entries())
+    {
+        Ok(entries) => entries,
+        Err(err) => return error_code(err),
+    };
+    let totals = match entry_totals(&entries) {
+        Ok(totals) => totals,
+        Err(err) => return error_code(err),
+    };
+    let progress = wrap_progress_event_callback(progress_callback, user_data, Some(totals));
+
+    let report = match repo.restore_entries_with_options(
+        archive_name,
+        entries,
+        None,
+        threads as usize,
+        RestoreOptions {
+            destination,
+            mode,
+            progress,
+            ..Default::default()
+        },
+    ) {
+        Ok(report) => report,
+        Err(err) => return error_code(err),
+    };
+
+    if !out_report.is_null() {
+        unsafe {
+            *out_report = CRestoreReport {
+                files: report.files_restored,
+                directories: report.directories_restored,
+                symlinks: report.symlinks_restored,
+                bytes: report.bytes_written,
+                skipped: report.skipped_identical + report.skipped_existing,
+                warnings: report.warnings.len() as u64,
+            }
+        };
+    }
+
+    *handle.restore_warnings.lock().unwrap() = report.warnings;
+
+    0
+}
+
+/// Returns the warnings of the last `repository_restore_archive_ex` on this handle, like
+/// ownership or permissions that could not be applied, as a NULL-terminated array freed
+/// with `free_string_array`. Their number is stored in `out_count` unless it is NULL.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_restore_warnings(
+    repo: *mut CRepository,
+    out_count: *mut c_uint,
+) -> *mut *mut c_char {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return std::ptr::null_mut();
+    }
+
+    let handle = unsafe { &*repo };
+    let warnings = handle.restore_warnings.lock().unwrap();
+
+    if !out_count.is_null() {
+        unsafe { *out_count = warnings.len() as c_uint };
+    }
+
+    null_terminated(
+        warnings
+            .iter()
+            .filter_map(|warning| CString::new(warning.as_str()).ok())
+            .map(CString::into_raw)
+            .collect(),
+    )
+}
+
+/// Deletes an archive and releases its chunk references. `progress_callback` may be
+/// NULL, it first receives the number of chunk references of the archive as
+/// `ScanComplete` and then `ChunkReleased` for each of them. Returns 0 or a negative
+/// `DDUP_ERR_*` code like `repository_delete_archive`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_delete_archive_ex(
+    repo: *mut CRepository,
+    archive_name: *const c_char,
+    progress_callback: CProgressEventCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+    let archive_name = match unsafe { utf8_argument(archive_name, "archive_name") } {
+        Ok(archive_name) => archive_name,
+        Err(code) => return code,
+    };
+
+    let progress = if progress_callback.is_some() {
+        let references = match repo
+            .get_archive(archive_name)
+            .and_then(|archive| chunk_references(archive.entries()))
+        {
+            Ok(references) => references,
+            Err(err) => return error_code(err),
+        };
+
+        wrap_deletion_event_callback(progress_callback, user_data, references)
+    } else {
+        None
+    };
+
+    match repo.delete_archive(archive_name, progress) {
+        Ok(_) => 0,
+        Err(err) => error_code(err),
+    }
+}
+
+/// Restores the entries at `paths` of an archive into `destination`, keeping their
+/// parent directories. `paths` is an array of `count` UTF-8 strings relative to the
+/// archive root, it and the strings stay owned by the caller. `progress_callback`
+/// may be NULL, it is called from the restore threads with `user_data`.
+///
+/// Returns 0 on success or a negative `DDUP_ERR_*` code like
+/// `repository_restore_archive_ex`, in which case `last_error_message` describes the
+/// failure. Nothing is restored and `DDUP_ERR_NOT_FOUND` is returned if a path does
+/// not exist in the archive.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_restore_paths(
+    repo: *mut CRepository,
+    archive_name: *const c_char,
+    paths: *const *const c_char,
+    count: usize,
+    destination: *const c_char,
+    progress_callback: CProgressCallback,
+    user_data: *mut c_void,
+    threads: c_uint,
+) -> c_int {
+    if repo.is_null() || (paths.is_null() && count > 0) {
+        set_last_error("repository or paths is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+    let (archive_name, destination) = match unsafe {
+        (
+            utf8_argument(archive_name, "archive_name"),
+            path_argument(destination, "destination"),
+        )
+    } {
+        (Ok(archive_name), Ok(destination)) => (archive_name, destination),
+        (Err(code), _) | (_, Err(code)) => return code,
+    };
+
+    let mut path_list = Vec::with_capacity(count);
+    for i in 0..count {
+        match unsafe { utf8_argument(*paths.add(i), "path") } {
+            Ok(path) => path_list.push(Path::new(path)),
+            Err(code) => return code,
+        }
+    }
+
+    match repo.restore_paths(
+        archive_name,
+        &path_list,
+        wrap_progress_callback(progress_callback, user_data),
+        threads as usize,
+        RestoreOptions {
+            destination: Some(destination),
+            ..Default::default()
+        },
+    ) {
+        Ok(_) => 0,
+        Err(err) => error_code(err),
+    }
+}
+
+/// Restores a whole archive into `destination`, shared by the byte and wide variants.
+pub(crate) unsafe fn restore_archive_into(
+    repo: *mut CRepository,
+    archive_name: *const c_char,
+    destination: PathBuf,
+    progress_callback: CProgressCallback,
+    user_data: *mut c_void,
+    threads: c_uint,
+) -> c_int {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+    let archive_name = match unsafe { utf8_argument(archive_name, "archive_name") } {
+        Ok(archive_name) => archive_name,
+        Err(code) => return code,
+    };
+
+    let entries = match repo
+        .get_archive(archive_name)
+        .and_then(|archive| archive.into_entries())
+    {
+        Ok(entries) => entries,
+        Err(err) => return error_code(err),
+    };
+
+    match repo.restore_entries_with_options(
+        archive_name,
+        entries,
+        wrap_progress_callback(progress_callback, user_data),
+        threads as usize,
+        RestoreOptions {
+            destination: Some(destination),
+            ..Default::default()
+        },
+    ) {
+        Ok(_) => 0,
+        Err(err) => error_code(err),
+    }
+}
+
+/// Restores a whole archive into `destination` instead of the repository's
+/// `archives-restored` directory, see `repository_restore_paths` for the arguments
+/// and return codes.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_restore_archive_to(
+    repo: *mut CRepository,
+    archive_name: *const c_char,
+    destination: *const c_char,
+    progress_callback: CProgressCallback,
+    user_data: *mut c_void,
+    threads: c_uint,
+) -> c_int {
+    match unsafe { path_argument(destination, "destination") } {
+        Ok(destination) => unsafe {
+            restore_archive_into(
+                repo,
+                archive_name,
+                destination,
+                progress_callback,
+                user_data,
+                threads,
+            )
+        },
+        Err(code) => code,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{archive_find_entry, free_archive};
+    use crate::entries::free_entry;
+    use crate::progress::{CProgressEvent, CProgressEventKind};
+    use crate::test_util::{c_path, c_string, file_content, strings, Fixture};
+    use crate::{free_string, DDUP_ERR_NOT_FOUND};
+    use std::ptr::null_mut;
+
+    /// Collects the strings passed to callbacks through their `user_data`.
+    type Calls = Mutex<Vec<String>>;
+
+    extern "C" fn record_path(path: *const c_char, user_data: *mut c_void) {
+        let calls = unsafe { &*(user_data as *const Calls) };
+        let path = unsafe { CStr::from_ptr(path) }.to_string_lossy();
+
+        calls.lock().unwrap().push(format!("progress {path}"));
+    }
+
+    extern "C" fn record_compression(
+        path: *const c_char,
+        user_data: *mut c_void,
+    ) -> CCompressionFormat {
+        let calls = unsafe { &*(user_data as *const Calls) };
+        let path = unsafe { CStr::from_ptr(path) }.to_string_lossy();
+
+        calls.lock().unwrap().push(format!("compression {path}"));
+        CCompressionFormat::None
+    }
+
+    extern "C" fn record_chunk(chunk_id: u64, _deleted: bool, user_data: *mut c_void) {
+        let calls = unsafe { &*(user_data as *const Calls) };
+
+        calls.lock().unwrap().push(chunk_id.to_string());
+    }
+
+    /// A `CProgressEvent` copied out of the callback.
+    #[derive(Debug)]
+    struct Event {
+        kind: CProgressEventKind,
+        path: Option<String>,
+        current_items: u64,
+        total_items: u64,
+        current_bytes: u64,
+        total_bytes: u64,
+    }
+
+    extern "C" fn record_event(event: *const CProgressEvent, user_data: *mut c_void) {
+        let events = unsafe { &*(user_data as *const Mutex<Vec<Event>>) };
+        let event = unsafe { &*event };
+
+        events.lock().unwrap().push(Event {
+            kind: event.kind,
+            path: (!event.path.is_null()).then(|| {
+                unsafe { CStr::from_ptr(event.path) }
+                    .to_string_lossy()
+                    .into_owned()
+            }),
+            current_items: event.current_items,
+            total_items: event.total_items,
+            current_bytes: event.current_bytes,
+            total_bytes: event.total_bytes,
+        });
+    }
+
+    fn count(calls: &Calls, prefix: &str, suffix: &str) -> usize {
+        calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| call.starts_with(prefix) && call.ends_with(suffix))
+            .count()
+    }
+
+    fn user_data<T>(value: &T) -> *mut c_void {
+        value as *const T as *mut c_void
+    }
+
+    #[test]
+    fn restore_paths_restores_the_selected_entries_only() {
+        let fixture = Fixture::new("restore-paths");
+        let name = c_string("archive");
+        let nested = c_string("directory/nested");
+        let missing = c_string("missing");
+        let destination = fixture.directory.join("destination");
+        let other = fixture.directory.join("other");
+        let everything = fixture.directory.join("everything");
+
+        unsafe {
+            let paths = [nested.as_ptr()];
+            assert_eq!(
+                repository_restore_paths(
+                    fixture.repo,
+                    name.as_ptr(),
+                    paths.as_ptr(),
+                    paths.len(),
+                    c_path(&destination).as_ptr(),
+                    None,
+                    null_mut(),
+                    2,
+                ),
+                0
+            );
+
+            // nothing is restored if one of the paths does not exist
+            let paths = [nested.as_ptr(), missing.as_ptr()];
+            assert_eq!(
+                repository_restore_paths(
+                    fixture.repo,
+                    name.as_ptr(),
+                    paths.as_ptr(),
+                    paths.len(),
+                    c_path(&other).as_ptr(),
+                    None,
+                    null_mut(),
+                    2,
+                ),
+                DDUP_ERR_NOT_FOUND
+            );
+
+            assert_eq!(
+                repository_restore_archive_to(
+                    fixture.repo,
+                    name.as_ptr(),
+                    c_path(&everything).as_ptr(),
+                    None,
+                    null_mut(),
+                    2,
+                ),
+                0
+            );
+        }
+
+        assert_eq!(
+            std::fs::read_to_string(destination.join("directory/nested/deep")).unwrap(),
+            "deep"
+        );
+        assert!(!destination.join("file").exists());
+        assert!(!destination.join("directory/small").exists());
+        assert!(!other.join("directory").exists());
+
+        assert_eq!(
+            std::fs::read(everything.join("file")).unwrap(),
+            file_content()
+        );
+        assert_eq!(
+            std::fs::read_to_string(everything.join("directory/small")).unwrap(),
+            "small"
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            std::fs::read_link(everything.join("link")).unwrap(),
+            Path::new("file")
+        );
+    }
+
+    #[test]
+    fn callbacks_receive_their_user_data() {
+        let fixture = Fixture::new("user-data");
+        let calls = Calls::default();
+        let restored = Calls::default();
+        let name = c_string("callbacks");
+        let small = c_string("directory/small");
+
+        unsafe {
+            let archive = repository_create_archive(
+                fixture.repo,
+                name.as_ptr(),
+                c_path(&fixture.source()).as_ptr(),
+                Some(record_path),
+                Some(record_compression),
+                user_data(&calls),
+                2,
+            );
+            assert!(!archive.is_null());
+            free_archive(archive);
+
+            let paths = [small.as_ptr()];
+            assert_eq!(
+                repository_restore_paths(
+                    fixture.repo,
+                    name.as_ptr(),
+                    paths.as_ptr(),
+                    paths.len(),
+                    c_path(&fixture.directory.join("destination")).as_ptr(),
+                    Some(record_path),
+                    user_data(&restored),
+                    2,
+                ),
+                0
+            );
+        }
+
+        for file in ["file", "small", "deep"] {
+            assert_eq!(count(&calls, "progress", file), 1, "{file}");
+            assert_eq!(count(&calls, "compression", file), 1, "{file}");
+        }
+        assert_eq!(count(&restored, "progress", "small"), 1);
+        assert_eq!(count(&restored, "progress", "deep"), 0);
+    }
+
+    #[test]
+    fn flush_makes_the_chunk_index_visible_to_new_handles() {
+        let fixture = Fixture::new("flush");
+        let stats = |repo| {
+            let mut stats = CChunkStats {
+                struct_size: std::mem::size_of::<CChunkStats>(),
+                chunk_count: 0,
+                total_references: 0,
+                deleted_id_count: 0,
+                chunk_size: 0,
+                max_chunk_count: 0,
+            };
+            assert_eq!(unsafe { repository_chunk_stats(repo, &mut stats) }, 0);
+
+            stats
+        };
+
+        unsafe {
+            assert_eq!(
+                repository_set_save_on_drop(fixture.repo, false),
+                fixture.repo
+            );
+            assert_eq!(repository_flush(fixture.repo), 0);
+            assert_eq!(repository_save(fixture.repo), 0);
+
+            let reopened = open_repository(
+                c_path(&fixture.directory.join("repository")).as_ptr(),
+                std::ptr::null(),
+            );
+            assert!(!reopened.is_null());
+
+            let (flushed, loaded) = (stats(fixture.repo), stats(reopened));
+            assert!(flushed.chunk_count > 0);
+            assert_eq!(loaded.chunk_count, flushed.chunk_count);
+            assert_eq!(loaded.total_references, flushed.total_references);
+
+            free_repository(reopened);
+        }
+    }
+
+    #[test]
+    fn clean_plan_predicts_what_clean_deletes() {
+        let fixture = Fixture::new("clean-plan");
+        let deleted = Calls::default();
+        let mut plan = CCleanPlan {
+            chunk_count: u64::MAX,
+            bytes: u64::MAX,
+        };
+        let mut result = CCleanResult {
+            chunks_deleted: 0,
+            bytes_reclaimed: 0,
+            duration_ms: 0,
+        };
+
+        unsafe {
+            assert_eq!(repository_clean_plan(fixture.repo, &mut plan), 0);
+            assert_eq!((plan.chunk_count, plan.bytes), (0, 0));
+
+            // releases every reference without deleting, like an interrupted delete
+            let chunk_count = {
+                let repository = CRepository::as_handle(fixture.repo).repository();
+                let chunk_count = repository.chunk_index.stats().chunk_count;
+                for chunk_id in 1..=chunk_count {
+                    while repository.chunk_index.references_by_id(chunk_id).unwrap() > 0 {
+                        repository.chunk_index.dereference_chunk_id(chunk_id, false);
+                    }
+                }
+
+                chunk_count
+            };
+
+            assert_eq!(repository_clean_plan(fixture.repo, &mut plan), 0);
+            assert_eq!(plan.chunk_count, chunk_count);
+            assert!(plan.bytes > 0);
+
+            assert_eq!(
+                repository_clean(
+                    fixture.repo,
+                    Some(record_chunk),
+                    user_data(&deleted),
+                    &mut result
+                ),
+                0
+            );
+            assert_eq!(result.chunks_deleted, plan.chunk_count);
+            assert_eq!(result.bytes_reclaimed, plan.bytes);
+            assert_eq!(deleted.lock().unwrap().len() as u64, plan.chunk_count);
+
+            assert_eq!(repository_clean_plan(fixture.repo, &mut plan), 0);
+            assert_eq!((plan.chunk_count, plan.bytes), (0, 0));
+        }
+    }
+
+    #[test]
+    fn chunk_stats_fill_as_much_as_the_caller_knows() {
+        let fixture = Fixture::new("chunk-stats");
+        let mut stats = CChunkStats {
+            struct_size: 0,
+            chunk_count: u64::MAX,
+            total_references: u64::MAX,
+            deleted_id_count: u64::MAX,
+            chunk_size: u64::MAX,
+            max_chunk_count: u64::MAX,
+        };
+
+        unsafe {
+            assert_eq!(
+                repository_chunk_stats(fixture.repo, &mut stats),
+                DDUP_ERR_NULL_ARG
+            );
+
+            // an older caller only knowing the chunk count
+            stats.struct_size = std::mem::size_of::<usize>() + std::mem::size_of::<u64>();
+            assert_eq!(repository_chunk_stats(fixture.repo, &mut stats), 0);
+            assert!(stats.chunk_count > 0 && stats.chunk_count < u64::MAX);
+            assert_eq!(stats.total_references, u64::MAX);
+            assert_eq!(stats.max_chunk_count, u64::MAX);
+
+            stats.struct_size = std::mem::size_of::<CChunkStats>();
+            assert_eq!(repository_chunk_stats(fixture.repo, &mut stats), 0);
+            assert!(stats.total_references >= stats.chunk_count);
+            assert_eq!(stats.deleted_id_count, 0);
+            assert_eq!(stats.chunk_size, 64);
+            assert_eq!(stats.max_chunk_count, 0);
+
+            assert!(repository_chunk_references(fixture.repo, 1) >= 1);
+            assert_eq!(repository_chunk_references(fixture.repo, u64::MAX), -1);
+        }
+    }
+
+    #[test]
+    fn create_archive_ex_leaves_out_excluded_paths() {
+        let fixture = Fixture::new("create-excludes");
+        std::fs::write(fixture.source().join("scratch.tmp"), "scratch").unwrap();
+
+        let name = c_string("excluded");
+        let nested = c_string("nested");
+        let tmp = c_string("*.tmp");
+        let excludes = [nested.as_ptr(), tmp.as_ptr()];
+        let mut skipped = null_mut();
+
+        unsafe {
+            let archive = repository_create_archive_ex(
+                fixture.repo,
+                name.as_ptr(),
+                c_path(&fixture.source()).as_ptr(),
+                excludes.as_ptr(),
+                excludes.len(),
+                None,
+                null_mut(),
+                None,
+                null_mut(),
+                DDUP_CREATE_ABORT_ON_ERROR,
+                2,
+                &mut skipped,
+            );
+            assert!(!archive.is_null());
+
+            let mut skipped = strings(skipped);
+            skipped.sort();
+            assert_eq!(skipped, ["directory/nested", "scratch.tmp"]);
+
+            let find = |path: &str| archive_find_entry(archive, c_string(path).as_ptr());
+            for path in ["directory/nested", "directory/nested/deep", "scratch.tmp"] {
+                assert!(find(path).is_null(), "{path}");
+            }
+            let small = find("directory/small");
+            assert!(!small.is_null());
+            free_entry(small);
+            free_archive(archive);
+
+            let mut skipped = null_mut();
+            assert!(repository_create_archive_ex(
+                fixture.repo,
+                c_string("policy").as_ptr(),
+                c_path(&fixture.source()).as_ptr(),
+                std::ptr::null(),
+                0,
+                None,
+                null_mut(),
+                None,
+                null_mut(),
+                7,
+                2,
+                &mut skipped,
+            )
+            .is_null());
+            assert!(skipped.is_null());
+        }
+    }
+
+    #[test]
+    fn cloned_handles_share_the_repository() {
+        let fixture = Fixture::new("clone-handle");
+
+        unsafe {
+            let clone = repository_clone_handle(fixture.repo);
+            assert!(!clone.is_null());
+
+            // the clone is used from another thread while the original stays here
+            let clone_address = clone as usize;
+            let source = fixture.source();
+            std::thread::spawn(move || {
+                let archive = repository_create_archive(
+                    clone_address as *mut CRepository,
+                    c_string("from-clone").as_ptr(),
+                    c_path(&source).as_ptr(),
+                    None,
+                    None,
+                    null_mut(),
+                    1,
+                );
+                assert!(!archive.is_null());
+                free_archive(archive);
+            })
+            .join()
+            .unwrap();
+            free_repository(clone);
+
+            let mut count = 0;
+            let mut names = strings(repository_list_archives(fixture.repo, &mut count));
+            names.sort();
+            assert_eq!(count, 2);
+            assert_eq!(names, ["archive", "from-clone"]);
+        }
+    }
+
+    #[test]
+    fn progress_events_report_counts_and_bytes() {
+        let fixture = Fixture::new("progress-events");
+        let name = c_string("events");
+        let created = Mutex::new(Vec::<Event>::new());
+        let deleted = Mutex::new(Vec::<Event>::new());
+
+        unsafe {
+            let archive = repository_create_archive_ex(
+                fixture.repo,
+                name.as_ptr(),
+                c_path(&fixture.source()).as_ptr(),
+                std::ptr::null(),
+                0,
+                Some(record_event),
+                user_data(&created),
+                None,
+                null_mut(),
+                DDUP_CREATE_ABORT_ON_ERROR,
+                2,
+                null_mut(),
+            );
+            assert!(!archive.is_null());
+            free_archive(archive);
+
+            assert_eq!(
+                repository_delete_archive_ex(
+                    fixture.repo,
+                    name.as_ptr(),
+                    Some(record_event),
+                    user_data(&deleted),
+                ),
+                0
+            );
+        }
+
+        let created = created.into_inner().unwrap();
+        let total_bytes = (file_content().len() + "small".len() + "deep".len()) as u64;
+        assert_eq!(created[0].kind, CProgressEventKind::ScanComplete);
+        assert_eq!(
+            (created[0].total_items, created[0].total_bytes),
+            (3, total_bytes)
+        );
+
+        let files = created
+            .iter()
+            .filter(|event| event.kind == CProgressEventKind::FileDone)
+            .collect::<Vec<_>>();
+        assert_eq!(files.len(), 3);
+        assert!(files.iter().all(|event| event.path.is_some()));
+        assert_eq!(created.iter().map(|e| e.current_items).max(), Some(3));
+        assert_eq!(
+            created.iter().map(|e| e.current_bytes).max(),
+            Some(total_bytes)
+        );
+
+        let deleted = deleted.into_inner().unwrap();
+        assert_eq!(deleted[0].kind, CProgressEventKind::ScanComplete);
+        let released = deleted
+            .iter()
+            .filter(|event| event.kind == CProgressEventKind::ChunkReleased)
+            .count() as u64;
+        assert!(released > 0);
+        assert_eq!(deleted[0].total_items, released);
+        assert!(deleted.iter().all(|event| event.current_bytes == 0));
+    }
+
+    #[test]
+    fn restore_archive_ex_applies_the_overwrite_policy() {
+        let fixture = Fixture::new("restore-ex");
+        let name = c_string("archive");
+        let destination = fixture.directory.join("destination");
+        let events = Mutex::new(Vec::<Event>::new());
+        let mut report = CRestoreReport {
+            files: 0,
+            directories: 0,
+            symlinks: 0,
+            bytes: 0,
+            skipped: 0,
+            warnings: 0,
+        };
+
+        let restore = |policy, report: &mut CRestoreReport| unsafe {
+            repository_restore_archive_ex(
+                fixture.repo,
+                name.as_ptr(),
+                c_path(&destination).as_ptr(),
+                policy,
+                Some(record_event),
+                user_data(&events),
+                2,
+                report,
+            )
+        };
+
+        let total_bytes = (file_content().len() + "small".len() + "deep".len()) as u64;
+        assert_eq!(restore(DDUP_RESTORE_OVERWRITE, &mut report), 0);
+        assert_eq!(report.files, 3);
+        assert!(report.directories >= 2);
+        assert_eq!(report.symlinks, if cfg!(unix) { 1 } else { 0 });
+        assert_eq!(report.bytes, total_bytes);
+        assert_eq!(report.skipped, 0);
+        assert_eq!(
+            std::fs::read(destination.join("file")).unwrap(),
+            file_content()
+        );
+
+        {
+            let events = events.lock().unwrap();
+            assert_eq!(events[0].kind, CProgressEventKind::ScanComplete);
+            assert_eq!(
+                (events[0].total_items, events[0].total_bytes),
+                (3, total_bytes)
+            );
+        }
+
+        std::fs::write(destination.join("file"), "changed").unwrap();
+        assert_eq!(restore(DDUP_RESTORE_SKIP_EXISTING, &mut report), 0);
+        assert!(report.skipped >= 3);
+        assert_eq!(
+            std::fs::read_to_string(destination.join("file")).unwrap(),
+            "changed"
+        );
+
+        assert_eq!(restore(DDUP_RESTORE_SKIP_IDENTICAL, &mut report), 0);
+        assert_eq!(
+            std::fs::read(destination.join("file")).unwrap(),
+            file_content()
+        );
+
+        assert_eq!(restore(7, &mut report), DDUP_ERR_NULL_ARG);
+
+        let mut count = u32::MAX;
+        let warnings = unsafe { strings(repository_restore_warnings(fixture.repo, &mut count)) };
+        assert_eq!(warnings.len() as u64, report.warnings);
+        assert_eq!(count as u64, report.warnings);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_are_taken_as_bytes() {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+        let fixture = Fixture::new("byte-paths");
+        let name = c_string("archive");
+        let directory = fixture.directory.join(OsStr::from_bytes(b"not-utf8-\xff"));
+
+        unsafe {
+            let repo = new_repository(c_path(&directory).as_ptr(), 64, 0);
+            assert!(!repo.is_null());
+
+            let archive = repository_create_archive(
+                repo,
+                name.as_ptr(),
+                c_path(&fixture.source()).as_ptr(),
+                None,
+                None,
+                null_mut(),
+                1,
+            );
+            assert!(!archive.is_null());
+            free_archive(archive);
+
+            let restored = repository_restore_archive(repo, name.as_ptr(), None, null_mut(), 1);
+            assert!(!restored.is_null());
+            let restored_path = PathBuf::from(std::ffi::OsString::from_vec(
+                CStr::from_ptr(restored).to_bytes().to_vec(),
+            ));
+            free_string(restored);
+
+            assert!(restored_path.starts_with(&directory));
+            assert_eq!(
+                std::fs::read(restored_path.join("file")).unwrap(),
+                file_content()
+            );
+
+            free_repository(repo);
+        }
     }
 }
@@ -0,0 +1,468 @@
+use crate::repository::CRepository;
+use crate::{set_last_error, DDUP_ERR_NOT_FOUND, DDUP_ERR_NULL_ARG};
+use ddup_bak::chunks::storage::ChunkStorage;
+use ddup_bak::chunks::ChunkHash;
+use ddup_bak::repository::Repository;
+use std::ffi::*;
+use std::io::Read;
+use std::sync::Arc;
+
+/// Receives a piece of chunk content from the `read` function of a `CChunkStorage`,
+/// along with the `sink_data` passed to `read`. Returns 0 to continue.
+pub type CChunkSink =
+    Option<extern "C" fn(data: *const u8, length: usize, sink_data: *mut c_void) -> c_int>;
+/// Receives one 32 byte chunk hash from the `list` function of a `CChunkStorage`.
+pub type CChunkHashSink = Option<extern "C" fn(hash: *const u8, sink_data: *mut c_void)>;
+
+/// A chunk storage implemented by the host. Every `hash` points to 32 bytes.
+///
+/// All functions return 0 on success, `DDUP_ERR_NOT_FOUND` for a chunk that does not
+/// exist and any other negative value for a failure. They are called concurrently from
+/// the worker threads of the library, so the functions and `ctx` must be thread-safe.
+///
+/// - `read` passes the whole content of a chunk to `sink` in one or more pieces, the
+///   library buffers it until `read` returns.
+/// - `write` receives the whole content of a chunk at once. Chunks are content-addressed,
+///   writing a chunk that already exists must succeed without changing it.
+/// - `remove` deletes a chunk.
+/// - `exists` returns 1 if the chunk exists and 0 if it does not.
+/// - `size` is optional, without it the size of a chunk is determined by reading it.
+/// - `list` is optional and passes every stored hash to `sink`, it is needed to rebuild
+///   or verify the repository.
+/// - `flush` is optional and makes every written chunk durable, see `repository_flush`.
+/// - `release` is optional and called with `ctx` once the repository is freed.
+///
+/// The struct itself is copied, only `ctx` has to stay valid until `release` is called.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CChunkStorage {
+    pub ctx: *mut c_void,
+    pub read: Option<
+        extern "C" fn(
+            ctx: *mut c_void,
+            hash: *const u8,
+            sink: CChunkSink,
+            sink_data: *mut c_void,
+        ) -> c_int,
+    >,
+    pub write: Option<
+        extern "C" fn(ctx: *mut c_void, hash: *const u8, data: *const u8, length: usize) -> c_int,
+    >,
+    pub remove: Option<extern "C" fn(ctx: *mut c_void, hash: *const u8) -> c_int>,
+    pub exists: Option<extern "C" fn(ctx: *mut c_void, hash: *const u8) -> c_int>,
+    pub size: Option<extern "C" fn(ctx: *mut c_void, hash: *const u8, size: *mut u64) -> c_int>,
+    pub list: Option<
+        extern "C" fn(ctx: *mut c_void, sink: CChunkHashSink, sink_data: *mut c_void) -> c_int,
+    >,
+    pub flush: Option<extern "C" fn(ctx: *mut c_void) -> c_int>,
+    pub release: Option<extern "C" fn(ctx: *mut c_void)>,
+}
+
+/// Implements `ChunkStorage` over the functions of a `CChunkStorage`.
+struct ForeignChunkStorage(CChunkStorage);
+
+// SAFETY: the header requires the functions and `ctx` of a `CChunkStorage` to be
+// thread-safe, the adapter itself holds no other state.
+unsafe impl Send for ForeignChunkStorage {}
+unsafe impl Sync for ForeignChunkStorage {}
+
+impl Drop for ForeignChunkStorage {
+    fn drop(&mut self) {
+        if let Some(release) = self.0.release {
+            release(self.0.ctx);
+        }
+    }
+}
+
+/// Maps the return code of a storage function to an error.
+fn storage_result(code: c_int, function: &str) -> std::io::Result<()> {
+    match code {
+        0 => Ok(()),
+        DDUP_ERR_NOT_FOUND => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Chunk not found in storage",
+        )),
+        code => Err(std::io::Error::other(format!(
+            "Chunk storage {function} failed with {code}"
+        ))),
+    }
+}
+
+extern "C" fn collect_chunk(data: *const u8, length: usize, sink_data: *mut c_void) -> c_int {
+    let buffer = unsafe { &mut *(sink_data as *mut Vec<u8>) };
+    if length > 0 {
+        buffer.extend_from_slice(unsafe { std::slice::from_raw_parts(data, length) });
+    }
+
+    0
+}
+
+extern "C" fn collect_hash(hash: *const u8, sink_data: *mut c_void) {
+    let hashes = unsafe { &mut *(sink_data as *mut Vec<ChunkHash>) };
+    let mut chunk = [0; 32];
+    chunk.copy_from_slice(unsafe { std::slice::from_raw_parts(hash, 32) });
+
+    hashes.push(chunk);
+}
+
+impl ForeignChunkStorage {
+    fn read_chunk(&self, chunk: &ChunkHash) -> std::io::Result<Vec<u8>> {
+        let read = self.0.read.expect("validated on creation");
+
+        let mut buffer = Vec::new();
+        storage_result(
+            read(
+                self.0.ctx,
+                chunk.as_ptr(),
+                Some(collect_chunk),
+                &mut buffer as *mut Vec<u8> as *mut c_void,
+            ),
+            "read",
+        )?;
+
+        Ok(buffer)
+    }
+}
+
+impl ChunkStorage for ForeignChunkStorage {
+    fn read_chunk_content(
+        &self,
+        chunk: &ChunkHash,
+    ) -> std::io::Result<Box<dyn std::io::Read + Send>> {
+        Ok(Box::new(std::io::Cursor::new(self.read_chunk(chunk)?)))
+    }
+
+    fn write_chunk_content(
+        &self,
+        chunk: &ChunkHash,
+        mut content: Box<dyn std::io::Read + Send>,
+    ) -> std::io::Result<()> {
+        let mut buffer = Vec::new();
+        content.read_to_end(&mut buffer)?;
+
+        self.write_chunk_bytes(chunk, &buffer)
+    }
+
+    fn write_chunk_bytes(&self, chunk: &ChunkHash, content: &[u8]) -> std::io::Result<()> {
+        let write = self.0.write.expect("validated on creation");
+        let exists = self.0.exists.expect("validated on creation");
+
+        match exists(self.0.ctx, chunk.as_ptr()) {
+            0 => {}
+            1 => return Ok(()),
+            code => storage_result(code, "exists")?,
+        }
+
+        storage_result(
+            write(self.0.ctx, chunk.as_ptr(), content.as_ptr(), content.len()),
+            "write",
+        )
+    }
+
+    fn delete_chunk_content(&self, chunk: &ChunkHash) -> std::io::Result<()> {
+        let remove = self.0.remove.expect("validated on creation");
+
+        storage_result(remove(self.0.ctx, chunk.as_ptr()), "remove")
+    }
+
+    fn chunk_size(&self, chunk: &ChunkHash) -> std::io::Result<u64> {
+        let Some(size_fn) = self.0.size else {
+            let exists = self.0.exists.expect("validated on creation");
+
+            return match exists(self.0.ctx, chunk.as_ptr()) {
+                0 => storage_result(DDUP_ERR_NOT_FOUND, "exists").map(|_| 0),
+                1 => Ok(self.read_chunk(chunk)?.len() as u64),
+                code => storage_result(code, "exists").map(|_| 0),
+            };
+        };
+
+        let mut size = 0;
+        storage_result(size_fn(self.0.ctx, chunk.as_ptr(), &mut size), "size")?;
+
+        Ok(size)
+    }
+
+    fn list_chunk_hashes(&self) -> std::io::Result<Vec<ChunkHash>> {
+        let Some(list) = self.0.list else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "The chunk storage cannot list its chunks",
+            ));
+        };
+
+        let mut hashes = Vec::new();
+        storage_result(
+            list(
+                self.0.ctx,
+                Some(collect_hash),
+                &mut hashes as *mut Vec<ChunkHash> as *mut c_void,
+            ),
+            "list",
+        )?;
+
+        Ok(hashes)
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        match self.0.flush {
+            Some(flush) => storage_result(flush(self.0.ctx), "flush"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Copies a `CChunkStorage`, setting the last error if a required function is missing.
+unsafe fn foreign_storage(storage: *const CChunkStorage) -> Result<Arc<dyn ChunkStorage>, c_int> {
+    if storage.is_null() {
+        set_last_error("storage is NULL");
+        return Err(DDUP_ERR_NULL_ARG);
+    }
+
+    let storage = unsafe { *storage };
+    if storage.read.is_none()
+        || storage.write.is_none()
+        || storage.remove.is_none()
+        || storage.exists.is_none()
+    {
+        set_last_error("storage requires read, write, remove and exists");
+        return Err(DDUP_ERR_NULL_ARG);
+    }
+
+    Ok(Arc::new(ForeignChunkStorage(storage)))
+}
+
+/// Opens a repository whose chunks are kept in a storage implemented by the host,
+/// `chunks_directory` may be NULL. Returns NULL on failure, see `last_error_message`.
+/// Once the storage was accepted, its `release` is also called if opening fails.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn open_repository_with_storage(
+    directory: *const c_char,
+    chunks_directory: *const c_char,
+    storage: *const CChunkStorage,
+) -> *mut CRepository {
+    let storage = match unsafe { foreign_storage(storage) } {
+        Ok(storage) => storage,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let directory = match unsafe { crate::path_argument(directory, "directory") } {
+        Ok(directory) => directory,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let chunks_directory = if chunks_directory.is_null() {
+        None
+    } else {
+        match unsafe { crate::path_argument(chunks_directory, "chunks_directory") } {
+            Ok(chunks_directory) => Some(chunks_directory),
+            Err(_) => return std::ptr::null_mut(),
+        }
+    };
+
+    match Repository::open(&directory, chunks_directory.as_deref(), Some(storage)) {
+        Ok(repository) => CRepository::from_repository(repository),
+        Err(err) => {
+            set_last_error(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Creates a repository whose chunks are kept in a storage implemented by the host.
+/// Returns NULL on failure, see `last_error_message`. `release` of the storage is also
+/// called if creating fails, unless the storage itself was rejected.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn new_repository_with_storage(
+    directory: *const c_char,
+    chunk_size: c_uint,
+    max_chunk_count: c_uint,
+    storage: *const CChunkStorage,
+) -> *mut CRepository {
+    let storage = match unsafe { foreign_storage(storage) } {
+        Ok(storage) => storage,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let directory = match unsafe { crate::path_argument(directory, "directory") } {
+        Ok(directory) => directory,
+        Err(_) => return std::ptr::null_mut(),
+    };
+
+    match Repository::new(
+        &directory,
+        chunk_size as usize,
+        max_chunk_count as usize,
+        Some(storage),
+    ) {
+        Ok(repository) => CRepository::from_repository(repository),
+        Err(err) => {
+            set_last_error(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::free_archive;
+    use crate::repository::{
+        free_repository, repository_create_archive, repository_restore_archive,
+    };
+    use crate::{free_string, last_error_message};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    /// The chunks of the toy storage below, shared by the worker threads.
+    type Chunks = Mutex<HashMap<ChunkHash, Vec<u8>>>;
+
+    static RELEASED: AtomicBool = AtomicBool::new(false);
+
+    fn chunks<'a>(ctx: *mut c_void) -> &'a Chunks {
+        unsafe { &*(ctx as *const Chunks) }
+    }
+
+    fn hash(hash: *const u8) -> ChunkHash {
+        unsafe { *(hash as *const ChunkHash) }
+    }
+
+    extern "C" fn read(
+        ctx: *mut c_void,
+        chunk: *const u8,
+        sink: CChunkSink,
+        sink_data: *mut c_void,
+    ) -> c_int {
+        let chunks = chunks(ctx).lock().unwrap();
+        let Some(content) = chunks.get(&hash(chunk)) else {
+            return DDUP_ERR_NOT_FOUND;
+        };
+
+        // hands the content over in two pieces, like a streaming store would
+        let (first, second) = content.split_at(content.len() / 2);
+        for piece in [first, second] {
+            let code = sink.unwrap()(piece.as_ptr(), piece.len(), sink_data);
+            if code != 0 {
+                return code;
+            }
+        }
+
+        0
+    }
+
+    extern "C" fn write(
+        ctx: *mut c_void,
+        chunk: *const u8,
+        data: *const u8,
+        length: usize,
+    ) -> c_int {
+        let content = unsafe { std::slice::from_raw_parts(data, length) };
+        chunks(ctx)
+            .lock()
+            .unwrap()
+            .insert(hash(chunk), content.to_vec());
+
+        0
+    }
+
+    extern "C" fn remove(ctx: *mut c_void, chunk: *const u8) -> c_int {
+        match chunks(ctx).lock().unwrap().remove(&hash(chunk)) {
+            Some(_) => 0,
+            None => DDUP_ERR_NOT_FOUND,
+        }
+    }
+
+    extern "C" fn exists(ctx: *mut c_void, chunk: *const u8) -> c_int {
+        chunks(ctx).lock().unwrap().contains_key(&hash(chunk)) as c_int
+    }
+
+    extern "C" fn release(ctx: *mut c_void) {
+        drop(unsafe { Box::from_raw(ctx as *mut Chunks) });
+        RELEASED.store(true, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn backup_and_restore_through_a_storage_vtable() {
+        let directory =
+            std::env::temp_dir().join(format!("ddup-bak-c-storage-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let source = directory.join("source");
+        std::fs::create_dir_all(source.join("directory")).unwrap();
+        let content = (0..10_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(source.join("file"), &content).unwrap();
+        std::fs::write(source.join("directory/small"), "small").unwrap();
+
+        let ctx = Box::into_raw(Box::new(Chunks::default()));
+        let storage = CChunkStorage {
+            ctx: ctx as *mut c_void,
+            read: Some(read),
+            write: Some(write),
+            remove: Some(remove),
+            exists: Some(exists),
+            size: None,
+            list: None,
+            flush: None,
+            release: Some(release),
+        };
+
+        let path = |path: &std::path::Path| CString::new(path.to_str().unwrap()).unwrap();
+        let name = CString::new("archive").unwrap();
+
+        unsafe {
+            let repo = new_repository_with_storage(
+                path(&directory.join("repository")).as_ptr(),
+                1024,
+                0,
+                &storage,
+            );
+            assert!(!repo.is_null());
+
+            let archive = repository_create_archive(
+                repo,
+                name.as_ptr(),
+                path(&source).as_ptr(),
+                None,
+                None,
+                std::ptr::null_mut(),
+                2,
+            );
+            assert!(!archive.is_null());
+            free_archive(archive);
+
+            // every chunk went to the storage of the host, none to the chunks directory
+            assert!(chunks(ctx as *mut c_void).lock().unwrap().len() >= 10);
+            assert!(!directory
+                .join("repository/.ddup-bak/chunks")
+                .read_dir()
+                .unwrap()
+                .any(|entry| entry.unwrap().file_type().unwrap().is_dir()));
+
+            let restored =
+                repository_restore_archive(repo, name.as_ptr(), None, std::ptr::null_mut(), 2);
+            assert!(!restored.is_null());
+            let restored_path =
+                std::path::PathBuf::from(CStr::from_ptr(restored).to_str().unwrap());
+            free_string(restored);
+
+            assert_eq!(std::fs::read(restored_path.join("file")).unwrap(), content);
+            assert_eq!(
+                std::fs::read(restored_path.join("directory/small")).unwrap(),
+                b"small"
+            );
+
+            // missing chunks surface as errors of the storage, not as panics
+            chunks(ctx as *mut c_void).lock().unwrap().clear();
+            std::fs::remove_dir_all(&restored_path).unwrap();
+            assert!(
+                repository_restore_archive(repo, name.as_ptr(), None, std::ptr::null_mut(), 1)
+                    .is_null()
+            );
+            assert!(!CStr::from_ptr(last_error_message()).is_empty());
+
+            assert!(!RELEASED.load(Ordering::SeqCst));
+            free_repository(repo);
+            assert!(RELEASED.load(Ordering::SeqCst));
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
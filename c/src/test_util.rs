@@ -0,0 +1,112 @@
+//! Fixtures shared by the unit tests of the crate.
+
+use crate::archive::free_archive;
+use crate::free_string_array;
+use crate::repository::{free_repository, new_repository, repository_create_archive, CRepository};
+use std::ffi::*;
+use std::path::{Path, PathBuf};
+
+/// A directory in the temp directory named after `name` and this process, so parallel
+/// test runs do not collide. Whatever an earlier run left there is removed.
+pub fn temp_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("libddupbak-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+
+    directory
+}
+
+pub fn c_string(string: &str) -> CString {
+    CString::new(string).unwrap()
+}
+
+pub fn c_path(path: &Path) -> CString {
+    crate::path_c_string(path).unwrap()
+}
+
+/// The content of `file` in the source of a `Fixture`, spanning several chunks.
+pub fn file_content() -> Vec<u8> {
+    (0..1000u32).map(|i| (i % 251) as u8).collect()
+}
+
+/// Takes a NULL-terminated string array returned by the library, freeing it.
+pub unsafe fn strings(array: *mut *mut c_char) -> Vec<String> {
+    assert!(!array.is_null());
+
+    let mut strings = Vec::new();
+    let mut string = array;
+    while !unsafe { *string }.is_null() {
+        strings.push(
+            unsafe { CStr::from_ptr(*string) }
+                .to_string_lossy()
+                .into_owned(),
+        );
+        string = unsafe { string.add(1) };
+    }
+    unsafe { free_string_array(array) };
+
+    strings
+}
+
+/// A repository with 64 byte chunks holding the archive `archive` of `source`:
+///
+/// - `file`, 1000 bytes of `file_content`
+/// - `directory/small`, containing `small`
+/// - `directory/nested/deep`, containing `deep`
+/// - `link`, a symlink to `file`
+///
+/// The repository handle is freed and the directory removed on drop.
+pub struct Fixture {
+    pub directory: PathBuf,
+    pub repo: *mut CRepository,
+}
+
+impl Fixture {
+    pub fn new(name: &str) -> Self {
+        let directory = temp_directory(name);
+
+        let source = directory.join("source");
+        std::fs::create_dir_all(source.join("directory/nested")).unwrap();
+        std::fs::write(source.join("file"), file_content()).unwrap();
+        std::fs::write(source.join("directory/small"), "small").unwrap();
+        std::fs::write(source.join("directory/nested/deep"), "deep").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("file", source.join("link")).unwrap();
+
+        let repo = unsafe { new_repository(c_path(&directory.join("repository")).as_ptr(), 64, 0) };
+        assert!(!repo.is_null());
+
+        let fixture = Self { directory, repo };
+        fixture.create_archive("archive");
+
+        fixture
+    }
+
+    pub fn source(&self) -> PathBuf {
+        self.directory.join("source")
+    }
+
+    /// Creates another archive of the source.
+    pub fn create_archive(&self, name: &str) {
+        unsafe {
+            let archive = repository_create_archive(
+                self.repo,
+                c_string(name).as_ptr(),
+                c_path(&self.source()).as_ptr(),
+                None,
+                None,
+                std::ptr::null_mut(),
+                2,
+            );
+            assert!(!archive.is_null());
+            free_archive(archive);
+        }
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        unsafe { free_repository(self.repo) };
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
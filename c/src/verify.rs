@@ -0,0 +1,230 @@
+use crate::repository::CRepository;
+use crate::{
+    error_code, null_terminated, path_c_string, set_last_error, utf8_argument, DDUP_ERR_NULL_ARG,
+};
+use ddup_bak::repository::{VerifyLevel, VerifyProblemKind};
+use std::ffi::*;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Only checks that every referenced chunk exists in the index and storage.
+pub const DDUP_VERIFY_QUICK: c_int = 0;
+/// Reads every referenced chunk, checks its hash and the resulting file sizes.
+pub const DDUP_VERIFY_FULL: c_int = 1;
+
+/// Receives the path of a checked file, whether it was found without problems and `user_data`.
+pub type CVerifyProgressCallback =
+    Option<extern "C" fn(path: *const c_char, ok: bool, user_data: *mut c_void)>;
+
+/// Totals of a verify, filled by `repository_verify_archive`.
+#[repr(C)]
+pub struct CVerifySummary {
+    pub entries_checked: u64,
+    pub chunks_checked: u64,
+    pub missing_chunks: u64,
+    pub corrupt_chunks: u64,
+}
+
+/// Verifies the archive `name` at `level`, `DDUP_VERIFY_QUICK` or `DDUP_VERIFY_FULL`.
+/// `progress_callback` is called from the worker threads after each file was checked.
+///
+/// Returns 0 if the archive is intact and 1 if problems were found, in both cases
+/// `out_summary` is filled unless it is NULL and the problems can be fetched with
+/// `repository_verify_problems`. Returns a negative `DDUP_ERR_*` code if the verify
+/// itself failed, see `last_error_message`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_verify_archive(
+    repo: *mut CRepository,
+    name: *const c_char,
+    level: c_int,
+    progress_callback: CVerifyProgressCallback,
+    user_data: *mut c_void,
+    threads: c_uint,
+    out_summary: *mut CVerifySummary,
+) -> c_int {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return DDUP_ERR_NULL_ARG;
+    }
+
+    let level = match level {
+        DDUP_VERIFY_QUICK => VerifyLevel::Quick,
+        DDUP_VERIFY_FULL => VerifyLevel::Full,
+        _ => {
+            set_last_error(format!("unknown verify level {level}"));
+            return DDUP_ERR_NULL_ARG;
+        }
+    };
+
+    let handle = unsafe { &*repo };
+    let repo = handle.repository();
+    let name = match unsafe { utf8_argument(name, "name") } {
+        Ok(name) => name,
+        Err(code) => return code,
+    };
+
+    let user_data = user_data as usize;
+    let progress = progress_callback.map(|callback_fn| {
+        Arc::new(move |path: &Path, ok: bool| {
+            if let Some(c_path) = path_c_string(path) {
+                callback_fn(c_path.as_ptr(), ok, user_data as *mut c_void);
+            }
+        }) as Arc<dyn Fn(&Path, bool) + Send + Sync>
+    });
+
+    let report = match repo.verify_archive_with_results(name, level, progress, threads as usize) {
+        Ok(report) => report,
+        Err(err) => return error_code(err),
+    };
+
+    if !out_summary.is_null() {
+        let count = |kind| {
+            report
+                .problems
+                .iter()
+                .filter(|problem| problem.kind == kind)
+                .count() as u64
+        };
+
+        unsafe {
+            *out_summary = CVerifySummary {
+                entries_checked: report.files_checked,
+                chunks_checked: report.chunks_checked,
+                missing_chunks: count(VerifyProblemKind::MissingChunk),
+                corrupt_chunks: count(VerifyProblemKind::CorruptChunk),
+            }
+        };
+    }
+
+    *handle.verify_problems.lock().unwrap() = report
+        .problems
+        .iter()
+        .map(|problem| match problem.chunk_id {
+            Some(chunk_id) => format!(
+                "{}: chunk {chunk_id}: {}",
+                problem.path.display(),
+                problem.reason
+            ),
+            None => format!("{}: {}", problem.path.display(), problem.reason),
+        })
+        .collect();
+
+    if report.is_ok() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Returns the problems found by the last `repository_verify_archive` on this handle as
+/// a NULL-terminated array of `<path>: [chunk <id>: ]<reason>` lines, freed with
+/// `free_string_array`. Their number is stored in `out_count` unless it is NULL.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_verify_problems(
+    repo: *mut CRepository,
+    out_count: *mut c_uint,
+) -> *mut *mut c_char {
+    if repo.is_null() {
+        set_last_error("repository is NULL");
+        return std::ptr::null_mut();
+    }
+
+    let handle = unsafe { &*repo };
+    let problems = handle.verify_problems.lock().unwrap();
+
+    if !out_count.is_null() {
+        unsafe { *out_count = problems.len() as c_uint };
+    }
+
+    null_terminated(
+        problems
+            .iter()
+            .filter_map(|problem| CString::new(problem.as_str()).ok())
+            .map(CString::into_raw)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{c_string, strings, Fixture};
+    use std::sync::Mutex;
+
+    extern "C" fn record(path: *const c_char, ok: bool, user_data: *mut c_void) {
+        let checked = unsafe { &*(user_data as *const Mutex<Vec<(String, bool)>>) };
+        let path = unsafe { CStr::from_ptr(path) }
+            .to_string_lossy()
+            .into_owned();
+
+        checked.lock().unwrap().push((path, ok));
+    }
+
+    fn summary() -> CVerifySummary {
+        CVerifySummary {
+            entries_checked: 0,
+            chunks_checked: 0,
+            missing_chunks: 0,
+            corrupt_chunks: 0,
+        }
+    }
+
+    #[test]
+    fn verify_reports_missing_chunks() {
+        let fixture = Fixture::new("verify");
+        let name = c_string("archive");
+        let checked = Mutex::new(Vec::<(String, bool)>::new());
+        let mut intact = summary();
+        let mut broken = summary();
+
+        let verify = |level, summary: &mut CVerifySummary| unsafe {
+            repository_verify_archive(
+                fixture.repo,
+                name.as_ptr(),
+                level,
+                Some(record),
+                &checked as *const _ as *mut c_void,
+                2,
+                summary,
+            )
+        };
+
+        assert_eq!(verify(DDUP_VERIFY_FULL, &mut intact), 0);
+        assert_eq!(intact.entries_checked, 3);
+        assert!(intact.chunks_checked > 0);
+        assert_eq!((intact.missing_chunks, intact.corrupt_chunks), (0, 0));
+        assert!(checked.lock().unwrap().iter().all(|(_, ok)| *ok));
+        assert!(unsafe {
+            strings(repository_verify_problems(
+                fixture.repo,
+                std::ptr::null_mut(),
+            ))
+        }
+        .is_empty());
+
+        // removes the content of every chunk behind the index
+        let chunks = fixture.directory.join("repository/.ddup-bak/chunks");
+        for entry in std::fs::read_dir(&chunks).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                std::fs::remove_dir_all(entry.path()).unwrap();
+            }
+        }
+        checked.lock().unwrap().clear();
+
+        assert_eq!(verify(DDUP_VERIFY_QUICK, &mut broken), 1);
+        assert_eq!(broken.missing_chunks, intact.chunks_checked);
+        assert!(checked.lock().unwrap().iter().all(|(_, ok)| !*ok));
+
+        let mut count = 0;
+        let problems = unsafe { strings(repository_verify_problems(fixture.repo, &mut count)) };
+        assert_eq!(count as usize, problems.len());
+        assert!(problems
+            .iter()
+            .any(|problem| problem.starts_with("file: chunk ")));
+
+        assert_eq!(verify(7, &mut broken), DDUP_ERR_NULL_ARG);
+    }
+}
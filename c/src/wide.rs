@@ -0,0 +1,150 @@
+use crate::archive::{new_archive_at, open_archive_at, CArchive};
+use crate::repository::{
+    new_repository_at, open_repository_at, restore_archive_default, restore_archive_into,
+    CProgressCallback, CRepository,
+};
+use crate::{set_last_error, DDUP_ERR_NULL_ARG};
+use std::ffi::*;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// Reads a required NUL-terminated UTF-16 path argument.
+unsafe fn wide_path_argument(ptr: *const u16, name: &str) -> Result<PathBuf, c_int> {
+    if ptr.is_null() {
+        set_last_error(format!("{name} is NULL"));
+        return Err(DDUP_ERR_NULL_ARG);
+    }
+
+    let mut length = 0;
+    while unsafe { *ptr.add(length) } != 0 {
+        length += 1;
+    }
+
+    let wide = unsafe { std::slice::from_raw_parts(ptr, length) };
+
+    Ok(PathBuf::from(OsString::from_wide(wide)))
+}
+
+/// Hands out a path as a NUL-terminated UTF-16 string, freed with `free_wide_string`.
+fn path_to_wide(path: &Path) -> *mut u16 {
+    let wide = path
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect::<Vec<_>>();
+
+    Box::into_raw(wide.into_boxed_slice()) as *mut u16
+}
+
+/// Frees a string returned by one of the `_w` functions.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn free_wide_string(ptr: *mut u16) {
+    if ptr.is_null() {
+        return;
+    }
+
+    let mut length = 0;
+    while unsafe { *ptr.add(length) } != 0 {
+        length += 1;
+    }
+
+    let _ = unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, length + 1)) };
+}
+
+/// Like `new_repository`, with `directory` as UTF-16.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn new_repository_w(
+    directory: *const u16,
+    chunk_size: c_uint,
+    max_chunk_count: c_uint,
+) -> *mut CRepository {
+    match unsafe { wide_path_argument(directory, "directory") } {
+        Ok(directory) => new_repository_at(&directory, chunk_size, max_chunk_count),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Like `open_repository`, with both directories as UTF-16.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn open_repository_w(
+    directory: *const u16,
+    chunks_directory: *const u16,
+) -> *mut CRepository {
+    let Ok(directory) = (unsafe { wide_path_argument(directory, "directory") }) else {
+        return std::ptr::null_mut();
+    };
+    let chunks_directory = if chunks_directory.is_null() {
+        None
+    } else {
+        match unsafe { wide_path_argument(chunks_directory, "chunks_directory") } {
+            Ok(chunks_directory) => Some(chunks_directory),
+            Err(_) => return std::ptr::null_mut(),
+        }
+    };
+
+    open_repository_at(&directory, chunks_directory.as_deref())
+}
+
+/// Like `new_archive`, with `path` as UTF-16.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn new_archive_w(path: *const u16) -> *mut CArchive {
+    match unsafe { wide_path_argument(path, "path") } {
+        Ok(path) => new_archive_at(&path),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Like `open_archive`, with `path` as UTF-16.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn open_archive_w(path: *const u16) -> *mut CArchive {
+    match unsafe { wide_path_argument(path, "path") } {
+        Ok(path) => open_archive_at(&path),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Like `repository_restore_archive`, returning the directory as UTF-16 which is freed
+/// with `free_wide_string`.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_restore_archive_w(
+    repo: *mut CRepository,
+    archive_name: *const c_char,
+    progress_callback: CProgressCallback,
+    user_data: *mut c_void,
+    threads: c_uint,
+) -> *mut u16 {
+    unsafe { restore_archive_default(repo, archive_name, progress_callback, user_data, threads) }
+        .map_or(std::ptr::null_mut(), |path| path_to_wide(&path))
+}
+
+/// Like `repository_restore_archive_to`, with `destination` as UTF-16.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn repository_restore_archive_to_w(
+    repo: *mut CRepository,
+    archive_name: *const c_char,
+    destination: *const u16,
+    progress_callback: CProgressCallback,
+    user_data: *mut c_void,
+    threads: c_uint,
+) -> c_int {
+    match unsafe { wide_path_argument(destination, "destination") } {
+        Ok(destination) => unsafe {
+            restore_archive_into(
+                repo,
+                archive_name,
+                destination,
+                progress_callback,
+                user_data,
+                threads,
+            )
+        },
+        Err(code) => code,
+    }
+}
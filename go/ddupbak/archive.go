@@ -5,16 +5,16 @@ package ddupbak
 #include <stdint.h>
 #include <libddupbak.h>
 
-extern enum CCompressionFormat goCompressionCallback(char* path, uint64_t size);
-extern uint64_t goRealSizeCallback(char* path);
+extern enum CCompressionFormat goCompressionCallback(char* path, uint64_t size, void* user_data);
+extern uint64_t goRealSizeCallback(char* path, void* user_data);
 
 // Define proper function pointers for callbacks
-static enum CCompressionFormat (*getCompressionCallback(void))(const char*, uint64_t) {
-    return (enum CCompressionFormat(*)(const char*, uint64_t))goCompressionCallback;
+static enum CCompressionFormat (*getCompressionCallback(void))(const char*, uint64_t, void*) {
+    return (enum CCompressionFormat(*)(const char*, uint64_t, void*))goCompressionCallback;
 }
 
-static uint64_t (*getRealSizeCallback(void))(const char*) {
-    return (uint64_t(*)(const char*))goRealSizeCallback;
+static uint64_t (*getRealSizeCallback(void))(const char*, void*) {
+    return (uint64_t(*)(const char*, void*))goRealSizeCallback;
 }
 */
 import "C"
@@ -43,7 +43,7 @@ var (
 )
 
 //export goCompressionCallback
-func goCompressionCallback(path *C.char, size C.uint64_t) C.enum_CCompressionFormat {
+func goCompressionCallback(path *C.char, size C.uint64_t, userData unsafe.Pointer) C.enum_CCompressionFormat {
 	archiveCallbacksLock.Lock()
 	defer archiveCallbacksLock.Unlock()
 
@@ -57,7 +57,7 @@ func goCompressionCallback(path *C.char, size C.uint64_t) C.enum_CCompressionFor
 }
 
 //export goRealSizeCallback
-func goRealSizeCallback(path *C.char) C.uint64_t {
+func goRealSizeCallback(path *C.char, userData unsafe.Pointer) C.uint64_t {
 	archiveCallbacksLock.Lock()
 	defer archiveCallbacksLock.Unlock()
 
@@ -138,7 +138,7 @@ func (a *Archive) SetCompressionCallback(callback CompressionCallback) error {
 	archiveCallbacksLock.Unlock()
 
 	cCallback := C.getCompressionCallback()
-	C.archive_set_compression_callback(a.archive, cCallback)
+	C.archive_set_compression_callback(a.archive, cCallback, nil)
 
 	return nil
 }
@@ -158,7 +158,7 @@ func (a *Archive) SetRealSizeCallback(callback RealSizeCallback) error {
 	archiveCallbacksLock.Unlock()
 
 	cCallback := C.getRealSizeCallback()
-	C.archive_set_real_size_callback(a.archive, cCallback)
+	C.archive_set_real_size_callback(a.archive, cCallback, nil)
 
 	return nil
 }
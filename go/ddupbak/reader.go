@@ -33,11 +33,7 @@ func (r *Repository) NewEntryReader(entry *Entry) (*EntryReader, error) {
 		return nil, errors.New("entry is not a file")
 	}
 
-	fileEntry, err := C.entry_as_file(entry.entry)
-	if err != nil {
-		return nil, errors.New("failed to convert entry to file entry")
-	}
-	reader := C.repository_create_entry_reader(r.repo, fileEntry)
+	reader := C.entry_open_reader(r.repo, entry.entry)
 	if reader == nil {
 		return nil, errors.New("failed to create entry reader")
 	}
@@ -6,21 +6,16 @@ package ddupbak
 #include <libddupbak.h>
 
 // Forward declarations for callback handling
-extern void goProgressChunkingCallback(char* path);
-extern void goProgressArchivingCallback(char* path);
-extern void goProgressRestoringCallback(char* path);
-extern void goProgressCleaningCallback(uint64_t chunkID, _Bool deleted);
-extern CCompressionFormat goCompressionFormatCallback(char* path);
+extern void goProgressChunkingCallback(char* path, void* user_data);
+extern void goProgressRestoringCallback(char* path, void* user_data);
+extern void goProgressCleaningCallback(uint64_t chunkID, _Bool deleted, void* user_data);
+extern CCompressionFormat goCompressionFormatCallback(char* path, void* user_data);
 
 // Define proper function pointers for callbacks
 static CProgressCallback getChunkingCallback() {
     return (CProgressCallback)goProgressChunkingCallback;
 }
 
-static CProgressCallback getArchivingCallback() {
-    return (CProgressCallback)goProgressArchivingCallback;
-}
-
 static CProgressCallback getRestoringCallback() {
     return (CProgressCallback)goProgressRestoringCallback;
 }
@@ -46,15 +41,12 @@ type Repository struct {
 	repo *C.struct_CRepository
 }
 
-// ProgressCallback is a callback for tracking progress operations (chunking, archiving, restoring)
+// ProgressCallback is a callback for tracking progress operations (chunking, restoring)
 type ProgressCallback func(path string)
 
 // ChunkingProgressCallback is a callback for tracking chunking progress
 type ChunkingProgressCallback = ProgressCallback
 
-// ArchivingProgressCallback is a callback for tracking archiving progress
-type ArchivingProgressCallback = ProgressCallback
-
 // RestoringProgressCallback is a callback for tracking restoring progress
 type RestoringProgressCallback = ProgressCallback
 
@@ -74,7 +66,7 @@ var (
 )
 
 //export goProgressChunkingCallback
-func goProgressChunkingCallback(path *C.char) {
+func goProgressChunkingCallback(path *C.char, userData unsafe.Pointer) {
 	pathStr := C.GoString(path)
 	activeCallbacksLock.Lock()
 	defer activeCallbacksLock.Unlock()
@@ -86,21 +78,8 @@ func goProgressChunkingCallback(path *C.char) {
 	}
 }
 
-//export goProgressArchivingCallback
-func goProgressArchivingCallback(path *C.char) {
-	pathStr := C.GoString(path)
-	activeCallbacksLock.Lock()
-	defer activeCallbacksLock.Unlock()
-
-	if cb, ok := activeCallbacks["archiving"]; ok {
-		if callback, ok := cb.(ArchivingProgressCallback); ok {
-			callback(pathStr)
-		}
-	}
-}
-
 //export goProgressRestoringCallback
-func goProgressRestoringCallback(path *C.char) {
+func goProgressRestoringCallback(path *C.char, userData unsafe.Pointer) {
 	pathStr := C.GoString(path)
 	activeCallbacksLock.Lock()
 	defer activeCallbacksLock.Unlock()
@@ -113,7 +92,7 @@ func goProgressRestoringCallback(path *C.char) {
 }
 
 //export goProgressCleaningCallback
-func goProgressCleaningCallback(chunkID C.uint64_t, deleted C._Bool) {
+func goProgressCleaningCallback(chunkID C.uint64_t, deleted C._Bool, userData unsafe.Pointer) {
 	activeCallbacksLock.Lock()
 	defer activeCallbacksLock.Unlock()
 
@@ -125,7 +104,7 @@ func goProgressCleaningCallback(chunkID C.uint64_t, deleted C._Bool) {
 }
 
 //export goCompressionFormatCallback
-func goCompressionFormatCallback(path *C.char) C.CCompressionFormat {
+func goCompressionFormatCallback(path *C.char, userData unsafe.Pointer) C.CCompressionFormat {
 	pathStr := C.GoString(path)
 	activeCallbacksLock.Lock()
 	defer activeCallbacksLock.Unlock()
@@ -196,7 +175,6 @@ func (r *Repository) Free() {
 	if r.repo != nil {
 		activeCallbacksLock.Lock()
 		delete(activeCallbacks, "chunking")
-		delete(activeCallbacks, "archiving")
 		delete(activeCallbacks, "restoring")
 		delete(activeCallbacks, "cleaning")
 		activeCallbacksLock.Unlock()
@@ -241,7 +219,7 @@ func (r *Repository) Clean(progressCallback CleaningProgressCallback) error {
 		cCallback = C.getCleaningCallback()
 	}
 
-	ret := C.repository_clean(r.repo, cCallback)
+	ret := C.repository_clean(r.repo, cCallback, nil, nil)
 	return cErrorToGoError(ret)
 }
 
@@ -250,7 +228,6 @@ func (r *Repository) CreateArchive(
 	name string,
 	directory string,
 	chunkingCallback ChunkingProgressCallback,
-	archivingCallback ArchivingProgressCallback,
 	compressionFormatCallback CompressionFormatCallback,
 	threads uint,
 ) (*Archive, error) {
@@ -273,15 +250,6 @@ func (r *Repository) CreateArchive(
 		cChunkingCallback = C.getChunkingCallback()
 	}
 
-	var cArchivingCallback C.CProgressCallback
-	if archivingCallback != nil {
-		activeCallbacksLock.Lock()
-		activeCallbacks["archiving"] = archivingCallback
-		activeCallbacksLock.Unlock()
-
-		cArchivingCallback = C.getArchivingCallback()
-	}
-
 	var cCompressionFormatCallback C.CCompressionFormatCallback
 	if compressionFormatCallback != nil {
 		activeCallbacksLock.Lock()
@@ -295,8 +263,8 @@ func (r *Repository) CreateArchive(
 		cName,
 		cDirectory,
 		cChunkingCallback,
-		cArchivingCallback,
 		cCompressionFormatCallback,
+		nil,
 		C.uint(threads),
 	)
 
@@ -374,6 +342,7 @@ func (r *Repository) RestoreArchive(
 		r.repo,
 		cArchiveName,
 		cCallback,
+		nil,
 		C.uint(threads),
 	)
 
@@ -408,6 +377,6 @@ func (r *Repository) DeleteArchive(
 		cCallback = C.getCleaningCallback()
 	}
 
-	ret := C.repository_delete_archive(r.repo, cArchiveName, cCallback)
+	ret := C.repository_delete_archive(r.repo, cArchiveName, cCallback, nil)
 	return cErrorToGoError(ret)
 }
@@ -1,4 +1,4 @@
-use super::CompressionFormat;
+use super::{Archive, CompressionFormat, DecodeLimits};
 use flate2::read::{DeflateDecoder, GzDecoder};
 use positioned_io::ReadAt;
 use std::{
@@ -6,11 +6,12 @@ use std::{
     fs::File,
     io::Read,
     ops::Deref,
-    sync::Arc,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
     time::SystemTime,
 };
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct EntryMode(u32);
 
 impl EntryMode {
@@ -81,6 +82,23 @@ impl EntryMode {
         self.0 &= !0o007;
         self.0 |= (read as u32) | (write as u32) << 1 | (execute as u32) << 2;
     }
+
+    /// Applies the mode to existing permissions.
+    /// On unix the permissions are replaced by the mode bits, elsewhere only
+    /// the readonly flag is derived from the write bits.
+    #[inline]
+    pub fn apply(&self, permissions: &mut std::fs::Permissions) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            permissions.set_mode(self.0);
+        }
+        #[cfg(not(unix))]
+        {
+            permissions.set_readonly(self.0 & 0o222 == 0);
+        }
+    }
 }
 
 impl Debug for EntryMode {
@@ -139,6 +157,9 @@ impl From<std::fs::Permissions> for EntryMode {
 }
 
 impl From<EntryMode> for std::fs::Permissions {
+    /// Outside of unix only the readonly flag is kept, derived like `EntryMode::apply`
+    /// does. Prefer `apply` on the permissions of the existing file there, which keeps
+    /// its other attributes.
     #[inline]
     fn from(permissions: EntryMode) -> std::fs::Permissions {
         #[cfg(unix)]
@@ -149,8 +170,9 @@ impl From<EntryMode> for std::fs::Permissions {
         }
         #[cfg(not(unix))]
         {
+            // SAFETY: the permissions are plain file attributes there, none set is valid
             let mut fs_permissions: std::fs::Permissions = unsafe { std::mem::zeroed() };
-            fs_permissions.set_readonly(permissions.0 & 0o444 != 0);
+            fs_permissions.set_readonly(permissions.0 & 0o222 == 0);
 
             fs_permissions
         }
@@ -176,6 +198,8 @@ pub struct FileEntry {
     pub size_compressed: Option<u64>,
     pub size_real: u64,
     pub size: u64,
+    /// Whether the content is a delta encoded chunk ID list, see `chunks::ids`.
+    pub delta_chunk_ids: bool,
 
     pub file: Arc<File>,
     pub offset: u64,
@@ -194,6 +218,7 @@ impl Clone for FileEntry {
             size_compressed: self.size_compressed,
             size_real: self.size_real,
             size: self.size,
+            delta_chunk_ids: self.delta_chunk_ids,
             file: Arc::clone(&self.file),
             decoder: None,
             offset: self.offset,
@@ -214,6 +239,7 @@ impl Debug for FileEntry {
             .field("size", &self.size)
             .field("size_real", &self.size_real)
             .field("size_compressed", &self.size_compressed)
+            .field("delta_chunk_ids", &self.delta_chunk_ids)
             .finish()
     }
 }
@@ -331,7 +357,168 @@ pub struct DirectoryEntry {
     pub mode: EntryMode,
     pub owner: (u32, u32),
     pub mtime: SystemTime,
-    pub entries: Vec<Entry>,
+    entries: Vec<Entry>,
+    /// The children of a directory of an archive opened with `Archive::open_lazy`,
+    /// `entries` stays empty while this is set.
+    lazy: Option<LazyEntries>,
+}
+
+impl DirectoryEntry {
+    /// Creates a directory entry with already decoded children.
+    pub fn new(
+        name: impl Into<String>,
+        mode: EntryMode,
+        owner: (u32, u32),
+        mtime: SystemTime,
+        entries: Vec<Entry>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            mode,
+            owner,
+            mtime,
+            entries,
+            lazy: None,
+        }
+    }
+
+    /// Sets the children to be decoded on first access, see `Archive::open_lazy`.
+    pub(crate) fn with_lazy(mut self, lazy: Option<LazyEntries>) -> Self {
+        self.lazy = lazy;
+
+        self
+    }
+
+    /// Returns the children of the directory, decoding them on first access
+    /// if the archive was opened lazily.
+    #[inline]
+    pub fn entries(&self) -> std::io::Result<&[Entry]> {
+        match &self.lazy {
+            Some(lazy) => lazy.get(),
+            None => Ok(&self.entries),
+        }
+    }
+
+    /// Returns the children of the directory for modification, lazily decoded
+    /// children are moved into `entries` first.
+    pub fn entries_mut(&mut self) -> std::io::Result<&mut Vec<Entry>> {
+        if let Some(lazy) = self.lazy.take() {
+            self.entries = lazy.into_entries()?;
+        }
+
+        Ok(&mut self.entries)
+    }
+
+    /// Consumes the directory and returns its children, decoding them if needed.
+    pub fn into_entries(self) -> std::io::Result<Vec<Entry>> {
+        match self.lazy {
+            Some(lazy) => lazy.into_entries(),
+            None => Ok(self.entries),
+        }
+    }
+
+    /// Whether the children of the directory are decoded,
+    /// always the case unless the archive was opened lazily.
+    #[inline]
+    pub fn is_loaded(&self) -> bool {
+        self.lazy
+            .as_ref()
+            .is_none_or(|lazy| lazy.entries.get().is_some())
+    }
+
+    /// Decodes the whole tree below the directory into `entries`.
+    pub fn load_all(&mut self) -> std::io::Result<()> {
+        for entry in self.entries_mut()? {
+            if let Entry::Directory(dir_entry) = entry {
+                dir_entry.load_all()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The decompressed end header of a lazily opened archive, shared by its directories.
+pub(crate) struct LazyHeader {
+    pub(crate) data: Vec<u8>,
+    /// Where the encoded children of every non empty directory start and end, by start.
+    pub(crate) directories: Vec<(usize, usize)>,
+    pub(crate) file: Arc<File>,
+    pub(crate) limits: DecodeLimits,
+    pub(crate) version: u8,
+}
+
+impl LazyHeader {
+    /// Returns where the encoded children of the directory starting at `start` end.
+    pub(crate) fn directory_end(&self, start: usize) -> Option<usize> {
+        self.directories
+            .binary_search_by_key(&start, |(start, _)| *start)
+            .ok()
+            .map(|index| self.directories[index].1)
+    }
+}
+
+/// The children of a directory of a lazily opened archive, decoded on first access.
+#[derive(Clone)]
+pub struct LazyEntries {
+    header: Arc<LazyHeader>,
+    start: usize,
+    count: usize,
+    depth: usize,
+    entries: OnceLock<Vec<Entry>>,
+}
+
+impl LazyEntries {
+    pub(crate) fn new(header: Arc<LazyHeader>, start: usize, count: usize, depth: usize) -> Self {
+        Self {
+            header,
+            start,
+            count,
+            depth,
+            entries: OnceLock::new(),
+        }
+    }
+
+    /// The number of children of the directory.
+    #[inline]
+    pub const fn len(&self) -> usize {
+        self.count
+    }
+
+    #[inline]
+    pub const fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn get(&self) -> std::io::Result<&[Entry]> {
+        if let Some(entries) = self.entries.get() {
+            return Ok(entries);
+        }
+
+        let entries = self.decode()?;
+
+        Ok(self.entries.get_or_init(|| entries))
+    }
+
+    fn into_entries(self) -> std::io::Result<Vec<Entry>> {
+        match self.entries.get() {
+            Some(_) => Ok(self.entries.into_inner().unwrap_or_default()),
+            None => self.decode(),
+        }
+    }
+
+    fn decode(&self) -> std::io::Result<Vec<Entry>> {
+        Archive::decode_lazy_entries(&self.header, self.start, self.count, self.depth)
+    }
+}
+
+impl Debug for LazyEntries {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LazyEntries")
+            .field("count", &self.count)
+            .field("loaded", &self.entries.get().is_some())
+            .finish()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -413,6 +600,71 @@ impl Entry {
     }
 }
 
+/// Depth first iterator over a tree of entries, see `Archive::walk`.
+/// Directories are yielded before their children, entries keep their archive order
+/// unless the walk is `sorted`. If the children of a lazily decoded directory cannot be
+/// decoded, the error is yielded in place of the directory and the walk ends.
+pub struct EntryWalk<'a> {
+    stack: Vec<(PathBuf, usize, &'a Entry)>,
+    sorted: bool,
+}
+
+impl<'a> EntryWalk<'a> {
+    /// Walks `entries` as if they were located in the `parent` directory.
+    pub fn new(parent: &Path, entries: &'a [Entry]) -> Self {
+        let mut walk = Self {
+            stack: Vec::new(),
+            sorted: false,
+        };
+        walk.push_children(parent, 0, entries);
+
+        walk
+    }
+
+    /// Yields the entries of every directory sorted by name.
+    pub fn sorted(mut self) -> Self {
+        self.sorted = true;
+        self.stack
+            .sort_unstable_by(|(_, _, a), (_, _, b)| b.name().cmp(a.name()));
+
+        self
+    }
+
+    fn push_children(&mut self, parent: &Path, depth: usize, entries: &'a [Entry]) {
+        let start = self.stack.len();
+
+        for entry in entries.iter().rev() {
+            self.stack.push((parent.join(entry.name()), depth, entry));
+        }
+
+        if self.sorted {
+            self.stack[start..].sort_unstable_by(|(_, _, a), (_, _, b)| b.name().cmp(a.name()));
+        }
+    }
+}
+
+impl<'a> Iterator for EntryWalk<'a> {
+    /// The path of the entry, its depth below the walked entries and the entry itself.
+    type Item = std::io::Result<(PathBuf, usize, &'a Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, depth, entry) = self.stack.pop()?;
+
+        if let Entry::Directory(dir_entry) = entry {
+            match dir_entry.entries() {
+                Ok(entries) => self.push_children(&path, depth + 1, entries),
+                Err(err) => {
+                    self.stack.clear();
+
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        Some(Ok((path, depth, entry)))
+    }
+}
+
 struct BoundedReader {
     file: Arc<File>,
     offset: u64,
@@ -9,8 +9,8 @@ use std::{
     ffi::OsStr,
     fmt::{Debug, Formatter},
     fs::{DirEntry, File, Metadata},
-    io::{Read, Seek, SeekFrom, Write},
-    path::Path,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Component, Path},
     sync::Arc,
     time::SystemTime,
 };
@@ -18,7 +18,16 @@ use std::{
 pub mod entries;
 
 pub const FILE_SIGNATURE: [u8; 7] = *b"DDUPBAK";
-pub const FILE_VERSION: u8 = 1;
+pub const FILE_VERSION: u8 = 2;
+
+/// The default size of the buffer payloads and the end header are written through.
+pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Only set by version 2 archives, the bit belongs to the mode in version 1 archives.
+const ENTRY_FLAG_DELTA_CHUNK_IDS: u32 = 1 << 25;
+const ENTRY_MODE_MASK: u32 = 0x01FFFFFF;
+/// The mode bits of version 1 archives, overlapping the compression format like they always did.
+const ENTRY_MODE_MASK_V1: u32 = 0x3FFFFFFF;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -61,7 +70,7 @@ impl Default for CompressionFormat {
 }
 
 #[inline]
-fn metadata_owner(_metadata: &Metadata) -> (u32, u32) {
+pub(crate) fn metadata_owner(_metadata: &Metadata) -> (u32, u32) {
     #[cfg(unix)]
     {
         use std::os::unix::fs::MetadataExt;
@@ -109,6 +118,9 @@ type RealSizeCallback = Option<Arc<dyn Fn(&Path) -> u64 + Send + Sync + 'static>
 
 pub struct Archive {
     file: Arc<File>,
+    /// Buffers writes to `file`, created on the first write.
+    writer: Option<BufWriter<Arc<File>>>,
+    write_buffer_size: usize,
     version: u8,
     compression_callback: CompressionFormatCallback,
     real_size_callback: RealSizeCallback,
@@ -138,6 +150,8 @@ impl Archive {
 
         Ok(Self {
             file: Arc::new(file),
+            writer: None,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
             version: FILE_VERSION,
             compression_callback: None,
             real_size_callback: None,
@@ -165,7 +179,30 @@ impl Archive {
     }
 
     /// Opens an existing archive file with custom decode limits.
-    pub fn open_file_with_limits(mut file: File, limits: DecodeLimits) -> std::io::Result<Self> {
+    pub fn open_file_with_limits(file: File, limits: DecodeLimits) -> std::io::Result<Self> {
+        Self::open_file_inner(file, limits, false)
+    }
+
+    /// Opens an existing archive like `open`, but only decodes the top level entries.
+    /// The children of directories are decoded on first access through
+    /// `DirectoryEntry::entries`, `walk` and `find_archive_entry`, so looking up a
+    /// few paths of a huge archive does not build its whole entry tree.
+    /// The end header is still read and checked as a whole, and kept in memory
+    /// while entries of the archive are alive.
+    pub fn open_lazy(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::open_lazy_with_limits(path, DecodeLimits::default())
+    }
+
+    /// Opens an existing archive lazily with custom decode limits, see `open_lazy`.
+    pub fn open_lazy_with_limits(
+        path: impl AsRef<Path>,
+        limits: DecodeLimits,
+    ) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        Self::open_file_inner(file, limits, true)
+    }
+
+    fn open_file_inner(mut file: File, limits: DecodeLimits, lazy: bool) -> std::io::Result<Self> {
         let len = file.metadata()?.len();
 
         let mut buffer = [0; 8];
@@ -177,6 +214,12 @@ impl Archive {
             ));
         }
         let version = buffer[7];
+        if version > FILE_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unsupported archive version {version}"),
+            ));
+        }
 
         file.read_exact_at(len - 16, &mut buffer)?;
         let entries_count = u64::from_le_bytes(buffer);
@@ -196,15 +239,63 @@ impl Archive {
         let mut entries = Vec::with_capacity(entries_count as usize);
         file.seek(SeekFrom::Start(entries_offset))?;
 
+        if lazy {
+            let mut data = Vec::new();
+            DeflateDecoder::new(file.try_clone()?).read_to_end(&mut data)?;
+            let file = Arc::new(file);
+
+            // checks the whole header and finds where the children of every directory end,
+            // without building the entries
+            let mut scan = HeaderScan {
+                data: &data,
+                position: 0,
+                directories: Vec::new(),
+            };
+            for _ in 0..entries_count {
+                scan.skip_entry(&limits, 0)?;
+            }
+
+            let mut directories = scan.directories;
+            directories.sort_unstable();
+
+            let mut cursor = LazyCursor {
+                header: Arc::new(entries::LazyHeader {
+                    data,
+                    directories,
+                    file: file.clone(),
+                    limits,
+                    version,
+                }),
+                position: 0,
+            };
+            for _ in 0..entries_count {
+                let entry = Self::decode_entry(&mut cursor, file.clone(), &limits, version, 0)?;
+                entries.push(entry);
+            }
+
+            return Ok(Self {
+                file,
+                writer: None,
+                write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+                version,
+                compression_callback: None,
+                real_size_callback: None,
+                entries,
+                entries_offset,
+            });
+        }
+
         let mut decoder = DeflateDecoder::new(file.try_clone()?);
         let file = Arc::new(file);
         for _ in 0..entries_count {
-            let entry = Self::decode_entry(&mut decoder, file.clone(), &limits, 0)?;
+            let entry = Self::decode_entry(&mut decoder, file.clone(), &limits, version, 0)?;
             entries.push(entry);
         }
 
         Ok(Self {
             file,
+            writer: None,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
             version,
             compression_callback: None,
             real_size_callback: None,
@@ -220,6 +311,38 @@ impl Archive {
         self.version
     }
 
+    /// Sets the size of the buffer payloads and the end header are written through,
+    /// `DEFAULT_WRITE_BUFFER_SIZE` by default. Anything buffered so far is flushed.
+    pub fn set_write_buffer_size(&mut self, size: usize) -> std::io::Result<&mut Self> {
+        self.flush_writer()?;
+        self.writer = None;
+        self.write_buffer_size = size;
+
+        Ok(self)
+    }
+
+    #[inline]
+    fn writer(&mut self) -> &mut BufWriter<Arc<File>> {
+        self.writer.get_or_insert_with(|| {
+            BufWriter::with_capacity(self.write_buffer_size, self.file.clone())
+        })
+    }
+
+    #[inline]
+    fn flush_writer(&mut self) -> std::io::Result<()> {
+        match self.writer {
+            Some(ref mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// The position of the next write, including anything still buffered.
+    #[inline]
+    fn write_position(&mut self) -> std::io::Result<u64> {
+        self.flush_writer()?;
+        self.file.stream_position()
+    }
+
     /// Sets the compression callback for the archive.
     /// This callback is called for each added file entry in the archive.
     /// The callback should return the compression format to use for the file.
@@ -249,7 +372,7 @@ impl Archive {
     /// This function will panic if any filename is not valid UTF-8 or longer than 255 bytes.
     pub fn add_directory(
         &mut self,
-        path: &str,
+        path: impl AsRef<Path>,
         progress: ProgressCallback,
     ) -> std::io::Result<&mut Self> {
         self.trim_end_header()?;
@@ -269,10 +392,30 @@ impl Archive {
         &self.entries
     }
 
-    /// Consumes the archive and returns the entries.
+    /// Walks all entries in the archive depth first, yielding their paths inside the archive.
     #[inline]
-    pub fn into_entries(self) -> Vec<entries::Entry> {
-        self.entries
+    pub fn walk(&self) -> entries::EntryWalk<'_> {
+        entries::EntryWalk::new(Path::new(""), &self.entries)
+    }
+
+    /// Consumes the archive and returns the entries,
+    /// the entries of lazily opened archives are decoded completely first.
+    #[inline]
+    pub fn into_entries(mut self) -> std::io::Result<Vec<entries::Entry>> {
+        self.load_entries()?;
+
+        Ok(self.entries)
+    }
+
+    /// Decodes all entries of a lazily opened archive into the `entries` of their directories.
+    pub fn load_entries(&mut self) -> std::io::Result<()> {
+        for entry in &mut self.entries {
+            if let entries::Entry::Directory(dir_entry) = entry {
+                dir_entry.load_all()?;
+            }
+        }
+
+        Ok(())
     }
 
     /// Writes a new file entry to the archive.
@@ -293,15 +436,16 @@ impl Archive {
         owner: (u32, u32),
         compression: CompressionFormat,
     ) -> std::io::Result<Box<entries::FileEntry>> {
-        let offset = self.file.stream_position()?;
+        let offset = self.write_position()?;
 
         let mut buffer = [0; 4096];
         let mut bytes_read = 0;
         let mut total_bytes = 0;
         match compression {
             CompressionFormat::None => {
+                let writer = self.writer();
                 loop {
-                    self.file.write_all(&buffer[..bytes_read])?;
+                    writer.write_all(&buffer[..bytes_read])?;
                     total_bytes += bytes_read;
 
                     bytes_read = reader.read(&mut buffer)?;
@@ -310,10 +454,10 @@ impl Archive {
                     }
                 }
 
-                self.file.flush()?;
+                writer.flush()?;
             }
             CompressionFormat::Gzip => {
-                let mut encoder = GzEncoder::new(&mut self.file, flate2::Compression::default());
+                let mut encoder = GzEncoder::new(self.writer(), flate2::Compression::default());
                 loop {
                     encoder.write_all(&buffer[..bytes_read])?;
                     total_bytes += bytes_read;
@@ -329,7 +473,7 @@ impl Archive {
             }
             CompressionFormat::Deflate => {
                 let mut encoder =
-                    DeflateEncoder::new(&mut self.file, flate2::Compression::default());
+                    DeflateEncoder::new(self.writer(), flate2::Compression::default());
                 loop {
                     encoder.write_all(&buffer[..bytes_read])?;
                     total_bytes += bytes_read;
@@ -346,7 +490,7 @@ impl Archive {
 
             #[cfg(feature = "brotli")]
             CompressionFormat::Brotli => {
-                let mut encoder = brotli::CompressorWriter::new(&mut self.file, 4096, 11, 22);
+                let mut encoder = brotli::CompressorWriter::new(self.writer(), 4096, 11, 22);
                 loop {
                     encoder.write_all(&buffer[..bytes_read])?;
                     total_bytes += bytes_read;
@@ -368,7 +512,7 @@ impl Archive {
 
         let size_compressed = match compression {
             CompressionFormat::None => None,
-            _ => Some(self.file.stream_position()? - offset),
+            _ => Some(self.write_position()? - offset),
         };
         let size_real = size_real.unwrap_or(total_bytes as u64);
 
@@ -382,12 +526,13 @@ impl Archive {
             size_compressed,
             size_real,
             size: total_bytes as u64,
+            delta_chunk_ids: false,
             offset,
             consumed: 0,
             compression,
         });
 
-        self.entries_offset = self.file.stream_position()?;
+        self.entries_offset = self.write_position()?;
 
         Ok(entry)
     }
@@ -419,114 +564,189 @@ impl Archive {
         entry: &'a entries::Entry,
         entry_parts: &[&OsStr],
         current_depth: usize,
-    ) -> Option<&'a entries::Entry> {
+    ) -> std::io::Result<Option<&'a entries::Entry>> {
         if entry_parts.len() > current_depth + 1 {
-            return None;
+            return Ok(None);
         }
 
-        let current_part = entry_parts.first()?;
+        let Some(current_part) = entry_parts.first() else {
+            return Ok(None);
+        };
         let entry_name: &OsStr = entry.name().as_ref();
         if entry_name != *current_part {
-            return None;
+            return Ok(None);
         }
 
         if entry_parts.len() == 1 {
-            return Some(entry);
+            return Ok(Some(entry));
         }
 
         if let entries::Entry::Directory(dir_entry) = entry {
             let remaining_parts = &entry_parts[1..];
 
-            for sub_entry in &dir_entry.entries {
+            for sub_entry in dir_entry.entries()? {
                 if let Some(found) = Self::recursive_find_archive_entry(
                     sub_entry,
                     remaining_parts,
                     current_depth - 1,
-                ) {
-                    return Some(found);
+                )? {
+                    return Ok(Some(found));
                 }
             }
         }
 
-        None
+        Ok(None)
     }
 
     fn recursive_find_archive_entry_mut<'a>(
         entry: &'a mut entries::Entry,
         entry_parts: &[&OsStr],
         current_depth: usize,
-    ) -> Option<&'a mut entries::Entry> {
+    ) -> std::io::Result<Option<&'a mut entries::Entry>> {
         if entry_parts.len() > current_depth + 1 {
-            return None;
+            return Ok(None);
         }
 
-        let current_part = entry_parts.first()?;
+        let Some(current_part) = entry_parts.first() else {
+            return Ok(None);
+        };
         let entry_name: &OsStr = entry.name().as_ref();
         if entry_name != *current_part {
-            return None;
+            return Ok(None);
         }
 
         if entry_parts.len() == 1 {
-            return Some(entry);
+            return Ok(Some(entry));
         }
 
         if let entries::Entry::Directory(dir_entry) = entry {
             let remaining_parts = &entry_parts[1..];
 
-            for sub_entry in &mut dir_entry.entries {
+            for sub_entry in dir_entry.entries_mut()? {
                 if let Some(found) = Self::recursive_find_archive_entry_mut(
                     sub_entry,
                     remaining_parts,
                     current_depth - 1,
-                ) {
-                    return Some(found);
+                )? {
+                    return Ok(Some(found));
                 }
             }
         }
 
-        None
+        Ok(None)
     }
 
     /// Finds an entry in the archive by name.
-    /// Returns `None` if the entry is not found.
+    /// Returns `None` if the entry is not found, errors if the children of a lazily
+    /// decoded directory on the way cannot be decoded.
     /// The entry name is the path inside the archive.
     /// Example: "world/user/level.dat" would be a valid entry name.
     #[inline]
-    pub fn find_archive_entry(&self, entry_name: &Path) -> Option<&entries::Entry> {
+    pub fn find_archive_entry(
+        &self,
+        entry_name: &Path,
+    ) -> std::io::Result<Option<&entries::Entry>> {
         let entry_parts = entry_name
             .components()
             .map(|c| c.as_os_str())
             .collect::<Vec<&OsStr>>();
         for entry in self.entries() {
             if let Some(found) =
-                Self::recursive_find_archive_entry(entry, &entry_parts, entry_parts.len())
+                Self::recursive_find_archive_entry(entry, &entry_parts, entry_parts.len())?
             {
-                return Some(found);
+                return Ok(Some(found));
             }
         }
 
-        None
+        Ok(None)
     }
 
     /// Finds an entry in the archive by name.
-    /// Returns `None` if the entry is not found.
+    /// Returns `None` if the entry is not found, errors if the children of a lazily
+    /// decoded directory on the way cannot be decoded.
     /// The entry name is the path inside the archive.
     /// Example: "world/user/level.dat" would be a valid entry name.
     #[inline]
-    pub fn find_archive_entry_mut(&mut self, entry_name: &Path) -> Option<&mut entries::Entry> {
+    pub fn find_archive_entry_mut(
+        &mut self,
+        entry_name: &Path,
+    ) -> std::io::Result<Option<&mut entries::Entry>> {
         let entry_parts = entry_name
             .components()
             .map(|c| c.as_os_str())
             .collect::<Vec<&OsStr>>();
         for entry in &mut self.entries {
             if let Some(found) =
-                Self::recursive_find_archive_entry_mut(entry, &entry_parts, entry_parts.len())
+                Self::recursive_find_archive_entry_mut(entry, &entry_parts, entry_parts.len())?
             {
-                return Some(found);
+                return Ok(Some(found));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the entries at the given archive paths together with the directories
+    /// leading to them, directories on the way only contain the selected entries.
+    /// Paths that do not exist in the archive are ignored.
+    pub fn select_entries(&self, paths: &[&Path]) -> std::io::Result<Vec<entries::Entry>> {
+        let paths = paths
+            .iter()
+            .map(|path| {
+                path.components()
+                    .filter_map(|c| match c {
+                        Component::Normal(name) => Some(name),
+                        _ => None,
+                    })
+                    .collect::<Vec<&OsStr>>()
+            })
+            .filter(|parts| !parts.is_empty())
+            .collect::<Vec<_>>();
+
+        Self::recursive_select_entries(self.entries(), &paths)
+    }
+
+    fn recursive_select_entries(
+        entries: &[entries::Entry],
+        paths: &[Vec<&OsStr>],
+    ) -> std::io::Result<Vec<entries::Entry>> {
+        let mut selected = Vec::new();
+
+        for entry in entries {
+            let entry_name: &OsStr = entry.name().as_ref();
+            let matching = paths
+                .iter()
+                .filter(|parts| parts[0] == entry_name)
+                .collect::<Vec<_>>();
+
+            if matching.is_empty() {
+                continue;
+            }
+
+            if matching.iter().any(|parts| parts.len() == 1) {
+                selected.push(entry.clone());
+            } else if let entries::Entry::Directory(dir_entry) = entry {
+                let remaining = matching
+                    .iter()
+                    .map(|parts| parts[1..].to_vec())
+                    .collect::<Vec<_>>();
+                let children = Self::recursive_select_entries(dir_entry.entries()?, &remaining)?;
+
+                if !children.is_empty() {
+                    selected.push(entries::Entry::Directory(Box::new(
+                        entries::DirectoryEntry::new(
+                            dir_entry.name.clone(),
+                            dir_entry.mode,
+                            dir_entry.owner,
+                            dir_entry.mtime,
+                            children,
+                        ),
+                    )));
+                }
             }
         }
 
-        None
+        Ok(selected)
     }
 
     pub fn trim_end_header(&mut self) -> std::io::Result<()> {
@@ -534,27 +754,45 @@ impl Archive {
             return Ok(());
         }
 
+        self.flush_writer()?;
         self.file.set_len(self.entries_offset)?;
-        self.file.flush()?;
         self.file.seek(SeekFrom::Start(self.entries_offset))?;
 
         Ok(())
     }
 
     pub fn write_end_header(&mut self) -> std::io::Result<()> {
-        let mut encoder = DeflateEncoder::new(&mut self.file, flate2::Compression::default());
+        let entries_count = self.entries.len() as u64;
+        let writer = self.writer.get_or_insert_with(|| {
+            BufWriter::with_capacity(self.write_buffer_size, self.file.clone())
+        });
+
+        let mut encoder = DeflateEncoder::new(&mut *writer, flate2::Compression::default());
         for entry in &self.entries {
             Self::encode_entry_metadata(&mut encoder, entry)?;
         }
 
         encoder.flush()?;
         encoder.finish()?;
-        self.file.flush()?;
 
-        self.file
-            .write_all(&(self.entries.len() as u64).to_le_bytes())?;
-        self.file.write_all(&self.entries_offset.to_le_bytes())?;
-        self.file.flush()?;
+        writer.write_all(&entries_count.to_le_bytes())?;
+        writer.write_all(&self.entries_offset.to_le_bytes())?;
+        writer.flush()?;
+
+        // the entries were just written in the layout of the current version, which
+        // readers of older versions cannot tell apart, so an appended older archive
+        // becomes one of the current version
+        if self.version < FILE_VERSION {
+            let mut file = &*self.file;
+            let position = file.stream_position()?;
+
+            file.seek(SeekFrom::Start(7))?;
+            file.write_all(&[FILE_VERSION])?;
+            file.seek(SeekFrom::Start(position))?;
+
+            self.version = FILE_VERSION;
+        }
+
         self.file.sync_all()?;
 
         Ok(())
@@ -567,10 +805,8 @@ impl Archive {
         let name = entry.name();
         let name_length = name.len() as u8;
 
-        writer.write_all(&varint::encode_u32(name_length as u32))?;
-
-        let mut buffer = Vec::with_capacity(name.len() + 4);
-        buffer.extend_from_slice(name.as_bytes());
+        varint::write_u32(writer, name_length as u32)?;
+        writer.write_all(name.as_bytes())?;
 
         let mode = entry.mode().bits();
         let compression = match entry {
@@ -583,41 +819,49 @@ impl Archive {
             entries::Entry::Symlink(_) => 2,
         };
 
-        let type_compression_mode =
-            (entry_type << 30) | ((compression.encode() as u32) << 26) | (mode & 0x3FFFFFFF);
-        buffer.extend_from_slice(&type_compression_mode.to_le_bytes()[..4]);
+        let flags = match entry {
+            entries::Entry::File(file_entry) if file_entry.delta_chunk_ids => {
+                ENTRY_FLAG_DELTA_CHUNK_IDS
+            }
+            _ => 0,
+        };
 
-        writer.write_all(&buffer)?;
+        let type_compression_mode = (entry_type << 30)
+            | ((compression.encode() as u32) << 26)
+            | flags
+            | (mode & ENTRY_MODE_MASK);
+        writer.write_all(&type_compression_mode.to_le_bytes())?;
 
         let (uid, gid) = entry.owner();
-        writer.write_all(&varint::encode_u32(uid))?;
-        writer.write_all(&varint::encode_u32(gid))?;
+        varint::write_u32(writer, uid)?;
+        varint::write_u32(writer, gid)?;
 
         let mtime = entry
             .mtime()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap_or_default();
-        writer.write_all(&varint::encode_u64(mtime.as_secs()))?;
+        varint::write_u64(writer, mtime.as_secs())?;
 
         match entry {
             entries::Entry::File(file_entry) => {
-                writer.write_all(&varint::encode_u64(file_entry.size))?;
+                varint::write_u64(writer, file_entry.size)?;
 
                 if let Some(size_compressed) = file_entry.size_compressed {
-                    writer.write_all(&varint::encode_u64(size_compressed))?;
+                    varint::write_u64(writer, size_compressed)?;
                 }
-                writer.write_all(&varint::encode_u64(file_entry.size_real))?;
-                writer.write_all(&varint::encode_u64(file_entry.offset))?;
+                varint::write_u64(writer, file_entry.size_real)?;
+                varint::write_u64(writer, file_entry.offset)?;
             }
             entries::Entry::Directory(dir_entry) => {
-                writer.write_all(&varint::encode_u64(dir_entry.entries.len() as u64))?;
+                let entries = dir_entry.entries()?;
+                varint::write_u64(writer, entries.len() as u64)?;
 
-                for sub_entry in &dir_entry.entries {
+                for sub_entry in entries {
                     Self::encode_entry_metadata(writer, sub_entry)?;
                 }
             }
             entries::Entry::Symlink(link_entry) => {
-                writer.write_all(&varint::encode_u64(link_entry.target.len() as u64))?;
+                varint::write_u64(writer, link_entry.target.len() as u64)?;
                 writer.write_all(link_entry.target.as_bytes())?;
                 writer.write_all(&[link_entry.target_dir as u8])?;
             }
@@ -658,13 +902,10 @@ impl Archive {
 
             match compression {
                 CompressionFormat::None => {
-                    std::io::copy(&mut file, &mut self.file)?;
-
-                    self.file.flush()?;
+                    std::io::copy(&mut file, self.writer())?;
                 }
                 CompressionFormat::Gzip => {
-                    let mut encoder =
-                        GzEncoder::new(&mut self.file, flate2::Compression::default());
+                    let mut encoder = GzEncoder::new(self.writer(), flate2::Compression::default());
                     std::io::copy(&mut file, &mut encoder)?;
 
                     encoder.flush()?;
@@ -672,7 +913,7 @@ impl Archive {
                 }
                 CompressionFormat::Deflate => {
                     let mut encoder =
-                        DeflateEncoder::new(&mut self.file, flate2::Compression::default());
+                        DeflateEncoder::new(self.writer(), flate2::Compression::default());
                     std::io::copy(&mut file, &mut encoder)?;
 
                     encoder.flush()?;
@@ -681,7 +922,7 @@ impl Archive {
 
                 #[cfg(feature = "brotli")]
                 CompressionFormat::Brotli => {
-                    let mut encoder = brotli::CompressorWriter::new(&mut self.file, 4096, 11, 22);
+                    let mut encoder = brotli::CompressorWriter::new(self.writer(), 4096, 11, 22);
                     std::io::copy(&mut file, &mut encoder)?;
                 }
                 #[cfg(not(feature = "brotli"))]
@@ -702,19 +943,20 @@ impl Archive {
                 decoder: None,
                 size_compressed: match compression {
                     CompressionFormat::None => None,
-                    _ => Some(self.file.stream_position()? - self.entries_offset),
+                    _ => Some(self.write_position()? - self.entries_offset),
                 },
                 size_real: match self.real_size_callback {
                     Some(ref f) => f(&path),
                     None => metadata.len(),
                 },
                 size: metadata.len(),
+                delta_chunk_ids: false,
                 offset: self.entries_offset,
                 consumed: 0,
                 compression,
             };
 
-            self.entries_offset = self.file.stream_position()?;
+            self.entries_offset = self.write_position()?;
 
             if let Some(entries) = entries {
                 entries.push(entries::Entry::File(Box::new(entry)));
@@ -727,13 +969,13 @@ impl Archive {
                 self.encode_entry(Some(&mut dir_entries), entry, progress.clone())?;
             }
 
-            let dir_entry = entries::DirectoryEntry {
-                name: file_name.to_string_lossy().into(),
-                mode: metadata.permissions().into(),
-                owner: metadata_owner(&metadata),
-                mtime: metadata.modified()?,
-                entries: dir_entries,
-            };
+            let dir_entry = entries::DirectoryEntry::new(
+                file_name.to_string_lossy(),
+                metadata.permissions().into(),
+                metadata_owner(&metadata),
+                metadata.modified()?,
+                dir_entries,
+            );
 
             if let Some(entries) = entries {
                 entries.push(entries::Entry::Directory(Box::new(dir_entry)));
@@ -770,13 +1012,41 @@ impl Archive {
         Ok(())
     }
 
-    fn decode_entry<S: Read>(
+    /// Decodes the `count` entries starting at `start` of the end header of a lazily opened archive.
+    pub(crate) fn decode_lazy_entries(
+        header: &Arc<entries::LazyHeader>,
+        start: usize,
+        count: usize,
+        depth: usize,
+    ) -> std::io::Result<Vec<entries::Entry>> {
+        let mut cursor = LazyCursor {
+            header: header.clone(),
+            position: start,
+        };
+
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let entry = Self::decode_entry(
+                &mut cursor,
+                header.file.clone(),
+                &header.limits,
+                header.version,
+                depth,
+            )?;
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+
+    fn decode_entry<S: EntrySource>(
         decoder: &mut S,
         file: Arc<File>,
         limits: &DecodeLimits,
+        version: u8,
         depth: usize,
     ) -> std::io::Result<entries::Entry> {
-        let name_length = varint::decode_u32(decoder)? as usize;
+        let name_length = varint::decode_u32_checked(decoder)? as usize;
 
         if name_length > limits.max_name_len {
             return Err(std::io::Error::new(
@@ -800,24 +1070,34 @@ impl Archive {
         let entry_type = (type_compression_mode >> 30) & 0b11;
         let compression =
             CompressionFormat::try_decode(((type_compression_mode >> 26) & 0b1111) as u8)?;
-        let mode = EntryMode::from(type_compression_mode & 0x3FFFFFFF);
+        let (delta_chunk_ids, mode) = if version >= 2 {
+            (
+                type_compression_mode & ENTRY_FLAG_DELTA_CHUNK_IDS != 0,
+                EntryMode::from(type_compression_mode & ENTRY_MODE_MASK),
+            )
+        } else {
+            (
+                false,
+                EntryMode::from(type_compression_mode & ENTRY_MODE_MASK_V1),
+            )
+        };
 
-        let uid = varint::decode_u32(decoder)?;
-        let gid = varint::decode_u32(decoder)?;
+        let uid = varint::decode_u32_checked(decoder)?;
+        let gid = varint::decode_u32_checked(decoder)?;
 
-        let mtime = varint::decode_u64(decoder)?;
+        let mtime = varint::decode_u64_checked(decoder)?;
         let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::new(mtime, 0);
 
-        let size = varint::decode_u64(decoder)?;
+        let size = varint::decode_u64_checked(decoder)?;
 
         match entry_type {
             0 => {
                 let size_compressed = match compression {
                     CompressionFormat::None => None,
-                    _ => Some(varint::decode_u64(decoder)?),
+                    _ => Some(varint::decode_u64_checked(decoder)?),
                 };
-                let size_real = varint::decode_u64(decoder)?;
-                let offset = varint::decode_u64(decoder)?;
+                let size_real = varint::decode_u64_checked(decoder)?;
+                let offset = varint::decode_u64_checked(decoder)?;
 
                 Ok(entries::Entry::File(Box::new(entries::FileEntry {
                     name,
@@ -829,6 +1109,7 @@ impl Archive {
                     size_compressed,
                     size_real,
                     size,
+                    delta_chunk_ids,
                     offset,
                     consumed: 0,
                     compression,
@@ -854,20 +1135,12 @@ impl Archive {
                     ));
                 }
 
-                let mut entries: Vec<entries::Entry> = Vec::with_capacity(child_count);
-                for _ in 0..child_count {
-                    let entry = Self::decode_entry(decoder, file.clone(), limits, depth + 1)?;
-                    entries.push(entry);
-                }
+                let (entries, lazy) =
+                    decoder.decode_children(&file, limits, version, child_count, depth + 1)?;
 
                 Ok(entries::Entry::Directory(Box::new(
-                    entries::DirectoryEntry {
-                        name,
-                        mode,
-                        owner: (uid, gid),
-                        mtime,
-                        entries,
-                    },
+                    entries::DirectoryEntry::new(name, mode, (uid, gid), mtime, entries)
+                        .with_lazy(lazy),
                 )))
             }
             2 => {
@@ -909,3 +1182,658 @@ impl Archive {
         }
     }
 }
+
+/// A stream of encoded entries, decides how the children of directories are decoded.
+trait EntrySource: Read {
+    fn decode_children(
+        &mut self,
+        file: &Arc<File>,
+        limits: &DecodeLimits,
+        version: u8,
+        count: usize,
+        depth: usize,
+    ) -> std::io::Result<(Vec<entries::Entry>, Option<entries::LazyEntries>)>;
+}
+
+impl<R: Read> EntrySource for DeflateDecoder<R> {
+    fn decode_children(
+        &mut self,
+        file: &Arc<File>,
+        limits: &DecodeLimits,
+        version: u8,
+        count: usize,
+        depth: usize,
+    ) -> std::io::Result<(Vec<entries::Entry>, Option<entries::LazyEntries>)> {
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let entry = Archive::decode_entry(self, file.clone(), limits, version, depth)?;
+            entries.push(entry);
+        }
+
+        Ok((entries, None))
+    }
+}
+
+/// Checks every entry of a decompressed end header without decoding it,
+/// recording where the children of every directory are located for lazy decoding.
+struct HeaderScan<'a> {
+    data: &'a [u8],
+    position: usize,
+    directories: Vec<(usize, usize)>,
+}
+
+impl Read for HeaderScan<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = (&self.data[self.position..]).read(buf)?;
+        self.position += bytes_read;
+
+        Ok(bytes_read)
+    }
+}
+
+impl<'a> HeaderScan<'a> {
+    fn take(&mut self, len: usize) -> std::io::Result<&'a [u8]> {
+        let data = self.data;
+        let bytes = data
+            .get(self.position..)
+            .and_then(|remaining| remaining.get(..len))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "end header ended within an entry",
+                )
+            })?;
+        self.position += len;
+
+        Ok(bytes)
+    }
+
+    /// Skips over an encoded entry, applying the same checks as `Archive::decode_entry`.
+    fn skip_entry(&mut self, limits: &DecodeLimits, depth: usize) -> std::io::Result<()> {
+        let name_length = varint::decode_u32_checked(self)? as usize;
+
+        if name_length > limits.max_name_len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "entry name length {} exceeds limit {}",
+                    name_length, limits.max_name_len
+                ),
+            ));
+        }
+
+        std::str::from_utf8(self.take(name_length)?)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let type_mode_bytes = self.take(4)?;
+        let type_compression_mode = u32::from_le_bytes([
+            type_mode_bytes[0],
+            type_mode_bytes[1],
+            type_mode_bytes[2],
+            type_mode_bytes[3],
+        ]);
+
+        let entry_type = (type_compression_mode >> 30) & 0b11;
+        let compression =
+            CompressionFormat::try_decode(((type_compression_mode >> 26) & 0b1111) as u8)?;
+
+        varint::decode_u32_checked(self)?;
+        varint::decode_u32_checked(self)?;
+        varint::decode_u64_checked(self)?;
+
+        let size = varint::decode_u64_checked(self)?;
+
+        match entry_type {
+            0 => {
+                if compression != CompressionFormat::None {
+                    varint::decode_u64_checked(self)?;
+                }
+                varint::decode_u64_checked(self)?;
+                varint::decode_u64_checked(self)?;
+
+                Ok(())
+            }
+            1 => {
+                let child_count = size as usize;
+
+                if child_count > limits.max_entry_count {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "directory child count {} exceeds limit {}",
+                            child_count, limits.max_entry_count
+                        ),
+                    ));
+                }
+
+                if depth >= limits.max_depth {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("directory nesting exceeded limit {}", limits.max_depth),
+                    ));
+                }
+
+                let start = self.position;
+                for _ in 0..child_count {
+                    self.skip_entry(limits, depth + 1)?;
+                }
+
+                if child_count > 0 {
+                    self.directories.push((start, self.position));
+                }
+
+                Ok(())
+            }
+            2 => {
+                let target_len = size as usize;
+
+                if target_len > limits.max_target_len {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "symlink target length {} exceeds limit {}",
+                            target_len, limits.max_target_len
+                        ),
+                    ));
+                }
+
+                std::str::from_utf8(self.take(target_len)?)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+                self.take(1)?;
+
+                Ok(())
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid entry type",
+            )),
+        }
+    }
+}
+
+/// Reads a decompressed end header, skipping over the children of directories.
+struct LazyCursor {
+    header: Arc<entries::LazyHeader>,
+    position: usize,
+}
+
+impl Read for LazyCursor {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = (&self.header.data[self.position..]).read(buf)?;
+        self.position += bytes_read;
+
+        Ok(bytes_read)
+    }
+}
+
+impl EntrySource for LazyCursor {
+    fn decode_children(
+        &mut self,
+        _file: &Arc<File>,
+        _limits: &DecodeLimits,
+        _version: u8,
+        count: usize,
+        depth: usize,
+    ) -> std::io::Result<(Vec<entries::Entry>, Option<entries::LazyEntries>)> {
+        if count == 0 {
+            return Ok((Vec::new(), None));
+        }
+
+        let start = self.position;
+        self.position = self.header.directory_end(start).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Directory missing from the lazy header",
+            )
+        })?;
+
+        Ok((
+            Vec::new(),
+            Some(entries::LazyEntries::new(
+                self.header.clone(),
+                start,
+                count,
+                depth,
+            )),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_path;
+
+    /// Writes an archive with a single file entry using delta encoded chunk IDs.
+    fn write_delta_archive(path: &Path) {
+        let mut archive = Archive::new(File::create(path).unwrap()).unwrap();
+
+        let mut entry = archive
+            .write_file_entry(
+                std::io::Cursor::new([2, 2, 2]),
+                None,
+                "file",
+                EntryMode::from(0o100644),
+                SystemTime::UNIX_EPOCH,
+                (1000, 1000),
+                CompressionFormat::None,
+            )
+            .unwrap();
+        entry.delta_chunk_ids = true;
+
+        archive.entries.push(entries::Entry::File(entry));
+        archive.write_end_header().unwrap();
+    }
+
+    #[test]
+    fn delta_chunk_ids_flag_round_trips() {
+        let path = temp_path("archive-delta");
+        write_delta_archive(&path);
+
+        for archive in [
+            Archive::open(&path).unwrap(),
+            Archive::open_lazy(&path).unwrap(),
+        ] {
+            assert_eq!(archive.version(), FILE_VERSION);
+
+            let Some(entries::Entry::File(file_entry)) = archive.entries().first() else {
+                panic!("expected a file entry");
+            };
+            assert!(file_entry.delta_chunk_ids);
+            assert_eq!(file_entry.mode.bits(), 0o100644);
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn version_1_archives_keep_bit_25_in_the_mode() {
+        let path = temp_path("archive-version-1");
+        write_delta_archive(&path);
+
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(7)).unwrap();
+        file.write_all(&[1]).unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(archive.version(), 1);
+
+        let Some(entries::Entry::File(file_entry)) = archive.entries().first() else {
+            panic!("expected a file entry");
+        };
+        assert!(!file_entry.delta_chunk_ids);
+        assert_eq!(
+            file_entry.mode.bits(),
+            0o100644 | ENTRY_FLAG_DELTA_CHUNK_IDS
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let path = temp_path("archive-version-newer");
+        write_delta_archive(&path);
+
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(7)).unwrap();
+        file.write_all(&[FILE_VERSION + 1]).unwrap();
+
+        for result in [Archive::open(&path), Archive::open_lazy(&path)] {
+            assert_eq!(
+                result.err().unwrap().kind(),
+                std::io::ErrorKind::InvalidData
+            );
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn appending_to_a_version_1_archive_upgrades_it() {
+        let path = temp_path("archive-version-upgrade");
+        write_delta_archive(&path);
+
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(7)).unwrap();
+        file.write_all(&[1]).unwrap();
+        drop(file);
+
+        let file = File::options().read(true).write(true).open(&path).unwrap();
+        let mut archive = Archive::open_file(file).unwrap();
+        archive.trim_end_header().unwrap();
+        let mut entry = archive
+            .write_file_entry(
+                std::io::Cursor::new([3, 3]),
+                None,
+                "appended",
+                EntryMode::from(0o100644),
+                SystemTime::UNIX_EPOCH,
+                (1000, 1000),
+                CompressionFormat::None,
+            )
+            .unwrap();
+        entry.delta_chunk_ids = true;
+        archive.entries.push(entries::Entry::File(entry));
+        archive.write_end_header().unwrap();
+        assert_eq!(archive.version(), FILE_VERSION);
+
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(archive.version(), FILE_VERSION);
+        let [
+            entries::Entry::File(original),
+            entries::Entry::File(appended),
+        ] = archive.entries()
+        else {
+            panic!("expected two file entries");
+        };
+        assert!(!original.delta_chunk_ids);
+        assert_eq!(original.mode.bits(), 0o100644);
+        assert!(appended.delta_chunk_ids);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Writes an archive of a small tree with nested, empty and non empty directories.
+    fn write_tree_archive(directory: &Path) -> std::path::PathBuf {
+        let source = directory.join("source");
+        std::fs::create_dir_all(source.join("a/b")).unwrap();
+        std::fs::create_dir_all(source.join("empty")).unwrap();
+        std::fs::write(source.join("a/b/c.txt"), "nested file content").unwrap();
+        std::fs::write(source.join("a/d.txt"), "d").unwrap();
+        std::fs::write(source.join("e.txt"), "top level file").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(source.join("e.txt"), source.join("link")).unwrap();
+
+        let path = directory.join("archive.ddup");
+        let mut archive = Archive::new(File::create(&path).unwrap()).unwrap();
+        archive
+            .add_entries(
+                std::fs::read_dir(&source).unwrap().flatten().collect(),
+                None,
+            )
+            .unwrap();
+
+        path
+    }
+
+    fn walked(archive: &Archive) -> Vec<(std::path::PathBuf, u32, u64, bool)> {
+        archive
+            .walk()
+            .sorted()
+            .map(|item| {
+                let (path, _, entry) = item.unwrap();
+                let size = match entry {
+                    entries::Entry::File(file_entry) => file_entry.size_real,
+                    _ => 0,
+                };
+
+                (path, entry.mode().bits(), size, entry.is_directory())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lazy_entries_match_eager_entries() {
+        let directory = temp_path("archive-lazy-tree");
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = write_tree_archive(&directory);
+
+        let eager = Archive::open(&path).unwrap();
+        let lazy = Archive::open_lazy(&path).unwrap();
+
+        let Some(entries::Entry::Directory(dir_entry)) =
+            lazy.find_archive_entry(Path::new("a")).unwrap()
+        else {
+            panic!("expected a directory entry");
+        };
+        assert!(!dir_entry.is_loaded());
+
+        let eager_entries = walked(&eager);
+        assert_eq!(eager_entries, walked(&lazy));
+        assert!(
+            eager_entries
+                .iter()
+                .any(|(path, _, size, _)| path == Path::new("a/b/c.txt") && *size == 19)
+        );
+        assert!(
+            eager_entries
+                .iter()
+                .any(|(path, ..)| path == Path::new("empty"))
+        );
+
+        let mut loaded = Archive::open_lazy(&path).unwrap();
+        loaded.load_entries().unwrap();
+        assert_eq!(eager_entries, walked(&loaded));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn lazy_open_of_a_huge_archive_only_decodes_what_is_visited() {
+        const DIRECTORIES: usize = 2000;
+        const LINKS: usize = 100;
+
+        let directory = temp_path("archive-lazy-huge");
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("archive.ddup");
+
+        let mut archive = Archive::new(File::create(&path).unwrap()).unwrap();
+        for d in 0..DIRECTORIES {
+            let links = (0..LINKS)
+                .map(|l| {
+                    entries::Entry::Symlink(Box::new(entries::SymlinkEntry {
+                        name: format!("link-{l}"),
+                        mode: EntryMode::from(0o120777),
+                        owner: (1000, 1000),
+                        mtime: SystemTime::UNIX_EPOCH,
+                        target: format!("../dir-{d}"),
+                        target_dir: true,
+                    }))
+                })
+                .collect();
+
+            archive.entries.push(entries::Entry::Directory(Box::new(
+                entries::DirectoryEntry::new(
+                    format!("dir-{d}"),
+                    EntryMode::from(0o40755),
+                    (1000, 1000),
+                    SystemTime::UNIX_EPOCH,
+                    links,
+                ),
+            )));
+        }
+        archive.write_end_header().unwrap();
+
+        let lazy = Archive::open_lazy(&path).unwrap();
+        assert_eq!(lazy.entries().len(), DIRECTORIES);
+
+        let last = format!("dir-{}/link-{}", DIRECTORIES - 1, LINKS - 1);
+        let Some(entries::Entry::Symlink(link)) =
+            lazy.find_archive_entry(Path::new(&last)).unwrap()
+        else {
+            panic!("expected a symlink entry");
+        };
+        assert_eq!(link.target, format!("../dir-{}", DIRECTORIES - 1));
+
+        let loaded = lazy
+            .entries()
+            .iter()
+            .filter(|entry| match entry {
+                entries::Entry::Directory(dir_entry) => dir_entry.is_loaded(),
+                _ => false,
+            })
+            .count();
+        assert_eq!(loaded, 1);
+
+        assert_eq!(lazy.walk().count(), DIRECTORIES * (LINKS + 1));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn header_scan_rejects_truncated_headers() {
+        let directory = temp_path("archive-scan");
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = write_tree_archive(&directory);
+
+        let archive = Archive::open(&path).unwrap();
+        let entries_count = archive.entries().len();
+
+        let mut file = File::open(&path).unwrap();
+        file.seek(SeekFrom::Start(archive.entries_offset)).unwrap();
+        let mut data = Vec::new();
+        DeflateDecoder::new(file).read_to_end(&mut data).unwrap();
+
+        let limits = DecodeLimits::default();
+        let scan = |data: &[u8]| {
+            let mut scan = HeaderScan {
+                data,
+                position: 0,
+                directories: Vec::new(),
+            };
+            for _ in 0..entries_count {
+                scan.skip_entry(&limits, 0)?;
+            }
+
+            Ok::<_, std::io::Error>((scan.position, scan.directories))
+        };
+
+        let (position, directories) = scan(&data).unwrap();
+        assert_eq!(position, data.len());
+        // "a" and "a/b" have children, "empty" does not
+        assert_eq!(directories.len(), 2);
+        assert!(
+            directories
+                .iter()
+                .all(|(start, end)| start < end && *end <= data.len())
+        );
+
+        for len in 0..data.len() {
+            assert!(scan(&data[..len]).is_err(), "{len} bytes scanned");
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn undecodable_lazy_entries_return_errors() {
+        let path = temp_path("archive-lazy-corrupt");
+        write_delta_archive(&path);
+
+        // a directory whose children point at an entry of an unknown type
+        let header = Arc::new(entries::LazyHeader {
+            data: vec![1, b'x', 0, 0, 0, 0b1100_0000, 0, 0, 0, 0],
+            directories: vec![(0, 10)],
+            file: Arc::new(File::open(&path).unwrap()),
+            limits: DecodeLimits::default(),
+            version: FILE_VERSION,
+        });
+        let mut archive = Archive::open(&path).unwrap();
+        archive.entries.push(entries::Entry::Directory(Box::new(
+            entries::DirectoryEntry::new(
+                "directory",
+                EntryMode::from(0o40755),
+                (0, 0),
+                SystemTime::UNIX_EPOCH,
+                Vec::new(),
+            )
+            .with_lazy(Some(entries::LazyEntries::new(header, 0, 1, 1))),
+        )));
+
+        let Some(entries::Entry::Directory(dir_entry)) =
+            archive.find_archive_entry(Path::new("directory")).unwrap()
+        else {
+            panic!("expected a directory entry");
+        };
+        assert!(dir_entry.entries().is_err());
+        assert!(dir_entry.clone().into_entries().is_err());
+
+        assert!(
+            archive
+                .find_archive_entry(Path::new("directory/x"))
+                .is_err()
+        );
+        assert!(archive.select_entries(&[Path::new("directory/x")]).is_err());
+
+        let walk = archive.walk().collect::<Vec<_>>();
+        assert_eq!(walk.len(), 2);
+        assert!(
+            walk[0]
+                .as_ref()
+                .is_ok_and(|(path, ..)| path == Path::new("file"))
+        );
+        assert!(walk[1].is_err());
+
+        assert!(archive.into_entries().is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write_buffer_size_does_not_change_the_archive() {
+        let mut compressions = vec![
+            CompressionFormat::None,
+            CompressionFormat::Gzip,
+            CompressionFormat::Deflate,
+        ];
+        #[cfg(feature = "brotli")]
+        compressions.push(CompressionFormat::Brotli);
+
+        let contents = [
+            Vec::new(),
+            b"small".to_vec(),
+            (0..100_000u32).map(|i| (i % 251) as u8).collect(),
+        ];
+
+        let mut archives = Vec::new();
+        for size in [1, 100, DEFAULT_WRITE_BUFFER_SIZE] {
+            let path = temp_path(&format!("archive-write-buffer-{size}"));
+            let mut archive = Archive::new(File::create(&path).unwrap()).unwrap();
+            archive.set_write_buffer_size(size).unwrap();
+
+            for (i, compression) in compressions.iter().enumerate() {
+                for (j, content) in contents.iter().enumerate() {
+                    let entry = archive
+                        .write_file_entry(
+                            content.as_slice(),
+                            None,
+                            format!("file-{i}-{j}"),
+                            EntryMode::from(0o100644),
+                            SystemTime::UNIX_EPOCH,
+                            (1000, 1000),
+                            *compression,
+                        )
+                        .unwrap();
+                    archive.entries.push(entries::Entry::File(entry));
+                }
+
+                // changing the size flushes what the previous buffer held
+                archive.set_write_buffer_size(size + i).unwrap();
+            }
+            archive.write_end_header().unwrap();
+            drop(archive);
+
+            let archive = Archive::open(&path).unwrap();
+            for (i, _) in compressions.iter().enumerate() {
+                for (j, content) in contents.iter().enumerate() {
+                    let Some(entries::Entry::File(entry)) = archive
+                        .find_archive_entry(Path::new(&format!("file-{i}-{j}")))
+                        .unwrap()
+                    else {
+                        panic!("expected a file entry");
+                    };
+
+                    let mut read = Vec::new();
+                    entry.clone().read_to_end(&mut read).unwrap();
+                    assert_eq!(&read, content, "file-{i}-{j} with a buffer of {size}");
+                }
+            }
+
+            archives.push(std::fs::read(&path).unwrap());
+            std::fs::remove_file(path).unwrap();
+        }
+
+        assert!(archives.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+}
@@ -0,0 +1,163 @@
+use crate::{archive::entries::FileEntry, varint};
+
+/// Encodes a list of chunk IDs for storage as the content of a file entry.
+/// Every ID is stored as the zigzag varint delta to the previous one, fresh data
+/// mostly receives ascending IDs, so most deltas fit in a single byte.
+pub fn encode(ids: &[u64]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(ids.len());
+    let mut previous = 0u64;
+    let mut buffer = [0; varint::MAX_LENGTH_U64];
+
+    for &id in ids {
+        let delta = id.wrapping_sub(previous) as i64;
+        let length = varint::encode_u64_into(((delta << 1) ^ (delta >> 63)) as u64, &mut buffer);
+        result.extend_from_slice(&buffer[..length]);
+
+        previous = id;
+    }
+
+    result
+}
+
+/// Decodes the chunk IDs stored as the content of a file entry.
+/// Supports both the delta encoded lists and the plain varint lists of version 1 archives.
+pub struct ChunkIdDecoder {
+    delta: bool,
+    previous: u64,
+    finished: bool,
+}
+
+impl ChunkIdDecoder {
+    #[inline]
+    pub fn new(entry: &FileEntry) -> Self {
+        Self {
+            delta: entry.delta_chunk_ids,
+            previous: 0,
+            finished: false,
+        }
+    }
+
+    /// Reads the next chunk ID from the entry, returns `None` once the list is exhausted.
+    pub fn next_id(&mut self, entry: &mut FileEntry) -> std::io::Result<Option<u64>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let value = match varint::decode_u64_checked(entry) {
+            Ok(value) => value,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                self.finished = true;
+                return Ok(None);
+            }
+            Err(err) => {
+                self.finished = true;
+                return Err(err);
+            }
+        };
+
+        let id = if self.delta {
+            let delta = ((value >> 1) as i64) ^ -((value & 1) as i64);
+            self.previous.wrapping_add_signed(delta)
+        } else {
+            value
+        };
+
+        if id == 0 {
+            self.finished = true;
+            return Ok(None);
+        }
+
+        self.previous = id;
+
+        Ok(Some(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        archive::{Archive, CompressionFormat, entries::EntryMode},
+        test_util::temp_path,
+    };
+    use std::fs::File;
+
+    /// Stores `content` as a file entry and reads the chunk IDs back from it.
+    fn decode(name: &str, content: Vec<u8>, delta: bool) -> Vec<u64> {
+        let path = temp_path(&format!("ids-{name}"));
+
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+
+        let mut archive = Archive::new(file).unwrap();
+        let mut entry = archive
+            .write_file_entry(
+                std::io::Cursor::new(content),
+                None,
+                "file",
+                EntryMode::from(0o100644),
+                std::time::SystemTime::UNIX_EPOCH,
+                (0, 0),
+                CompressionFormat::None,
+            )
+            .unwrap();
+        entry.delta_chunk_ids = delta;
+
+        let mut ids = Vec::new();
+        let mut decoder = ChunkIdDecoder::new(&entry);
+        while let Some(id) = decoder.next_id(&mut entry).unwrap() {
+            ids.push(id);
+        }
+
+        std::fs::remove_file(path).unwrap();
+
+        ids
+    }
+
+    #[test]
+    fn delta_encoded_ids_round_trip() {
+        let ids = [1, 2, 3, 1_000_000, 5, u64::MAX, 7, 7, 300];
+
+        assert_eq!(decode("delta", encode(&ids), true), ids);
+        assert_eq!(decode("empty", encode(&[]), true), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn ascending_ids_take_a_byte_each() {
+        assert_eq!(encode(&(1..=100).collect::<Vec<_>>()).len(), 100);
+    }
+
+    #[test]
+    fn delta_encoding_shrinks_huge_entries() {
+        // a file of 100k chunks backed up into a repository holding a million chunks
+        let ids = (1_000_000..1_100_000).collect::<Vec<u64>>();
+
+        let mut plain = Vec::new();
+        for &id in &ids {
+            varint::write_u64(&mut plain, id).unwrap();
+        }
+        let delta = encode(&ids);
+
+        assert_eq!(plain.len(), 3 * ids.len());
+        assert!(delta.len() < plain.len() / 2, "{} bytes", delta.len());
+        assert_eq!(decode("huge-plain", plain, false), ids);
+        assert_eq!(decode("huge-delta", delta, true), ids);
+    }
+
+    #[test]
+    fn plain_ids_of_version_1_archives_decode() {
+        let ids = [5, 1, 70_000, u64::MAX];
+
+        let mut content = Vec::new();
+        for id in ids {
+            varint::write_u64(&mut content, id).unwrap();
+        }
+
+        assert_eq!(decode("plain", content, false), ids);
+    }
+}
@@ -1,16 +1,43 @@
 use atomicwrites::{AllowOverwrite, AtomicFile};
+use blake2::{Blake2b, Digest, digest::consts::U32};
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::{
-    fs::File,
+    fs::{File, OpenOptions, TryLockError},
     io::{Read, Seek, SeekFrom, Write},
-    path::Path,
+    path::{Path, PathBuf},
     sync::{
-        Arc, Mutex,
+        Arc, Condvar, Mutex,
         atomic::{AtomicU64, Ordering},
     },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
+/// Starts every lock file, followed by the format version and a CRC32 of the state.
+const LOCK_MAGIC: &[u8; 8] = b"DDUPLOCK";
+/// Version of the lock file format. Version 1 had no header, version 2 appended the
+/// sessions, marked with `LEGACY_SESSIONS_MAGIC`, version 3 had sessions without a
+/// heartbeat and version 4 did not identify the host. All of them are read and
+/// rewritten as this one.
+const LOCK_FORMAT_VERSION: u32 = 5;
+const LEGACY_SESSIONS_MAGIC: &[u8; 4] = b"DDLS";
+/// Where the heartbeat is stored in the lock file, after the header. It is rewritten in
+/// place and therefore not part of the checksum.
+const HEARTBEAT_OFFSET: u64 = 16;
+/// Where the state covered by the checksum starts, followed by the sessions.
+const STATE_OFFSET: usize = 24;
+/// How often a process holding the lock refreshes the heartbeat.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a session may go without a heartbeat before its readers are dropped.
+const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+/// The shortest session timeout, a few heartbeats so a busy system does not lose readers.
+const MIN_SESSION_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the refresh thread reads the lock file unless set otherwise.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+/// How long dropping a lock waits for its refresh thread to exit.
+const REFRESH_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LockMode {
@@ -31,161 +58,1557 @@ impl LockMode {
     fn as_u8(self) -> u8 {
         self as u8
     }
+
+    /// The other mode readers and writers have to keep apart from.
+    fn other(self) -> Self {
+        match self {
+            LockMode::Destructive => LockMode::NonDestructive,
+            LockMode::NonDestructive => LockMode::Destructive,
+            LockMode::None => LockMode::None,
+        }
+    }
+}
+
+/// How a lock is shared between processes. Every process using a repository has to use
+/// the same backend, which is why it is part of the repository configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockBackend {
+    /// The holders are kept in the lock file and polled by a refresh thread, which works
+    /// on any file system but leaves stale holders behind when processes are killed.
+    #[default]
+    Polled,
+    /// Advisory locks of the operating system, `flock` on unix and `LockFileEx` on
+    /// windows, on files next to the lock file. The system releases them when a process
+    /// exits and waiting needs no thread, but some network file systems do not support them.
+    Native,
+}
+
+impl std::str::FromStr for LockBackend {
+    type Err = std::io::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "polled" => Ok(LockBackend::Polled),
+            "native" => Ok(LockBackend::Native),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown lock backend {value:?}, expected polled or native"),
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for LockBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LockBackend::Polled => "polled",
+            LockBackend::Native => "native",
+        })
+    }
+}
+
+/// Lock state left behind by a process that is gone, cleared by `RwLock::recover_stale`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaleLock {
+    /// The process holding the write lock no longer exists.
+    Writer { pid: u64, mode: LockMode },
+    /// Readers were counted, but no process refreshed the heartbeat for `age`, `None`
+    /// if the lock file has no heartbeat yet.
+    Readers {
+        counts: [u64; 3],
+        age: Option<Duration>,
+    },
+    /// The process of a session exited, or was killed, without releasing its readers.
+    /// `age` is how long the session went without a heartbeat if the process seems to
+    /// exist but stopped refreshing it, `None` if the process is gone.
+    /// The session is identified by `pid`, the `host_id` of its host and the start time
+    /// of its process, the pid alone may belong to a process of another host.
+    Session {
+        pid: u64,
+        host: u64,
+        start_time: u64,
+        counts: [u64; 3],
+        age: Option<Duration>,
+    },
+    /// The lock file could not be decoded and was reset to an unlocked state, reported
+    /// whenever it is read rather than by `recover_stale`.
+    Corrupt { reason: &'static str },
+}
+
+impl std::fmt::Display for StaleLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StaleLock::Writer { pid, mode } => {
+                write!(f, "recovered stale {mode:?} write lock from pid {pid}")
+            }
+            StaleLock::Readers { counts, age } => {
+                write!(
+                    f,
+                    "recovered {} stale reader(s) without a heartbeat",
+                    counts.iter().sum::<u64>()
+                )?;
+
+                match age {
+                    Some(age) => write!(f, " for {}s", age.as_secs()),
+                    None => Ok(()),
+                }
+            }
+            StaleLock::Session {
+                pid,
+                counts,
+                age: None,
+                ..
+            } => {
+                write!(
+                    f,
+                    "recovered {} reader(s) of exited pid {pid}",
+                    counts.iter().sum::<u64>()
+                )
+            }
+            StaleLock::Session {
+                pid,
+                counts,
+                age: Some(age),
+                ..
+            } => {
+                write!(
+                    f,
+                    "recovered {} reader(s) of pid {pid} without a heartbeat for {}s",
+                    counts.iter().sum::<u64>(),
+                    age.as_secs()
+                )
+            }
+            StaleLock::Corrupt { reason } => {
+                write!(f, "reinitialized corrupt lock file ({reason})")
+            }
+        }
+    }
+}
+
+/// Called for every stale lock that was recovered, in addition to a warning being logged.
+pub type StaleLockCallback = Option<Arc<dyn Fn(&StaleLock) + Send + Sync>>;
+
+/// An acquisition of a lock that had to wait, passed to the callback set with
+/// `RwLock::set_wait_callback`. The holder is the one at the time of the event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockWaitEvent {
+    /// The requested mode.
+    pub mode: LockMode,
+    /// Whether a write lock was requested, otherwise a read lock.
+    pub write: bool,
+    pub writer_pid: Option<u64>,
+    pub writer_mode: Option<LockMode>,
+    /// Readers of every process, indexed by `LockMode`.
+    pub reader_counts: [u64; 3],
+    /// How long the acquisition waited so far.
+    pub elapsed: Duration,
+    /// Whether the lock was acquired, otherwise it is still waited for.
+    pub acquired: bool,
+}
+
+/// Called for acquisitions waiting longer than the threshold, see `RwLock::set_wait_callback`.
+pub type LockWaitCallback = Option<Arc<dyn Fn(&LockWaitEvent) + Send + Sync>>;
+
+/// How much the acquisitions of a lock had to wait, returned by `RwLock::metrics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LockMetrics {
+    /// Acquisitions that were not granted right away.
+    pub contended: u64,
+    /// Acquisitions that gave up after their timeout.
+    pub timeouts: u64,
+    /// Time spent waiting by all of them.
+    pub wait_time: Duration,
+}
+
+/// One blocking acquisition of a lock.
+struct Acquisition {
+    mode: LockMode,
+    write: bool,
+    timeout: Duration,
+    started: Instant,
+    deadline: Option<Instant>,
+    backoff: Duration,
+    waited: bool,
+    /// Whether the wait callback was told about this acquisition.
+    reported: bool,
+}
+
+impl Acquisition {
+    fn new(mode: LockMode, write: bool, timeout: Duration) -> Self {
+        let started = Instant::now();
+
+        Self {
+            mode,
+            write,
+            timeout,
+            started,
+            deadline: started.checked_add(timeout),
+            backoff: Duration::from_millis(1),
+            waited: false,
+            reported: false,
+        }
+    }
+}
+
+/// Who holds a lock according to its lock file, returned by `RwLock::status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LockStatus {
+    pub writer_pid: Option<u64>,
+    /// `host_id` of the writer's host, `None` for the native backend, which only sees
+    /// processes of this host.
+    pub writer_host: Option<u64>,
+    pub writer_mode: Option<LockMode>,
+    /// Readers of every process, indexed by `LockMode`. The native backend only knows
+    /// whether there are readers of a mode, which it counts as 1.
+    pub reader_counts: [u64; 3],
+    /// How long ago a holder of the lock refreshed the heartbeat, `None` if never.
+    pub heartbeat_age: Option<Duration>,
+}
+
+/// The refresh thread of a polled lock, which only runs while the lock is waited for
+/// or held by this process.
+#[derive(Default)]
+struct RefreshState {
+    handle: Option<JoinHandle<()>>,
+    running: bool,
+    shutdown: bool,
+}
+
+/// Stops the refresh thread once the last clone of a lock, including those held by
+/// its guards, is dropped.
+struct RefreshOwner(Arc<Shared>);
+
+impl Drop for RefreshOwner {
+    fn drop(&mut self) {
+        self.0.stop_refresh();
+    }
+}
+
+/// Wakes the refresh thread when the directory of the lock file changes, so it does
+/// not have to poll the lock file while waiting.
+#[cfg(target_os = "linux")]
+struct LockWatcher {
+    inotify: OwnedFd,
+    wakeup: OwnedFd,
+}
+
+#[cfg(target_os = "linux")]
+impl LockWatcher {
+    fn new(path: &Path) -> std::io::Result<Self> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let directory = std::ffi::CString::new(directory.as_os_str().as_bytes())?;
+
+        unsafe {
+            let inotify = libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC);
+            if inotify < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let inotify = OwnedFd::from_raw_fd(inotify);
+
+            // the state is replaced by a rename, the heartbeat written in place
+            if libc::inotify_add_watch(
+                inotify.as_raw_fd(),
+                directory.as_ptr(),
+                libc::IN_MODIFY | libc::IN_MOVED_TO,
+            ) < 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            let wakeup = libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC);
+            if wakeup < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            Ok(Self {
+                inotify,
+                wakeup: OwnedFd::from_raw_fd(wakeup),
+            })
+        }
+    }
+
+    /// Waits until something in the directory changed, `wake` was called or `timeout`
+    /// passed, discarding the events.
+    fn wait(&self, timeout: Duration) {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.inotify.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.wakeup.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } > 0 {
+            let mut buffer = [0u8; 4096];
+            for fd in &fds {
+                while unsafe { libc::read(fd.fd, buffer.as_mut_ptr().cast(), buffer.len()) } > 0 {}
+            }
+        }
+    }
+
+    fn wake(&self) {
+        let value = 1u64;
+        unsafe {
+            libc::write(
+                self.wakeup.as_raw_fd(),
+                (&value as *const u64).cast(),
+                std::mem::size_of::<u64>(),
+            )
+        };
+    }
+}
+
+/// The state shared by all clones of a lock and its refresh thread.
+struct Shared {
+    path: PathBuf,
+    writer_mode: AtomicU64,
+    writer_present: AtomicU64,
+    writer_pid: AtomicU64,
+    writer_host: AtomicU64,
+    reader_counts: [AtomicU64; 3],
+    process_reader_counts: [AtomicU64; 3],
+    process_has_writer: AtomicU64,
+    /// Seconds without a heartbeat after which reader counts are considered stale, 0 never.
+    stale_reader_age: AtomicU64,
+    /// Seconds without a heartbeat after which a session is considered dead, 0 never.
+    session_timeout: AtomicU64,
+    stale_callback: Mutex<StaleLockCallback>,
+    wait_callback: Mutex<LockWaitCallback>,
+    /// Milliseconds an acquisition waits before it is reported to the wait callback.
+    wait_threshold: AtomicU64,
+    contended: AtomicU64,
+    timeouts: AtomicU64,
+    wait_nanos: AtomicU64,
+    native: Option<NativeLock>,
+    /// Milliseconds between two reads of the refresh thread.
+    refresh_interval: AtomicU64,
+    /// Lock calls of this process waiting for the lock.
+    waiters: AtomicU64,
+    /// Reads of the lock file, counted to tell whether an idle lock causes any IO.
+    state_reads: AtomicU64,
+    refresh: Mutex<RefreshState>,
+    refresh_wakeup: Condvar,
+    #[cfg(target_os = "linux")]
+    watcher: Option<LockWatcher>,
+}
+
+/// Counts a lock call as waiting until it is dropped, keeping the refresh thread running.
+struct Waiting<'a>(&'a Shared);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shared {
+    fn new(path: PathBuf, native: Option<NativeLock>) -> Self {
+        // a polled lock without a watcher still works, it just polls while waiting
+        #[cfg(target_os = "linux")]
+        let watcher = match native {
+            Some(_) => None,
+            None => LockWatcher::new(&path)
+                .inspect_err(|e| log::debug!("Not watching {}: {e}", path.display()))
+                .ok(),
+        };
+
+        Self {
+            path,
+            writer_mode: AtomicU64::new(LockMode::None as u64),
+            writer_present: AtomicU64::new(0),
+            writer_pid: AtomicU64::new(0),
+            writer_host: AtomicU64::new(0),
+            reader_counts: Default::default(),
+            process_reader_counts: Default::default(),
+            process_has_writer: AtomicU64::new(0),
+            stale_reader_age: AtomicU64::new(0),
+            session_timeout: AtomicU64::new(DEFAULT_SESSION_TIMEOUT.as_secs()),
+            stale_callback: Mutex::new(None),
+            wait_callback: Mutex::new(None),
+            wait_threshold: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            wait_nanos: AtomicU64::new(0),
+            native,
+            refresh_interval: AtomicU64::new(DEFAULT_REFRESH_INTERVAL.as_millis() as u64),
+            waiters: AtomicU64::new(0),
+            state_reads: AtomicU64::new(0),
+            refresh: Mutex::new(RefreshState::default()),
+            refresh_wakeup: Condvar::new(),
+            #[cfg(target_os = "linux")]
+            watcher,
+        }
+    }
+
+    /// Reads the lock file, rewriting it if it has an earlier format and resetting it if
+    /// it cannot be decoded rather than trusting whatever it contains.
+    fn load_state(&self) -> std::io::Result<LockState> {
+        self.state_reads.fetch_add(1, Ordering::SeqCst);
+
+        match RwLock::read_state(&self.path)? {
+            DecodedState::Current(state) => Ok(state),
+            DecodedState::Legacy(state) => {
+                RwLock::write_state(&self.path, &state)?;
+
+                Ok(state)
+            }
+            DecodedState::Corrupt(reason) => {
+                let state = LockState::default();
+                RwLock::write_state(&self.path, &state)?;
+                self.report(&StaleLock::Corrupt { reason });
+
+                Ok(state)
+            }
+        }
+    }
+
+    fn add_wait_time(&self, elapsed: Duration) {
+        self.wait_nanos.fetch_add(
+            elapsed.as_nanos().min(u64::MAX as u128) as u64,
+            Ordering::SeqCst,
+        );
+    }
+
+    fn report(&self, stale: &StaleLock) {
+        log::warn!("{stale} in {}", self.path.display());
+
+        if let Some(callback) = self.stale_callback.lock().unwrap().clone() {
+            callback(stale);
+        }
+    }
+
+    fn store_cache(&self, state: &LockState) {
+        self.writer_mode
+            .store(state.writer_mode as u64, Ordering::SeqCst);
+        self.writer_present
+            .store(state.writer_present as u64, Ordering::SeqCst);
+        self.writer_pid.store(state.writer_pid, Ordering::SeqCst);
+        self.writer_host.store(state.writer_host, Ordering::SeqCst);
+
+        for (cached, count) in self.reader_counts.iter().zip(state.reader_counts) {
+            cached.store(count, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether the cached state says this process holds the write lock.
+    fn writer_is_current(&self) -> bool {
+        self.writer_present.load(Ordering::SeqCst) != 0
+            && self.writer_pid.load(Ordering::SeqCst) == RwLock::current_pid()
+            && self.writer_host.load(Ordering::SeqCst) == host_id()
+    }
+
+    fn holding(&self) -> bool {
+        self.process_has_writer.load(Ordering::SeqCst) > 0
+            || self
+                .process_reader_counts
+                .iter()
+                .any(|count| count.load(Ordering::SeqCst) > 0)
+    }
+
+    fn waiting(self: &Arc<Self>) -> Waiting<'_> {
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        self.start_refresh();
+
+        Waiting(self)
+    }
+
+    /// Starts the refresh thread of a polled lock unless it is already running.
+    fn start_refresh(self: &Arc<Self>) {
+        if self.native.is_some() {
+            return;
+        }
+
+        let mut refresh = self.refresh.lock().unwrap();
+        if refresh.running || refresh.shutdown {
+            return;
+        }
+
+        // a thread that stopped because the lock was idle has nothing left to do
+        if let Some(handle) = refresh.handle.take() {
+            let _ = handle.join();
+        }
+
+        let shared = Arc::clone(self);
+        refresh.running = true;
+        refresh.handle = Some(thread::spawn(move || shared.refresh_loop()));
+    }
+
+    /// Keeps the cached state fresh and the heartbeat alive until the lock is neither
+    /// waited for nor held anymore.
+    fn refresh_loop(&self) {
+        let mut last_heartbeat: Option<Instant> = None;
+
+        loop {
+            {
+                let mut refresh = self.refresh.lock().unwrap();
+                if refresh.shutdown || (!self.holding() && self.waiters.load(Ordering::SeqCst) == 0)
+                {
+                    refresh.running = false;
+                    self.refresh_wakeup.notify_all();
+
+                    return;
+                }
+            }
+
+            let heartbeat = self.holding()
+                && last_heartbeat.is_none_or(|heartbeat| heartbeat.elapsed() >= HEARTBEAT_INTERVAL);
+            if heartbeat {
+                if let Err(e) = RwLock::write_heartbeat(&self.path) {
+                    log::warn!("Error writing lock heartbeat: {e}");
+                }
+
+                last_heartbeat = Some(Instant::now());
+            }
+
+            let state = if heartbeat {
+                self.refresh_session()
+            } else {
+                self.load_state()
+            };
+
+            match state {
+                Ok(state) => self.store_cache(&state),
+                Err(e) => log::warn!("Error in lock refresh thread: {e}"),
+            }
+
+            self.sleep_refresh();
+        }
+    }
+
+    /// Refreshes the heartbeat of the session of this process, if it has one. It is
+    /// covered by the checksum, so it takes a rewrite of the state like any other update.
+    fn refresh_session(&self) -> std::io::Result<LockState> {
+        let _guard = self.lock_state()?;
+
+        let mut state = self.load_state()?;
+        if let Some(session) = state.current_session() {
+            session.heartbeat = unix_now();
+            RwLock::write_state(&self.path, &state)?;
+        }
+
+        Ok(state)
+    }
+
+    /// Locks the `.state` file next to the lock file exclusively until the returned file
+    /// is dropped, so no two rewrites of the state, of any process or thread, interleave.
+    /// The lock file itself is replaced by every write and cannot hold the lock.
+    fn lock_state(&self) -> std::io::Result<File> {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(".state");
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(PathBuf::from(path))?;
+        file.lock()?;
+
+        Ok(file)
+    }
+
+    /// Sleeps for the refresh interval, or with a watcher until the lock file changes
+    /// but at least as often as the heartbeat has to be refreshed.
+    fn sleep_refresh(&self) {
+        let interval = Duration::from_millis(self.refresh_interval.load(Ordering::SeqCst));
+
+        #[cfg(target_os = "linux")]
+        if let Some(watcher) = &self.watcher {
+            watcher.wait(interval.max(HEARTBEAT_INTERVAL));
+            return;
+        }
+
+        let refresh = self.refresh.lock().unwrap();
+        if !refresh.shutdown {
+            let _ = self.refresh_wakeup.wait_timeout(refresh, interval);
+        }
+    }
+
+    /// Wakes the refresh thread and waits a moment for it to exit. A thread stuck in IO,
+    /// like on a hung network file system, is left behind instead of blocking the drop.
+    fn stop_refresh(&self) {
+        let mut refresh = self.refresh.lock().unwrap();
+        refresh.shutdown = true;
+        self.refresh_wakeup.notify_all();
+
+        #[cfg(target_os = "linux")]
+        if let Some(watcher) = &self.watcher {
+            watcher.wake();
+        }
+
+        let deadline = Instant::now() + REFRESH_SHUTDOWN_TIMEOUT;
+        while refresh.running {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+
+            refresh = self
+                .refresh_wakeup
+                .wait_timeout(refresh, remaining)
+                .unwrap()
+                .0;
+        }
+
+        let handle = refresh.handle.take();
+        let stopped = !refresh.running;
+        drop(refresh);
+
+        match handle {
+            Some(handle) if stopped => {
+                let _ = handle.join();
+            }
+            Some(_) => log::warn!(
+                "Lock refresh thread of {} did not stop, leaving it behind",
+                self.path.display()
+            ),
+            None => {}
+        }
+    }
+}
+
+/// The files of the native backend, indexed like the reader counts with the file only
+/// writers lock in place of `LockMode::None`. Readers lock the file of their mode shared,
+/// writers lock the writer file and the file of the other mode exclusively, so readers
+/// of their own mode can stay. The pid and mode of the writer are kept in an owner file
+/// next to them, as locked files cannot be read on every platform.
+struct NativeLock {
+    paths: [PathBuf; 3],
+    files: [File; 3],
+    owner_path: PathBuf,
+    held: Mutex<NativeHeld>,
+}
+
+/// Which of the files of a `NativeLock` this process has locked, a file must not be
+/// locked twice through the same handle.
+#[derive(Default)]
+struct NativeHeld {
+    shared: [bool; 3],
+    exclusive: Option<LockMode>,
 }
 
-#[derive(Debug, Clone)]
+impl NativeLock {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let native_path = |suffix: &str| {
+            let mut path = path.as_os_str().to_owned();
+            path.push(format!(".{suffix}"));
+
+            PathBuf::from(path)
+        };
+
+        let paths = ["writer", "destructive", "non-destructive"].map(native_path);
+        let open = |path: &PathBuf| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+        };
+
+        Ok(Self {
+            files: [open(&paths[0])?, open(&paths[1])?, open(&paths[2])?],
+            paths,
+            owner_path: native_path("owner"),
+            held: Mutex::new(NativeHeld::default()),
+        })
+    }
+
+    /// Locks `file` without waiting, false if another handle holds a conflicting lock.
+    fn try_lock(file: &File, shared: bool) -> std::io::Result<bool> {
+        let result = if shared {
+            file.try_lock_shared()
+        } else {
+            file.try_lock()
+        };
+
+        match result {
+            Ok(()) => Ok(true),
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    fn try_read(&self, mode: LockMode) -> std::io::Result<bool> {
+        let mut held = self.held.lock().unwrap();
+
+        // the write lock of this process already keeps out everyone the reader has to
+        if held.shared[mode as usize] || held.exclusive.is_some() {
+            return Ok(true);
+        }
+
+        if !Self::try_lock(&self.files[mode as usize], true)? {
+            return Ok(false);
+        }
+
+        held.shared[mode as usize] = true;
+
+        Ok(true)
+    }
+
+    fn release_read(&self, mode: LockMode) -> std::io::Result<()> {
+        let mut held = self.held.lock().unwrap();
+
+        if std::mem::take(&mut held.shared[mode as usize]) {
+            self.files[mode as usize].unlock()?;
+        }
+
+        Ok(())
+    }
+
+    fn try_write(&self, mode: LockMode) -> std::io::Result<bool> {
+        let mut held = self.held.lock().unwrap();
+        let other = mode.other() as usize;
+
+        if held.exclusive.is_some() {
+            return Ok(true);
+        }
+
+        // readers of this process keep writers of the other mode out like any others
+        if held.shared[other] || !Self::try_lock(&self.files[0], false)? {
+            return Ok(false);
+        }
+
+        let locked = Self::try_lock(&self.files[other], false).and_then(|locked| {
+            if locked && let Err(e) = self.write_owner(mode) {
+                self.files[other].unlock()?;
+                return Err(e);
+            }
+
+            Ok(locked)
+        });
+        if !matches!(locked, Ok(true)) {
+            self.files[0].unlock()?;
+            return locked;
+        }
+
+        held.exclusive = Some(mode);
+
+        Ok(true)
+    }
+
+    fn release_write(&self) -> std::io::Result<()> {
+        let mut held = self.held.lock().unwrap();
+
+        if let Some(mode) = held.exclusive.take() {
+            self.files[mode.other() as usize].unlock()?;
+            self.files[0].unlock()?;
+        }
+
+        Ok(())
+    }
+
+    fn write_owner(&self, mode: LockMode) -> std::io::Result<()> {
+        AtomicFile::new(&self.owner_path, AllowOverwrite).write(|f| {
+            f.write_all(&RwLock::current_pid().to_le_bytes())?;
+            f.write_all(&[mode.as_u8()])
+        })?;
+
+        Ok(())
+    }
+
+    /// Probes the files through handles of their own, which also see the locks of
+    /// this process. A probe holds a lock for a moment, so it may make a `try_` call
+    /// of another process fail.
+    fn status(&self) -> std::io::Result<LockStatus> {
+        let probe = |index: usize| -> std::io::Result<bool> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&self.paths[index])?;
+            let free = Self::try_lock(&file, false)?;
+            if free {
+                file.unlock()?;
+            }
+
+            Ok(!free)
+        };
+
+        let mut status = LockStatus::default();
+
+        if probe(0)? {
+            let owner = std::fs::read(&self.owner_path)?;
+            if owner.len() < 9 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Truncated lock owner file {}", self.owner_path.display()),
+                ));
+            }
+
+            status.writer_pid = Some(u64::from_le_bytes(owner[..8].try_into().unwrap()));
+            status.writer_mode = Some(LockMode::from_u8(owner[8]));
+        }
+
+        for mode in [LockMode::Destructive, LockMode::NonDestructive] {
+            // a writer of the other mode holds the file itself and keeps readers out
+            if status.writer_mode.is_some_and(|writer| writer != mode) {
+                continue;
+            }
+
+            status.reader_counts[mode as usize] = probe(mode as usize)? as u64;
+        }
+
+        Ok(status)
+    }
+}
+
+#[derive(Clone)]
 pub struct RwLock {
-    path: Arc<String>,
-    writer_mode: Arc<AtomicU64>,
-    writer_present: Arc<AtomicU64>,
-    writer_pid: Arc<AtomicU64>,
-    reader_counts: Arc<Vec<AtomicU64>>,
-    refresh: Arc<Mutex<Option<JoinHandle<()>>>>,
-    running: Arc<AtomicU64>,
-    process_reader_counts: Arc<Vec<AtomicU64>>,
-    process_has_writer: Arc<AtomicU64>,
-}
-
-#[derive(Debug, Clone)]
+    shared: Arc<Shared>,
+    /// Only held so the refresh thread stops with the last clone.
+    _owner: Arc<RefreshOwner>,
+}
+
+impl std::fmt::Debug for RwLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RwLock")
+            .field("path", &self.shared.path)
+            .field("backend", &self.backend())
+            .field("writer_mode", &self.shared.writer_mode)
+            .field("writer_present", &self.shared.writer_present)
+            .field("writer_pid", &self.shared.writer_pid)
+            .field("reader_counts", &self.shared.reader_counts)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 struct LockState {
     writer_mode: u8,
     writer_present: u8,
     writer_pid: u64,
+    /// `host_id` of the writer, 0 if there is none.
+    writer_host: u64,
+    /// Readers of all processes, including those counted before sessions existed.
     reader_counts: [u64; 3],
+    /// Seconds since the unix epoch a holder of the lock was last known to be alive,
+    /// 0 for lock files written before the heartbeat existed.
+    heartbeat: u64,
+    sessions: Vec<Session>,
+}
+
+/// What `RwLock::read_state` found in the lock file.
+enum DecodedState {
+    Current(LockState),
+    /// A lock file of an earlier format, rewritten once it was read.
+    Legacy(LockState),
+    /// Not a lock file, or one that was damaged, reinitialized to an empty state.
+    Corrupt(&'static str),
+}
+
+/// The readers of one process, identified by its pid and start time so a reused pid
+/// is not mistaken for the process that left the readers behind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Session {
+    pid: u64,
+    host: u64,
+    /// Start time as reported by the system, 0 where it is not known.
+    start_time: u64,
+    reader_counts: [u64; 3],
+    /// Seconds since the unix epoch the process last refreshed the session, 0 for
+    /// sessions of lock files written before sessions had a heartbeat.
+    heartbeat: u64,
+}
+
+impl Session {
+    fn current() -> Self {
+        Self {
+            pid: RwLock::current_pid(),
+            host: host_id(),
+            start_time: current_start_time(),
+            reader_counts: [0; 3],
+            heartbeat: unix_now(),
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        self.pid == RwLock::current_pid()
+            && self.host == host_id()
+            && self.start_time == current_start_time()
+    }
+
+    /// Whether the process of the session still exists. Processes of other hosts cannot
+    /// be checked and count as alive, only their heartbeat tells when they are gone.
+    fn alive(&self) -> bool {
+        if self.host != host_id() {
+            return true;
+        }
+
+        process_alive(self.pid)
+            && (self.start_time == 0
+                || process_start_time(self.pid)
+                    .is_none_or(|start_time| start_time == self.start_time))
+    }
+
+    /// How long ago the session was refreshed, `None` if it never was.
+    fn heartbeat_age(&self) -> Option<Duration> {
+        (self.heartbeat != 0)
+            .then(|| Duration::from_secs(unix_now().saturating_sub(self.heartbeat)))
+    }
+}
+
+impl LockState {
+    /// Whether the write lock is held by this process.
+    fn writer_is_current(&self) -> bool {
+        self.writer_present != 0
+            && self.writer_pid == RwLock::current_pid()
+            && self.writer_host == host_id()
+    }
+
+    fn set_writer(&mut self, mode: Option<LockMode>) {
+        match mode {
+            Some(mode) => {
+                self.writer_present = 1;
+                self.writer_mode = mode.as_u8();
+                self.writer_pid = RwLock::current_pid();
+                self.writer_host = host_id();
+            }
+            None => {
+                self.writer_present = 0;
+                self.writer_mode = LockMode::None.as_u8();
+                self.writer_pid = 0;
+                self.writer_host = 0;
+            }
+        }
+    }
+
+    /// How long ago the heartbeat was refreshed, `None` if it never was.
+    fn heartbeat_age(&self) -> Option<Duration> {
+        (self.heartbeat != 0)
+            .then(|| Duration::from_secs(unix_now().saturating_sub(self.heartbeat)))
+    }
+
+    fn current_session(&mut self) -> Option<&mut Session> {
+        self.sessions
+            .iter_mut()
+            .find(|session| session.is_current())
+    }
+
+    fn add_reader(&mut self, mode: LockMode) {
+        let index = match self.sessions.iter().position(Session::is_current) {
+            Some(index) => index,
+            None => {
+                self.sessions.push(Session::current());
+                self.sessions.len() - 1
+            }
+        };
+
+        let session = &mut self.sessions[index];
+        session.reader_counts[mode as usize] += 1;
+        session.heartbeat = unix_now();
+        self.reader_counts[mode as usize] += 1;
+    }
+
+    /// Releases a reader of this process. If its session was dropped for going without
+    /// a heartbeat, its readers were already subtracted and the totals are left alone.
+    fn remove_reader(&mut self, mode: LockMode) {
+        let Some(index) = self.sessions.iter().position(Session::is_current) else {
+            return;
+        };
+
+        let session = &mut self.sessions[index];
+        if session.reader_counts[mode as usize] == 0 {
+            return;
+        }
+
+        session.reader_counts[mode as usize] -= 1;
+        if session.reader_counts == [0; 3] {
+            self.sessions.remove(index);
+        }
+
+        self.reader_counts[mode as usize] = self.reader_counts[mode as usize].saturating_sub(1);
+    }
+
+    /// The sessions of processes that no longer exist, or that did not refresh their
+    /// heartbeat for `timeout` if it is not zero, as stale locks to recover.
+    fn dead_sessions(&self, timeout: Duration) -> impl Iterator<Item = StaleLock> {
+        self.sessions.iter().filter_map(move |session| {
+            if session.is_current() {
+                return None;
+            }
+
+            let age = session.heartbeat_age();
+            if !session.alive() {
+                Some(StaleLock::Session {
+                    pid: session.pid,
+                    host: session.host,
+                    start_time: session.start_time,
+                    counts: session.reader_counts,
+                    age: None,
+                })
+            } else if !timeout.is_zero() && age.is_some_and(|age| age >= timeout) {
+                Some(StaleLock::Session {
+                    pid: session.pid,
+                    host: session.host,
+                    start_time: session.start_time,
+                    counts: session.reader_counts,
+                    age,
+                })
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Removes the session of `pid` on `host` started at `start_time` together with its readers.
+    fn remove_session(&mut self, pid: u64, host: u64, start_time: u64) {
+        self.sessions.retain(|session| {
+            if (session.pid, session.host, session.start_time) != (pid, host, start_time) {
+                return true;
+            }
+
+            for (total, count) in self.reader_counts.iter_mut().zip(session.reader_counts) {
+                *total = total.saturating_sub(count);
+            }
+
+            false
+        });
+    }
+}
+
+/// When the process with `pid` started, in clock ticks since boot, `None` if unknown.
+#[cfg(target_os = "linux")]
+fn process_start_time(pid: u64) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+
+    // the name in parentheses may contain spaces, the start time is the 22nd field
+    stat.rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(19)?
+        .parse()
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_start_time(_pid: u64) -> Option<u64> {
+    None
+}
+
+fn current_start_time() -> u64 {
+    static START_TIME: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+
+    *START_TIME.get_or_init(|| process_start_time(RwLock::current_pid()).unwrap_or(0))
+}
+
+/// Identifies this host in the lock file, so processes of different hosts sharing a
+/// repository on a network file system are not mistaken for one another because their
+/// pids collide. It is a hash of the hostname, which has to differ between such hosts.
+/// A boot id is left out on purpose, a lock left behind before a reboot would look like
+/// it was held by another host and could never be recovered.
+pub fn host_id() -> u64 {
+    static HOST_ID: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+
+    *HOST_ID.get_or_init(|| {
+        let mut hasher = Blake2b::<U32>::new();
+        hasher.update(hostname());
+        let hash = hasher.finalize();
+
+        // 0 is written for a writer that is not present
+        u64::from_le_bytes(hash[..8].try_into().unwrap()).max(1)
+    })
+}
+
+#[cfg(unix)]
+fn hostname() -> Vec<u8> {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+        return Vec::new();
+    }
+
+    let length = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    buffer[..length].to_vec()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Vec<u8> {
+    std::env::var("COMPUTERNAME")
+        .unwrap_or_default()
+        .into_bytes()
+}
+
+/// Reads the little-endian fields of a lock file one after another, the caller checks
+/// the length beforehand.
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn skip(&mut self, length: usize) {
+        self.0 = &self.0[length..];
+    }
+
+    fn u8(&mut self) -> u8 {
+        let value = self.0[0];
+        self.skip(1);
+
+        value
+    }
+
+    fn u64(&mut self) -> u64 {
+        let value = u64::from_le_bytes(self.0[..8].try_into().unwrap());
+        self.skip(8);
+
+        value
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+/// Whether a process with `pid` exists. Processes that cannot be checked count as alive.
+#[cfg(unix)]
+fn process_alive(pid: u64) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 {
+        return false;
+    }
+
+    // signal 0 only checks for existence, EPERM means it exists but belongs to someone else
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+
+    std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// Whether a process with `pid` exists. Processes that cannot be checked count as alive.
+#[cfg(windows)]
+fn process_alive(pid: u64) -> bool {
+    use std::ffi::c_void;
+
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+    const ERROR_INVALID_PARAMETER: u32 = 87;
+    const STILL_ACTIVE: u32 = 259;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn OpenProcess(access: u32, inherit_handle: i32, pid: u32) -> *mut c_void;
+        fn GetExitCodeProcess(process: *mut c_void, exit_code: *mut u32) -> i32;
+        fn CloseHandle(handle: *mut c_void) -> i32;
+        fn GetLastError() -> u32;
+    }
+
+    let Ok(pid) = u32::try_from(pid) else {
+        return false;
+    };
+
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process.is_null() {
+            return GetLastError() != ERROR_INVALID_PARAMETER;
+        }
+
+        let mut exit_code = 0;
+        let alive = GetExitCodeProcess(process, &mut exit_code) == 0 || exit_code == STILL_ACTIVE;
+        CloseHandle(process);
+
+        alive
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn process_alive(_pid: u64) -> bool {
+    true
 }
 
 impl RwLock {
+    /// Opens the lock at `path` with the given backend, `new` uses `LockBackend::Polled`.
+    pub fn with_backend<P: AsRef<Path>>(path: P, backend: LockBackend) -> std::io::Result<Self> {
+        match backend {
+            LockBackend::Polled => Self::new(path),
+            LockBackend::Native => Ok(Self::from_shared(Shared::new(
+                path.as_ref().to_path_buf(),
+                Some(NativeLock::open(path.as_ref())?),
+            ))),
+        }
+    }
+
     pub fn new<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
-        let path_str = path.as_ref().to_string_lossy().to_string();
-        let path_arc = Arc::new(path_str.clone());
+        let shared = Shared::new(path.as_ref().to_path_buf(), None);
 
         let state = if !path.as_ref().exists() {
-            let initial_state = LockState {
-                writer_mode: LockMode::None.as_u8(),
-                writer_present: 0,
-                writer_pid: 0,
-                reader_counts: [0; 3],
-            };
-            Self::write_state(&path_str, &initial_state)?;
+            let initial_state = LockState::default();
+            Self::write_state(&shared.path, &initial_state)?;
             initial_state
         } else {
-            Self::read_state(&path_str)?
+            shared.load_state()?
         };
 
-        let reader_counts = Arc::new(
-            (0..3)
-                .map(|i| AtomicU64::new(state.reader_counts[i]))
-                .collect::<Vec<_>>(),
+        shared.store_cache(&state);
+
+        Ok(Self::from_shared(shared))
+    }
+
+    fn from_shared(shared: Shared) -> Self {
+        let shared = Arc::new(shared);
+
+        Self {
+            _owner: Arc::new(RefreshOwner(Arc::clone(&shared))),
+            shared,
+        }
+    }
+
+    /// Sets how often the refresh thread of a polled lock reads the lock file while the
+    /// lock is waited for or held, 100ms by default. On linux the thread is woken when
+    /// the lock file changes instead and reads it on its own only about once a second.
+    /// The thread does not run at all while the lock is idle.
+    pub fn set_refresh_interval(&self, interval: Duration) {
+        self.shared.refresh_interval.store(
+            interval.as_millis().clamp(1, u64::MAX as u128) as u64,
+            Ordering::SeqCst,
         );
+    }
 
-        let writer_mode = Arc::new(AtomicU64::new(state.writer_mode as u64));
-        let writer_present = Arc::new(AtomicU64::new(state.writer_present as u64));
-        let writer_pid = Arc::new(AtomicU64::new(state.writer_pid));
-
-        let process_reader_counts = Arc::new((0..3).map(|_| AtomicU64::new(0)).collect::<Vec<_>>());
-        let process_has_writer = Arc::new(AtomicU64::new(0));
-
-        let running = Arc::new(AtomicU64::new(1));
-        let running_clone = Arc::clone(&running);
-        let path_clone = Arc::clone(&path_arc);
-        let writer_mode_clone = Arc::clone(&writer_mode);
-        let writer_present_clone = Arc::clone(&writer_present);
-        let writer_pid_clone = Arc::clone(&writer_pid);
-        let reader_counts_clone = Arc::clone(&reader_counts);
-
-        let refresh = thread::spawn(move || {
-            while running_clone.load(Ordering::SeqCst) == 1 {
-                thread::sleep(Duration::from_millis(100));
-
-                match Self::read_state(&path_clone) {
-                    Ok(state) => {
-                        writer_mode_clone.store(state.writer_mode as u64, Ordering::SeqCst);
-                        writer_present_clone.store(state.writer_present as u64, Ordering::SeqCst);
-                        writer_pid_clone.store(state.writer_pid, Ordering::SeqCst);
-
-                        for (i, count) in state.reader_counts.iter().enumerate() {
-                            if i < reader_counts_clone.len() {
-                                reader_counts_clone[i].store(*count, Ordering::SeqCst);
-                            }
-                        }
+    /// How often this lock read the lock file, including the reads of its refresh thread,
+    /// to check that an idle lock causes no IO.
+    pub fn state_reads(&self) -> u64 {
+        self.shared.state_reads.load(Ordering::SeqCst)
+    }
+
+    /// Lets reader counts be cleared once no process refreshed the heartbeat for `age`,
+    /// which happens when readers were killed without releasing the lock. Every process
+    /// holding the lock refreshes the heartbeat about once a second. `None`, the default,
+    /// keeps reader counts until they are released.
+    pub fn set_stale_reader_age(&self, age: Option<Duration>) {
+        self.shared
+            .stale_reader_age
+            .store(age.map_or(0, |age| age.as_secs().max(1)), Ordering::SeqCst);
+    }
+
+    /// Sets how long the session of another process may go without a heartbeat before
+    /// its readers are dropped, 30s by default and at least 5s. Every process holding
+    /// readers refreshes its session about once a second from the refresh thread, so
+    /// the timeout has to cover the longest time such a process may not get to run,
+    /// like while it is stopped in a debugger or its system is suspended. A process
+    /// that comes back after that has lost its readers without noticing. `None` only
+    /// drops the sessions of processes that no longer exist.
+    pub fn set_session_timeout(&self, timeout: Option<Duration>) {
+        self.shared.session_timeout.store(
+            timeout.map_or(0, |timeout| timeout.max(MIN_SESSION_TIMEOUT).as_secs()),
+            Ordering::SeqCst,
+        );
+    }
+
+    pub fn backend(&self) -> LockBackend {
+        if self.shared.native.is_some() {
+            LockBackend::Native
+        } else {
+            LockBackend::Polled
+        }
+    }
+
+    /// Sets the callback receiving every stale lock recovered by this lock.
+    pub fn set_stale_callback(&self, callback: StaleLockCallback) {
+        *self.shared.stale_callback.lock().unwrap() = callback;
+    }
+
+    /// Clears the write lock and the readers of processes of this host that no longer
+    /// exist, sessions without a heartbeat for the session timeout and, if a stale
+    /// reader age is set, reader counts whose heartbeat is older than that. A write lock
+    /// of another host is never cleared, its process cannot be checked. Blocked lock calls
+    /// do this on their own, returns whether anything was recovered. The native backend
+    /// never has anything to recover, the system releases the locks of exited processes.
+    pub fn recover_stale(&self) -> std::io::Result<bool> {
+        if self.shared.native.is_some() {
+            return Ok(false);
+        }
+
+        let stale_reader_age =
+            Duration::from_secs(self.shared.stale_reader_age.load(Ordering::SeqCst));
+        let session_timeout =
+            Duration::from_secs(self.shared.session_timeout.load(Ordering::SeqCst));
+        let process_readers = self
+            .shared
+            .process_reader_counts
+            .iter()
+            .any(|count| count.load(Ordering::SeqCst) > 0);
+
+        let find_stale = |state: &LockState| {
+            let mut stale = Vec::new();
+
+            // the pids of other hosts cannot be checked
+            if state.writer_present != 0
+                && !state.writer_is_current()
+                && state.writer_host == host_id()
+                && !process_alive(state.writer_pid)
+            {
+                stale.push(StaleLock::Writer {
+                    pid: state.writer_pid,
+                    mode: LockMode::from_u8(state.writer_mode),
+                });
+            }
+
+            stale.extend(state.dead_sessions(session_timeout));
+
+            if !stale_reader_age.is_zero()
+                && !process_readers
+                && state.reader_counts.iter().any(|count| *count > 0)
+                && state
+                    .heartbeat_age()
+                    .is_none_or(|age| age >= stale_reader_age)
+            {
+                stale.push(StaleLock::Readers {
+                    counts: state.reader_counts,
+                    age: state.heartbeat_age(),
+                });
+            }
+
+            stale
+        };
+
+        if find_stale(&self.shared.load_state()?).is_empty() {
+            return Ok(false);
+        }
+
+        let mut recovered = Vec::new();
+        self.update_state(|mut state| {
+            recovered = find_stale(&state);
+
+            for stale in &recovered {
+                match stale {
+                    StaleLock::Writer { .. } => {
+                        state.set_writer(None);
                     }
-                    Err(e) => {
-                        eprintln!("Error in refresh thread: {e}");
+                    StaleLock::Readers { .. } => {
+                        state.reader_counts = [0; 3];
+                        state.sessions.clear();
                     }
+                    StaleLock::Session {
+                        pid,
+                        host,
+                        start_time,
+                        ..
+                    } => state.remove_session(*pid, *host, *start_time),
+                    StaleLock::Corrupt { .. } => {}
                 }
             }
-        });
 
-        Ok(Self {
-            path: path_arc,
+            state
+        })?;
+
+        for stale in &recovered {
+            self.shared.report(stale);
+        }
+
+        Ok(!recovered.is_empty())
+    }
+
+    fn read_state(path: &Path) -> std::io::Result<DecodedState> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+
+        if !bytes.starts_with(LOCK_MAGIC) {
+            return Ok(Self::decode_legacy_state(&bytes).map_or(
+                DecodedState::Corrupt("unknown format"),
+                DecodedState::Legacy,
+            ));
+        }
+
+        if bytes.len() < STATE_OFFSET {
+            return Ok(DecodedState::Corrupt("truncated"));
+        }
+
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        let checksum = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        let heartbeat = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+
+        if version > LOCK_FORMAT_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Lock file has version {version}, this version of ddup-bak supports up to {LOCK_FORMAT_VERSION}"
+                ),
+            ));
+        }
+
+        let (state_length, session_length) = Self::layout(version);
+        let payload = &bytes[STATE_OFFSET..];
+        if payload.len() < state_length {
+            return Ok(DecodedState::Corrupt("truncated"));
+        }
+
+        // the number of sessions is the last field of the state, before 4 bytes of padding
+        let session_count = u32::from_le_bytes(
+            payload[state_length - 8..state_length - 4]
+                .try_into()
+                .unwrap(),
+        );
+        if payload.len() != state_length + session_count as usize * session_length {
+            return Ok(DecodedState::Corrupt("truncated"));
+        }
+
+        if crc32fast::hash(payload) != checksum {
+            return Ok(DecodedState::Corrupt("checksum mismatch"));
+        }
+
+        let mut fields = Fields(payload);
+        let writer_mode = fields.u8();
+        let writer_present = fields.u8();
+        fields.skip(6);
+
+        let writer_pid = fields.u64();
+        let writer_host = if version >= 5 {
+            fields.u64()
+        } else {
+            host_id()
+        };
+        let reader_counts = [fields.u64(), fields.u64(), fields.u64()];
+        fields.skip(8);
+
+        let state = LockState {
             writer_mode,
             writer_present,
             writer_pid,
+            writer_host,
             reader_counts,
-            refresh: Arc::new(Mutex::new(Some(refresh))),
-            running,
-            process_reader_counts,
-            process_has_writer,
+            heartbeat,
+            sessions: Self::decode_sessions(fields.0, version),
+        };
+
+        Ok(if version < LOCK_FORMAT_VERSION {
+            DecodedState::Legacy(state)
+        } else {
+            DecodedState::Current(state)
         })
     }
 
-    fn read_state(path: &str) -> std::io::Result<LockState> {
-        let mut file = File::open(path)?;
-        let mut reader_counts = [0u64; 3];
+    /// The length of the state and of a session in a lock file of `version`.
+    fn layout(version: u32) -> (usize, usize) {
+        match version {
+            ..=3 => (48, 40),
+            4 => (48, 48),
+            _ => (56, 56),
+        }
+    }
 
-        file.seek(SeekFrom::Start(0))?;
-        let mut writer_mode_buf = [0; 1];
-        file.read_exact(&mut writer_mode_buf)?;
-        let writer_mode = writer_mode_buf[0];
+    /// Decodes a lock file written before it had a header, `None` if `bytes` are not one.
+    fn decode_legacy_state(bytes: &[u8]) -> Option<LockState> {
+        const LEGACY_LENGTH: usize = 56;
 
-        file.seek(SeekFrom::Current(7))?;
+        if bytes.len() < LEGACY_LENGTH {
+            return None;
+        }
 
-        let mut writer_present_buf = [0; 1];
-        file.read_exact(&mut writer_present_buf)?;
-        let writer_present = writer_present_buf[0];
+        let sessions = if bytes.len() == LEGACY_LENGTH {
+            &[][..]
+        } else if bytes.len() >= LEGACY_LENGTH + 12
+            && &bytes[LEGACY_LENGTH..LEGACY_LENGTH + 4] == LEGACY_SESSIONS_MAGIC
+        {
+            let version = u32::from_le_bytes(bytes[60..64].try_into().unwrap());
+            let session_count = u32::from_le_bytes(bytes[64..68].try_into().unwrap());
+            let sessions = &bytes[68..];
 
-        file.seek(SeekFrom::Current(7))?;
+            if version != 2 || sessions.len() != session_count as usize * Self::layout(2).1 {
+                return None;
+            }
 
-        let mut writer_pid_buf = [0; 8];
-        file.read_exact(&mut writer_pid_buf)?;
-        let writer_pid = u64::from_le_bytes(writer_pid_buf);
+            sessions
+        } else {
+            return None;
+        };
 
-        for reader_count in reader_counts.iter_mut() {
-            let mut count_buf = [0; 8];
-            if file.read_exact(&mut count_buf).is_ok() {
-                *reader_count = u64::from_le_bytes(count_buf);
-            } else {
-                break;
-            }
+        if bytes[0] > LockMode::NonDestructive.as_u8() || bytes[8] > 1 {
+            return None;
         }
 
-        Ok(LockState {
-            writer_mode,
-            writer_present,
-            writer_pid,
-            reader_counts,
+        let field =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+
+        Some(LockState {
+            writer_mode: bytes[0],
+            writer_present: bytes[8],
+            writer_pid: field(16),
+            writer_host: host_id(),
+            reader_counts: [field(24), field(32), field(40)],
+            heartbeat: field(48),
+            sessions: Self::decode_sessions(sessions, 2),
         })
     }
 
-    fn write_state(path: &str, state: &LockState) -> std::io::Result<()> {
+    /// Decodes the sessions of a lock file of `version`. Lock files written before they
+    /// identified the host were only used by this one, as far as it can be told.
+    fn decode_sessions(bytes: &[u8], version: u32) -> Vec<Session> {
+        bytes
+            .chunks_exact(Self::layout(version).1)
+            .map(|session| {
+                let mut fields = Fields(session);
+
+                Session {
+                    pid: fields.u64(),
+                    host: if version >= 5 {
+                        fields.u64()
+                    } else {
+                        host_id()
+                    },
+                    start_time: fields.u64(),
+                    reader_counts: [fields.u64(), fields.u64(), fields.u64()],
+                    heartbeat: if version >= 4 { fields.u64() } else { 0 },
+                }
+            })
+            .collect()
+    }
+
+    /// Refreshes only the global heartbeat in place, without taking the state lock. It
+    /// never overwrites the state, but a heartbeat written while another process replaces
+    /// the lock file lands in the replaced file and is lost until the next one. Session
+    /// heartbeats are part of the state and written by `Shared::refresh_session`.
+    fn write_heartbeat(path: &Path) -> std::io::Result<()> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+
+        file.seek(SeekFrom::Start(HEARTBEAT_OFFSET))?;
+        file.write_all(&unix_now().to_le_bytes())
+    }
+
+    fn write_state(path: &Path, state: &LockState) -> std::io::Result<()> {
         let atomic_file = AtomicFile::new(path, AllowOverwrite);
 
-        atomic_file.write(|f| {
-            f.seek(SeekFrom::Start(0))?;
+        let (state_length, session_length) = Self::layout(LOCK_FORMAT_VERSION);
+        let mut payload = Vec::with_capacity(state_length + state.sessions.len() * session_length);
+        payload.extend_from_slice(&[state.writer_mode, state.writer_present]);
+        payload.extend_from_slice(&[0; 6]); // Padding
+        payload.extend_from_slice(&state.writer_pid.to_le_bytes());
+        payload.extend_from_slice(&state.writer_host.to_le_bytes());
 
-            f.write_all(&[state.writer_mode])?;
-            f.write_all(&[0; 7])?; // Padding
+        for count in &state.reader_counts {
+            payload.extend_from_slice(&count.to_le_bytes());
+        }
 
-            f.write_all(&[state.writer_present])?;
-            f.write_all(&[0; 7])?; // Padding
+        payload.extend_from_slice(&(state.sessions.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&[0; 4]); // Padding
 
-            f.write_all(&state.writer_pid.to_le_bytes())?;
+        for session in &state.sessions {
+            payload.extend_from_slice(&session.pid.to_le_bytes());
+            payload.extend_from_slice(&session.host.to_le_bytes());
+            payload.extend_from_slice(&session.start_time.to_le_bytes());
 
-            for count in &state.reader_counts {
-                f.write_all(&count.to_le_bytes())?;
+            for count in &session.reader_counts {
+                payload.extend_from_slice(&count.to_le_bytes());
             }
 
-            Ok(())
+            payload.extend_from_slice(&session.heartbeat.to_le_bytes());
+        }
+
+        atomic_file.write(|f| {
+            f.write_all(LOCK_MAGIC)?;
+            f.write_all(&LOCK_FORMAT_VERSION.to_le_bytes())?;
+            f.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+            f.write_all(&state.heartbeat.to_le_bytes())?;
+            f.write_all(&payload)
         })?;
 
         Ok(())
@@ -195,43 +1618,14 @@ impl RwLock {
     where
         F: FnOnce(LockState) -> LockState,
     {
-        let atomic_file = AtomicFile::new(&*self.path, AllowOverwrite);
+        let _guard = self.shared.lock_state()?;
 
-        let current_state = Self::read_state(&self.path)?;
+        let current_state = self.shared.load_state()?;
         let new_state = update_fn(current_state);
 
-        self.writer_mode
-            .store(new_state.writer_mode as u64, Ordering::SeqCst);
-        self.writer_present
-            .store(new_state.writer_present as u64, Ordering::SeqCst);
-        self.writer_pid
-            .store(new_state.writer_pid, Ordering::SeqCst);
-
-        for (i, count) in new_state.reader_counts.iter().enumerate() {
-            if i < self.reader_counts.len() {
-                self.reader_counts[i].store(*count, Ordering::SeqCst);
-            }
-        }
-
-        atomic_file.write(|f| {
-            f.seek(SeekFrom::Start(0))?;
-
-            f.write_all(&[new_state.writer_mode])?;
-            f.write_all(&[0; 7])?;
-
-            f.write_all(&[new_state.writer_present])?;
-            f.write_all(&[0; 7])?;
-
-            f.write_all(&new_state.writer_pid.to_le_bytes())?;
+        self.shared.store_cache(&new_state);
 
-            for count in &new_state.reader_counts {
-                f.write_all(&count.to_le_bytes())?;
-            }
-
-            Ok(())
-        })?;
-
-        Ok(())
+        Self::write_state(&self.shared.path, &new_state)
     }
 
     fn current_pid() -> u64 {
@@ -239,10 +1633,20 @@ impl RwLock {
     }
 
     fn process_owns_writer(&self) -> bool {
-        self.process_has_writer.load(Ordering::SeqCst) > 0
+        self.shared.process_has_writer.load(Ordering::SeqCst) > 0
     }
 
     pub fn read_lock(&self, mode: LockMode) -> std::io::Result<ReadGuard> {
+        self.read_lock_timeout(mode, Duration::MAX)
+    }
+
+    /// Like `read_lock`, but fails with a `WouldBlock` error naming the holder of the
+    /// lock if it could not be acquired within `timeout`.
+    pub fn read_lock_timeout(
+        &self,
+        mode: LockMode,
+        timeout: Duration,
+    ) -> std::io::Result<ReadGuard> {
         if mode == LockMode::None {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
@@ -251,50 +1655,76 @@ impl RwLock {
         }
 
         if self.process_owns_writer() {
-            self.process_reader_counts[mode as usize].fetch_add(1, Ordering::SeqCst);
+            self.shared.process_reader_counts[mode as usize].fetch_add(1, Ordering::SeqCst);
+
+            return Ok(ReadGuard {
+                lock: self.clone(),
+                mode,
+                active: true,
+                counted: false,
+            });
+        }
+
+        let mut acquisition = Acquisition::new(mode, false, timeout);
+
+        if let Some(native) = &self.shared.native {
+            while !native.try_read(mode)? {
+                self.wait(&mut acquisition)?;
+            }
+
+            self.shared.process_reader_counts[mode as usize].fetch_add(1, Ordering::SeqCst);
+            self.acquired(&acquisition);
 
             return Ok(ReadGuard {
                 lock: self.clone(),
                 mode,
                 active: true,
+                counted: false,
             });
         }
 
-        let mut backoff = Duration::from_millis(1);
-        let max_backoff = Duration::from_secs(1);
+        let _waiting = self.shared.waiting();
 
         loop {
             let current_writer_mode =
-                LockMode::from_u8(self.writer_mode.load(Ordering::SeqCst) as u8);
-            let writer_present = self.writer_present.load(Ordering::SeqCst) != 0;
-            let writer_pid = self.writer_pid.load(Ordering::SeqCst);
-            let current_pid = Self::current_pid();
+                LockMode::from_u8(self.shared.writer_mode.load(Ordering::SeqCst) as u8);
+            let writer_present = self.shared.writer_present.load(Ordering::SeqCst) != 0;
 
-            if !writer_present || current_writer_mode == mode || writer_pid == current_pid {
+            if !writer_present || current_writer_mode == mode || self.shared.writer_is_current() {
+                let mut acquired = false;
                 match self.update_state(|mut state| {
                     if state.writer_present != 0
                         && LockMode::from_u8(state.writer_mode) != mode
-                        && state.writer_pid != current_pid
+                        && !state.writer_is_current()
                     {
                         return state;
                     }
 
-                    state.reader_counts[mode as usize] += 1;
+                    state.add_reader(mode);
+
+                    state.heartbeat = unix_now();
+                    acquired = true;
                     state
                 }) {
+                    Ok(()) if !acquired => {
+                        self.wait(&mut acquisition)?;
+                        continue;
+                    }
                     Ok(()) => {
-                        self.process_reader_counts[mode as usize].fetch_add(1, Ordering::SeqCst);
+                        self.shared.process_reader_counts[mode as usize]
+                            .fetch_add(1, Ordering::SeqCst);
+                        self.acquired(&acquisition);
 
                         return Ok(ReadGuard {
                             lock: self.clone(),
                             mode,
                             active: true,
+                            counted: true,
                         });
                     }
                     Err(e) => {
                         if e.kind() == std::io::ErrorKind::WouldBlock {
-                            thread::sleep(backoff);
-                            backoff = std::cmp::min(backoff * 2, max_backoff);
+                            self.wait(&mut acquisition)?;
                             continue;
                         }
                         return Err(e);
@@ -302,12 +1732,25 @@ impl RwLock {
                 }
             }
 
-            thread::sleep(backoff);
-            backoff = std::cmp::min(backoff * 2, max_backoff);
+            if self.recover_stale()? {
+                continue;
+            }
+
+            self.wait(&mut acquisition)?;
         }
     }
 
     pub fn write_lock(&self, mode: LockMode) -> std::io::Result<WriteGuard> {
+        self.write_lock_timeout(mode, Duration::MAX)
+    }
+
+    /// Like `write_lock`, but fails with a `WouldBlock` error naming the holder of the
+    /// lock if it could not be acquired within `timeout`.
+    pub fn write_lock_timeout(
+        &self,
+        mode: LockMode,
+        timeout: Duration,
+    ) -> std::io::Result<WriteGuard> {
         if mode == LockMode::None {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
@@ -316,7 +1759,26 @@ impl RwLock {
         }
 
         if self.process_owns_writer() {
-            self.process_has_writer.fetch_add(1, Ordering::SeqCst);
+            self.shared
+                .process_has_writer
+                .fetch_add(1, Ordering::SeqCst);
+
+            return Ok(WriteGuard {
+                lock: self.clone(),
+                mode,
+                active: true,
+            });
+        }
+
+        let mut acquisition = Acquisition::new(mode, true, timeout);
+
+        if let Some(native) = &self.shared.native {
+            while !native.try_write(mode)? {
+                self.wait(&mut acquisition)?;
+            }
+
+            self.shared.process_has_writer.store(1, Ordering::SeqCst);
+            self.acquired(&acquisition);
 
             return Ok(WriteGuard {
                 lock: self.clone(),
@@ -325,28 +1787,29 @@ impl RwLock {
             });
         }
 
-        let mut backoff = Duration::from_millis(1);
-        let max_backoff = Duration::from_secs(1);
-        let current_pid = Self::current_pid();
+        let _waiting = self.shared.waiting();
 
         loop {
-            let writer_present = self.writer_present.load(Ordering::SeqCst) != 0;
-            let writer_pid = self.writer_pid.load(Ordering::SeqCst);
+            let writer_present = self.shared.writer_present.load(Ordering::SeqCst) != 0;
 
             let incompatible_readers = (0..3).any(|i| {
                 if i == mode as usize {
                     false
                 } else {
-                    self.reader_counts[i].load(Ordering::SeqCst) > 0
+                    self.shared.reader_counts[i].load(Ordering::SeqCst) > 0
                 }
             });
 
-            if (writer_present && writer_pid != current_pid) || incompatible_readers {
-                thread::sleep(backoff);
-                backoff = std::cmp::min(backoff * 2, max_backoff);
+            if (writer_present && !self.shared.writer_is_current()) || incompatible_readers {
+                if self.recover_stale()? {
+                    continue;
+                }
+
+                self.wait(&mut acquisition)?;
                 continue;
             }
 
+            let mut acquired = false;
             match self.update_state(|mut state| {
                 let incompatible_readers = (0..3).any(|i| {
                     if i == mode as usize {
@@ -356,19 +1819,23 @@ impl RwLock {
                     }
                 });
 
-                if (state.writer_present != 0 && state.writer_pid != current_pid)
-                    || incompatible_readers
+                if (state.writer_present != 0 && !state.writer_is_current()) || incompatible_readers
                 {
                     return state;
                 }
 
-                state.writer_mode = mode.as_u8();
-                state.writer_present = 1;
-                state.writer_pid = current_pid;
+                state.set_writer(Some(mode));
+                state.heartbeat = unix_now();
+                acquired = true;
                 state
             }) {
+                Ok(()) if !acquired => {
+                    self.wait(&mut acquisition)?;
+                    continue;
+                }
                 Ok(()) => {
-                    self.process_has_writer.store(1, Ordering::SeqCst);
+                    self.shared.process_has_writer.store(1, Ordering::SeqCst);
+                    self.acquired(&acquisition);
 
                     return Ok(WriteGuard {
                         lock: self.clone(),
@@ -378,8 +1845,7 @@ impl RwLock {
                 }
                 Err(e) => {
                     if e.kind() == std::io::ErrorKind::WouldBlock {
-                        thread::sleep(backoff);
-                        backoff = std::cmp::min(backoff * 2, max_backoff);
+                        self.wait(&mut acquisition)?;
                         continue;
                     }
 
@@ -389,6 +1855,130 @@ impl RwLock {
         }
     }
 
+    /// Sleeps for the current backoff of `acquisition`, at most until its deadline, and
+    /// doubles it. Fails once the deadline has passed, reports the wait to the wait
+    /// callback once it took longer than the threshold.
+    fn wait(&self, acquisition: &mut Acquisition) -> std::io::Result<()> {
+        acquisition.waited = true;
+
+        let sleep = match acquisition.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    self.shared.timeouts.fetch_add(1, Ordering::SeqCst);
+                    self.shared.add_wait_time(acquisition.started.elapsed());
+
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::WouldBlock,
+                        format!(
+                            "Timed out after {:?} waiting for a {:?} {} lock, held by {}",
+                            acquisition.timeout,
+                            acquisition.mode,
+                            if acquisition.write { "write" } else { "read" },
+                            self.holders()
+                        ),
+                    ));
+                }
+
+                acquisition.backoff.min(remaining)
+            }
+            None => acquisition.backoff,
+        };
+
+        thread::sleep(sleep);
+        acquisition.backoff = std::cmp::min(acquisition.backoff * 2, Duration::from_secs(1));
+
+        let threshold = Duration::from_millis(self.shared.wait_threshold.load(Ordering::SeqCst));
+        if !acquisition.reported && acquisition.started.elapsed() >= threshold {
+            let callback = self.shared.wait_callback.lock().unwrap().clone();
+            if let Some(callback) = callback {
+                acquisition.reported = true;
+                callback(&self.wait_event(acquisition, false));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts an acquisition that had to wait and reports it to the wait callback if the
+    /// wait was reported before.
+    fn acquired(&self, acquisition: &Acquisition) {
+        if !acquisition.waited {
+            return;
+        }
+
+        self.shared.contended.fetch_add(1, Ordering::SeqCst);
+        self.shared.add_wait_time(acquisition.started.elapsed());
+
+        if acquisition.reported {
+            let callback = self.shared.wait_callback.lock().unwrap().clone();
+            if let Some(callback) = callback {
+                callback(&self.wait_event(acquisition, true));
+            }
+        }
+    }
+
+    fn wait_event(&self, acquisition: &Acquisition, acquired: bool) -> LockWaitEvent {
+        let status = self.known_status();
+
+        LockWaitEvent {
+            mode: acquisition.mode,
+            write: acquisition.write,
+            writer_pid: status.writer_pid,
+            writer_mode: status.writer_mode,
+            reader_counts: status.reader_counts,
+            elapsed: acquisition.started.elapsed(),
+            acquired,
+        }
+    }
+
+    /// Sets the callback told about acquisitions waiting longer than `threshold`, once
+    /// when the threshold has passed and once more when the lock was acquired.
+    pub fn set_wait_callback(&self, callback: LockWaitCallback, threshold: Duration) {
+        self.shared.wait_threshold.store(
+            threshold.as_millis().min(u64::MAX as u128) as u64,
+            Ordering::SeqCst,
+        );
+        *self.shared.wait_callback.lock().unwrap() = callback;
+    }
+
+    /// How much this lock, and every clone of it, had to wait so far.
+    pub fn metrics(&self) -> LockMetrics {
+        LockMetrics {
+            contended: self.shared.contended.load(Ordering::SeqCst),
+            timeouts: self.shared.timeouts.load(Ordering::SeqCst),
+            wait_time: Duration::from_nanos(self.shared.wait_nanos.load(Ordering::SeqCst)),
+        }
+    }
+
+    /// Describes who holds the lock, like `Destructive writer pid 1234 and 2 reader(s)`.
+    pub fn holders(&self) -> String {
+        let status = self.known_status();
+        let readers = (0..3)
+            .filter_map(|i| {
+                let count = status.reader_counts[i];
+
+                (count > 0).then(|| format!("{count} {:?} reader(s)", LockMode::from_u8(i as u8)))
+            })
+            .collect::<Vec<_>>();
+
+        let mut holders = Vec::new();
+        if let (Some(mode), Some(pid)) = (status.writer_mode, status.writer_pid) {
+            if status.writer_host.is_some_and(|host| host != host_id()) {
+                holders.push(format!("{mode:?} writer pid {pid} on another host"));
+            } else {
+                holders.push(format!("{mode:?} writer pid {pid}"));
+            }
+        }
+        holders.extend(readers);
+
+        if holders.is_empty() {
+            "nobody".to_string()
+        } else {
+            holders.join(" and ")
+        }
+    }
+
     pub fn try_read_lock(&self, mode: LockMode) -> std::io::Result<Option<ReadGuard>> {
         if mode == LockMode::None {
             return Err(std::io::Error::new(
@@ -398,45 +1988,74 @@ impl RwLock {
         }
 
         if self.process_owns_writer() {
-            self.process_reader_counts[mode as usize].fetch_add(1, Ordering::SeqCst);
+            self.shared.process_reader_counts[mode as usize].fetch_add(1, Ordering::SeqCst);
+
+            return Ok(Some(ReadGuard {
+                lock: self.clone(),
+                mode,
+                active: true,
+                counted: false,
+            }));
+        }
+
+        if let Some(native) = &self.shared.native {
+            if !native.try_read(mode)? {
+                return Ok(None);
+            }
+
+            self.shared.process_reader_counts[mode as usize].fetch_add(1, Ordering::SeqCst);
 
             return Ok(Some(ReadGuard {
                 lock: self.clone(),
                 mode,
                 active: true,
+                counted: false,
             }));
         }
 
-        let current_writer_mode = LockMode::from_u8(self.writer_mode.load(Ordering::SeqCst) as u8);
-        let writer_present = self.writer_present.load(Ordering::SeqCst) != 0;
-        let writer_pid = self.writer_pid.load(Ordering::SeqCst);
-        let current_pid = Self::current_pid();
+        // the refresh thread does not run while the lock is idle, so the cache may be old
+        self.shared.store_cache(&self.shared.load_state()?);
+
+        let current_writer_mode =
+            LockMode::from_u8(self.shared.writer_mode.load(Ordering::SeqCst) as u8);
+        let writer_present = self.shared.writer_present.load(Ordering::SeqCst) != 0;
 
-        if !writer_present || current_writer_mode == mode || writer_pid == current_pid {
+        if !writer_present || current_writer_mode == mode || self.shared.writer_is_current() {
+            let mut acquired = false;
             match self.update_state(|mut state| {
                 if state.writer_present != 0
                     && LockMode::from_u8(state.writer_mode) != mode
-                    && state.writer_pid != current_pid
+                    && !state.writer_is_current()
                 {
                     return state;
                 }
 
-                state.reader_counts[mode as usize] += 1;
+                state.add_reader(mode);
+
+                state.heartbeat = unix_now();
+                acquired = true;
                 state
             }) {
+                Ok(()) if !acquired => {}
                 Ok(()) => {
-                    self.process_reader_counts[mode as usize].fetch_add(1, Ordering::SeqCst);
+                    self.shared.process_reader_counts[mode as usize].fetch_add(1, Ordering::SeqCst);
+                    self.shared.start_refresh();
 
                     return Ok(Some(ReadGuard {
                         lock: self.clone(),
                         mode,
                         active: true,
+                        counted: true,
                     }));
                 }
                 Err(e) => return Err(e),
             }
         }
 
+        if self.recover_stale()? {
+            return self.try_read_lock(mode);
+        }
+
         Ok(None)
     }
 
@@ -449,7 +2068,23 @@ impl RwLock {
         }
 
         if self.process_owns_writer() {
-            self.process_has_writer.fetch_add(1, Ordering::SeqCst);
+            self.shared
+                .process_has_writer
+                .fetch_add(1, Ordering::SeqCst);
+
+            return Ok(Some(WriteGuard {
+                lock: self.clone(),
+                mode,
+                active: true,
+            }));
+        }
+
+        if let Some(native) = &self.shared.native {
+            if !native.try_write(mode)? {
+                return Ok(None);
+            }
+
+            self.shared.process_has_writer.store(1, Ordering::SeqCst);
 
             return Ok(Some(WriteGuard {
                 lock: self.clone(),
@@ -458,22 +2093,27 @@ impl RwLock {
             }));
         }
 
-        let writer_present = self.writer_present.load(Ordering::SeqCst) != 0;
-        let writer_pid = self.writer_pid.load(Ordering::SeqCst);
-        let current_pid = Self::current_pid();
+        self.shared.store_cache(&self.shared.load_state()?);
+
+        let writer_present = self.shared.writer_present.load(Ordering::SeqCst) != 0;
 
         let incompatible_readers = (0..3).any(|i| {
             if i == mode as usize {
                 false
             } else {
-                self.reader_counts[i].load(Ordering::SeqCst) > 0
+                self.shared.reader_counts[i].load(Ordering::SeqCst) > 0
             }
         });
 
-        if (writer_present && writer_pid != current_pid) || incompatible_readers {
+        if (writer_present && !self.shared.writer_is_current()) || incompatible_readers {
+            if self.recover_stale()? {
+                return self.try_write_lock(mode);
+            }
+
             return Ok(None);
         }
 
+        let mut acquired = false;
         match self.update_state(|mut state| {
             let incompatible_readers = (0..3).any(|i| {
                 if i == mode as usize {
@@ -483,20 +2123,19 @@ impl RwLock {
                 }
             });
 
-            if (state.writer_present != 0 && state.writer_pid != current_pid)
-                || incompatible_readers
-            {
+            if (state.writer_present != 0 && !state.writer_is_current()) || incompatible_readers {
                 return state;
             }
 
-            state.writer_mode = mode.as_u8();
-            state.writer_present = 1;
-            state.writer_pid = current_pid;
+            state.set_writer(Some(mode));
+            state.heartbeat = unix_now();
+            acquired = true;
             state
         }) {
+            Ok(()) if !acquired => Ok(None),
             Ok(()) => {
-                self.process_has_writer.store(1, Ordering::SeqCst);
-
+                self.shared.process_has_writer.store(1, Ordering::SeqCst);
+                self.shared.start_refresh();
                 Ok(Some(WriteGuard {
                     lock: self.clone(),
                     mode,
@@ -507,36 +2146,69 @@ impl RwLock {
         }
     }
 
+    /// Reads who holds the lock from the lock file, not from the state cached by the
+    /// refresh thread, for diagnostics.
+    pub fn status(&self) -> std::io::Result<LockStatus> {
+        if let Some(native) = &self.shared.native {
+            return native.status();
+        }
+
+        let state = self.shared.load_state()?;
+        let writer_present = state.writer_present != 0;
+
+        Ok(LockStatus {
+            writer_pid: writer_present.then_some(state.writer_pid),
+            writer_host: writer_present.then_some(state.writer_host),
+            writer_mode: writer_present.then(|| LockMode::from_u8(state.writer_mode)),
+            reader_counts: state.reader_counts,
+            heartbeat_age: state.heartbeat_age(),
+        })
+    }
+
+    /// A fresh `status`, falling back to the state cached by the last refresh, which the
+    /// native backend does not have, if the lock file cannot be read.
+    fn known_status(&self) -> LockStatus {
+        let error = match self.status() {
+            Ok(status) => return status,
+            Err(e) => e,
+        };
+        log::debug!(
+            "Error reading lock status of {}: {error}",
+            self.shared.path.display()
+        );
+
+        let writer_present = self.shared.writer_present.load(Ordering::SeqCst) != 0;
+
+        LockStatus {
+            writer_pid: writer_present.then(|| self.shared.writer_pid.load(Ordering::SeqCst)),
+            writer_host: writer_present.then(|| self.shared.writer_host.load(Ordering::SeqCst)),
+            writer_mode: writer_present
+                .then(|| LockMode::from_u8(self.shared.writer_mode.load(Ordering::SeqCst) as u8)),
+            reader_counts: std::array::from_fn(|i| {
+                self.shared.reader_counts[i].load(Ordering::SeqCst)
+            }),
+            heartbeat_age: None,
+        }
+    }
+
     pub fn reader_count(&self, mode: LockMode) -> u64 {
-        self.reader_counts[mode as usize].load(Ordering::SeqCst)
+        self.known_status().reader_counts[mode as usize]
     }
 
     pub fn total_reader_count(&self) -> u64 {
-        (0..3)
-            .map(|i| self.reader_counts[i].load(Ordering::SeqCst))
-            .sum()
+        self.known_status().reader_counts.iter().sum()
     }
 
     pub fn has_writer(&self) -> bool {
-        self.writer_present.load(Ordering::SeqCst) != 0
+        self.known_status().writer_pid.is_some()
     }
 
     pub fn writer_mode(&self) -> Option<LockMode> {
-        if self.has_writer() {
-            Some(LockMode::from_u8(
-                self.writer_mode.load(Ordering::SeqCst) as u8
-            ))
-        } else {
-            None
-        }
+        self.known_status().writer_mode
     }
 
     pub fn writer_pid(&self) -> Option<u64> {
-        if self.has_writer() {
-            Some(self.writer_pid.load(Ordering::SeqCst))
-        } else {
-            None
-        }
+        self.known_status().writer_pid
     }
 }
 
@@ -544,6 +2216,9 @@ pub struct ReadGuard {
     lock: RwLock,
     mode: LockMode,
     active: bool,
+    /// Whether this guard added a reader to the polled lock file, reentrant guards of a
+    /// process holding the writer do not.
+    counted: bool,
 }
 
 impl ReadGuard {
@@ -553,14 +2228,16 @@ impl ReadGuard {
 
     pub fn unlock(&mut self) -> std::io::Result<()> {
         if self.active {
-            let prev_count =
-                self.lock.process_reader_counts[self.mode as usize].fetch_sub(1, Ordering::SeqCst);
+            let prev_count = self.lock.shared.process_reader_counts[self.mode as usize]
+                .fetch_sub(1, Ordering::SeqCst);
 
-            if prev_count == 1 && !self.lock.process_owns_writer() {
+            if prev_count == 1
+                && let Some(native) = &self.lock.shared.native
+            {
+                native.release_read(self.mode)?;
+            } else if self.counted {
                 self.lock.update_state(|mut state| {
-                    if state.reader_counts[self.mode as usize] > 0 {
-                        state.reader_counts[self.mode as usize] -= 1;
-                    }
+                    state.remove_reader(self.mode);
                     state
                 })?;
             }
@@ -576,7 +2253,7 @@ impl Drop for ReadGuard {
         if self.active
             && let Err(e) = self.unlock()
         {
-            eprintln!("Error releasing read lock in drop: {e}");
+            log::error!("Error releasing read lock in drop: {e}");
         }
     }
 }
@@ -594,15 +2271,20 @@ impl WriteGuard {
 
     pub fn unlock(&mut self) -> std::io::Result<()> {
         if self.active {
-            let prev_count = self.lock.process_has_writer.fetch_sub(1, Ordering::SeqCst);
-
-            if prev_count == 1 {
+            let prev_count = self
+                .lock
+                .shared
+                .process_has_writer
+                .fetch_sub(1, Ordering::SeqCst);
+
+            if prev_count == 1
+                && let Some(native) = &self.lock.shared.native
+            {
+                native.release_write()?;
+            } else if prev_count == 1 {
                 self.lock.update_state(|mut state| {
-                    let current_pid = RwLock::current_pid();
-                    if state.writer_present != 0 && state.writer_pid == current_pid {
-                        state.writer_present = 0;
-                        state.writer_mode = LockMode::None.as_u8();
-                        state.writer_pid = 0;
+                    if state.writer_is_current() {
+                        state.set_writer(None);
                     }
                     state
                 })?;
@@ -619,19 +2301,541 @@ impl Drop for WriteGuard {
         if self.active
             && let Err(e) = self.unlock()
         {
-            eprintln!("Error releasing write lock in drop: {e}");
+            log::error!("Error releasing write lock in drop: {e}");
         }
     }
 }
 
-impl Drop for RwLock {
-    fn drop(&mut self) {
-        self.running.store(0, Ordering::SeqCst);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{temp_directory, temp_path};
+
+    /// A pid above any pid limit, which never belongs to a running process.
+    const DEAD_PID: u64 = i32::MAX as u64;
+
+    fn session(pid: u64, host: u64, start_time: u64, readers: u64) -> Session {
+        Session {
+            pid,
+            host,
+            start_time,
+            reader_counts: [0, readers, 0],
+            heartbeat: unix_now(),
+        }
+    }
 
-        if let Ok(mut refresh_guard) = self.refresh.lock()
-            && let Some(handle) = refresh_guard.take()
-        {
-            let _ = handle.join();
+    #[test]
+    fn remove_session_matches_pid_host_and_start_time() {
+        let mut state = LockState {
+            reader_counts: [0, 7, 0],
+            sessions: vec![
+                session(42, 1, 100, 1),
+                session(42, 2, 100, 2),
+                session(42, 1, 200, 4),
+            ],
+            ..Default::default()
+        };
+
+        state.remove_session(42, 2, 100);
+        assert_eq!(state.reader_counts, [0, 5, 0]);
+        assert_eq!(
+            state
+                .sessions
+                .iter()
+                .map(|session| (session.pid, session.host, session.start_time))
+                .collect::<Vec<_>>(),
+            [(42, 1, 100), (42, 1, 200)]
+        );
+
+        state.remove_session(42, 3, 100);
+        assert_eq!(state.sessions.len(), 2);
+    }
+
+    #[test]
+    fn dead_session_of_a_colliding_pid_keeps_the_live_one() {
+        let path = temp_path("lock-colliding-pid");
+        let lock = RwLock::new(&path).unwrap();
+
+        let guard = lock.read_lock(LockMode::NonDestructive).unwrap();
+        lock.update_state(|mut state| {
+            // same pid and host as this process, but another start time, so a previous
+            // process that reused the pid
+            let mut stale = Session::current();
+            stale.start_time = stale.start_time.wrapping_add(1);
+            stale.reader_counts = [0, 0, 3];
+
+            state.reader_counts[2] += 3;
+            state.sessions.push(stale);
+            state
+        })
+        .unwrap();
+
+        if current_start_time() != 0 {
+            assert!(lock.recover_stale().unwrap());
+
+            let state = lock.shared.load_state().unwrap();
+            assert_eq!(state.sessions.len(), 1);
+            assert!(state.sessions[0].is_current());
+            assert_eq!(state.reader_counts, [0, 0, 1]);
+        }
+
+        drop(guard);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Writes a lock file held for writing by `pid` of `host`.
+    fn write_writer_state(path: &Path, pid: u64, host: u64) {
+        RwLock::write_state(
+            path,
+            &LockState {
+                writer_mode: LockMode::Destructive.as_u8(),
+                writer_present: 1,
+                writer_pid: pid,
+                writer_host: host,
+                heartbeat: unix_now(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn write_lock_of_a_dead_pid_is_taken_over() {
+        let path = temp_path("lock-dead-writer");
+        write_writer_state(&path, DEAD_PID, host_id());
+
+        let lock = RwLock::new(&path).unwrap();
+        let recovered = Arc::new(Mutex::new(Vec::new()));
+        lock.set_stale_callback(Some({
+            let recovered = Arc::clone(&recovered);
+
+            Arc::new(move |stale: &StaleLock| recovered.lock().unwrap().push(*stale))
+        }));
+
+        let guard = lock
+            .write_lock_timeout(LockMode::Destructive, Duration::from_secs(5))
+            .unwrap();
+        assert!(lock.shared.load_state().unwrap().writer_is_current());
+        assert_eq!(
+            *recovered.lock().unwrap(),
+            [StaleLock::Writer {
+                pid: DEAD_PID,
+                mode: LockMode::Destructive,
+            }]
+        );
+
+        drop(guard);
+        assert_eq!(lock.status().unwrap().writer_pid, None);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write_lock_of_another_host_is_kept() {
+        let path = temp_path("lock-other-host");
+        write_writer_state(&path, DEAD_PID, host_id().wrapping_add(1));
+
+        let lock = RwLock::new(&path).unwrap();
+        assert!(!lock.recover_stale().unwrap());
+        assert!(
+            lock.try_write_lock(LockMode::Destructive)
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(lock.status().unwrap().writer_pid, Some(DEAD_PID));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn readers_without_heartbeat_decay_after_the_stale_age() {
+        let path = temp_path("lock-stale-readers");
+        RwLock::write_state(
+            &path,
+            &LockState {
+                reader_counts: [0, 2, 0],
+                heartbeat: unix_now() - 120,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let lock = RwLock::new(&path).unwrap();
+        assert!(!lock.recover_stale().unwrap());
+
+        lock.set_stale_reader_age(Some(Duration::from_secs(60)));
+        assert!(lock.recover_stale().unwrap());
+        assert_eq!(lock.status().unwrap().reader_counts, [0; 3]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn readers_of_dead_sessions_are_pruned() {
+        let path = temp_path("lock-dead-session");
+        RwLock::write_state(
+            &path,
+            &LockState {
+                reader_counts: [0, 0, 3],
+                heartbeat: unix_now(),
+                sessions: vec![
+                    Session {
+                        reader_counts: [0, 0, 2],
+                        ..session(DEAD_PID, host_id(), 0, 0)
+                    },
+                    Session {
+                        reader_counts: [0, 0, 1],
+                        ..session(DEAD_PID, host_id().wrapping_add(1), 0, 0)
+                    },
+                ],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let lock = RwLock::new(&path).unwrap();
+        assert!(lock.recover_stale().unwrap());
+
+        // the session of the other host may still be alive
+        let state = lock.shared.load_state().unwrap();
+        assert_eq!(state.reader_counts, [0, 0, 1]);
+        assert_eq!(state.sessions.len(), 1);
+        assert_ne!(state.sessions[0].host, host_id());
+        assert!(
+            lock.try_write_lock(LockMode::Destructive)
+                .unwrap()
+                .is_none()
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sessions_without_heartbeat_time_out() {
+        let path = temp_path("lock-session-timeout");
+        RwLock::write_state(
+            &path,
+            &LockState {
+                reader_counts: [0, 1, 0],
+                heartbeat: unix_now(),
+                sessions: vec![Session {
+                    heartbeat: unix_now() - 120,
+                    ..session(DEAD_PID, host_id().wrapping_add(1), 0, 1)
+                }],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let lock = RwLock::new(&path).unwrap();
+        lock.set_session_timeout(Some(Duration::from_secs(60)));
+
+        let guard = lock
+            .write_lock_timeout(LockMode::NonDestructive, Duration::from_secs(5))
+            .unwrap();
+        let state = lock.shared.load_state().unwrap();
+        assert_eq!(state.reader_counts, [0; 3]);
+        assert!(state.sessions.is_empty());
+
+        drop(guard);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn released_readers_leave_no_session_behind() {
+        let path = temp_path("lock-release-session");
+        let lock = RwLock::new(&path).unwrap();
+
+        let first = lock.read_lock(LockMode::NonDestructive).unwrap();
+        let second = lock.read_lock(LockMode::NonDestructive).unwrap();
+        let state = lock.shared.load_state().unwrap();
+        assert_eq!(state.sessions.len(), 1);
+        assert_eq!(state.sessions[0].reader_counts, [0, 0, 2]);
+
+        drop(first);
+        drop(second);
+        let state = lock.shared.load_state().unwrap();
+        assert!(state.sessions.is_empty());
+        assert_eq!(state.reader_counts, [0; 3]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn corruption(path: &Path) -> Option<&'static str> {
+        match RwLock::read_state(path).unwrap() {
+            DecodedState::Corrupt(reason) => Some(reason),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn read_state_rejects_damaged_files() {
+        let path = temp_path("lock-damaged");
+        let state = LockState {
+            reader_counts: [0, 1, 0],
+            heartbeat: unix_now(),
+            sessions: vec![session(DEAD_PID, host_id(), 7, 1)],
+            ..Default::default()
+        };
+        RwLock::write_state(&path, &state).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+
+        match RwLock::read_state(&path).unwrap() {
+            DecodedState::Current(decoded) => {
+                assert_eq!(decoded.reader_counts, state.reader_counts);
+                assert_eq!(decoded.sessions, state.sessions);
+            }
+            _ => panic!("expected a current lock state"),
+        }
+
+        for length in [STATE_OFFSET - 1, STATE_OFFSET + 10, bytes.len() - 1] {
+            std::fs::write(&path, &bytes[..length]).unwrap();
+            assert_eq!(corruption(&path), Some("truncated"), "length {length}");
+        }
+
+        let mut flipped = bytes.clone();
+        flipped[STATE_OFFSET + 16] ^= 1;
+        std::fs::write(&path, &flipped).unwrap();
+        assert_eq!(corruption(&path), Some("checksum mismatch"));
+
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(corruption(&path), Some("unknown format"));
+        std::fs::write(&path, [0xAB; 200]).unwrap();
+        assert_eq!(corruption(&path), Some("unknown format"));
+
+        let mut future = bytes.clone();
+        future[8..12].copy_from_slice(&(LOCK_FORMAT_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, &future).unwrap();
+        assert!(RwLock::read_state(&path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_lock_file_is_reset_and_reported() {
+        let path = temp_path("lock-reset");
+        let lock = RwLock::new(&path).unwrap();
+
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        lock.set_stale_callback(Some({
+            let reported = Arc::clone(&reported);
+
+            Arc::new(move |stale: &StaleLock| reported.lock().unwrap().push(*stale))
+        }));
+
+        std::fs::write(&path, [0xFF; 100]).unwrap();
+        let guard = lock
+            .write_lock_timeout(LockMode::Destructive, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(
+            reported.lock().unwrap().first(),
+            Some(&StaleLock::Corrupt {
+                reason: "unknown format"
+            })
+        );
+        assert!(matches!(
+            RwLock::read_state(&path).unwrap(),
+            DecodedState::Current(state) if state.writer_is_current()
+        ));
+
+        drop(guard);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn legacy_lock_files_are_upgraded() {
+        let path = temp_path("lock-legacy");
+
+        let mut legacy = vec![0; 56];
+        legacy[32..40].copy_from_slice(&2u64.to_le_bytes());
+        legacy[48..56].copy_from_slice(&unix_now().to_le_bytes());
+        std::fs::write(&path, &legacy).unwrap();
+
+        let lock = RwLock::new(&path).unwrap();
+        assert_eq!(lock.status().unwrap().reader_counts, [0, 2, 0]);
+        assert!(matches!(
+            RwLock::read_state(&path).unwrap(),
+            DecodedState::Current(_)
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn session_heartbeats_do_not_lose_concurrent_updates() {
+        let path = temp_path("lock-heartbeat-race");
+        let reader = RwLock::new(&path).unwrap();
+        let writer = RwLock::new(&path).unwrap();
+        let _read = reader.read_lock(LockMode::NonDestructive).unwrap();
+
+        let stop = std::sync::atomic::AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while !stop.load(Ordering::SeqCst) {
+                    reader.shared.refresh_session().unwrap();
+                }
+            });
+
+            // stops the refresh above even if an assertion below fails
+            struct Stop<'a>(&'a std::sync::atomic::AtomicBool);
+            impl Drop for Stop<'_> {
+                fn drop(&mut self) {
+                    self.0.store(true, Ordering::SeqCst);
+                }
+            }
+            let _stop = Stop(&stop);
+
+            for _ in 0..100 {
+                // a lost release would leave the writer behind, time out instead of hanging
+                let mut guard = writer
+                    .write_lock_timeout(LockMode::NonDestructive, Duration::from_secs(5))
+                    .unwrap();
+                assert!(writer.shared.load_state().unwrap().writer_is_current());
+
+                guard.unlock().unwrap();
+                assert_eq!(writer.shared.load_state().unwrap().writer_present, 0);
+            }
+        });
+
+        let state = reader.shared.load_state().unwrap();
+        assert_eq!(state.reader_counts, [0, 0, 1]);
+        assert_eq!(state.sessions.len(), 1);
+
+        drop(_read);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("state"));
+    }
+
+    #[test]
+    fn acquisitions_time_out_while_another_instance_holds_the_lock() {
+        let directory = temp_directory("lock-timeout");
+        let timeout = Duration::from_millis(200);
+
+        // the polled backend cannot check the writer of another host, it never goes away
+        let polled = directory.join("polled.lock");
+        write_writer_state(&polled, DEAD_PID, host_id().wrapping_add(1));
+        let native = directory.join("native.lock");
+        let holder = RwLock::with_backend(&native, LockBackend::Native).unwrap();
+        let _held = holder.write_lock(LockMode::Destructive).unwrap();
+
+        for lock in [
+            RwLock::new(&polled).unwrap(),
+            RwLock::with_backend(&native, LockBackend::Native).unwrap(),
+        ] {
+            let started = Instant::now();
+            let err = lock
+                .write_lock_timeout(LockMode::Destructive, timeout)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+            assert!(err.to_string().contains("Destructive write lock"), "{err}");
+            assert!(started.elapsed() >= timeout);
+            assert!(started.elapsed() < timeout * 10);
+
+            let started = Instant::now();
+            let err = lock
+                .read_lock_timeout(LockMode::NonDestructive, timeout)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+            assert!(started.elapsed() >= timeout);
+
+            let metrics = lock.metrics();
+            assert_eq!(metrics.timeouts, 2);
+            assert!(metrics.wait_time >= timeout * 2);
+        }
+
+        drop(_held);
+        drop(holder);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn idle_locks_do_not_read_the_lock_file() {
+        let path = temp_path("lock-idle");
+        let lock = RwLock::new(&path).unwrap();
+        lock.set_refresh_interval(Duration::from_millis(10));
+
+        let reads = lock.state_reads();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(lock.state_reads(), reads, "reads of a lock never used");
+
+        // the refresh thread only runs while the lock is held
+        let guard = lock.read_lock(LockMode::NonDestructive).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(lock.state_reads() > reads);
+        drop(guard);
+
+        let started = Instant::now();
+        while lock.shared.refresh.lock().unwrap().running {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "refresh thread kept running"
+            );
+            thread::sleep(Duration::from_millis(10));
         }
+
+        let reads = lock.state_reads();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(lock.state_reads(), reads, "reads of a released lock");
+        assert_eq!(lock.metrics(), LockMetrics::default());
+
+        drop(lock);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("state"));
+    }
+
+    #[test]
+    fn wait_callback_reports_contended_acquisitions_only() {
+        let directory = temp_directory("lock-wait-callback");
+        let path = directory.join("index.lock");
+        let holder = RwLock::with_backend(&path, LockBackend::Native).unwrap();
+        let waiter = RwLock::with_backend(&path, LockBackend::Native).unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        waiter.set_wait_callback(
+            Some({
+                let events = Arc::clone(&events);
+
+                Arc::new(move |event: &LockWaitEvent| events.lock().unwrap().push(*event))
+            }),
+            Duration::from_millis(50),
+        );
+
+        drop(waiter.write_lock(LockMode::Destructive).unwrap());
+        assert!(events.lock().unwrap().is_empty());
+        assert_eq!(waiter.metrics(), LockMetrics::default());
+
+        let held = holder.write_lock(LockMode::Destructive).unwrap();
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| {
+                waiter
+                    .write_lock_timeout(LockMode::NonDestructive, Duration::from_secs(5))
+                    .map(drop)
+            });
+
+            thread::sleep(Duration::from_millis(300));
+            drop(held);
+            waiting.join().unwrap().unwrap();
+        });
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2, "{events:?}");
+        assert!(!events[0].acquired);
+        assert!(events[0].write);
+        assert_eq!(events[0].mode, LockMode::NonDestructive);
+        assert_eq!(events[0].writer_pid, Some(std::process::id() as u64));
+        assert_eq!(events[0].writer_mode, Some(LockMode::Destructive));
+        assert!(events[0].elapsed >= Duration::from_millis(50));
+        assert!(events[1].acquired);
+        assert!(events[1].elapsed >= Duration::from_millis(300));
+
+        let metrics = waiter.metrics();
+        assert_eq!(metrics.contended, 1);
+        assert_eq!(metrics.timeouts, 0);
+        assert!(metrics.wait_time >= Duration::from_millis(300));
+
+        drop((holder, waiter));
+        std::fs::remove_dir_all(directory).unwrap();
     }
 }
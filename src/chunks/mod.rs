@@ -1,28 +1,105 @@
-use crate::{archive::CompressionFormat, repository::DeletionProgressCallback, varint};
+use crate::{archive::CompressionFormat, repository::CleanProgressCallback, varint};
 use blake2::{Blake2b, Digest, digest::consts::U32};
 use dashmap::DashMap;
 use flate2::{
+    Compress, FlushCompress, Status,
     read::{DeflateDecoder, GzDecoder},
     write::{DeflateEncoder, GzEncoder},
 };
 use parking_lot::{Mutex, RwLock};
+use positioned_io::ReadAt;
 use std::{
+    cell::RefCell,
     collections::{HashMap, VecDeque},
     fs::File,
-    io::{Cursor, Read, Seek, SeekFrom, Write},
-    path::PathBuf,
-    sync::{Arc, atomic::AtomicU64},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
 };
 
 mod hasher;
+pub mod ids;
 pub mod lock;
 pub mod reader;
 pub mod storage;
 
 pub type ChunkHash = [u8; 32];
 
+/// Files chunked serially from this size on have their next chunk read on a thread of its
+/// own while the current one is stored, for smaller files spawning it costs more than it saves.
+const PIPELINED_CHUNKING_SIZE: usize = 4 * 1024 * 1024;
+
 pub type RebuildProgressCallback =
     Option<Arc<dyn Fn(u64, &ChunkHash, u64) + Send + Sync + 'static>>;
+pub type ChunkProgressCallback = Option<Arc<dyn Fn(u64) + Send + Sync + 'static>>;
+
+/// Summary of the unreferenced chunks a clean removes (or would remove).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CleanPlan {
+    pub chunk_count: u64,
+    pub bytes: u64,
+}
+
+/// Numbers the chunk index keeps in memory, returned by `ChunkIndex::stats`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChunkIndexStats {
+    pub chunk_count: u64,
+    /// Sum of the reference counts of all chunks.
+    pub total_references: u64,
+    /// Chunk IDs freed by deletions that have not been reused yet.
+    pub deleted_id_count: u64,
+    pub chunk_size: usize,
+    pub max_chunk_count: usize,
+}
+
+type ChunkMap = DashMap<u64, (ChunkHash, u64), hasher::RandomizingHasherBuilder>;
+type ChunkHashMap = DashMap<ChunkHash, u64, hasher::RandomizingHasherBuilder>;
+
+/// How the in-memory maps of a `ChunkIndex` are laid out.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkIndexTuning {
+    /// Number of shards of each map, more shards mean less contention between threads
+    /// adding chunks at the cost of memory. Rounded up to a power of two of at least 2.
+    pub shard_amount: usize,
+    /// Number of chunks a new index has room for before growing, an opened index
+    /// is sized from the chunk count stored in it instead.
+    pub capacity: usize,
+}
+
+impl Default for ChunkIndexTuning {
+    fn default() -> Self {
+        let parallelism = std::thread::available_parallelism().map_or(1, usize::from);
+
+        Self {
+            shard_amount: (parallelism * 4).next_power_of_two().clamp(4, 1024),
+            capacity: 4096,
+        }
+    }
+}
+
+impl ChunkIndexTuning {
+    fn maps(&self, capacity: usize) -> (ChunkMap, ChunkHashMap) {
+        let shard_amount = self.shard_amount.max(2).next_power_of_two();
+        // chunks never spread perfectly evenly, leave the fuller shards some headroom
+        let capacity = capacity.saturating_add(capacity / 8);
+
+        (
+            DashMap::with_capacity_and_hasher_and_shard_amount(
+                capacity,
+                hasher::RandomizingHasherBuilder,
+                shard_amount,
+            ),
+            DashMap::with_capacity_and_hasher_and_shard_amount(
+                capacity,
+                hasher::RandomizingHasherBuilder,
+                shard_amount,
+            ),
+        )
+    }
+}
 
 pub struct ChunkIndex {
     pub directory: PathBuf,
@@ -32,8 +109,10 @@ pub struct ChunkIndex {
 
     next_id: Arc<AtomicU64>,
     deleted_chunks: Arc<Mutex<VecDeque<u64>>>,
-    chunks: Arc<DashMap<u64, (ChunkHash, u64), hasher::RandomizingHasherBuilder>>,
-    chunk_hashes: Arc<DashMap<ChunkHash, u64, hasher::RandomizingHasherBuilder>>,
+    chunks: Arc<ChunkMap>,
+    chunk_hashes: Arc<ChunkHashMap>,
+    /// Whether the index changed since it was last loaded or saved.
+    dirty: Arc<AtomicBool>,
 
     chunk_size: usize,
     max_chunk_count: usize,
@@ -51,6 +130,7 @@ impl Clone for ChunkIndex {
             deleted_chunks: Arc::clone(&self.deleted_chunks),
             chunks: Arc::clone(&self.chunks),
             chunk_hashes: Arc::clone(&self.chunk_hashes),
+            dirty: Arc::clone(&self.dirty),
 
             chunk_size: self.chunk_size,
             max_chunk_count: self.max_chunk_count,
@@ -69,6 +149,69 @@ fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
     Ok(total)
 }
 
+/// Like `read_full`, reading at `offset` without moving the cursor of `file`.
+fn read_full_at(file: &File, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match file.read_at(offset + total as u64, &mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(total)
+}
+
+thread_local! {
+    /// The buffer `add_chunk` assembles the stored content of a chunk in, kept around
+    /// so every thread only grows it to the largest chunk once.
+    static COMPRESSION_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    /// The deflate state `add_chunk` compresses with, reset for every chunk.
+    static DEFLATE: RefCell<Option<Compress>> = const { RefCell::new(None) };
+}
+
+/// Deflates `data` onto the end of `output`, producing the same stream as a
+/// `DeflateEncoder` at the default level.
+fn deflate_into(data: &[u8], output: &mut Vec<u8>) -> std::io::Result<()> {
+    let mut compress = DEFLATE
+        .take()
+        .unwrap_or_else(|| Compress::new(flate2::Compression::default(), false));
+    compress.reset();
+
+    // incompressible data grows slightly, reserving for that avoids growing mid-way
+    output.reserve(data.len() + data.len() / 1000 + 64);
+
+    let mut flush = FlushCompress::None;
+    loop {
+        let consumed = compress.total_in() as usize;
+        if consumed == data.len() {
+            flush = FlushCompress::Finish;
+        }
+
+        let status = compress
+            .compress_vec(&data[consumed..], output, flush)
+            .map_err(std::io::Error::other)?;
+        if status == Status::StreamEnd {
+            break;
+        }
+
+        output.reserve(4096);
+    }
+
+    DEFLATE.set(Some(compress));
+
+    Ok(())
+}
+
+/// The error for a file that could only be read up to `read` while `len` bytes were expected.
+fn file_changed(path: &Path, len: usize, read: usize) -> std::io::Error {
+    std::io::Error::other(format!(
+        "{} changed during backup, expected {len} bytes but reading stopped at byte {read}",
+        path.display()
+    ))
+}
+
 impl ChunkIndex {
     pub fn new(
         directory: PathBuf,
@@ -76,6 +219,24 @@ impl ChunkIndex {
         max_chunk_count: usize,
         storage: Arc<dyn storage::ChunkStorage>,
     ) -> std::io::Result<Self> {
+        Self::new_with_tuning(
+            directory,
+            chunk_size,
+            max_chunk_count,
+            storage,
+            ChunkIndexTuning::default(),
+        )
+    }
+
+    /// Creates a new chunk index with custom map tuning.
+    pub fn new_with_tuning(
+        directory: PathBuf,
+        chunk_size: usize,
+        max_chunk_count: usize,
+        storage: Arc<dyn storage::ChunkStorage>,
+        tuning: ChunkIndexTuning,
+    ) -> std::io::Result<Self> {
+        let (chunks, chunk_hashes) = tuning.maps(tuning.capacity);
         let lock = lock::RwLock::new(directory.join("index.lock"))?;
 
         Ok(Self {
@@ -86,16 +247,9 @@ impl ChunkIndex {
 
             next_id: Arc::new(AtomicU64::new(1)),
             deleted_chunks: Arc::new(Mutex::new(VecDeque::new())),
-            chunks: Arc::new(DashMap::with_capacity_and_hasher_and_shard_amount(
-                10_000,
-                hasher::RandomizingHasherBuilder,
-                1024,
-            )),
-            chunk_hashes: Arc::new(DashMap::with_capacity_and_hasher_and_shard_amount(
-                10_000,
-                hasher::RandomizingHasherBuilder,
-                1024,
-            )),
+            chunks: Arc::new(chunks),
+            chunk_hashes: Arc::new(chunk_hashes),
+            dirty: Arc::new(AtomicBool::new(true)),
 
             chunk_size,
             max_chunk_count,
@@ -105,6 +259,15 @@ impl ChunkIndex {
     pub fn open(
         directory: PathBuf,
         storage: Arc<dyn storage::ChunkStorage>,
+    ) -> std::io::Result<Self> {
+        Self::open_with_tuning(directory, storage, ChunkIndexTuning::default())
+    }
+
+    /// Opens an existing chunk index with custom map tuning.
+    pub fn open_with_tuning(
+        directory: PathBuf,
+        storage: Arc<dyn storage::ChunkStorage>,
+        tuning: ChunkIndexTuning,
     ) -> std::io::Result<Self> {
         let file = File::open(directory.join("index"))?;
         let mut decoder = DeflateDecoder::new(file);
@@ -127,19 +290,10 @@ impl ChunkIndex {
         let next_id = u64::from_le_bytes(buffer[24..32].try_into().map_err(map_err)?);
 
         let mut result_deleted_chunks = VecDeque::with_capacity(deleted_chunks);
-        let result_chunks = DashMap::with_capacity_and_hasher_and_shard_amount(
-            chunk_count,
-            hasher::RandomizingHasherBuilder,
-            1024,
-        );
-        let result_chunk_hashes = DashMap::with_capacity_and_hasher_and_shard_amount(
-            chunk_count,
-            hasher::RandomizingHasherBuilder,
-            1024,
-        );
+        let (result_chunks, result_chunk_hashes) = tuning.maps(chunk_count);
 
         for _ in 0..deleted_chunks {
-            let id = varint::decode_u64(&mut decoder)?;
+            let id = varint::decode_u64_checked(&mut decoder)?;
             result_deleted_chunks.push_back(id);
         }
 
@@ -149,8 +303,8 @@ impl ChunkIndex {
                 break;
             }
 
-            let id = varint::decode_u64(&mut decoder)?;
-            let count = varint::decode_u64(&mut decoder)?;
+            let id = varint::decode_u64_checked(&mut decoder)?;
+            let count = varint::decode_u64_checked(&mut decoder)?;
 
             result_chunks.insert(id, (buffer, count));
             result_chunk_hashes.insert(buffer, id);
@@ -168,6 +322,7 @@ impl ChunkIndex {
             deleted_chunks: Arc::new(Mutex::new(result_deleted_chunks)),
             chunks: Arc::new(result_chunks),
             chunk_hashes: Arc::new(result_chunk_hashes),
+            dirty: Arc::new(AtomicBool::new(false)),
 
             chunk_size,
             max_chunk_count,
@@ -184,18 +339,8 @@ impl ChunkIndex {
     ) -> std::io::Result<Self> {
         let chunk_hashes_on_disk: Vec<ChunkHash> = storage.list_chunk_hashes()?;
 
-        let chunks: DashMap<u64, (ChunkHash, u64), hasher::RandomizingHasherBuilder> =
-            DashMap::with_capacity_and_hasher_and_shard_amount(
-                chunk_hashes_on_disk.len(),
-                hasher::RandomizingHasherBuilder,
-                1024,
-            );
-        let chunk_hashes_map: DashMap<ChunkHash, u64, hasher::RandomizingHasherBuilder> =
-            DashMap::with_capacity_and_hasher_and_shard_amount(
-                chunk_hashes_on_disk.len(),
-                hasher::RandomizingHasherBuilder,
-                1024,
-            );
+        let (chunks, chunk_hashes_map) =
+            ChunkIndexTuning::default().maps(chunk_hashes_on_disk.len());
 
         let old_id_to_hash = Self::try_recover_old_id_map(&directory);
 
@@ -218,23 +363,31 @@ impl ChunkIndex {
             }
         }
 
+        let mut references: HashMap<u64, u64> = HashMap::new();
         if archives_directory.exists() {
             for dir_entry in std::fs::read_dir(archives_directory)?.flatten() {
                 let path = dir_entry.path();
-                if path.extension().and_then(|e| e.to_str()) != Some("ddup") {
+                // checkpoints of interrupted creates reference chunks just like archives
+                if !matches!(
+                    path.extension().and_then(|e| e.to_str()),
+                    Some("ddup" | "partial")
+                ) {
                     continue;
                 }
 
-                let archive = match crate::archive::Archive::open(path) {
-                    Ok(a) => a,
-                    Err(_) => continue,
-                };
+                let entries =
+                    match crate::archive::Archive::open(path).and_then(|a| a.into_entries()) {
+                        Ok(entries) => entries,
+                        Err(_) => continue,
+                    };
 
-                Self::walk_archive_entries_for_refs(
-                    archive.into_entries(),
-                    &old_to_new_id,
-                    &chunks,
-                );
+                Self::walk_archive_entries_for_refs(entries, &old_to_new_id, &mut references)?;
+            }
+        }
+
+        for (chunk_id, count) in references {
+            if let Some(mut entry) = chunks.get_mut(&chunk_id) {
+                entry.value_mut().1 += count;
             }
         }
 
@@ -257,6 +410,7 @@ impl ChunkIndex {
             deleted_chunks: Arc::new(Mutex::new(VecDeque::new())),
             chunks: Arc::new(chunks),
             chunk_hashes: Arc::new(chunk_hashes_map),
+            dirty: Arc::new(AtomicBool::new(true)),
 
             chunk_size,
             max_chunk_count,
@@ -294,12 +448,12 @@ impl ChunkIndex {
                 break;
             }
 
-            let id = match crate::varint::decode_u64(&mut decoder) {
+            let id = match crate::varint::decode_u64_checked(&mut decoder) {
                 Ok(v) => v,
                 Err(_) => break,
             };
 
-            if crate::varint::decode_u64(&mut decoder).is_err() {
+            if crate::varint::decode_u64_checked(&mut decoder).is_err() {
                 map.insert(id, hash_buf);
                 break;
             }
@@ -313,31 +467,56 @@ impl ChunkIndex {
     fn walk_archive_entries_for_refs(
         entries: Vec<crate::archive::entries::Entry>,
         old_to_new_id: &HashMap<u64, u64>,
-        chunks: &DashMap<u64, (ChunkHash, u64), hasher::RandomizingHasherBuilder>,
-    ) {
+        references: &mut HashMap<u64, u64>,
+    ) -> std::io::Result<()> {
         for entry in entries {
             match entry {
-                crate::archive::entries::Entry::File(mut file_entry) => loop {
-                    let old_chunk_id = varint::decode_u64(&mut file_entry);
-                    let Ok(old_chunk_id) = old_chunk_id else {
-                        break;
-                    };
+                crate::archive::entries::Entry::File(mut file_entry) => {
+                    let mut ids = ids::ChunkIdDecoder::new(&file_entry);
 
-                    if let Some(&new_id) = old_to_new_id.get(&old_chunk_id)
-                        && let Some(mut e) = chunks.get_mut(&new_id)
-                    {
-                        e.value_mut().1 += 1;
+                    while let Ok(Some(old_chunk_id)) = ids.next_id(&mut file_entry) {
+                        if let Some(&new_id) = old_to_new_id.get(&old_chunk_id) {
+                            *references.entry(new_id).or_insert(0) += 1;
+                        }
                     }
-                },
+                }
                 crate::archive::entries::Entry::Directory(dir_entry) => {
-                    Self::walk_archive_entries_for_refs(dir_entry.entries, old_to_new_id, chunks);
+                    Self::walk_archive_entries_for_refs(
+                        dir_entry.into_entries()?,
+                        old_to_new_id,
+                        references,
+                    )?;
                 }
                 _ => {}
             }
         }
+
+        Ok(())
+    }
+
+    /// Writes the index to disk if it changed since it was loaded or last saved,
+    /// or regardless of that with `force`.
+    pub fn save(&self, force: bool) -> std::io::Result<()> {
+        // cleared up front, so changes made while saving are saved by the next call
+        if !self.dirty.swap(false, Ordering::SeqCst) && !force {
+            return Ok(());
+        }
+
+        self.write_index().inspect_err(|_| self.mark_dirty())
     }
 
-    pub fn save(&self) -> std::io::Result<()> {
+    /// Whether the index changed since it was loaded or last saved.
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    fn write_index(&self) -> std::io::Result<()> {
         let index_path = self.directory.join("index");
         let tmp_path = self.directory.join("index.tmp");
 
@@ -351,23 +530,18 @@ impl ChunkIndex {
             encoder.write_all(&(self.chunk_size as u32).to_le_bytes())?;
             encoder.write_all(&(self.max_chunk_count as u32).to_le_bytes())?;
             encoder.write_all(&(self.chunks.len() as u64).to_le_bytes())?;
-            encoder.write_all(
-                &self
-                    .next_id
-                    .load(std::sync::atomic::Ordering::Relaxed)
-                    .to_le_bytes(),
-            )?;
+            encoder.write_all(&self.next_id.load(Ordering::Relaxed).to_le_bytes())?;
 
             for id in deleted_chunks.iter() {
-                encoder.write_all(&varint::encode_u64(*id))?;
+                varint::write_u64(&mut encoder, *id)?;
             }
 
             for entry in self.chunks.iter() {
                 let (id, (chunk, count)) = entry.pair();
 
                 encoder.write_all(chunk)?;
-                encoder.write_all(&varint::encode_u64(*id))?;
-                encoder.write_all(&varint::encode_u64(*count))?;
+                varint::write_u64(&mut encoder, *id)?;
+                varint::write_u64(&mut encoder, *count)?;
             }
 
             let inner = encoder.finish()?;
@@ -400,9 +574,8 @@ impl ChunkIndex {
         0
     }
 
-    pub fn clean(&self, progress: DeletionProgressCallback) -> std::io::Result<()> {
-        let chunks_to_delete: Vec<_> = self
-            .chunks
+    fn unreferenced_chunks(&self) -> Vec<(u64, ChunkHash)> {
+        self.chunks
             .iter()
             .filter_map(|entry| {
                 let (id, (chunk, count)) = (entry.key(), entry.value());
@@ -412,29 +585,72 @@ impl ChunkIndex {
                     None
                 }
             })
-            .collect();
+            .collect()
+    }
+
+    /// Computes what `clean` would delete without touching the storage.
+    pub fn clean_dry_run(&self, progress: CleanProgressCallback) -> std::io::Result<CleanPlan> {
+        let mut plan = CleanPlan::default();
+
+        for (id, chunk) in self.unreferenced_chunks() {
+            let size = match self.storage.chunk_size(&chunk) {
+                Ok(size) => size,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+                Err(err) => return Err(err),
+            };
+
+            if let Some(f) = progress.clone() {
+                f(id, size);
+            }
+
+            plan.chunk_count += 1;
+            plan.bytes += size;
+        }
+
+        Ok(plan)
+    }
+
+    pub fn clean(&self, progress: CleanProgressCallback) -> std::io::Result<CleanPlan> {
+        let chunks_to_delete = self.unreferenced_chunks();
 
+        let mut plan = CleanPlan::default();
         let mut deleted_ids = Vec::with_capacity(chunks_to_delete.len());
 
         for (id, chunk) in chunks_to_delete {
+            // content that is already gone only has to leave the index
+            let size = match self.storage.chunk_size(&chunk) {
+                Ok(size) => Some(size),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err),
+            };
+
             if let Some(f) = progress.clone() {
-                f(id, true);
+                f(id, size.unwrap_or(0));
             }
 
-            self.storage.delete_chunk_content(&chunk)?;
+            if size.is_some() {
+                self.storage.delete_chunk_content(&chunk)?;
+            }
 
             self.chunk_hashes.remove(&chunk);
             self.chunks.remove(&id);
 
+            plan.chunk_count += 1;
+            plan.bytes += size.unwrap_or(0);
+
             deleted_ids.push(id);
         }
 
+        if !deleted_ids.is_empty() {
+            self.mark_dirty();
+        }
+
         let mut deleted_chunks = self.deleted_chunks.lock();
         for id in deleted_ids {
             deleted_chunks.push_back(id);
         }
 
-        Ok(())
+        Ok(plan)
     }
 
     #[inline]
@@ -448,6 +664,7 @@ impl ChunkIndex {
         }
 
         *count -= 1;
+        self.mark_dirty();
 
         if *count == 0 && clean {
             drop(entry);
@@ -498,19 +715,157 @@ impl ChunkIndex {
         }
     }
 
+    /// Returns a snapshot of all chunks in the index as `(id, hash, reference count)`.
+    pub fn chunk_entries(&self) -> Vec<(u64, ChunkHash, u64)> {
+        self.chunks
+            .iter()
+            .map(|entry| {
+                let (id, (chunk, count)) = (entry.key(), entry.value());
+                (*id, *chunk, *count)
+            })
+            .collect()
+    }
+
+    /// Computes the numbers of the index without touching the storage.
+    pub fn stats(&self) -> ChunkIndexStats {
+        let mut stats = ChunkIndexStats {
+            deleted_id_count: self.deleted_chunks.lock().len() as u64,
+            chunk_size: self.chunk_size,
+            max_chunk_count: self.max_chunk_count,
+            ..Default::default()
+        };
+
+        for entry in self.chunks.iter() {
+            stats.chunk_count += 1;
+            stats.total_references += entry.value().1;
+        }
+
+        stats
+    }
+
+    /// Returns the reference count of a chunk ID, `None` if it is not part of the index.
+    #[inline]
+    pub fn references_by_id(&self, chunk_id: u64) -> Option<u64> {
+        self.chunks.get(&chunk_id).map(|v| v.1)
+    }
+
+    /// Returns the IDs of deleted chunks that are queued for reuse.
+    #[inline]
+    pub fn deleted_chunk_ids(&self) -> Vec<u64> {
+        self.deleted_chunks.lock().iter().copied().collect()
+    }
+
+    /// Removes the given IDs from the reuse queue of deleted chunks.
+    pub fn forget_deleted_chunk_ids(&self, ids: &[u64]) {
+        let mut deleted_chunks = self.deleted_chunks.lock();
+        let count = deleted_chunks.len();
+
+        deleted_chunks.retain(|id| !ids.contains(id));
+        if deleted_chunks.len() != count {
+            self.mark_dirty();
+        }
+    }
+
+    /// Adds one reference per occurrence of a chunk ID, inserting chunks that are
+    /// not part of the index yet. Occurrences are counted locally first, so the
+    /// shared map is only locked once per unique chunk.
+    fn add_references(&self, chunk_ids: &[u64], chunks: &[ChunkHash]) {
+        if chunk_ids.is_empty() {
+            return;
+        }
+
+        let mut references: HashMap<u64, (ChunkHash, u64)> = HashMap::new();
+        for (chunk_id, hash) in chunk_ids.iter().zip(chunks) {
+            references.entry(*chunk_id).or_insert((*hash, 0)).1 += 1;
+        }
+
+        for (chunk_id, (hash, count)) in references {
+            self.chunks.entry(chunk_id).or_insert((hash, 0)).1 += count;
+        }
+
+        self.mark_dirty();
+    }
+
+    /// Overwrites the reference count of a chunk.
+    /// Returns `false` if the chunk ID is not part of the index.
+    pub fn set_references(&self, chunk_id: u64, count: u64) -> bool {
+        match self.chunks.get_mut(&chunk_id) {
+            Some(mut entry) => {
+                entry.value_mut().1 = count;
+                self.mark_dirty();
+
+                true
+            }
+            None => false,
+        }
+    }
+
     #[inline]
     pub fn get_chunk_id(&self, chunk: &ChunkHash) -> Option<u64> {
         self.chunk_hashes.get(chunk).map(|v| *v)
     }
 
+    #[inline]
+    pub fn get_chunk_hash(&self, chunk_id: u64) -> Option<ChunkHash> {
+        self.chunks.get(&chunk_id).map(|v| v.0)
+    }
+
+    /// Returns the size of a chunk in the storage, after compression.
+    pub fn stored_size(&self, chunk_id: u64) -> std::io::Result<u64> {
+        let chunk = self.get_chunk_hash(chunk_id).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Chunk ID {chunk_id} not found"),
+            )
+        })?;
+
+        self.storage.chunk_size(&chunk)
+    }
+
+    /// Reads the content of a chunk and checks it against the hash it is stored under.
+    /// Returns the size of the uncompressed content.
+    pub fn verify_chunk_id(&self, chunk_id: u64) -> std::io::Result<u64> {
+        let chunk = self.get_chunk_hash(chunk_id).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Chunk ID {chunk_id} not found"),
+            )
+        })?;
+
+        let mut reader = self.read_chunk_id_content(chunk_id)?;
+        let mut hasher = Blake2b::<U32>::new();
+        let mut buffer = [0; 8192];
+        let mut size = 0;
+
+        loop {
+            let bytes_read = reader.read(&mut buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..bytes_read]);
+            size += bytes_read as u64;
+        }
+
+        if hasher.finalize().as_slice() != chunk {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Chunk ID {chunk_id} content does not match its hash"),
+            ));
+        }
+
+        Ok(size)
+    }
+
     #[inline]
     fn next_id(&self) -> u64 {
+        self.mark_dirty();
+
         if let Some(id) = self.deleted_chunks.lock().pop_front() {
             return id;
         }
 
-        self.next_id
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        self.next_id.fetch_add(1, Ordering::Relaxed)
     }
 
     fn add_chunk(
@@ -533,21 +888,18 @@ impl ChunkIndex {
             return Ok(id);
         }
 
-        let mut final_data = vec![compression.encode()];
+        let mut final_data = COMPRESSION_BUFFER.take();
+        final_data.clear();
+        final_data.push(compression.encode());
 
         match compression {
             CompressionFormat::None => final_data.extend_from_slice(data),
+            CompressionFormat::Deflate => deflate_into(data, &mut final_data)?,
             CompressionFormat::Gzip => {
                 let mut encoder = GzEncoder::new(&mut final_data, flate2::Compression::default());
                 encoder.write_all(data)?;
                 encoder.finish()?;
             }
-            CompressionFormat::Deflate => {
-                let mut encoder =
-                    DeflateEncoder::new(&mut final_data, flate2::Compression::default());
-                encoder.write_all(data)?;
-                encoder.finish()?;
-            }
             #[cfg(feature = "brotli")]
             CompressionFormat::Brotli => {
                 let mut encoder = brotli::CompressorWriter::new(&mut final_data, 4096, 11, 22);
@@ -563,104 +915,185 @@ impl ChunkIndex {
             }
         }
 
-        self.storage
-            .write_chunk_content(chunk, Box::new(Cursor::new(final_data)))?;
+        let result = self.storage.write_chunk_bytes(chunk, &final_data);
+        COMPRESSION_BUFFER.set(final_data);
+        result?;
 
         Ok(id)
     }
 
-    pub fn chunk_file(
-        &self,
-        path: &PathBuf,
-        compression: CompressionFormat,
-        scope: Option<&rayon::Scope<'_>>,
-    ) -> std::io::Result<Vec<u64>> {
-        let file = File::open(path)?;
-        let len = file.metadata()?.len() as usize;
-
+    /// The chunk size and chunk count `chunk_file` splits a file of `len` bytes into.
+    pub fn file_chunk_layout(&self, len: usize) -> (usize, usize) {
         let mut chunk_size = self.chunk_size;
-        let mut chunk_count = len.div_ceil(chunk_size);
-        let mut chunk_threshold = 50;
         if self.max_chunk_count > 0 {
+            let mut chunk_count = len.div_ceil(chunk_size);
             while chunk_count > self.max_chunk_count {
                 chunk_count /= 2;
                 chunk_size *= 2;
             }
+        }
 
-            chunk_threshold = self.max_chunk_count / 2;
+        (chunk_size, len.div_ceil(chunk_size))
+    }
+
+    /// Checks whether the file at `path` has the content of `chunk_ids`, by splitting it
+    /// like `chunk_file` does and comparing the hashes of the pieces. Nothing is stored.
+    pub fn file_matches_chunks(&self, path: &Path, chunk_ids: &[u64]) -> std::io::Result<bool> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+
+        let (chunk_size, chunk_count) = self.file_chunk_layout(len);
+        if chunk_count != chunk_ids.len() {
+            return Ok(false);
         }
 
-        chunk_count = len.div_ceil(chunk_size);
+        let mut reader = file.take(len as u64);
+        let mut buffer = vec![0; chunk_size.min(len)];
+        let mut hasher = Blake2b::<U32>::new();
 
-        if chunk_count > chunk_threshold
-            && let Some(scope) = scope
-        {
-            let path = path.clone();
-            let self_clone = self.clone();
+        for chunk_id in chunk_ids {
+            let bytes_read = read_full(&mut reader, &mut buffer)?;
+            if bytes_read == 0 {
+                return Ok(false);
+            }
 
-            let (sender, receiver) = std::sync::mpsc::channel();
+            hasher.update(&buffer[..bytes_read]);
+            let hash = hasher.finalize_reset();
 
-            scope.spawn(move |_| {
-                match self_clone.chunk_file_parallel(&path, compression, chunk_size, chunk_count) {
-                    Ok(chunk_ids) => {
-                        let _ = sender.send(Ok(chunk_ids));
-                    }
-                    Err(e) => {
-                        let _ = sender.send(Err(e));
-                    }
-                }
-            });
+            if self
+                .get_chunk_hash(*chunk_id)
+                .is_none_or(|expected| expected[..] != hash[..])
+            {
+                return Ok(false);
+            }
+        }
 
-            return match receiver.recv() {
-                Ok(result) => result,
-                Err(_) => Err(std::io::Error::other(
-                    "Failed to receive result from parallel chunking task",
-                )),
-            };
+        Ok(true)
+    }
+
+    /// Chunks the file at `path` as it was when it was opened, adding new chunks to the
+    /// storage and a reference to every chunk. With a scope, files of many chunks are
+    /// read and chunked by several threads. Fails if the file shrinks while it is read.
+    pub fn chunk_file(
+        &self,
+        path: &PathBuf,
+        compression: CompressionFormat,
+        scope: Option<&rayon::Scope<'_>>,
+        progress: ChunkProgressCallback,
+    ) -> std::io::Result<Vec<u64>> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+
+        let (chunk_size, chunk_count) = self.file_chunk_layout(len);
+        let chunk_threshold = if self.max_chunk_count > 0 {
+            self.max_chunk_count / 2
+        } else {
+            50
+        };
+
+        // the workers are threads of their own, waiting for them on the scope instead
+        // of spawning onto it keeps a single threaded pool from deadlocking
+        if chunk_count > chunk_threshold && scope.is_some() {
+            return self.chunk_file_parallel(
+                path,
+                file,
+                len,
+                compression,
+                chunk_size,
+                chunk_count,
+                progress,
+            );
         }
 
-        let mut file = File::open(path)?;
+        let mut reader = file.take(len as u64);
         let mut chunks = Vec::with_capacity(chunk_count);
         let mut chunk_ids = Vec::with_capacity(chunk_count);
-        let mut buffer = vec![0; chunk_size];
         let mut hasher = Blake2b::<U32>::new();
+        let mut total = 0;
 
-        loop {
-            let bytes_read = read_full(&mut file, &mut buffer)?;
-            if bytes_read == 0 {
-                break;
-            }
-
-            hasher.update(&buffer[..bytes_read]);
+        let mut store = |content: &[u8]| -> std::io::Result<()> {
+            hasher.update(content);
             let hash = hasher.finalize_reset();
             let mut hash_array = [0; 32];
             hash_array.copy_from_slice(&hash);
 
-            chunk_ids.push(self.add_chunk(&hash_array, &buffer[..bytes_read], compression)?);
+            chunk_ids.push(self.add_chunk(&hash_array, content, compression)?);
             chunks.push(hash_array);
-        }
+            total += content.len();
+
+            if let Some(f) = &progress {
+                f(content.len() as u64);
+            }
+
+            Ok(())
+        };
 
-        for (i, chunk_id) in chunk_ids.iter().enumerate() {
-            let mut entry = self
-                .chunks
-                .entry(*chunk_id)
-                .or_insert_with(|| (chunks[i], 0));
+        if chunk_count <= 1 || len < PIPELINED_CHUNKING_SIZE {
+            let mut buffer = vec![0; chunk_size.min(len)];
+            loop {
+                let bytes_read = read_full(&mut reader, &mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
 
-            entry.1 += 1;
+                store(&buffer[..bytes_read])?;
+            }
+        } else {
+            // the next chunk is read while the current one is hashed, compressed and stored,
+            // two buffers are passed back and forth between the reader and this thread
+            std::thread::scope(|scope| -> std::io::Result<()> {
+                let (full_sender, full_receiver) = std::sync::mpsc::sync_channel(1);
+                let (empty_sender, empty_receiver) = std::sync::mpsc::channel::<Vec<u8>>();
+                for _ in 0..2 {
+                    let _ = empty_sender.send(vec![0; chunk_size]);
+                }
+
+                scope.spawn(move || {
+                    while let Ok(mut buffer) = empty_receiver.recv() {
+                        let result = match read_full(&mut reader, &mut buffer) {
+                            Ok(0) => break,
+                            Ok(bytes_read) => Ok((buffer, bytes_read)),
+                            Err(err) => Err(err),
+                        };
+
+                        let failed = result.is_err();
+                        if full_sender.send(result).is_err() || failed {
+                            break;
+                        }
+                    }
+                });
+
+                for result in full_receiver {
+                    let (buffer, bytes_read) = result?;
+                    store(&buffer[..bytes_read])?;
+
+                    let _ = empty_sender.send(buffer);
+                }
+
+                Ok(())
+            })?;
+        }
+
+        if total < len {
+            return Err(file_changed(path, len, total));
         }
 
+        self.add_references(&chunk_ids, &chunks);
+
         Ok(chunk_ids)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn chunk_file_parallel(
         &self,
-        path: &PathBuf,
+        path: &Path,
+        file: File,
+        file_size: usize,
         compression: CompressionFormat,
         chunk_size: usize,
         chunk_count: usize,
+        progress: ChunkProgressCallback,
     ) -> std::io::Result<Vec<u64>> {
-        let file_size = std::fs::metadata(path)?.len() as usize;
-
         let mut chunk_boundaries = VecDeque::with_capacity(chunk_count);
         for i in 0..chunk_count {
             let start = i * chunk_size;
@@ -679,7 +1112,8 @@ impl ChunkIndex {
 
         let threads = rayon::current_num_threads();
         let pool_size = threads.min(expected_chunks);
-        let path = path.clone();
+        let path = path.to_path_buf();
+        let file = Arc::new(file);
 
         let chunk_queue = Arc::new(Mutex::new(chunk_boundaries));
         let results = Arc::new(Mutex::new(Vec::with_capacity(expected_chunks)));
@@ -690,10 +1124,15 @@ impl ChunkIndex {
             let chunk_queue = Arc::clone(&chunk_queue);
             let results = Arc::clone(&results);
             let error = Arc::clone(&error);
+            let file = Arc::clone(&file);
             let path = path.clone();
             let self_clone = self.clone();
+            let progress = progress.clone();
 
             let handle = std::thread::spawn(move || {
+                // reused for every chunk of this worker, only the last one may be smaller
+                let mut buffer = Vec::new();
+
                 loop {
                     let (idx, start, end) = if let Some(chunk) = chunk_queue.lock().pop_front() {
                         chunk
@@ -705,24 +1144,15 @@ impl ChunkIndex {
                         continue;
                     }
 
-                    let run = || {
-                        let mut file = File::open(&path)?;
-                        file.seek(SeekFrom::Start(start as u64))?;
-
+                    let mut run = || {
                         let size = end - start;
-                        let mut buffer = vec![0; size];
-
-                        let bytes_read = read_full(&mut file, &mut buffer)?;
+                        buffer.resize(size, 0);
 
-                        if bytes_read == 0 && start < file_size {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::UnexpectedEof,
-                                format!("Read 0 bytes at position {start} (expected up to {size})"),
-                            ));
+                        let bytes_read = read_full_at(&file, start as u64, &mut buffer)?;
+                        if bytes_read < size {
+                            return Err(file_changed(&path, file_size, start + bytes_read));
                         }
 
-                        buffer.truncate(bytes_read);
-
                         let mut hasher = Blake2b::<U32>::new();
                         hasher.update(&buffer);
                         let hash = hasher.finalize();
@@ -732,6 +1162,10 @@ impl ChunkIndex {
 
                         let chunk_id = self_clone.add_chunk(&hash_array, &buffer, compression)?;
 
+                        if let Some(f) = &progress {
+                            f(buffer.len() as u64);
+                        }
+
                         Ok((idx, chunk_id, hash_array))
                     };
 
@@ -781,15 +1215,442 @@ impl ChunkIndex {
         }
         drop(results_lock);
 
-        for (i, chunk_id) in chunk_ids.iter().enumerate() {
-            let mut entry = self
-                .chunks
-                .entry(*chunk_id)
-                .or_insert_with(|| (chunks[i], 0));
+        self.add_references(&chunk_ids, &chunks);
+
+        Ok(chunk_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_directory;
+    use storage::ChunkStorage;
+
+    fn chunk_index(directory: &Path, chunk_size: usize, max_chunk_count: usize) -> ChunkIndex {
+        ChunkIndex::new(
+            directory.to_path_buf(),
+            chunk_size,
+            max_chunk_count,
+            Arc::new(storage::ChunkStorageMemory::default()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn file_chunk_layout_doubles_chunks_above_max_count() {
+        let directory = temp_directory("chunks-layout");
+
+        assert_eq!(
+            chunk_index(&directory, 16, 0).file_chunk_layout(100),
+            (16, 7)
+        );
+        assert_eq!(
+            chunk_index(&directory, 16, 4).file_chunk_layout(100),
+            (32, 4)
+        );
+        assert_eq!(chunk_index(&directory, 16, 4).file_chunk_layout(0), (16, 0));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn file_matches_chunks_compares_content() {
+        let directory = temp_directory("chunks-matches");
+        let index = chunk_index(&directory, 16, 0);
+
+        let path = directory.join("file");
+        std::fs::write(&path, [7; 100]).unwrap();
+        let chunk_ids = index
+            .chunk_file(&path, CompressionFormat::None, None, None)
+            .unwrap();
 
-            entry.1 += 1;
+        assert_eq!(chunk_ids.len(), 7);
+        assert!(index.file_matches_chunks(&path, &chunk_ids).unwrap());
+
+        let mut content = [7; 100];
+        content[99] = 8;
+        std::fs::write(&path, content).unwrap();
+        assert!(!index.file_matches_chunks(&path, &chunk_ids).unwrap());
+
+        std::fs::write(&path, [7; 80]).unwrap();
+        assert!(!index.file_matches_chunks(&path, &chunk_ids).unwrap());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn serial_chunking_reads_every_chunk() {
+        let directory = temp_directory("chunks-serial");
+        let index = chunk_index(&directory, 64 * 1024, 0);
+
+        for length in [0, 1000, 300 * 1024, PIPELINED_CHUNKING_SIZE + 1000] {
+            let path = directory.join(format!("file-{length}"));
+            let content = (0..length).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+            std::fs::write(&path, &content).unwrap();
+
+            let chunk_ids = index
+                .chunk_file(&path, CompressionFormat::None, None, None)
+                .unwrap();
+            assert_eq!(
+                chunk_ids.len(),
+                length.div_ceil(64 * 1024),
+                "length {length}"
+            );
+            assert!(index.file_matches_chunks(&path, &chunk_ids).unwrap());
+
+            let mut restored = Vec::new();
+            for chunk_id in chunk_ids {
+                index
+                    .read_chunk_id_content(chunk_id)
+                    .unwrap()
+                    .read_to_end(&mut restored)
+                    .unwrap();
+            }
+            assert_eq!(restored, content);
         }
 
-        Ok(chunk_ids)
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    /// The memory storage without its own `chunk_size`, to test the default.
+    #[derive(Default)]
+    struct DefaultSizeStorage(storage::ChunkStorageMemory);
+
+    impl ChunkStorage for DefaultSizeStorage {
+        fn read_chunk_content(
+            &self,
+            chunk: &ChunkHash,
+        ) -> std::io::Result<Box<dyn std::io::Read + Send>> {
+            self.0.read_chunk_content(chunk)
+        }
+
+        fn write_chunk_content(
+            &self,
+            chunk: &ChunkHash,
+            content: Box<dyn std::io::Read + Send>,
+        ) -> std::io::Result<()> {
+            self.0.write_chunk_content(chunk, content)
+        }
+
+        fn delete_chunk_content(&self, chunk: &ChunkHash) -> std::io::Result<()> {
+            self.0.delete_chunk_content(chunk)
+        }
+
+        fn list_chunk_hashes(&self) -> std::io::Result<Vec<ChunkHash>> {
+            self.0.list_chunk_hashes()
+        }
+    }
+
+    #[test]
+    fn clean_drops_chunks_missing_from_the_storage() {
+        let directory = temp_directory("chunks-clean-missing");
+        let storage = Arc::new(DefaultSizeStorage::default());
+        let index = ChunkIndex::new(directory.clone(), 16, 0, storage.clone()).unwrap();
+
+        let path = directory.join("file");
+        std::fs::write(&path, [[1; 16], [2; 16]].concat()).unwrap();
+        let chunk_ids = index
+            .chunk_file(&path, CompressionFormat::None, None, None)
+            .unwrap();
+        assert_eq!(chunk_ids.len(), 2);
+
+        let [missing, present] = [0, 1].map(|i| index.get_chunk_hash(chunk_ids[i]).unwrap());
+        let size = storage.0.chunk_size(&present).unwrap();
+        assert_eq!(storage.chunk_size(&present).unwrap(), size);
+
+        for &chunk_id in chunk_ids.iter() {
+            index.set_references(chunk_id, 0);
+        }
+        storage.delete_chunk_content(&missing).unwrap();
+
+        let plan = index.clean_dry_run(None).unwrap();
+        assert_eq!((plan.chunk_count, plan.bytes), (2, size));
+
+        let plan = index.clean(None).unwrap();
+        assert_eq!((plan.chunk_count, plan.bytes), (2, size));
+        assert!(index.get_chunk_hash(chunk_ids[0]).is_none());
+        assert!(storage.list_chunk_hashes().unwrap().is_empty());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn files_that_shrink_while_chunked_fail() {
+        let directory = temp_directory("chunks-shrink");
+
+        // serially, serially with the next chunk read ahead and by several threads
+        for (chunk_size, length, parallel) in [
+            (64, 1000, false),
+            (64 * 1024, PIPELINED_CHUNKING_SIZE + 1000, false),
+            (64, 64 * 200, true),
+        ] {
+            let index = chunk_index(&directory, chunk_size, 0);
+            let path = directory.join(format!("file-{length}"));
+            std::fs::write(&path, vec![1; length]).unwrap();
+
+            let truncated = Arc::new(AtomicBool::new(false));
+            let progress: ChunkProgressCallback = Some(Arc::new({
+                let path = path.clone();
+                let truncated = Arc::clone(&truncated);
+                move |_| {
+                    if !truncated.swap(true, Ordering::SeqCst) {
+                        File::options()
+                            .write(true)
+                            .open(&path)
+                            .unwrap()
+                            .set_len(length as u64 / 2)
+                            .unwrap();
+                    }
+                }
+            }));
+
+            let result = if parallel {
+                rayon::scope(|scope| {
+                    index.chunk_file(&path, CompressionFormat::None, Some(scope), progress)
+                })
+            } else {
+                index.chunk_file(&path, CompressionFormat::None, None, progress)
+            };
+
+            let err = result.unwrap_err();
+            assert!(
+                err.to_string().contains("changed during backup"),
+                "length {length}: {err}"
+            );
+            // nothing is referenced for a file that failed
+            assert_eq!(index.stats().total_references, 0, "length {length}");
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn files_that_grow_while_chunked_keep_their_length() {
+        let directory = temp_directory("chunks-grow");
+        let index = chunk_index(&directory, 64, 0);
+
+        let path = directory.join("file");
+        std::fs::write(&path, [1; 1000]).unwrap();
+
+        let progress: ChunkProgressCallback = Some(Arc::new({
+            let path = path.clone();
+            move |_| {
+                File::options()
+                    .append(true)
+                    .open(&path)
+                    .unwrap()
+                    .write_all(&[2; 64])
+                    .unwrap();
+            }
+        }));
+
+        let chunk_ids = index
+            .chunk_file(&path, CompressionFormat::None, None, progress)
+            .unwrap();
+        assert_eq!(chunk_ids.len(), 1000usize.div_ceil(64));
+
+        let mut restored = Vec::new();
+        for chunk_id in chunk_ids {
+            index
+                .read_chunk_id_content(chunk_id)
+                .unwrap()
+                .read_to_end(&mut restored)
+                .unwrap();
+        }
+        assert_eq!(restored, [1; 1000]);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn index_is_only_saved_when_changed() {
+        let directory = temp_directory("chunks-dirty");
+        let storage = Arc::new(storage::ChunkStorageMemory::default());
+        let index_path = directory.join("index");
+
+        let index = ChunkIndex::new(directory.clone(), 64, 0, storage.clone()).unwrap();
+        assert!(index.is_dirty());
+        index.save(false).unwrap();
+        assert!(!index.is_dirty());
+        assert!(index_path.exists());
+
+        let path = directory.join("file");
+        std::fs::write(&path, [1; 1000]).unwrap();
+        let chunk_ids = index
+            .chunk_file(&path, CompressionFormat::Deflate, None, None)
+            .unwrap();
+        assert!(index.is_dirty());
+        index.save(false).unwrap();
+        drop(index);
+
+        // reading leaves an opened index clean, saving it writes nothing
+        let index = ChunkIndex::open(directory.clone(), storage.clone()).unwrap();
+        assert!(!index.is_dirty());
+        index
+            .read_chunk_id_content(chunk_ids[0])
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap();
+        assert!(index.file_matches_chunks(&path, &chunk_ids).unwrap());
+        assert!(!index.is_dirty());
+
+        std::fs::remove_file(&index_path).unwrap();
+        index.save(false).unwrap();
+        assert!(!index_path.exists());
+
+        index.save(true).unwrap();
+        assert!(index_path.exists());
+
+        std::fs::remove_file(&index_path).unwrap();
+        index.set_references(chunk_ids[0], 1);
+        assert!(index.is_dirty());
+        index.save(false).unwrap();
+        assert!(index_path.exists());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn tuning_does_not_change_the_index() {
+        let default = ChunkIndexTuning::default();
+        assert!(default.shard_amount.is_power_of_two());
+        assert!((4..=1024).contains(&default.shard_amount));
+
+        let directory = temp_directory("chunks-tuning");
+        let storage = Arc::new(storage::ChunkStorageMemory::default());
+
+        let index = ChunkIndex::new_with_tuning(
+            directory.clone(),
+            64,
+            0,
+            storage.clone(),
+            ChunkIndexTuning {
+                shard_amount: 1,
+                capacity: 0,
+            },
+        )
+        .unwrap();
+
+        let path = directory.join("file");
+        let content = (0..10_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &content).unwrap();
+        let chunk_ids = index
+            .chunk_file(&path, CompressionFormat::None, None, None)
+            .unwrap();
+        index.save(true).unwrap();
+
+        for tuning in [
+            ChunkIndexTuning {
+                shard_amount: 3,
+                capacity: 1,
+            },
+            ChunkIndexTuning {
+                shard_amount: 1024,
+                capacity: 1 << 16,
+            },
+            default,
+        ] {
+            let opened =
+                ChunkIndex::open_with_tuning(directory.clone(), storage.clone(), tuning).unwrap();
+
+            let (stats, opened_stats) = (index.stats(), opened.stats());
+            assert_eq!(opened_stats.chunk_count, stats.chunk_count, "{tuning:?}");
+            assert_eq!(
+                opened_stats.total_references, stats.total_references,
+                "{tuning:?}"
+            );
+            for &chunk_id in &chunk_ids {
+                assert_eq!(
+                    opened.get_chunk_hash(chunk_id),
+                    index.get_chunk_hash(chunk_id)
+                );
+            }
+            assert!(opened.file_matches_chunks(&path, &chunk_ids).unwrap());
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn reused_compression_state_produces_the_same_chunks() {
+        let large = (0..300_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect::<Vec<_>>();
+        let inputs = [large.clone(), b"small".to_vec(), Vec::new(), large];
+
+        // the deflate state and the output buffer are reused from one call to the next
+        let mut output = Vec::new();
+        for input in &inputs {
+            output.clear();
+            output.push(0xff);
+            deflate_into(input, &mut output).unwrap();
+
+            let mut encoder = DeflateEncoder::new(vec![0xff], flate2::Compression::default());
+            encoder.write_all(input).unwrap();
+            assert_eq!(output, encoder.finish().unwrap());
+        }
+
+        let directory = temp_directory("chunks-compression-buffer");
+        let index = chunk_index(&directory, 1024 * 1024, 0);
+
+        for compression in [
+            CompressionFormat::Deflate,
+            CompressionFormat::Gzip,
+            CompressionFormat::None,
+        ] {
+            for (i, input) in inputs.iter().enumerate() {
+                let path = directory.join(format!("file-{}-{i}", compression.encode()));
+                let mut content = input.clone();
+                // keep every chunk new, equal chunks would not be compressed again
+                content.push(compression.encode());
+                content.push(i as u8);
+                std::fs::write(&path, &content).unwrap();
+
+                let chunk_ids = index.chunk_file(&path, compression, None, None).unwrap();
+
+                let mut restored = Vec::new();
+                index
+                    .read_chunk_id_content(chunk_ids[0])
+                    .unwrap()
+                    .read_to_end(&mut restored)
+                    .unwrap();
+                assert_eq!(restored, content);
+            }
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn duplicate_chunks_are_referenced_once_per_occurrence() {
+        let directory = temp_directory("chunks-references");
+        let index = chunk_index(&directory, 64, 0);
+
+        // two distinct chunks, alternating, 200 chunks in total
+        let path = directory.join("file");
+        let content = (0..200)
+            .flat_map(|i| [(i % 2) as u8; 64])
+            .collect::<Vec<_>>();
+        std::fs::write(&path, &content).unwrap();
+
+        let chunk_ids = index
+            .chunk_file(&path, CompressionFormat::None, None, None)
+            .unwrap();
+        assert_eq!(chunk_ids.len(), 200);
+        assert_eq!(index.stats().chunk_count, 2);
+        assert_eq!(index.references_by_id(chunk_ids[0]), Some(100));
+        assert_eq!(index.references_by_id(chunk_ids[1]), Some(100));
+
+        let parallel_ids = rayon::scope(|scope| {
+            index.chunk_file(&path, CompressionFormat::None, Some(scope), None)
+        })
+        .unwrap();
+        assert_eq!(parallel_ids, chunk_ids);
+        assert_eq!(index.references_by_id(chunk_ids[0]), Some(200));
+        assert_eq!(index.references_by_id(chunk_ids[1]), Some(200));
+        assert_eq!(index.stats().total_references, 400);
+
+        std::fs::remove_dir_all(directory).unwrap();
     }
 }
@@ -1,77 +1,574 @@
-use super::ChunkIndex;
+use super::{ChunkIndex, ids::ChunkIdDecoder};
 use crate::archive::entries::FileEntry;
-use std::io::Read;
+use rayon::prelude::*;
+use std::{
+    collections::VecDeque,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+/// Files of at least this size are read ahead in parallel by `EntryReader::parallel`.
+pub const PARALLEL_READ_THRESHOLD: u64 = 16 * 1024 * 1024;
+/// The most decompressed chunk content `EntryReader::parallel` reads ahead.
+pub const READ_AHEAD_BUDGET: usize = 256 * 1024 * 1024;
+
+/// The default size of the buffer `EntryReader::copy_to` streams chunks through.
+pub const DEFAULT_COPY_BUFFER_SIZE: usize = 256 * 1024;
 
 pub struct EntryReader {
     pub entry: Box<FileEntry>,
     pub chunk_index: ChunkIndex,
 
+    ids: ChunkIdDecoder,
     finished: bool,
+
+    /// The decompressed content of the chunk currently being read, streamed as it is read.
+    current: Option<Box<dyn Read + Send>>,
+    /// How many bytes of the current chunk were read so far.
+    current_read: u64,
+
+    /// A chunk that was read ahead and is being served from memory.
     buffer: Vec<u8>,
     buffer_pos: usize,
+
+    position: u64,
+    /// The size of every chunk of the file but the last, known once the first chunk was read.
+    chunk_size: Option<u64>,
+
+    /// How many chunks are fetched and decompressed at once, in parallel if more than one.
+    read_ahead: usize,
+    /// Chunks read ahead, in the order of the file.
+    pending: VecDeque<Vec<u8>>,
+
+    /// The size of the buffer `copy_to` streams chunks through.
+    copy_buffer_size: usize,
 }
 
 impl EntryReader {
     pub fn new(entry: Box<FileEntry>, chunk_index: ChunkIndex) -> Self {
         Self {
+            ids: ChunkIdDecoder::new(&entry),
             entry,
             chunk_index,
             finished: false,
+            current: None,
+            current_read: 0,
             buffer: Vec::new(),
             buffer_pos: 0,
+            position: 0,
+            chunk_size: None,
+            read_ahead: 1,
+            pending: VecDeque::new(),
+            copy_buffer_size: DEFAULT_COPY_BUFFER_SIZE,
         }
     }
 
-    fn fill_buffer(&mut self) -> std::io::Result<()> {
-        if self.finished {
-            return Ok(());
-        }
+    /// Like `new`, but files of at least `PARALLEL_READ_THRESHOLD` bytes are read ahead
+    /// by as many chunks as the current rayon pool has threads, fetched and decompressed
+    /// in parallel on that pool. At most `READ_AHEAD_BUDGET` bytes are read ahead.
+    pub fn parallel(entry: Box<FileEntry>, chunk_index: ChunkIndex) -> Self {
+        let read_ahead = if entry.size_real >= PARALLEL_READ_THRESHOLD {
+            rayon::current_num_threads()
+                .min(READ_AHEAD_BUDGET / chunk_index.chunk_size.max(1))
+                .max(1)
+        } else {
+            1
+        };
+
+        let mut reader = Self::new(entry, chunk_index);
+        reader.set_read_ahead(read_ahead);
+
+        reader
+    }
+
+    /// Sets how many chunks are fetched and decompressed at once, in parallel on the
+    /// current rayon pool if more than one. Each of them is held in memory until read,
+    /// with a single chunk the content is streamed without being held in memory.
+    #[inline]
+    pub fn set_read_ahead(&mut self, chunks: usize) {
+        self.read_ahead = chunks.max(1);
+    }
+
+    /// Sets the size of the buffer `copy_to` streams chunks through, it is allocated once
+    /// per call and reused for every chunk. Defaults to `DEFAULT_COPY_BUFFER_SIZE`.
+    #[inline]
+    pub fn set_copy_buffer_size(&mut self, size: usize) {
+        self.copy_buffer_size = size.max(1);
+    }
+
+    /// The number of bytes read or skipped so far.
+    #[inline]
+    pub fn position(&self) -> u64 {
+        self.position
+    }
 
-        if self.buffer_pos < self.buffer.len() {
-            return Ok(());
+    /// The number of bytes left until the end of the file.
+    #[inline]
+    pub fn remaining(&self) -> u64 {
+        self.entry.size_real.saturating_sub(self.position)
+    }
+
+    /// Starts over at the beginning of the file.
+    pub fn reset(&mut self) {
+        *self.entry = (*self.entry).clone();
+        self.ids = ChunkIdDecoder::new(&self.entry);
+        self.finished = false;
+        self.current = None;
+        self.current_read = 0;
+        self.buffer.clear();
+        self.buffer_pos = 0;
+        self.pending.clear();
+        self.position = 0;
+    }
+
+    /// Skips up to `n` bytes, returning how many were skipped. Whole chunks are skipped
+    /// by only advancing in the chunk ID list, only the chunk the new position falls
+    /// into is read.
+    pub fn skip(&mut self, n: u64) -> std::io::Result<u64> {
+        let n = n.min(self.entry.size_real.saturating_sub(self.position));
+        let mut remaining = n;
+        let mut discard = [0; 8192];
+
+        while remaining > 0 {
+            let buffered = ((self.buffer.len() - self.buffer_pos) as u64).min(remaining);
+            if buffered > 0 {
+                self.buffer_pos += buffered as usize;
+                self.position += buffered;
+                remaining -= buffered;
+
+                continue;
+            }
+
+            if self.current.is_some() {
+                let length = (discard.len() as u64).min(remaining) as usize;
+                let bytes_read = self.read_current(&mut discard[..length])?;
+                remaining -= bytes_read as u64;
+
+                continue;
+            }
+
+            // the last chunk may be shorter, but then less than a chunk remains to skip
+            if let Some(chunk_size) = self.chunk_size
+                && remaining >= chunk_size
+                && self.pending.is_empty()
+                && !self.finished
+            {
+                if self.ids.next_id(&mut self.entry)?.is_none() {
+                    self.finished = true;
+                    break;
+                }
+
+                self.position += chunk_size;
+                remaining -= chunk_size;
+
+                continue;
+            }
+
+            if !self.next_chunk()? {
+                break;
+            }
         }
 
+        Ok(n - remaining)
+    }
+
+    /// Moves on to the next chunk, either one read ahead or a stream of the next chunk ID.
+    /// Returns `false` at the end of the file.
+    fn next_chunk(&mut self) -> std::io::Result<bool> {
+        self.current = None;
         self.buffer.clear();
         self.buffer_pos = 0;
 
-        let chunk_id = match crate::varint::decode_u64(&mut self.entry) {
-            Ok(id) => id,
-            Err(_) => {
-                self.finished = true;
-                return Ok(());
+        if self.finished {
+            return Ok(false);
+        }
+
+        if self.pending.is_empty() && self.read_ahead > 1 {
+            self.read_ahead()?;
+        }
+
+        if let Some(chunk) = self.pending.pop_front() {
+            self.buffer = chunk;
+            if self.chunk_size.is_none() {
+                self.chunk_size = Some(self.buffer.len() as u64);
             }
+
+            return Ok(true);
+        }
+
+        let Some(chunk_id) = self.ids.next_id(&mut self.entry)? else {
+            self.finished = true;
+            return Ok(false);
         };
 
-        let mut chunk = self.chunk_index.read_chunk_id_content(chunk_id)?;
-        chunk.read_to_end(&mut self.buffer)?;
+        self.current = Some(self.chunk_index.read_chunk_id_content(chunk_id)?);
+        self.current_read = 0;
+
+        Ok(true)
+    }
+
+    /// Reads from the chunk currently streamed, dropping it at its end.
+    fn read_current(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(current) = self.current.as_mut() else {
+            return Ok(0);
+        };
+
+        let bytes_read = current.read(buf)?;
+        if bytes_read == 0 && !buf.is_empty() {
+            self.current = None;
+            if self.chunk_size.is_none() {
+                self.chunk_size = Some(self.current_read);
+            }
+        }
+
+        self.current_read += bytes_read as u64;
+        self.position += bytes_read as u64;
+
+        Ok(bytes_read)
+    }
+
+    /// Fetches and decompresses the next `read_ahead` chunks in parallel.
+    fn read_ahead(&mut self) -> std::io::Result<()> {
+        let mut chunk_ids = Vec::with_capacity(self.read_ahead);
+        while chunk_ids.len() < self.read_ahead
+            && let Some(chunk_id) = self.ids.next_id(&mut self.entry)?
+        {
+            chunk_ids.push(chunk_id);
+        }
+
+        let chunk_index = &self.chunk_index;
+        let chunks = chunk_ids
+            .into_par_iter()
+            .map(|chunk_id| {
+                let mut content = Vec::new();
+                chunk_index
+                    .read_chunk_id_content(chunk_id)?
+                    .read_to_end(&mut content)?;
+
+                Ok(content)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        self.pending.extend(chunks);
+        if self.pending.is_empty() {
+            self.finished = true;
+        }
 
         Ok(())
     }
+
+    /// Writes the rest of the file to `writer`, calling `written` with the size of every
+    /// write. Chunks read ahead are written whole, streamed chunks through a fixed buffer.
+    /// Returns the number of bytes written.
+    pub fn copy_to<W: Write>(
+        &mut self,
+        writer: &mut W,
+        mut written: impl FnMut(u64),
+    ) -> std::io::Result<u64> {
+        let mut total = 0;
+        let mut copy_buffer = Vec::new();
+
+        loop {
+            let length = if self.buffer_pos < self.buffer.len() {
+                let chunk = &self.buffer[self.buffer_pos..];
+                writer.write_all(chunk)?;
+
+                self.buffer_pos = self.buffer.len();
+                self.position += chunk.len() as u64;
+
+                chunk.len()
+            } else if self.current.is_some() {
+                if copy_buffer.is_empty() {
+                    copy_buffer = vec![0; self.copy_buffer_size];
+                }
+
+                let bytes_read = self.read_current(&mut copy_buffer)?;
+                writer.write_all(&copy_buffer[..bytes_read])?;
+
+                bytes_read
+            } else if self.next_chunk()? {
+                continue;
+            } else {
+                return Ok(total);
+            };
+
+            if length > 0 {
+                total += length as u64;
+                written(length as u64);
+            }
+        }
+    }
 }
 
 impl Read for EntryReader {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.buffer_pos >= self.buffer.len() {
-            self.fill_buffer()?;
+        if buf.is_empty() {
+            return Ok(0);
         }
 
-        if self.buffer_pos >= self.buffer.len() {
-            return Ok(0);
+        loop {
+            if self.buffer_pos < self.buffer.len() {
+                let bytes_to_copy = (self.buffer.len() - self.buffer_pos).min(buf.len());
+                buf[..bytes_to_copy].copy_from_slice(
+                    &self.buffer[self.buffer_pos..self.buffer_pos + bytes_to_copy],
+                );
+
+                self.buffer_pos += bytes_to_copy;
+                self.position += bytes_to_copy as u64;
+
+                return Ok(bytes_to_copy);
+            }
+
+            if self.current.is_some() {
+                let bytes_read = self.read_current(buf)?;
+                if bytes_read > 0 {
+                    return Ok(bytes_read);
+                }
+
+                continue;
+            }
+
+            if !self.next_chunk()? {
+                return Ok(0);
+            }
         }
+    }
+}
 
-        let bytes_available = self.buffer.len() - self.buffer_pos;
-        let bytes_to_copy = std::cmp::min(bytes_available, buf.len());
+/// Seeking forward skips, seeking backward starts over and skips from the beginning.
+/// Positions past the end of the file are clamped to the end.
+impl Seek for EntryReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.entry.size_real.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Cannot seek before the start of the file",
+            )
+        })?;
+
+        if target < self.position {
+            self.reset();
+        }
 
-        buf[..bytes_to_copy]
-            .copy_from_slice(&self.buffer[self.buffer_pos..self.buffer_pos + bytes_to_copy]);
+        self.skip(target - self.position)?;
 
-        self.buffer_pos += bytes_to_copy;
+        Ok(self.position)
+    }
+
+    #[inline]
+    fn stream_position(&mut self) -> std::io::Result<u64> {
+        Ok(self.position)
+    }
+}
 
-        if bytes_to_copy < buf.len() && self.buffer_pos >= self.buffer.len() && !self.finished {
-            let additional_bytes = self.read(&mut buf[bytes_to_copy..])?;
-            return Ok(bytes_to_copy + additional_bytes);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        archive::entries::Entry,
+        repository::{CreateOptions, Repository},
+        test_util::temp_path,
+    };
+    use std::path::Path;
+
+    /// Backs up a file of 1000 bytes in chunks of 64 and returns readers for it,
+    /// streaming and reading ahead.
+    fn readers(name: &str) -> (std::path::PathBuf, Vec<u8>, Vec<EntryReader>) {
+        let directory = temp_path(&format!("reader-{name}"));
+
+        let source = directory.join("source");
+        std::fs::create_dir_all(&source).unwrap();
+        let content = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(source.join("file"), &content).unwrap();
+
+        let repository = Repository::new(&directory.join("repository"), 64, 0, None).unwrap();
+        repository
+            .create_archive(
+                "archive",
+                Some(ignore::WalkBuilder::new(&source).build()),
+                Some(&source),
+                None,
+                None,
+                1,
+                CreateOptions::default(),
+            )
+            .unwrap();
+        let archive = repository.get_archive("archive").unwrap();
+
+        let Some(Entry::File(entry)) = archive.find_archive_entry(Path::new("file")).unwrap()
+        else {
+            panic!("expected a file entry");
+        };
+
+        let streaming = EntryReader::new(entry.clone(), repository.chunk_index.clone());
+        let mut read_ahead = EntryReader::new(entry.clone(), repository.chunk_index.clone());
+        read_ahead.set_read_ahead(4);
+
+        (directory, content, vec![streaming, read_ahead])
+    }
+
+    fn read(reader: &mut EntryReader, length: usize) -> Vec<u8> {
+        let mut buffer = vec![0; length];
+        let bytes_read = read_full(reader, &mut buffer);
+        buffer.truncate(bytes_read);
+
+        buffer
+    }
+
+    fn read_full(reader: &mut EntryReader, buffer: &mut [u8]) -> usize {
+        let mut total = 0;
+        while total < buffer.len() {
+            match reader.read(&mut buffer[total..]).unwrap() {
+                0 => break,
+                n => total += n,
+            }
         }
 
-        Ok(bytes_to_copy)
+        total
+    }
+
+    #[test]
+    fn skip_lands_on_the_right_byte() {
+        let (directory, content, readers) = readers("skip");
+
+        for mut reader in readers {
+            assert_eq!(reader.skip(200).unwrap(), 200);
+            assert_eq!(reader.position(), 200);
+            assert_eq!(read(&mut reader, 10), content[200..210]);
+
+            // within the current chunk and across several chunks
+            assert_eq!(reader.skip(5).unwrap(), 5);
+            assert_eq!(read(&mut reader, 3), content[215..218]);
+            assert_eq!(reader.skip(500).unwrap(), 500);
+            assert_eq!(read(&mut reader, 64), content[718..782]);
+
+            assert_eq!(reader.skip(10_000).unwrap(), 218);
+            assert_eq!(reader.remaining(), 0);
+            assert!(read(&mut reader, 1).is_empty());
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn seek_forward_backward_and_from_the_end() {
+        let (directory, content, readers) = readers("seek");
+
+        for mut reader in readers {
+            assert_eq!(reader.seek(SeekFrom::Start(640)).unwrap(), 640);
+            assert_eq!(read(&mut reader, 4), content[640..644]);
+
+            assert_eq!(reader.seek(SeekFrom::Start(63)).unwrap(), 63);
+            assert_eq!(read(&mut reader, 2), content[63..65]);
+
+            assert_eq!(reader.seek(SeekFrom::Current(-65)).unwrap(), 0);
+            assert_eq!(read(&mut reader, 1000), content);
+
+            assert_eq!(reader.seek(SeekFrom::End(-3)).unwrap(), 997);
+            assert_eq!(read(&mut reader, 10), content[997..]);
+
+            assert_eq!(reader.seek(SeekFrom::Start(5000)).unwrap(), 1000);
+            assert!(reader.seek(SeekFrom::Current(-2000)).is_err());
+
+            reader.reset();
+            assert_eq!(reader.position(), 0);
+            assert_eq!(read(&mut reader, 8), content[..8]);
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn reads_of_any_size_return_the_content() {
+        let (directory, content, readers) = readers("read-sizes");
+
+        for mut reader in readers {
+            for size in [1, 13, 64, 65, 10 * 1024 * 1024] {
+                reader.reset();
+
+                let mut read_content = Vec::new();
+                let mut buffer = vec![0; size];
+                loop {
+                    match reader.read(&mut buffer).unwrap() {
+                        0 => break,
+                        n => read_content.extend_from_slice(&buffer[..n]),
+                    }
+                }
+
+                assert_eq!(read_content, content, "buffer of {size} bytes");
+            }
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn copy_to_writes_the_rest_with_any_buffer_size() {
+        let (directory, content, readers) = readers("copy-to");
+
+        for mut reader in readers {
+            for size in [1, 7, DEFAULT_COPY_BUFFER_SIZE] {
+                reader.reset();
+                reader.set_copy_buffer_size(size);
+                assert_eq!(reader.skip(100).unwrap(), 100);
+
+                let mut output = Vec::new();
+                let mut reported = 0;
+                let total = reader
+                    .copy_to(&mut output, |written| reported += written)
+                    .unwrap();
+
+                assert_eq!(output, content[100..], "buffer of {size} bytes");
+                assert_eq!(total, 900);
+                assert_eq!(reported, 900);
+                assert_eq!(reader.remaining(), 0);
+            }
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn parallel_reads_ahead_large_files_only() {
+        let (directory, content, readers) = readers("parallel");
+        let entry = readers[0].entry.clone();
+        let chunk_index = readers[0].chunk_index.clone();
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(3)
+            .build()
+            .unwrap();
+
+        let small = pool.install(|| EntryReader::parallel(entry.clone(), chunk_index.clone()));
+        assert_eq!(small.read_ahead, 1);
+
+        // only the size decides, the content is never read past the real chunks
+        let mut large_entry = entry.clone();
+        large_entry.size_real = PARALLEL_READ_THRESHOLD;
+        let large =
+            pool.install(|| EntryReader::parallel(large_entry.clone(), chunk_index.clone()));
+        assert_eq!(large.read_ahead, 3);
+
+        // a read ahead of a thread per chunk would exceed the budget
+        let mut huge_chunks = chunk_index.clone();
+        huge_chunks.chunk_size = READ_AHEAD_BUDGET / 2;
+        let budgeted = pool.install(|| EntryReader::parallel(large_entry, huge_chunks));
+        assert_eq!(budgeted.read_ahead, 2);
+
+        let mut reader = pool.install(|| {
+            let mut reader = EntryReader::parallel(entry, chunk_index);
+            reader.set_read_ahead(3);
+            reader
+        });
+        let mut output = Vec::new();
+        pool.install(|| reader.copy_to(&mut output, |_| {}))
+            .unwrap();
+        assert_eq!(output, content);
+
+        std::fs::remove_dir_all(directory).unwrap();
     }
 }
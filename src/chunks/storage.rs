@@ -1,8 +1,12 @@
 use super::ChunkHash;
+use dashmap::DashMap;
 use std::{
     io::Write,
-    path::PathBuf,
-    sync::atomic::{AtomicU64, Ordering},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
 pub trait ChunkStorage: Sync + Send {
@@ -33,9 +37,30 @@ pub trait ChunkStorage: Sync + Send {
         chunk: &ChunkHash,
         content: Box<dyn std::io::Read + Send>,
     ) -> std::io::Result<()>;
+
+    /// Like `write_chunk_content`, for content that is already in memory.
+    /// Storages that can write a borrowed slice without copying it should override this.
+    #[inline]
+    fn write_chunk_bytes(&self, chunk: &ChunkHash, content: &[u8]) -> std::io::Result<()> {
+        self.write_chunk_content(chunk, Box::new(std::io::Cursor::new(content.to_vec())))
+    }
+
     fn delete_chunk_content(&self, chunk: &ChunkHash) -> std::io::Result<()>;
 
+    /// Returns the size of the stored chunk content in bytes. The default reads the whole
+    /// chunk, storages that know the size without reading it should override this.
+    fn chunk_size(&self, chunk: &ChunkHash) -> std::io::Result<u64> {
+        std::io::copy(&mut self.read_chunk_content(chunk)?, &mut std::io::sink())
+    }
+
     fn list_chunk_hashes(&self) -> std::io::Result<Vec<ChunkHash>>;
+
+    /// Makes every chunk written so far durable. Storages that persist chunks as
+    /// they are written, like the local one, keep the default.
+    #[inline]
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 pub struct ChunkStorageLocal(pub PathBuf);
@@ -59,23 +84,13 @@ impl ChunkStorageLocal {
 
         Some(hash)
     }
-}
 
-impl ChunkStorage for ChunkStorageLocal {
-    fn read_chunk_content(
+    /// Writes a chunk to a temporary file with `write`, then moves it into place.
+    /// Chunks that already exist are left alone.
+    fn write_chunk_file(
         &self,
         chunk: &ChunkHash,
-    ) -> std::io::Result<Box<dyn std::io::Read + Send>> {
-        let path = self.0.join(self.path_from_chunk(chunk));
-        let file = std::fs::File::open(path)?;
-
-        Ok(Box::new(file))
-    }
-
-    fn write_chunk_content(
-        &self,
-        chunk: &ChunkHash,
-        mut content: Box<dyn std::io::Read + Send>,
+        write: impl FnOnce(&mut std::fs::File) -> std::io::Result<()>,
     ) -> std::io::Result<()> {
         let path = self.0.join(self.path_from_chunk(chunk));
         if let Some(parent) = path.parent() {
@@ -94,15 +109,7 @@ impl ChunkStorage for ChunkStorageLocal {
         let write_result = (|| {
             let mut file = std::fs::File::create(&tmp_path)?;
 
-            let mut buffer = [0; 4096];
-            loop {
-                let bytes_read = content.read(&mut buffer)?;
-                if bytes_read == 0 {
-                    break;
-                }
-                file.write_all(&buffer[..bytes_read])?;
-            }
-
+            write(&mut file)?;
             file.sync_all()?;
 
             Ok(())
@@ -117,6 +124,42 @@ impl ChunkStorage for ChunkStorageLocal {
 
         Ok(())
     }
+}
+
+impl ChunkStorage for ChunkStorageLocal {
+    fn read_chunk_content(
+        &self,
+        chunk: &ChunkHash,
+    ) -> std::io::Result<Box<dyn std::io::Read + Send>> {
+        let path = self.0.join(self.path_from_chunk(chunk));
+        let file = std::fs::File::open(path)?;
+
+        Ok(Box::new(file))
+    }
+
+    fn write_chunk_content(
+        &self,
+        chunk: &ChunkHash,
+        mut content: Box<dyn std::io::Read + Send>,
+    ) -> std::io::Result<()> {
+        self.write_chunk_file(chunk, |file| {
+            let mut buffer = [0; 4096];
+            loop {
+                let bytes_read = content.read(&mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                file.write_all(&buffer[..bytes_read])?;
+            }
+
+            Ok(())
+        })
+    }
+
+    #[inline]
+    fn write_chunk_bytes(&self, chunk: &ChunkHash, content: &[u8]) -> std::io::Result<()> {
+        self.write_chunk_file(chunk, |file| file.write_all(content))
+    }
 
     fn delete_chunk_content(&self, chunk: &ChunkHash) -> std::io::Result<()> {
         let mut path = self.0.join(self.path_from_chunk(chunk));
@@ -139,6 +182,12 @@ impl ChunkStorage for ChunkStorageLocal {
         Ok(())
     }
 
+    fn chunk_size(&self, chunk: &ChunkHash) -> std::io::Result<u64> {
+        let path = self.0.join(self.path_from_chunk(chunk));
+
+        Ok(std::fs::metadata(path)?.len())
+    }
+
     fn list_chunk_hashes(&self) -> std::io::Result<Vec<ChunkHash>> {
         let mut hashes = Vec::new();
 
@@ -205,3 +254,285 @@ impl ChunkStorage for ChunkStorageLocal {
         Ok(hashes)
     }
 }
+
+/// Keeps chunks in memory, nothing survives the process. Meant for tests.
+#[derive(Default)]
+pub struct ChunkStorageMemory(pub DashMap<ChunkHash, Arc<[u8]>>);
+
+impl ChunkStorage for ChunkStorageMemory {
+    fn read_chunk_content(
+        &self,
+        chunk: &ChunkHash,
+    ) -> std::io::Result<Box<dyn std::io::Read + Send>> {
+        let content = self.0.get(chunk).map(|c| Arc::clone(&c)).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "Chunk not found in memory")
+        })?;
+
+        Ok(Box::new(std::io::Cursor::new(content)))
+    }
+
+    fn write_chunk_content(
+        &self,
+        chunk: &ChunkHash,
+        mut content: Box<dyn std::io::Read + Send>,
+    ) -> std::io::Result<()> {
+        if self.0.contains_key(chunk) {
+            return Ok(());
+        }
+
+        let mut buffer = Vec::new();
+        content.read_to_end(&mut buffer)?;
+        self.0.insert(*chunk, buffer.into());
+
+        Ok(())
+    }
+
+    #[inline]
+    fn write_chunk_bytes(&self, chunk: &ChunkHash, content: &[u8]) -> std::io::Result<()> {
+        if !self.0.contains_key(chunk) {
+            self.0.insert(*chunk, content.into());
+        }
+
+        Ok(())
+    }
+
+    fn delete_chunk_content(&self, chunk: &ChunkHash) -> std::io::Result<()> {
+        self.0.remove(chunk).map(|_| ()).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "Chunk not found in memory")
+        })
+    }
+
+    fn chunk_size(&self, chunk: &ChunkHash) -> std::io::Result<u64> {
+        self.0.get(chunk).map(|c| c.len() as u64).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "Chunk not found in memory")
+        })
+    }
+
+    fn list_chunk_hashes(&self) -> std::io::Result<Vec<ChunkHash>> {
+        Ok(self.0.iter().map(|entry| *entry.key()).collect())
+    }
+}
+
+/// Chunk files smaller than this are read directly, mapping them costs more than it saves.
+#[cfg(all(unix, feature = "mmap"))]
+pub const MMAP_MIN_SIZE: u64 = 16 * 1024;
+
+/// The local storage, reading chunks through memory maps instead of `read` calls.
+/// Chunk files below `MMAP_MIN_SIZE` or that fail to map are read like `ChunkStorageLocal`
+/// does, everything else is left to the wrapped storage.
+///
+/// # Safety
+/// A chunk file that is truncated while it is mapped makes reading the mapping past
+/// the new end raise `SIGBUS`. The mapping is made with the length the file had when
+/// it was opened. Chunks are written to a temporary file and renamed into place, and
+/// deleting a chunk only unlinks it, so this can only happen if something besides this
+/// storage modifies chunk files in place.
+#[cfg(all(unix, feature = "mmap"))]
+pub struct ChunkStorageLocalMapped(pub ChunkStorageLocal);
+
+#[cfg(all(unix, feature = "mmap"))]
+struct Mapping {
+    ptr: std::ptr::NonNull<libc::c_void>,
+    len: usize,
+}
+
+// the mapping is read only and unmapped exactly once, on drop
+#[cfg(all(unix, feature = "mmap"))]
+unsafe impl Send for Mapping {}
+#[cfg(all(unix, feature = "mmap"))]
+unsafe impl Sync for Mapping {}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl Mapping {
+    /// Maps the first `len` bytes of `file` read only, `None` if that fails.
+    fn new(file: &std::fs::File, len: usize) -> Option<Self> {
+        use std::os::fd::AsRawFd;
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+
+        Some(Self {
+            ptr: std::ptr::NonNull::new(ptr)?,
+            len,
+        })
+    }
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl AsRef<[u8]> for Mapping {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr() as *const u8, self.len) }
+    }
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr(), self.len) };
+    }
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl ChunkStorage for ChunkStorageLocalMapped {
+    fn read_chunk_content(
+        &self,
+        chunk: &ChunkHash,
+    ) -> std::io::Result<Box<dyn std::io::Read + Send>> {
+        let path = self.0.0.join(self.path_from_chunk(chunk));
+        let file = std::fs::File::open(path)?;
+
+        let len = file.metadata()?.len();
+        if len < MMAP_MIN_SIZE {
+            return Ok(Box::new(file));
+        }
+
+        match Mapping::new(&file, len as usize) {
+            Some(mapping) => Ok(Box::new(std::io::Cursor::new(mapping))),
+            None => Ok(Box::new(file)),
+        }
+    }
+
+    #[inline]
+    fn write_chunk_content(
+        &self,
+        chunk: &ChunkHash,
+        content: Box<dyn std::io::Read + Send>,
+    ) -> std::io::Result<()> {
+        self.0.write_chunk_content(chunk, content)
+    }
+
+    #[inline]
+    fn write_chunk_bytes(&self, chunk: &ChunkHash, content: &[u8]) -> std::io::Result<()> {
+        self.0.write_chunk_bytes(chunk, content)
+    }
+
+    #[inline]
+    fn delete_chunk_content(&self, chunk: &ChunkHash) -> std::io::Result<()> {
+        self.0.delete_chunk_content(chunk)
+    }
+
+    #[inline]
+    fn chunk_size(&self, chunk: &ChunkHash) -> std::io::Result<u64> {
+        self.0.chunk_size(chunk)
+    }
+
+    #[inline]
+    fn list_chunk_hashes(&self) -> std::io::Result<Vec<ChunkHash>> {
+        self.0.list_chunk_hashes()
+    }
+}
+
+/// Creates the chunk storage described by `url`. Supported schemes are
+/// `file://<path>`, relative paths are resolved against `base`, `mmap://<path>`, the
+/// same storage read through memory maps if built with the `mmap` feature, and `memory://`.
+pub fn storage_from_url(url: &str, base: &Path) -> std::io::Result<Arc<dyn ChunkStorage>> {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid storage url {url:?}, expected <scheme>://<location>"),
+        ));
+    };
+
+    match scheme {
+        "file" => {
+            if rest.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "file:// storage requires a path",
+                ));
+            }
+
+            Ok(Arc::new(ChunkStorageLocal(base.join(rest))))
+        }
+        #[cfg(all(unix, feature = "mmap"))]
+        "mmap" => {
+            if rest.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "mmap:// storage requires a path",
+                ));
+            }
+
+            Ok(Arc::new(ChunkStorageLocalMapped(ChunkStorageLocal(
+                base.join(rest),
+            ))))
+        }
+        #[cfg(not(all(unix, feature = "mmap")))]
+        "mmap" => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "The mmap:// storage backend requires the 'mmap' feature on unix",
+        )),
+        "memory" => Ok(Arc::new(ChunkStorageMemory::default())),
+        "s3" | "sftp" => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!(
+                "The {scheme}:// storage backend is not available in this build, rebuild with --features {scheme}"
+            ),
+        )),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Unknown storage scheme {scheme:?}, supported are file://, mmap:// and memory://"
+            ),
+        )),
+    }
+}
+
+#[cfg(all(test, unix, feature = "mmap"))]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_directory;
+    use std::io::Read;
+
+    #[test]
+    fn mapped_reads_match_plain_reads() {
+        let directory = temp_directory("storage-mmap");
+        let local = ChunkStorageLocal(directory.clone());
+        let mapped =
+            storage_from_url(&format!("mmap://{}", directory.display()), &directory).unwrap();
+
+        let min = MMAP_MIN_SIZE as usize;
+        for (i, size) in [0, 1, min - 1, min, min + 1, min * 5 + 3]
+            .into_iter()
+            .enumerate()
+        {
+            let chunk = [i as u8; 32];
+            let content = (0..size).map(|j| (j % 253) as u8).collect::<Vec<_>>();
+            mapped.write_chunk_bytes(&chunk, &content).unwrap();
+            assert_eq!(mapped.chunk_size(&chunk).unwrap(), size as u64);
+
+            let mut plain = Vec::new();
+            local
+                .read_chunk_content(&chunk)
+                .unwrap()
+                .read_to_end(&mut plain)
+                .unwrap();
+            assert_eq!(plain, content, "{size} bytes");
+
+            // a mapping outlives the chunk file being deleted
+            let mut reader = mapped.read_chunk_content(&chunk).unwrap();
+            mapped.delete_chunk_content(&chunk).unwrap();
+            assert!(mapped.read_chunk_content(&chunk).is_err());
+
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read).unwrap();
+            assert_eq!(read, content, "{size} bytes");
+        }
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,13 +1,62 @@
-use crate::commands::{Progress, open_repository};
+use crate::commands::{EXIT_NOT_FOUND, EXIT_USAGE, Output, Progress, open_repository, print_line};
 use clap::ArgMatches;
 use colored::Colorize;
-use ddup_bak::archive::entries::Entry;
-use std::{fs::File, io::Write};
+use ddup_bak::{
+    archive::entries::Entry,
+    convert,
+    repository::{ProgressEvent, ProgressEventCallback},
+};
+use std::{
+    fs::File,
+    io::{Read, Seek, Write},
+    sync::Arc,
+};
 
 enum Format {
     Tar,
-    TarGz,
+    TarGz {
+        level: u32,
+    },
+    TarZst {
+        level: i32,
+    },
+    #[cfg(feature = "xz")]
+    TarXz {
+        level: u32,
+    },
     Ddup,
+    Zip {
+        skip_symlinks: bool,
+    },
+}
+
+/// Counts the bytes read from an entry towards the progress bar.
+struct ProgressReader<'a, R: Read> {
+    inner: R,
+    progress: Option<&'a Progress>,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        if let Some(progress) = self.progress {
+            progress.incr_bytes(read as u64);
+        }
+
+        Ok(read)
+    }
+}
+
+/// Forwards the events of the library conversions to the progress bar.
+fn progress_events(progress: Option<&Progress>) -> ProgressEventCallback {
+    let progress = progress?.clone();
+
+    Some(Arc::new(move |event| match event {
+        ProgressEvent::FileDone { .. } => progress.incr(1usize),
+        ProgressEvent::BytesProcessed(bytes) => progress.incr_bytes(bytes),
+        ProgressEvent::ScanComplete { .. } => {}
+    }))
 }
 
 pub fn convert(matches: &ArgMatches) -> std::io::Result<i32> {
@@ -16,11 +65,54 @@ pub fn convert(matches: &ArgMatches) -> std::io::Result<i32> {
     let name = matches.get_one::<String>("name").expect("required");
     let output = matches.get_one::<String>("output");
     let format = matches.get_one::<String>("format").expect("required");
+    let level = matches.get_one::<i32>("level").copied();
+
+    let levels = match format.as_str() {
+        "tar.gz" => Some((0, 9)),
+        "tar.zst" => Some((1, 22)),
+        "tar.xz" => Some((0, 9)),
+        _ => None,
+    };
+    match (level, levels) {
+        (Some(level), Some((min, max))) if level < min || level > max => {
+            Output::error(format!(
+                "{} {} {}",
+                format.cyan(),
+                "only supports compression levels".red(),
+                format!("{min} to {max}").cyan()
+            ));
+
+            return Ok(EXIT_USAGE);
+        }
+        (Some(_), None) => {
+            Output::error(format!(
+                "{} {}",
+                format.cyan(),
+                "does not support a compression level!".red()
+            ));
+
+            return Ok(EXIT_USAGE);
+        }
+        _ => {}
+    }
+
     let format = match format.as_str() {
         "tar" => Format::Tar,
-        "tar.gz" => Format::TarGz,
+        "tar.gz" => Format::TarGz {
+            level: level.map_or(6, |level| level as u32),
+        },
+        "tar.zst" => Format::TarZst {
+            level: level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL),
+        },
+        #[cfg(feature = "xz")]
+        "tar.xz" => Format::TarXz {
+            level: level.map_or(6, |level| level as u32),
+        },
         "ddup" => Format::Ddup,
-        _ => panic!("invalid format"),
+        "zip" => Format::Zip {
+            skip_symlinks: matches.get_flag("skip_symlinks"),
+        },
+        _ => unreachable!("clap only accepts known formats"),
     };
 
     if !repository
@@ -28,254 +120,258 @@ pub fn convert(matches: &ArgMatches) -> std::io::Result<i32> {
         .into_iter()
         .any(|archive_name| archive_name == *name)
     {
-        println!(
+        Output::error(format!(
             "{} {} {}",
             "backup".red(),
             name.cyan(),
             "does not exist!".red()
-        );
+        ));
 
-        return Ok(1);
+        return Ok(EXIT_NOT_FOUND);
     }
 
     let archive = repository.get_archive(name)?;
 
-    if let Some(output) = output {
-        println!("{}", "converting backup...".bright_black());
+    // the converted backup may be streamed to stdout, so everything else goes to stderr
+    print_line(true, "converting backup...".bright_black());
 
-        fn recursive_count_entries(entry: &Entry) -> usize {
-            match entry {
-                Entry::Directory(entries) => {
-                    let mut count = 1;
+    let mut total = 0;
+    let mut total_bytes = 0;
+    for item in archive.walk() {
+        let (_, _, entry) = item?;
 
-                    for entry in entries.entries.iter() {
-                        count += recursive_count_entries(entry);
-                    }
-
-                    count
-                }
-                _ => 1,
-            }
+        total += 1;
+        if let Entry::File(file) = entry {
+            total_bytes += file.size_real;
         }
+    }
 
-        let mut total = 0;
-        for entry in archive.entries().iter() {
-            total += recursive_count_entries(entry);
-        }
+    let mut progress = Progress::new(total);
+    progress.set_total_bytes(total_bytes);
+    progress.bar("resolving chunks...");
 
-        let mut progress = Progress::new(total);
-        progress.spinner(|progress, spinner| {
-            format!(
-                "\r\x1B[K {} {} {}/{} ({}%)",
-                "resolving chunks...".bright_black().italic(),
-                spinner.cyan(),
-                progress.progress().to_string().cyan(),
-                progress.total.to_string().cyan(),
-                progress.percent().round().to_string().cyan()
-            )
-        });
-
-        let file = File::create(output)?;
-
-        convert_entries_file(
+    let result = match output {
+        Some(output) if matches!(format, Format::Ddup) => ddup_convert_entries(
+            &mut repository,
+            archive.into_entries()?,
+            File::create(output)?,
+            Some(&progress),
+        ),
+        Some(output) => convert_entries(
+            &mut repository,
+            archive.into_entries()?,
+            std::io::BufWriter::new(File::create(output)?),
+            Some(&progress),
+            format,
+        ),
+        None => convert_entries(
             &mut repository,
-            archive.into_entries(),
-            file,
+            archive.into_entries()?,
+            std::io::stdout().lock(),
             Some(&progress),
             format,
-        )?;
+        ),
+    };
 
-        progress.finish();
+    progress.finish();
+    result?;
 
-        println!(
+    print_line(
+        true,
+        format!(
             "{} {}",
             "converting backup...".bright_black(),
             "DONE".green().bold()
-        );
-    } else {
-        let output = std::io::stdout().lock();
-
-        convert_entries(
-            &mut repository,
-            archive.into_entries(),
-            output,
-            None,
-            format,
-        )?;
-    }
+        ),
+    );
 
     Ok(0)
 }
 
-fn convert_entries<S: Write + 'static>(
+fn convert_entries<W: Write>(
     repository: &mut ddup_bak::repository::Repository,
     entries: Vec<Entry>,
-    output: S,
+    mut output: W,
     progress: Option<&Progress>,
     format: Format,
 ) -> std::io::Result<()> {
     match format {
-        Format::Tar | Format::TarGz => {
-            let output: Box<dyn Write + 'static> = match format {
-                Format::Tar => Box::new(output),
-                Format::TarGz => Box::new(flate2::write::GzEncoder::new(
-                    output,
-                    flate2::Compression::default(),
-                )),
-                _ => unreachable!(),
-            };
+        Format::Tar => {
+            convert::to_tar(repository, entries, &mut output, progress_events(progress))?;
+        }
+        Format::TarGz { level } => {
+            convert::to_tar_gz(
+                repository,
+                entries,
+                &mut output,
+                level,
+                progress_events(progress),
+            )?;
+        }
+        Format::TarZst { level } => {
+            let mut output = zstd::Encoder::new(&mut output, level)?;
+
+            convert::to_tar(repository, entries, &mut output, progress_events(progress))?;
+            output.finish()?;
+        }
+        #[cfg(feature = "xz")]
+        Format::TarXz { level } => {
+            let mut output = xz2::write::XzEncoder::new(&mut output, level);
 
-            let mut tar = tar::Builder::new(output);
-            tar.mode(tar::HeaderMode::Complete);
+            convert::to_tar(repository, entries, &mut output, progress_events(progress))?;
+            output.finish()?;
+        }
+        Format::Ddup => {
+            // ddup archives are written with random access, so they are built in a
+            // temporary file first and copied to the stream afterwards
+            let warning = format!(
+                "{} {}",
+                "warning:".yellow(),
+                "ddup archives cannot be streamed, buffering in a temporary file".bright_black()
+            );
+            match progress {
+                Some(progress) => progress.println(warning),
+                None => print_line(true, warning),
+            }
+
+            let path =
+                std::env::temp_dir().join(format!(".ddup-bak-convert-{}.ddup", std::process::id()));
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+
+            let result = ddup_convert_entries(repository, entries, file.try_clone()?, progress)
+                .and_then(|_| {
+                    let mut file = file;
+                    file.seek(std::io::SeekFrom::Start(0))?;
+
+                    std::io::copy(&mut file, &mut output)
+                });
+
+            std::fs::remove_file(&path)?;
+            result?;
+        }
+        Format::Zip { skip_symlinks } => {
+            let mut zip = zip::ZipWriter::new_stream(&mut output);
 
             for entry in entries {
-                tar_recursive_convert_entries(entry, repository, &mut tar, progress, "")?;
+                zip_recursive_convert_entries(
+                    entry,
+                    repository,
+                    &mut zip,
+                    progress,
+                    "",
+                    skip_symlinks,
+                )?;
             }
 
-            tar.finish()?;
+            zip.finish()?;
         }
-        _ => unimplemented!(),
     }
 
-    Ok(())
+    output.flush()
 }
 
-fn convert_entries_file(
+fn ddup_convert_entries(
     repository: &mut ddup_bak::repository::Repository,
     entries: Vec<Entry>,
     output: File,
     progress: Option<&Progress>,
-    format: Format,
 ) -> std::io::Result<()> {
-    match format {
-        Format::Tar | Format::TarGz => {
-            let output: Box<dyn Write + 'static> = match format {
-                Format::Tar => Box::new(output),
-                Format::TarGz => Box::new(flate2::write::GzEncoder::new(
-                    output,
-                    flate2::Compression::default(),
-                )),
-                _ => unreachable!(),
-            };
-
-            let mut tar = tar::Builder::new(output);
-            tar.mode(tar::HeaderMode::Complete);
-
-            for entry in entries {
-                tar_recursive_convert_entries(entry, repository, &mut tar, progress, "")?;
-            }
-
-            tar.finish()?;
-        }
-        Format::Ddup => {
-            let mut archive = ddup_bak::archive::Archive::new(output)?;
-
-            for entry in entries {
-                ddup_recursive_convert_entries(entry, repository, &mut archive, progress, None)?;
-            }
+    let mut archive = ddup_bak::archive::Archive::new(output)?;
 
-            archive.write_end_header()?;
-        }
+    for entry in entries {
+        ddup_recursive_convert_entries(entry, repository, &mut archive, progress, None)?;
     }
 
-    Ok(())
+    archive.write_end_header()
 }
 
-fn tar_recursive_convert_entries(
+/// Converts a timestamp to the local time MS-DOS format used by zip, which cannot
+/// represent times before 1980.
+fn zip_time(time: std::time::SystemTime) -> zip::DateTime {
+    use chrono::{Datelike, Timelike};
+
+    let datetime: chrono::DateTime<chrono::Local> = time.into();
+
+    zip::DateTime::from_date_and_time(
+        datetime.year().clamp(1980, 2107) as u16,
+        datetime.month() as u8,
+        datetime.day() as u8,
+        datetime.hour() as u8,
+        datetime.minute() as u8,
+        datetime.second() as u8,
+    )
+    .unwrap_or_default()
+}
+
+fn zip_recursive_convert_entries<W: Write + Seek>(
     entry: Entry,
     repository: &mut ddup_bak::repository::Repository,
-    archive: &mut tar::Builder<Box<dyn Write>>,
+    archive: &mut zip::ZipWriter<W>,
     progress: Option<&Progress>,
     parent_path: &str,
+    skip_symlinks: bool,
 ) -> std::io::Result<()> {
-    match entry {
-        Entry::Directory(entries) => {
-            let path = if parent_path.is_empty() {
-                entries.name.clone()
-            } else {
-                format!("{}/{}", parent_path, entries.name)
-            };
-
-            let mut entry_header = tar::Header::new_gnu();
-            entry_header.set_uid(entries.owner.0 as u64);
-            entry_header.set_gid(entries.owner.1 as u64);
-            entry_header.set_mode(entries.mode.bits());
-
-            entry_header.set_mtime(
-                entries
-                    .mtime
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs(),
-            );
-            entry_header.set_entry_type(tar::EntryType::Directory);
+    let path = if parent_path.is_empty() {
+        entry.name().to_string()
+    } else {
+        format!("{}/{}", parent_path, entry.name())
+    };
 
-            let dir_path = if path.ends_with('/') {
-                path.clone()
-            } else {
-                format!("{path}/")
-            };
+    let options = zip::write::SimpleFileOptions::default()
+        .last_modified_time(zip_time(entry.mtime()))
+        .unix_permissions(entry.mode().bits());
 
-            archive.append_data(&mut entry_header, &dir_path, std::io::empty())?;
+    match entry {
+        Entry::Directory(entries) => {
+            archive.add_directory(format!("{path}/"), options)?;
 
             if let Some(progress) = progress {
                 progress.incr(1usize);
             }
 
-            for entry in entries.entries {
-                tar_recursive_convert_entries(entry, repository, archive, progress, &path)?;
+            for entry in entries.into_entries()? {
+                zip_recursive_convert_entries(
+                    entry,
+                    repository,
+                    archive,
+                    progress,
+                    &path,
+                    skip_symlinks,
+                )?;
             }
         }
         Entry::File(file) => {
-            let path = if parent_path.is_empty() {
-                file.name.clone()
-            } else {
-                format!("{}/{}", parent_path, file.name)
-            };
-
-            let mut entry_header = tar::Header::new_gnu();
-            entry_header.set_uid(file.owner.0 as u64);
-            entry_header.set_gid(file.owner.1 as u64);
-            entry_header.set_mode(file.mode.bits());
-
-            entry_header.set_mtime(
-                file.mtime
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs(),
-            );
-            entry_header.set_entry_type(tar::EntryType::Regular);
-            entry_header.set_size(file.size_real);
-
-            let reader = repository.entry_reader(Entry::File(file.clone()))?;
+            archive.start_file(&path, options.large_file(file.size_real >= u32::MAX as u64))?;
 
-            archive.append_data(&mut entry_header, &path, reader)?;
+            let mut reader = ProgressReader {
+                inner: repository.entry_reader(Entry::File(file))?,
+                progress,
+            };
+            std::io::copy(&mut reader, archive)?;
 
             if let Some(progress) = progress {
                 progress.incr(1usize);
             }
         }
         Entry::Symlink(link) => {
-            let path = if parent_path.is_empty() {
-                link.name.clone()
+            if skip_symlinks {
+                let warning = format!(
+                    "{} {} {}",
+                    "warning:".yellow(),
+                    "skipped symlink".bright_black(),
+                    path.cyan()
+                );
+                match progress {
+                    Some(progress) => progress.println(warning),
+                    None => print_line(true, warning),
+                }
             } else {
-                format!("{}/{}", parent_path, link.name)
-            };
-
-            let mut entry_header = tar::Header::new_gnu();
-            entry_header.set_uid(link.owner.0 as u64);
-            entry_header.set_gid(link.owner.1 as u64);
-            entry_header.set_mode(link.mode.bits());
-
-            entry_header.set_mtime(
-                link.mtime
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs(),
-            );
-            entry_header.set_entry_type(tar::EntryType::Symlink);
-
-            archive.append_link(&mut entry_header, &path, &link.target)?;
+                archive.add_symlink(&path, &link.target, options)?;
+            }
 
             if let Some(progress) = progress {
                 progress.incr(1usize);
@@ -295,19 +391,19 @@ fn ddup_recursive_convert_entries(
 ) -> std::io::Result<()> {
     match entry {
         Entry::Directory(directory) => {
-            let mut dir_entry = ddup_bak::archive::entries::DirectoryEntry {
-                name: directory.name,
-                owner: directory.owner,
-                mode: directory.mode,
-                mtime: directory.mtime,
-                entries: Vec::new(),
-            };
+            let mut dir_entry = ddup_bak::archive::entries::DirectoryEntry::new(
+                directory.name.clone(),
+                directory.mode,
+                directory.owner,
+                directory.mtime,
+                Vec::new(),
+            );
 
             if let Some(progress) = progress {
                 progress.incr(1usize);
             }
 
-            for entry in directory.entries {
+            for entry in directory.into_entries()? {
                 ddup_recursive_convert_entries(
                     entry,
                     repository,
@@ -318,14 +414,19 @@ fn ddup_recursive_convert_entries(
             }
 
             if let Some(parent) = parent_entry {
-                parent.entries.push(Entry::Directory(Box::new(dir_entry)));
+                parent
+                    .entries_mut()?
+                    .push(Entry::Directory(Box::new(dir_entry)));
             } else {
                 archive.entries.push(Entry::Directory(Box::new(dir_entry)));
             }
         }
         Entry::File(file) => {
             let file_entry = archive.write_file_entry(
-                repository.entry_reader(Entry::File(file.clone()))?,
+                ProgressReader {
+                    inner: repository.entry_reader(Entry::File(file.clone()))?,
+                    progress,
+                },
                 None,
                 file.name,
                 file.mode,
@@ -335,7 +436,7 @@ fn ddup_recursive_convert_entries(
             )?;
 
             if let Some(parent) = parent_entry {
-                parent.entries.push(Entry::File(file_entry));
+                parent.entries_mut()?.push(Entry::File(file_entry));
             } else {
                 archive.entries.push(Entry::File(file_entry));
             }
@@ -346,7 +447,7 @@ fn ddup_recursive_convert_entries(
         }
         Entry::Symlink(link) => {
             if let Some(parent) = parent_entry {
-                parent.entries.push(Entry::Symlink(link));
+                parent.entries_mut()?.push(Entry::Symlink(link));
             } else {
                 archive.entries.push(Entry::Symlink(link));
             }
@@ -1,20 +1,170 @@
-use crate::commands::{Progress, open_repository};
+use crate::commands::{
+    EXIT_NOT_FOUND, EXIT_USAGE, Output, Progress, cancellation, exit_code, format_bytes,
+    open_repository,
+};
 use clap::ArgMatches;
 use colored::Colorize;
-use std::{path::Path, sync::Arc};
+use ddup_bak::{
+    archive::entries::Entry,
+    repository::{ArchiveMetadata, CreateOptions, ProgressEvent, Repository},
+};
+use std::{
+    io::Write,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+/// Name of the ignore file honored at the root of the backed up directory, gitignore syntax.
+const IGNORE_FILE: &str = ".ddupbakignore";
+
+/// Builds the walker for the backed up directory. A path is skipped when it matches
+/// an `--exclude` glob, a line of the `--exclude-from` file or the `.ddupbakignore` file
+/// in the root. The flags are checked first, so the ignore file cannot re-include
+/// (`!pattern`) what they exclude. Skipped paths are counted in `excluded`.
+fn build_walker(
+    root: &Path,
+    matches: &ArgMatches,
+    excluded: Arc<AtomicU64>,
+) -> std::io::Result<ignore::Walk> {
+    let mut patterns = matches
+        .get_many::<String>("exclude")
+        .map(|patterns| patterns.cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    if let Some(exclude_from) = matches.get_one::<String>("exclude_from") {
+        for line in std::fs::read_to_string(exclude_from)?.lines() {
+            let line = line.trim();
+
+            if !line.is_empty() && !line.starts_with('#') {
+                patterns.push(line.to_string());
+            }
+        }
+    }
+
+    let mut overrides = ignore::overrides::OverrideBuilder::new(root);
+    for pattern in patterns.iter() {
+        overrides
+            .add(&format!("!{pattern}"))
+            .map_err(std::io::Error::other)?;
+    }
+    let overrides = overrides.build().map_err(std::io::Error::other)?;
+
+    let mut ignore_file = ignore::gitignore::GitignoreBuilder::new(root);
+    if root.join(IGNORE_FILE).exists()
+        && let Some(err) = ignore_file.add(root.join(IGNORE_FILE))
+    {
+        return Err(std::io::Error::other(err));
+    }
+    let ignore_file = ignore_file.build().map_err(std::io::Error::other)?;
+
+    Ok(ignore::WalkBuilder::new(root)
+        .follow_links(false)
+        .git_global(false)
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+
+            if overrides.matched(entry.path(), is_dir).is_ignore()
+                || ignore_file
+                    .matched_path_or_any_parents(entry.path(), is_dir)
+                    .is_ignore()
+            {
+                excluded.fetch_add(1, Ordering::Relaxed);
+
+                return false;
+            }
+
+            true
+        })
+        .build())
+}
+
+fn print_excluded(excluded: &AtomicU64) {
+    let excluded = excluded.load(Ordering::Relaxed);
+    if excluded > 0 {
+        Output::status(format!(
+            "{} {} {}",
+            "skipped".bright_black(),
+            excluded.to_string().cyan(),
+            "paths matching excludes".bright_black()
+        ));
+    }
+}
+
+/// Prints what a backup would contain, without chunking or writing anything.
+fn plan(
+    repository: &Repository,
+    walker: ignore::Walk,
+    directory_root: Option<&Path>,
+    excluded: &AtomicU64,
+) -> std::io::Result<i32> {
+    Output::status("planning backup...".bright_black());
+
+    let plan = repository.plan_archive(Some(walker), directory_root, None)?;
+
+    Output::status(format!(
+        "{} {}",
+        "planning backup...".bright_black(),
+        "DONE".green().bold()
+    ));
+
+    if Output::is_json() {
+        for (path, size) in plan.files.iter() {
+            Output::json(serde_json::json!({
+                "path": path.to_string_lossy(),
+                "size": size,
+            }));
+        }
+
+        Output::json(serde_json::json!({
+            "dry_run": true,
+            "files": plan.files.len(),
+            "directories": plan.directories,
+            "symlinks": plan.symlinks,
+            "bytes": plan.bytes,
+            "excluded": excluded.load(Ordering::Relaxed),
+        }));
+
+        return Ok(0);
+    }
+
+    let mut lock = std::io::stdout().lock();
+    for (path, size) in plan.files.iter() {
+        writeln!(lock, "{:>8} {}", format_bytes(*size), path.display())?;
+    }
+    drop(lock);
+
+    println!(
+        "{} {} {} {} {} {} {}",
+        "would back up".bright_black(),
+        format!("{} files,", plan.files.len()).cyan(),
+        format!("{} directories", plan.directories).cyan(),
+        "and".bright_black(),
+        format!("{} symlinks", plan.symlinks).cyan(),
+        "totaling".bright_black(),
+        format_bytes(plan.bytes).cyan()
+    );
+    print_excluded(excluded);
+
+    Ok(0)
+}
 
 pub fn create(matches: &ArgMatches) -> std::io::Result<i32> {
-    let repository = open_repository(true);
+    let dry_run = matches.get_flag("dry_run");
+    let repository = open_repository(!dry_run);
     let name = matches.get_one::<String>("name").expect("required");
     let directory = matches.get_one::<String>("directory");
     let threads = matches.get_one::<usize>("threads").expect("required");
     let compression = matches.get_one::<String>("compression").expect("required");
+    let count_first = !matches.get_flag("no_count");
     let compression = match compression.as_str() {
         "none" => ddup_bak::archive::CompressionFormat::None,
         "gzip" => ddup_bak::archive::CompressionFormat::Gzip,
         "deflate" => ddup_bak::archive::CompressionFormat::Deflate,
         "brotli" => ddup_bak::archive::CompressionFormat::Brotli,
-        _ => panic!("invalid compression format"),
+        _ => unreachable!("clap only accepts known compression formats"),
     };
 
     if repository
@@ -22,36 +172,138 @@ pub fn create(matches: &ArgMatches) -> std::io::Result<i32> {
         .into_iter()
         .any(|backup| backup == *name)
     {
-        println!(
+        Output::error(format!(
             "{} {} {}",
             "backup".red(),
             name.cyan(),
             "already exists!".red()
-        );
+        ));
 
         return Ok(1);
     }
 
-    println!("{}", "creating backup...".bright_black());
+    let metadata = ArchiveMetadata {
+        tags: matches
+            .get_many::<String>("tag")
+            .map(|tags| tags.cloned().collect())
+            .unwrap_or_default(),
+        description: matches.get_one::<String>("description").cloned(),
+    };
+    for tag in metadata.tags.iter() {
+        if let Err(err) = ArchiveMetadata::check_tag(tag) {
+            Output::error(err.to_string().red());
+
+            return Ok(EXIT_USAGE);
+        }
+    }
+
+    if let Some(directory) = directory
+        && !Path::new(directory).is_dir()
+    {
+        Output::error(format!(
+            "{} {} {}",
+            "directory".red(),
+            directory.cyan(),
+            "does not exist!".red()
+        ));
+
+        return Ok(EXIT_NOT_FOUND);
+    }
+
+    let excluded = Arc::new(AtomicU64::new(0));
+    let walker = build_walker(
+        directory.map_or(repository.directory.as_path(), Path::new),
+        matches,
+        Arc::clone(&excluded),
+    )?;
+
+    if dry_run {
+        return plan(&repository, walker, directory.map(Path::new), &excluded);
+    }
+
+    let resume = matches.get_flag("resume");
+    match repository.partial_archive(name)? {
+        Some(partial) if resume => {
+            let (mut files, mut bytes) = (0u64, 0u64);
+            for item in partial.walk() {
+                let (_, _, entry) = item?;
+                if let Entry::File(file) = entry {
+                    files += 1;
+                    bytes += file.size_real;
+                }
+            }
+
+            Output::status(format!(
+                "{} {} {} {}",
+                "resuming from checkpoint,".bright_black(),
+                format!("{files} files").cyan(),
+                format!("({})", format_bytes(bytes)).cyan(),
+                "already done".bright_black()
+            ));
+        }
+        Some(_) if matches.get_flag("restart") => {
+            Output::status("discarding checkpoint...".bright_black());
+            repository.discard_partial_archive(name, None)?;
+            Output::status(format!(
+                "{} {}",
+                "discarding checkpoint...".bright_black(),
+                "DONE".green().bold()
+            ));
+        }
+        Some(_) => {
+            Output::error(format!(
+                "{} {} {}",
+                "backup".red(),
+                name.cyan(),
+                "was interrupted!".red()
+            ));
+            Output::error(format!(
+                "{} {} {} {} {}",
+                "Pass".red(),
+                "--resume".cyan(),
+                "to continue it or".red(),
+                "--restart".cyan(),
+                "to start over.".red()
+            ));
+
+            return Ok(1);
+        }
+        None if resume => {
+            Output::error(format!(
+                "{} {} {}",
+                "backup".red(),
+                name.cyan(),
+                "has no checkpoint to resume!".red()
+            ));
+
+            return Ok(EXIT_NOT_FOUND);
+        }
+        None => {}
+    }
+
+    Output::status("creating backup...".bright_black());
 
     let mut progress = Progress::new(usize::MAX);
-    progress.spinner(|progress, spinner| {
-        format!(
-            "\r\x1B[K {} {} {}",
-            "chunking...".bright_black().italic(),
-            spinner.cyan(),
-            progress.text.read().cyan()
-        )
+    let scanned = Arc::new(AtomicBool::new(!count_first));
+    progress.spinner({
+        let scanned = Arc::clone(&scanned);
+
+        move |progress, spinner| {
+            if !scanned.load(Ordering::SeqCst) {
+                return format!(
+                    "\r\x1B[K {} {}",
+                    "counting files...".bright_black().italic(),
+                    spinner.cyan()
+                );
+            }
+
+            progress.render_bar("chunking...", spinner)
+        }
     });
 
-    repository.create_archive(
+    let archive = repository.create_archive(
         name,
-        directory.map(|d| {
-            ignore::WalkBuilder::new(Path::new(d))
-                .follow_links(false)
-                .git_global(false)
-                .build()
-        }),
+        Some(walker),
         directory.map(Path::new),
         Some({
             let progress = progress.clone();
@@ -62,15 +314,89 @@ pub fn create(matches: &ArgMatches) -> std::io::Result<i32> {
         }),
         Some(Arc::new(move |_, _| compression)),
         *threads,
-    )?;
+        CreateOptions {
+            count_first,
+            progress: Some({
+                let progress = progress.clone();
+
+                Arc::new(move |event| match event {
+                    ProgressEvent::ScanComplete { files, bytes } => {
+                        progress.set_total(files as usize);
+                        progress.set_total_bytes(bytes);
+                        scanned.store(true, Ordering::SeqCst);
+                    }
+                    ProgressEvent::FileDone { .. } => progress.incr(1usize),
+                    ProgressEvent::BytesProcessed(bytes) => progress.incr_bytes(bytes),
+                })
+            }),
+            resume,
+            cancel: Some(cancellation()),
+        },
+    );
 
     progress.finish();
 
-    println!(
+    let archive = match archive {
+        Ok(archive) => archive,
+        Err(err) if repository.partial_archive_path(name).exists() => {
+            Output::error(format!("{} {}", "error:".red(), err));
+            Output::error(format!(
+                "{} {} {}",
+                "kept a checkpoint of the finished files, rerun with".red(),
+                "--resume".cyan(),
+                "to continue.".red()
+            ));
+
+            return Ok(exit_code(&err));
+        }
+        Err(err) => return Err(err),
+    };
+
+    if !metadata.is_empty() {
+        repository.set_archive_metadata(name, &metadata)?;
+    }
+
+    Output::status(format!(
         "{} {}",
         "creating backup...".bright_black(),
         "DONE".green().bold()
-    );
+    ));
+
+    print_excluded(&excluded);
+
+    let (mut files, mut directories, mut symlinks, mut bytes) = (0u64, 0u64, 0u64, 0u64);
+    for item in archive.walk() {
+        let (_, _, entry) = item?;
+        match entry {
+            Entry::File(file) => {
+                files += 1;
+                bytes += file.size_real;
+            }
+            Entry::Directory(_) => directories += 1,
+            Entry::Symlink(_) => symlinks += 1,
+        }
+    }
+
+    Output::verbose(format!(
+        "{} {} {} {} {} {} {}",
+        "backed up".bright_black(),
+        format!("{files} files,").cyan(),
+        format!("{directories} directories").cyan(),
+        "and".bright_black(),
+        format!("{symlinks} symlinks").cyan(),
+        "totaling".bright_black(),
+        format_bytes(bytes).cyan()
+    ));
+    Output::json(serde_json::json!({
+        "name": name,
+        "files": files,
+        "directories": directories,
+        "symlinks": symlinks,
+        "bytes": bytes,
+        "excluded": excluded.load(Ordering::Relaxed),
+        "tags": metadata.tags,
+        "description": metadata.description,
+    }));
 
     Ok(0)
 }
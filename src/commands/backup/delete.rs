@@ -1,65 +1,136 @@
-use crate::commands::{Progress, open_repository};
+use crate::commands::{
+    EXIT_NOT_FOUND, Output, Progress, confirm, deletion_impact, open_repository,
+};
 use clap::ArgMatches;
 use colored::Colorize;
-use std::sync::Arc;
+use ddup_bak::chunks::lock::LockMode;
+use std::{collections::HashSet, sync::Arc};
 
 pub fn delete(matches: &ArgMatches) -> std::io::Result<i32> {
     let repository = open_repository(true);
-    let name = matches.get_one::<String>("name").expect("required");
-
-    if !repository
-        .list_archives()?
-        .into_iter()
-        .any(|name| name == *name)
-    {
-        println!(
+    let all = matches.get_flag("all");
+    let strict = matches.get_flag("strict");
+
+    let existing = repository.list_archives()?;
+    let mut names = if all {
+        let mut names = existing.clone();
+        names.sort();
+
+        names
+    } else {
+        matches
+            .get_many::<String>("name")
+            .expect("required")
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    let mut seen = HashSet::new();
+    names.retain(|name| seen.insert(name.clone()));
+
+    let (names, missing): (Vec<_>, Vec<_>) =
+        names.into_iter().partition(|name| existing.contains(name));
+
+    for name in missing.iter() {
+        Output::error(format!(
             "{} {} {}",
             "backup".red(),
             name.cyan(),
             "does not exist!".red()
-        );
+        ));
+        Output::json(serde_json::json!({
+            "name": name,
+            "deleted": false,
+            "error": "does not exist",
+        }));
+    }
+
+    if names.is_empty() {
+        if all {
+            Output::error(format!("{}", "no backups found".red()));
+        }
 
+        return Ok(EXIT_NOT_FOUND);
+    }
+
+    if strict && !missing.is_empty() {
+        return Ok(EXIT_NOT_FOUND);
+    }
+
+    if !confirm(deletion_impact(&repository, &names)?) {
         return Ok(1);
     }
 
-    println!("{}", "deleting backup...".bright_black());
+    Output::status("deleting backups...".bright_black());
 
-    let mut progress = Progress::new(usize::MAX);
+    let mut progress = Progress::new(names.len());
     progress.spinner(|progress, spinner| {
         format!(
-            "\r\x1B[K {} {} {}",
+            "\r\x1B[K {} {} {}/{} {}",
             "dereferencing chunks...".bright_black().italic(),
             spinner.cyan(),
+            progress.progress().to_string().cyan(),
+            progress.total().to_string().cyan(),
             progress.text.read().cyan()
         )
     });
 
-    repository.delete_archive(
-        name,
-        Some({
-            let progress = progress.clone();
-
-            Arc::new(move |chunk, deleted| {
-                progress.set_text(format!(
-                    "{} {}",
-                    format!("chunk #{chunk}").cyan(),
-                    if deleted {
-                        "(deleted)".green()
-                    } else {
-                        "(not deleted)".red()
-                    }
-                ));
-            })
-        }),
-    )?;
+    repository.with_write_lock(LockMode::Destructive, |repository| {
+        for name in names.iter() {
+            repository.delete_archive(
+                name,
+                Some({
+                    let progress = progress.clone();
+                    let name = name.clone();
+
+                    Arc::new(move |chunk, deleted| {
+                        progress.set_text(format!(
+                            "{} {} {}",
+                            name.cyan(),
+                            format!("chunk #{chunk}").cyan(),
+                            if deleted {
+                                "(deleted)".green()
+                            } else {
+                                "(not deleted)".red()
+                            }
+                        ));
+                    })
+                }),
+            )?;
+
+            progress.incr(1usize);
+            Output::verbose(format!("{} {}", "deleted".bright_black(), name.cyan()));
+            Output::json(serde_json::json!({
+                "name": name,
+                "deleted": true,
+            }));
+        }
+
+        Ok(())
+    })?;
 
     progress.finish();
 
-    println!(
+    Output::status(format!(
         "{} {}",
-        "deleting backup...".bright_black(),
+        "deleting backups...".bright_black(),
         "DONE".green().bold()
-    );
+    ));
+    Output::result(format!(
+        "{} {} {}",
+        "deleted".bright_black(),
+        names.len().to_string().cyan(),
+        if names.len() == 1 {
+            "backup"
+        } else {
+            "backups"
+        }
+        .bright_black()
+    ));
 
-    Ok(0)
+    Ok(if missing.is_empty() {
+        0
+    } else {
+        EXIT_NOT_FOUND
+    })
 }
@@ -0,0 +1,227 @@
+use crate::commands::{
+    EXIT_FAILURE, EXIT_NOT_FOUND, Output, exit_code, format_bytes, open_repository,
+};
+use chrono::{DateTime, Local};
+use clap::ArgMatches;
+use colored::Colorize;
+use ddup_bak::{
+    archive::entries::Entry,
+    repository::{DiffEntry, DiffStatus},
+};
+use std::{io::Write, time::SystemTime};
+
+#[derive(Default)]
+struct DiffStat {
+    added: u64,
+    modified: u64,
+    deleted: u64,
+
+    bytes_added: u64,
+    bytes_removed: u64,
+}
+
+#[inline]
+fn entry_size(entry: Option<&Entry>) -> u64 {
+    match entry {
+        Some(Entry::File(file)) => file.size_real,
+        Some(Entry::Symlink(link)) => link.target.len() as u64,
+        _ => 0,
+    }
+}
+
+#[inline]
+fn format_time(time: SystemTime) -> String {
+    let datetime: DateTime<Local> = time.into();
+
+    datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn render_changes(entry: &DiffEntry) -> String {
+    let (Some(old), Some(new)) = (entry.old, entry.new) else {
+        return String::new();
+    };
+
+    let mut changes = Vec::new();
+
+    if entry.changes.size {
+        changes.push(format!(
+            "size {} -> {}",
+            format_bytes(entry_size(entry.old)),
+            format_bytes(entry_size(entry.new))
+        ));
+    }
+    if entry.changes.mode {
+        changes.push(format!(
+            "mode {:o} -> {:o}",
+            old.mode().bits() & 0o7777,
+            new.mode().bits() & 0o7777
+        ));
+    }
+    if entry.changes.mtime {
+        changes.push(format!(
+            "mtime {} -> {}",
+            format_time(old.mtime()),
+            format_time(new.mtime())
+        ));
+    }
+    if entry.changes.owner {
+        let (old_uid, old_gid) = old.owner();
+        let (new_uid, new_gid) = new.owner();
+
+        changes.push(format!("owner {old_uid}:{old_gid} -> {new_uid}:{new_gid}"));
+    }
+    if entry.changes.target
+        && let (Entry::Symlink(old), Entry::Symlink(new)) = (old, new)
+    {
+        changes.push(format!("target {} -> {}", old.target, new.target));
+    }
+    if entry.changes.content {
+        changes.push("content".to_string());
+    }
+
+    changes.join(", ")
+}
+
+fn entry_json(entry: &DiffEntry) -> serde_json::Value {
+    let mut changes = Vec::new();
+    for (changed, name) in [
+        (entry.changes.size, "size"),
+        (entry.changes.mode, "mode"),
+        (entry.changes.mtime, "mtime"),
+        (entry.changes.owner, "owner"),
+        (entry.changes.target, "target"),
+        (entry.changes.content, "content"),
+    ] {
+        if changed {
+            changes.push(name);
+        }
+    }
+
+    serde_json::json!({
+        "status": match entry.status {
+            DiffStatus::Added => "added",
+            DiffStatus::Modified => "modified",
+            DiffStatus::Deleted => "deleted",
+        },
+        "path": entry.path.to_string_lossy(),
+        "changes": changes,
+        "old_size": entry.old.map(|_| entry_size(entry.old)),
+        "new_size": entry.new.map(|_| entry_size(entry.new)),
+    })
+}
+
+fn render_entry(entry: &DiffEntry, name_only: bool) -> String {
+    if name_only {
+        return format!("{}\n", entry.path.display());
+    }
+
+    let status = match entry.status {
+        DiffStatus::Added => "A".green().bold(),
+        DiffStatus::Modified => "M".yellow().bold(),
+        DiffStatus::Deleted => "D".red().bold(),
+    };
+
+    let path = match entry.old.or(entry.new) {
+        Some(Entry::Directory(_)) => format!("{}/", entry.path.display()).blue().bold(),
+        _ => entry.path.display().to_string().normal(),
+    };
+
+    let changes = render_changes(entry);
+    if changes.is_empty() {
+        format!("{status} {path}\n")
+    } else {
+        format!("{status} {path} {}\n", changes.bright_black())
+    }
+}
+
+fn run(matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+
+    let old = matches.get_one::<String>("old").expect("required");
+    let new = matches.get_one::<String>("new").expect("required");
+    let name_only = matches.get_flag("name_only");
+    let only_stat = matches.get_flag("stat");
+    let content = matches.get_flag("content");
+
+    let archives = repository.list_archives()?;
+    for name in [old, new] {
+        if !archives.iter().any(|n| n == name) {
+            Output::error(format!(
+                "{} {} {}",
+                "backup".red(),
+                name.cyan(),
+                "does not exist!".red()
+            ));
+
+            return Ok(EXIT_NOT_FOUND);
+        }
+    }
+
+    let mut stat = DiffStat::default();
+    let mut lock = std::io::stdout().lock();
+
+    repository.diff_archives(old, new, content, |entry| {
+        let (old_size, new_size) = (entry_size(entry.old), entry_size(entry.new));
+
+        match entry.status {
+            DiffStatus::Added => stat.added += 1,
+            DiffStatus::Modified => stat.modified += 1,
+            DiffStatus::Deleted => stat.deleted += 1,
+        }
+
+        stat.bytes_added += new_size.saturating_sub(old_size);
+        stat.bytes_removed += old_size.saturating_sub(new_size);
+
+        if Output::is_json() {
+            if !only_stat {
+                Output::json(entry_json(&entry));
+            }
+        } else if !only_stat {
+            lock.write_all(render_entry(&entry, name_only).as_bytes())?;
+        }
+
+        Ok(())
+    })?;
+
+    if only_stat && Output::is_json() {
+        Output::json(serde_json::json!({
+            "added": stat.added,
+            "modified": stat.modified,
+            "deleted": stat.deleted,
+            "bytes_added": stat.bytes_added,
+            "bytes_removed": stat.bytes_removed,
+        }));
+    } else if only_stat {
+        writeln!(
+            lock,
+            "{} added, {} modified, {} deleted, +{} -{}",
+            stat.added.to_string().green(),
+            stat.modified.to_string().yellow(),
+            stat.deleted.to_string().red(),
+            format_bytes(stat.bytes_added).green(),
+            format_bytes(stat.bytes_removed).red()
+        )?;
+    }
+
+    if stat.added + stat.modified + stat.deleted == 0 {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
+
+pub fn diff(matches: &ArgMatches) -> std::io::Result<i32> {
+    // mirror diff(1): 0 when identical and 1 when different, so generic errors
+    // use 2 instead of 1, the more specific exit codes are kept
+    match run(matches) {
+        Ok(code) => Ok(code),
+        Err(err) => {
+            Output::error(format!("{} {}", "error:".red(), err));
+
+            Ok(match exit_code(&err) {
+                EXIT_FAILURE => 2,
+                code => code,
+            })
+        }
+    }
+}
@@ -1,46 +1,200 @@
-use crate::commands::open_repository;
+use crate::commands::{EXIT_NOT_FOUND, Output, open_archive_lazy, open_repository};
 use clap::ArgMatches;
 use colored::Colorize;
-use ddup_bak::archive::entries::Entry;
-use std::path::Path;
+use ddup_bak::{archive::entries::Entry, repository::Repository};
+use std::{
+    io::{IsTerminal, Write},
+    path::Path,
+};
 
-pub fn cat(name: &str, matches: &ArgMatches) -> std::io::Result<i32> {
-    let repository = open_repository(false);
-    let path = matches.get_one::<String>("path").expect("required");
-
-    if !repository
-        .list_archives()?
-        .into_iter()
-        .any(|name| name == *name)
-    {
-        println!(
-            "{} {} {}",
-            "backup".red(),
-            name.cyan(),
-            "does not exist!".red()
-        );
+/// Refuses content that looks binary, git considers anything with a NUL byte
+/// in the first 8000 bytes to be binary.
+struct BinaryGuard<W: Write> {
+    inner: W,
+    checked: bool,
+}
+
+impl<W: Write> Write for BinaryGuard<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !self.checked {
+            if buf[..buf.len().min(8000)].contains(&0) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "binary content",
+                ));
+            }
+
+            self.checked = true;
+        }
+
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Streams a file entry to `output` or stdout, refusing binary content on a terminal unless `force` is set.
+pub fn cat_file(
+    repository: &Repository,
+    path: &str,
+    entry: &Entry,
+    offset: u64,
+    length: Option<u64>,
+    output: Option<&str>,
+    force: bool,
+) -> std::io::Result<i32> {
+    let Entry::File(file) = entry else {
+        Output::error(format!("{} {}", path.cyan(), "is not a file!".red()));
 
         return Ok(1);
+    };
+    let entry = Entry::File(file.clone());
+
+    if let Some(output) = output {
+        let mut file = std::fs::File::create(output)?;
+
+        repository.read_entry_range(entry, offset, length, &mut file)?;
+    } else if std::io::stdout().is_terminal() && !force {
+        let mut stdout = BinaryGuard {
+            inner: std::io::stdout().lock(),
+            checked: false,
+        };
+
+        match repository.read_entry_range(entry, offset, length, &mut stdout) {
+            Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+                println!(
+                    "{} {} {} {} {}",
+                    path.cyan(),
+                    "looks like a binary file, use".yellow(),
+                    "--force".cyan(),
+                    "or".yellow(),
+                    "--output".cyan()
+                );
+
+                return Ok(1);
+            }
+            result => result?,
+        };
+    } else {
+        repository.read_entry_range(entry, offset, length, &mut std::io::stdout().lock())?;
     }
 
-    let archive = repository.get_archive(name)?;
+    std::io::stdout().flush()?;
+
+    Ok(0)
+}
 
-    if let Some(entry) = archive.find_archive_entry(Path::new(path)) {
-        match entry {
-            Entry::File(file) => {
-                repository
-                    .read_entry_content(Entry::File(file.clone()), &mut std::io::stdout().lock())?;
+pub fn cat(name: &str, matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+    let paths = matches
+        .get_many::<String>("path")
+        .expect("required")
+        .collect::<Vec<_>>();
+    let offset = matches.get_one::<u64>("offset").copied().unwrap_or(0);
+    let length = matches.get_one::<u64>("length").copied();
+    let output = matches.get_one::<String>("output").map(Path::new);
+    let force = matches.get_flag("force");
+
+    let archive = match open_archive_lazy(&repository, name)? {
+        Ok(archive) => archive,
+        Err(code) => return Ok(code),
+    };
+
+    let mut entries = Vec::with_capacity(paths.len());
+    for path in paths.iter() {
+        match archive.find_archive_entry(Path::new(path))? {
+            Some(entry @ Entry::File(_)) => entries.push((path.as_str(), entry)),
+            Some(Entry::Directory(_)) => {
+                Output::error(format!(
+                    "{} {} {} {}",
+                    path.cyan(),
+                    "is a directory, use".red(),
+                    "backup fs extract".cyan(),
+                    "instead!".red()
+                ));
+
+                return Ok(1);
             }
-            _ => {
-                println!("{} {}", path.cyan(), "is not a file!".red());
+            Some(_) => {
+                Output::error(format!("{} {}", path.cyan(), "is not a file!".red()));
 
                 return Ok(1);
             }
+            None => {
+                Output::error(format!("{} {}", path.cyan(), "does not exist!".red()));
+
+                return Ok(EXIT_NOT_FOUND);
+            }
+        }
+    }
+
+    let Some(output) = output else {
+        for (path, entry) in entries {
+            let code = cat_file(&repository, path, entry, offset, length, None, force)?;
+            if code != 0 {
+                return Ok(code);
+            }
         }
+
+        return Ok(0);
+    };
+
+    // one file to a file path streams directly, otherwise every file keeps its
+    // name inside the output directory
+    let targets = if entries.len() == 1 && !output.is_dir() {
+        vec![output.to_path_buf()]
     } else {
-        println!("{} {}", path.cyan(), "does not exist!".red());
+        std::fs::create_dir_all(output)?;
 
-        return Ok(1);
+        entries
+            .iter()
+            .map(|(_, entry)| output.join(entry.name()))
+            .collect()
+    };
+
+    for (i, target) in targets.iter().enumerate() {
+        let collides = targets[..i].contains(target);
+        if collides || (!force && std::fs::symlink_metadata(target).is_ok()) {
+            Output::error(format!(
+                "{} {}{}",
+                target.display().to_string().cyan(),
+                if collides {
+                    "is written by more than one path".red()
+                } else {
+                    "already exists".red()
+                },
+                if collides {
+                    "!".red()
+                } else {
+                    format!(", use {} to overwrite it!", "--force".cyan()).red()
+                }
+            ));
+
+            return Ok(1);
+        }
+    }
+
+    for ((path, entry), target) in entries.into_iter().zip(targets) {
+        let target = target.to_string_lossy();
+        cat_file(
+            &repository,
+            path,
+            entry,
+            offset,
+            length,
+            Some(&target),
+            force,
+        )?;
+
+        if !Output::is_quiet() && !Output::is_json() {
+            eprintln!("{} {} {}", path.cyan(), "->".bright_black(), target.cyan());
+        }
+        Output::json(serde_json::json!({
+            "path": path,
+            "output": target,
+        }));
     }
 
     Ok(0)
@@ -0,0 +1,189 @@
+use crate::commands::{EXIT_NOT_FOUND, Output, format_bytes, open_archive_lazy, open_repository};
+use clap::ArgMatches;
+use colored::Colorize;
+use ddup_bak::{archive::entries::Entry, chunks::ids::ChunkIdDecoder, repository::Repository};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+struct DuLine {
+    path: PathBuf,
+    logical: u64,
+    stored: Option<u64>,
+}
+
+struct Du<'a> {
+    repository: &'a Repository,
+    stored: bool,
+    max_depth: usize,
+    human: bool,
+    sort: bool,
+
+    chunk_sizes: HashMap<u64, u64>,
+    lines: Vec<DuLine>,
+}
+
+impl Du<'_> {
+    fn print(&self, line: &DuLine) -> std::io::Result<()> {
+        let format = |bytes: u64| {
+            if self.human {
+                format_bytes(bytes)
+            } else {
+                bytes.to_string()
+            }
+        };
+
+        let mut lock = std::io::stdout().lock();
+        match line.stored {
+            Some(stored) => writeln!(
+                lock,
+                "{}\t{}\t{}",
+                format(line.logical),
+                format(stored).cyan(),
+                line.path.display().to_string().blue().bold()
+            ),
+            None => writeln!(
+                lock,
+                "{}\t{}",
+                format(line.logical),
+                line.path.display().to_string().blue().bold()
+            ),
+        }
+    }
+
+    /// Returns the logical size of the subtree and the chunk IDs it references,
+    /// directories are reported after their children like du(1) does.
+    fn walk(
+        &mut self,
+        path: &Path,
+        depth: usize,
+        entries: &[Entry],
+    ) -> std::io::Result<(u64, HashSet<u64>)> {
+        let mut entries = entries.iter().collect::<Vec<_>>();
+        entries.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+
+        let mut logical = 0;
+        let mut chunks = HashSet::new();
+
+        for entry in entries {
+            match entry {
+                Entry::File(file_entry) => {
+                    logical += file_entry.size_real;
+
+                    if self.stored {
+                        let mut file_entry = file_entry.clone();
+                        let mut ids = ChunkIdDecoder::new(&file_entry);
+
+                        while let Some(chunk_id) = ids.next_id(&mut file_entry)? {
+                            chunks.insert(chunk_id);
+                        }
+                    }
+                }
+                Entry::Directory(dir_entry) => {
+                    let (dir_logical, dir_chunks) =
+                        self.walk(&path.join(&dir_entry.name), depth + 1, dir_entry.entries()?)?;
+
+                    logical += dir_logical;
+                    chunks.extend(dir_chunks);
+                }
+                Entry::Symlink(_) => {}
+            }
+        }
+
+        if depth <= self.max_depth {
+            let stored = if self.stored {
+                let mut stored = 0;
+                for chunk_id in chunks.iter() {
+                    stored += match self.chunk_sizes.get(chunk_id) {
+                        Some(size) => *size,
+                        None => {
+                            let size = self.repository.chunk_index.stored_size(*chunk_id)?;
+                            self.chunk_sizes.insert(*chunk_id, size);
+
+                            size
+                        }
+                    };
+                }
+
+                Some(stored)
+            } else {
+                None
+            };
+
+            let line = DuLine {
+                path: path.to_path_buf(),
+                logical,
+                stored,
+            };
+
+            if self.sort {
+                self.lines.push(line);
+            } else {
+                self.print(&line)?;
+            }
+        }
+
+        Ok((logical, chunks))
+    }
+}
+
+pub fn du(name: &str, matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+    let path = matches.get_one::<String>("path");
+
+    let archive = match open_archive_lazy(&repository, name)? {
+        Ok(archive) => archive,
+        Err(code) => return Ok(code),
+    };
+
+    let mut du = Du {
+        repository: &repository,
+        stored: matches.get_flag("stored"),
+        max_depth: matches
+            .get_one::<usize>("max_depth")
+            .copied()
+            .unwrap_or(usize::MAX),
+        human: matches.get_flag("human"),
+        sort: matches.get_flag("sort"),
+        chunk_sizes: HashMap::new(),
+        lines: Vec::new(),
+    };
+
+    let path = Path::new(path.map_or(".", |s| s.as_str()));
+    if let Some(entry) = archive.find_archive_entry(path)? {
+        let Entry::Directory(dir) = entry else {
+            Output::error(format!(
+                "{} {}",
+                path.display().to_string().cyan(),
+                "is not a directory!".red()
+            ));
+
+            return Ok(1);
+        };
+
+        du.walk(path, 0, dir.entries()?)?;
+    } else if path.components().all(|c| c.as_os_str() == ".") {
+        du.walk(path, 0, archive.entries())?;
+    } else {
+        Output::error(format!(
+            "{} {}",
+            path.display().to_string().cyan(),
+            "does not exist!".red()
+        ));
+
+        return Ok(EXIT_NOT_FOUND);
+    }
+
+    if du.sort {
+        let mut lines = std::mem::take(&mut du.lines);
+        lines.sort_by_key(|line| std::cmp::Reverse(line.stored.unwrap_or(line.logical)));
+
+        for line in lines.iter() {
+            du.print(line)?;
+        }
+    }
+
+    Ok(0)
+}
@@ -0,0 +1,181 @@
+use crate::commands::{EXIT_NOT_FOUND, Output, Progress, open_archive, open_repository};
+use clap::ArgMatches;
+use colored::Colorize;
+use ddup_bak::{
+    archive::entries::Entry,
+    repository::{Repository, RestoreMode, RestoreOptions},
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+fn recursive_count_entries(entry: &Entry) -> std::io::Result<usize> {
+    match entry {
+        Entry::Directory(dir_entry) => Ok(1 + dir_entry
+            .entries()?
+            .iter()
+            .map(recursive_count_entries)
+            .sum::<std::io::Result<usize>>()?),
+        _ => Ok(1),
+    }
+}
+
+pub fn extract(name: &str, matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+    let path = matches.get_one::<String>("path").expect("required");
+    let destination = matches.get_one::<String>("destination");
+    let threads = *matches.get_one::<usize>("threads").expect("required");
+    let overwrite = matches.get_flag("overwrite");
+    let skip_existing = matches.get_flag("skip_existing");
+
+    let archive = match open_archive(&repository, name)? {
+        Ok(archive) => archive,
+        Err(code) => return Ok(code),
+    };
+
+    let Some(entry) = archive.find_archive_entry(Path::new(path))? else {
+        Output::error(format!("{} {}", path.cyan(), "does not exist!".red()));
+
+        return Ok(EXIT_NOT_FOUND);
+    };
+
+    extract_entry(
+        &repository,
+        name,
+        entry,
+        destination.map(|d| d.as_str()),
+        threads,
+        overwrite,
+        skip_existing,
+    )
+}
+
+/// Extracts `entry` of archive `name` to `destination`, into it when it is a directory.
+pub fn extract_entry(
+    repository: &Repository,
+    name: &str,
+    entry: &Entry,
+    destination: Option<&str>,
+    threads: usize,
+    overwrite: bool,
+    skip_existing: bool,
+) -> std::io::Result<i32> {
+    let target = match destination {
+        Some(destination) if Path::new(destination).is_dir() => {
+            Path::new(destination).join(entry.name())
+        }
+        Some(destination) => PathBuf::from(destination),
+        None => PathBuf::from(entry.name()),
+    };
+
+    let Some(file_name) = target.file_name().map(|f| f.to_string_lossy().to_string()) else {
+        Output::error(format!(
+            "{} {}",
+            target.display().to_string().cyan(),
+            "is not a valid destination!".red()
+        ));
+
+        return Ok(1);
+    };
+    let parent = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    if std::fs::symlink_metadata(&target).is_ok() {
+        if skip_existing && !entry.is_directory() {
+            println!(
+                "{} {}",
+                target.display().to_string().cyan(),
+                "already exists, skipped".bright_black()
+            );
+
+            return Ok(0);
+        } else if !overwrite && !skip_existing {
+            Output::error(format!(
+                "{} {} {} {} {}",
+                target.display().to_string().cyan(),
+                "already exists! Use".red(),
+                "--overwrite".cyan(),
+                "or".red(),
+                "--skip-existing".cyan()
+            ));
+
+            return Ok(1);
+        }
+    }
+
+    let mut entry = entry.clone();
+    match &mut entry {
+        Entry::File(file_entry) => file_entry.name = file_name,
+        Entry::Directory(dir_entry) => dir_entry.name = file_name,
+        Entry::Symlink(link_entry) => link_entry.name = file_name,
+    }
+
+    Output::status("extracting...".bright_black());
+
+    let mut progress = Progress::new(recursive_count_entries(&entry)?);
+    progress.spinner(|progress, spinner| {
+        format!(
+            "\r\x1B[K {} {} {}/{} ({}%)",
+            "extracting entries...".bright_black().italic(),
+            spinner.cyan(),
+            progress.progress().to_string().cyan(),
+            progress.total().to_string().cyan(),
+            progress.percent().round().to_string().cyan()
+        )
+    });
+
+    let report = repository.restore_entries_with_options(
+        name,
+        vec![entry],
+        Some({
+            let progress = progress.clone();
+
+            Arc::new(move |_| {
+                progress.incr(1usize);
+            })
+        }),
+        threads,
+        RestoreOptions {
+            destination: Some(parent),
+            mode: if skip_existing {
+                RestoreMode::SkipExisting
+            } else {
+                RestoreMode::Overwrite
+            },
+            ..Default::default()
+        },
+    );
+
+    progress.finish();
+    let report = report?;
+
+    Output::status(format!(
+        "{} {}",
+        "extracting...".bright_black(),
+        "DONE".green().bold()
+    ));
+
+    for warning in report.warnings.iter() {
+        Output::result(format!("{} {}", "warning:".yellow(), warning));
+    }
+
+    if skip_existing {
+        Output::result(format!(
+            "{} {} {}",
+            "skipped".bright_black(),
+            report.skipped_existing.to_string().cyan(),
+            "existing entries".bright_black()
+        ));
+    }
+
+    Output::result(format!(
+        "{} {}",
+        "extracted to".bright_black(),
+        target.display().to_string().cyan()
+    ));
+
+    Ok(0)
+}
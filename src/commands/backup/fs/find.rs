@@ -0,0 +1,158 @@
+use crate::commands::{open_archive_lazy, open_repository};
+use clap::ArgMatches;
+use colored::Colorize;
+use ddup_bak::archive::entries::Entry;
+use std::{io::Write, path::Path, time::SystemTime};
+
+const SIZE_UNITS: &[(char, u64)] = &[
+    ('c', 1),
+    ('k', 1024),
+    ('K', 1024),
+    ('M', 1024 * 1024),
+    ('G', 1024 * 1024 * 1024),
+];
+
+#[derive(Clone, Copy)]
+pub enum Comparison {
+    Less(u64),
+    Equal(u64),
+    Greater(u64),
+}
+
+impl Comparison {
+    #[inline]
+    fn matches(&self, value: u64) -> bool {
+        match *self {
+            Comparison::Less(limit) => value < limit,
+            Comparison::Equal(limit) => value == limit,
+            Comparison::Greater(limit) => value > limit,
+        }
+    }
+}
+
+/// Parses find(1) style numbers, `+N` means more than, `-N` less than and `N` exactly.
+/// The number may be followed by one of the `units` suffixes, which multiplies it.
+fn parse_comparison(value: &str, units: &[(char, u64)]) -> Result<Comparison, String> {
+    let (comparison, number): (fn(u64) -> Comparison, &str) =
+        if let Some(number) = value.strip_prefix('+') {
+            (Comparison::Greater, number)
+        } else if let Some(number) = value.strip_prefix('-') {
+            (Comparison::Less, number)
+        } else {
+            (Comparison::Equal, value)
+        };
+
+    let (number, multiplier) = match units.iter().find(|(suffix, _)| number.ends_with(*suffix)) {
+        Some((suffix, multiplier)) => (&number[..number.len() - suffix.len_utf8()], *multiplier),
+        None => (number, 1),
+    };
+
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("invalid number {value:?}"))?;
+
+    Ok(comparison(number * multiplier))
+}
+
+pub fn parse_size(value: &str) -> Result<Comparison, String> {
+    parse_comparison(value, SIZE_UNITS)
+}
+
+pub fn parse_mtime(value: &str) -> Result<Comparison, String> {
+    parse_comparison(value, &[])
+}
+
+enum Pattern {
+    Glob(globset::GlobMatcher),
+    Regex(regex::Regex),
+}
+
+impl Pattern {
+    #[inline]
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            Pattern::Glob(glob) => glob.is_match(path),
+            Pattern::Regex(regex) => regex.is_match(&path.to_string_lossy()),
+        }
+    }
+}
+
+pub fn find(name: &str, matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+    let pattern = matches.get_one::<String>("pattern").expect("required");
+    let entry_type = matches.get_one::<String>("type");
+    let null = matches.get_flag("null");
+
+    let pattern = if matches.get_flag("regex") {
+        Pattern::Regex(regex::Regex::new(pattern).map_err(std::io::Error::other)?)
+    } else {
+        Pattern::Glob(
+            globset::Glob::new(pattern)
+                .map_err(std::io::Error::other)?
+                .compile_matcher(),
+        )
+    };
+
+    let size = matches.get_one::<Comparison>("size");
+    let mtime = matches.get_one::<Comparison>("mtime");
+
+    let archive = match open_archive_lazy(&repository, name)? {
+        Ok(archive) => archive,
+        Err(code) => return Ok(code),
+    };
+
+    let now = SystemTime::now();
+    let mut lock = std::io::stdout().lock();
+    for item in archive.walk() {
+        let (path, _, entry) = item?;
+        let type_matches = match entry_type.map(|t| t.as_str()) {
+            Some("f") => entry.is_file(),
+            Some("d") => entry.is_directory(),
+            Some("l") => entry.is_symlink(),
+            _ => true,
+        };
+
+        if !type_matches || !pattern.matches(&path) {
+            continue;
+        }
+
+        if let Some(size) = size {
+            let entry_size = match entry {
+                Entry::File(file) => file.size_real,
+                Entry::Symlink(link) => link.target.len() as u64,
+                Entry::Directory(_) => 0,
+            };
+
+            if !size.matches(entry_size) {
+                continue;
+            }
+        }
+
+        if let Some(mtime) = mtime {
+            let days = now
+                .duration_since(entry.mtime())
+                .unwrap_or_default()
+                .as_secs()
+                / (60 * 60 * 24);
+
+            if !mtime.matches(days) {
+                continue;
+            }
+        }
+
+        if null {
+            lock.write_all(path.to_string_lossy().as_bytes())?;
+            lock.write_all(b"\0")?;
+        } else {
+            let rendered = match entry {
+                Entry::Directory(_) => path.display().to_string().blue().bold(),
+                Entry::Symlink(_) => path.display().to_string().bright_cyan().bold(),
+                Entry::File(_) => path.display().to_string().normal(),
+            };
+
+            writeln!(lock, "{rendered}")?;
+        }
+    }
+
+    Ok(0)
+}
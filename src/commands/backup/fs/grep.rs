@@ -0,0 +1,209 @@
+use crate::commands::{
+    Output,
+    backup::fs::find::{Comparison, parse_size},
+    open_archive, open_repository,
+};
+use clap::ArgMatches;
+use colored::Colorize;
+use ddup_bak::{archive::entries::Entry, repository::Repository};
+use rayon::prelude::*;
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+/// Files with a NUL byte in the first block are treated as binary, like grep(1) does.
+const BINARY_PROBE_SIZE: usize = 8192;
+
+/// Parses `--max-size`, which takes the same units as `find --size` but no `+`/`-`.
+pub fn parse_max_size(value: &str) -> Result<u64, String> {
+    match parse_size(value)? {
+        Comparison::Equal(size) => Ok(size),
+        _ => Err(format!("invalid size {value:?}")),
+    }
+}
+
+struct Match {
+    line: u64,
+    text: Vec<u8>,
+}
+
+/// Searches one file, returns `None` if it was skipped for being binary.
+fn search_file(
+    repository: &Repository,
+    entry: &Entry,
+    regex: &regex::bytes::Regex,
+    binary: bool,
+) -> std::io::Result<Option<Vec<Match>>> {
+    let mut reader =
+        BufReader::with_capacity(BINARY_PROBE_SIZE, repository.entry_reader(entry.clone())?);
+
+    if !binary && reader.fill_buf()?.contains(&0) {
+        return Ok(None);
+    }
+
+    let mut matches = Vec::new();
+    let mut line = Vec::new();
+    let mut line_number = 0;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+
+        line_number += 1;
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+
+        if regex.is_match(&line) {
+            matches.push(Match {
+                line: line_number,
+                text: line.clone(),
+            });
+        }
+    }
+
+    Ok(Some(matches))
+}
+
+fn highlight(regex: &regex::bytes::Regex, text: &[u8]) -> String {
+    let mut rendered = String::new();
+    let mut last = 0;
+    for found in regex.find_iter(text) {
+        if found.is_empty() {
+            continue;
+        }
+
+        rendered.push_str(&String::from_utf8_lossy(&text[last..found.start()]));
+        rendered.push_str(
+            &String::from_utf8_lossy(found.as_bytes())
+                .red()
+                .bold()
+                .to_string(),
+        );
+        last = found.end();
+    }
+    rendered.push_str(&String::from_utf8_lossy(&text[last..]));
+
+    rendered
+}
+
+pub fn grep(name: &str, matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+    let pattern = matches.get_one::<String>("pattern").expect("required");
+    let count = matches.get_flag("count");
+    let binary = matches.get_flag("binary");
+    let max_size = matches.get_one::<u64>("max_size").copied();
+    let threads = *matches.get_one::<usize>("threads").expect("required");
+
+    let pattern = if matches.get_flag("regex") {
+        pattern.clone()
+    } else {
+        regex::escape(pattern)
+    };
+    let regex = regex::bytes::RegexBuilder::new(&pattern)
+        .case_insensitive(matches.get_flag("ignore_case"))
+        .build()
+        .map_err(std::io::Error::other)?;
+
+    let path_glob = match matches.get_one::<String>("path") {
+        Some(glob) => Some(
+            globset::Glob::new(glob)
+                .map_err(std::io::Error::other)?
+                .compile_matcher(),
+        ),
+        None => None,
+    };
+
+    let archive = match open_archive(&repository, name)? {
+        Ok(archive) => archive,
+        Err(code) => return Ok(code),
+    };
+
+    let files: Vec<(PathBuf, Entry)> = archive
+        .walk()
+        .filter(|item| match item {
+            Ok((path, _, Entry::File(file))) => {
+                path_glob.as_ref().is_none_or(|glob| glob.is_match(path))
+                    && max_size.is_none_or(|max_size| file.size_real <= max_size)
+            }
+            Ok(_) => false,
+            Err(_) => true,
+        })
+        .map(|item| item.map(|(path, _, entry)| (path, entry.clone())))
+        .collect::<std::io::Result<_>>()?;
+
+    let worker_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(std::io::Error::other)?;
+
+    let found = AtomicBool::new(false);
+    let stdout = Mutex::new(std::io::stdout());
+
+    worker_pool.install(|| {
+        files
+            .par_iter()
+            .try_for_each(|(path, entry)| -> std::io::Result<()> {
+                let Some(file_matches) = search_file(&repository, entry, &regex, binary)? else {
+                    Output::verbose(format!("skipping binary file {}", path.display()));
+                    return Ok(());
+                };
+
+                if file_matches.is_empty() {
+                    return Ok(());
+                }
+                found.store(true, Ordering::Relaxed);
+
+                if Output::is_json() {
+                    let _stdout = stdout.lock().unwrap();
+                    if count {
+                        Output::json(serde_json::json!({
+                            "path": path.to_string_lossy(),
+                            "count": file_matches.len(),
+                        }));
+                    } else {
+                        for file_match in file_matches {
+                            Output::json(serde_json::json!({
+                                "path": path.to_string_lossy(),
+                                "line": file_match.line,
+                                "text": String::from_utf8_lossy(&file_match.text),
+                            }));
+                        }
+                    }
+
+                    return Ok(());
+                }
+
+                let mut output = String::new();
+                if count {
+                    output.push_str(&format!(
+                        "{}{}{}\n",
+                        path.display().to_string().cyan(),
+                        ":".bright_black(),
+                        file_matches.len()
+                    ));
+                } else {
+                    for file_match in file_matches {
+                        output.push_str(&format!(
+                            "{}{}{}{}{}\n",
+                            path.display().to_string().cyan(),
+                            ":".bright_black(),
+                            file_match.line.to_string().green(),
+                            ":".bright_black(),
+                            highlight(&regex, &file_match.text)
+                        ));
+                    }
+                }
+
+                stdout.lock().unwrap().write_all(output.as_bytes())
+            })
+    })?;
+
+    Ok(if found.load(Ordering::Relaxed) { 0 } else { 1 })
+}
@@ -1,27 +1,15 @@
-use crate::commands::open_repository;
+use crate::commands::{
+    EXIT_NOT_FOUND, Output, entry_json, format_bytes, open_archive_lazy, open_repository,
+    terminal_width,
+};
 use chrono::{DateTime, Local};
 use clap::ArgMatches;
-use colored::Colorize;
-use ddup_bak::archive::entries::{Entry, EntryMode};
+use colored::{ColoredString, Colorize};
+use ddup_bak::archive::entries::{Entry, EntryMode, EntryWalk};
 use std::{collections::HashMap, io::Write, path::Path, time::SystemTime};
 
 #[inline]
-fn format_bytes(bytes: u64) -> String {
-    if bytes < 1024 {
-        format!("{bytes}")
-    } else if bytes < 1024 * 1024 {
-        format!("{:.1}K", bytes as f64 / 1024.0)
-    } else if bytes < 1024 * 1024 * 1024 {
-        format!("{:.1}M", bytes as f64 / (1024.0 * 1024.0))
-    } else if bytes < 1024 * 1024 * 1024 * 1024 {
-        format!("{:.1}G", bytes as f64 / (1024.0 * 1024.0 * 1024.0))
-    } else {
-        format!("{:.1}T", bytes as f64 / (1024.0 * 1024.0 * 1024.0 * 1024.0))
-    }
-}
-
-#[inline]
-fn render_unix_permissions(mode: EntryMode) -> String {
+pub fn render_unix_permissions(mode: EntryMode) -> String {
     let mode_bits = mode.bits();
     let mut result = String::with_capacity(9);
 
@@ -40,15 +28,63 @@ fn render_unix_permissions(mode: EntryMode) -> String {
     result
 }
 
+/// Like coreutils, the default time style shows the year instead of the time
+/// for entries older than this or from the future.
+const RECENT_SECS: u64 = 60 * 60 * 24 * 365 / 2;
+
+#[derive(Debug, Clone, Copy)]
+enum TimeStyle {
+    Default,
+    FullIso,
+    Relative,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Long { time_style: TimeStyle, du: bool },
+    Short,
+    OnePerLine,
+}
+
+fn format_relative(time: SystemTime) -> String {
+    let Ok(age) = SystemTime::now().duration_since(time) else {
+        return "now".to_string();
+    };
+
+    let seconds = age.as_secs();
+    match seconds {
+        0..60 => format!("{seconds}s ago"),
+        60..3600 => format!("{}m ago", seconds / 60),
+        3600..86400 => format!("{}h ago", seconds / 3600),
+        86400..2592000 => format!("{}d ago", seconds / 86400),
+        2592000..31536000 => format!("{}mo ago", seconds / 2592000),
+        _ => format!("{}y ago", seconds / 31536000),
+    }
+}
+
 #[inline]
-fn format_time(time: SystemTime) -> String {
+fn format_time(time: SystemTime, style: TimeStyle) -> String {
     let datetime: DateTime<Local> = time.into();
 
-    datetime.format("%b %e %H:%M").to_string()
+    match style {
+        TimeStyle::FullIso => datetime.format("%Y-%m-%d %H:%M:%S%.9f %z").to_string(),
+        TimeStyle::Relative => format!("{:>8}", format_relative(time)),
+        TimeStyle::Default => {
+            let recent = SystemTime::now()
+                .duration_since(time)
+                .is_ok_and(|age| age.as_secs() < RECENT_SECS);
+
+            if recent {
+                datetime.format("%b %e %H:%M").to_string()
+            } else {
+                datetime.format("%b %e  %Y").to_string()
+            }
+        }
+    }
 }
 
 #[cfg(unix)]
-fn get_username(uid: u32) -> String {
+pub fn get_username(uid: u32) -> String {
     use libc::{getpwuid, getpwuid_r, passwd, uid_t};
     use std::{ffi::CStr, mem::MaybeUninit};
 
@@ -90,7 +126,7 @@ fn get_username(uid: u32) -> String {
 }
 
 #[cfg(unix)]
-fn get_groupname(gid: u32) -> String {
+pub fn get_groupname(gid: u32) -> String {
     use libc::{getgrgid, getgrgid_r, gid_t, group};
     use std::{ffi::CStr, mem::MaybeUninit};
 
@@ -130,12 +166,12 @@ fn get_groupname(gid: u32) -> String {
 }
 
 #[cfg(not(unix))]
-fn get_username(uid: u32) -> String {
+pub fn get_username(uid: u32) -> String {
     uid.to_string()
 }
 
 #[cfg(not(unix))]
-fn get_groupname(gid: u32) -> String {
+pub fn get_groupname(gid: u32) -> String {
     gid.to_string()
 }
 
@@ -144,151 +180,140 @@ fn is_executable(_mode: EntryMode) -> bool {
     _mode.bits() & 0o111 != 0
 }
 
+fn colored_name(entry: &Entry) -> ColoredString {
+    match entry {
+        Entry::File(file) if is_executable(file.mode) => file.name.green().bold(),
+        Entry::File(file) => file.name.normal(),
+        Entry::Directory(dir) => dir.name.blue().bold(),
+        Entry::Symlink(link) => link.name.bright_cyan().bold(),
+    }
+}
+
+/// The `ls -l` type character of an entry.
+#[inline]
+fn type_char(entry: &Entry) -> char {
+    match entry {
+        Entry::File(_) => '-',
+        Entry::Directory(_) => 'd',
+        Entry::Symlink(_) => 'l',
+    }
+}
+
+/// Like on most unix file systems a directory is linked from its parent, itself and each subdirectory.
+#[inline]
+fn link_count(entry: &Entry) -> std::io::Result<usize> {
+    match entry {
+        Entry::Directory(dir) => Ok(2 + dir.entries()?.iter().filter(|e| e.is_directory()).count()),
+        _ => Ok(1),
+    }
+}
+
+/// The logical size of all files below `entries`.
+fn recursive_size(entries: &[Entry]) -> std::io::Result<u64> {
+    entries
+        .iter()
+        .map(|entry| match entry {
+            Entry::File(file) => Ok(file.size_real),
+            Entry::Directory(dir) => recursive_size(dir.entries()?),
+            Entry::Symlink(_) => Ok(0),
+        })
+        .sum()
+}
+
+/// The size column of an entry, directories only have one with `du` set.
+#[inline]
+fn entry_size(entry: &Entry, du: bool) -> std::io::Result<Option<u64>> {
+    match entry {
+        Entry::File(file) => Ok(Some(file.size_real)),
+        Entry::Symlink(link) => Ok(Some(link.target.len() as u64)),
+        Entry::Directory(dir) if du => Ok(Some(recursive_size(dir.entries()?)?)),
+        Entry::Directory(_) => Ok(None),
+    }
+}
+
+#[derive(Default)]
+struct ColumnWidths {
+    link_count: usize,
+    user: usize,
+    group: usize,
+    size: usize,
+    name: usize,
+}
+
+fn render_size(size: Option<u64>) -> String {
+    size.map_or_else(|| "-".to_string(), format_bytes)
+}
+
 fn calculate_column_widths(
     entries: &[&Entry],
+    sizes: &[Option<u64>],
     users: &mut HashMap<u32, String>,
     groups: &mut HashMap<u32, String>,
-) -> (usize, usize, usize, usize) {
-    let mut max_link_count_len = 0;
-    let mut max_user_len = 0;
-    let mut max_group_len = 0;
-    let mut max_size_len = 0;
-
-    for entry in entries {
-        let link_count = match entry {
-            Entry::Directory(dir) => dir.entries.len(),
-            _ => 1,
-        };
-
-        let size = match entry {
-            Entry::File(file) => format_bytes(file.size_real).len(),
-            Entry::Symlink(link) => format_bytes(link.target.len() as u64).len(),
-            _ => 1,
-        };
+) -> std::io::Result<ColumnWidths> {
+    let mut widths = ColumnWidths::default();
+    let has_symlinks = entries.iter().any(|entry| entry.is_symlink());
 
+    for (entry, size) in entries.iter().zip(sizes) {
         let (uid, gid) = entry.owner();
 
         let username = users.entry(uid).or_insert_with(|| get_username(uid));
         let groupname = groups.entry(gid).or_insert_with(|| get_groupname(gid));
 
-        max_link_count_len = max_link_count_len.max(link_count.to_string().len());
-        max_user_len = max_user_len.max(username.len());
-        max_group_len = max_group_len.max(groupname.len());
-        max_size_len = max_size_len.max(size);
+        widths.link_count = widths.link_count.max(link_count(entry)?.to_string().len());
+        widths.user = widths.user.max(username.len());
+        widths.group = widths.group.max(groupname.len());
+        widths.size = widths.size.max(render_size(*size).len());
+
+        // names are only padded when there is a target column after them
+        if has_symlinks {
+            widths.name = widths.name.max(entry.name().chars().count());
+        }
     }
 
-    (
-        max_link_count_len,
-        max_user_len,
-        max_group_len,
-        max_size_len,
-    )
+    Ok(widths)
 }
 
 fn render_entry(
     entry: &Entry,
-    link_count_width: usize,
-    user_width: usize,
-    group_width: usize,
-    size_width: usize,
+    size: Option<u64>,
+    widths: &ColumnWidths,
     users: &HashMap<u32, String>,
     groups: &HashMap<u32, String>,
-) -> String {
-    let file_type = match entry {
-        Entry::File(_) => '-',
-        Entry::Directory(_) => 'd',
-        Entry::Symlink(_) => 'l',
-    };
-
+    time_style: TimeStyle,
+) -> std::io::Result<String> {
     let (uid, gid) = entry.owner();
     let username = users.get(&uid).expect("user should exist");
     let groupname = groups.get(&gid).expect("group should exist");
 
-    let perms = render_unix_permissions(entry.mode());
-    let time_str = format_time(entry.mtime());
-
-    match entry {
-        Entry::File(file) => {
-            let name = if is_executable(file.mode) {
-                file.name.green().bold()
-            } else {
-                file.name.normal()
-            };
-
-            format!(
-                "{}{} {:>width_link_count$} {:<width_user$} {:<width_group$} {:>width_size$} {} {}\n",
-                file_type,
-                perms,
-                1,
-                username,
-                groupname,
-                format_bytes(file.size_real),
-                time_str,
-                name,
-                width_link_count = link_count_width,
-                width_user = user_width,
-                width_group = group_width,
-                width_size = size_width
-            )
-        }
-        Entry::Directory(dir) => {
-            let name = dir.name.blue().bold();
-            let link_count = dir.entries.len();
-
-            format!(
-                "{}{} {:>width_link_count$} {:<width_user$} {:<width_group$} {:>width_size$} {} {}\n",
-                file_type,
-                perms,
-                link_count,
-                username,
-                groupname,
-                0,
-                time_str,
-                name,
-                width_link_count = link_count_width,
-                width_user = user_width,
-                width_group = group_width,
-                width_size = size_width
-            )
-        }
-        Entry::Symlink(link) => {
-            let name = link.name.bright_cyan().bold();
-            let target = format!(
-                "-> {}",
-                if is_executable(link.mode) {
-                    link.target.blue().on_green()
-                } else {
-                    link.target.blue()
-                }
-            );
-
-            format!(
-                "{}{} {:>width_link_count$} {:<width_user$} {:<width_group$} {:>width_size$} {} {} {}\n",
-                file_type,
-                perms,
-                1,
-                username,
-                groupname,
-                format_bytes(link.target.len() as u64),
-                time_str,
-                name,
-                target,
-                width_link_count = link_count_width,
-                width_user = user_width,
-                width_group = group_width,
-                width_size = size_width
-            )
-        }
+    let mut line = format!(
+        "{}{} {:>width_link_count$} {:<width_user$} {:<width_group$} {:>width_size$} {} {}",
+        type_char(entry),
+        render_unix_permissions(entry.mode()),
+        link_count(entry)?,
+        username,
+        groupname,
+        render_size(size),
+        format_time(entry.mtime(), time_style),
+        colored_name(entry),
+        width_link_count = widths.link_count,
+        width_user = widths.user,
+        width_group = widths.group,
+        width_size = widths.size
+    );
+
+    if let Entry::Symlink(link) = entry {
+        let padding = widths.name.saturating_sub(link.name.chars().count());
+        line.extend(std::iter::repeat_n(' ', padding));
+        line.push_str(" -> ");
+        line.push_str(&link.target);
     }
-}
 
-fn render_entries(mut entries: Vec<&Entry>) -> std::io::Result<()> {
-    let mut users = HashMap::new();
-    let mut groups = HashMap::new();
+    line.push('\n');
 
-    let (link_count_width, user_width, group_width, size_width) =
-        calculate_column_widths(&entries, &mut users, &mut groups);
+    Ok(line)
+}
 
+fn sort_entries(entries: &mut [&Entry]) {
     entries.sort_unstable_by(|a, b| {
         let a_name = a.name().to_lowercase();
         let b_name = b.name().to_lowercase();
@@ -299,18 +324,21 @@ fn render_entries(mut entries: Vec<&Entry>) -> std::io::Result<()> {
 
         a_name.cmp(&b_name)
     });
+}
+
+fn render_long(
+    entries: &[&Entry],
+    sizes: &[Option<u64>],
+    time_style: TimeStyle,
+) -> std::io::Result<()> {
+    let mut users = HashMap::new();
+    let mut groups = HashMap::new();
+
+    let widths = calculate_column_widths(entries, sizes, &mut users, &mut groups)?;
 
     let mut lock = std::io::stdout().lock();
-    for entry in entries {
-        let rendered_entry = render_entry(
-            entry,
-            link_count_width,
-            user_width,
-            group_width,
-            size_width,
-            &users,
-            &groups,
-        );
+    for (entry, size) in entries.iter().zip(sizes) {
+        let rendered_entry = render_entry(entry, *size, &widths, &users, &groups, time_style)?;
 
         lock.write_all(rendered_entry.as_bytes())?;
     }
@@ -318,84 +346,210 @@ fn render_entries(mut entries: Vec<&Entry>) -> std::io::Result<()> {
     Ok(())
 }
 
-pub fn ls(name: &str, matches: &ArgMatches) -> std::io::Result<i32> {
-    let repository = open_repository(false);
-    let path = matches.get_one::<String>("path");
+/// Renders names in columns filled top to bottom like `ls`, using as few rows as fit the width.
+fn render_short(entries: &[&Entry], width: usize) -> std::io::Result<()> {
+    const GAP: usize = 2;
+
+    let widths = entries
+        .iter()
+        .map(|e| e.name().chars().count())
+        .collect::<Vec<_>>();
+
+    let mut rows = entries.len().max(1);
+    let mut column_widths = Vec::new();
+    for candidate in 1..=entries.len() {
+        let columns = entries.len().div_ceil(candidate);
+        let candidate_widths = (0..columns)
+            .map(|column| {
+                widths
+                    .iter()
+                    .skip(column * candidate)
+                    .take(candidate)
+                    .max()
+                    .copied()
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+
+        if candidate_widths.iter().sum::<usize>() + GAP * (columns - 1) <= width
+            || candidate == entries.len()
+        {
+            rows = candidate;
+            column_widths = candidate_widths;
+            break;
+        }
+    }
 
-    if !repository
-        .list_archives()?
-        .into_iter()
-        .any(|name| name == *name)
-    {
-        println!(
-            "{} {} {}",
-            "backup".red(),
-            name.cyan(),
-            "does not exist!".red()
-        );
+    let mut lock = std::io::stdout().lock();
+    for row in 0..rows {
+        let mut line = String::new();
+
+        for (column, column_width) in column_widths.iter().enumerate() {
+            let Some(entry) = entries.get(column * rows + row) else {
+                break;
+            };
+
+            let is_last = entries.get((column + 1) * rows + row).is_none();
+            line.push_str(&colored_name(entry).to_string());
 
-        return Ok(1);
+            if !is_last {
+                let padding = column_width - widths[column * rows + row] + GAP;
+                line.extend(std::iter::repeat_n(' ', padding));
+            }
+        }
+
+        line.push('\n');
+        lock.write_all(line.as_bytes())?;
     }
 
-    let archive = repository.get_archive(name)?;
+    Ok(())
+}
 
-    let path = Path::new(path.map_or(".", |s| s.as_str()));
-    if let Some(entry) = archive.find_archive_entry(path) {
-        let entries = match entry {
-            Entry::Directory(dir) => {
-                let mut entries = Vec::with_capacity(dir.entries.len());
+fn render_block(parent: &Path, mut entries: Vec<&Entry>, format: Format) -> std::io::Result<()> {
+    if Output::is_json() {
+        let parent = parent.strip_prefix(".").unwrap_or(parent);
+        for entry in entries {
+            Output::json(entry_json(&parent.join(entry.name()), entry));
+        }
+
+        return Ok(());
+    }
 
-                for entry in dir.entries.iter() {
-                    entries.push(entry);
-                }
+    sort_entries(&mut entries);
 
-                entries
+    let (time_style, du) = match format {
+        Format::Long { time_style, du } => (time_style, du),
+        Format::Short => return render_short(&entries, terminal_width().unwrap_or(0)),
+        Format::OnePerLine => return render_short(&entries, 0),
+    };
+
+    // symlink sizes are the length of their target, which is not data stored in the backup
+    let sizes = entries
+        .iter()
+        .map(|entry| entry_size(entry, du))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let total = entries
+        .iter()
+        .zip(&sizes)
+        .filter(|(entry, _)| !entry.is_symlink())
+        .filter_map(|(_, size)| *size)
+        .sum();
+
+    println!("total {} entries, {}", entries.len(), format_bytes(total));
+
+    render_long(&entries, &sizes, time_style)
+}
+
+/// Lists `entries` of the directory `parent` in the short or the long format.
+pub fn list_entries(parent: &Path, entries: Vec<&Entry>, long: bool) -> std::io::Result<()> {
+    render_block(
+        parent,
+        entries,
+        if long {
+            Format::Long {
+                time_style: TimeStyle::Default,
+                du: false,
             }
-            _ => Vec::from([entry]),
-        };
-
-        println!(
-            "total {} entries, {}",
-            entries.len(),
-            format_bytes(
-                entries
-                    .iter()
-                    .map(|e| match e {
-                        Entry::File(f) => f.size_real,
-                        Entry::Symlink(s) => s.target.len() as u64,
-                        _ => 0,
-                    })
-                    .sum()
-            )
-        );
+        } else {
+            Format::Short
+        },
+    )
+}
 
-        render_entries(entries)?;
-    } else if path.components().all(|c| c.as_os_str() == ".") {
-        println!(
-            "total {} entries, {}",
-            archive.entries().len(),
-            format_bytes(
-                archive
-                    .entries()
-                    .iter()
-                    .map(|e| match e {
-                        Entry::File(f) => f.size_real,
-                        Entry::Symlink(s) => s.target.len() as u64,
-                        _ => 0,
-                    })
-                    .sum()
-            )
-        );
+/// Renders one block per directory like `ls -R`, subdirectories deeper than `max_depth` are skipped.
+fn render_recursive(
+    path: &Path,
+    entries: &[Entry],
+    max_depth: usize,
+    format: Format,
+) -> std::io::Result<()> {
+    let mut directories = Vec::from([(path.to_path_buf(), entries)]);
+    for item in EntryWalk::new(path, entries) {
+        let (path, depth, entry) = item?;
+        if let Entry::Directory(dir) = entry
+            && depth < max_depth
+        {
+            directories.push((path, dir.entries()?));
+        }
+    }
+
+    directories.sort_by_cached_key(|(path, _)| {
+        path.components()
+            .map(|c| c.as_os_str().to_string_lossy().to_lowercase())
+            .collect::<Vec<_>>()
+    });
+
+    for (i, (path, entries)) in directories.into_iter().enumerate() {
+        if !Output::is_json() {
+            if i > 0 {
+                println!();
+            }
+
+            println!("{}:", path.display().to_string().blue().bold());
+        }
+
+        render_block(&path, entries.iter().collect(), format)?;
+    }
+
+    Ok(())
+}
+
+pub fn ls(name: &str, matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+    let path = matches.get_one::<String>("path");
+    let max_depth = match matches.get_one::<usize>("depth") {
+        Some(depth) => Some(*depth),
+        None if matches.get_flag("recursive") => Some(usize::MAX),
+        None => None,
+    };
 
-        render_entries(archive.entries().iter().collect::<Vec<_>>())?;
+    let time_style = match matches.get_one::<String>("time_style").map(|s| s.as_str()) {
+        Some("full-iso") => TimeStyle::FullIso,
+        Some("relative") => TimeStyle::Relative,
+        _ => TimeStyle::Default,
+    };
+    let format = if matches.get_flag("long") {
+        Format::Long {
+            time_style,
+            du: matches.get_flag("du"),
+        }
+    } else if matches.get_flag("one_per_line") {
+        Format::OnePerLine
     } else {
-        println!(
+        Format::Short
+    };
+
+    let archive = match open_archive_lazy(&repository, name)? {
+        Ok(archive) => archive,
+        Err(code) => return Ok(code),
+    };
+
+    let path = Path::new(path.map_or(".", |s| s.as_str()));
+    if let Some(entry) = archive.find_archive_entry(path)? {
+        match entry {
+            Entry::Directory(dir) if let Some(max_depth) = max_depth => {
+                render_recursive(path, dir.entries()?, max_depth, format)?
+            }
+            Entry::Directory(dir) => render_block(path, dir.entries()?.iter().collect(), format)?,
+            _ => render_block(
+                path.parent().unwrap_or(Path::new("")),
+                Vec::from([entry]),
+                format,
+            )?,
+        }
+    } else if path.components().all(|c| c.as_os_str() == ".") {
+        match max_depth {
+            Some(max_depth) => render_recursive(path, archive.entries(), max_depth, format)?,
+            None => render_block(Path::new(""), archive.entries().iter().collect(), format)?,
+        }
+    } else {
+        Output::error(format!(
             "{} {}",
             path.display().to_string().cyan(),
             "does not exist!".red()
-        );
+        ));
 
-        return Ok(1);
+        return Ok(EXIT_NOT_FOUND);
     }
 
     Ok(0)
@@ -1,2 +1,9 @@
 pub mod cat;
+pub mod du;
+pub mod extract;
+pub mod find;
+pub mod grep;
 pub mod ls;
+pub mod shell;
+pub mod stat;
+pub mod tree;
@@ -0,0 +1,256 @@
+use crate::commands::{
+    backup::fs::{cat::cat_file, extract::extract_entry, ls::list_entries, stat::print_entry_stat},
+    open_archive, open_repository,
+};
+use clap::ArgMatches;
+use colored::Colorize;
+use ddup_bak::archive::{Archive, entries::Entry};
+use std::{
+    io::{BufRead, IsTerminal, Write},
+    path::{Component, Path, PathBuf},
+};
+
+const HELP: &[(&str, &str)] = &[
+    ("ls [-l] [path]", "list a directory"),
+    (
+        "cd [path]",
+        "change the current directory, the root without a path",
+    ),
+    ("pwd", "print the current directory"),
+    ("cat <path>", "print a file"),
+    ("stat <path>", "show the metadata of an entry"),
+    (
+        "get <path> [dest]",
+        "extract an entry to the local file system",
+    ),
+    ("help", "show this help"),
+    ("exit", "leave the shell"),
+];
+
+/// Splits a command line on whitespace, single and double quotes group words
+/// and a backslash escapes the next character.
+fn split_line(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', _) => {
+                if let Some(c) = chars.next() {
+                    word.get_or_insert_default().push(c);
+                }
+            }
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                word.get_or_insert_default();
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (c, None) if c.is_whitespace() => {
+                if let Some(word) = word.take() {
+                    words.push(word);
+                }
+            }
+            (c, _) => word.get_or_insert_default().push(c),
+        }
+    }
+
+    words.extend(word);
+
+    words
+}
+
+/// Resolves `path` against `cwd`, both relative to the archive root.
+fn resolve(cwd: &Path, path: &str) -> PathBuf {
+    let mut resolved = if path.starts_with('/') {
+        PathBuf::new()
+    } else {
+        cwd.to_path_buf()
+    };
+
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            _ => {}
+        }
+    }
+
+    resolved
+}
+
+enum Target<'a> {
+    Root(&'a [Entry]),
+    Entry(&'a Entry),
+}
+
+fn lookup<'a>(archive: &'a Archive, path: &Path) -> std::io::Result<Option<Target<'a>>> {
+    if path.as_os_str().is_empty() {
+        return Ok(Some(Target::Root(archive.entries())));
+    }
+
+    Ok(archive.find_archive_entry(path)?.map(Target::Entry))
+}
+
+fn print_error(path: &Path, message: &str) {
+    println!(
+        "{} {}",
+        format!("/{}", path.display()).cyan(),
+        message.red()
+    );
+}
+
+pub fn shell(name: &str, _matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+
+    let archive = match open_archive(&repository, name)? {
+        Ok(archive) => archive,
+        Err(code) => return Ok(code),
+    };
+
+    let interactive = std::io::stdin().is_terminal();
+    let mut cwd = PathBuf::new();
+    let mut lines = std::io::stdin().lock().lines();
+
+    loop {
+        if interactive {
+            print!(
+                "{}:{} ",
+                name.cyan(),
+                format!("/{}>", cwd.display()).blue().bold()
+            );
+            std::io::stdout().flush()?;
+        }
+
+        let Some(line) = lines.next() else {
+            if interactive {
+                println!();
+            }
+
+            break;
+        };
+
+        let words = split_line(&line?);
+        let Some((command, args)) = words.split_first() else {
+            continue;
+        };
+
+        match (command.as_str(), args) {
+            ("exit" | "quit", _) => break,
+            ("help", _) => {
+                for (usage, description) in HELP {
+                    println!("{:<20} {}", usage.cyan(), description.bright_black());
+                }
+            }
+            ("pwd", _) => println!("/{}", cwd.display()),
+            ("ls", args) => {
+                let long = args.iter().any(|arg| arg == "-l");
+                let path = resolve(
+                    &cwd,
+                    args.iter()
+                        .find(|arg| *arg != "-l")
+                        .map_or(".", |arg| arg.as_str()),
+                );
+
+                match lookup(&archive, &path)? {
+                    Some(Target::Root(entries)) => {
+                        list_entries(Path::new(""), entries.iter().collect(), long)?
+                    }
+                    Some(Target::Entry(Entry::Directory(dir))) => {
+                        list_entries(&path, dir.entries()?.iter().collect(), long)?
+                    }
+                    Some(Target::Entry(entry)) => list_entries(
+                        path.parent().unwrap_or(Path::new("")),
+                        Vec::from([entry]),
+                        long,
+                    )?,
+                    None => print_error(&path, "does not exist!"),
+                }
+            }
+            ("cd", args) => {
+                let path = resolve(&cwd, args.first().map_or("/", |arg| arg.as_str()));
+
+                match lookup(&archive, &path)? {
+                    Some(Target::Root(_)) | Some(Target::Entry(Entry::Directory(_))) => cwd = path,
+                    Some(Target::Entry(_)) => print_error(&path, "is not a directory!"),
+                    None => print_error(&path, "does not exist!"),
+                }
+            }
+            ("cat", [path]) => {
+                let path = resolve(&cwd, path);
+
+                match lookup(&archive, &path)? {
+                    Some(Target::Entry(entry @ Entry::File(_))) => {
+                        cat_file(
+                            &repository,
+                            &format!("/{}", path.display()),
+                            entry,
+                            0,
+                            None,
+                            None,
+                            false,
+                        )?;
+                    }
+                    Some(_) => print_error(&path, "is not a file!"),
+                    None => print_error(&path, "does not exist!"),
+                }
+            }
+            ("stat", [path]) => {
+                let path = resolve(&cwd, path);
+
+                match lookup(&archive, &path)? {
+                    Some(Target::Entry(entry)) => {
+                        print_entry_stat(&repository, &path, entry, false)?
+                    }
+                    Some(Target::Root(entries)) => println!(
+                        "{:<8} /\n{:<8} {}",
+                        "path".bright_black(),
+                        "entries".bright_black(),
+                        entries.len().to_string().cyan()
+                    ),
+                    None => print_error(&path, "does not exist!"),
+                }
+            }
+            ("get", [path, destination @ ..]) if destination.len() <= 1 => {
+                let path = resolve(&cwd, path);
+
+                match lookup(&archive, &path)? {
+                    Some(Target::Entry(entry)) => {
+                        extract_entry(
+                            &repository,
+                            name,
+                            entry,
+                            destination.first().map(|d| d.as_str()),
+                            0,
+                            false,
+                            false,
+                        )?;
+                    }
+                    Some(Target::Root(_)) => {
+                        print_error(&path, "is the archive root, use restore!")
+                    }
+                    None => print_error(&path, "does not exist!"),
+                }
+            }
+            ("cat" | "stat" | "get", _) => {
+                let usage = HELP
+                    .iter()
+                    .find(|(usage, _)| usage.starts_with(command.as_str()))
+                    .map_or("", |(usage, _)| usage);
+
+                println!("{} {}", "usage:".red(), usage.cyan());
+            }
+            (command, _) => println!(
+                "{} {}{}",
+                "unknown command".red(),
+                command.cyan(),
+                ", try help".red()
+            ),
+        }
+    }
+
+    Ok(0)
+}
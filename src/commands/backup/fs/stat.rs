@@ -0,0 +1,280 @@
+use crate::commands::{
+    EXIT_NOT_FOUND, Output,
+    backup::fs::ls::{get_groupname, get_username, render_unix_permissions},
+    entry_json, format_bytes, open_archive_lazy, open_repository,
+};
+use chrono::{DateTime, Local};
+use clap::ArgMatches;
+use colored::Colorize;
+use ddup_bak::{
+    archive::entries::{Entry, FileEntry},
+    chunks::ids::ChunkIdDecoder,
+    repository::Repository,
+};
+use std::{collections::HashSet, path::Path};
+
+struct ChunkLine {
+    id: u64,
+    hash: Option<String>,
+    references: u64,
+    stored: Option<u64>,
+}
+
+/// Totals of everything below a directory.
+#[derive(Default)]
+struct Totals {
+    files: u64,
+    directories: u64,
+    symlinks: u64,
+    bytes: u64,
+}
+
+impl Totals {
+    fn walk(&mut self, entries: &[Entry]) -> std::io::Result<()> {
+        for entry in entries {
+            match entry {
+                Entry::File(file) => {
+                    self.files += 1;
+                    self.bytes += file.size_real;
+                }
+                Entry::Directory(dir) => {
+                    self.directories += 1;
+                    self.walk(dir.entries()?)?;
+                }
+                Entry::Symlink(_) => self.symlinks += 1,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the chunk ID list of a file, the same chunk may appear more than once.
+fn file_chunks(repository: &Repository, file: &FileEntry) -> std::io::Result<Vec<ChunkLine>> {
+    let mut file = file.clone();
+    let mut ids = ChunkIdDecoder::new(&file);
+    let mut chunks = Vec::new();
+
+    while let Some(id) = ids.next_id(&mut file)? {
+        let hash = repository.chunk_index.get_chunk_hash(id);
+
+        chunks.push(ChunkLine {
+            id,
+            hash: hash.map(|hash| hash.iter().map(|b| format!("{b:02x}")).collect()),
+            references: hash.map_or(0, |hash| repository.chunk_index.references(&hash)),
+            stored: match hash {
+                Some(hash) => Some(repository.chunk_index.storage.chunk_size(&hash)?),
+                None => None,
+            },
+        });
+    }
+
+    Ok(chunks)
+}
+
+fn field(name: &str, value: impl std::fmt::Display) {
+    println!("{:<12} {}", name.bright_black(), value);
+}
+
+/// Prints everything the archive knows about one entry, the chunk IDs of a file only with `chunks` set.
+pub fn print_entry_stat(
+    repository: &Repository,
+    path: &Path,
+    entry: &Entry,
+    chunks: bool,
+) -> std::io::Result<()> {
+    let (uid, gid) = entry.owner();
+    let mtime: DateTime<Local> = entry.mtime().into();
+    let mode = entry.mode();
+
+    let mut json = entry_json(path, entry);
+    json["mode_symbolic"] = render_unix_permissions(mode).into();
+    json["user"] = get_username(uid).into();
+    json["group"] = get_groupname(gid).into();
+
+    let mut lines = Vec::new();
+    lines.push(("path", format!("/{}", path.display()).cyan()));
+    lines.push((
+        "type",
+        match entry {
+            Entry::File(_) => "file",
+            Entry::Directory(_) => "directory",
+            Entry::Symlink(_) => "symlink",
+        }
+        .cyan(),
+    ));
+    lines.push((
+        "mode",
+        format!(
+            "{} ({:o})",
+            render_unix_permissions(mode),
+            mode.bits() & 0o7777
+        )
+        .cyan(),
+    ));
+    lines.push((
+        "owner",
+        format!(
+            "{} ({uid}) / {} ({gid})",
+            get_username(uid),
+            get_groupname(gid)
+        )
+        .cyan(),
+    ));
+    lines.push((
+        "mtime",
+        mtime
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+            .cyan(),
+    ));
+
+    let mut chunk_lines = Vec::new();
+    match entry {
+        Entry::File(file) => {
+            chunk_lines = file_chunks(repository, file)?;
+
+            let mut seen = HashSet::new();
+            let stored = chunk_lines
+                .iter()
+                .filter(|chunk| seen.insert(chunk.id))
+                .filter_map(|chunk| chunk.stored)
+                .sum::<u64>();
+            let id_list = file.size_compressed.unwrap_or(file.size);
+
+            lines.push((
+                "size",
+                format!(
+                    "{} ({} bytes)",
+                    format_bytes(file.size_real),
+                    file.size_real
+                )
+                .cyan(),
+            ));
+            lines.push((
+                "stored",
+                format!("{} ({} bytes)", format_bytes(stored), stored).cyan(),
+            ));
+            lines.push((
+                "compression",
+                format!("{:?}", file.compression).to_lowercase().cyan(),
+            ));
+            lines.push((
+                "id list",
+                format!("{} bytes at offset {}", id_list, file.offset).cyan(),
+            ));
+            lines.push((
+                "chunks",
+                format!("{} ({} unique)", chunk_lines.len(), seen.len()).cyan(),
+            ));
+
+            json["stored"] = stored.into();
+            json["compression"] = format!("{:?}", file.compression).to_lowercase().into();
+            json["id_list_size"] = id_list.into();
+            json["offset"] = file.offset.into();
+            json["chunk_count"] = chunk_lines.len().into();
+            json["unique_chunks"] = seen.len().into();
+        }
+        Entry::Directory(dir) => {
+            let mut totals = Totals::default();
+            totals.walk(dir.entries()?)?;
+
+            lines.push(("entries", dir.entries()?.len().to_string().cyan()));
+            lines.push((
+                "contains",
+                format!(
+                    "{} files, {} directories, {} symlinks",
+                    totals.files, totals.directories, totals.symlinks
+                )
+                .cyan(),
+            ));
+            lines.push((
+                "size",
+                format!("{} ({} bytes)", format_bytes(totals.bytes), totals.bytes).cyan(),
+            ));
+
+            json["size"] = totals.bytes.into();
+            json["entries"] = dir.entries()?.len().into();
+            json["files"] = totals.files.into();
+            json["directories"] = totals.directories.into();
+            json["symlinks"] = totals.symlinks.into();
+        }
+        Entry::Symlink(link) => {
+            lines.push(("target", link.target.cyan()));
+            lines.push(("target dir", link.target_dir.to_string().cyan()));
+
+            json["target_dir"] = link.target_dir.into();
+        }
+    }
+
+    if Output::is_json() {
+        if chunks {
+            json["chunks"] = chunk_lines
+                .iter()
+                .map(|chunk| {
+                    serde_json::json!({
+                        "id": chunk.id,
+                        "hash": chunk.hash,
+                        "references": chunk.references,
+                        "stored": chunk.stored,
+                    })
+                })
+                .collect();
+        }
+
+        Output::json(json);
+
+        return Ok(());
+    }
+
+    for (name, value) in lines {
+        field(name, value);
+    }
+
+    if chunks && !chunk_lines.is_empty() {
+        println!();
+        println!(
+            "{:>8}  {:<64}  {:>6}  {:>9}",
+            "ID".bright_black(),
+            "HASH".bright_black(),
+            "REFS".bright_black(),
+            "STORED".bright_black()
+        );
+
+        for chunk in chunk_lines {
+            println!(
+                "{:>8}  {:<64}  {:>6}  {:>9}",
+                chunk.id,
+                chunk.hash.as_deref().unwrap_or("missing").cyan(),
+                chunk.references,
+                chunk.stored.map_or("-".to_string(), format_bytes)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn stat(name: &str, matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+    let path = Path::new(matches.get_one::<String>("path").expect("required"));
+    let chunks = matches.get_flag("chunks");
+
+    let archive = match open_archive_lazy(&repository, name)? {
+        Ok(archive) => archive,
+        Err(code) => return Ok(code),
+    };
+
+    let Some(entry) = archive.find_archive_entry(path)? else {
+        Output::error(format!(
+            "{} {}",
+            path.display().to_string().cyan(),
+            "does not exist!".red()
+        ));
+
+        return Ok(EXIT_NOT_FOUND);
+    };
+
+    print_entry_stat(&repository, path, entry, chunks)?;
+
+    Ok(0)
+}
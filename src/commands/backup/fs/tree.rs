@@ -0,0 +1,203 @@
+use crate::commands::{EXIT_NOT_FOUND, Output, format_bytes, open_archive_lazy, open_repository};
+use clap::ArgMatches;
+use colored::{ColoredString, Colorize};
+use ddup_bak::archive::entries::{Entry, EntryWalk};
+use std::{io::Write, path::Path};
+
+struct Connectors {
+    branch: &'static str,
+    last: &'static str,
+    pipe: &'static str,
+    blank: &'static str,
+}
+
+const UNICODE: Connectors = Connectors {
+    branch: "├── ",
+    last: "└── ",
+    pipe: "│   ",
+    blank: "    ",
+};
+
+const ASCII: Connectors = Connectors {
+    branch: "|-- ",
+    last: "`-- ",
+    pipe: "|   ",
+    blank: "    ",
+};
+
+#[cfg(unix)]
+fn terminal_width() -> Option<usize> {
+    unsafe {
+        if libc::isatty(libc::STDOUT_FILENO) == 0 {
+            return None;
+        }
+
+        let mut size: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0 {
+            Some(size.ws_col as usize)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn terminal_width() -> Option<usize> {
+    None
+}
+
+/// Cuts `text` to at most `width` characters, marking the cut with a trailing `~`.
+fn truncate(text: String, width: Option<usize>) -> String {
+    match width {
+        Some(width) if text.chars().count() > width => {
+            let mut text = text
+                .chars()
+                .take(width.saturating_sub(1))
+                .collect::<String>();
+            text.push('~');
+
+            text
+        }
+        _ => text,
+    }
+}
+
+fn render_name(entry: &Entry, width: Option<usize>) -> ColoredString {
+    match entry {
+        Entry::Directory(dir) => truncate(dir.name.clone(), width).blue().bold(),
+        Entry::Symlink(link) => {
+            let name = truncate(format!("{} -> {}", link.name, link.target), width);
+
+            match name.split_once(" -> ") {
+                Some((name, target)) => {
+                    format!("{} -> {}", name.bright_cyan().bold(), target.blue()).normal()
+                }
+                None => name.bright_cyan().bold(),
+            }
+        }
+        Entry::File(file) if file.mode.bits() & 0o111 != 0 => {
+            truncate(file.name.clone(), width).green().bold()
+        }
+        Entry::File(file) => truncate(file.name.clone(), width).normal(),
+    }
+}
+
+pub fn tree(name: &str, matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+    let path = matches.get_one::<String>("path");
+    let max_depth = matches.get_one::<usize>("depth").copied();
+    let dirs_only = matches.get_flag("dirs_only");
+    let connectors = if matches.get_flag("ascii") {
+        &ASCII
+    } else {
+        &UNICODE
+    };
+
+    let archive = match open_archive_lazy(&repository, name)? {
+        Ok(archive) => archive,
+        Err(code) => return Ok(code),
+    };
+
+    let path = Path::new(path.map_or(".", |s| s.as_str()));
+    let entries = match archive.find_archive_entry(path)? {
+        Some(Entry::Directory(dir)) => dir.entries()?,
+        Some(_) => {
+            Output::error(format!(
+                "{} {}",
+                path.display().to_string().cyan(),
+                "is not a directory!".red()
+            ));
+
+            return Ok(1);
+        }
+        None if path.components().all(|c| c.as_os_str() == ".") => archive.entries(),
+        None => {
+            Output::error(format!(
+                "{} {}",
+                path.display().to_string().cyan(),
+                "does not exist!".red()
+            ));
+
+            return Ok(EXIT_NOT_FOUND);
+        }
+    };
+
+    let items = EntryWalk::new(path, entries)
+        .sorted()
+        .filter(|item| {
+            item.as_ref().is_err()
+                || item.as_ref().is_ok_and(|(_, depth, entry)| {
+                    max_depth.is_none_or(|max_depth| *depth < max_depth)
+                        && (!dirs_only || entry.is_directory())
+                })
+        })
+        .map(|item| item.map(|(_, depth, entry)| (depth, entry)))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    // an entry is the last of its directory when no sibling follows before the walk
+    // returns to a shallower depth
+    let mut last = vec![false; items.len()];
+    let mut sibling_follows = Vec::new();
+    for (i, (depth, _)) in items.iter().enumerate().rev() {
+        sibling_follows.resize(depth + 1, false);
+
+        last[i] = !sibling_follows[*depth];
+        sibling_follows[*depth] = true;
+    }
+
+    let width = terminal_width();
+    let mut lock = std::io::stdout().lock();
+    writeln!(lock, "{}", path.display().to_string().blue().bold())?;
+
+    let mut directories = 0;
+    let mut files = 0;
+    let mut bytes = 0;
+    let mut ancestors_last = Vec::new();
+
+    for (i, (depth, entry)) in items.iter().enumerate() {
+        ancestors_last.truncate(*depth);
+
+        let mut prefix = String::new();
+        for ancestor_last in ancestors_last.iter() {
+            prefix.push_str(if *ancestor_last {
+                connectors.blank
+            } else {
+                connectors.pipe
+            });
+        }
+        prefix.push_str(if last[i] {
+            connectors.last
+        } else {
+            connectors.branch
+        });
+
+        let name_width = width.map(|width| width.saturating_sub(prefix.chars().count()).max(1));
+        writeln!(lock, "{}{}", prefix, render_name(entry, name_width))?;
+
+        match entry {
+            Entry::Directory(_) => directories += 1,
+            Entry::File(file) => {
+                files += 1;
+                bytes += file.size_real;
+            }
+            Entry::Symlink(_) => files += 1,
+        }
+
+        ancestors_last.push(last[i]);
+    }
+
+    writeln!(lock)?;
+    if dirs_only {
+        writeln!(lock, "{directories} directories")?;
+    } else {
+        writeln!(
+            lock,
+            "{} directories, {} files, {} total",
+            directories,
+            files,
+            format_bytes(bytes)
+        )?;
+    }
+
+    Ok(0)
+}
@@ -0,0 +1,172 @@
+use crate::commands::{Output, format_bytes, json_time, open_archive, open_repository};
+use chrono::{DateTime, Local};
+use clap::ArgMatches;
+use colored::Colorize;
+use ddup_bak::archive::entries::Entry;
+use std::{collections::BTreeMap, path::PathBuf};
+
+const LARGEST_FILES: usize = 10;
+
+pub fn info(matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+    let name = matches.get_one::<String>("name").expect("required");
+
+    let archive = match open_archive(&repository, name)? {
+        Ok(archive) => archive,
+        Err(code) => return Ok(code),
+    };
+
+    Output::status("collecting backup info...".bright_black());
+
+    let (mut files, mut directories, mut symlinks) = (0u64, 0u64, 0u64);
+    let mut compression: BTreeMap<String, u64> = BTreeMap::new();
+    let mut largest: Vec<(PathBuf, u64)> = Vec::new();
+    for item in archive.walk() {
+        let (path, _, entry) = item?;
+        match entry {
+            Entry::File(file) => {
+                files += 1;
+                *compression
+                    .entry(format!("{:?}", file.compression).to_lowercase())
+                    .or_default() += 1;
+                largest.push((path, file.size_real));
+            }
+            Entry::Directory(_) => directories += 1,
+            Entry::Symlink(_) => symlinks += 1,
+        }
+    }
+
+    largest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    largest.truncate(LARGEST_FILES);
+
+    let stats = repository.archive_stats(name)?;
+    let metadata = repository.archive_metadata(name)?;
+    let archive_size = std::fs::metadata(repository.archive_path(name))?.len();
+    let sidecar = repository.archive_metadata_path(name).exists();
+    let checkpoint = repository.partial_archive_path(name).exists();
+    let shared_bytes = stats.stored_bytes - stats.exclusive_bytes;
+
+    Output::status(format!(
+        "{} {}",
+        "collecting backup info...".bright_black(),
+        "DONE".green().bold()
+    ));
+
+    if Output::is_json() {
+        Output::json(serde_json::json!({
+            "name": name,
+            "created": json_time(stats.created),
+            "tags": metadata.tags,
+            "description": metadata.description,
+            "files": files,
+            "directories": directories,
+            "symlinks": symlinks,
+            "archive_bytes": archive_size,
+            "logical_bytes": stats.logical_bytes,
+            "chunks": stats.chunks,
+            "stored_bytes": stats.stored_bytes,
+            "exclusive_bytes": stats.exclusive_bytes,
+            "shared_bytes": shared_bytes,
+            "compression": compression,
+            "largest_files": largest
+                .iter()
+                .map(|(path, size)| serde_json::json!({
+                    "path": path.to_string_lossy(),
+                    "size": size,
+                }))
+                .collect::<Vec<_>>(),
+            "metadata_sidecar": sidecar,
+            "checkpoint": checkpoint,
+        }));
+
+        return Ok(0);
+    }
+
+    let created: DateTime<Local> = stats.created.into();
+
+    Output::status("");
+    println!("{:<14} {}", "name", name.cyan());
+    println!(
+        "{:<14} {}",
+        "created",
+        created.format("%Y-%m-%d %H:%M:%S").to_string().cyan()
+    );
+    if !metadata.tags.is_empty() {
+        println!("{:<14} {}", "tags", metadata.tags.join(", ").yellow());
+    }
+    if let Some(description) = &metadata.description {
+        println!("{:<14} {}", "description", description.italic());
+    }
+    println!(
+        "{:<14} {}",
+        "entries",
+        format!("{files} files, {directories} directories, {symlinks} symlinks").cyan()
+    );
+    println!(
+        "{:<14} {}",
+        "logical",
+        format_bytes(stats.logical_bytes).cyan()
+    );
+    println!(
+        "{:<14} {} {}",
+        "stored",
+        format_bytes(stats.stored_bytes).cyan(),
+        format!(
+            "in {} chunks, {} exclusive, {} shared",
+            stats.chunks,
+            format_bytes(stats.exclusive_bytes),
+            format_bytes(shared_bytes)
+        )
+        .bright_black()
+    );
+    println!(
+        "{:<14} {}",
+        "archive file",
+        format_bytes(archive_size).cyan()
+    );
+    println!(
+        "{:<14} {}",
+        "compression",
+        if compression.is_empty() {
+            "none".bright_black()
+        } else {
+            compression
+                .iter()
+                .map(|(format, count)| format!("{format} {count}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+                .cyan()
+        }
+    );
+    println!(
+        "{:<14} {}",
+        "metadata",
+        if sidecar {
+            "sidecar present".cyan()
+        } else {
+            "none".bright_black()
+        }
+    );
+    if checkpoint {
+        println!(
+            "{:<14} {}",
+            "checkpoint",
+            "an interrupted create of this name can be resumed".yellow()
+        );
+    }
+
+    if !largest.is_empty() {
+        println!();
+        println!("{}", "largest files".bright_black());
+
+        for (path, size) in largest.iter() {
+            println!(
+                "{:>9} {}",
+                format_bytes(*size),
+                path.display().to_string().cyan()
+            );
+        }
+    }
+
+    Ok(0)
+}
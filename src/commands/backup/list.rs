@@ -1,31 +1,155 @@
-use crate::commands::open_repository;
+use crate::commands::{Output, format_bytes, json_time, open_repository};
+use chrono::{DateTime, Local};
 use clap::ArgMatches;
 use colored::Colorize;
+use ddup_bak::repository::ArchiveInfo;
 
-pub fn list(_matches: &ArgMatches) -> std::io::Result<i32> {
-    let repository = open_repository(false);
+const MAX_NAME_WIDTH: usize = 40;
+
+fn truncate_name(name: &str) -> String {
+    if name.chars().count() <= MAX_NAME_WIDTH {
+        return name.to_string();
+    }
 
-    println!("{}", "listing backups...".bright_black());
+    let mut truncated = name.chars().take(MAX_NAME_WIDTH - 1).collect::<String>();
+    truncated.push('…');
+
+    truncated
+}
 
-    let list = repository.list_archives()?;
+fn render_table(archives: &[ArchiveInfo]) {
+    let show_tags = archives.iter().any(|a| !a.metadata.tags.is_empty());
+
+    let names = archives
+        .iter()
+        .map(|a| truncate_name(&a.name))
+        .collect::<Vec<_>>();
+    let name_width = names
+        .iter()
+        .map(|n| n.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(4);
 
     println!(
-        "{} {}",
-        "listing backups...".bright_black(),
-        "DONE".green().bold()
+        "{:<name_width$} {:<19} {:>9} {:>9} {:>8}{}",
+        "NAME".bright_black(),
+        "CREATED".bright_black(),
+        "SIZE".bright_black(),
+        "LOGICAL".bright_black(),
+        "ENTRIES".bright_black(),
+        if show_tags {
+            format!(" {}", "TAGS".bright_black())
+        } else {
+            String::new()
+        }
     );
 
+    for (archive, name) in archives.iter().zip(names) {
+        let created: DateTime<Local> = archive.created.into();
+
+        println!(
+            "{:<name_width$} {:<19} {:>9} {:>9} {:>8}{}",
+            name.cyan(),
+            created
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+                .bright_black(),
+            format_bytes(archive.size),
+            format_bytes(archive.logical_bytes),
+            archive.entries,
+            if !archive.metadata.tags.is_empty() {
+                format!(" {}", archive.metadata.tags.join(",").yellow())
+            } else {
+                String::new()
+            }
+        );
+
+        if let Some(description) = &archive.metadata.description {
+            println!(
+                "{:<name_width$} {}",
+                "",
+                description.bright_black().italic()
+            );
+        }
+    }
+}
+
+pub fn list(matches: &ArgMatches) -> std::io::Result<i32> {
+    let sort = matches.get_one::<String>("sort").expect("required");
+    let reverse = matches.get_flag("reverse");
+    let format = matches.get_one::<String>("format").expect("required");
+
+    if format == "json" {
+        Output::Json.set();
+    }
+
+    let repository = open_repository(false);
+
+    let plain = format == "plain" && !Output::is_json();
+
+    if !plain {
+        Output::status("listing backups...".bright_black());
+    }
+
+    let mut list = repository.list_archives_detailed()?;
+
+    if !plain {
+        Output::status(format!(
+            "{} {}",
+            "listing backups...".bright_black(),
+            "DONE".green().bold()
+        ));
+    }
+
+    if let Some(tags) = matches.get_many::<String>("tag") {
+        let tags = tags.collect::<Vec<_>>();
+        list.retain(|a| tags.iter().all(|tag| a.metadata.has_tag(tag)));
+    }
+
+    match sort.as_str() {
+        "size" => list.sort_by_key(|a| a.size),
+        "date" => list.sort_by_key(|a| a.created),
+        _ => list.sort_by(|a, b| a.name.cmp(&b.name)),
+    }
+
+    if reverse {
+        list.reverse();
+    }
+
+    if Output::is_json() {
+        for backup in list.iter() {
+            Output::json(serde_json::json!({
+                "name": backup.name,
+                "created": json_time(backup.created),
+                "size": backup.size,
+                "entries": backup.entries,
+                "logical_bytes": backup.logical_bytes,
+                "tags": backup.metadata.tags,
+                "description": backup.metadata.description,
+            }));
+        }
+
+        return Ok(if list.is_empty() { 1 } else { 0 });
+    }
+
+    if plain {
+        for backup in list.iter() {
+            println!("{}", backup.name);
+        }
+
+        return Ok(if list.is_empty() { 1 } else { 0 });
+    }
+
     if list.is_empty() {
-        println!();
-        println!("{}", "no backups found".red());
+        Output::status("");
+        Output::error(format!("{}", "no backups found".red()));
         return Ok(1);
     }
 
-    println!();
+    Output::status("");
 
-    for backup in list {
-        println!("{}", backup.cyan().bold().underline());
-    }
+    render_table(&list);
 
     Ok(0)
 }
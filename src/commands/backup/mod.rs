@@ -1,6 +1,13 @@
 pub mod convert;
 pub mod create;
 pub mod delete;
+pub mod diff;
 pub mod fs;
+pub mod info;
 pub mod list;
+pub mod mount;
+pub mod prune;
+pub mod rename;
 pub mod restore;
+pub mod tag;
+pub mod verify;
@@ -0,0 +1,39 @@
+use crate::commands::{Output, open_archive, open_repository};
+use clap::ArgMatches;
+use colored::Colorize;
+
+/// Mounting needs a FUSE file system implementation, which this build does not contain yet.
+/// The command checks its arguments and fails clearly instead of leaving the mountpoint empty.
+pub fn mount(matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+    let name = matches.get_one::<String>("name").expect("required");
+    let mountpoint = matches.get_one::<String>("mountpoint").expect("required");
+
+    if let Err(code) = open_archive(&repository, name)? {
+        return Ok(code);
+    }
+
+    if !std::path::Path::new(mountpoint).is_dir() {
+        Output::error(format!(
+            "{} {} {}",
+            "mountpoint".red(),
+            mountpoint.cyan(),
+            "is not a directory!".red()
+        ));
+
+        return Ok(1);
+    }
+
+    Output::error(format!(
+        "{} {}",
+        "mounting backups is not supported by this build,".red(),
+        format!(
+            "use {} or {} instead!",
+            "backup fs shell".cyan(),
+            "backup fs extract".cyan()
+        )
+        .red()
+    ));
+
+    Ok(1)
+}
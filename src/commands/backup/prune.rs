@@ -0,0 +1,214 @@
+use crate::commands::{EXIT_USAGE, Output, Progress, confirm, deletion_impact, open_repository};
+use chrono::{DateTime, Local};
+use clap::ArgMatches;
+use colored::Colorize;
+use ddup_bak::{
+    chunks::lock::LockMode,
+    repository::{PruneDecision, Repository, RetentionPolicy},
+};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+fn format_age(created: SystemTime) -> String {
+    let seconds = SystemTime::now()
+        .duration_since(created)
+        .unwrap_or_default()
+        .as_secs();
+
+    if seconds < 60 * 60 {
+        format!("{}m", seconds / 60)
+    } else if seconds < 60 * 60 * 24 {
+        format!("{}h", seconds / (60 * 60))
+    } else {
+        format!("{}d", seconds / (60 * 60 * 24))
+    }
+}
+
+fn render_decisions(decisions: &[PruneDecision], dry_run: bool) {
+    let name_width = decisions
+        .iter()
+        .map(|d| d.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+
+    for decision in decisions {
+        let created: DateTime<Local> = decision.created.into();
+
+        let status = if decision.keep() {
+            "keep  ".green()
+        } else if dry_run {
+            "remove".yellow()
+        } else {
+            "remove".red()
+        };
+
+        println!(
+            "{} {:<name_width$} {} {:>5} {}",
+            status,
+            decision.name.cyan(),
+            created.format("%Y-%m-%d %H:%M").to_string().bright_black(),
+            format_age(decision.created),
+            if decision.keep() {
+                format!("kept: {}", decision.reasons.join(", "))
+            } else {
+                String::new()
+            }
+            .bright_black()
+        );
+    }
+}
+
+pub fn prune(matches: &ArgMatches) -> std::io::Result<i32> {
+    let dry_run = matches.get_flag("dry_run");
+    let clean = matches.get_flag("clean");
+    let policy = RetentionPolicy {
+        keep_last: *matches.get_one::<usize>("keep_last").expect("required"),
+        keep_daily: *matches.get_one::<usize>("keep_daily").expect("required"),
+        keep_weekly: *matches.get_one::<usize>("keep_weekly").expect("required"),
+        keep_monthly: *matches.get_one::<usize>("keep_monthly").expect("required"),
+    };
+
+    if policy.is_empty() {
+        Output::error(format!(
+            "{}",
+            "at least one of --keep-last, --keep-daily, --keep-weekly or --keep-monthly must be greater than 0"
+                .red()
+        ));
+
+        return Ok(EXIT_USAGE);
+    }
+
+    let repository = open_repository(!dry_run);
+
+    if dry_run {
+        let decisions = repository.plan_prune(&policy)?;
+        render_decisions(&decisions, true);
+
+        println!();
+        println!(
+            "{} {} {}",
+            "would remove".bright_black(),
+            decisions
+                .iter()
+                .filter(|d| !d.keep())
+                .count()
+                .to_string()
+                .cyan(),
+            "backups".bright_black()
+        );
+
+        return Ok(0);
+    }
+
+    // planning under the lock keeps the confirmed removals from going stale before the prune
+    repository.with_write_lock(LockMode::Destructive, |repository| {
+        prune_locked(repository, &policy, clean)
+    })
+}
+
+fn prune_locked(
+    repository: &Repository,
+    policy: &RetentionPolicy,
+    clean: bool,
+) -> std::io::Result<i32> {
+    let removals = repository
+        .plan_prune(policy)?
+        .into_iter()
+        .filter(|d| !d.keep())
+        .map(|d| d.name)
+        .collect::<Vec<_>>();
+    if !removals.is_empty() && !confirm(deletion_impact(repository, &removals)?) {
+        return Ok(1);
+    }
+
+    Output::status("pruning backups...".bright_black());
+
+    let mut progress = Progress::new(usize::MAX);
+    progress.spinner(|progress, spinner| {
+        format!(
+            "\r\x1B[K {} {} {}",
+            "dereferencing chunks...".bright_black().italic(),
+            spinner.cyan(),
+            progress.text.read().cyan()
+        )
+    });
+
+    let decisions = repository.prune(
+        policy,
+        Some({
+            let progress = progress.clone();
+
+            Arc::new(move |chunk, deleted| {
+                progress.set_text(format!(
+                    "{} {}",
+                    format!("chunk #{chunk}").cyan(),
+                    if deleted {
+                        "(deleted)".green()
+                    } else {
+                        "(not deleted)".red()
+                    }
+                ));
+            })
+        }),
+    )?;
+
+    progress.finish();
+
+    Output::status(format!(
+        "{} {}",
+        "pruning backups...".bright_black(),
+        "DONE".green().bold()
+    ));
+    Output::status("");
+
+    render_decisions(&decisions, false);
+
+    if clean {
+        Output::status("");
+        Output::status("cleaning repository...".bright_black());
+
+        let cleaned = Arc::new(AtomicU64::new(0));
+        let mut progress = Progress::new(usize::MAX);
+        progress.spinner({
+            let cleaned = Arc::clone(&cleaned);
+
+            move |_, spinner| {
+                format!(
+                    "\r\x1B[K {} {} {}",
+                    "cleaning repository...".bright_black().italic(),
+                    spinner.cyan(),
+                    format!("{} chunks", cleaned.load(Ordering::SeqCst)).cyan()
+                )
+            }
+        });
+
+        let plan = repository.clean(
+            false,
+            Some(Arc::new(move |_, _| {
+                cleaned.fetch_add(1, Ordering::SeqCst);
+            })),
+        )?;
+
+        progress.finish();
+
+        Output::status(format!(
+            "{} {}",
+            "cleaning repository...".bright_black(),
+            "DONE".green().bold()
+        ));
+        Output::result(format!(
+            "{} {} {}",
+            "reclaimed".bright_black(),
+            plan.chunk_count.to_string().cyan(),
+            "chunks".bright_black()
+        ));
+    }
+
+    Ok(0)
+}
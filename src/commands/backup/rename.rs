@@ -0,0 +1,115 @@
+use crate::commands::{
+    EXIT_NOT_FOUND, Output, Progress, confirm, deletion_impact, open_repository,
+};
+use clap::ArgMatches;
+use colored::Colorize;
+use std::sync::Arc;
+
+pub fn rename(matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(true);
+    let name = matches.get_one::<String>("name").expect("required");
+    let new_name = matches.get_one::<String>("new_name").expect("required");
+    let force = matches.get_flag("force");
+
+    let archives = repository.list_archives()?;
+    if !archives.iter().any(|archive| archive == name) {
+        Output::error(format!(
+            "{} {} {}",
+            "backup".red(),
+            name.cyan(),
+            "does not exist!".red()
+        ));
+
+        return Ok(EXIT_NOT_FOUND);
+    }
+
+    if name == new_name {
+        Output::error(format!(
+            "{} {} {}",
+            "backup".red(),
+            name.cyan(),
+            "already has that name!".red()
+        ));
+
+        return Ok(1);
+    }
+
+    if archives.iter().any(|archive| archive == new_name) {
+        if !force {
+            Output::error(format!(
+                "{} {} {} {} {}",
+                "backup".red(),
+                new_name.cyan(),
+                "already exists! Use".red(),
+                "--force".cyan(),
+                "to replace it.".red()
+            ));
+
+            return Ok(1);
+        }
+
+        if !confirm(deletion_impact(
+            &repository,
+            std::slice::from_ref(new_name),
+        )?) {
+            return Ok(1);
+        }
+
+        Output::status(format!(
+            "{} {}{}",
+            "deleting".bright_black(),
+            new_name.cyan(),
+            "...".bright_black()
+        ));
+
+        let mut progress = Progress::new(usize::MAX);
+        progress.spinner(|progress, spinner| {
+            format!(
+                "\r\x1B[K {} {} {}",
+                "dereferencing chunks...".bright_black().italic(),
+                spinner.cyan(),
+                progress.text.read().cyan()
+            )
+        });
+
+        let result = repository.delete_archive(
+            new_name,
+            Some({
+                let progress = progress.clone();
+
+                Arc::new(move |chunk, _| {
+                    progress.set_text(format!("chunk #{chunk}"));
+                })
+            }),
+        );
+
+        progress.finish();
+        result?;
+
+        Output::status(format!(
+            "{} {}{} {}",
+            "deleting".bright_black(),
+            new_name.cyan(),
+            "...".bright_black(),
+            "DONE".green().bold()
+        ));
+    }
+
+    Output::status("renaming backup...".bright_black());
+
+    repository.rename_archive(name, new_name)?;
+
+    Output::status(format!(
+        "{} {}",
+        "renaming backup...".bright_black(),
+        "DONE".green().bold()
+    ));
+    Output::result(format!(
+        "{} {} {}",
+        name.cyan(),
+        "->".bright_black(),
+        new_name.cyan()
+    ));
+
+    Ok(0)
+}
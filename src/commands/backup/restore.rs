@@ -1,8 +1,123 @@
-use crate::commands::{Progress, open_repository};
+use crate::commands::{
+    EXIT_NOT_FOUND, Output, Progress, cancellation, confirm, format_bytes, open_archive,
+    open_repository,
+};
 use clap::ArgMatches;
 use colored::Colorize;
-use ddup_bak::archive::entries::Entry;
-use std::sync::Arc;
+use ddup_bak::{
+    archive::{
+        Archive,
+        entries::{Entry, EntryWalk},
+    },
+    repository::{ProgressEvent, RestoreMode, RestoreOptions, RestoreOwnership},
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+pub fn parse_id_map(value: &str) -> Result<(u32, u32), String> {
+    let (from, to) = value
+        .split_once(':')
+        .ok_or_else(|| format!("invalid id mapping {value:?}, expected FROM:TO"))?;
+
+    Ok((
+        from.parse()
+            .map_err(|_| format!("invalid id {from:?} in mapping {value:?}"))?,
+        to.parse()
+            .map_err(|_| format!("invalid id {to:?} in mapping {value:?}"))?,
+    ))
+}
+
+/// Removes the entries of `directory` that are not in `entries` or have another type there,
+/// restoring `entries` into it afterwards leaves only the content of the backup behind.
+/// Returns the number of entries removed.
+fn remove_unlisted(directory: &Path, entries: &[Entry], root: bool) -> std::io::Result<usize> {
+    let listed = entries
+        .iter()
+        .map(|entry| (entry.name(), entry))
+        .collect::<HashMap<_, _>>();
+
+    let existing = match std::fs::read_dir(directory) {
+        Ok(existing) => existing,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    let mut removed = 0;
+    for entry in existing {
+        let entry = entry?;
+
+        let name = entry.file_name();
+        if root && name == ".ddup-bak" {
+            continue;
+        }
+
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+
+        match name.to_str().and_then(|name| listed.get(name)) {
+            Some(Entry::Directory(listed)) if file_type.is_dir() => {
+                removed += remove_unlisted(&path, listed.entries()?, false)?;
+                continue;
+            }
+            Some(Entry::File(_)) if file_type.is_file() => continue,
+            Some(Entry::Symlink(_)) if file_type.is_symlink() => continue,
+            _ => {}
+        }
+
+        if file_type.is_dir() {
+            std::fs::remove_dir_all(path)?;
+        } else {
+            std::fs::remove_file(path)?;
+        }
+
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+/// Expands the requested archive paths, arguments containing glob characters are matched
+/// against every path in the archive like `fs find` does.
+/// Returns the paths to restore and the arguments that matched nothing.
+fn resolve_paths(
+    archive: &Archive,
+    arguments: &[&String],
+) -> std::io::Result<(Vec<PathBuf>, Vec<String>)> {
+    let mut paths = Vec::new();
+    let mut missing = Vec::new();
+
+    for argument in arguments {
+        let before = paths.len();
+
+        if argument.contains(['*', '?', '[', '{']) {
+            let glob = globset::Glob::new(argument.trim_start_matches('/'))
+                .map_err(std::io::Error::other)?
+                .compile_matcher();
+
+            for item in archive.walk() {
+                let (path, _, _) = item?;
+                if glob.is_match(&path) {
+                    paths.push(path);
+                }
+            }
+        } else {
+            let path = Path::new(argument.trim_start_matches('/'));
+
+            if archive.find_archive_entry(path)?.is_some() {
+                paths.push(path.to_path_buf());
+            }
+        }
+
+        if paths.len() == before {
+            missing.push(argument.to_string());
+        }
+    }
+
+    Ok((paths, missing))
+}
 
 pub fn restore(matches: &ArgMatches) -> std::io::Result<i32> {
     let repository = open_repository(false);
@@ -10,61 +125,148 @@ pub fn restore(matches: &ArgMatches) -> std::io::Result<i32> {
     let name = matches.get_one::<String>("name").expect("required");
     let destination = matches.get_one::<String>("destination");
     let threads = matches.get_one::<usize>("threads").expect("required");
+    let skip_identical = matches.get_flag("skip_identical");
+    let verify_content = matches.get_flag("verify_content");
+    let force = matches.get_flag("force");
+    let no_chown = matches.get_flag("no_chown");
+    let strict_ownership = matches.get_flag("strict_ownership");
+    let ignore_missing = matches.get_flag("ignore_missing");
+    let uid_map = matches
+        .get_many::<(u32, u32)>("uid_map")
+        .map(|ids| ids.copied().collect::<HashMap<_, _>>())
+        .unwrap_or_default();
+    let gid_map = matches
+        .get_many::<(u32, u32)>("gid_map")
+        .map(|ids| ids.copied().collect::<HashMap<_, _>>())
+        .unwrap_or_default();
 
-    if !repository
-        .list_archives()?
-        .into_iter()
-        .any(|name| name == *name)
-    {
-        println!(
-            "{} {} {}",
-            "backup".red(),
-            name.cyan(),
-            "does not exist!".red()
-        );
+    let archive = match open_archive(&repository, name)? {
+        Ok(archive) => archive,
+        Err(code) => return Ok(code),
+    };
 
-        return Ok(1);
+    let arguments = matches
+        .get_many::<String>("path")
+        .map(|paths| paths.collect::<Vec<_>>())
+        .unwrap_or_default();
+    let (paths, missing) = resolve_paths(&archive, &arguments)?;
+
+    for argument in missing.iter() {
+        if ignore_missing && !paths.is_empty() {
+            Output::result(format!(
+                "{} {} {}",
+                "warning:".yellow(),
+                argument.cyan(),
+                "does not exist in the backup".bright_black()
+            ));
+        } else {
+            Output::error(format!(
+                "{} {}",
+                argument.cyan(),
+                "does not exist in the backup!".red()
+            ));
+        }
     }
 
-    println!("{}", "restoring backup...".bright_black());
+    if !missing.is_empty() && (paths.is_empty() || !ignore_missing) {
+        if !paths.is_empty() {
+            Output::error(format!(
+                "{} {} {}",
+                "nothing restored, pass".red(),
+                "--ignore-missing".cyan(),
+                "to restore the paths that exist".red()
+            ));
+        }
 
-    let archive = repository.get_archive(name)?;
+        return Ok(EXIT_NOT_FOUND);
+    }
 
-    fn recursive_count_entries(entry: &Entry) -> usize {
-        match entry {
-            Entry::Directory(entries) => {
-                let mut count = 1;
+    for path in paths.iter() {
+        Output::verbose(format!(
+            "{} {}",
+            "selected".bright_black(),
+            path.display().to_string().cyan()
+        ));
+    }
 
-                for entry in entries.entries.iter() {
-                    count += recursive_count_entries(entry);
-                }
+    // replacing removes entries, that always needs confirmation
+    if let Some(destination) = destination
+        && (force || !skip_identical)
+    {
+        let existing = match std::fs::read_dir(destination) {
+            Ok(entries) => entries
+                .flatten()
+                .filter(|entry| entry.file_name() != ".ddup-bak")
+                .count(),
+            Err(_) => 0,
+        };
 
-                count
-            }
-            _ => 1,
+        if existing > 0
+            && !confirm(format!(
+                "{} {} {} {}",
+                if force {
+                    "This will replace"
+                } else {
+                    "This will overwrite files among"
+                }
+                .yellow(),
+                format!("{existing} existing entries").cyan(),
+                "in".yellow(),
+                format!("{destination}.").cyan()
+            ))
+        {
+            return Ok(1);
         }
     }
 
+    Output::status("restoring backup...".bright_black());
+
+    let entries = if arguments.is_empty() {
+        archive.into_entries()?
+    } else {
+        archive.select_entries(&paths.iter().map(|path| path.as_path()).collect::<Vec<_>>())?
+    };
+
+    if let Some(destination) = destination
+        && force
+    {
+        Output::status(format!(
+            "{} {}{}",
+            "removing entries not in the backup from".bright_black(),
+            destination.cyan(),
+            "...".bright_black()
+        ));
+
+        let removed = remove_unlisted(Path::new(destination), &entries, true)?;
+
+        Output::status(format!(
+            "{} {}{} {} {}",
+            "removing entries not in the backup from".bright_black(),
+            destination.cyan(),
+            "...".bright_black(),
+            "DONE".green().bold(),
+            format!("({removed} removed)").bright_black()
+        ));
+    }
+
     let mut total = 0;
-    for entry in archive.entries().iter() {
-        total += recursive_count_entries(entry);
+    let mut total_bytes = 0;
+    for item in EntryWalk::new(Path::new(""), &entries) {
+        let (_, _, entry) = item?;
+
+        total += 1;
+        if let Entry::File(file) = entry {
+            total_bytes += file.size_real;
+        }
     }
 
     let mut progress = Progress::new(total);
-    progress.spinner(|progress, spinner| {
-        format!(
-            "\r\x1B[K {} {} {}/{} ({}%)",
-            "restoring chunks...".bright_black().italic(),
-            spinner.cyan(),
-            progress.progress().to_string().cyan(),
-            progress.total.to_string().cyan(),
-            progress.percent().round().to_string().cyan()
-        )
-    });
-
-    repository.restore_entries(
+    progress.set_total_bytes(total_bytes);
+    progress.bar("restoring chunks...");
+
+    let report = repository.restore_entries_with_options(
         name,
-        archive.into_entries(),
+        entries,
         Some({
             let progress = progress.clone();
 
@@ -73,71 +275,159 @@ pub fn restore(matches: &ArgMatches) -> std::io::Result<i32> {
             })
         }),
         *threads,
-    )?;
+        RestoreOptions {
+            destination: destination.map(PathBuf::from),
+            mode: if skip_identical {
+                RestoreMode::SkipIdentical
+            } else {
+                RestoreMode::Overwrite
+            },
+            ownership: if no_chown {
+                RestoreOwnership::Skip
+            } else if !uid_map.is_empty() || !gid_map.is_empty() {
+                RestoreOwnership::Map(uid_map, gid_map)
+            } else {
+                RestoreOwnership::Preserve
+            },
+            strict_ownership,
+            verify_content,
+            progress: Some({
+                let progress = progress.clone();
+
+                Arc::new(move |event| {
+                    if let ProgressEvent::BytesProcessed(bytes) = event {
+                        progress.incr_bytes(bytes);
+                    }
+                })
+            }),
+            cancel: Some(cancellation()),
+            ..Default::default()
+        },
+    );
 
     progress.finish();
+    let report = report?;
 
-    println!(
+    Output::status(format!(
         "{} {}",
         "restoring backup...".bright_black(),
         "DONE".green().bold()
-    );
+    ));
 
-    if let Some(destination) = destination {
-        println!(
-            "{} {}{}",
-            "restoring to".bright_black(),
-            destination.cyan(),
-            "...".bright_black()
-        );
+    Output::verbose(format!(
+        "{} {} {} {}",
+        "restored".bright_black(),
+        format!("{} files,", report.files_restored).cyan(),
+        format_bytes(report.bytes_written).cyan(),
+        "written".bright_black()
+    ));
 
-        if std::path::Path::new(destination).exists() {
-            for entry in std::fs::read_dir(destination)? {
-                let entry = entry?;
+    if !arguments.is_empty() {
+        Output::status(format!(
+            "{} {} {}{}",
+            "restored".bright_black(),
+            paths.len().to_string().cyan(),
+            if paths.len() == 1 { "path" } else { "paths" }.bright_black(),
+            if missing.is_empty() {
+                String::new()
+            } else {
+                format!(" {}", format!("({} missing)", missing.len()).yellow())
+            }
+        ));
+    }
 
-                let path = entry.path();
-                let Some(file_name) = path.file_name() else {
-                    continue;
-                };
+    for warning in report.warnings.iter() {
+        Output::result(format!("{} {}", "warning:".yellow(), warning));
+    }
 
-                if file_name == ".ddup-bak" {
-                    continue;
-                }
+    if skip_identical {
+        Output::status(format!(
+            "{} {} {}",
+            "skipped".bright_black(),
+            report.skipped_identical.to_string().cyan(),
+            "identical files".bright_black()
+        ));
+    }
 
-                if path.is_file() {
-                    std::fs::remove_file(path)?;
-                } else if path.is_dir() {
-                    std::fs::remove_dir_all(path)?;
-                }
-            }
-        }
+    Output::result(format!(
+        "{} {}",
+        "restored to".bright_black(),
+        report.destination.display().to_string().cyan()
+    ));
 
-        let source = std::path::Path::new(".ddup-bak/archives-restored/").join(name);
-        let destination = std::path::Path::new(destination);
+    Output::json(serde_json::json!({
+        "name": name,
+        "destination": report.destination,
+        "files_restored": report.files_restored,
+        "directories_restored": report.directories_restored,
+        "symlinks_restored": report.symlinks_restored,
+        "bytes_written": report.bytes_written,
+        "skipped_identical": report.skipped_identical,
+        "skipped_existing": report.skipped_existing,
+        "warnings": report.warnings,
+        "paths": paths,
+        "missing": missing,
+    }));
 
-        std::fs::create_dir_all(destination)?;
+    Ok(0)
+}
 
-        for entry in std::fs::read_dir(source)? {
-            let entry = entry?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ddup_bak::repository::{CreateOptions, Repository};
 
-            let path = entry.path();
-            let Some(file_name) = path.file_name() else {
-                continue;
-            };
+    #[test]
+    fn remove_unlisted_keeps_only_archive_entries() {
+        let directory =
+            std::env::temp_dir().join(format!("ddup-bak-restore-unlisted-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
 
-            let destination_path = destination.join(file_name);
+        let source = directory.join("source");
+        std::fs::create_dir_all(source.join("kept")).unwrap();
+        std::fs::write(source.join("kept/file"), "content").unwrap();
+        std::fs::write(source.join("replaced"), "content").unwrap();
 
-            std::fs::rename(path, destination_path)?;
-        }
+        let repository = Repository::new(&directory.join("repository"), 1024, 0, None).unwrap();
+        let entries = repository
+            .create_archive(
+                "archive",
+                Some(ignore::WalkBuilder::new(&source).build()),
+                Some(&source),
+                None,
+                None,
+                1,
+                CreateOptions::default(),
+            )
+            .unwrap()
+            .into_entries()
+            .unwrap();
 
-        println!(
-            "{} {} {} {}",
-            "restoring to".bright_black(),
-            destination.to_string_lossy().cyan(),
-            "...".bright_black(),
-            "DONE".green().bold()
+        let destination = directory.join("destination");
+        std::fs::create_dir_all(destination.join(".ddup-bak")).unwrap();
+        std::fs::create_dir_all(destination.join("kept")).unwrap();
+        std::fs::create_dir_all(destination.join("replaced")).unwrap();
+        std::fs::write(destination.join("kept/file"), "old").unwrap();
+        std::fs::write(destination.join("kept/extra"), "old").unwrap();
+        std::fs::write(destination.join("extra"), "old").unwrap();
+
+        assert_eq!(remove_unlisted(&destination, &entries, true).unwrap(), 3);
+
+        let mut names = std::fs::read_dir(&destination)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [".ddup-bak", "kept"]);
+        assert!(destination.join("kept/file").is_file());
+        assert!(!destination.join("kept/extra").exists());
+
+        assert_eq!(
+            remove_unlisted(&directory.join("missing"), &entries, true).unwrap(),
+            0
         );
-    }
 
-    Ok(0)
+        drop(repository);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
 }
@@ -0,0 +1,97 @@
+use crate::commands::{EXIT_NOT_FOUND, EXIT_USAGE, Output, open_repository};
+use clap::ArgMatches;
+use colored::Colorize;
+use ddup_bak::repository::ArchiveMetadata;
+
+fn print_tags(name: &str, metadata: &ArchiveMetadata) {
+    Output::result(format!(
+        "{} {} {}",
+        name.cyan(),
+        "tags:".bright_black(),
+        if metadata.tags.is_empty() {
+            "none".bright_black()
+        } else {
+            metadata.tags.join(", ").cyan()
+        }
+    ));
+    Output::json(serde_json::json!({
+        "name": name,
+        "tags": metadata.tags,
+    }));
+}
+
+pub fn add(matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(true);
+    let name = matches.get_one::<String>("name").expect("required");
+    let tags = matches.get_many::<String>("tag").expect("required");
+
+    if !repository
+        .list_archives()?
+        .iter()
+        .any(|archive| archive == name)
+    {
+        Output::error(format!(
+            "{} {} {}",
+            "backup".red(),
+            name.cyan(),
+            "does not exist!".red()
+        ));
+
+        return Ok(EXIT_NOT_FOUND);
+    }
+
+    let mut metadata = repository.archive_metadata(name)?;
+    for tag in tags {
+        if let Err(err) = metadata.add_tag(tag) {
+            Output::error(err.to_string().red());
+
+            return Ok(EXIT_USAGE);
+        }
+    }
+
+    repository.set_archive_metadata(name, &metadata)?;
+    print_tags(name, &metadata);
+
+    Ok(0)
+}
+
+pub fn remove(matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(true);
+    let name = matches.get_one::<String>("name").expect("required");
+    let tags = matches.get_many::<String>("tag").expect("required");
+
+    if !repository
+        .list_archives()?
+        .iter()
+        .any(|archive| archive == name)
+    {
+        Output::error(format!(
+            "{} {} {}",
+            "backup".red(),
+            name.cyan(),
+            "does not exist!".red()
+        ));
+
+        return Ok(EXIT_NOT_FOUND);
+    }
+
+    let mut metadata = repository.archive_metadata(name)?;
+    let mut missing = false;
+    for tag in tags {
+        if !metadata.remove_tag(tag) {
+            Output::error(format!(
+                "{} {} {}",
+                "backup".red(),
+                name.cyan(),
+                format!("has no tag {tag}").red()
+            ));
+
+            missing = true;
+        }
+    }
+
+    repository.set_archive_metadata(name, &metadata)?;
+    print_tags(name, &metadata);
+
+    Ok(if missing { EXIT_NOT_FOUND } else { 0 })
+}
@@ -0,0 +1,191 @@
+use crate::commands::{EXIT_INTEGRITY, EXIT_NOT_FOUND, Output, Progress, open_repository};
+use clap::ArgMatches;
+use colored::Colorize;
+use ddup_bak::{
+    archive::entries::Entry,
+    repository::{Repository, VerifyLevel, VerifyReport},
+};
+use std::sync::Arc;
+
+fn recursive_count_files(entry: &Entry) -> std::io::Result<usize> {
+    match entry {
+        Entry::File(_) => Ok(1),
+        Entry::Directory(dir_entry) => dir_entry.entries()?.iter().map(recursive_count_files).sum(),
+        Entry::Symlink(_) => Ok(0),
+    }
+}
+
+fn verify_one(
+    repository: &Repository,
+    name: &str,
+    level: VerifyLevel,
+    threads: usize,
+) -> std::io::Result<VerifyReport> {
+    let total = repository
+        .get_archive(name)?
+        .entries()
+        .iter()
+        .map(recursive_count_files)
+        .sum::<std::io::Result<usize>>()?;
+
+    let mut progress = Progress::new(total);
+    progress.spinner(|progress, spinner| {
+        format!(
+            "\r\x1B[K {} {} {}/{} ({}%)",
+            "verifying files...".bright_black().italic(),
+            spinner.cyan(),
+            progress.progress().to_string().cyan(),
+            progress.total().to_string().cyan(),
+            progress.percent().round().to_string().cyan()
+        )
+    });
+
+    let report = repository.verify_archive(
+        name,
+        level,
+        Some({
+            let progress = progress.clone();
+
+            Arc::new(move |_| {
+                progress.incr(1usize);
+            })
+        }),
+        threads,
+    );
+
+    progress.finish();
+
+    report
+}
+
+fn print_json(name: &str, report: &VerifyReport) {
+    Output::json(serde_json::json!({
+        "name": name,
+        "ok": report.is_ok(),
+        "files_checked": report.files_checked,
+        "chunks_checked": report.chunks_checked,
+        "bytes_checked": report.bytes_checked,
+        "problems": report
+            .problems
+            .iter()
+            .map(|problem| {
+                serde_json::json!({
+                    "path": problem.path.to_string_lossy(),
+                    "chunk_id": problem.chunk_id,
+                    "reason": problem.reason,
+                })
+            })
+            .collect::<Vec<_>>(),
+    }));
+}
+
+fn print_problems(name: &str, report: &VerifyReport) {
+    for problem in report.problems.iter() {
+        println!(
+            "{} {} {} {} {}",
+            "FAILED".red().bold(),
+            name.cyan(),
+            problem.path.display(),
+            match problem.chunk_id {
+                Some(chunk_id) => format!("chunk={chunk_id}"),
+                None => "chunk=-".to_string(),
+            }
+            .bright_black(),
+            problem.reason.red()
+        );
+    }
+}
+
+pub fn verify(matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+    let threads = *matches.get_one::<usize>("threads").expect("required");
+    let level = if matches.get_flag("full") {
+        VerifyLevel::Full
+    } else {
+        VerifyLevel::Quick
+    };
+
+    let names = if matches.get_flag("all") {
+        let mut names = repository.list_archives()?;
+        names.sort();
+
+        names
+    } else {
+        let name = matches.get_one::<String>("name").expect("required");
+
+        if !repository.list_archives()?.iter().any(|n| n == name) {
+            Output::error(format!(
+                "{} {} {}",
+                "backup".red(),
+                name.cyan(),
+                "does not exist!".red()
+            ));
+
+            return Ok(EXIT_NOT_FOUND);
+        }
+
+        vec![name.clone()]
+    };
+
+    let mut failed = 0;
+    for name in names.iter() {
+        Output::status(format!(
+            "{} {}{}",
+            "verifying".bright_black(),
+            name.cyan(),
+            "...".bright_black()
+        ));
+
+        let report = verify_one(&repository, name, level, threads)?;
+
+        if Output::is_json() {
+            if !report.is_ok() {
+                failed += 1;
+            }
+
+            print_json(name, &report);
+            continue;
+        }
+
+        print_problems(name, &report);
+
+        if report.is_ok() {
+            println!(
+                "{} {} {} {} {}",
+                "OK".green().bold(),
+                name.cyan(),
+                format!("files={}", report.files_checked).bright_black(),
+                format!("chunks={}", report.chunks_checked).bright_black(),
+                format!("bytes={}", report.bytes_checked).bright_black()
+            );
+        } else {
+            failed += 1;
+
+            println!(
+                "{} {} {} {} {}",
+                "FAILED".red().bold(),
+                name.cyan(),
+                format!("files={}", report.files_checked).bright_black(),
+                format!("chunks={}", report.chunks_checked).bright_black(),
+                format!("problems={}", report.problems.len()).red()
+            );
+        }
+    }
+
+    if names.len() > 1 && !Output::is_json() {
+        println!();
+        println!(
+            "{} {} {} {}",
+            "verified".bright_black(),
+            names.len().to_string().cyan(),
+            "backups,".bright_black(),
+            if failed == 0 {
+                "all OK".green().bold()
+            } else {
+                format!("{failed} failed").red().bold()
+            }
+        );
+    }
+
+    Ok(if failed == 0 { 0 } else { EXIT_INTEGRITY })
+}
@@ -0,0 +1,116 @@
+use crate::commands::{EXIT_INTEGRITY, Output, Progress, open_repository};
+use clap::ArgMatches;
+use colored::Colorize;
+use std::sync::Arc;
+
+pub fn check(matches: &ArgMatches) -> std::io::Result<i32> {
+    let repair = matches.get_flag("repair");
+    let repository = open_repository(repair);
+
+    Output::status("checking repository...".bright_black());
+
+    let mut progress = Progress::new(usize::MAX);
+    progress.spinner(|progress, spinner| {
+        format!(
+            "\r\x1B[K {} {} {}",
+            "checking archives...".bright_black().italic(),
+            spinner.cyan(),
+            progress.text.read().cyan()
+        )
+    });
+
+    let report = repository.check(
+        Some({
+            let progress = progress.clone();
+
+            Arc::new(move |archive| {
+                progress.set_text(archive.to_string_lossy());
+            })
+        }),
+        repair,
+    )?;
+
+    progress.finish();
+
+    Output::status(format!(
+        "{} {}",
+        "checking repository...".bright_black(),
+        "DONE".green().bold()
+    ));
+
+    println!(
+        "{} {} {}",
+        "checked".bright_black(),
+        report.archives_checked.to_string().cyan(),
+        "archives".bright_black()
+    );
+
+    if report.is_ok() {
+        println!("{}", "no inconsistencies found".green());
+
+        return Ok(0);
+    }
+
+    for mismatch in report.count_mismatches.iter() {
+        println!(
+            "{} {} {} {} {} {}",
+            format!("chunk #{}", mismatch.chunk_id).cyan(),
+            "has".red(),
+            mismatch.index_count.to_string().cyan(),
+            "references in the index, but".red(),
+            mismatch.archive_count.to_string().cyan(),
+            "in archives".red()
+        );
+    }
+    for chunk_id in report.missing_from_index.iter() {
+        println!(
+            "{} {}",
+            format!("chunk #{chunk_id}").cyan(),
+            "is referenced by an archive but missing from the index".red()
+        );
+    }
+    for chunk_id in report.missing_from_storage.iter() {
+        println!(
+            "{} {}",
+            format!("chunk #{chunk_id}").cyan(),
+            "is referenced by an archive but missing from the storage".red()
+        );
+    }
+    for chunk_id in report.unreferenced.iter() {
+        println!(
+            "{} {}",
+            format!("chunk #{chunk_id}").cyan(),
+            "has references in the index but is not used by any archive".red()
+        );
+    }
+    for chunk_id in report.deleted_overlap.iter() {
+        println!(
+            "{} {}",
+            format!("chunk #{chunk_id}").cyan(),
+            "is queued for reuse while still in use".red()
+        );
+    }
+
+    if report.repaired {
+        println!(
+            "{}",
+            "reference counts have been repaired, run clean to free unused chunks".green()
+        );
+
+        if !report.has_missing_chunks() {
+            return Ok(0);
+        }
+    } else if !report.count_mismatches.is_empty()
+        || !report.unreferenced.is_empty()
+        || !report.deleted_overlap.is_empty()
+    {
+        println!(
+            "{} {} {}",
+            "Run".red(),
+            "ddup-bak check --repair".cyan(),
+            "to fix the reference counts.".red()
+        );
+    }
+
+    Ok(EXIT_INTEGRITY)
+}
@@ -1,46 +1,139 @@
-use crate::commands::{Progress, open_repository};
+use crate::commands::{EXIT_LOCK_BUSY, Output, Progress, confirm, format_bytes, open_repository};
 use clap::ArgMatches;
 use colored::Colorize;
-use std::sync::Arc;
+use ddup_bak::{chunks::lock::LockMode, repository::Repository};
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
 
-pub fn clean(_matches: &ArgMatches) -> std::io::Result<i32> {
-    let repository = open_repository(true);
+/// Describes the other processes holding the repository lock, if any.
+fn lock_holders(repository: &Repository) -> Option<String> {
+    if let Err(err) = repository.chunk_index.lock.recover_stale() {
+        log::warn!("Could not check the repository lock for stale holders: {err}");
+    }
 
-    println!("{}", "cleaning repository...".bright_black());
+    let status = match repository.lock_status() {
+        Ok(status) => status,
+        Err(err) => return Some(format!("an unreadable lock file ({err})")),
+    };
+    let readers = status.reader_counts.iter().sum::<u64>();
 
+    match (status.writer_pid, readers) {
+        (None, 0) => None,
+        (Some(pid), 0) => Some(format!("process {pid}")),
+        (None, readers) => Some(format!("{readers} reader(s)")),
+        (Some(pid), readers) => Some(format!("process {pid} and {readers} reader(s)")),
+    }
+}
+
+fn refuse(holders: &str) -> std::io::Result<i32> {
+    Output::error(format!(
+        "{} {}{}",
+        "repository is locked by".red(),
+        holders.cyan(),
+        ", refusing to clean!".red()
+    ));
+
+    Ok(EXIT_LOCK_BUSY)
+}
+
+pub fn clean(matches: &ArgMatches) -> std::io::Result<i32> {
+    let dry_run = matches.get_flag("dry_run");
+    let repository = open_repository(!dry_run);
+
+    if let Some(holders) = lock_holders(&repository) {
+        return refuse(&holders);
+    }
+
+    if dry_run {
+        Output::status("calculating reclaimable space...".bright_black());
+
+        let plan = repository.clean(true, None)?;
+
+        Output::status(format!(
+            "{} {}",
+            "calculating reclaimable space...".bright_black(),
+            "DONE".green().bold()
+        ));
+        println!(
+            "{} {} {} {}",
+            "would delete".bright_black(),
+            format!("{} chunks", plan.chunk_count).cyan(),
+            "freeing".bright_black(),
+            format_bytes(plan.bytes).cyan()
+        );
+
+        return Ok(0);
+    }
+
+    // the lock is reentrant, so holding it keeps other processes from adding references
+    // between planning and deleting, the clean below reuses it
+    let Some(mut lock) = repository.try_lock(LockMode::Destructive)? else {
+        return refuse(&lock_holders(&repository).unwrap_or_else(|| "another process".into()));
+    };
+
+    let plan = repository.clean(true, None)?;
+    if plan.chunk_count > 0
+        && !confirm(format!(
+            "{} {} {}",
+            "This will delete".yellow(),
+            format!("{} unreferenced chunks,", plan.chunk_count).cyan(),
+            format!("freeing {}.", format_bytes(plan.bytes)).yellow()
+        ))
+    {
+        return Ok(1);
+    }
+
+    Output::status("cleaning repository...".bright_black());
+
+    let freed = Arc::new(AtomicU64::new(0));
     let mut progress = Progress::new(usize::MAX);
-    progress.spinner(|progress, spinner| {
-        format!(
-            "\r\x1B[K {} {} {}",
-            "cleaning repository...".bright_black().italic(),
-            spinner.cyan(),
-            progress.text.read().cyan()
-        )
+    progress.spinner({
+        let freed = Arc::clone(&freed);
+
+        move |progress, spinner| {
+            format!(
+                "\r\x1B[K {} {} {} {}",
+                "cleaning repository...".bright_black().italic(),
+                spinner.cyan(),
+                format_bytes(freed.load(Ordering::SeqCst)).cyan(),
+                progress.text.read().cyan()
+            )
+        }
     });
 
-    repository.clean(Some({
-        let progress = progress.clone();
+    let plan = repository.clean(
+        false,
+        Some({
+            let progress = progress.clone();
 
-        Arc::new(move |chunk, deleted| {
-            progress.set_text(format!(
-                "{} {}",
-                format!("chunk #{chunk}").cyan(),
-                if deleted {
+            Arc::new(move |chunk, size| {
+                freed.fetch_add(size, Ordering::SeqCst);
+                progress.set_text(format!(
+                    "{} {}",
+                    format!("chunk #{chunk}").cyan(),
                     "(deleted)".green()
-                } else {
-                    "(not deleted)".red()
-                }
-            ));
-        })
-    }))?;
+                ));
+            })
+        }),
+    )?;
 
     progress.finish();
+    lock.unlock()?;
 
-    println!(
+    Output::status(format!(
         "{} {}",
         "cleaning repository...".bright_black(),
         "DONE".green().bold()
-    );
+    ));
+    Output::result(format!(
+        "{} {} {} {}",
+        "deleted".bright_black(),
+        format!("{} chunks", plan.chunk_count).cyan(),
+        "freeing".bright_black(),
+        format_bytes(plan.bytes).cyan()
+    ));
 
     Ok(0)
 }
@@ -1,6 +1,10 @@
+use crate::commands::{EXIT_USAGE, Output, exit_code, write_config};
 use clap::ArgMatches;
 use colored::Colorize;
-use ddup_bak::repository::Repository;
+use ddup_bak::{
+    chunks::{lock::LockBackend, storage::storage_from_url},
+    repository::Repository,
+};
 use std::path::Path;
 
 pub fn init(matches: &ArgMatches) -> std::io::Result<i32> {
@@ -10,28 +14,86 @@ pub fn init(matches: &ArgMatches) -> std::io::Result<i32> {
         .get_one::<usize>("max_chunk_count")
         .expect("required");
 
+    let storage_url = matches.get_one::<String>("storage");
+    let lock_backend = matches
+        .get_one::<String>("lock_backend")
+        .expect("has default")
+        .parse::<LockBackend>()?;
+
     if std::path::Path::new(directory).join(".ddup-bak").exists() {
-        println!("{} {}", ".ddup-bak".cyan(), "already exists!".red());
+        Output::error(format!(
+            "{} {}",
+            ".ddup-bak".cyan(),
+            "already exists!".red()
+        ));
 
         return Ok(1);
     }
 
-    println!(
+    // the directory itself has no repository, so anything found is a parent
+    if let Ok(outer) = Repository::discover(Path::new(directory)) {
+        Output::error(format!(
+            "{} {}{} {}",
+            "directory is already inside the repository at".yellow(),
+            outer.display().to_string().cyan(),
+            ",".yellow(),
+            "nested repositories back up into each other!".yellow()
+        ));
+
+        if !matches.get_flag("force") {
+            Output::error(format!(
+                "{} {} {}",
+                "Pass".red(),
+                "--force".cyan(),
+                "to create a nested repository anyway.".red()
+            ));
+
+            return Ok(EXIT_USAGE);
+        }
+    }
+
+    let storage = match storage_url {
+        Some(url) if url.starts_with("memory://") => {
+            Output::error(format!(
+                "{} {}",
+                "memory://".cyan(),
+                "storage does not persist between commands!".red()
+            ));
+
+            return Ok(EXIT_USAGE);
+        }
+        Some(url) => match storage_from_url(url, Path::new(directory)) {
+            Ok(storage) => Some(storage),
+            Err(err) => {
+                Output::error(format!("{} {}", "invalid storage:".red(), err));
+
+                return Ok(exit_code(&err));
+            }
+        },
+        None => None,
+    };
+
+    Output::status(format!(
         "{} {} {}",
         "initializing".bright_black(),
         ".ddup-bak".cyan(),
         "...".bright_black()
-    );
+    ));
 
-    Repository::new(Path::new(directory), chunk_size, max_chunk_count, None)?;
+    Repository::new(Path::new(directory), chunk_size, max_chunk_count, storage)?
+        .set_lock_backend(lock_backend)?;
+
+    if let Some(storage_url) = storage_url {
+        write_config(Path::new(directory), storage_url)?;
+    }
 
-    println!(
+    Output::status(format!(
         "{} {} {} {}",
         "initializing".bright_black(),
         ".ddup-bak".cyan(),
         "...".bright_black(),
         "DONE".green().bold()
-    );
+    ));
 
     Ok(0)
 }
@@ -1,61 +1,721 @@
 use colored::Colorize;
-use ddup_bak::repository::Repository;
+use ddup_bak::{
+    archive::{Archive, entries::Entry},
+    chunks::{
+        lock::{LockBackend, LockWaitEvent},
+        storage::{ChunkStorage, storage_from_url},
+    },
+    repository::Repository,
+};
 use parking_lot::RwLock;
 use std::{
-    path::Path,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
     sync::{
-        Arc,
-        atomic::{AtomicBool, AtomicUsize},
+        Arc, LazyLock,
+        atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering},
     },
+    time::{Duration, Instant},
 };
 
 pub mod backup;
+pub mod check;
 pub mod clean;
 pub mod init;
 pub mod rebuild;
+pub mod stats;
 
-pub fn open_repository(save: bool) -> Repository {
-    if let Ok(mut repository) = Repository::open(Path::new("."), None, None) {
-        repository.set_save_on_drop(save);
+/// How commands print their results, selected once by the global `--json` flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// Colored text for people.
+    Human,
+    /// One JSON object per line on stdout, status messages are suppressed
+    /// and errors are printed to stderr.
+    Json,
+}
 
-        repository
+/// Exit codes shared by all commands, listed in `ddup-bak --help`.
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_NOT_FOUND: i32 = 3;
+pub const EXIT_LOCK_BUSY: i32 = 4;
+pub const EXIT_INTEGRITY: i32 = 5;
+/// Like shells report a process killed by SIGINT.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Maps a library error to the exit code reported for it.
+pub fn exit_code(err: &std::io::Error) -> i32 {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => EXIT_NOT_FOUND,
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::ResourceBusy => EXIT_LOCK_BUSY,
+        std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => EXIT_INTEGRITY,
+        std::io::ErrorKind::InvalidInput => EXIT_USAGE,
+        std::io::ErrorKind::Interrupted => EXIT_INTERRUPTED,
+        _ => EXIT_FAILURE,
+    }
+}
+
+/// Exits with `code`, in JSON mode a failure ends the output with
+/// `{"error": {"code": .., "message": ..}}` describing the first reported error.
+pub fn exit(code: i32) -> ! {
+    if code != EXIT_SUCCESS {
+        Output::json(serde_json::json!({
+            "error": {
+                "code": code,
+                "message": FIRST_ERROR.lock().take().unwrap_or_else(|| "failed".to_string()),
+            }
+        }));
+    }
+
+    std::process::exit(code)
+}
+
+static FIRST_ERROR: parking_lot::Mutex<Option<String>> = parking_lot::Mutex::new(None);
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+static VERBOSITY: AtomicU8 = AtomicU8::new(1);
+
+impl Output {
+    #[inline]
+    pub fn set(self) {
+        JSON_OUTPUT.store(self == Output::Json, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get() -> Self {
+        if JSON_OUTPUT.load(Ordering::Relaxed) {
+            Output::Json
+        } else {
+            Output::Human
+        }
+    }
+
+    #[inline]
+    pub fn is_json() -> bool {
+        Self::get() == Output::Json
+    }
+
+    /// Sets how much is printed, 0 is `--quiet`, 1 the default and every `-v` adds one.
+    #[inline]
+    pub fn set_verbosity(verbosity: u8) {
+        VERBOSITY.store(verbosity, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn verbosity() -> u8 {
+        VERBOSITY.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn is_quiet() -> bool {
+        Self::verbosity() == 0
+    }
+
+    /// Prints a status line, nothing is printed in JSON mode or with `--quiet`.
+    #[inline]
+    pub fn status(line: impl std::fmt::Display) {
+        if !Self::is_json() && !Self::is_quiet() {
+            print_line(false, line);
+        }
+    }
+
+    /// Prints a result line, unlike `status` it is kept with `--quiet`.
+    #[inline]
+    pub fn result(line: impl std::fmt::Display) {
+        if !Self::is_json() {
+            print_line(false, line);
+        }
+    }
+
+    /// Prints a detail line that is only shown with `-v`.
+    #[inline]
+    pub fn verbose(line: impl std::fmt::Display) {
+        if !Self::is_json() && Self::verbosity() >= 2 {
+            print_line(false, line);
+        }
+    }
+
+    /// Prints an error line, to stderr in JSON mode so stdout stays parseable.
+    #[inline]
+    pub fn error(line: impl std::fmt::Display) {
+        FIRST_ERROR
+            .lock()
+            .get_or_insert_with(|| strip_ansi(&line.to_string()));
+
+        print_line(Self::is_json(), line);
+    }
+
+    /// Prints one JSON object on its own line, nothing is printed in human mode.
+    #[inline]
+    pub fn json(value: serde_json::Value) {
+        if Self::is_json() {
+            print_line(false, value);
+        }
+    }
+}
+
+/// Prints the `log` records of the library to stderr.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let level = match record.level() {
+            log::Level::Error => "error:".red(),
+            log::Level::Warn => "warning:".yellow(),
+            log::Level::Info => "info:".cyan(),
+            log::Level::Debug => "debug:".bright_black(),
+            log::Level::Trace => "trace:".bright_black(),
+        };
+
+        print_line(true, format!("{} {}", level, record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Installs the logger for the given verbosity, `DDUP_BAK_LOG=<level>`
+/// (error, warn, info, debug or trace) takes precedence over the flags.
+pub fn init_logging(verbosity: u8) {
+    let level = std::env::var("DDUP_BAK_LOG")
+        .ok()
+        .and_then(|level| level.parse::<log::LevelFilter>().ok())
+        .unwrap_or(match verbosity {
+            0 => log::LevelFilter::Error,
+            1 => log::LevelFilter::Warn,
+            2 => log::LevelFilter::Info,
+            3 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        });
+
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Applies the global `--color` choice, `auto` only colors when stdout is a terminal
+/// and `NO_COLOR` is unset.
+pub fn init_color(choice: &str) {
+    match choice {
+        "always" => colored::control::set_override(true),
+        "never" => colored::control::set_override(false),
+        _ => {
+            if !std::io::stdout().is_terminal() || std::env::var_os("NO_COLOR").is_some() {
+                colored::control::set_override(false);
+            }
+        }
+    }
+}
+
+/// The column count of the terminal stdout is connected to, `None` when stdout is not a terminal.
+pub fn terminal_width() -> Option<usize> {
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+
+    if let Some(columns) = std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+    {
+        return Some(columns);
+    }
+
+    #[cfg(unix)]
+    {
+        let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0
+            && size.ws_col > 0
+        {
+            return Some(size.ws_col as usize);
+        }
+    }
+
+    Some(80)
+}
+
+/// The progress line currently drawn on stderr, `None` while there is none.
+static PROGRESS_LINE: parking_lot::Mutex<Option<String>> = parking_lot::Mutex::new(None);
+
+/// Prints a line to stdout, or stderr with `stderr` set, above the progress line
+/// if one is drawn. The progress line is cleared first and redrawn after, so
+/// lines printed while a spinner runs never end up in the middle of it.
+pub fn print_line(stderr: bool, line: impl std::fmt::Display) {
+    let progress_line = PROGRESS_LINE.lock();
+    let mut err = std::io::stderr().lock();
+
+    if progress_line.is_some() {
+        err.write_all(b"\r\x1B[K").ok();
+    }
+
+    if stderr {
+        writeln!(err, "{line}").ok();
     } else {
-        println!("{}", "repository is not initialized or is corrupted!".red());
-        println!(
-            "{} {} {}",
-            "Run".red(),
-            "ddup-bak init .".cyan(),
-            "to initialize a new repository.".red()
+        let mut out = std::io::stdout().lock();
+        writeln!(out, "{line}").ok();
+        out.flush().ok();
+    }
+
+    if let Some(progress_line) = progress_line.as_ref() {
+        err.write_all(progress_line.as_bytes()).ok();
+    }
+    err.flush().ok();
+}
+
+/// Removes ANSI escape sequences and carriage returns from a line.
+pub fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1B' => {
+                if chars.next() == Some('[') {
+                    for c in chars.by_ref() {
+                        if c.is_ascii_alphabetic() {
+                            break;
+                        }
+                    }
+                }
+            }
+            '\r' => {}
+            c => stripped.push(c),
+        }
+    }
+
+    stripped
+}
+
+static CANCEL: LazyLock<Arc<AtomicBool>> = LazyLock::new(|| Arc::new(AtomicBool::new(false)));
+static CANCELLABLE: AtomicBool = AtomicBool::new(false);
+
+/// Installs the Ctrl-C handler. Commands that called `cancellation` are asked to stop
+/// and clean up, a second Ctrl-C or any other command exits right away.
+pub fn init_interrupt_handler() {
+    let result = ctrlc::set_handler(|| {
+        if !CANCELLABLE.load(Ordering::SeqCst) || CANCEL.swap(true, Ordering::SeqCst) {
+            eprintln!();
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+
+        print_line(
+            true,
+            format!(
+                "{} {}",
+                "interrupted, stopping after the work in progress...".yellow(),
+                "press Ctrl-C again to quit immediately".bright_black()
+            ),
         );
-        println!(
+    });
+
+    if let Err(err) = result {
+        log::warn!("Could not install the Ctrl-C handler: {err}");
+    }
+}
+
+/// The flag set by the first Ctrl-C, to pass as the `cancel` option of long running operations.
+pub fn cancellation() -> Arc<AtomicBool> {
+    CANCELLABLE.store(true, Ordering::SeqCst);
+
+    Arc::clone(&CANCEL)
+}
+
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// Answers every confirmation with yes, set by the global `--yes` flag.
+#[inline]
+pub fn set_assume_yes(yes: bool) {
+    ASSUME_YES.store(yes, Ordering::Relaxed);
+}
+
+static LOCK_TIMEOUT: RwLock<Option<Duration>> = RwLock::new(None);
+
+/// Limits how long commands wait for the repository lock, set by the global `--lock-timeout` flag.
+#[inline]
+pub fn set_lock_timeout(timeout: Option<Duration>) {
+    *LOCK_TIMEOUT.write() = timeout;
+}
+
+/// Asks before a destructive action, `question` should describe its impact.
+/// Without `--yes` and a terminal on stdin the action is refused, so scripts
+/// and cron jobs never destroy data without opting in.
+pub fn confirm(question: impl std::fmt::Display) -> bool {
+    if ASSUME_YES.load(Ordering::Relaxed) {
+        return true;
+    }
+
+    if !std::io::stdin().is_terminal() {
+        Output::error(format!(
             "{} {} {}",
-            "Run".red(),
-            "ddup-bak rebuild .".cyan(),
-            "to attempt to rebuild the repository.".red()
+            question,
+            "Refusing without a terminal, pass".red(),
+            "--yes".cyan()
+        ));
+
+        return false;
+    }
+
+    eprint!(
+        "{} {} {} ",
+        question,
+        "Continue?".bold(),
+        "[y/N]".bright_black()
+    );
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+
+    if matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        return true;
+    }
+
+    Output::error("aborted".red());
+
+    false
+}
+
+/// Describes what deleting `names` would dereference, for confirmation prompts.
+pub fn deletion_impact(repository: &Repository, names: &[String]) -> std::io::Result<String> {
+    let (mut chunks, mut exclusive_bytes) = (0, 0);
+    for name in names {
+        let stats = repository.archive_stats(name)?;
+
+        chunks += stats.chunks;
+        exclusive_bytes += stats.exclusive_bytes;
+    }
+
+    Ok(format!(
+        "{} {} {} {} {}",
+        "This will delete".yellow(),
+        if names.len() == 1 {
+            format!("archive '{}'", names[0])
+        } else {
+            format!("{} archives ({})", names.len(), names.join(", "))
+        }
+        .cyan(),
+        "and dereference".yellow(),
+        format!("{chunks} chunks,").cyan(),
+        format!(
+            "freeing at least {} once cleaned.",
+            format_bytes(exclusive_bytes)
+        )
+        .yellow()
+    ))
+}
+
+/// Formats a timestamp for JSON output as RFC 3339 in UTC.
+#[inline]
+pub fn json_time(time: std::time::SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Describes an archive entry for JSON output, `path` is the entry's path inside the archive.
+pub fn entry_json(path: &Path, entry: &Entry) -> serde_json::Value {
+    let (uid, gid) = entry.owner();
+    let mut value = serde_json::json!({
+        "path": path.to_string_lossy(),
+        "name": entry.name(),
+        "type": match entry {
+            Entry::File(_) => "file",
+            Entry::Directory(_) => "directory",
+            Entry::Symlink(_) => "symlink",
+        },
+        "mode": entry.mode().bits(),
+        "uid": uid,
+        "gid": gid,
+        "size": match entry {
+            Entry::File(file) => file.size_real,
+            Entry::Symlink(link) => link.target.len() as u64,
+            Entry::Directory(_) => 0,
+        },
+        "mtime": json_time(entry.mtime()),
+    });
+
+    if let Entry::Symlink(link) = entry {
+        value["target"] = link.target.clone().into();
+    }
+
+    value
+}
+
+/// Repository settings chosen at `init`, relative to the repository directory.
+pub const CONFIG_FILE: &str = ".ddup-bak/config.json";
+
+/// Reader counts without a heartbeat for this long were left behind by killed processes.
+const STALE_READER_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// Waits for the repository lock longer than this are reported.
+const LOCK_WAIT_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Tells why a command seems stuck before it started, and how long it was.
+fn report_lock_wait(event: &LockWaitEvent) {
+    if event.acquired {
+        Output::verbose(
+            format!("acquired lock after {}", format_duration(event.elapsed)).bright_black(),
         );
 
-        std::process::exit(1);
+        return;
+    }
+
+    let holder = match (event.writer_pid, event.writer_mode) {
+        (Some(pid), Some(mode)) => format!("pid {pid} ({mode:?})"),
+        _ => format!("{} reader(s)", event.reader_counts.iter().sum::<u64>()),
+    };
+
+    Output::status(format!("waiting for lock held by {holder}...").bright_black());
+}
+
+/// Persists the storage url chosen at `init` so later commands open the same storage.
+/// The lock backend is stored by the repository itself.
+pub fn write_config(directory: &Path, storage: &str) -> std::io::Result<()> {
+    let config = serde_json::json!({ "storage": storage });
+
+    std::fs::write(
+        directory.join(CONFIG_FILE),
+        serde_json::to_string_pretty(&config)?,
+    )
+}
+
+/// Reads the configuration of the repository in `directory`, an empty object if it has none.
+fn read_config(directory: &Path) -> std::io::Result<serde_json::Value> {
+    match std::fs::read(directory.join(CONFIG_FILE)) {
+        Ok(config) => Ok(serde_json::from_slice(&config)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(serde_json::json!({})),
+        Err(err) => Err(err),
+    }
+}
+
+/// Opens the chunk storage configured for the repository in `directory`,
+/// `None` means the default storage in `.ddup-bak/chunks`.
+pub fn open_storage(directory: &Path) -> std::io::Result<Option<Arc<dyn ChunkStorage>>> {
+    match read_config(directory)?
+        .get("storage")
+        .and_then(|storage| storage.as_str())
+    {
+        Some(url) => storage_from_url(url, directory).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The lock backend earlier versions kept in the configuration of the repository in
+/// `directory`, `None` if there is none. The repository stores it itself now.
+fn config_lock_backend(directory: &Path) -> std::io::Result<Option<LockBackend>> {
+    match read_config(directory)?
+        .get("lock_backend")
+        .and_then(|backend| backend.as_str())
+    {
+        Some(backend) => backend.parse().map(Some),
+        None => Ok(None),
+    }
+}
+
+pub fn open_repository(save: bool) -> Repository {
+    // commands work from any subdirectory of a repository, falling back to the current
+    // directory keeps the hints below when there is none
+    let directory = Repository::discover(Path::new(".")).unwrap_or_else(|_| PathBuf::from("."));
+    if directory != Path::new(".") {
+        log::debug!("Using the repository at {}", directory.display());
+    }
+
+    let storage = match open_storage(&directory) {
+        Ok(storage) => storage,
+        Err(err) => {
+            Output::error(format!("{} {}", "could not open chunk storage:".red(), err));
+
+            exit(exit_code(&err));
+        }
+    };
+    let lock_backend = match config_lock_backend(&directory) {
+        Ok(lock_backend) => lock_backend,
+        Err(err) => {
+            Output::error(format!(
+                "{} {}",
+                "could not open repository lock:".red(),
+                err
+            ));
+
+            exit(exit_code(&err));
+        }
+    };
+
+    match Repository::open(&directory, None, storage) {
+        Ok(mut repository) => {
+            // moves the backend of the configuration into the repository
+            if let Some(lock_backend) = lock_backend
+                && let Err(err) = repository.set_lock_backend(lock_backend)
+            {
+                Output::error(format!(
+                    "{} {}",
+                    "could not open repository lock:".red(),
+                    err
+                ));
+
+                exit(exit_code(&err));
+            }
+
+            repository.set_save_on_drop(save);
+            repository.set_lock_timeout(*LOCK_TIMEOUT.read());
+            repository
+                .set_lock_wait_callback(Some(Arc::new(report_lock_wait)), LOCK_WAIT_THRESHOLD);
+            repository
+                .chunk_index
+                .lock
+                .set_stale_reader_age(Some(STALE_READER_AGE));
+
+            repository
+        }
+        Err(err) => {
+            Output::error("repository is not initialized or is corrupted!".red());
+            Output::error(format!(
+                "{} {} {}",
+                "Run".red(),
+                "ddup-bak init .".cyan(),
+                "to initialize a new repository.".red()
+            ));
+            Output::error(format!(
+                "{} {} {}",
+                "Run".red(),
+                "ddup-bak rebuild .".cyan(),
+                "to attempt to rebuild the repository.".red()
+            ));
+
+            exit(exit_code(&err));
+        }
+    }
+}
+
+/// Opens a backup by name, printing why if it does not exist or cannot be read,
+/// in which case the exit code to return is given back.
+pub fn open_archive(repository: &Repository, name: &str) -> std::io::Result<Result<Archive, i32>> {
+    open_archive_with(repository, name, Repository::get_archive)
+}
+
+/// Like `open_archive`, but directories are only decoded when they are accessed.
+/// For commands that look at a few paths or walk the entries without keeping them.
+pub fn open_archive_lazy(
+    repository: &Repository,
+    name: &str,
+) -> std::io::Result<Result<Archive, i32>> {
+    open_archive_with(repository, name, Repository::get_archive_lazy)
+}
+
+fn open_archive_with(
+    repository: &Repository,
+    name: &str,
+    open: fn(&Repository, &str) -> std::io::Result<Archive>,
+) -> std::io::Result<Result<Archive, i32>> {
+    if !repository
+        .list_archives()?
+        .into_iter()
+        .any(|archive| archive == name)
+    {
+        Output::error(format!(
+            "{} {} {}",
+            "backup".red(),
+            name.cyan(),
+            "does not exist!".red()
+        ));
+
+        return Ok(Err(EXIT_NOT_FOUND));
+    }
+
+    match open(repository, name) {
+        Ok(archive) => Ok(Ok(archive)),
+        Err(err) => {
+            Output::error(format!(
+                "{} {} {} {}",
+                "backup".red(),
+                name.cyan(),
+                "could not be read:".red(),
+                err
+            ));
+
+            Ok(Err(exit_code(&err)))
+        }
+    }
+}
+
+#[inline]
+pub fn format_bytes(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{bytes}")
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1}K", bytes as f64 / 1024.0)
+    } else if bytes < 1024 * 1024 * 1024 {
+        format!("{:.1}M", bytes as f64 / (1024.0 * 1024.0))
+    } else if bytes < 1024 * 1024 * 1024 * 1024 {
+        format!("{:.1}G", bytes as f64 / (1024.0 * 1024.0 * 1024.0))
+    } else {
+        format!("{:.1}T", bytes as f64 / (1024.0 * 1024.0 * 1024.0 * 1024.0))
     }
 }
 
 const SPINNER: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const BAR_WIDTH: usize = 20;
+
+/// How often the transfer rate is resampled, shorter windows make the ETA jumpy.
+const RATE_WINDOW: Duration = Duration::from_millis(500);
+/// The shortest time between two redraws of the progress line, the spinner advances at the same pace.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the drawing thread checks whether it should stop or redraw.
+const TICK: Duration = Duration::from_millis(20);
+/// How often a line is printed when stderr is not a terminal.
+const PLAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Formats a duration as `M:SS`, or `H:MM:SS` once it reaches an hour.
+#[inline]
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    if seconds >= 60 * 60 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / (60 * 60),
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
 
 pub struct Progress {
-    pub total: usize,
+    total: Arc<AtomicUsize>,
+    total_bytes: Arc<AtomicU64>,
 
     pub text: Arc<RwLock<String>>,
     finished: Arc<AtomicBool>,
     progress: Arc<AtomicUsize>,
+    bytes: Arc<AtomicU64>,
+    /// Moving average of processed bytes per second, stored as `f64` bits.
+    rate: Arc<AtomicU64>,
+    started: Instant,
     thread: Option<std::thread::JoinHandle<()>>,
 }
 
 impl Clone for Progress {
     fn clone(&self) -> Self {
         Self {
-            total: self.total,
+            total: Arc::clone(&self.total),
+            total_bytes: Arc::clone(&self.total_bytes),
             text: Arc::clone(&self.text),
             finished: Arc::clone(&self.finished),
             progress: Arc::clone(&self.progress),
+            bytes: Arc::clone(&self.bytes),
+            rate: Arc::clone(&self.rate),
+            started: self.started,
             thread: None,
         }
     }
@@ -64,18 +724,46 @@ impl Clone for Progress {
 impl Progress {
     pub fn new(total: usize) -> Self {
         Self {
-            total,
+            total: Arc::new(AtomicUsize::new(total)),
+            total_bytes: Arc::new(AtomicU64::new(0)),
             text: Arc::new(RwLock::new(String::new())),
             finished: Arc::new(AtomicBool::new(false)),
             progress: Arc::new(AtomicUsize::new(0)),
+            bytes: Arc::new(AtomicU64::new(0)),
+            rate: Arc::new(AtomicU64::new(0)),
+            started: Instant::now(),
             thread: None,
         }
     }
 
     #[inline]
     pub fn incr<N: Into<usize>>(&self, n: N) {
-        self.progress
-            .fetch_add(n.into(), std::sync::atomic::Ordering::SeqCst);
+        self.progress.fetch_add(n.into(), Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn incr_bytes(&self, n: u64) {
+        self.bytes.fetch_add(n, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn set_total(&self, total: usize) {
+        self.total.store(total, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn set_total_bytes(&self, total_bytes: u64) {
+        self.total_bytes.store(total_bytes, Ordering::SeqCst);
     }
 
     #[inline]
@@ -86,63 +774,329 @@ impl Progress {
 
     #[inline]
     pub fn progress(&self) -> usize {
-        self.progress.load(std::sync::atomic::Ordering::SeqCst)
+        self.progress.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst)
     }
 
     #[inline]
     pub fn percent(&self) -> f64 {
-        (self.progress() as f64 / self.total as f64) * 100.0
+        (self.progress() as f64 / self.total() as f64) * 100.0
     }
 
+    /// Processed bytes per second, averaged over the last few seconds.
+    #[inline]
+    pub fn rate(&self) -> f64 {
+        f64::from_bits(self.rate.load(Ordering::SeqCst))
+    }
+
+    /// How long processing the remaining bytes takes at the current rate,
+    /// `None` without a byte total or before the rate is known.
+    pub fn eta(&self) -> Option<Duration> {
+        let total_bytes = self.total_bytes();
+        let rate = self.rate();
+
+        if total_bytes == 0 || rate <= 0.0 {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(
+            total_bytes.saturating_sub(self.bytes()) as f64 / rate,
+        ))
+    }
+
+    /// Completed fraction by bytes if a byte total is known, otherwise by items.
+    fn fraction(&self) -> Option<f64> {
+        let total_bytes = self.total_bytes();
+        let total = self.total();
+
+        if total_bytes > 0 {
+            Some(self.bytes() as f64 / total_bytes as f64)
+        } else if total > 0 && total != usize::MAX {
+            Some(self.progress() as f64 / total as f64)
+        } else {
+            None
+        }
+        .map(|fraction| fraction.clamp(0.0, 1.0))
+    }
+
+    /// Folds the bytes processed since the last sample into the moving average rate.
+    fn sample_rate(&self, last_sample: &mut (Instant, u64)) {
+        let elapsed = last_sample.0.elapsed();
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+
+        let bytes = self.bytes();
+        let current = bytes.saturating_sub(last_sample.1) as f64 / elapsed.as_secs_f64();
+        let rate = self.rate();
+
+        let rate = if rate <= 0.0 {
+            current
+        } else {
+            rate * 0.7 + current * 0.3
+        };
+
+        self.rate.store(rate.to_bits(), Ordering::SeqCst);
+        *last_sample = (Instant::now(), bytes);
+    }
+
+    /// Renders `label [#####.....] 43% 1.2G/3.0G 85.0M/s ETA 0:23 text`, parts
+    /// without known totals are left out.
+    pub fn render_bar(&self, label: &str, spinner: &str) -> String {
+        let mut line = format!(
+            "\r\x1B[K {} {}",
+            label.bright_black().italic(),
+            spinner.cyan()
+        );
+
+        if let Some(fraction) = self.fraction() {
+            let filled = (fraction * BAR_WIDTH as f64).round() as usize;
+
+            line.push_str(&format!(
+                " [{}{}] {}",
+                "#".repeat(filled).cyan(),
+                ".".repeat(BAR_WIDTH - filled).bright_black(),
+                format!("{:.0}%", (fraction * 100.0).floor()).cyan()
+            ));
+        }
+
+        let total_bytes = self.total_bytes();
+        if total_bytes > 0 {
+            line.push_str(&format!(
+                " {}/{}",
+                format_bytes(self.bytes()),
+                format_bytes(total_bytes)
+            ));
+        } else if self.bytes() > 0 {
+            line.push_str(&format!(" {}", format_bytes(self.bytes())));
+        } else if self.total() != usize::MAX {
+            line.push_str(&format!(" {}/{}", self.progress(), self.total()));
+        }
+
+        let rate = self.rate();
+        if rate > 0.0 {
+            line.push_str(&format!(" {}/s", format_bytes(rate as u64)));
+        }
+
+        if let Some(eta) = self.eta() {
+            line.push_str(
+                &format!(" ETA {}", format_duration(eta))
+                    .bright_black()
+                    .to_string(),
+            );
+        }
+
+        let text = self.text.read();
+        if !text.is_empty() {
+            line.push_str(&format!(" {}", text.cyan()));
+        }
+
+        line
+    }
+
+    /// Shows a progress bar, see `render_bar`.
+    #[inline]
+    pub fn bar(&mut self, label: &'static str) {
+        self.spinner(move |progress, spinner| progress.render_bar(label, spinner));
+    }
+
+    /// Prints a line to stderr above the progress line, see `print_line`.
+    #[inline]
+    pub fn println(&self, line: impl std::fmt::Display) {
+        print_line(true, line);
+    }
+
+    /// Redraws the line returned by `fmt` until `finish` is called. When stderr is
+    /// not a terminal the line is only printed plainly every few seconds with `-v`,
+    /// nothing is shown with `--quiet`.
+    ///
+    /// The line is drawn by a single thread, at most every `REDRAW_INTERVAL` and
+    /// only if it changed. Anything printed meanwhile should go through `println`
+    /// or `Output` so it is placed above the line instead of tearing it.
     pub fn spinner<F>(&mut self, fmt: F)
     where
         F: Fn(&Progress, &str) -> String + Send + Sync + 'static,
     {
-        let total = self.total;
-        let text = Arc::clone(&self.text);
-        let finished = Arc::clone(&self.finished);
-        let progress = Arc::clone(&self.progress);
+        let progress = self.clone();
+        let is_terminal = std::io::stderr().is_terminal();
+
+        if Output::is_quiet() || (!is_terminal && Output::verbosity() < 2) {
+            return;
+        }
 
         let thread = std::thread::spawn(move || {
-            let mut i = 0;
-
-            loop {
-                eprint!(
-                    "{}",
-                    fmt(
-                        &Progress {
-                            total,
-                            text: Arc::clone(&text),
-                            finished: Arc::clone(&finished),
-                            progress: Arc::clone(&progress),
-                            thread: None
-                        },
-                        &SPINNER[i].to_string()
-                    )
-                );
-
-                i = (i + 1) % SPINNER.len();
-                std::thread::sleep(std::time::Duration::from_millis(50));
-
-                if finished.load(std::sync::atomic::Ordering::SeqCst) {
-                    break;
+            let mut last_sample = (Instant::now(), progress.bytes());
+            let mut last_draw: Option<Instant> = None;
+
+            while !progress.finished.load(Ordering::SeqCst) {
+                progress.sample_rate(&mut last_sample);
+
+                if is_terminal {
+                    if last_draw.is_none_or(|last| last.elapsed() >= REDRAW_INTERVAL) {
+                        let i = (progress.started.elapsed().as_millis()
+                            / REDRAW_INTERVAL.as_millis()) as usize;
+                        let line = fmt(&progress, &SPINNER[i % SPINNER.len()].to_string());
+
+                        let mut progress_line = PROGRESS_LINE.lock();
+                        if progress_line.as_deref() != Some(line.as_str()) {
+                            let mut err = std::io::stderr().lock();
+                            err.write_all(line.as_bytes()).ok();
+                            err.flush().ok();
+
+                            *progress_line = Some(line);
+                        }
+
+                        last_draw = Some(Instant::now());
+                    }
+                } else if last_draw.is_none_or(|last| last.elapsed() >= PLAIN_INTERVAL) {
+                    let line = fmt(&progress, "-");
+                    print_line(true, strip_ansi(&line).trim());
+
+                    last_draw = Some(Instant::now());
                 }
+
+                std::thread::sleep(TICK);
+            }
+
+            if PROGRESS_LINE.lock().take().is_some() {
+                let mut err = std::io::stderr().lock();
+                err.write_all(b"\r\x1B[K").ok();
+                err.flush().ok();
             }
         });
 
         self.thread = Some(thread);
     }
 
+    /// Stops the spinner and clears its line, so the next status line takes its place.
     pub fn finish(&mut self) {
-        self.finished
-            .store(true, std::sync::atomic::Ordering::SeqCst);
+        self.finished.store(true, Ordering::SeqCst);
 
         if let Some(thread) = self.thread.take()
             && thread.join().is_err()
         {
-            eprintln!("{}", "Failed to join progress thread".red());
+            print_line(true, "Failed to join progress thread".red());
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ddup_bak::repository::CreateOptions;
+
+    fn temp_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("ddup-bak-commands-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(directory.join("source")).unwrap();
+        std::fs::write(directory.join("source/file"), "content").unwrap();
+
+        directory
+    }
+
+    /// Backs up `source` of `directory` and restores it, checking the content survived.
+    fn back_up_and_restore(directory: &Path, repository: &Repository) {
+        let source = directory.join("source");
+        repository
+            .create_archive(
+                "archive",
+                Some(ignore::WalkBuilder::new(&source).build()),
+                Some(&source),
+                None,
+                None,
+                1,
+                CreateOptions::default(),
+            )
+            .unwrap();
+
+        let restored = repository.restore_archive("archive", None, 1).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(restored.join("file")).unwrap(),
+            "content"
+        );
+    }
+
+    #[test]
+    fn config_round_trips_the_storage_url() {
+        let directory = temp_directory("config");
+        Repository::new(&directory, 1024, 0, None).unwrap();
+
+        assert!(open_storage(&directory).unwrap().is_none());
 
-        println!();
+        write_config(&directory, "file://elsewhere").unwrap();
+        assert_eq!(
+            read_config(&directory).unwrap()["storage"],
+            "file://elsewhere"
+        );
+        assert!(open_storage(&directory).unwrap().is_some());
+        assert_eq!(config_lock_backend(&directory).unwrap(), None);
+
+        write_config(&directory, "unknown://").unwrap();
+        assert_eq!(
+            open_storage(&directory).err().unwrap().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn file_storage_is_used_by_later_commands() {
+        let directory = temp_directory("file-storage");
+        let storage = storage_from_url("file://elsewhere", &directory).unwrap();
+        let repository = Repository::new(&directory, 1024, 0, Some(storage)).unwrap();
+        write_config(&directory, "file://elsewhere").unwrap();
+
+        back_up_and_restore(&directory, &repository);
+        drop(repository);
+        assert!(
+            std::fs::read_dir(directory.join("elsewhere"))
+                .unwrap()
+                .count()
+                > 0
+        );
+
+        // a later command opens the storage from the configuration alone
+        std::fs::remove_dir_all(directory.join(".ddup-bak/archives-restored/archive")).unwrap();
+        let repository =
+            Repository::open(&directory, None, open_storage(&directory).unwrap()).unwrap();
+        let restored = repository.restore_archive("archive", None, 1).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(restored.join("file")).unwrap(),
+            "content"
+        );
+
+        drop(repository);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn memory_storage_backs_up_and_restores() {
+        let directory = temp_directory("memory-storage");
+        let storage = storage_from_url("memory://", &directory).unwrap();
+        let repository = Repository::new(&directory, 1024, 0, Some(Arc::clone(&storage))).unwrap();
+
+        back_up_and_restore(&directory, &repository);
+        assert!(!storage.list_chunk_hashes().unwrap().is_empty());
+
+        drop(repository);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn missing_backends_name_the_feature() {
+        for scheme in ["s3", "sftp"] {
+            let err = storage_from_url(&format!("{scheme}://bucket/prefix"), Path::new("."))
+                .err()
+                .unwrap();
+
+            assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+            assert!(err.to_string().contains(&format!("--features {scheme}")));
+        }
     }
 }
@@ -1,4 +1,4 @@
-use crate::commands::Progress;
+use crate::commands::{EXIT_NOT_FOUND, Output, Progress, open_storage};
 use clap::ArgMatches;
 use colored::Colorize;
 use ddup_bak::repository::Repository;
@@ -12,17 +12,21 @@ pub fn rebuild(matches: &ArgMatches) -> std::io::Result<i32> {
         .expect("required");
 
     if !std::path::Path::new(directory).join(".ddup-bak").exists() {
-        println!("{} {}", ".ddup-bak".cyan(), "does not exist!".red());
+        Output::error(format!(
+            "{} {}",
+            ".ddup-bak".cyan(),
+            "does not exist!".red()
+        ));
 
-        return Ok(1);
+        return Ok(EXIT_NOT_FOUND);
     }
 
-    println!(
+    Output::status(format!(
         "{} {} {}",
         "rebuilding".bright_black(),
         ".ddup-bak".cyan(),
         "...".bright_black()
-    );
+    ));
 
     let mut progress = Progress::new(usize::MAX);
     progress.spinner(|progress, spinner| {
@@ -34,14 +38,12 @@ pub fn rebuild(matches: &ArgMatches) -> std::io::Result<i32> {
         )
     });
 
-    progress.finish();
-
-    Repository::open_or_rebuild(
+    let result = Repository::open_or_rebuild(
         Path::new(directory),
         chunk_size,
         max_chunk_count,
         None,
-        None,
+        open_storage(Path::new(directory))?,
         Some({
             let progress = progress.clone();
 
@@ -53,15 +55,18 @@ pub fn rebuild(matches: &ArgMatches) -> std::io::Result<i32> {
                 ));
             })
         }),
-    )?;
+    );
+
+    progress.finish();
+    result?;
 
-    println!(
+    Output::status(format!(
         "{} {} {} {}",
         "rebuilding".bright_black(),
         ".ddup-bak".cyan(),
         "...".bright_black(),
         "DONE".green().bold()
-    );
+    ));
 
     Ok(0)
 }
@@ -0,0 +1,168 @@
+use crate::commands::{EXIT_NOT_FOUND, Output, Progress, format_bytes, json_time, open_repository};
+use chrono::{DateTime, Local};
+use clap::ArgMatches;
+use colored::Colorize;
+use ddup_bak::repository::ArchiveStats;
+
+fn render_archives(archives: &[ArchiveStats]) {
+    if Output::is_json() {
+        for archive in archives {
+            Output::json(serde_json::json!({
+                "type": "archive",
+                "name": archive.name,
+                "created": json_time(archive.created),
+                "files": archive.files,
+                "chunks": archive.chunks,
+                "logical_bytes": archive.logical_bytes,
+                "stored_bytes": archive.stored_bytes,
+                "exclusive_bytes": archive.exclusive_bytes,
+            }));
+        }
+
+        return;
+    }
+
+    let name_width = archives
+        .iter()
+        .map(|a| a.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+
+    println!(
+        "{:<name_width$} {:>9} {:>9} {:>8} {}",
+        "NAME".bright_black(),
+        "SIZE".bright_black(),
+        "EXCLUSIVE".bright_black(),
+        "FILES".bright_black(),
+        "CREATED".bright_black()
+    );
+
+    for archive in archives {
+        let created: DateTime<Local> = archive.created.into();
+
+        println!(
+            "{:<name_width$} {:>9} {:>9} {:>8} {}",
+            archive.name.cyan(),
+            format_bytes(archive.logical_bytes),
+            format_bytes(archive.exclusive_bytes),
+            archive.files,
+            created
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+                .bright_black()
+        );
+    }
+}
+
+pub fn stats(matches: &ArgMatches) -> std::io::Result<i32> {
+    let repository = open_repository(false);
+
+    let archive = matches.get_one::<String>("archive");
+    let sort = matches.get_one::<String>("sort").expect("required");
+
+    let mut names = repository.list_archives()?;
+    if let Some(archive) = archive {
+        if !names.iter().any(|name| name == archive) {
+            Output::error(format!(
+                "{} {} {}",
+                "backup".red(),
+                archive.cyan(),
+                "does not exist!".red()
+            ));
+
+            return Ok(EXIT_NOT_FOUND);
+        }
+
+        names = vec![archive.clone()];
+    } else if Output::is_json() {
+        let stats = repository.stats()?;
+
+        Output::json(serde_json::json!({
+            "type": "repository",
+            "archives": stats.archives,
+            "unique_chunks": stats.unique_chunks,
+            "stored_bytes": stats.stored_bytes,
+            "logical_bytes": stats.logical_bytes,
+            "dedup_ratio": stats.dedup_ratio(),
+            "deleted_chunk_ids": stats.deleted_chunk_ids,
+            "missing_chunks": stats.missing_chunks,
+        }));
+    } else {
+        Output::status("collecting repository stats...".bright_black());
+
+        let stats = repository.stats()?;
+
+        Output::status(format!(
+            "{} {}",
+            "collecting repository stats...".bright_black(),
+            "DONE".green().bold()
+        ));
+        Output::status("");
+
+        println!("{:<14} {}", "archives", stats.archives.to_string().cyan());
+        println!(
+            "{:<14} {}",
+            "unique chunks",
+            stats.unique_chunks.to_string().cyan()
+        );
+        println!(
+            "{:<14} {}",
+            "stored",
+            format_bytes(stats.stored_bytes).cyan()
+        );
+        println!(
+            "{:<14} {}",
+            "logical",
+            format_bytes(stats.logical_bytes).cyan()
+        );
+        println!(
+            "{:<14} {}",
+            "dedup ratio",
+            format!("{:.2}x", stats.dedup_ratio()).cyan()
+        );
+        println!(
+            "{:<14} {}",
+            "deleted ids",
+            stats.deleted_chunk_ids.to_string().cyan()
+        );
+        if stats.missing_chunks > 0 {
+            println!(
+                "{:<14} {}",
+                "missing",
+                stats.missing_chunks.to_string().red()
+            );
+        }
+        println!();
+    }
+
+    let mut progress = Progress::new(names.len());
+    progress.spinner(|progress, spinner| {
+        format!(
+            "\r\x1B[K {} {} {}/{} ({}%)",
+            "collecting backup stats...".bright_black().italic(),
+            spinner.cyan(),
+            progress.progress().to_string().cyan(),
+            progress.total().to_string().cyan(),
+            progress.percent().round().to_string().cyan()
+        )
+    });
+
+    let mut archives = Vec::with_capacity(names.len());
+    for name in names {
+        archives.push(repository.archive_stats(&name)?);
+        progress.incr(1usize);
+    }
+
+    progress.finish();
+
+    match sort.as_str() {
+        "size" => archives.sort_by_key(|a| std::cmp::Reverse(a.logical_bytes)),
+        "date" => archives.sort_by_key(|a| a.created),
+        _ => archives.sort_by(|a, b| a.name.cmp(&b.name)),
+    }
+
+    render_archives(&archives);
+
+    Ok(0)
+}
@@ -0,0 +1,148 @@
+use crate::{
+    archive::entries::Entry,
+    repository::{ProgressEvent, ProgressEventCallback, Repository},
+};
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
+
+/// Reports the bytes read from an entry as `BytesProcessed`.
+struct ProgressReader<'a, R: Read> {
+    inner: R,
+    progress: &'a ProgressEventCallback,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        if let Some(f) = self.progress {
+            f(ProgressEvent::BytesProcessed(read as u64));
+        }
+
+        Ok(read)
+    }
+}
+
+/// Writes `entries` to `output` as a tar stream, file contents are read from the
+/// repository one after another. Every converted entry, not only files, is reported
+/// as `FileDone` and the bytes read as `BytesProcessed`.
+pub fn to_tar<W: Write>(
+    repository: &Repository,
+    entries: Vec<Entry>,
+    output: W,
+    progress: ProgressEventCallback,
+) -> std::io::Result<()> {
+    let mut tar = tar::Builder::new(output);
+    tar.mode(tar::HeaderMode::Complete);
+
+    for entry in entries {
+        tar_recursive_convert_entries(entry, repository, &mut tar, &progress, "")?;
+    }
+
+    tar.finish()
+}
+
+/// Like `to_tar`, compressing the stream with gzip at `level` (0 to 9).
+pub fn to_tar_gz<W: Write>(
+    repository: &Repository,
+    entries: Vec<Entry>,
+    output: W,
+    level: u32,
+    progress: ProgressEventCallback,
+) -> std::io::Result<()> {
+    let mut output = flate2::write::GzEncoder::new(output, flate2::Compression::new(level));
+
+    to_tar(repository, entries, &mut output, progress)?;
+    output.finish()?;
+
+    Ok(())
+}
+
+fn tar_header(entry: &Entry) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_uid(entry.owner().0 as u64);
+    header.set_gid(entry.owner().1 as u64);
+    header.set_mode(entry.mode().bits());
+
+    header.set_mtime(
+        entry
+            .mtime()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    );
+
+    header
+}
+
+fn tar_recursive_convert_entries<W: Write>(
+    entry: Entry,
+    repository: &Repository,
+    archive: &mut tar::Builder<W>,
+    progress: &ProgressEventCallback,
+    parent_path: &str,
+) -> std::io::Result<()> {
+    let path = if parent_path.is_empty() {
+        entry.name().to_string()
+    } else {
+        format!("{}/{}", parent_path, entry.name())
+    };
+
+    let mut entry_header = tar_header(&entry);
+    let mut bytes = 0;
+
+    match entry {
+        Entry::Directory(entries) => {
+            entry_header.set_entry_type(tar::EntryType::Directory);
+
+            let dir_path = if path.ends_with('/') {
+                path.clone()
+            } else {
+                format!("{path}/")
+            };
+
+            archive.append_data(&mut entry_header, &dir_path, std::io::empty())?;
+
+            if let Some(f) = progress {
+                f(ProgressEvent::FileDone {
+                    path: Path::new(&path),
+                    bytes,
+                });
+            }
+
+            for entry in entries.into_entries()? {
+                tar_recursive_convert_entries(entry, repository, archive, progress, &path)?;
+            }
+
+            return Ok(());
+        }
+        Entry::File(file) => {
+            entry_header.set_entry_type(tar::EntryType::Regular);
+            entry_header.set_size(file.size_real);
+            bytes = file.size_real;
+
+            let reader = ProgressReader {
+                inner: repository.entry_reader(Entry::File(file))?,
+                progress,
+            };
+
+            archive.append_data(&mut entry_header, &path, reader)?;
+        }
+        Entry::Symlink(link) => {
+            entry_header.set_entry_type(tar::EntryType::Symlink);
+
+            archive.append_link(&mut entry_header, &path, &link.target)?;
+        }
+    }
+
+    if let Some(f) = progress {
+        f(ProgressEvent::FileDone {
+            path: Path::new(&path),
+            bytes,
+        });
+    }
+
+    Ok(())
+}
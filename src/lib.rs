@@ -1,4 +1,7 @@
 pub mod archive;
 pub mod chunks;
+pub mod convert;
 pub mod repository;
+#[cfg(test)]
+mod test_util;
 mod varint;
@@ -1,4 +1,4 @@
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 use colored::Colorize;
 
 mod commands;
@@ -12,6 +12,64 @@ fn cli() -> Command {
         .arg_required_else_help(true)
         .allow_external_subcommands(true)
         .version(VERSION)
+        .after_help(
+            "Exit codes:\n    0  success\n    1  failure\n    2  usage error\n    3  not found\n    4  repository lock busy\n    5  integrity failure\n  130  interrupted",
+        )
+        .arg(
+            Arg::new("quiet")
+                .help("Only print errors and results")
+                .short('q')
+                .long("quiet")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("verbose")
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("verbose")
+                .help("Print more details, can be repeated")
+                .short('v')
+                .long("verbose")
+                .action(ArgAction::Count)
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("color")
+                .help("When to use colors")
+                .long("color")
+                .num_args(1)
+                .default_value("auto")
+                .value_parser(["auto", "always", "never"])
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("yes")
+                .help("Do not ask for confirmation before destructive actions")
+                .short('y')
+                .long("yes")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("json")
+                .help("Print results as newline delimited JSON, also enabled by DDUP_BAK_JSON=1")
+                .long("json")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("lock_timeout")
+                .help("Seconds to wait for a locked repository before giving up, waits forever by default")
+                .long("lock-timeout")
+                .num_args(1)
+                .value_parser(clap::value_parser!(u64))
+                .global(true)
+                .required(false),
+        )
         .subcommand(
             Command::new("init")
                 .about("Initializes a new ddup-bak repository")
@@ -42,6 +100,29 @@ fn cli() -> Command {
                         .value_parser(clap::value_parser!(usize))
                         .required(false),
                 )
+                .arg(
+                    Arg::new("storage")
+                        .help("Where to store chunks, as file://<path> (relative to the repository) or mmap://<path> to read them through memory maps, defaults to .ddup-bak/chunks")
+                        .long("storage")
+                        .num_args(1)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("lock_backend")
+                        .help("How processes share the repository lock, polled works on any file system, native uses the locks of the operating system")
+                        .long("lock-backend")
+                        .num_args(1)
+                        .default_value("polled")
+                        .value_parser(["polled", "native"])
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("force")
+                        .help("Initialize the repository even inside the directory of another repository")
+                        .long("force")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
                 .arg_required_else_help(false),
         )
         .subcommand(
@@ -79,6 +160,47 @@ fn cli() -> Command {
         .subcommand(
             Command::new("clean")
                 .about("Cleans up unreferenced chunks from the repository")
+                .visible_alias("gc")
+                .arg(
+                    Arg::new("dry_run")
+                        .help("Only report how much space would be freed, without deleting anything")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg_required_else_help(false),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Checks the chunk index against the references of all backups")
+                .arg(
+                    Arg::new("repair")
+                        .help("Rewrite wrong reference counts to the counts found in the backups")
+                        .long("repair")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg_required_else_help(false),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Shows storage and deduplication statistics")
+                .arg(
+                    Arg::new("archive")
+                        .help("Only show the statistics of this backup")
+                        .long("archive")
+                        .num_args(1)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("sort")
+                        .help("How to sort the backup table")
+                        .long("sort")
+                        .num_args(1)
+                        .default_value("name")
+                        .value_parser(["size", "name", "date"])
+                        .required(false),
+                )
                 .arg_required_else_help(false),
         )
         .subcommand(
@@ -101,11 +223,11 @@ fn cli() -> Command {
                         )
                         .arg(
                             Arg::new("threads")
-                                .help("The number of threads to use for the backup")
+                                .help("The number of threads to use for the backup, 0 uses all available cores")
                                 .short('t')
                                 .long("threads")
                                 .num_args(1)
-                                .default_value("16")
+                                .default_value("0")
                                 .value_parser(clap::value_parser!(usize))
                                 .required(false),
                         )
@@ -116,19 +238,199 @@ fn cli() -> Command {
                                 .long("compression")
                                 .num_args(1)
                                 .default_value("deflate")
+                                .value_parser(["none", "gzip", "deflate", "brotli"])
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("no_count")
+                                .help("Skip counting files before the backup, progress will not show a total")
+                                .long("no-count")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("dry_run")
+                                .help("Only print what would be backed up, without creating the backup")
+                                .long("dry-run")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("resume")
+                                .help("Continue an interrupted backup from its checkpoint")
+                                .long("resume")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with_all(["restart", "dry_run"])
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("restart")
+                                .help("Discard the checkpoint of an interrupted backup and start over")
+                                .long("restart")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with("dry_run")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("exclude")
+                                .help("Skip paths matching this glob, can be given multiple times")
+                                .long("exclude")
+                                .num_args(1)
+                                .action(ArgAction::Append)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("exclude_from")
+                                .help("Read globs to skip from this file, one per line")
+                                .long("exclude-from")
+                                .num_args(1)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("tag")
+                                .help("Tag the backup, can be given multiple times")
+                                .long("tag")
+                                .num_args(1)
+                                .action(ArgAction::Append)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("description")
+                                .help("Describe the backup")
+                                .long("description")
+                                .num_args(1)
                                 .required(false),
                         )
                         .arg_required_else_help(true),
                 )
                 .subcommand(
                     Command::new("delete")
-                        .about("Deletes a backup")
+                        .about("Deletes one or more backups")
                         .arg(
                             Arg::new("name")
-                                .help("The name of the backup to delete")
+                                .help("The names of the backups to delete")
+                                .num_args(1..)
+                                .required_unless_present("all"),
+                        )
+                        .arg(
+                            Arg::new("all")
+                                .help("Delete every backup")
+                                .long("all")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with("name")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("strict")
+                                .help("Delete nothing if any of the backups does not exist")
+                                .long("strict")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
+                        .arg_required_else_help(false),
+                )
+                .subcommand(
+                    Command::new("tag")
+                        .about("Manages the tags of a backup")
+                        .subcommand(
+                            Command::new("add")
+                                .about("Adds tags to a backup")
+                                .arg(
+                                    Arg::new("name")
+                                        .help("The name of the backup to tag")
+                                        .num_args(1)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::new("tag")
+                                        .help("The tags to add")
+                                        .num_args(1..)
+                                        .required(true),
+                                ),
+                        )
+                        .subcommand(
+                            Command::new("remove")
+                                .about("Removes tags from a backup")
+                                .arg(
+                                    Arg::new("name")
+                                        .help("The name of the backup to untag")
+                                        .num_args(1)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::new("tag")
+                                        .help("The tags to remove")
+                                        .num_args(1..)
+                                        .required(true),
+                                ),
+                        )
+                        .subcommand_required(true)
+                        .arg_required_else_help(true),
+                )
+                .subcommand(
+                    Command::new("info")
+                        .about("Shows a summary of a single backup")
+                        .arg(
+                            Arg::new("name")
+                                .help("The name of the backup to summarize")
+                                .num_args(1)
+                                .required(true),
+                        )
+                        .arg_required_else_help(true),
+                )
+                .subcommand(
+                    Command::new("mount")
+                        .about("Mounts a backup as a read only file system")
+                        .arg(
+                            Arg::new("name")
+                                .help("The name of the backup to mount")
+                                .num_args(1)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("mountpoint")
+                                .help("The directory to mount the backup on")
                                 .num_args(1)
                                 .required(true),
                         )
+                        .arg(
+                            Arg::new("daemon")
+                                .help("Run in the background instead of until Ctrl-C")
+                                .long("daemon")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("allow_other")
+                                .help("Allow other users to access the mount")
+                                .long("allow-other")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
+                        .arg_required_else_help(true),
+                )
+                .subcommand(
+                    Command::new("rename")
+                        .about("Renames a backup")
+                        .arg(
+                            Arg::new("name")
+                                .help("The name of the backup to rename")
+                                .num_args(1)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("new_name")
+                                .help("The new name of the backup")
+                                .num_args(1)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("force")
+                                .help("Delete an existing backup with the new name first")
+                                .long("force")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
                         .arg_required_else_help(false),
                 )
                 .subcommand(
@@ -140,22 +442,95 @@ fn cli() -> Command {
                                 .num_args(1)
                                 .required(true),
                         )
+                        .arg(
+                            Arg::new("path")
+                                .help("Only restore these archive paths, globs like 'logs/*.gz' are matched against the backup")
+                                .num_args(1..)
+                                .required(false),
+                        )
                         .arg(
                             Arg::new("destination")
                                 .help("The destination to restore the backup to")
+                                .short('d')
+                                .long("destination")
                                 .num_args(1)
                                 .required(false),
                         )
+                        .arg(
+                            Arg::new("ignore_missing")
+                                .help("Restore the paths that exist when some of them are not in the backup")
+                                .long("ignore-missing")
+                                .action(ArgAction::SetTrue)
+                                .requires("path")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("force")
+                                .help("Replace the existing content of the destination instead of merging into it")
+                                .long("force")
+                                .action(ArgAction::SetTrue)
+                                .requires("destination")
+                                .conflicts_with("skip_identical")
+                                .required(false),
+                        )
                         .arg(
                             Arg::new("threads")
-                                .help("The number of threads to use for the restore")
+                                .help("The number of threads to use for the restore, 0 uses all available cores")
                                 .short('t')
                                 .long("threads")
                                 .num_args(1)
-                                .default_value("16")
+                                .default_value("0")
                                 .value_parser(clap::value_parser!(usize))
                                 .required(false),
                         )
+                        .arg(
+                            Arg::new("skip_identical")
+                                .help("Skip files that already exist with a matching size and modification time")
+                                .long("skip-identical")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("verify_content")
+                                .help("Also compare the content of files skipped by --skip-identical against the backup")
+                                .long("verify-content")
+                                .action(ArgAction::SetTrue)
+                                .requires("skip_identical")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("no_chown")
+                                .help("Do not restore file ownership")
+                                .long("no-chown")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with_all(["uid_map", "gid_map"])
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("strict_ownership")
+                                .help("Fail the restore when ownership or permissions cannot be applied")
+                                .long("strict-ownership")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("uid_map")
+                                .help("Restore files owned by one user id as another (FROM:TO), can be repeated")
+                                .long("uid-map")
+                                .num_args(1)
+                                .action(ArgAction::Append)
+                                .value_parser(commands::backup::restore::parse_id_map)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("gid_map")
+                                .help("Restore files owned by one group id as another (FROM:TO), can be repeated")
+                                .long("gid-map")
+                                .num_args(1)
+                                .action(ArgAction::Append)
+                                .value_parser(commands::backup::restore::parse_id_map)
+                                .required(false),
+                        )
                         .arg_required_else_help(false),
                 )
                 .subcommand(
@@ -180,15 +555,203 @@ fn cli() -> Command {
                                 .long("format")
                                 .num_args(1)
                                 .required(true)
-                                .value_parser(["tar", "tar.gz", "ddup"])
+                                .value_parser([
+                                    "tar",
+                                    "tar.gz",
+                                    "tar.zst",
+                                    #[cfg(feature = "xz")]
+                                    "tar.xz",
+                                    "ddup",
+                                    "zip",
+                                ])
                                 .default_value("tar")
                                 .required(false),
                         )
+                        .arg(
+                            Arg::new("level")
+                                .help("The compression level of tar.gz, tar.zst and tar.xz output")
+                                .long("level")
+                                .num_args(1)
+                                .value_parser(clap::value_parser!(i32))
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("skip_symlinks")
+                                .help("Skip symlinks when converting to zip instead of storing their target")
+                                .long("skip-symlinks")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
                         .arg_required_else_help(false),
                 )
+                .subcommand(
+                    Command::new("prune")
+                        .about("Deletes backups that are not kept by a retention policy")
+                        .arg(
+                            Arg::new("keep_last")
+                                .help("Keep the N most recent backups")
+                                .long("keep-last")
+                                .num_args(1)
+                                .default_value("0")
+                                .value_parser(clap::value_parser!(usize))
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("keep_daily")
+                                .help("Keep the most recent backup of each of the last N days with backups")
+                                .long("keep-daily")
+                                .num_args(1)
+                                .default_value("0")
+                                .value_parser(clap::value_parser!(usize))
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("keep_weekly")
+                                .help("Keep the most recent backup of each of the last N weeks with backups")
+                                .long("keep-weekly")
+                                .num_args(1)
+                                .default_value("0")
+                                .value_parser(clap::value_parser!(usize))
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("keep_monthly")
+                                .help("Keep the most recent backup of each of the last N months with backups")
+                                .long("keep-monthly")
+                                .num_args(1)
+                                .default_value("0")
+                                .value_parser(clap::value_parser!(usize))
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("dry_run")
+                                .help("Only show which backups would be deleted")
+                                .long("dry-run")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with("clean")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("clean")
+                                .help("Clean up unreferenced chunks after pruning")
+                                .long("clean")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
+                        .arg_required_else_help(true),
+                )
+                .subcommand(
+                    Command::new("verify")
+                        .about("Verifies that the chunks of a backup are intact")
+                        .arg(
+                            Arg::new("name")
+                                .help("The name of the backup to verify")
+                                .num_args(1)
+                                .required_unless_present("all"),
+                        )
+                        .arg(
+                            Arg::new("all")
+                                .help("Verify every backup in the repository")
+                                .long("all")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with("name")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("full")
+                                .help("Read every chunk and check its hash, by default only checks that all chunks exist")
+                                .long("full")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("threads")
+                                .help("The number of threads to use for the verification, 0 uses all available cores")
+                                .short('t')
+                                .long("threads")
+                                .num_args(1)
+                                .default_value("0")
+                                .value_parser(clap::value_parser!(usize))
+                                .required(false),
+                        )
+                        .arg_required_else_help(true),
+                )
+                .subcommand(
+                    Command::new("diff")
+                        .about("Shows the differences between two backups")
+                        .arg(
+                            Arg::new("old")
+                                .help("The name of the older backup")
+                                .num_args(1)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("new")
+                                .help("The name of the newer backup")
+                                .num_args(1)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("name_only")
+                                .help("Only print the paths of changed entries")
+                                .long("name-only")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with("stat")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("stat")
+                                .help("Only print a summary of the changes")
+                                .long("stat")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("content")
+                                .help("Also compare the chunks of files with the same size")
+                                .long("content")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
+                        .arg_required_else_help(true),
+                )
                 .subcommand(
                     Command::new("list")
                         .about("Lists all backups")
+                        .arg(
+                            Arg::new("sort")
+                                .help("The field to sort backups by")
+                                .long("sort")
+                                .num_args(1)
+                                .default_value("date")
+                                .value_parser(["name", "date", "size"])
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("reverse")
+                                .help("Reverse the sort order")
+                                .short('r')
+                                .long("reverse")
+                                .action(ArgAction::SetTrue)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("tag")
+                                .help("Only list backups with this tag, can be given multiple times")
+                                .long("tag")
+                                .num_args(1)
+                                .action(ArgAction::Append)
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("format")
+                                .help("The output format, plain prints one name per line")
+                                .long("format")
+                                .num_args(1)
+                                .default_value("table")
+                                .value_parser(["table", "plain", "json"])
+                                .required(false),
+                        )
                         .arg_required_else_help(false),
                 )
                 .subcommand(
@@ -209,17 +772,361 @@ fn cli() -> Command {
                                         .num_args(1)
                                         .required(false),
                                 )
+                                .arg(
+                                    Arg::new("recursive")
+                                        .help("List subdirectories recursively")
+                                        .short('R')
+                                        .long("recursive")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("depth")
+                                        .help("List subdirectories recursively, up to this many levels deep")
+                                        .long("depth")
+                                        .num_args(1)
+                                        .value_parser(clap::value_parser!(usize))
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("long")
+                                        .help("Use the long listing format with permissions, owner, size and time")
+                                        .short('l')
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("one_per_line")
+                                        .help("List one name per line")
+                                        .short('1')
+                                        .action(ArgAction::SetTrue)
+                                        .conflicts_with("long")
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("time_style")
+                                        .help("How the long listing format shows times")
+                                        .long("time-style")
+                                        .num_args(1)
+                                        .value_parser(["full-iso", "relative"])
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("du")
+                                        .help("Show the total size of the files below each directory in the long listing format")
+                                        .long("du")
+                                        .action(ArgAction::SetTrue)
+                                        .requires("long")
+                                        .required(false),
+                                )
+                                .arg_required_else_help(false),
+                        )
+                        .subcommand(
+                            Command::new("du")
+                                .about("Shows the size of each directory in the backup file system")
+                                .disable_help_flag(true)
+                                .arg(
+                                    Arg::new("path")
+                                        .help("The directory to summarize")
+                                        .num_args(1)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("max_depth")
+                                        .help("Only print directories up to this many levels below the path")
+                                        .short('d')
+                                        .long("max-depth")
+                                        .num_args(1)
+                                        .value_parser(clap::value_parser!(usize))
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("stored")
+                                        .help("Also print the deduplicated size of the chunks in the storage")
+                                        .long("stored")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("human")
+                                        .help("Print sizes in human readable format")
+                                        .short('h')
+                                        .long("human-readable")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("sort")
+                                        .help("Sort directories by size, largest first")
+                                        .long("sort")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("help")
+                                        .help("Print help")
+                                        .long("help")
+                                        .action(ArgAction::Help),
+                                )
+                                .arg_required_else_help(false),
+                        )
+                        .subcommand(
+                            Command::new("find")
+                                .about("Finds entries in the backup file system by their path")
+                                .arg(
+                                    Arg::new("pattern")
+                                        .help("The glob to match archive paths against")
+                                        .num_args(1)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::new("regex")
+                                        .help("Treat the pattern as a regular expression")
+                                        .long("regex")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("type")
+                                        .help("Only match files (f), directories (d) or symlinks (l)")
+                                        .long("type")
+                                        .num_args(1)
+                                        .value_parser(["f", "d", "l"])
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("size")
+                                        .help("Only match entries larger (+N) or smaller (-N) than N bytes, with an optional k, M or G suffix")
+                                        .long("size")
+                                        .num_args(1)
+                                        .allow_hyphen_values(true)
+                                        .value_parser(commands::backup::fs::find::parse_size)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("mtime")
+                                        .help("Only match entries modified more (+N) or less (-N) than N days ago")
+                                        .long("mtime")
+                                        .num_args(1)
+                                        .allow_hyphen_values(true)
+                                        .value_parser(commands::backup::fs::find::parse_mtime)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("null")
+                                        .help("Separate paths with NUL instead of newlines, for xargs -0")
+                                        .short('0')
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg_required_else_help(true),
+                        )
+                        .subcommand(
+                            Command::new("tree")
+                                .about("Shows the backup file system as a tree")
+                                .arg(
+                                    Arg::new("path")
+                                        .help("The directory to show")
+                                        .num_args(1)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("depth")
+                                        .help("Only descend this many levels")
+                                        .short('L')
+                                        .long("depth")
+                                        .num_args(1)
+                                        .value_parser(clap::value_parser!(usize))
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("dirs_only")
+                                        .help("Only show directories")
+                                        .short('d')
+                                        .long("dirs-only")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("ascii")
+                                        .help("Draw the tree with ASCII characters")
+                                        .long("ascii")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
                                 .arg_required_else_help(false),
                         )
+                        .subcommand(
+                            Command::new("grep")
+                                .about("Searches the content of the files in the backup file system")
+                                .arg(
+                                    Arg::new("pattern")
+                                        .help("The string to search for")
+                                        .num_args(1)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::new("regex")
+                                        .help("Treat the pattern as a regular expression")
+                                        .long("regex")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("ignore_case")
+                                        .help("Match the pattern case-insensitively")
+                                        .short('i')
+                                        .long("ignore-case")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("count")
+                                        .help("Only print the number of matching lines of each file")
+                                        .short('c')
+                                        .long("count")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("binary")
+                                        .help("Also search files that look binary")
+                                        .long("binary")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("path")
+                                        .help("Only search files whose path matches this glob")
+                                        .long("path")
+                                        .num_args(1)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("max_size")
+                                        .help("Skip files larger than this, with an optional k, M or G suffix")
+                                        .long("max-size")
+                                        .num_args(1)
+                                        .value_parser(commands::backup::fs::grep::parse_max_size)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("threads")
+                                        .help("The number of files to search at once, 0 uses all available cores")
+                                        .short('t')
+                                        .long("threads")
+                                        .num_args(1)
+                                        .default_value("0")
+                                        .value_parser(clap::value_parser!(usize))
+                                        .required(false),
+                                )
+                                .arg_required_else_help(true),
+                        )
+                        .subcommand(
+                            Command::new("extract")
+                                .about("Extracts a file or directory from the backup file system")
+                                .arg(
+                                    Arg::new("path")
+                                        .help("The path of the file or directory to extract")
+                                        .num_args(1)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::new("destination")
+                                        .help("Where to extract to, defaults to the entry name in the current directory")
+                                        .num_args(1)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("overwrite")
+                                        .help("Overwrite existing files")
+                                        .long("overwrite")
+                                        .action(ArgAction::SetTrue)
+                                        .conflicts_with("skip_existing")
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("skip_existing")
+                                        .help("Leave existing files untouched")
+                                        .long("skip-existing")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("threads")
+                                        .help("The number of threads to use for extracting, 0 uses all available cores")
+                                        .short('t')
+                                        .long("threads")
+                                        .num_args(1)
+                                        .default_value("0")
+                                        .value_parser(clap::value_parser!(usize))
+                                        .required(false),
+                                )
+                                .arg_required_else_help(true),
+                        )
                         .subcommand(
                             Command::new("cat")
                                 .about("Displays the content of a file in the backup file system")
                                 .arg(
                                     Arg::new("path")
-                                        .help("The path to the file to display")
+                                        .help("The paths of the files to display")
+                                        .num_args(1..)
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::new("offset")
+                                        .help("The byte offset to start reading at")
+                                        .long("offset")
+                                        .num_args(1)
+                                        .value_parser(clap::value_parser!(u64))
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("length")
+                                        .help("The number of bytes to read")
+                                        .long("length")
+                                        .num_args(1)
+                                        .value_parser(clap::value_parser!(u64))
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("output")
+                                        .help("Write the content to this file instead of stdout, multiple files are written into this directory")
+                                        .short('o')
+                                        .long("output")
+                                        .num_args(1)
+                                        .required(false),
+                                )
+                                .arg(
+                                    Arg::new("force")
+                                        .help("Print binary content to a terminal and overwrite existing output files")
+                                        .long("force")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg_required_else_help(false),
+                        )
+                        .subcommand(
+                            Command::new("stat")
+                                .about("Shows everything the backup knows about a single entry")
+                                .arg(
+                                    Arg::new("path")
+                                        .help("The path of the entry to inspect")
                                         .num_args(1)
                                         .required(true),
                                 )
+                                .arg(
+                                    Arg::new("chunks")
+                                        .help("List the chunk IDs of a file with their reference counts")
+                                        .long("chunks")
+                                        .action(ArgAction::SetTrue)
+                                        .required(false),
+                                )
+                                .arg_required_else_help(true),
+                        )
+                        .subcommand(
+                            Command::new("shell")
+                                .about("Browses the backup file system interactively")
                                 .arg_required_else_help(false),
                         ),
                 )
@@ -230,10 +1137,10 @@ fn cli() -> Command {
 
 fn handle_command_result(result: std::io::Result<i32>) {
     match result {
-        Ok(code) => std::process::exit(code),
+        Ok(code) => commands::exit(code),
         Err(err) => {
-            eprintln!("{} {}", "error:".red(), err);
-            std::process::exit(1);
+            commands::Output::error(format!("{} {}", "error:".red(), err));
+            commands::exit(commands::exit_code(&err));
         }
     }
 }
@@ -241,12 +1148,34 @@ fn handle_command_result(result: std::io::Result<i32>) {
 fn main() {
     let matches = cli().get_matches();
 
+    let verbosity = if matches.get_flag("quiet") {
+        0
+    } else {
+        1 + matches.get_count("verbose")
+    };
+    commands::init_color(matches.get_one::<String>("color").expect("required"));
+    commands::set_assume_yes(matches.get_flag("yes"));
+    commands::set_lock_timeout(
+        matches
+            .get_one::<u64>("lock_timeout")
+            .map(|seconds| std::time::Duration::from_secs(*seconds)),
+    );
+    commands::Output::set_verbosity(verbosity);
+    commands::init_logging(verbosity);
+    commands::init_interrupt_handler();
+
+    if matches.get_flag("json") || std::env::var("DDUP_BAK_JSON").is_ok_and(|value| value == "1") {
+        commands::Output::Json.set();
+    }
+
     match matches.subcommand() {
         Some(("init", sub_matches)) => handle_command_result(commands::init::init(sub_matches)),
         Some(("rebuild", sub_matches)) => {
             handle_command_result(commands::rebuild::rebuild(sub_matches))
         }
         Some(("clean", sub_matches)) => handle_command_result(commands::clean::clean(sub_matches)),
+        Some(("check", sub_matches)) => handle_command_result(commands::check::check(sub_matches)),
+        Some(("stats", sub_matches)) => handle_command_result(commands::stats::stats(sub_matches)),
         Some(("backup", sub_matches)) => match sub_matches.subcommand() {
             Some(("create", sub_matches)) => {
                 handle_command_result(commands::backup::create::create(sub_matches))
@@ -254,12 +1183,39 @@ fn main() {
             Some(("delete", sub_matches)) => {
                 handle_command_result(commands::backup::delete::delete(sub_matches))
             }
+            Some(("rename", sub_matches)) => {
+                handle_command_result(commands::backup::rename::rename(sub_matches))
+            }
+            Some(("tag", sub_matches)) => match sub_matches.subcommand() {
+                Some(("add", sub_sub_matches)) => {
+                    handle_command_result(commands::backup::tag::add(sub_sub_matches))
+                }
+                Some(("remove", sub_sub_matches)) => {
+                    handle_command_result(commands::backup::tag::remove(sub_sub_matches))
+                }
+                _ => unreachable!(),
+            },
+            Some(("info", sub_matches)) => {
+                handle_command_result(commands::backup::info::info(sub_matches))
+            }
+            Some(("mount", sub_matches)) => {
+                handle_command_result(commands::backup::mount::mount(sub_matches))
+            }
             Some(("restore", sub_matches)) => {
                 handle_command_result(commands::backup::restore::restore(sub_matches))
             }
             Some(("convert", sub_matches)) => {
                 handle_command_result(commands::backup::convert::convert(sub_matches))
             }
+            Some(("prune", sub_matches)) => {
+                handle_command_result(commands::backup::prune::prune(sub_matches))
+            }
+            Some(("verify", sub_matches)) => {
+                handle_command_result(commands::backup::verify::verify(sub_matches))
+            }
+            Some(("diff", sub_matches)) => {
+                handle_command_result(commands::backup::diff::diff(sub_matches))
+            }
             Some(("list", sub_matches)) => {
                 handle_command_result(commands::backup::list::list(sub_matches))
             }
@@ -270,12 +1226,54 @@ fn main() {
                         sub_sub_matches,
                     ))
                 }
+                Some(("du", sub_sub_matches)) => {
+                    handle_command_result(commands::backup::fs::du::du(
+                        sub_matches.get_one::<String>("name").unwrap(),
+                        sub_sub_matches,
+                    ))
+                }
+                Some(("find", sub_sub_matches)) => {
+                    handle_command_result(commands::backup::fs::find::find(
+                        sub_matches.get_one::<String>("name").unwrap(),
+                        sub_sub_matches,
+                    ))
+                }
+                Some(("grep", sub_sub_matches)) => {
+                    handle_command_result(commands::backup::fs::grep::grep(
+                        sub_matches.get_one::<String>("name").unwrap(),
+                        sub_sub_matches,
+                    ))
+                }
+                Some(("tree", sub_sub_matches)) => {
+                    handle_command_result(commands::backup::fs::tree::tree(
+                        sub_matches.get_one::<String>("name").unwrap(),
+                        sub_sub_matches,
+                    ))
+                }
+                Some(("extract", sub_sub_matches)) => {
+                    handle_command_result(commands::backup::fs::extract::extract(
+                        sub_matches.get_one::<String>("name").unwrap(),
+                        sub_sub_matches,
+                    ))
+                }
                 Some(("cat", sub_sub_matches)) => {
                     handle_command_result(commands::backup::fs::cat::cat(
                         sub_matches.get_one::<String>("name").unwrap(),
                         sub_sub_matches,
                     ))
                 }
+                Some(("stat", sub_sub_matches)) => {
+                    handle_command_result(commands::backup::fs::stat::stat(
+                        sub_matches.get_one::<String>("name").unwrap(),
+                        sub_sub_matches,
+                    ))
+                }
+                Some(("shell", sub_sub_matches)) => {
+                    handle_command_result(commands::backup::fs::shell::shell(
+                        sub_matches.get_one::<String>("name").unwrap(),
+                        sub_sub_matches,
+                    ))
+                }
                 _ => cli().print_help().unwrap(),
             },
             _ => unreachable!(),
@@ -1,27 +1,834 @@
 use crate::{
     archive::{
-        Archive, CompressionFormat, CompressionFormatCallback, ProgressCallback, entries::Entry,
+        Archive, CompressionFormat, CompressionFormatCallback, ProgressCallback,
+        entries::{DirectoryEntry, Entry, EntryMode, FileEntry, SymlinkEntry},
+        metadata_owner,
+    },
+    chunks::{
+        ChunkIndex, CleanPlan, RebuildProgressCallback,
+        ids::ChunkIdDecoder,
+        lock::{
+            self, LockBackend, LockMetrics, LockMode, LockStatus, LockWaitCallback, ReadGuard,
+            WriteGuard,
+        },
+        reader::{DEFAULT_COPY_BUFFER_SIZE, EntryReader},
+        storage,
     },
-    chunks::{ChunkIndex, RebuildProgressCallback, lock::LockMode, reader::EntryReader, storage},
 };
 use parking_lot::{Mutex, RwLock};
 use std::{
+    collections::HashMap,
     fs::{File, FileTimes},
-    io::{Cursor, Write},
+    io::{Cursor, Read, Write},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{Receiver, Sender},
+    },
+    time::Duration,
 };
 
 pub type DeletionProgressCallback = Option<Arc<dyn Fn(u64, bool) + Send + Sync + 'static>>;
+pub type CleanProgressCallback = Option<Arc<dyn Fn(u64, u64) + Send + Sync + 'static>>;
+pub type ProgressEventCallback = Option<Arc<dyn Fn(ProgressEvent) + Send + Sync + 'static>>;
+pub type VerifyProgressCallback = Option<Arc<dyn Fn(&Path, bool) + Send + Sync + 'static>>;
+
+/// Upper bound for worker threads, larger requests are clamped.
+pub const MAX_THREADS: usize = 1024;
+
+/// Resolves a requested worker thread count, `0` uses one thread per available core.
+pub fn resolve_threads(threads: usize) -> usize {
+    match threads {
+        0 => std::thread::available_parallelism()
+            .map(|threads| threads.get())
+            .unwrap_or(1),
+        threads if threads > MAX_THREADS => {
+            log::warn!("clamping {threads} threads to {MAX_THREADS}");
+
+            MAX_THREADS
+        }
+        threads => threads,
+    }
+}
+
+/// Returns the error of an operation stopped through its `cancel` flag.
+#[inline]
+fn check_cancelled(cancel: &Option<Arc<AtomicBool>>) -> std::io::Result<()> {
+    if cancel
+        .as_ref()
+        .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Interrupted,
+            "Operation was interrupted",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Creates or truncates the file at `path` for a restore. A symlink or special file already
+/// at `path` is removed first and the open does not follow links, so a restore into a
+/// populated destination never writes through a link to somewhere outside of it.
+fn create_restore_file(path: &Path) -> std::io::Result<File> {
+    if let Ok(metadata) = path.symlink_metadata()
+        && !metadata.is_file()
+        && !metadata.is_dir()
+    {
+        std::fs::remove_file(path)?;
+    }
+
+    let mut options = File::options();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+
+    options.open(path)
+}
+
+/// Creates the directory at `path` for a restore, replacing a symlink or file in its place
+/// so the restore of its entries does not follow a link out of the destination.
+fn create_restore_directory(path: &Path) -> std::io::Result<()> {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => {
+            std::fs::remove_file(path)?;
+            std::fs::create_dir(path)
+        }
+        Err(_) => std::fs::create_dir_all(path),
+    }
+}
+
+/// Structured progress reported by long running repository operations.
+#[derive(Debug, Clone, Copy)]
+pub enum ProgressEvent<'a> {
+    /// The pre-scan finished, these are the totals the operation will process.
+    ScanComplete { files: u64, bytes: u64 },
+    /// A file has been fully processed.
+    FileDone { path: &'a Path, bytes: u64 },
+    /// A chunk of file content has been processed.
+    BytesProcessed(u64),
+}
+
+/// Options for `Repository::create_archive`.
+#[derive(Clone, Default)]
+pub struct CreateOptions {
+    /// Walks the directory once before chunking so `ScanComplete` can report
+    /// the real totals before any `FileDone`/`BytesProcessed` events.
+    pub count_first: bool,
+    pub progress: ProgressEventCallback,
+    /// Continues from the checkpoint an interrupted create left behind, files with an
+    /// unchanged size and modification time are taken over without chunking them again.
+    pub resume: bool,
+    /// Stops the create with an `Interrupted` error once set, files that are being chunked
+    /// are finished and kept as a checkpoint like on any other error.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RestoreMode {
+    /// Every entry is written, existing files are truncated and replaced.
+    #[default]
+    Overwrite,
+    /// Files that already exist at the destination with the same size and
+    /// modification time are left untouched, only their mode and owner are corrected.
+    SkipIdentical,
+    /// Files and symlinks that already exist at the destination are left untouched.
+    SkipExisting,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum RestoreOwnership {
+    /// Restores the user and group IDs stored in the archive.
+    #[default]
+    Preserve,
+    /// Leaves ownership to whoever runs the restore.
+    Skip,
+    /// Translates user IDs with the first and group IDs with the second map,
+    /// IDs without a mapping are restored unchanged.
+    Map(HashMap<u32, u32>, HashMap<u32, u32>),
+}
+
+impl RestoreOwnership {
+    /// Returns the owner to restore for an archived owner, `None` if ownership should not be changed.
+    pub fn map(&self, (uid, gid): (u32, u32)) -> Option<(u32, u32)> {
+        match self {
+            RestoreOwnership::Preserve => Some((uid, gid)),
+            RestoreOwnership::Skip => None,
+            RestoreOwnership::Map(uids, gids) => Some((
+                uids.get(&uid).copied().unwrap_or(uid),
+                gids.get(&gid).copied().unwrap_or(gid),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkFallback {
+    /// Fails the restore.
+    #[default]
+    Fail,
+    /// Copies the target file in place of the link once everything else is restored. Targets
+    /// that are directories, missing or outside of the destination get a placeholder.
+    CopyTarget,
+    /// Writes a regular file containing the link target in place of the link.
+    Placeholder,
+}
+
+/// Files below this size are restored in batches by default, see `RestoreOptions::inline_restore_size`.
+pub const DEFAULT_INLINE_RESTORE_SIZE: u64 = 64 * 1024;
+/// The most small entries restored by a single task.
+const RESTORE_BATCH_LENGTH: usize = 64;
+
+/// Options for `Repository::restore_entries_with_options`.
+#[derive(Clone, Default)]
+pub struct RestoreOptions {
+    /// Where to restore to, defaults to `.ddup-bak/archives-restored/<name>`.
+    pub destination: Option<PathBuf>,
+    pub mode: RestoreMode,
+    pub ownership: RestoreOwnership,
+    /// What to do when a symlink cannot be created because the process lacks the privilege,
+    /// only relevant on windows where creating symlinks requires developer mode or admin rights.
+    pub symlink_fallback: SymlinkFallback,
+    /// Fails the restore when ownership or permissions cannot be applied,
+    /// by default these failures are recorded as warnings in the report.
+    pub strict_ownership: bool,
+    /// Receives `BytesProcessed` for every chunk written and `FileDone` for every file,
+    /// files skipped by the restore mode are reported as processed as well.
+    pub progress: ProgressEventCallback,
+    /// Stops the restore with an `Interrupted` error once set, files that were already
+    /// written stay in the destination.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Files smaller than this are restored in batches instead of a task each,
+    /// defaults to `DEFAULT_INLINE_RESTORE_SIZE`. `Some(0)` gives every entry its own task.
+    pub inline_restore_size: Option<u64>,
+    /// With `RestoreMode::SkipIdentical`, files matching in size and modification time are
+    /// only skipped once their content hashes to the chunks of the entry as well.
+    pub verify_content: bool,
+}
+
+impl std::fmt::Debug for RestoreOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RestoreOptions")
+            .field("destination", &self.destination)
+            .field("mode", &self.mode)
+            .field("ownership", &self.ownership)
+            .field("symlink_fallback", &self.symlink_fallback)
+            .field("strict_ownership", &self.strict_ownership)
+            .field("progress", &self.progress.is_some())
+            .field("inline_restore_size", &self.inline_restore_size)
+            .field("verify_content", &self.verify_content)
+            .finish()
+    }
+}
+
+/// Summary of a finished restore.
+#[derive(Debug, Clone, Default)]
+pub struct RestoreReport {
+    pub destination: PathBuf,
+
+    pub files_restored: u64,
+    pub directories_restored: u64,
+    pub symlinks_restored: u64,
+    pub bytes_written: u64,
+    pub skipped_identical: u64,
+    pub skipped_existing: u64,
+
+    pub warnings: Vec<String>,
+}
+
+/// A chunk whose reference count in the index differs from the references found in archives.
+#[derive(Debug, Clone, Copy)]
+pub struct ReferenceMismatch {
+    pub chunk_id: u64,
+    pub index_count: u64,
+    pub archive_count: u64,
+}
+
+/// Result of `Repository::check`.
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    pub archives_checked: u64,
+
+    /// Chunks referenced by archives with a wrong reference count in the index.
+    pub count_mismatches: Vec<ReferenceMismatch>,
+    /// Chunk IDs referenced by archives that do not exist in the index.
+    pub missing_from_index: Vec<u64>,
+    /// Chunk IDs referenced by archives whose content is missing from the storage.
+    pub missing_from_storage: Vec<u64>,
+    /// Chunk IDs with a nonzero reference count that no archive references.
+    pub unreferenced: Vec<u64>,
+    /// Chunk IDs queued for reuse that are still live in the index.
+    pub deleted_overlap: Vec<u64>,
+
+    /// Whether any reference counts or reuse queue entries were rewritten.
+    pub repaired: bool,
+}
+
+impl CheckReport {
+    /// Returns `true` if no inconsistencies were found.
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.count_mismatches.is_empty()
+            && self.missing_from_index.is_empty()
+            && self.missing_from_storage.is_empty()
+            && self.unreferenced.is_empty()
+            && self.deleted_overlap.is_empty()
+    }
+
+    /// Returns `true` if chunks referenced by archives are missing, which `repair` cannot fix.
+    #[inline]
+    pub fn has_missing_chunks(&self) -> bool {
+        !self.missing_from_index.is_empty() || !self.missing_from_storage.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifyLevel {
+    /// Only checks that every referenced chunk exists in the index and storage.
+    #[default]
+    Quick,
+    /// Reads every referenced chunk, checks its hash and the resulting file sizes.
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyProblemKind {
+    /// A referenced chunk is not in the index or storage.
+    MissingChunk,
+    /// A chunk could not be read or its content does not match its hash.
+    CorruptChunk,
+    /// The chunk list of the file is unreadable or its chunks do not add up to its size.
+    File,
+}
+
+/// A single problem found by `Repository::verify_archive`.
+#[derive(Debug, Clone)]
+pub struct VerifyProblem {
+    /// Path of the affected file inside the archive.
+    pub path: PathBuf,
+    pub chunk_id: Option<u64>,
+    pub kind: VerifyProblemKind,
+    pub reason: String,
+}
+
+/// Result of `Repository::verify_archive`.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub files_checked: u64,
+    pub chunks_checked: u64,
+    pub bytes_checked: u64,
+
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffStatus {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// Which properties of an entry differ between two archives.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffChanges {
+    pub size: bool,
+    pub mode: bool,
+    pub mtime: bool,
+    pub owner: bool,
+    pub target: bool,
+    /// Only set when the chunk ID lists are compared, see `Repository::diff_archives`.
+    pub content: bool,
+}
+
+impl DiffChanges {
+    #[inline]
+    pub fn any(&self) -> bool {
+        self.size || self.mode || self.mtime || self.owner || self.target || self.content
+    }
+}
+
+/// A single difference reported by `Repository::diff_archives`.
+/// `old` is the entry in the first archive, `new` the entry in the second one.
+#[derive(Debug, Clone, Copy)]
+pub struct DiffEntry<'a> {
+    pub path: &'a Path,
+    pub status: DiffStatus,
+    pub changes: DiffChanges,
+
+    pub old: Option<&'a Entry>,
+    pub new: Option<&'a Entry>,
+}
+
+/// Which archives `Repository::prune` keeps, every archive not selected by any rule is removed.
+/// The daily, weekly and monthly rules keep the newest archive of each of the most recent
+/// days, weeks and months that have archives, in local time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+impl RetentionPolicy {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+    }
+}
+
+/// What `Repository::prune` decided for a single archive.
+#[derive(Debug, Clone)]
+pub struct PruneDecision {
+    pub name: String,
+    pub created: std::time::SystemTime,
+    /// The rules that keep this archive, empty if it is removed.
+    pub reasons: Vec<String>,
+}
+
+impl PruneDecision {
+    #[inline]
+    pub fn keep(&self) -> bool {
+        !self.reasons.is_empty()
+    }
+}
+
+/// Repository wide numbers returned by `Repository::stats`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RepositoryStats {
+    pub archives: u64,
+    pub unique_chunks: u64,
+    /// Size of all chunks in the storage, after compression.
+    pub stored_bytes: u64,
+    /// Sum of the file sizes of all archives.
+    pub logical_bytes: u64,
+    /// Chunk IDs freed by deletions that have not been reused yet.
+    pub deleted_chunk_ids: u64,
+    /// Chunks in the index whose content is missing from the storage, see [`Repository::check`].
+    pub missing_chunks: u64,
+}
+
+impl RepositoryStats {
+    /// How many logical bytes are stored per byte in the storage.
+    #[inline]
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return 0.0;
+        }
+
+        self.logical_bytes as f64 / self.stored_bytes as f64
+    }
+}
+
+/// User supplied tags and description, stored next to the archive in `<name>.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveMetadata {
+    pub tags: Vec<String>,
+    pub description: Option<String>,
+}
+
+impl ArchiveMetadata {
+    /// Checks that a tag is usable, tags may not be empty or contain commas or newlines.
+    pub fn check_tag(tag: &str) -> std::io::Result<()> {
+        if tag.trim().is_empty() || tag.contains([',', '\n', '\r']) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid tag {tag:?}, tags may not be empty or contain commas or newlines"),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Adds a tag, returns `false` if the archive already had it.
+    pub fn add_tag(&mut self, tag: &str) -> std::io::Result<bool> {
+        Self::check_tag(tag)?;
+
+        if self.tags.iter().any(|t| t == tag) {
+            return Ok(false);
+        }
+
+        self.tags.push(tag.to_string());
+
+        Ok(true)
+    }
+
+    /// Removes a tag, returns `false` if the archive did not have it.
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        let len = self.tags.len();
+        self.tags.retain(|t| t != tag);
+
+        self.tags.len() != len
+    }
+
+    #[inline]
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.description.is_none()
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "tags": self.tags,
+            "description": self.description,
+        })
+    }
 
+    fn from_json(value: &serde_json::Value) -> Self {
+        Self {
+            tags: value
+                .get("tags")
+                .and_then(|tags| tags.as_array())
+                .map(|tags| {
+                    tags.iter()
+                        .filter_map(|tag| tag.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            description: value
+                .get("description")
+                .and_then(|description| description.as_str())
+                .map(String::from),
+        }
+    }
+}
+
+/// An archive as listed by `Repository::list_archives_detailed`.
+#[derive(Debug, Clone)]
+pub struct ArchiveInfo {
+    pub name: String,
+    pub created: std::time::SystemTime,
+    /// Size of the archive file itself, not including chunks.
+    pub size: u64,
+
+    /// Files, directories and symlinks in the archive.
+    pub entries: u64,
+    /// Sum of the file sizes in the archive.
+    pub logical_bytes: u64,
+
+    pub metadata: ArchiveMetadata,
+}
+
+/// Numbers for a single archive returned by `Repository::archive_stats`.
+#[derive(Debug, Clone)]
+pub struct ArchiveStats {
+    pub name: String,
+    pub created: std::time::SystemTime,
+
+    pub files: u64,
+    /// Unique chunks referenced by the archive.
+    pub chunks: u64,
+    /// Sum of the file sizes in the archive.
+    pub logical_bytes: u64,
+    /// Stored size of all chunks referenced by the archive, shared or not.
+    pub stored_bytes: u64,
+    /// Stored size of the chunks only referenced by this archive,
+    /// this is what deleting the archive and cleaning would free.
+    pub exclusive_bytes: u64,
+}
+
+/// What `Repository::plan_archive` would back up.
+#[derive(Debug, Clone, Default)]
+pub struct ArchivePlan {
+    /// The files that would be chunked with their size, relative to the directory root.
+    pub files: Vec<(PathBuf, u64)>,
+    pub directories: u64,
+    pub symlinks: u64,
+    pub bytes: u64,
+}
+
+/// A change to the entry tree of an archive being created, applied in order by the
+/// thread owning the archive so workers never wait for each other to touch it.
+enum TreeUpdate {
+    /// A directory or symlink at a path relative to the backed up directory.
+    Entry { path: PathBuf, entry: Entry },
+    /// A chunked file, its chunk IDs are written as the payload of its entry.
+    File {
+        path: PathBuf,
+        source: PathBuf,
+        metadata: Box<std::fs::Metadata>,
+        compression: CompressionFormat,
+        chunk_ids: Vec<u8>,
+    },
+}
+
+struct RestoreState {
+    options: RestoreOptions,
+    io_buffer_size: usize,
+
+    files_restored: AtomicU64,
+    directories_restored: AtomicU64,
+    symlinks_restored: AtomicU64,
+    bytes_written: AtomicU64,
+    skipped_identical: AtomicU64,
+    skipped_existing: AtomicU64,
+
+    warnings: Mutex<Vec<String>>,
+    /// Links restored as a placeholder with `SymlinkFallback::CopyTarget`, together with
+    /// their target and why the link failed. Their targets are copied over the
+    /// placeholders by `copy_deferred_targets` once the whole tree is restored.
+    deferred_copies: Mutex<Vec<(PathBuf, String, std::io::Error)>>,
+}
+
+impl RestoreState {
+    #[inline]
+    fn progress(&self, event: ProgressEvent) {
+        if let Some(f) = &self.options.progress {
+            f(event)
+        }
+    }
+
+    /// Whether the entry at `path` is left alone because of `RestoreMode::SkipExisting`.
+    fn skip_existing(&self, path: &Path) -> bool {
+        if self.options.mode == RestoreMode::SkipExisting && std::fs::symlink_metadata(path).is_ok()
+        {
+            self.skipped_existing.fetch_add(1, Ordering::Relaxed);
+
+            return true;
+        }
+
+        false
+    }
+
+    /// Turns a permission error into a warning unless `strict_ownership` is set.
+    fn warn_on_denied(
+        &self,
+        result: std::io::Result<()>,
+        message: impl FnOnce() -> String,
+    ) -> std::io::Result<()> {
+        match result {
+            Err(err)
+                if !self.options.strict_ownership
+                    && matches!(
+                        err.kind(),
+                        std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::Unsupported
+                    ) =>
+            {
+                self.warnings.lock().push(format!("{}: {err}", message()));
+
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Applies the ownership option to `path`, symlinks themselves are changed, not their target.
+    #[cfg(unix)]
+    fn chown(&self, path: &Path, owner: (u32, u32)) -> std::io::Result<()> {
+        let Some((uid, gid)) = self.options.ownership.map(owner) else {
+            return Ok(());
+        };
+
+        self.warn_on_denied(
+            std::os::unix::fs::lchown(path, Some(uid), Some(gid)),
+            || {
+                format!(
+                    "failed to change owner of {} to {uid}:{gid}",
+                    path.display()
+                )
+            },
+        )
+    }
+
+    fn set_permissions(&self, path: &Path, mode: EntryMode) -> std::io::Result<()> {
+        #[cfg(unix)]
+        let result = std::fs::set_permissions(path, mode.into());
+        #[cfg(not(unix))]
+        let result = (|| {
+            let mut permissions = std::fs::metadata(path)?.permissions();
+            mode.apply(&mut permissions);
+
+            std::fs::set_permissions(path, permissions)
+        })();
+
+        self.warn_on_denied(result, || {
+            format!(
+                "failed to set permissions of {} to {:o}",
+                path.display(),
+                mode.bits()
+            )
+        })
+    }
+
+    /// Sets the readonly attribute of a symlink itself, `std::fs::set_permissions` would follow it.
+    #[cfg(windows)]
+    fn set_link_permissions(&self, path: &Path, mode: EntryMode) -> std::io::Result<()> {
+        use std::os::windows::fs::OpenOptionsExt;
+
+        const FILE_READ_ATTRIBUTES: u32 = 0x80;
+        const FILE_WRITE_ATTRIBUTES: u32 = 0x100;
+        const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x02000000;
+        const FILE_FLAG_OPEN_REPARSE_POINT: u32 = 0x00200000;
+
+        let result = (|| {
+            let link = std::fs::OpenOptions::new()
+                .access_mode(FILE_READ_ATTRIBUTES | FILE_WRITE_ATTRIBUTES)
+                .custom_flags(FILE_FLAG_OPEN_REPARSE_POINT | FILE_FLAG_BACKUP_SEMANTICS)
+                .open(path)?;
+
+            let mut permissions = link.metadata()?.permissions();
+            mode.apply(&mut permissions);
+
+            link.set_permissions(permissions)
+        })();
+
+        self.warn_on_denied(result, || {
+            format!(
+                "failed to set permissions of {} to {:o}",
+                path.display(),
+                mode.bits()
+            )
+        })
+    }
+
+    /// Handles a symlink that could not be created because the process lacks
+    /// the privilege to create symlinks, according to `symlink_fallback`.
+    #[cfg(windows)]
+    fn symlink_fallback(
+        &self,
+        path: &Path,
+        link_entry: &crate::archive::entries::SymlinkEntry,
+        err: std::io::Error,
+    ) -> std::io::Result<()> {
+        const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+
+        if err.raw_os_error() != Some(ERROR_PRIVILEGE_NOT_HELD)
+            && err.kind() != std::io::ErrorKind::PermissionDenied
+        {
+            return Err(err);
+        }
+
+        if self.options.symlink_fallback == SymlinkFallback::Fail {
+            return Err(err);
+        }
+
+        std::fs::write(path, link_entry.target.as_bytes())?;
+
+        // the target may not be restored yet, it is copied once everything else is
+        if self.options.symlink_fallback == SymlinkFallback::CopyTarget {
+            self.deferred_copies
+                .lock()
+                .push((path.to_path_buf(), link_entry.target.clone(), err));
+
+            return Ok(());
+        }
+
+        self.warnings.lock().push(format!(
+            "failed to create symlink {} -> {}, wrote a placeholder instead: {err}",
+            path.display(),
+            link_entry.target,
+        ));
+
+        Ok(())
+    }
+
+    /// Replaces the placeholders of links restored with `SymlinkFallback::CopyTarget` by
+    /// a copy of their target, see `copy_target_source`. Links whose target cannot be
+    /// copied keep the placeholder.
+    fn copy_deferred_targets(&self, destination: &Path) -> std::io::Result<()> {
+        let deferred = std::mem::take(&mut *self.deferred_copies.lock());
+
+        for (path, target, err) in deferred {
+            let copied = match copy_target_source(destination, &path, &target) {
+                Some(source) => {
+                    std::fs::copy(source, &path)?;
+                    true
+                }
+                None => false,
+            };
+
+            self.warnings.lock().push(format!(
+                "failed to create symlink {} -> {target}, {}: {err}",
+                path.display(),
+                if copied {
+                    "copied the target instead"
+                } else {
+                    "wrote a placeholder instead"
+                }
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Where the file the link at `path` points to is, resolved against the link and only
+/// if it is inside `destination`, so a restored link cannot copy files from elsewhere.
+fn copy_target_source(destination: &Path, path: &Path, target: &str) -> Option<PathBuf> {
+    let source = std::fs::canonicalize(path.parent()?.join(target)).ok()?;
+    let destination = std::fs::canonicalize(destination).ok()?;
+
+    let inside = source.starts_with(&destination)
+        && std::fs::canonicalize(path).is_ok_and(|path| path != source);
+
+    (inside && source.is_file()).then_some(source)
+}
+
+/// Clones share the chunk index and its lock, every clone saves the index when dropped
+/// unless `save_on_drop` is unset for it.
+#[derive(Clone)]
 pub struct Repository {
     pub directory: PathBuf,
     pub save_on_drop: bool,
+    /// How long operations wait for the repository lock before failing with a
+    /// `WouldBlock` error, `None` waits forever.
+    pub lock_timeout: Option<Duration>,
+    /// The size of the buffer file contents are copied through when restoring or reading
+    /// entries, defaults to `DEFAULT_COPY_BUFFER_SIZE`.
+    pub io_buffer_size: usize,
 
     pub chunk_index: ChunkIndex,
 }
 
 impl Repository {
+    /// Finds the repository `start` belongs to, like git searching for `.git`.
+    /// Returns `start` itself when it contains a `.ddup-bak` directory, otherwise the
+    /// closest parent directory that does, or a `NotFound` error.
+    pub fn discover(start: &Path) -> std::io::Result<PathBuf> {
+        if start.join(".ddup-bak").is_dir() {
+            return Ok(start.to_path_buf());
+        }
+
+        let start = start
+            .canonicalize()
+            .or_else(|_| std::path::absolute(start))?;
+
+        start
+            .ancestors()
+            .find(|directory| directory.join(".ddup-bak").is_dir())
+            .map(Path::to_path_buf)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!(
+                        "No repository found in {} or any parent directory",
+                        start.display()
+                    ),
+                )
+            })
+    }
+
     /// Opens an existing repository.
     /// The repository must be initialized with `new` before use.
     /// The repository directory must contain a `.ddup-bak` directory.
@@ -40,11 +847,16 @@ impl Repository {
             ),
         )?;
 
-        Ok(Self {
+        log::debug!("Opened repository at {}", directory.display());
+
+        Self {
             directory: directory.to_path_buf(),
             save_on_drop: true,
+            lock_timeout: None,
+            io_buffer_size: DEFAULT_COPY_BUFFER_SIZE,
             chunk_index,
-        })
+        }
+        .with_stored_lock_backend()
     }
 
     /// Rebuilds a corrupted repository by scanning archives and chunk storage.
@@ -77,13 +889,16 @@ impl Repository {
             progress,
         )?;
 
-        chunk_index.save()?;
+        chunk_index.save(true)?;
 
-        Ok(Self {
+        Self {
             directory: directory.to_path_buf(),
             save_on_drop: true,
+            lock_timeout: None,
+            io_buffer_size: DEFAULT_COPY_BUFFER_SIZE,
             chunk_index,
-        })
+        }
+        .with_stored_lock_backend()
     }
 
     /// Opens a repository, falling back to rebuild if the index is corrupt.
@@ -137,16 +952,26 @@ impl Repository {
         Ok(Self {
             directory: directory.to_path_buf(),
             save_on_drop: true,
+            lock_timeout: None,
+            io_buffer_size: DEFAULT_COPY_BUFFER_SIZE,
             chunk_index,
         })
     }
 
+    /// Saves the chunk index if it changed since it was loaded or last saved.
     pub fn save(&self) -> std::io::Result<()> {
-        self.chunk_index.save()?;
+        self.chunk_index.save(false)?;
 
         Ok(())
     }
 
+    /// Saves the chunk index and flushes the chunk storage, so everything written
+    /// so far survives a crash.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.save()?;
+        self.chunk_index.storage.flush()
+    }
+
     #[inline]
     pub fn archive_path(&self, name: &str) -> PathBuf {
         self.directory
@@ -154,6 +979,13 @@ impl Repository {
             .join(format!("{name}.ddup"))
     }
 
+    #[inline]
+    pub fn archive_metadata_path(&self, name: &str) -> PathBuf {
+        self.directory
+            .join(".ddup-bak/archives")
+            .join(format!("{name}.json"))
+    }
+
     /// Sets the save_on_drop flag.
     /// If set to true, the repository will save all changes to disk when dropped.
     /// If set to false, the repository will not save changes when dropped.
@@ -166,26 +998,213 @@ impl Repository {
         self
     }
 
-    /// Lists all archives in the repository.
-    /// Returns a vector of archive names without the ".ddup" extension.
-    /// Example: "my_archive" instead of "my_archive.ddup".
-    /// The archives are stored in the ".ddup-bak/archives" directory.
-    pub fn list_archives(&self) -> std::io::Result<Vec<String>> {
-        let mut archives = Vec::new();
-        let archive_dir = self.directory.join(".ddup-bak/archives");
+    /// Sets how long operations wait for the repository lock, see `lock_timeout`.
+    #[inline]
+    pub const fn set_lock_timeout(&mut self, lock_timeout: Option<Duration>) -> &mut Self {
+        self.lock_timeout = lock_timeout;
 
-        for entry in std::fs::read_dir(archive_dir)?.flatten() {
-            if let Some(name) = entry.file_name().to_str()
-                && let Some(stripped) = name.strip_suffix(".ddup")
-            {
-                archives.push(stripped.to_string());
-            }
+        self
+    }
+
+    /// Sets the size of the buffer file contents are copied through, see `io_buffer_size`.
+    #[inline]
+    pub const fn set_io_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.io_buffer_size = size;
+
+        self
+    }
+
+    /// Switches the repository lock to `backend` and stores it in the repository, so
+    /// `open` uses it from then on. Every process using the repository has to use the
+    /// same backend, so it is set right after creating the repository, before anything
+    /// was locked.
+    pub fn set_lock_backend(&mut self, backend: LockBackend) -> std::io::Result<&mut Self> {
+        if self.chunk_index.lock.backend() != backend {
+            self.use_lock_backend(backend)?;
+
+            atomicwrites::AtomicFile::new(self.lock_backend_path(), atomicwrites::AllowOverwrite)
+                .write(|f| f.write_all(backend.to_string().as_bytes()))
+                .map_err(std::io::Error::from)?;
         }
 
-        Ok(archives)
+        Ok(self)
     }
 
-    /// Gets an archive by name.
+    /// The lock backend of the repository, see `set_lock_backend`.
+    #[inline]
+    pub fn lock_backend(&self) -> LockBackend {
+        self.chunk_index.lock.backend()
+    }
+
+    /// Where the backend chosen with `set_lock_backend` is kept, next to the lock.
+    #[inline]
+    fn lock_backend_path(&self) -> PathBuf {
+        self.chunk_index.directory.join("index.lock.backend")
+    }
+
+    fn use_lock_backend(&mut self, backend: LockBackend) -> std::io::Result<()> {
+        self.chunk_index.lock = Arc::new(lock::RwLock::with_backend(
+            self.chunk_index.directory.join("index.lock"),
+            backend,
+        )?);
+
+        Ok(())
+    }
+
+    /// Switches to the backend stored by `set_lock_backend`, repositories without one
+    /// keep the default.
+    fn with_stored_lock_backend(mut self) -> std::io::Result<Self> {
+        let backend = match std::fs::read_to_string(self.lock_backend_path()) {
+            Ok(backend) => backend.trim().parse::<LockBackend>().map_err(|err| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(self),
+            Err(err) => return Err(err),
+        };
+
+        if self.chunk_index.lock.backend() != backend {
+            self.use_lock_backend(backend)?;
+        }
+
+        Ok(self)
+    }
+
+    /// Sets the callback told about operations waiting longer than `threshold` for the
+    /// repository lock, see `RwLock::set_wait_callback`. It belongs to the lock, which
+    /// `set_lock_backend` replaces, so it is set after the backend.
+    pub fn set_lock_wait_callback(
+        &mut self,
+        callback: LockWaitCallback,
+        threshold: Duration,
+    ) -> &mut Self {
+        self.chunk_index.lock.set_wait_callback(callback, threshold);
+
+        self
+    }
+
+    fn read_lock(&self, mode: LockMode) -> std::io::Result<ReadGuard> {
+        self.chunk_index
+            .lock
+            .read_lock_timeout(mode, self.lock_timeout.unwrap_or(Duration::MAX))
+    }
+
+    fn write_lock(&self, mode: LockMode) -> std::io::Result<WriteGuard> {
+        self.chunk_index
+            .lock
+            .write_lock_timeout(mode, self.lock_timeout.unwrap_or(Duration::MAX))
+    }
+
+    /// Lists all archives in the repository.
+    /// Returns a vector of archive names without the ".ddup" extension.
+    /// Example: "my_archive" instead of "my_archive.ddup".
+    /// The archives are stored in the ".ddup-bak/archives" directory.
+    /// Where `create_archive` keeps the finished files of an archive it could not complete.
+    #[inline]
+    pub fn partial_archive_path(&self, name: &str) -> PathBuf {
+        self.directory
+            .join(".ddup-bak/archives")
+            .join(format!("{name}.partial"))
+    }
+
+    /// Opens the checkpoint of an interrupted `create_archive`, if there is one.
+    pub fn partial_archive(&self, name: &str) -> std::io::Result<Option<Archive>> {
+        match Archive::open(self.partial_archive_path(name)) {
+            Ok(archive) => Ok(Some(archive)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Discards the checkpoint of an interrupted `create_archive` and dereferences its chunks.
+    /// Returns whether there was a checkpoint.
+    pub fn discard_partial_archive(
+        &self,
+        name: &str,
+        progress: DeletionProgressCallback,
+    ) -> std::io::Result<bool> {
+        let mut w = self.write_lock(LockMode::Destructive)?;
+
+        let Some(archive) = self.partial_archive(name)? else {
+            w.unlock()?;
+
+            return Ok(false);
+        };
+
+        for entry in archive.into_entries()? {
+            self.recursive_delete_archive(entry, progress.clone())?;
+        }
+
+        std::fs::remove_file(self.partial_archive_path(name))?;
+
+        w.unlock()?;
+
+        log::info!("Discarded the checkpoint of archive {name}");
+
+        Ok(true)
+    }
+
+    /// Names of the archives with a checkpoint of an interrupted `create_archive`.
+    fn list_partial_archives(&self) -> std::io::Result<Vec<String>> {
+        let mut archives = Vec::new();
+        let archive_dir = self.directory.join(".ddup-bak/archives");
+
+        for entry in std::fs::read_dir(archive_dir)?.flatten() {
+            if let Some(name) = entry.file_name().to_str()
+                && let Some(stripped) = name.strip_suffix(".partial")
+            {
+                archives.push(stripped.to_string());
+            }
+        }
+
+        Ok(archives)
+    }
+
+    pub fn list_archives(&self) -> std::io::Result<Vec<String>> {
+        let mut archives = Vec::new();
+        let archive_dir = self.directory.join(".ddup-bak/archives");
+
+        for entry in std::fs::read_dir(archive_dir)?.flatten() {
+            if let Some(name) = entry.file_name().to_str()
+                && let Some(stripped) = name.strip_suffix(".ddup")
+            {
+                archives.push(stripped.to_string());
+            }
+        }
+
+        Ok(archives)
+    }
+
+    /// Lists all archives with their metadata.
+    /// Only the entry headers of each archive are read, no chunks are touched.
+    pub fn list_archives_detailed(&self) -> std::io::Result<Vec<ArchiveInfo>> {
+        let mut archives = Vec::new();
+
+        for name in self.list_archives()? {
+            let metadata = std::fs::metadata(self.archive_path(&name))?;
+            let archive = self.get_archive(&name)?;
+
+            archives.push(ArchiveInfo {
+                created: metadata.modified()?,
+                size: metadata.len(),
+                entries: archive
+                    .entries()
+                    .iter()
+                    .map(Self::recursive_count)
+                    .sum::<std::io::Result<u64>>()?,
+                logical_bytes: archive
+                    .entries()
+                    .iter()
+                    .map(Self::recursive_logical_size)
+                    .sum::<std::io::Result<u64>>()?,
+                metadata: self.archive_metadata(&name)?,
+                name,
+            });
+        }
+
+        Ok(archives)
+    }
+
+    /// Gets an archive by name.
     /// Do not use this method to extract data, the data is chunked and compressed.
     /// Use `restore_archive` instead.
     pub fn get_archive(&self, name: &str) -> std::io::Result<Archive> {
@@ -194,18 +1213,725 @@ impl Repository {
         Archive::open(&archive_path)
     }
 
-    pub fn clean(&self, progress: DeletionProgressCallback) -> std::io::Result<()> {
-        let mut w = self.chunk_index.lock.write_lock(LockMode::Destructive)?;
-        self.chunk_index.clean(progress)?;
+    /// Opens an archive like `get_archive`, but decodes the children of directories
+    /// only when they are accessed, see `Archive::open_lazy`.
+    pub fn get_archive_lazy(&self, name: &str) -> std::io::Result<Archive> {
+        Archive::open_lazy(self.archive_path(name))
+    }
+
+    /// Returns when an archive was created, this is the modification time of the archive file.
+    pub fn archive_created(&self, name: &str) -> std::io::Result<std::time::SystemTime> {
+        std::fs::metadata(self.archive_path(name))?.modified()
+    }
+
+    fn recursive_count(entry: &Entry) -> std::io::Result<u64> {
+        match entry {
+            Entry::Directory(dir_entry) => Ok(1 + dir_entry
+                .entries()?
+                .iter()
+                .map(Self::recursive_count)
+                .sum::<std::io::Result<u64>>()?),
+            _ => Ok(1),
+        }
+    }
+
+    fn recursive_logical_size(entry: &Entry) -> std::io::Result<u64> {
+        match entry {
+            Entry::File(file_entry) => Ok(file_entry.size_real),
+            Entry::Directory(dir_entry) => dir_entry
+                .entries()?
+                .iter()
+                .map(Self::recursive_logical_size)
+                .sum(),
+            Entry::Symlink(_) => Ok(0),
+        }
+    }
+
+    /// Computes repository wide numbers.
+    /// Archives are opened one at a time and only their entry headers are read.
+    pub fn stats(&self) -> std::io::Result<RepositoryStats> {
+        let mut r = self.read_lock(LockMode::NonDestructive)?;
+
+        let mut stats = RepositoryStats {
+            deleted_chunk_ids: self.chunk_index.deleted_chunk_ids().len() as u64,
+            ..Default::default()
+        };
+
+        for name in self.list_archives()? {
+            let archive = self.get_archive(&name)?;
+
+            stats.archives += 1;
+            stats.logical_bytes += archive
+                .entries()
+                .iter()
+                .map(Self::recursive_logical_size)
+                .sum::<std::io::Result<u64>>()?;
+        }
+
+        for (_, chunk, _) in self.chunk_index.chunk_entries() {
+            stats.unique_chunks += 1;
+            match self.chunk_index.storage.chunk_size(&chunk) {
+                Ok(size) => stats.stored_bytes += size,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => stats.missing_chunks += 1,
+                Err(err) => return Err(err),
+            }
+        }
+
+        r.unlock()?;
+
+        Ok(stats)
+    }
+
+    /// Computes the numbers of a single archive, this reads the chunk ID lists of all its files.
+    pub fn archive_stats(&self, name: &str) -> std::io::Result<ArchiveStats> {
+        let mut r = self.read_lock(LockMode::NonDestructive)?;
+
+        let mut files = Vec::new();
+        for entry in self.get_archive(name)?.into_entries()? {
+            Self::recursive_collect_files(entry, Path::new(""), &mut files)?;
+        }
+
+        let mut stats = ArchiveStats {
+            name: name.to_string(),
+            created: self.archive_created(name)?,
+            files: files.len() as u64,
+            chunks: 0,
+            logical_bytes: 0,
+            stored_bytes: 0,
+            exclusive_bytes: 0,
+        };
+
+        let mut references: HashMap<u64, u64> = HashMap::new();
+        for (_, mut file_entry) in files {
+            stats.logical_bytes += file_entry.size_real;
+
+            let mut ids = ChunkIdDecoder::new(&file_entry);
+            while let Some(chunk_id) = ids.next_id(&mut file_entry)? {
+                *references.entry(chunk_id).or_default() += 1;
+            }
+        }
+
+        stats.chunks = references.len() as u64;
+        for (chunk_id, count) in references {
+            let Some(chunk) = self.chunk_index.get_chunk_hash(chunk_id) else {
+                continue;
+            };
+
+            let size = self.chunk_index.storage.chunk_size(&chunk)?;
+            stats.stored_bytes += size;
+            if self.chunk_index.references(&chunk) <= count {
+                stats.exclusive_bytes += size;
+            }
+        }
+
+        r.unlock()?;
+
+        Ok(stats)
+    }
+
+    /// Decides which archives a prune with the given policy would keep, without modifying anything.
+    /// The decisions are sorted from newest to oldest archive.
+    pub fn plan_prune(&self, policy: &RetentionPolicy) -> std::io::Result<Vec<PruneDecision>> {
+        use chrono::{DateTime, Datelike, Local};
+
+        if policy.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Retention policy would not keep any archive",
+            ));
+        }
+
+        let mut decisions = Vec::new();
+        for name in self.list_archives()? {
+            decisions.push(PruneDecision {
+                created: self.archive_created(&name)?,
+                name,
+                reasons: Vec::new(),
+            });
+        }
+
+        decisions.sort_by(|a, b| b.created.cmp(&a.created).then_with(|| a.name.cmp(&b.name)));
+
+        let mut days = Vec::new();
+        let mut weeks = Vec::new();
+        let mut months = Vec::new();
+
+        for (i, decision) in decisions.iter_mut().enumerate() {
+            let created: DateTime<Local> = decision.created.into();
+
+            if i < policy.keep_last {
+                decision.reasons.push(format!("last {}", i + 1));
+            }
+
+            let day = created.date_naive();
+            if days.len() < policy.keep_daily && !days.contains(&day) {
+                days.push(day);
+                decision
+                    .reasons
+                    .push(format!("daily slot {}", day.format("%Y-%m-%d")));
+            }
+
+            let week = created.iso_week();
+            if weeks.len() < policy.keep_weekly && !weeks.contains(&week) {
+                weeks.push(week);
+                decision
+                    .reasons
+                    .push(format!("weekly slot {}-W{:02}", week.year(), week.week()));
+            }
+
+            let month = (created.year(), created.month());
+            if months.len() < policy.keep_monthly && !months.contains(&month) {
+                months.push(month);
+                decision
+                    .reasons
+                    .push(format!("monthly slot {}-{:02}", month.0, month.1));
+            }
+        }
+
+        Ok(decisions)
+    }
+
+    fn recursive_collect_files(
+        entry: Entry,
+        parent: &Path,
+        files: &mut Vec<(PathBuf, Box<crate::archive::entries::FileEntry>)>,
+    ) -> std::io::Result<()> {
+        let path = parent.join(entry.name());
+
+        match entry {
+            Entry::File(file_entry) => files.push((path, file_entry)),
+            Entry::Directory(dir_entry) => {
+                for sub_entry in dir_entry.into_entries()? {
+                    Self::recursive_collect_files(sub_entry, &path, files)?;
+                }
+            }
+            Entry::Symlink(_) => {}
+        }
+
+        Ok(())
+    }
+
+    fn verify_file(
+        chunk_index: &ChunkIndex,
+        path: PathBuf,
+        mut file_entry: Box<crate::archive::entries::FileEntry>,
+        level: VerifyLevel,
+        report: &Mutex<VerifyReport>,
+    ) -> bool {
+        let mut problems = Vec::new();
+        let mut chunks_checked = 0;
+        let mut bytes_checked = 0;
+
+        let mut ids = ChunkIdDecoder::new(&file_entry);
+        loop {
+            let chunk_id = match ids.next_id(&mut file_entry) {
+                Ok(Some(chunk_id)) => chunk_id,
+                Ok(None) => break,
+                Err(err) => {
+                    problems.push(VerifyProblem {
+                        path: path.clone(),
+                        chunk_id: None,
+                        kind: VerifyProblemKind::File,
+                        reason: format!("unreadable chunk list: {err}"),
+                    });
+                    break;
+                }
+            };
+
+            chunks_checked += 1;
+
+            let result = match level {
+                VerifyLevel::Quick => match chunk_index.get_chunk_hash(chunk_id) {
+                    Some(chunk) => chunk_index.storage.chunk_size(&chunk).map(|_| 0),
+                    None => Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        "missing from the index",
+                    )),
+                },
+                VerifyLevel::Full => chunk_index.verify_chunk_id(chunk_id),
+            };
+
+            match result {
+                Ok(size) => bytes_checked += size,
+                Err(err) => problems.push(VerifyProblem {
+                    path: path.clone(),
+                    chunk_id: Some(chunk_id),
+                    kind: if err.kind() == std::io::ErrorKind::NotFound {
+                        VerifyProblemKind::MissingChunk
+                    } else {
+                        VerifyProblemKind::CorruptChunk
+                    },
+                    reason: err.to_string(),
+                }),
+            }
+        }
+
+        if level == VerifyLevel::Full
+            && problems.is_empty()
+            && bytes_checked != file_entry.size_real
+        {
+            problems.push(VerifyProblem {
+                path,
+                chunk_id: None,
+                kind: VerifyProblemKind::File,
+                reason: format!(
+                    "size mismatch, expected {} bytes but chunks contain {bytes_checked}",
+                    file_entry.size_real
+                ),
+            });
+        }
+
+        let ok = problems.is_empty();
+
+        let mut report = report.lock();
+        report.files_checked += 1;
+        report.chunks_checked += chunks_checked;
+        report.bytes_checked += bytes_checked;
+        report.problems.extend(problems);
+
+        ok
+    }
+
+    /// Verifies that all chunks referenced by an archive are present, with `VerifyLevel::Full`
+    /// their content is also read and checked against the stored hashes.
+    /// The progress callback is called with the archive path of each file after it was checked.
+    pub fn verify_archive(
+        &self,
+        name: &str,
+        level: VerifyLevel,
+        progress: ProgressCallback,
+        threads: usize,
+    ) -> std::io::Result<VerifyReport> {
+        self.verify_archive_with_results(
+            name,
+            level,
+            progress.map(|f| {
+                Arc::new(move |path: &Path, _| f(path)) as Arc<dyn Fn(&Path, bool) + Send + Sync>
+            }),
+            threads,
+        )
+    }
+
+    /// Like `verify_archive`, the progress callback also receives whether the file was
+    /// found without problems.
+    pub fn verify_archive_with_results(
+        &self,
+        name: &str,
+        level: VerifyLevel,
+        progress: VerifyProgressCallback,
+        threads: usize,
+    ) -> std::io::Result<VerifyReport> {
+        if !self.list_archives()?.iter().any(|n| n == name) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Archive {name} not found"),
+            ));
+        }
+
+        let mut r = self.read_lock(LockMode::NonDestructive)?;
+
+        let mut files = Vec::new();
+        for entry in self.get_archive(name)?.into_entries()? {
+            Self::recursive_collect_files(entry, Path::new(""), &mut files)?;
+        }
+
+        let worker_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(resolve_threads(threads))
+            .build()
+            .map_err(std::io::Error::other)?;
+        let report = Mutex::new(VerifyReport::default());
+
+        worker_pool.in_place_scope(|scope| {
+            for (path, file_entry) in files {
+                let report = &report;
+                let progress = progress.clone();
+
+                scope.spawn(move |_| {
+                    let ok = Self::verify_file(
+                        &self.chunk_index,
+                        path.clone(),
+                        file_entry,
+                        level,
+                        report,
+                    );
+
+                    if let Some(f) = &progress {
+                        f(&path, ok);
+                    }
+                });
+            }
+        });
+
+        r.unlock()?;
+
+        let mut report = report.into_inner();
+        report.problems.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(report)
+    }
+
+    fn diff_chunk_ids(
+        file_a: &crate::archive::entries::FileEntry,
+        file_b: &crate::archive::entries::FileEntry,
+    ) -> std::io::Result<bool> {
+        let mut file_a = file_a.clone();
+        let mut file_b = file_b.clone();
+
+        let mut ids_a = ChunkIdDecoder::new(&file_a);
+        let mut ids_b = ChunkIdDecoder::new(&file_b);
+
+        loop {
+            let id_a = ids_a.next_id(&mut file_a)?;
+            let id_b = ids_b.next_id(&mut file_b)?;
+
+            if id_a != id_b {
+                return Ok(true);
+            }
+
+            if id_a.is_none() {
+                return Ok(false);
+            }
+        }
+    }
+
+    fn diff_subtree(
+        path: &Path,
+        entry: &Entry,
+        status: DiffStatus,
+        callback: &mut impl FnMut(DiffEntry) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let path = path.join(entry.name());
+
+        callback(DiffEntry {
+            path: &path,
+            status,
+            changes: DiffChanges::default(),
+            old: (status == DiffStatus::Deleted).then_some(entry),
+            new: (status == DiffStatus::Added).then_some(entry),
+        })?;
+
+        if let Entry::Directory(dir_entry) = entry {
+            let mut entries = dir_entry.entries()?.iter().collect::<Vec<_>>();
+            entries.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+
+            for entry in entries {
+                Self::diff_subtree(&path, entry, status, callback)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn diff_entries(
+        path: &Path,
+        entries_a: &[Entry],
+        entries_b: &[Entry],
+        content: bool,
+        callback: &mut impl FnMut(DiffEntry) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let mut entries_a = entries_a.iter().collect::<Vec<_>>();
+        let mut entries_b = entries_b.iter().collect::<Vec<_>>();
+        entries_a.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+        entries_b.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+
+        let mut entries_a = entries_a.into_iter().peekable();
+        let mut entries_b = entries_b.into_iter().peekable();
+
+        loop {
+            let (entry_a, entry_b) = match (entries_a.peek(), entries_b.peek()) {
+                (None, None) => break,
+                (Some(_), None) => (entries_a.next(), None),
+                (None, Some(_)) => (None, entries_b.next()),
+                (Some(a), Some(b)) => match a.name().cmp(b.name()) {
+                    std::cmp::Ordering::Less => (entries_a.next(), None),
+                    std::cmp::Ordering::Greater => (None, entries_b.next()),
+                    std::cmp::Ordering::Equal => (entries_a.next(), entries_b.next()),
+                },
+            };
+
+            let (entry_a, entry_b) = match (entry_a, entry_b) {
+                (Some(entry_a), None) => {
+                    Self::diff_subtree(path, entry_a, DiffStatus::Deleted, callback)?;
+                    continue;
+                }
+                (None, Some(entry_b)) => {
+                    Self::diff_subtree(path, entry_b, DiffStatus::Added, callback)?;
+                    continue;
+                }
+                (Some(entry_a), Some(entry_b)) => (entry_a, entry_b),
+                (None, None) => unreachable!(),
+            };
+
+            let mut changes = DiffChanges {
+                mode: entry_a.mode() != entry_b.mode(),
+                mtime: entry_a.mtime() != entry_b.mtime(),
+                owner: entry_a.owner() != entry_b.owner(),
+                ..Default::default()
+            };
+
+            match (entry_a, entry_b) {
+                (Entry::File(file_a), Entry::File(file_b)) => {
+                    changes.size = file_a.size_real != file_b.size_real;
+                    changes.content = content && Self::diff_chunk_ids(file_a, file_b)?;
+                }
+                (Entry::Symlink(link_a), Entry::Symlink(link_b)) => {
+                    changes.target = link_a.target != link_b.target;
+                }
+                (Entry::Directory(_), Entry::Directory(_)) => {}
+                _ => {
+                    // the entry changed its type, report it as removed and added again
+                    Self::diff_subtree(path, entry_a, DiffStatus::Deleted, callback)?;
+                    Self::diff_subtree(path, entry_b, DiffStatus::Added, callback)?;
+                    continue;
+                }
+            }
+
+            let entry_path = path.join(entry_a.name());
+            if changes.any() {
+                callback(DiffEntry {
+                    path: &entry_path,
+                    status: DiffStatus::Modified,
+                    changes,
+                    old: Some(entry_a),
+                    new: Some(entry_b),
+                })?;
+            }
+
+            if let (Entry::Directory(dir_a), Entry::Directory(dir_b)) = (entry_a, entry_b) {
+                Self::diff_entries(
+                    &entry_path,
+                    dir_a.entries()?,
+                    dir_b.entries()?,
+                    content,
+                    callback,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compares two archives, calling the callback for every added, modified or deleted entry.
+    /// Entries are reported while both trees are walked, ordered by path.
+    /// With `content` set the chunk ID lists of files are compared as well, which detects
+    /// content changes that kept the size and modification time.
+    pub fn diff_archives(
+        &self,
+        name_a: &str,
+        name_b: &str,
+        content: bool,
+        mut callback: impl FnMut(DiffEntry) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let archives = self.list_archives()?;
+        for name in [name_a, name_b] {
+            if !archives.iter().any(|n| n == name) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Archive {name} not found"),
+                ));
+            }
+        }
+
+        let archive_a = self.get_archive(name_a)?;
+        let archive_b = self.get_archive(name_b)?;
+
+        Self::diff_entries(
+            Path::new(""),
+            archive_a.entries(),
+            archive_b.entries(),
+            content,
+            &mut callback,
+        )
+    }
+
+    /// Deletes every archive not kept by the retention policy.
+    /// Returns the decisions for all archives, see `plan_prune`.
+    pub fn prune(
+        &self,
+        policy: &RetentionPolicy,
+        progress: DeletionProgressCallback,
+    ) -> std::io::Result<Vec<PruneDecision>> {
+        self.with_write_lock(LockMode::Destructive, |repository| {
+            let decisions = repository.plan_prune(policy)?;
+            for decision in decisions.iter().filter(|d| !d.keep()) {
+                repository.delete_archive(&decision.name, progress.clone())?;
+            }
+
+            Ok(decisions)
+        })
+    }
+
+    /// Deletes all unreferenced chunks from the repository.
+    /// With `dry_run` set nothing is deleted, the returned plan only reports what would be freed.
+    /// The progress callback receives the chunk ID and its stored size in bytes.
+    pub fn clean(
+        &self,
+        dry_run: bool,
+        progress: CleanProgressCallback,
+    ) -> std::io::Result<CleanPlan> {
+        if dry_run {
+            let mut r = self.read_lock(LockMode::NonDestructive)?;
+            let plan = self.chunk_index.clean_dry_run(progress)?;
+
+            r.unlock()?;
+
+            return Ok(plan);
+        }
+
+        let mut w = self.write_lock(LockMode::Destructive)?;
+        let plan = self.chunk_index.clean(progress)?;
 
         w.unlock()?;
 
+        log::info!("Deleted {} unreferenced chunks", plan.chunk_count);
+
+        Ok(plan)
+    }
+
+    fn recursive_count_references(
+        entry: Entry,
+        references: &mut HashMap<u64, u64>,
+    ) -> std::io::Result<()> {
+        match entry {
+            Entry::File(mut file_entry) => {
+                let mut ids = ChunkIdDecoder::new(&file_entry);
+
+                while let Some(chunk_id) = ids.next_id(&mut file_entry)? {
+                    *references.entry(chunk_id).or_default() += 1;
+                }
+            }
+            Entry::Directory(dir_entry) => {
+                for sub_entry in dir_entry.into_entries()? {
+                    Self::recursive_count_references(sub_entry, references)?;
+                }
+            }
+            Entry::Symlink(_) => {}
+        }
+
         Ok(())
     }
 
+    /// Audits the chunk index against the references of all archives.
+    /// The progress callback is called with the path of each archive before it is scanned.
+    ///
+    /// With `repair` set the index reference counts are rewritten to the counts found in the archives
+    /// and live IDs are removed from the reuse queue. Chunks missing from the index or storage cannot be repaired.
+    pub fn check(&self, progress: ProgressCallback, repair: bool) -> std::io::Result<CheckReport> {
+        let (mut w, mut r) = if repair {
+            (Some(self.write_lock(LockMode::Destructive)?), None)
+        } else {
+            (None, Some(self.read_lock(LockMode::NonDestructive)?))
+        };
+
+        let mut report = CheckReport::default();
+        let mut references = HashMap::new();
+
+        for name in self.list_archives()? {
+            let archive_path = self.archive_path(&name);
+            if let Some(f) = &progress {
+                f(&archive_path);
+            }
+
+            for entry in Archive::open(&archive_path)?.into_entries()? {
+                Self::recursive_count_references(entry, &mut references)?;
+            }
+
+            report.archives_checked += 1;
+        }
+
+        // checkpoints of interrupted creates hold references until they are resumed or discarded
+        for name in self.list_partial_archives()? {
+            for entry in Archive::open(self.partial_archive_path(&name))?.into_entries()? {
+                Self::recursive_count_references(entry, &mut references)?;
+            }
+        }
+
+        let index: HashMap<u64, (crate::chunks::ChunkHash, u64)> = self
+            .chunk_index
+            .chunk_entries()
+            .into_iter()
+            .map(|(id, chunk, count)| (id, (chunk, count)))
+            .collect();
+
+        for (&chunk_id, &archive_count) in references.iter() {
+            let Some(&(chunk, index_count)) = index.get(&chunk_id) else {
+                report.missing_from_index.push(chunk_id);
+                continue;
+            };
+
+            if index_count != archive_count {
+                report.count_mismatches.push(ReferenceMismatch {
+                    chunk_id,
+                    index_count,
+                    archive_count,
+                });
+            }
+
+            match self.chunk_index.storage.chunk_size(&chunk) {
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    report.missing_from_storage.push(chunk_id);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        for (&chunk_id, &(_, index_count)) in index.iter() {
+            if index_count > 0 && !references.contains_key(&chunk_id) {
+                report.unreferenced.push(chunk_id);
+            }
+        }
+
+        for chunk_id in self.chunk_index.deleted_chunk_ids() {
+            if index.contains_key(&chunk_id) {
+                report.deleted_overlap.push(chunk_id);
+            }
+        }
+
+        report.count_mismatches.sort_by_key(|m| m.chunk_id);
+        report.missing_from_index.sort_unstable();
+        report.missing_from_storage.sort_unstable();
+        report.unreferenced.sort_unstable();
+        report.deleted_overlap.sort_unstable();
+
+        if repair {
+            for mismatch in report.count_mismatches.iter() {
+                report.repaired |= self
+                    .chunk_index
+                    .set_references(mismatch.chunk_id, mismatch.archive_count);
+            }
+
+            for &chunk_id in report.unreferenced.iter() {
+                report.repaired |= self.chunk_index.set_references(chunk_id, 0);
+            }
+
+            if !report.deleted_overlap.is_empty() {
+                self.chunk_index
+                    .forget_deleted_chunk_ids(&report.deleted_overlap);
+                report.repaired = true;
+            }
+        }
+
+        if let Some(w) = w.as_mut() {
+            w.unlock()?;
+        }
+        if let Some(r) = r.as_mut() {
+            r.unlock()?;
+        }
+
+        Ok(report)
+    }
+
+    /// Opens the content of a file entry for reading, large files are read ahead in
+    /// parallel on the current rayon pool, see `EntryReader::parallel`.
     pub fn entry_reader(&self, entry: Entry) -> std::io::Result<EntryReader> {
         match entry {
-            Entry::File(file_entry) => Ok(EntryReader::new(file_entry, self.chunk_index.clone())),
+            Entry::File(file_entry) => {
+                let mut reader = EntryReader::parallel(file_entry, self.chunk_index.clone());
+                reader.set_copy_buffer_size(self.io_buffer_size);
+
+                Ok(reader)
+            }
             _ => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "Entry is not a file",
@@ -217,24 +1943,30 @@ impl Repository {
     pub fn archive_path_parent<'a>(
         archive: &'a mut Archive,
         entry: &Path,
-    ) -> Option<&'a mut Box<crate::archive::entries::DirectoryEntry>> {
-        archive
-            .find_archive_entry_mut(entry.parent()?)
+    ) -> std::io::Result<Option<&'a mut Box<crate::archive::entries::DirectoryEntry>>> {
+        let Some(parent) = entry.parent() else {
+            return Ok(None);
+        };
+
+        Ok(archive
+            .find_archive_entry_mut(parent)?
             .and_then(|e| match e {
                 Entry::Directory(dir) => Some(dir),
                 _ => None,
-            })
+            }))
     }
 
     #[allow(clippy::too_many_arguments)]
     fn recursive_create_archive(
-        archive: Arc<Mutex<Option<Archive>>>,
+        updates: Sender<TreeUpdate>,
         chunk_index: &ChunkIndex,
         entry: ignore::DirEntry,
         metadata: std::fs::Metadata,
         root_path: &Path,
         progress_chunking: ProgressCallback,
+        progress_events: ProgressEventCallback,
         compression_callback: CompressionFormatCallback,
+        resumed: Option<&Mutex<HashMap<PathBuf, Box<FileEntry>>>>,
         scope: &rayon::Scope,
         error: Arc<RwLock<Option<std::io::Error>>>,
     ) -> std::io::Result<()> {
@@ -244,11 +1976,7 @@ impl Repository {
                 "Path is not a subpath of the root directory",
             )
         })?;
-        let Some(file_name) = path.file_name() else {
-            return Ok(());
-        };
-
-        if error.read().is_some() {
+        if path.file_name().is_none() || error.read().is_some() {
             return Ok(());
         }
 
@@ -262,81 +1990,325 @@ impl Repository {
                 .map(|f| f(path, &metadata))
                 .unwrap_or(CompressionFormat::Deflate);
 
-            let chunks =
-                chunk_index.chunk_file(&entry.path().to_path_buf(), compression, Some(scope))?;
+            let resumed = resumed.and_then(|resumed| {
+                let mut resumed = resumed.lock();
+                let unchanged = resumed.get(path).is_some_and(|file| {
+                    file.size_real == metadata.len()
+                        && metadata.modified().is_ok_and(|mtime| {
+                            let secs = |time: std::time::SystemTime| {
+                                time.duration_since(std::time::SystemTime::UNIX_EPOCH)
+                                    .unwrap_or_default()
+                                    .as_secs()
+                            };
+
+                            secs(mtime) == secs(file.mtime)
+                        })
+                });
 
-            let mut chunk_content = Vec::new();
-            for id in chunks {
-                chunk_content.extend_from_slice(&crate::varint::encode_u64(id));
-            }
+                if unchanged {
+                    resumed.remove(path)
+                } else {
+                    None
+                }
+            });
+
+            let chunks = match resumed {
+                // the checkpoint already holds the references of these chunks
+                Some(mut file) => {
+                    let mut chunks = Vec::new();
+                    let mut ids = ChunkIdDecoder::new(&file);
+                    while let Some(chunk_id) = ids.next_id(&mut file)? {
+                        chunks.push(chunk_id);
+                    }
 
-            let mut archive_lock = archive.lock();
-            let Some(archive) = archive_lock.as_mut() else {
-                return Err(std::io::Error::other("Archive has already been finalized"));
+                    if let Some(f) = &progress_events {
+                        f(ProgressEvent::BytesProcessed(metadata.len()));
+                    }
+
+                    chunks
+                }
+                None => chunk_index.chunk_file(
+                    &entry.path().to_path_buf(),
+                    compression,
+                    Some(scope),
+                    progress_events.clone().map(|f| {
+                        Arc::new(move |bytes| f(ProgressEvent::BytesProcessed(bytes)))
+                            as Arc<dyn Fn(u64) + Send + Sync>
+                    }),
+                )?,
             };
 
-            let file_entry = archive.write_file_entry(
-                Cursor::new(chunk_content),
-                Some(metadata.len()),
-                file_name.to_string_lossy(),
-                metadata.permissions().into(),
-                metadata.modified().unwrap_or(std::time::SystemTime::now()),
-                {
-                    #[cfg(unix)]
-                    {
-                        use std::os::unix::fs::MetadataExt;
-                        (metadata.uid(), metadata.gid())
-                    }
-                    #[cfg(windows)]
-                    {
-                        (0, 0)
-                    }
-                },
+            // the receiver only goes away once the archive failed, which is reported there
+            let _ = updates.send(TreeUpdate::File {
+                path: path.to_path_buf(),
+                source: entry.into_path(),
+                metadata: Box::new(metadata),
                 compression,
-            )?;
+                chunk_ids: crate::chunks::ids::encode(&chunks),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Owns the archive while it is created, writing the payload of files and inserting
+    /// every entry into the tree in the order they are received. Stops at the first
+    /// error, the archive is given back either way.
+    fn apply_tree_updates(
+        mut archive: Archive,
+        updates: Receiver<TreeUpdate>,
+        root_path: &Path,
+        progress_events: &ProgressEventCallback,
+        error: &RwLock<Option<std::io::Error>>,
+    ) -> Archive {
+        for update in updates {
+            if let Err(err) =
+                Self::apply_tree_update(&mut archive, update, root_path, progress_events)
+            {
+                let mut error = error.write();
+                if error.is_none() {
+                    *error = Some(err);
+                }
+
+                break;
+            }
+        }
+
+        archive
+    }
+
+    fn apply_tree_update(
+        archive: &mut Archive,
+        update: TreeUpdate,
+        root_path: &Path,
+        progress_events: &ProgressEventCallback,
+    ) -> std::io::Result<()> {
+        match update {
+            TreeUpdate::Entry { path, entry } => {
+                Self::insert_archive_entry(archive, root_path, &path, entry)
+            }
+            TreeUpdate::File {
+                path,
+                source,
+                metadata,
+                compression,
+                chunk_ids,
+            } => {
+                let Some(file_name) = path.file_name() else {
+                    return Ok(());
+                };
+
+                let mut file_entry = archive.write_file_entry(
+                    Cursor::new(chunk_ids),
+                    Some(metadata.len()),
+                    file_name.to_string_lossy(),
+                    metadata.permissions().into(),
+                    metadata.modified().unwrap_or(std::time::SystemTime::now()),
+                    metadata_owner(&metadata),
+                    compression,
+                )?;
+                file_entry.delta_chunk_ids = true;
+
+                Self::insert_archive_entry(archive, root_path, &path, Entry::File(file_entry))?;
+
+                if let Some(f) = progress_events {
+                    f(ProgressEvent::FileDone {
+                        path: &source,
+                        bytes: metadata.len(),
+                    });
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Inserts `entry` into the directory its `path` belongs to. Parents the walk did not
+    /// yield are added with the metadata of the directory they were found in on disk.
+    fn insert_archive_entry(
+        archive: &mut Archive,
+        root_path: &Path,
+        path: &Path,
+        entry: Entry,
+    ) -> std::io::Result<()> {
+        let Some(parent_path) = path.parent().filter(|p| !p.as_os_str().is_empty()) else {
+            archive.entries.push(entry);
+            return Ok(());
+        };
+
+        if let Some(parent) = Self::archive_path_parent(archive, path)? {
+            parent.entries_mut()?.push(entry);
+            return Ok(());
+        }
+
+        if archive.find_archive_entry(parent_path)?.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Parent entry {} is not a directory", parent_path.display()),
+            ));
+        }
+
+        let metadata = std::fs::symlink_metadata(root_path.join(parent_path))?;
+        Self::insert_archive_entry(
+            archive,
+            root_path,
+            parent_path,
+            Self::directory_entry(parent_path, &metadata),
+        )?;
+
+        match Self::archive_path_parent(archive, path)? {
+            Some(parent) => parent.entries_mut()?.push(entry),
+            None => archive.entries.push(entry),
+        }
+
+        Ok(())
+    }
+
+    fn directory_entry(path: &Path, metadata: &std::fs::Metadata) -> Entry {
+        Entry::Directory(Box::new(DirectoryEntry::new(
+            path.file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default(),
+            metadata.permissions().into(),
+            metadata_owner(metadata),
+            metadata.modified().unwrap_or(std::time::SystemTime::now()),
+            Vec::new(),
+        )))
+    }
+
+    /// Checks that the walker was started at `root`, entry paths are stored relative to it.
+    fn check_source_root(entry: &ignore::DirEntry, root: &Path) -> std::io::Result<()> {
+        if entry.path() != root {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "The walker root {} does not match the directory root {}",
+                    entry.path().display(),
+                    root.display()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The entries `create_archive` and `plan_archive` back up, the repository directory
+    /// itself is walked when no walker is given and `.ddup-bak` is always skipped.
+    fn source_entries(
+        &self,
+        directory: Option<ignore::Walk>,
+    ) -> impl Iterator<Item = ignore::DirEntry> + use<> {
+        directory
+            .unwrap_or_else(|| {
+                ignore::WalkBuilder::new(&self.directory)
+                    .follow_links(false)
+                    .git_global(false)
+                    .build()
+            })
+            .flatten()
+            .filter(|entry| entry.file_name() != ".ddup-bak")
+    }
+
+    /// Walks the source exactly like `create_archive` would, without chunking anything,
+    /// writing an archive or taking a lock. The progress callback is called for every file.
+    pub fn plan_archive(
+        &self,
+        directory: Option<ignore::Walk>,
+        directory_root: Option<&Path>,
+        progress: ProgressCallback,
+    ) -> std::io::Result<ArchivePlan> {
+        let root = directory_root.unwrap_or(&self.directory);
+        let mut plan = ArchivePlan::default();
+
+        for entry in self.source_entries(directory) {
+            if entry.depth() == 0 {
+                Self::check_source_root(&entry, root)?;
+                continue;
+            }
+
+            let path = entry.path();
+            let metadata = path.symlink_metadata()?;
+            if path.file_name().is_none() {
+                continue;
+            }
+
+            if metadata.is_dir() {
+                plan.directories += 1;
+            } else if metadata.is_symlink() {
+                plan.symlinks += 1;
+            } else if metadata.is_file() {
+                if let Some(f) = &progress {
+                    f(path);
+                }
 
-            if let Some(parent) = Self::archive_path_parent(archive, path) {
-                parent.entries.push(Entry::File(file_entry));
-            } else {
-                archive.entries.push(Entry::File(file_entry));
+                plan.bytes += metadata.len();
+                plan.files.push((
+                    path.strip_prefix(root).unwrap_or(path).to_path_buf(),
+                    metadata.len(),
+                ));
             }
-        } else if metadata.is_symlink()
-            && let Ok(target) = std::fs::read_link(entry.path())
-        {
-            let mut archive_lock = archive.lock();
-            let Some(archive) = archive_lock.as_mut() else {
-                return Err(std::io::Error::other("Archive has already been finalized"));
-            };
+        }
 
-            let link_entry = Entry::Symlink(Box::new(crate::archive::entries::SymlinkEntry {
-                name: file_name.to_string_lossy().into(),
-                mode: metadata.permissions().into(),
-                mtime: metadata.modified().unwrap_or(std::time::SystemTime::now()),
-                owner: {
-                    #[cfg(unix)]
-                    {
-                        use std::os::unix::fs::MetadataExt;
-                        (metadata.uid(), metadata.gid())
-                    }
-                    #[cfg(windows)]
-                    {
-                        (0, 0)
-                    }
-                },
-                target: target.to_string_lossy().into_owned(),
-                target_dir: target.is_dir(),
-            }));
+        Ok(plan)
+    }
 
-            if let Some(parent) = Self::archive_path_parent(archive, path) {
-                parent.entries.push(link_entry);
-            } else {
-                archive.entries.push(link_entry);
+    fn dereference_files(
+        &self,
+        files: impl Iterator<Item = Box<FileEntry>>,
+    ) -> std::io::Result<()> {
+        for mut file in files {
+            let mut ids = ChunkIdDecoder::new(&file);
+            while let Some(chunk_id) = ids.next_id(&mut file)? {
+                self.chunk_index.dereference_chunk_id(chunk_id, false);
             }
         }
 
         Ok(())
     }
 
+    /// Keeps the finished files of a failed `create_archive` as a checkpoint to resume from,
+    /// their chunks stay referenced. Without any finished file the archive is removed and an
+    /// earlier checkpoint is kept as it was.
+    fn checkpoint_archive(
+        &self,
+        name: &str,
+        archive: Option<Archive>,
+        resumed: Option<Mutex<HashMap<PathBuf, Box<FileEntry>>>>,
+    ) {
+        let archive_path = self.archive_path(name);
+
+        let Some(mut archive) = archive.filter(|archive| {
+            archive
+                .walk()
+                .flatten()
+                .any(|(_, _, entry)| entry.is_file())
+        }) else {
+            let _ = std::fs::remove_file(&archive_path);
+            return;
+        };
+
+        let result = archive.write_end_header().and_then(|_| {
+            std::fs::rename(&archive_path, self.partial_archive_path(name))?;
+
+            // the new checkpoint replaces the old one, whatever was not taken over is dropped
+            match resumed {
+                Some(resumed) => self.dereference_files(resumed.into_inner().into_values()),
+                None => Ok(()),
+            }
+        });
+
+        match result {
+            Ok(()) => log::info!("Kept a checkpoint of the interrupted archive {name}"),
+            Err(err) => {
+                log::warn!("Could not keep a checkpoint of archive {name}: {err}");
+                let _ = std::fs::remove_file(&archive_path);
+            }
+        }
+    }
+
+    /// Backs up `directory` (the repository directory by default) as a new archive.
+    /// Entry paths are stored relative to `directory_root`, which must be the path
+    /// the walker was started at, it defaults to the repository directory.
     #[allow(clippy::too_many_arguments)]
     pub fn create_archive(
         &self,
@@ -346,6 +2318,7 @@ impl Repository {
         progress_chunking: ProgressCallback,
         compression_callback: CompressionFormatCallback,
         threads: usize,
+        options: CreateOptions,
     ) -> std::io::Result<Archive> {
         if self.list_archives()?.iter().any(|n| n == name) {
             return Err(std::io::Error::new(
@@ -354,131 +2327,209 @@ impl Repository {
             ));
         }
 
-        let mut w = self.chunk_index.lock.write_lock(LockMode::NonDestructive)?;
+        let mut w = self.write_lock(LockMode::NonDestructive)?;
 
         let archive_path = self.archive_path(name);
+        let partial_path = self.partial_archive_path(name);
+
+        let resumed = match self.partial_archive(name)? {
+            Some(partial) if options.resume => {
+                let mut files = HashMap::new();
+                for item in partial.walk() {
+                    let (path, _, entry) = item?;
+                    if let Entry::File(file) = entry {
+                        files.insert(path, file.clone());
+                    }
+                }
+
+                Some(Mutex::new(files))
+            }
+            Some(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!(
+                        "Archive {name} has a checkpoint of an interrupted create, resume or discard it"
+                    ),
+                ));
+            }
+            None => None,
+        };
 
         let worker_pool = Arc::new(
             rayon::ThreadPoolBuilder::new()
-                .num_threads(threads)
+                .num_threads(resolve_threads(threads))
                 .build()
                 .map_err(std::io::Error::other)?,
         );
         let error = Arc::new(RwLock::new(None));
 
-        let walker = directory.unwrap_or_else(|| {
-            ignore::WalkBuilder::new(&self.directory)
-                .follow_links(false)
-                .git_global(false)
-                .build()
-        });
+        let entries: Box<dyn Iterator<Item = ignore::DirEntry>> = if options.count_first {
+            let entries = self.source_entries(directory).collect::<Vec<_>>();
+
+            let (mut files, mut bytes) = (0, 0);
+            for entry in entries.iter() {
+                if let Ok(metadata) = entry.path().symlink_metadata()
+                    && metadata.is_file()
+                {
+                    files += 1;
+                    bytes += metadata.len();
+                }
+            }
 
-        let archive = Arc::new(Mutex::new(Some(Archive::new(File::create(
-            &archive_path,
-        )?)?)));
+            if let Some(f) = &options.progress {
+                f(ProgressEvent::ScanComplete { files, bytes });
+            }
 
-        worker_pool.in_place_scope(|scope| {
-            for entry in walker.flatten() {
-                let path = entry.path();
-                let metadata = match path.symlink_metadata() {
-                    Ok(metadata) => metadata,
-                    Err(err) => {
+            Box::new(entries.into_iter())
+        } else {
+            Box::new(self.source_entries(directory))
+        };
+
+        let archive = Archive::new(File::create(&archive_path)?)?;
+        let root_path = directory_root.unwrap_or(&self.directory);
+        let (updates, received_updates) = std::sync::mpsc::channel();
+
+        let mut archive = std::thread::scope(|threads| {
+            let writer = threads.spawn(|| {
+                Self::apply_tree_updates(
+                    archive,
+                    received_updates,
+                    root_path,
+                    &options.progress,
+                    &error,
+                )
+            });
+
+            worker_pool.in_place_scope(|scope| {
+                for entry in entries {
+                    if let Err(err) = check_cancelled(&options.cancel) {
                         let mut error = error.write();
                         if error.is_none() {
                             *error = Some(err);
                         }
                         break;
                     }
-                };
-                if path.file_name() == Some(".ddup-bak".as_ref()) {
-                    continue;
-                }
-                let Some(file_name) = path.file_name() else {
-                    continue;
-                };
 
-                if error.read().is_some() {
-                    break;
-                }
-
-                if metadata.is_dir() {
-                    let mut archive_lock = archive.lock();
-                    let Some(archive) = archive_lock.as_mut() else {
-                        break;
-                    };
+                    if entry.depth() == 0 {
+                        if let Err(err) = Self::check_source_root(&entry, root_path) {
+                            *error.write() = Some(err);
+                            break;
+                        }
 
-                    let dir_entry =
-                        Entry::Directory(Box::new(crate::archive::entries::DirectoryEntry {
-                            name: file_name.to_string_lossy().into(),
-                            mode: metadata.permissions().into(),
-                            mtime: metadata.modified().unwrap_or(std::time::SystemTime::now()),
-                            owner: {
-                                #[cfg(unix)]
-                                {
-                                    use std::os::unix::fs::MetadataExt;
-                                    (metadata.uid(), metadata.gid())
-                                }
-                                #[cfg(windows)]
-                                {
-                                    (0, 0)
-                                }
-                            },
-                            entries: Vec::new(),
-                        }));
-
-                    if let Some(parent) = Self::archive_path_parent(
-                        archive,
-                        path.strip_prefix(directory_root.unwrap_or(&self.directory))
-                            .unwrap_or(path),
-                    ) {
-                        parent.entries.push(dir_entry);
-                    } else {
-                        archive.entries.push(dir_entry);
+                        continue;
                     }
-                }
-
-                scope.spawn({
-                    let error = Arc::clone(&error);
-                    let archive = Arc::clone(&archive);
-                    let chunk_index = self.chunk_index.clone();
-                    let directory_root = directory_root.unwrap_or(&self.directory);
-                    let progress_chunking = progress_chunking.clone();
-                    let compression_callback = compression_callback.clone();
 
-                    move |scope| {
-                        if let Err(err) = Self::recursive_create_archive(
-                            archive,
-                            &chunk_index,
-                            entry,
-                            metadata,
-                            directory_root,
-                            progress_chunking,
-                            compression_callback,
-                            scope,
-                            Arc::clone(&error),
-                        ) {
+                    let path = entry.path();
+                    let metadata = match path.symlink_metadata() {
+                        Ok(metadata) => metadata,
+                        Err(err) => {
                             let mut error = error.write();
                             if error.is_none() {
                                 *error = Some(err);
                             }
+                            break;
+                        }
+                    };
+                    let Some(file_name) = path.file_name() else {
+                        continue;
+                    };
+
+                    if error.read().is_some() {
+                        break;
+                    }
+
+                    if !metadata.is_file() {
+                        if let Some(f) = &progress_chunking {
+                            f(path)
                         }
+
+                        // directories and symlinks carry no payload, they go straight to the tree
+                        let entry = if metadata.is_dir() {
+                            Self::directory_entry(Path::new(file_name), &metadata)
+                        } else if metadata.is_symlink()
+                            && let Ok(target) = std::fs::read_link(path)
+                        {
+                            Entry::Symlink(Box::new(SymlinkEntry {
+                                name: file_name.to_string_lossy().into(),
+                                mode: metadata.permissions().into(),
+                                mtime: metadata.modified().unwrap_or(std::time::SystemTime::now()),
+                                owner: metadata_owner(&metadata),
+                                target_dir: target.is_dir(),
+                                target: target.to_string_lossy().into_owned(),
+                            }))
+                        } else {
+                            continue;
+                        };
+
+                        let _ = updates.send(TreeUpdate::Entry {
+                            path: path.strip_prefix(root_path).unwrap_or(path).to_path_buf(),
+                            entry,
+                        });
+
+                        continue;
                     }
-                });
-            }
-        });
+
+                    scope.spawn({
+                        let error = Arc::clone(&error);
+                        let updates = updates.clone();
+                        let chunk_index = self.chunk_index.clone();
+                        let progress_chunking = progress_chunking.clone();
+                        let progress_events = options.progress.clone();
+                        let compression_callback = compression_callback.clone();
+                        let resumed = resumed.as_ref();
+                        let cancel = &options.cancel;
+
+                        move |scope| {
+                            if let Err(err) = check_cancelled(cancel).and_then(|_| {
+                                Self::recursive_create_archive(
+                                    updates,
+                                    &chunk_index,
+                                    entry,
+                                    metadata,
+                                    root_path,
+                                    progress_chunking,
+                                    progress_events,
+                                    compression_callback,
+                                    resumed,
+                                    scope,
+                                    Arc::clone(&error),
+                                )
+                            }) {
+                                let mut error = error.write();
+                                if error.is_none() {
+                                    *error = Some(err);
+                                }
+                            }
+                        }
+                    });
+                }
+            });
+
+            // the writer finishes once every update was applied
+            drop(updates);
+            writer
+                .join()
+                .map_err(|_| std::io::Error::other("Archive writer panicked"))
+        })?;
 
         if let Some(err) = error.write().take() {
-            let _ = std::fs::remove_file(&archive_path);
+            self.checkpoint_archive(name, Some(archive), resumed);
+
             return Err(err);
         }
 
-        let Some(mut archive) = archive.lock().take() else {
-            return Err(std::io::Error::other("Archive has already been finalized"));
-        };
         archive.write_end_header()?;
 
+        // files of the checkpoint that changed or disappeared since are no longer referenced
+        if let Some(resumed) = resumed {
+            self.dereference_files(resumed.into_inner().into_values())?;
+            std::fs::remove_file(&partial_path)?;
+        }
+
         w.unlock()?;
 
+        log::info!("Created archive {name}");
+
         Ok(archive)
     }
 
@@ -488,17 +2539,10 @@ impl Repository {
         stream: &mut S,
     ) -> std::io::Result<()> {
         match entry {
-            Entry::File(mut file_entry) => {
-                loop {
-                    let chunk_id = crate::varint::decode_u64(&mut file_entry);
-                    let Ok(chunk_id) = chunk_id else {
-                        break;
-                    };
-
-                    let mut chunk = self.chunk_index.read_chunk_id_content(chunk_id)?;
-
-                    std::io::copy(&mut chunk, stream)?;
-                }
+            Entry::File(file_entry) => {
+                let mut reader = EntryReader::parallel(file_entry, self.chunk_index.clone());
+                reader.set_copy_buffer_size(self.io_buffer_size);
+                reader.copy_to(stream, |_| {})?;
 
                 Ok(())
             }
@@ -509,12 +2553,230 @@ impl Repository {
         }
     }
 
+    /// Writes `length` bytes of a file starting at `offset` to the stream, or everything after
+    /// `offset` if no length is given. Returns the number of bytes written.
+    /// All chunks of a file except the last one have the same size, so once the size of one
+    /// chunk is known the chunks before the offset are skipped without reading them.
+    pub fn read_entry_range<S: Write>(
+        &self,
+        entry: Entry,
+        offset: u64,
+        length: Option<u64>,
+        stream: &mut S,
+    ) -> std::io::Result<u64> {
+        let Entry::File(mut file_entry) = entry else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Entry is not a file",
+            ));
+        };
+
+        let mut remaining = length.unwrap_or(u64::MAX);
+        let mut position = 0;
+        let mut written = 0;
+        let mut chunk_size = None;
+
+        let mut ids = ChunkIdDecoder::new(&file_entry);
+        while remaining > 0
+            && let Some(chunk_id) = ids.next_id(&mut file_entry)?
+        {
+            if let Some(chunk_size) = chunk_size
+                && position + chunk_size <= offset
+            {
+                position += chunk_size;
+                continue;
+            }
+
+            let mut chunk = self.chunk_index.read_chunk_id_content(chunk_id)?;
+
+            let skipped = std::io::copy(
+                &mut (&mut chunk).take(offset.saturating_sub(position)),
+                &mut std::io::sink(),
+            )?;
+            let copied = std::io::copy(&mut (&mut chunk).take(remaining), stream)?;
+
+            remaining -= copied;
+            written += copied;
+
+            if chunk_size.is_none() {
+                let rest = std::io::copy(&mut chunk, &mut std::io::sink())?;
+                chunk_size = Some(skipped + copied + rest);
+            }
+
+            position += chunk_size.unwrap_or_default();
+        }
+
+        Ok(written)
+    }
+
+    /// Checks whether the file at `path` already matches `file_entry` closely enough
+    /// to skip rewriting it, correcting mode and owner if only those differ.
+    fn restore_identical_file(
+        chunk_index: &ChunkIndex,
+        path: &Path,
+        file_entry: &crate::archive::entries::FileEntry,
+        state: &RestoreState,
+    ) -> std::io::Result<bool> {
+        let Ok(metadata) = path.symlink_metadata() else {
+            return Ok(false);
+        };
+
+        if !metadata.is_file() || metadata.len() != file_entry.size_real {
+            return Ok(false);
+        }
+
+        let mtime = |time: std::time::SystemTime| {
+            time.duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+
+        if mtime(metadata.modified()?) != mtime(file_entry.mtime) {
+            return Ok(false);
+        }
+
+        if state.options.verify_content {
+            let mut file_entry = file_entry.clone();
+            let mut chunk_ids = Vec::new();
+
+            let mut ids = ChunkIdDecoder::new(&file_entry);
+            while let Some(chunk_id) = ids.next_id(&mut file_entry)? {
+                chunk_ids.push(chunk_id);
+            }
+
+            if !chunk_index.file_matches_chunks(path, &chunk_ids)? {
+                return Ok(false);
+            }
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+            if metadata.permissions().mode() & 0o7777 != file_entry.mode.bits() & 0o7777 {
+                state.set_permissions(path, file_entry.mode)?;
+            }
+
+            if let Some((uid, gid)) = state.options.ownership.map(file_entry.owner)
+                && (metadata.uid() != uid || metadata.gid() != gid)
+            {
+                state.chown(path, file_entry.owner)?;
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            if metadata.permissions().readonly() != (file_entry.mode.bits() & 0o222 == 0) {
+                state.set_permissions(path, file_entry.mode)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Spawns the restore of `entries` into `directory`. Directories and large files get
+    /// a task each, small files and symlinks are restored in batches to keep the
+    /// scheduling overhead of trees with many tiny files down.
+    fn spawn_restore_entries(
+        chunk_index: &ChunkIndex,
+        entries: Vec<Entry>,
+        directory: &Path,
+        progress: &ProgressCallback,
+        scope: &rayon::Scope,
+        state: &Arc<RestoreState>,
+        error: &Arc<RwLock<Option<std::io::Error>>>,
+    ) {
+        let inline_size = state
+            .options
+            .inline_restore_size
+            .unwrap_or(DEFAULT_INLINE_RESTORE_SIZE);
+
+        let mut batch = Vec::new();
+        for entry in entries {
+            let inline = match &entry {
+                Entry::File(file_entry) => file_entry.size_real < inline_size,
+                Entry::Symlink(_) => inline_size > 0,
+                Entry::Directory(_) => false,
+            };
+
+            if !inline {
+                Self::spawn_restore_batch(
+                    chunk_index,
+                    vec![entry],
+                    directory,
+                    progress,
+                    scope,
+                    state,
+                    error,
+                );
+
+                continue;
+            }
+
+            batch.push(entry);
+            if batch.len() == RESTORE_BATCH_LENGTH {
+                Self::spawn_restore_batch(
+                    chunk_index,
+                    std::mem::take(&mut batch),
+                    directory,
+                    progress,
+                    scope,
+                    state,
+                    error,
+                );
+            }
+        }
+
+        if !batch.is_empty() {
+            Self::spawn_restore_batch(chunk_index, batch, directory, progress, scope, state, error);
+        }
+    }
+
+    fn spawn_restore_batch(
+        chunk_index: &ChunkIndex,
+        batch: Vec<Entry>,
+        directory: &Path,
+        progress: &ProgressCallback,
+        scope: &rayon::Scope,
+        state: &Arc<RestoreState>,
+        error: &Arc<RwLock<Option<std::io::Error>>>,
+    ) {
+        scope.spawn({
+            let error = Arc::clone(error);
+            let state = Arc::clone(state);
+            let chunk_index = chunk_index.clone();
+            let directory = directory.to_path_buf();
+            let progress = progress.clone();
+
+            move |scope| {
+                for entry in batch {
+                    if let Err(err) = Self::recursive_restore_archive(
+                        &chunk_index,
+                        entry,
+                        &directory,
+                        progress.clone(),
+                        scope,
+                        Arc::clone(&state),
+                        Arc::clone(&error),
+                    ) {
+                        let mut error = error.write();
+                        if error.is_none() {
+                            *error = Some(err);
+                        }
+
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
     fn recursive_restore_archive(
         chunk_index: &ChunkIndex,
         entry: Entry,
         directory: &Path,
         progress: ProgressCallback,
         scope: &rayon::Scope,
+        state: Arc<RestoreState>,
         error: Arc<RwLock<Option<std::io::Error>>>,
     ) -> std::io::Result<()> {
         let path = directory.join(entry.name());
@@ -523,169 +2785,222 @@ impl Repository {
             return Ok(());
         }
 
+        check_cancelled(&state.options.cancel)?;
+
         if let Some(f) = &progress {
             f(&path)
         }
 
         match entry {
-            Entry::File(mut file_entry) => {
-                let mut file = File::create(&path)?;
+            Entry::File(file_entry) => {
+                let skipped = if state.skip_existing(&path) {
+                    true
+                } else if state.options.mode == RestoreMode::SkipIdentical
+                    && Self::restore_identical_file(chunk_index, &path, &file_entry, &state)?
+                {
+                    state.skipped_identical.fetch_add(1, Ordering::Relaxed);
 
-                loop {
-                    let chunk_id = crate::varint::decode_u64(&mut file_entry)?;
-                    if chunk_id == 0 {
-                        break;
-                    }
+                    true
+                } else {
+                    false
+                };
 
-                    let mut chunk = chunk_index.read_chunk_id_content(chunk_id)?;
+                if skipped {
+                    state.progress(ProgressEvent::BytesProcessed(file_entry.size_real));
+                    state.progress(ProgressEvent::FileDone {
+                        path: &path,
+                        bytes: file_entry.size_real,
+                    });
 
-                    std::io::copy(&mut chunk, &mut file)?;
+                    return Ok(());
                 }
 
-                file.set_permissions(file_entry.mode.into())?;
+                let mut file = create_restore_file(&path)?;
+                let mut reader = EntryReader::parallel(file_entry, chunk_index.clone());
+                reader.set_copy_buffer_size(state.io_buffer_size);
+
+                reader.copy_to(&mut file, |written| {
+                    state.bytes_written.fetch_add(written, Ordering::Relaxed);
+                    state.progress(ProgressEvent::BytesProcessed(written));
+                })?;
+
+                let file_entry = reader.entry;
+
+                state.set_permissions(&path, file_entry.mode)?;
                 file.set_times(FileTimes::new().set_modified(file_entry.mtime))?;
 
                 #[cfg(unix)]
-                {
-                    let (uid, gid) = file_entry.owner;
+                state.chown(&path, file_entry.owner)?;
 
-                    std::os::unix::fs::lchown(&path, Some(uid), Some(gid))?;
-                }
+                state.files_restored.fetch_add(1, Ordering::Relaxed);
+                state.progress(ProgressEvent::FileDone {
+                    path: &path,
+                    bytes: file_entry.size_real,
+                });
             }
             Entry::Directory(dir_entry) => {
-                std::fs::create_dir_all(&path)?;
+                create_restore_directory(&path)?;
 
-                std::fs::set_permissions(&path, dir_entry.mode.into())?;
+                state.set_permissions(&path, dir_entry.mode)?;
 
                 #[cfg(unix)]
-                {
-                    let (uid, gid) = dir_entry.owner;
-                    std::os::unix::fs::chown(&path, Some(uid), Some(gid))?;
+                state.chown(&path, dir_entry.owner)?;
+
+                state.directories_restored.fetch_add(1, Ordering::Relaxed);
+
+                Self::spawn_restore_entries(
+                    chunk_index,
+                    dir_entry.into_entries()?,
+                    &path,
+                    &progress,
+                    scope,
+                    &state,
+                    &error,
+                );
+            }
+            #[cfg(unix)]
+            Entry::Symlink(link_entry) => {
+                if state.skip_existing(&path) {
+                    return Ok(());
                 }
 
-                for sub_entry in dir_entry.entries {
-                    scope.spawn({
-                        let error = Arc::clone(&error);
-                        let chunk_index = chunk_index.clone();
-                        let path = path.to_path_buf();
-                        let progress = progress.clone();
+                if state.options.mode == RestoreMode::SkipIdentical
+                    && std::fs::read_link(&path)
+                        .is_ok_and(|target| target == Path::new(&link_entry.target))
+                {
+                    state.skipped_identical.fetch_add(1, Ordering::Relaxed);
 
-                        move |scope| {
-                            if let Err(err) = Self::recursive_restore_archive(
-                                &chunk_index,
-                                sub_entry,
-                                &path,
-                                progress,
-                                scope,
-                                Arc::clone(&error),
-                            ) {
-                                let mut error = error.write();
-                                if error.is_none() {
-                                    *error = Some(err);
-                                }
-                            }
-                        }
-                    });
+                    return Ok(());
                 }
-            }
-            #[cfg(unix)]
-            Entry::Symlink(link_entry) => {
+
+                if std::fs::symlink_metadata(&path).is_ok_and(|metadata| !metadata.is_dir()) {
+                    std::fs::remove_file(&path)?;
+                }
+
+                // symlink permissions are not used on unix, setting them would follow the link
                 std::os::unix::fs::symlink(link_entry.target, &path)?;
-                std::fs::set_permissions(&path, link_entry.mode.into())?;
 
-                let (uid, gid) = link_entry.owner;
-                std::os::unix::fs::lchown(&path, Some(uid), Some(gid))?;
+                state.chown(&path, link_entry.owner)?;
+
+                state.symlinks_restored.fetch_add(1, Ordering::Relaxed);
             }
             #[cfg(windows)]
             Entry::Symlink(link_entry) => {
-                if link_entry.target_dir {
-                    std::os::windows::fs::symlink_dir(link_entry.target, &path)?;
+                if state.skip_existing(&path) {
+                    return Ok(());
+                }
+
+                if state.options.mode == RestoreMode::SkipIdentical
+                    && std::fs::read_link(&path)
+                        .is_ok_and(|target| target == Path::new(&link_entry.target))
+                {
+                    state.skipped_identical.fetch_add(1, Ordering::Relaxed);
+
+                    return Ok(());
+                }
+
+                if std::fs::symlink_metadata(&path).is_ok_and(|metadata| !metadata.is_dir()) {
+                    std::fs::remove_file(&path)?;
+                }
+
+                let result = if link_entry.target_dir {
+                    std::os::windows::fs::symlink_dir(&link_entry.target, &path)
                 } else {
-                    std::os::windows::fs::symlink_file(link_entry.target, &path)?;
+                    std::os::windows::fs::symlink_file(&link_entry.target, &path)
+                };
+
+                match result {
+                    Ok(()) => state.set_link_permissions(&path, link_entry.mode)?,
+                    Err(err) => state.symlink_fallback(&path, &link_entry, err)?,
                 }
 
-                std::fs::set_permissions(&path, link_entry.mode.into())?;
+                state.symlinks_restored.fetch_add(1, Ordering::Relaxed);
             }
         }
 
         Ok(())
     }
 
-    pub fn restore_archive(
+    pub fn restore_archive(
+        &self,
+        name: &str,
+        progress: ProgressCallback,
+        threads: usize,
+    ) -> std::io::Result<PathBuf> {
+        if !self.list_archives()?.iter().any(|n| n == name) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Archive {name} not found"),
+            ));
+        }
+
+        let archive = self.get_archive(name)?;
+
+        Ok(self
+            .restore_entries_with_options(
+                name,
+                archive.into_entries()?,
+                progress,
+                threads,
+                RestoreOptions::default(),
+            )?
+            .destination)
+    }
+
+    pub fn restore_entries(
+        &self,
+        name: &str,
+        entries: Vec<Entry>,
+        progress: ProgressCallback,
+        threads: usize,
+    ) -> std::io::Result<PathBuf> {
+        Ok(self
+            .restore_entries_with_options(
+                name,
+                entries,
+                progress,
+                threads,
+                RestoreOptions::default(),
+            )?
+            .destination)
+    }
+
+    /// Restores only the given archive paths, keeping their location relative to the archive root.
+    /// Fails with `NotFound` when any of the paths does not exist in the archive.
+    pub fn restore_paths(
         &self,
         name: &str,
+        paths: &[&Path],
         progress: ProgressCallback,
         threads: usize,
-    ) -> std::io::Result<PathBuf> {
-        if !self.list_archives()?.iter().any(|n| n == name) {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                format!("Archive {name} not found"),
-            ));
-        }
-
-        let mut r = self.chunk_index.lock.read_lock(LockMode::NonDestructive)?;
-
-        let archive_path = self.archive_path(name);
-        let archive = Archive::open(&archive_path)?;
-        let destination = self
-            .directory
-            .join(".ddup-bak/archives-restored")
-            .join(name);
-
-        std::fs::create_dir_all(&destination)?;
-
-        let worker_pool = Arc::new(
-            rayon::ThreadPoolBuilder::new()
-                .num_threads(threads)
-                .build()
-                .map_err(std::io::Error::other)?,
-        );
-        let error = Arc::new(RwLock::new(None));
-
-        worker_pool.in_place_scope(|scope| {
-            for entry in archive.into_entries() {
-                scope.spawn({
-                    let error = Arc::clone(&error);
-                    let chunk_index = self.chunk_index.clone();
-                    let destination = destination.clone();
-                    let progress = progress.clone();
-
-                    move |scope| {
-                        if let Err(err) = Self::recursive_restore_archive(
-                            &chunk_index,
-                            entry,
-                            &destination,
-                            progress,
-                            scope,
-                            Arc::clone(&error),
-                        ) {
-                            let mut error = error.write();
-                            if error.is_none() {
-                                *error = Some(err);
-                            }
-                        }
-                    }
-                });
+        options: RestoreOptions,
+    ) -> std::io::Result<RestoreReport> {
+        let archive = self.get_archive(name)?;
+
+        for path in paths {
+            if archive.find_archive_entry(path)?.is_none() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Path {} not found in archive {name}", path.display()),
+                ));
             }
-        });
-
-        if let Some(err) = error.write().take() {
-            return Err(err);
         }
 
-        r.unlock()?;
+        let entries = archive.select_entries(paths)?;
 
-        Ok(destination)
+        self.restore_entries_with_options(name, entries, progress, threads, options)
     }
 
-    pub fn restore_entries(
+    /// Restores the given entries of an archive into the destination from `options`.
+    /// Returns a report with the destination and what was written or skipped.
+    pub fn restore_entries_with_options(
         &self,
         name: &str,
         entries: Vec<Entry>,
         progress: ProgressCallback,
         threads: usize,
-    ) -> std::io::Result<PathBuf> {
+        options: RestoreOptions,
+    ) -> std::io::Result<RestoreReport> {
         if !self.list_archives()?.iter().any(|n| n == name) {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::NotFound,
@@ -693,57 +3008,72 @@ impl Repository {
             ));
         }
 
-        let mut r = self.chunk_index.lock.read_lock(LockMode::NonDestructive)?;
+        let mut r = self.read_lock(LockMode::NonDestructive)?;
 
-        let destination = self
-            .directory
-            .join(".ddup-bak/archives-restored")
-            .join(name);
+        let destination = options.destination.clone().unwrap_or_else(|| {
+            self.directory
+                .join(".ddup-bak/archives-restored")
+                .join(name)
+        });
 
         std::fs::create_dir_all(&destination)?;
 
         let worker_pool = Arc::new(
             rayon::ThreadPoolBuilder::new()
-                .num_threads(threads)
+                .num_threads(resolve_threads(threads))
                 .build()
                 .map_err(std::io::Error::other)?,
         );
         let error = Arc::new(RwLock::new(None));
+        let state = Arc::new(RestoreState {
+            options,
+            io_buffer_size: self.io_buffer_size,
+            files_restored: AtomicU64::new(0),
+            directories_restored: AtomicU64::new(0),
+            symlinks_restored: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            skipped_identical: AtomicU64::new(0),
+            skipped_existing: AtomicU64::new(0),
+            warnings: Mutex::new(Vec::new()),
+            deferred_copies: Mutex::new(Vec::new()),
+        });
 
         worker_pool.in_place_scope(|scope| {
-            for entry in entries {
-                scope.spawn({
-                    let error = Arc::clone(&error);
-                    let chunk_index = self.chunk_index.clone();
-                    let destination = destination.clone();
-                    let progress = progress.clone();
-
-                    move |scope| {
-                        if let Err(err) = Self::recursive_restore_archive(
-                            &chunk_index,
-                            entry,
-                            &destination,
-                            progress,
-                            scope,
-                            Arc::clone(&error),
-                        ) {
-                            let mut error = error.write();
-                            if error.is_none() {
-                                *error = Some(err);
-                            }
-                        }
-                    }
-                });
-            }
+            Self::spawn_restore_entries(
+                &self.chunk_index,
+                entries,
+                &destination,
+                &progress,
+                scope,
+                &state,
+                &error,
+            );
         });
 
         if let Some(err) = error.write().take() {
             return Err(err);
         }
 
+        state.copy_deferred_targets(&destination)?;
+
         r.unlock()?;
 
-        Ok(destination)
+        log::info!(
+            "Restored {} files of archive {name} to {}",
+            state.files_restored.load(Ordering::Relaxed),
+            destination.display()
+        );
+
+        Ok(RestoreReport {
+            destination,
+            files_restored: state.files_restored.load(Ordering::Relaxed),
+            directories_restored: state.directories_restored.load(Ordering::Relaxed),
+            symlinks_restored: state.symlinks_restored.load(Ordering::Relaxed),
+            bytes_written: state.bytes_written.load(Ordering::Relaxed),
+            skipped_identical: state.skipped_identical.load(Ordering::Relaxed),
+            skipped_existing: state.skipped_existing.load(Ordering::Relaxed),
+            warnings: std::mem::take(&mut *state.warnings.lock()),
+        })
     }
 
     fn recursive_delete_archive(
@@ -752,20 +3082,19 @@ impl Repository {
         progress: DeletionProgressCallback,
     ) -> std::io::Result<()> {
         match entry {
-            Entry::File(mut file_entry) => loop {
-                let chunk_id = crate::varint::decode_u64(&mut file_entry)?;
-                if chunk_id == 0 {
-                    break;
-                }
+            Entry::File(mut file_entry) => {
+                let mut ids = ChunkIdDecoder::new(&file_entry);
 
-                if let Some(deleted) = self.chunk_index.dereference_chunk_id(chunk_id, true)
-                    && let Some(f) = &progress
-                {
-                    f(chunk_id, deleted)
+                while let Some(chunk_id) = ids.next_id(&mut file_entry)? {
+                    if let Some(deleted) = self.chunk_index.dereference_chunk_id(chunk_id, true)
+                        && let Some(f) = &progress
+                    {
+                        f(chunk_id, deleted)
+                    }
                 }
-            },
+            }
             Entry::Directory(dir_entry) => {
-                for sub_entry in dir_entry.entries {
+                for sub_entry in dir_entry.into_entries()? {
                     self.recursive_delete_archive(sub_entry, progress.clone())?;
                 }
             }
@@ -787,16 +3116,160 @@ impl Repository {
             ));
         }
 
-        let mut w = self.chunk_index.lock.write_lock(LockMode::Destructive)?;
+        let mut w = self.write_lock(LockMode::Destructive)?;
 
         let archive_path = self.archive_path(name);
         let archive = Archive::open(&archive_path)?;
 
-        for entry in archive.into_entries() {
+        for entry in archive.into_entries()? {
             self.recursive_delete_archive(entry, progress.clone())?;
         }
 
         std::fs::remove_file(archive_path)?;
+        match std::fs::remove_file(self.archive_metadata_path(name)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+
+        w.unlock()?;
+
+        log::info!("Deleted archive {name}");
+
+        Ok(())
+    }
+
+    /// Takes the write lock in `mode` if nobody else holds it, returning `None` instead
+    /// of waiting otherwise. The lock is reentrant within the process, so repository calls
+    /// made while the guard is alive reuse it, e.g. to skip a scheduled backup if another
+    /// process is already working on the repository.
+    pub fn try_lock(&self, mode: LockMode) -> std::io::Result<Option<WriteGuard>> {
+        self.chunk_index.lock.try_write_lock(mode)
+    }
+
+    /// Reports who holds the repository lock, for diagnostics.
+    pub fn lock_status(&self) -> std::io::Result<LockStatus> {
+        self.chunk_index.lock.status()
+    }
+
+    /// Reports how long operations of this process waited for the repository lock.
+    pub fn lock_metrics(&self) -> LockMetrics {
+        self.chunk_index.lock.metrics()
+    }
+
+    /// Runs `f` while holding the write lock in `mode`. The lock is reentrant within
+    /// the process, so repository calls made by `f` reuse it instead of waiting for it
+    /// one by one, e.g. to delete several archives under a single destructive lock.
+    pub fn with_write_lock<T>(
+        &self,
+        mode: LockMode,
+        f: impl FnOnce(&Self) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let mut w = self.write_lock(mode)?;
+        let result = f(self);
+
+        w.unlock()?;
+
+        result
+    }
+
+    fn check_archive_name(name: &str) -> std::io::Result<()> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid archive name {name:?}"),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Renames an archive, failing if `new_name` is already taken.
+    /// The archive file is renamed in a single step, so an interrupted rename
+    /// leaves the archive under either the old or the new name.
+    pub fn rename_archive(&self, name: &str, new_name: &str) -> std::io::Result<()> {
+        Self::check_archive_name(new_name)?;
+
+        let mut w = self.write_lock(LockMode::NonDestructive)?;
+
+        let archives = self.list_archives()?;
+        if !archives.iter().any(|n| n == name) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Archive {name} not found"),
+            ));
+        }
+        if archives.iter().any(|n| n == new_name) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Archive {new_name} already exists"),
+            ));
+        }
+
+        std::fs::rename(self.archive_path(name), self.archive_path(new_name))?;
+        match std::fs::rename(
+            self.archive_metadata_path(name),
+            self.archive_metadata_path(new_name),
+        ) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+
+        w.unlock()?;
+
+        log::info!("Renamed archive {name} to {new_name}");
+
+        Ok(())
+    }
+
+    /// Reads the tags and description of an archive, archives without any are returned
+    /// with empty metadata.
+    pub fn archive_metadata(&self, name: &str) -> std::io::Result<ArchiveMetadata> {
+        if !self.archive_path(name).exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Archive {name} not found"),
+            ));
+        }
+
+        match std::fs::read(self.archive_metadata_path(name)) {
+            Ok(data) => Ok(ArchiveMetadata::from_json(&serde_json::from_slice(&data)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Ok(ArchiveMetadata::default())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Replaces the tags and description of an archive, the file is replaced atomically.
+    pub fn set_archive_metadata(
+        &self,
+        name: &str,
+        metadata: &ArchiveMetadata,
+    ) -> std::io::Result<()> {
+        for tag in metadata.tags.iter() {
+            ArchiveMetadata::check_tag(tag)?;
+        }
+
+        let mut w = self.write_lock(LockMode::NonDestructive)?;
+
+        if !self.archive_path(name).exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Archive {name} not found"),
+            ));
+        }
+
+        let path = self.archive_metadata_path(name);
+        if metadata.is_empty() {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        } else {
+            atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite)
+                .write(|f| f.write_all(metadata.to_json().to_string().as_bytes()))
+                .map_err(std::io::Error::from)?;
+        }
 
         w.unlock()?;
 
@@ -809,7 +3282,629 @@ impl Drop for Repository {
         if self.save_on_drop
             && let Err(err) = self.save()
         {
-            eprintln!("Failed to save repository: {err}");
+            log::error!("Failed to save repository: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_directory;
+
+    /// A temp directory with an empty `source` directory to back up.
+    fn source_directory(name: &str) -> PathBuf {
+        let directory = temp_directory(&format!("repository-{name}"));
+        std::fs::create_dir_all(directory.join("source")).unwrap();
+
+        directory
+    }
+
+    fn create_archive(directory: &Path, name: &str) -> Repository {
+        let repository = Repository::new(&directory.join("repository"), 16, 0, None).unwrap();
+        let source = directory.join("source");
+
+        repository
+            .create_archive(
+                name,
+                Some(ignore::WalkBuilder::new(&source).build()),
+                Some(&source),
+                None,
+                None,
+                1,
+                CreateOptions::default(),
+            )
+            .unwrap();
+
+        repository
+    }
+
+    fn restore(repository: &Repository, name: &str, options: RestoreOptions) -> RestoreReport {
+        let entries = repository
+            .get_archive(name)
+            .unwrap()
+            .into_entries()
+            .unwrap();
+
+        repository
+            .restore_entries_with_options(name, entries, None, 1, options)
+            .unwrap()
+    }
+
+    #[test]
+    fn scan_totals_match_the_processed_totals() {
+        let directory = source_directory("scan-totals");
+        let source = directory.join("source");
+        std::fs::create_dir_all(source.join("directory/nested")).unwrap();
+        std::fs::write(source.join("file"), [1; 100]).unwrap();
+        std::fs::write(source.join("directory/file"), [2; 1000]).unwrap();
+        std::fs::write(source.join("directory/nested/empty"), []).unwrap();
+        std::fs::write(source.join("directory/nested/file"), [3; 5000]).unwrap();
+        // hidden files are skipped by the walker, so the scan must skip them as well
+        std::fs::write(source.join(".hidden"), [4; 300]).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("file", source.join("link")).unwrap();
+
+        #[derive(Default)]
+        struct Totals {
+            scanned: Option<(u64, u64)>,
+            files: u64,
+            file_bytes: u64,
+            processed_bytes: u64,
+        }
+        let totals = Arc::new(Mutex::new(Totals::default()));
+
+        let repository = Repository::new(&directory.join("repository"), 16, 0, None).unwrap();
+        repository
+            .create_archive(
+                "archive",
+                Some(ignore::WalkBuilder::new(&source).build()),
+                Some(&source),
+                None,
+                None,
+                2,
+                CreateOptions {
+                    count_first: true,
+                    progress: Some({
+                        let totals = Arc::clone(&totals);
+
+                        Arc::new(move |event| {
+                            let mut totals = totals.lock();
+                            match event {
+                                ProgressEvent::ScanComplete { files, bytes } => {
+                                    assert_eq!(totals.files, 0, "scan after the first file");
+                                    totals.scanned = Some((files, bytes));
+                                }
+                                ProgressEvent::FileDone { bytes, .. } => {
+                                    totals.files += 1;
+                                    totals.file_bytes += bytes;
+                                }
+                                ProgressEvent::BytesProcessed(bytes) => {
+                                    totals.processed_bytes += bytes
+                                }
+                            }
+                        })
+                    }),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let totals = totals.lock();
+        assert_eq!(totals.scanned, Some((4, 6100)));
+        assert_eq!(totals.files, 4);
+        assert_eq!(totals.file_bytes, 6100);
+        assert_eq!(totals.processed_bytes, 6100);
+
+        drop(repository);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn restoring_twice_writes_nothing_the_second_time() {
+        let directory = source_directory("restore-twice");
+        std::fs::create_dir_all(directory.join("source/directory")).unwrap();
+        std::fs::write(directory.join("source/file"), [1; 40]).unwrap();
+        std::fs::write(directory.join("source/directory/file"), [2; 100]).unwrap();
+        let repository = create_archive(&directory, "archive");
+
+        let options = RestoreOptions {
+            destination: Some(directory.join("destination")),
+            mode: RestoreMode::SkipIdentical,
+            ..Default::default()
+        };
+
+        let report = restore(&repository, "archive", options.clone());
+        assert_eq!(report.bytes_written, 140);
+        assert_eq!(report.skipped_identical, 0);
+
+        let report = restore(&repository, "archive", options);
+        assert_eq!(report.bytes_written, 0);
+        assert_eq!(report.files_restored, 0);
+        assert_eq!(report.skipped_identical, 2);
+
+        drop(repository);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn skip_identical_verifies_content_when_asked() {
+        let directory = source_directory("verify-content");
+        std::fs::write(directory.join("source/file"), [1; 40]).unwrap();
+        let repository = create_archive(&directory, "archive");
+
+        let destination = directory.join("destination");
+        restore(
+            &repository,
+            "archive",
+            RestoreOptions {
+                destination: Some(destination.clone()),
+                ..Default::default()
+            },
+        );
+
+        // same size and modification time, different content
+        let path = destination.join("file");
+        let mtime = path.metadata().unwrap().modified().unwrap();
+        std::fs::write(&path, [2; 40]).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let options = RestoreOptions {
+            destination: Some(destination.clone()),
+            mode: RestoreMode::SkipIdentical,
+            ..Default::default()
+        };
+
+        let report = restore(&repository, "archive", options.clone());
+        assert_eq!(report.skipped_identical, 1);
+        assert_eq!(std::fs::read(&path).unwrap(), [2; 40]);
+
+        let report = restore(
+            &repository,
+            "archive",
+            RestoreOptions {
+                verify_content: true,
+                ..options.clone()
+            },
+        );
+        assert_eq!(report.skipped_identical, 0);
+        assert_eq!(std::fs::read(&path).unwrap(), [1; 40]);
+
+        let report = restore(
+            &repository,
+            "archive",
+            RestoreOptions {
+                verify_content: true,
+                ..options
+            },
+        );
+        assert_eq!(report.skipped_identical, 1);
+
+        drop(repository);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restore_does_not_follow_existing_symlinks() {
+        let directory = source_directory("symlinks");
+        std::fs::create_dir_all(directory.join("source/directory")).unwrap();
+        std::fs::write(directory.join("source/file"), "archived").unwrap();
+        std::fs::write(directory.join("source/directory/file"), "archived").unwrap();
+        let repository = create_archive(&directory, "archive");
+
+        let outside = directory.join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("file"), "outside").unwrap();
+
+        let destination = directory.join("destination");
+        std::fs::create_dir_all(&destination).unwrap();
+        std::os::unix::fs::symlink(outside.join("file"), destination.join("file")).unwrap();
+        std::os::unix::fs::symlink(&outside, destination.join("directory")).unwrap();
+
+        restore(
+            &repository,
+            "archive",
+            RestoreOptions {
+                destination: Some(destination.clone()),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(std::fs::read(outside.join("file")).unwrap(), b"outside");
+        assert!(!destination.join("file").is_symlink());
+        assert_eq!(
+            std::fs::read(destination.join("file")).unwrap(),
+            b"archived"
+        );
+        assert!(!destination.join("directory").is_symlink());
+        assert_eq!(
+            std::fs::read(destination.join("directory/file")).unwrap(),
+            b"archived"
+        );
+
+        drop(repository);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn check_repairs_reference_counts() {
+        let directory = source_directory("check-repair");
+        std::fs::write(directory.join("source/file"), [1; 40]).unwrap();
+        let repository = create_archive(&directory, "archive");
+
+        let (chunk_id, _, count) = repository.chunk_index.chunk_entries()[0];
+        repository.chunk_index.set_references(chunk_id, count + 3);
+
+        let report = repository.check(None, false).unwrap();
+        assert_eq!(report.count_mismatches.len(), 1);
+        assert!(!report.repaired);
+
+        let report = repository.check(None, true).unwrap();
+        assert_eq!(report.count_mismatches[0].archive_count, count);
+        assert!(report.repaired);
+        assert!(!report.has_missing_chunks());
+
+        let report = repository.check(None, true).unwrap();
+        assert!(report.is_ok());
+        assert!(!report.repaired);
+
+        drop(repository);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn check_reports_missing_chunks_as_unrepaired() {
+        let directory = source_directory("check-missing");
+        std::fs::write(directory.join("source/file"), [1; 40]).unwrap();
+        let repository = create_archive(&directory, "archive");
+
+        let (chunk_id, chunk, _) = repository.chunk_index.chunk_entries()[0];
+        repository
+            .chunk_index
+            .storage
+            .delete_chunk_content(&chunk)
+            .unwrap();
+
+        let report = repository.check(None, true).unwrap();
+        assert_eq!(report.missing_from_storage, [chunk_id]);
+        assert!(report.has_missing_chunks());
+        assert!(!report.repaired);
+
+        drop(repository);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn stats_counts_missing_chunks() {
+        let directory = source_directory("stats-missing");
+        std::fs::write(directory.join("source/file"), [1; 40]).unwrap();
+        let repository = create_archive(&directory, "archive");
+
+        let (_, chunk, _) = repository.chunk_index.chunk_entries()[0];
+        repository
+            .chunk_index
+            .storage
+            .delete_chunk_content(&chunk)
+            .unwrap();
+
+        let stats = repository.stats().unwrap();
+        assert_eq!(
+            stats.unique_chunks,
+            repository.chunk_index.chunk_entries().len() as u64
+        );
+        assert_eq!(stats.missing_chunks, 1);
+
+        drop(repository);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    /// Run by `lock_backend_is_shared_with_other_processes` in a child process, a no-op
+    /// otherwise. Exits with 0 if the write lock of the repository was free, 1 if not.
+    #[test]
+    fn lock_backend_child() {
+        let Some(directory) = std::env::var_os("DDUP_BAK_LOCK_CHILD") else {
+            return;
+        };
+
+        let mut repository = Repository::open(Path::new(&directory), None, None).unwrap();
+        repository.set_save_on_drop(false);
+        assert_eq!(
+            repository.lock_backend().to_string(),
+            std::env::var("DDUP_BAK_LOCK_CHILD_BACKEND").unwrap()
+        );
+
+        let free = repository
+            .chunk_index
+            .lock
+            .try_write_lock(LockMode::Destructive)
+            .unwrap()
+            .is_some();
+
+        std::process::exit(if free { 0 } else { 1 });
+    }
+
+    #[test]
+    fn lock_backend_is_shared_with_other_processes() {
+        for backend in [LockBackend::Polled, LockBackend::Native] {
+            let directory = source_directory(&format!("lock-backend-{backend}"));
+            let path = directory.join("repository");
+
+            Repository::new(&path, 16, 0, None)
+                .unwrap()
+                .set_lock_backend(backend)
+                .unwrap();
+
+            let repository = Repository::open(&path, None, None).unwrap();
+            assert_eq!(repository.lock_backend(), backend);
+
+            let child = || {
+                std::process::Command::new(std::env::current_exe().unwrap())
+                    .args(["--exact", "repository::tests::lock_backend_child"])
+                    .env("DDUP_BAK_LOCK_CHILD", &path)
+                    .env("DDUP_BAK_LOCK_CHILD_BACKEND", backend.to_string())
+                    .stdout(std::process::Stdio::null())
+                    .status()
+                    .unwrap()
+                    .code()
+            };
+
+            let mut guard = repository.write_lock(LockMode::Destructive).unwrap();
+            assert_eq!(child(), Some(1), "{backend} lock held by the parent");
+
+            guard.unlock().unwrap();
+            assert_eq!(child(), Some(0), "{backend} lock released by the parent");
+
+            drop(repository);
+            std::fs::remove_dir_all(directory).unwrap();
+        }
+    }
+
+    #[test]
+    fn try_lock_contends_across_repository_instances() {
+        let directory = source_directory("try-lock");
+        std::fs::write(directory.join("source/file"), [1; 40]).unwrap();
+        let mut first = create_archive(&directory, "archive");
+        // the polled backend lets every instance of a process share the lock, the
+        // native one locks per open file like separate processes would
+        first.set_lock_backend(LockBackend::Native).unwrap();
+        first.save().unwrap();
+
+        let mut second = Repository::open(&directory.join("repository"), None, None).unwrap();
+        second.set_save_on_drop(false);
+        second.set_lock_timeout(Some(Duration::from_millis(100)));
+
+        let mut guard = first.try_lock(LockMode::Destructive).unwrap().unwrap();
+        assert!(second.try_lock(LockMode::Destructive).unwrap().is_none());
+        assert!(second.try_lock(LockMode::NonDestructive).unwrap().is_none());
+
+        let status = second.lock_status().unwrap();
+        assert_eq!(status.writer_pid, Some(std::process::id() as u64));
+        assert_eq!(status.writer_mode, Some(LockMode::Destructive));
+
+        assert_eq!(
+            second.delete_archive("archive", None).err().unwrap().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+        assert_eq!(second.list_archives().unwrap(), ["archive"]);
+
+        guard.unlock().unwrap();
+        assert_eq!(second.lock_status().unwrap().writer_pid, None);
+
+        let mut guard = second.try_lock(LockMode::Destructive).unwrap().unwrap();
+        assert!(first.try_lock(LockMode::Destructive).unwrap().is_none());
+        guard.unlock().unwrap();
+
+        drop((first, second));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_targets_stay_inside_the_destination() {
+        let directory = source_directory("copy-target");
+        let destination = directory.join("destination");
+        std::fs::create_dir_all(destination.join("directory")).unwrap();
+        std::fs::write(destination.join("file"), "inside").unwrap();
+        std::fs::write(directory.join("outside"), "outside").unwrap();
+
+        let link = destination.join("directory/link");
+        std::fs::write(&link, "placeholder").unwrap();
+
+        assert_eq!(
+            copy_target_source(&destination, &link, "../file"),
+            Some(std::fs::canonicalize(destination.join("file")).unwrap())
+        );
+        assert_eq!(
+            copy_target_source(&destination, &link, "../../outside"),
+            None
+        );
+        assert_eq!(
+            copy_target_source(
+                &destination,
+                &link,
+                directory.join("outside").to_str().unwrap()
+            ),
+            None
+        );
+        assert_eq!(copy_target_source(&destination, &link, "."), None);
+        assert_eq!(copy_target_source(&destination, &link, "link"), None);
+        assert_eq!(copy_target_source(&destination, &link, "missing"), None);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    /// Fills `source` with a tree of small files, a file of several restore tasks,
+    /// nested and empty directories and, on unix, symlinks.
+    fn fill_source(source: &Path) {
+        for i in 0..40 {
+            let directory = source.join(format!("directory-{}/nested-{}", i % 4, i % 3));
+            std::fs::create_dir_all(&directory).unwrap();
+            std::fs::write(directory.join(format!("file-{i}")), vec![i as u8; i * 7]).unwrap();
+        }
+        std::fs::create_dir_all(source.join("empty/nested-empty")).unwrap();
+
+        let large = (0..(DEFAULT_INLINE_RESTORE_SIZE * 3 / 2) as u32)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(source.join("large"), large).unwrap();
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("large", source.join("link")).unwrap();
+            std::os::unix::fs::symlink("../large", source.join("directory-1/link")).unwrap();
+        }
+    }
+
+    /// Every path below `directory` with the content of files and the target of symlinks.
+    fn tree(directory: &Path) -> Vec<(PathBuf, Option<Vec<u8>>)> {
+        let mut tree = Vec::new();
+        let mut stack = vec![directory.to_path_buf()];
+
+        while let Some(path) = stack.pop() {
+            for entry in std::fs::read_dir(&path).unwrap() {
+                let path = entry.unwrap().path();
+                let metadata = path.symlink_metadata().unwrap();
+                let relative = path.strip_prefix(directory).unwrap().to_path_buf();
+
+                if metadata.is_symlink() {
+                    let target = std::fs::read_link(&path).unwrap();
+                    tree.push((relative, Some(target.to_string_lossy().as_bytes().to_vec())));
+                } else if metadata.is_dir() {
+                    tree.push((relative, None));
+                    stack.push(path);
+                } else {
+                    tree.push((relative, Some(std::fs::read(&path).unwrap())));
+                }
+            }
+        }
+
+        tree.sort();
+        tree
+    }
+
+    #[test]
+    fn restore_batching_and_buffers_do_not_change_the_result() {
+        let directory = source_directory("restore-batching");
+        fill_source(&directory.join("source"));
+        let mut repository = create_archive(&directory, "archive");
+        let expected = tree(&directory.join("source"));
+
+        let mut reports = Vec::new();
+        for (inline_restore_size, io_buffer_size, threads) in [
+            (None, DEFAULT_COPY_BUFFER_SIZE, 1),
+            (None, DEFAULT_COPY_BUFFER_SIZE, 4),
+            (Some(0), DEFAULT_COPY_BUFFER_SIZE, 4),
+            (Some(u64::MAX), DEFAULT_COPY_BUFFER_SIZE, 4),
+            (None, 7, 4),
+        ] {
+            repository.set_io_buffer_size(io_buffer_size);
+
+            let destination = directory.join(format!(
+                "destination-{inline_restore_size:?}-{io_buffer_size}-{threads}"
+            ));
+            let entries = repository
+                .get_archive("archive")
+                .unwrap()
+                .into_entries()
+                .unwrap();
+            let report = repository
+                .restore_entries_with_options(
+                    "archive",
+                    entries,
+                    None,
+                    threads,
+                    RestoreOptions {
+                        destination: Some(destination.clone()),
+                        inline_restore_size,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+
+            assert_eq!(tree(&destination), expected, "{destination:?}");
+            assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+            reports.push((
+                report.files_restored,
+                report.directories_restored,
+                report.symlinks_restored,
+                report.bytes_written,
+            ));
+        }
+
+        assert!(reports.windows(2).all(|pair| pair[0] == pair[1]));
+
+        // reading an entry goes through the same buffer
+        let archive = repository.get_archive("archive").unwrap();
+        let entry = archive.find_archive_entry(Path::new("large")).unwrap();
+        let mut content = Vec::new();
+        repository
+            .read_entry_content(entry.unwrap().clone(), &mut content)
+            .unwrap();
+        assert_eq!(
+            content,
+            std::fs::read(directory.join("source/large")).unwrap()
+        );
+
+        drop(repository);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn archives_do_not_depend_on_the_thread_count() {
+        let directory = source_directory("create-threads");
+        let source = directory.join("source");
+        fill_source(&source);
+        let repository = Repository::new(&directory.join("repository"), 16, 0, None).unwrap();
+
+        let mut walks = Vec::new();
+        for threads in [1, 4] {
+            let name = format!("archive-{threads}");
+            repository
+                .create_archive(
+                    &name,
+                    Some(ignore::WalkBuilder::new(&source).build()),
+                    Some(&source),
+                    None,
+                    None,
+                    threads,
+                    CreateOptions::default(),
+                )
+                .unwrap();
+
+            let archive = repository.get_archive(&name).unwrap();
+            let mut walk = archive
+                .walk()
+                .map(|entry| {
+                    let (path, depth, entry) = entry.unwrap();
+                    let content = match entry {
+                        Entry::File(_) => {
+                            let mut content = Vec::new();
+                            repository
+                                .read_entry_content(entry.clone(), &mut content)
+                                .unwrap();
+                            Some(content)
+                        }
+                        Entry::Symlink(symlink) => Some(symlink.target.as_bytes().to_vec()),
+                        Entry::Directory(_) => None,
+                    };
+
+                    (path, depth, entry.mode().bits(), content)
+                })
+                .collect::<Vec<_>>();
+            walk.sort();
+            walks.push(walk);
         }
+
+        assert_eq!(walks[0].len(), tree(&source).len());
+        assert_eq!(walks[0], walks[1]);
+
+        drop(repository);
+        std::fs::remove_dir_all(directory).unwrap();
     }
 }
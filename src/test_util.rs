@@ -0,0 +1,21 @@
+//! Fixtures shared by the unit tests of the crate.
+
+use std::path::PathBuf;
+
+/// A path in the temp directory named after `name` and this process, so parallel test
+/// runs do not collide. Whatever an earlier run left there is removed.
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ddup-bak-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let _ = std::fs::remove_file(&path);
+
+    path
+}
+
+/// Like `temp_path`, but creates the directory.
+pub fn temp_directory(name: &str) -> PathBuf {
+    let directory = temp_path(name);
+    std::fs::create_dir_all(&directory).unwrap();
+
+    directory
+}
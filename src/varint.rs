@@ -1,69 +1,261 @@
-use std::io::Read;
+use std::io::{Read, Write};
 
+/// The most bytes a varint encoded `u32` takes.
+pub const MAX_LENGTH_U32: usize = 5;
+/// The most bytes a varint encoded `u64` takes.
+pub const MAX_LENGTH_U64: usize = 10;
+
+/// Encodes `value` into the start of `buf`, returning the number of bytes used.
 #[inline]
-pub fn encode_u32(value: u32) -> Vec<u8> {
-    let mut result = Vec::with_capacity(5);
+pub fn encode_u32_into(value: u32, buf: &mut [u8; MAX_LENGTH_U32]) -> usize {
     let mut value = value;
+    let mut length = 0;
 
     while value > 0x7F {
-        result.push((value & 0x7F) as u8 | 0x80);
+        buf[length] = (value & 0x7F) as u8 | 0x80;
         value >>= 7;
+        length += 1;
     }
-    result.push(value as u8);
+    buf[length] = value as u8;
+
+    length + 1
+}
 
-    result
+/// Writes `value` to `writer` without allocating.
+#[inline]
+pub fn write_u32<W: Write>(writer: &mut W, value: u32) -> std::io::Result<()> {
+    let mut buf = [0; MAX_LENGTH_U32];
+    let length = encode_u32_into(value, &mut buf);
+
+    writer.write_all(&buf[..length])
 }
 
+/// Encodes `value` into a new buffer. Prefer [`write_u32`] or [`encode_u32_into`] on hot
+/// paths, this allocates.
 #[inline]
+#[allow(dead_code)]
+pub fn encode_u32(value: u32) -> Vec<u8> {
+    let mut buf = [0; MAX_LENGTH_U32];
+    let length = encode_u32_into(value, &mut buf);
+
+    buf[..length].to_vec()
+}
+
+/// Decodes a varint encoded `u32`, see [`decode_u32_checked`].
+#[inline]
+#[allow(dead_code)]
 pub fn decode_u32<S: Read>(stream: &mut S) -> std::io::Result<u32> {
+    decode_u32_checked(stream)
+}
+
+/// Decodes a varint encoded `u32`, rejecting malformed input instead of panicking or
+/// wrapping. Errors are reported the same way as [`decode_u64_checked`].
+pub fn decode_u32_checked<S: Read>(stream: &mut S) -> std::io::Result<u32> {
     let mut result = 0;
-    let mut shift: u8 = 0;
 
     let mut byte = [0; 1];
-    loop {
-        stream.read_exact(&mut byte)?;
+    for index in 0..MAX_LENGTH_U32 {
+        if let Err(err) = stream.read_exact(&mut byte) {
+            if index == 0 || err.kind() != std::io::ErrorKind::UnexpectedEof {
+                return Err(err);
+            }
 
-        result |= ((byte[0] & 0x7F) as u32) << shift;
-        if byte[0] & 0x80 == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Truncated varint",
+            ));
+        }
+
+        let bits = (byte[0] & 0x7F) as u32;
+        // the 5th byte only has room for the top 4 bits of a u32
+        if index == MAX_LENGTH_U32 - 1 && bits > 0x0F {
             break;
         }
 
-        shift += 7;
+        result |= bits << (index * 7);
+        if byte[0] & 0x80 == 0 {
+            return Ok(result);
+        }
     }
 
-    Ok(result)
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "Varint does not fit in 32 bits",
+    ))
 }
 
+/// Encodes `value` into the start of `buf`, returning the number of bytes used.
 #[inline]
-pub fn encode_u64(value: u64) -> Vec<u8> {
-    let mut result = Vec::with_capacity(10);
+pub fn encode_u64_into(value: u64, buf: &mut [u8; MAX_LENGTH_U64]) -> usize {
     let mut value = value;
+    let mut length = 0;
 
     while value > 0x7F {
-        result.push((value & 0x7F) as u8 | 0x80);
+        buf[length] = (value & 0x7F) as u8 | 0x80;
         value >>= 7;
+        length += 1;
     }
-    result.push(value as u8);
+    buf[length] = value as u8;
+
+    length + 1
+}
+
+/// Writes `value` to `writer` without allocating.
+#[inline]
+pub fn write_u64<W: Write>(writer: &mut W, value: u64) -> std::io::Result<()> {
+    let mut buf = [0; MAX_LENGTH_U64];
+    let length = encode_u64_into(value, &mut buf);
 
-    result
+    writer.write_all(&buf[..length])
 }
 
+/// Encodes `value` into a new buffer. Prefer [`write_u64`] or [`encode_u64_into`] on hot
+/// paths, this allocates.
 #[inline]
-pub fn decode_u64<S: Read>(stream: &mut S) -> std::io::Result<u64> {
+#[allow(dead_code)]
+pub fn encode_u64(value: u64) -> Vec<u8> {
+    let mut buf = [0; MAX_LENGTH_U64];
+    let length = encode_u64_into(value, &mut buf);
+
+    buf[..length].to_vec()
+}
+
+/// Decodes a varint encoded `u64`, rejecting malformed input instead of panicking or
+/// wrapping. Fails with `UnexpectedEof` only if the stream ends before the first byte, a value
+/// cut off by the end of the stream or one that does not fit a `u64` is `InvalidData`.
+pub fn decode_u64_checked<S: Read>(stream: &mut S) -> std::io::Result<u64> {
     let mut result = 0;
-    let mut shift: u8 = 0;
 
     let mut byte = [0; 1];
-    loop {
-        stream.read_exact(&mut byte)?;
+    for index in 0..MAX_LENGTH_U64 {
+        if let Err(err) = stream.read_exact(&mut byte) {
+            if index == 0 || err.kind() != std::io::ErrorKind::UnexpectedEof {
+                return Err(err);
+            }
 
-        result |= ((byte[0] & 0x7F) as u64) << shift;
-        if byte[0] & 0x80 == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Truncated varint",
+            ));
+        }
+
+        let bits = (byte[0] & 0x7F) as u64;
+        // the 10th byte only has room for the top bit of a u64
+        if index == MAX_LENGTH_U64 - 1 && bits > 1 {
             break;
         }
 
-        shift += 7;
+        result |= bits << (index * 7);
+        if byte[0] & 0x80 == 0 {
+            return Ok(result);
+        }
     }
 
-    Ok(result)
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "Varint does not fit in 64 bits",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    const U64_BOUNDARIES: [(u64, usize); 10] = [
+        (0, 1),
+        (0x7F, 1),
+        (0x80, 2),
+        (0x3FFF, 2),
+        (0x4000, 3),
+        (u32::MAX as u64, 5),
+        (u32::MAX as u64 + 1, 5),
+        (1 << 56, 9),
+        (1 << 63, 10),
+        (u64::MAX, 10),
+    ];
+
+    #[test]
+    fn u64_boundaries_round_trip() {
+        for (value, expected_length) in U64_BOUNDARIES {
+            let mut buf = [0; MAX_LENGTH_U64];
+            let length = encode_u64_into(value, &mut buf);
+            assert_eq!(length, expected_length, "length of {value}");
+
+            let mut written = Vec::new();
+            write_u64(&mut written, value).unwrap();
+            assert_eq!(written, buf[..length]);
+            assert_eq!(encode_u64(value), written);
+
+            assert_eq!(decode_u64_checked(&mut &written[..]).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn u32_boundaries_round_trip() {
+        for (value, expected_length) in [(0, 1), (0x7F, 1), (0x80, 2), (1 << 28, 5), (u32::MAX, 5)]
+        {
+            let mut buf = [0; MAX_LENGTH_U32];
+            let length = encode_u32_into(value, &mut buf);
+            assert_eq!(length, expected_length, "length of {value}");
+
+            let mut written = Vec::new();
+            write_u32(&mut written, value).unwrap();
+            assert_eq!(written, buf[..length]);
+            assert_eq!(encode_u32(value), written);
+
+            assert_eq!(decode_u32_checked(&mut &written[..]).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn checked_decode_rejects_malformed_input() {
+        let kind = |bytes: &[u8]| decode_u64_checked(&mut &bytes[..]).unwrap_err().kind();
+
+        assert_eq!(kind(&[]), ErrorKind::UnexpectedEof);
+        assert_eq!(kind(&[0x80]), ErrorKind::InvalidData);
+        assert_eq!(kind(&[0xFF, 0xFF]), ErrorKind::InvalidData);
+
+        // the 10th byte may only carry the top bit
+        let mut too_large = [0xFF; MAX_LENGTH_U64];
+        too_large[MAX_LENGTH_U64 - 1] = 0x02;
+        assert_eq!(kind(&too_large), ErrorKind::InvalidData);
+
+        let too_long = [0x80; MAX_LENGTH_U64 + 1];
+        assert_eq!(kind(&too_long), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn checked_u32_decode_rejects_malformed_input() {
+        let kind = |bytes: &[u8]| decode_u32_checked(&mut &bytes[..]).unwrap_err().kind();
+
+        assert_eq!(kind(&[]), ErrorKind::UnexpectedEof);
+        assert_eq!(kind(&[0x80]), ErrorKind::InvalidData);
+
+        // the 5th byte may only carry the top 4 bits
+        let mut too_large = [0xFF; MAX_LENGTH_U32];
+        too_large[MAX_LENGTH_U32 - 1] = 0x10;
+        assert_eq!(kind(&too_large), ErrorKind::InvalidData);
+
+        let too_long = [0x80; MAX_LENGTH_U32 + 1];
+        assert_eq!(kind(&too_long), ErrorKind::InvalidData);
+
+        // a u64 sized value is not silently truncated
+        assert_eq!(
+            kind(&encode_u64(u32::MAX as u64 + 1)),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn checked_decode_stops_after_the_value() {
+        let mut stream = &[0xAC, 0x02, 0x05][..];
+
+        assert_eq!(decode_u64_checked(&mut stream).unwrap(), 300);
+        assert_eq!(decode_u64_checked(&mut stream).unwrap(), 5);
+        assert_eq!(
+            decode_u64_checked(&mut stream).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
 }
@@ -0,0 +1,74 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+/// Exit code of commands naming a backup that does not exist, see `commands::EXIT_NOT_FOUND`.
+const EXIT_NOT_FOUND: i32 = 3;
+
+/// A repository in the temp directory holding a single backup named `present`.
+fn repository(name: &str) -> PathBuf {
+    let directory =
+        std::env::temp_dir().join(format!("ddup-bak-cli-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("file"), "content").unwrap();
+
+    assert!(run(&directory, &["init", "."]).status.success());
+    assert!(
+        run(&directory, &["backup", "create", "present"])
+            .status
+            .success()
+    );
+
+    directory
+}
+
+fn run(directory: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ddup-bak"))
+        .args(["--color", "always", "--yes"])
+        .args(args)
+        .current_dir(directory)
+        .env_remove("DDUP_BAK_JSON")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn commands_report_missing_backups() {
+    let directory = repository("missing");
+    let mount = directory.join("mount");
+
+    for args in [
+        &["backup", "restore", "missing"][..],
+        &["backup", "delete", "missing"],
+        &["backup", "info", "missing"],
+        &["backup", "verify", "missing"],
+        &["backup", "rename", "missing", "renamed"],
+        &["backup", "mount", "missing", mount.to_str().unwrap()],
+        &["backup", "fs", "missing", "ls"],
+        &["backup", "fs", "missing", "cat", "file"],
+    ] {
+        let output = run(&directory, args);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert_eq!(
+            output.status.code(),
+            Some(EXIT_NOT_FOUND),
+            "{args:?}: {stderr}"
+        );
+        assert!(
+            stdout.contains("\x1B[31mdoes not exist!"),
+            "{args:?}: {stdout}"
+        );
+        assert!(!stderr.contains("panicked"), "{args:?}: {stderr}");
+    }
+
+    // the backup that exists is still found by the same commands
+    let output = run(&directory, &["backup", "fs", "present", "cat", "file"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "content");
+
+    std::fs::remove_dir_all(directory).unwrap();
+}